@@ -0,0 +1,304 @@
+use crate::store::diff::{PackageDiff, RemovedPackage, StoreDiff};
+use crate::store::fingerprint::{fnv, FNV_OFFSET};
+use anyhow::{anyhow, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A single change reported by a diff, which is what an acknowledgment is made of.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Change {
+    /// The name of the package, or of the package and the dependency separated by a slash, such as `firefox/nss`.
+    pub name: String,
+    pub ver_from: String,
+    /// The new version, which is empty for removed packages.
+    pub ver_to: String,
+    /// The kind of change, as used by `--where`.
+    pub kind: String,
+}
+
+impl Change {
+    fn store(name: String, diff: &StoreDiff) -> Self {
+        Self {
+            name,
+            ver_from: diff.ver_from.clone(),
+            ver_to: diff.ver_to.clone(),
+            kind: diff.kind().into(),
+        }
+    }
+
+    fn removal(removal: &RemovedPackage) -> Self {
+        Self {
+            name: removal.name.clone(),
+            ver_from: removal.version.clone(),
+            ver_to: String::new(),
+            kind: "removed".into(),
+        }
+    }
+
+    /// Returns every change in `diff`, which are the change to the package itself and each of its dependencies.
+    fn of_diff(diff: &PackageDiff) -> impl Iterator<Item = Self> + '_ {
+        let pkg = diff
+            .pkg
+            .iter()
+            .map(move |pkg| Self::store(diff.name.clone(), pkg));
+
+        let deps = diff
+            .deps
+            .iter()
+            .map(move |dep| Self::store(format!("{}/{}", diff.name, dep.name), dep));
+
+        pkg.chain(deps)
+    }
+}
+
+/// The changes that were acknowledged with `nixup ack`, which are kept in the data directory until a new
+/// baseline is saved.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Acks {
+    /// A hash over every change in `changes`, which doesn't depend on the order the changes were found in.
+    pub digest: u64,
+    /// The number of package updates and removals that were acknowledged.
+    pub updates: usize,
+    pub changes: BTreeSet<Change>,
+}
+
+impl Acks {
+    /// Gathers every change in `diffs` and `removals`, which should be found without any filters so the
+    /// same system always has the same digest.
+    pub fn new(diffs: &[PackageDiff], removals: &[RemovedPackage]) -> Self {
+        let changes = diffs
+            .iter()
+            .flat_map(Change::of_diff)
+            .chain(removals.iter().map(Change::removal))
+            .collect::<BTreeSet<_>>();
+
+        Self {
+            digest: digest(&changes),
+            updates: diffs.len() + removals.len(),
+            changes,
+        }
+    }
+
+    /// Loads the acknowledgments in `data_dir`, if there are any.
+    ///
+    /// Acknowledgments that can't be read are treated as missing, which only means every change is shown again.
+    pub fn load(data_dir: &Path) -> Option<Self> {
+        let contents = fs::read(Self::path(data_dir)).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    /// Saves the acknowledgments to `data_dir`, replacing any that were saved before.
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let path = Self::path(data_dir);
+        let temp_path = path.with_extension("json.tmp");
+
+        let contents = serde_json::to_vec(self)?;
+
+        fs::write(&temp_path, contents)
+            .with_context(|| anyhow!("failed to write {}", temp_path.display()))?;
+
+        fs::rename(&temp_path, &path).with_context(|| {
+            anyhow!(
+                "failed to move {} to {}",
+                temp_path.display(),
+                path.display()
+            )
+        })
+    }
+
+    /// Removes the acknowledgments in `data_dir`, which only apply to diffs against the baseline they were made with.
+    pub fn clear(data_dir: &Path) -> Result<()> {
+        let path = Self::path(data_dir);
+
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).with_context(|| anyhow!("failed to remove {}", path.display())),
+        }
+    }
+
+    pub fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join("acked.json")
+    }
+
+    /// Returns true if `current` has exactly the same changes as were acknowledged.
+    pub fn is_identical(&self, current: &Self) -> bool {
+        self.digest == current.digest
+    }
+
+    /// Returns true if every change in `diff` was acknowledged.
+    pub fn covers(&self, diff: &PackageDiff) -> bool {
+        Change::of_diff(diff).all(|change| self.changes.contains(&change))
+    }
+
+    pub fn covers_removal(&self, removal: &RemovedPackage) -> bool {
+        self.changes.contains(&Change::removal(removal))
+    }
+}
+
+/// Hashes `changes` in order, which is always sorted.
+fn digest(changes: &BTreeSet<Change>) -> u64 {
+    changes.iter().fold(FNV_OFFSET, |hash, change| {
+        [
+            change.name.as_bytes(),
+            &[0],
+            change.ver_from.as_bytes(),
+            &[0],
+            change.ver_to.as_bytes(),
+            &[0],
+            change.kind.as_bytes(),
+            &[0],
+        ]
+        .iter()
+        .fold(hash, |hash, bytes| fnv(hash, bytes))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store_diff(name: &str, ver_from: &str, ver_to: &str) -> StoreDiff {
+        StoreDiff {
+            name: name.into(),
+            suffix: None,
+            suffix_from: None,
+            ver_from: ver_from.into(),
+            ver_to: ver_to.into(),
+            register_time: 0,
+        }
+    }
+
+    fn package(
+        name: &str,
+        versions: Option<(&str, &str)>,
+        deps: &[(&str, &str, &str)],
+    ) -> PackageDiff {
+        PackageDiff {
+            name: name.into(),
+            pkg: versions.map(|(from, to)| store_diff(name, from, to)),
+            deps: deps
+                .iter()
+                .map(|&(dep, from, to)| store_diff(dep, from, to))
+                .collect(),
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: None,
+        }
+    }
+
+    fn removal(name: &str, version: &str) -> RemovedPackage {
+        RemovedPackage {
+            name: name.into(),
+            version: version.into(),
+            gone: Vec::new(),
+            retained: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn digest_ignores_order() {
+        let firefox = || {
+            package(
+                "firefox",
+                Some(("123.0", "124.0")),
+                &[("nss", "3.97", "3.98")],
+            )
+        };
+        let git = || package("git", Some(("2.44.0", "2.43.0")), &[]);
+
+        let acks = Acks::new(&[firefox(), git()], &[removal("vim", "9.1")]);
+        let reversed = Acks::new(&[git(), firefox()], &[removal("vim", "9.1")]);
+
+        assert_eq!(acks, reversed);
+        assert_eq!(acks.updates, 3);
+        assert_eq!(acks.changes.len(), 4);
+        assert!(acks.is_identical(&reversed));
+
+        let downgrade = acks
+            .changes
+            .iter()
+            .find(|change| change.name == "git")
+            .unwrap();
+
+        assert_eq!(downgrade.kind, "downgraded");
+
+        // A dependency changing to another version is a different diff, even with the same packages
+        let changed = Acks::new(
+            &[
+                package(
+                    "firefox",
+                    Some(("123.0", "124.0")),
+                    &[("nss", "3.97", "3.99")],
+                ),
+                git(),
+            ],
+            &[removal("vim", "9.1")],
+        );
+
+        assert!(!acks.is_identical(&changed));
+    }
+
+    #[test]
+    fn cover_partial_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let acked = [
+            package(
+                "firefox",
+                Some(("123.0", "124.0")),
+                &[("nss", "3.97", "3.98")],
+            ),
+            package("git", Some(("2.43.0", "2.44.0")), &[]),
+        ];
+
+        assert_eq!(Acks::load(dir.path()), None);
+
+        Acks::new(&acked, &[removal("vim", "9.1")])
+            .save(dir.path())
+            .unwrap();
+
+        let acks = Acks::load(dir.path()).unwrap();
+
+        let current = [
+            // Exactly as acknowledged
+            package(
+                "firefox",
+                Some(("123.0", "124.0")),
+                &[("nss", "3.97", "3.98")],
+            ),
+            // Updated again since it was acknowledged
+            package("git", Some(("2.43.0", "2.45.0")), &[]),
+            // Only the package's own change was acknowledged, not the new dependency change
+            package(
+                "firefox",
+                Some(("123.0", "124.0")),
+                &[("nss", "3.97", "3.98"), ("zlib", "1.3", "1.3.1")],
+            ),
+            // The same dependency change, but under a package it wasn't acknowledged for
+            package("thunderbird", None, &[("nss", "3.97", "3.98")]),
+        ];
+
+        let covered = current
+            .iter()
+            .map(|diff| acks.covers(diff))
+            .collect::<Vec<_>>();
+
+        assert_eq!(covered, [true, false, false, false]);
+
+        assert!(acks.covers_removal(&removal("vim", "9.1")));
+        assert!(!acks.covers_removal(&removal("vim", "9.0")));
+        assert!(!acks.covers_removal(&removal("emacs", "29.2")));
+
+        assert!(!acks.is_identical(&Acks::new(&current, &[])));
+
+        Acks::clear(dir.path()).unwrap();
+        assert_eq!(Acks::load(dir.path()), None);
+
+        // Clearing when nothing was acknowledged isn't an error
+        Acks::clear(dir.path()).unwrap();
+    }
+}
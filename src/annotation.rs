@@ -0,0 +1,187 @@
+use crate::critical::CriticalChange;
+use crate::runs::Changes;
+use crate::store::diff::Outcome;
+use std::fmt;
+
+/// How prominently an annotation is shown by GitHub Actions.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Level {
+    Notice,
+    Warning,
+}
+
+impl Level {
+    fn command(self) -> &'static str {
+        match self {
+            Self::Notice => "notice",
+            Self::Warning => "warning",
+        }
+    }
+}
+
+/// A finding worth surfacing in the checks of a GitHub Actions run.
+#[derive(Debug, PartialEq)]
+pub struct Annotation {
+    pub level: Level,
+    pub title: &'static str,
+    pub message: String,
+}
+
+impl Annotation {
+    fn new(level: Level, title: &'static str, message: String) -> Self {
+        Self {
+            level,
+            title,
+            message,
+        }
+    }
+}
+
+/// Formats the annotation as a GitHub Actions workflow command, such as `::warning title=Downgrade::...`.
+impl fmt::Display for Annotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "::{} title={}::{}",
+            self.level.command(),
+            escape_property(self.title),
+            escape_data(&self.message)
+        )
+    }
+}
+
+/// Picks out the findings of a diff that are worth an annotation.
+///
+/// Ordinary updates aren't annotated, since a large update would bury everything else.
+pub fn collect(
+    changes: &Changes,
+    critical: &[CriticalChange],
+    reboot_pending: bool,
+) -> Vec<Annotation> {
+    let mut annotations = Vec::new();
+
+    if changes.outcome == Outcome::EmptyBaseline {
+        annotations.push(Annotation::new(
+            Level::Warning,
+            "Empty saved state",
+            "the saved state has no packages, so nothing could be compared".into(),
+        ));
+    }
+
+    for downgrade in &changes.downgrades {
+        annotations.push(Annotation::new(
+            Level::Warning,
+            "Downgrade",
+            downgrade.clone(),
+        ));
+    }
+
+    for change in critical {
+        let mut message = format!("{}: {} -> {}", change.name, change.ver_from, change.ver_to);
+
+        if !change.as_package {
+            message.push_str(&format!(
+                " (dependency of {})",
+                change.dependents.join(", ")
+            ));
+        }
+
+        annotations.push(Annotation::new(
+            Level::Warning,
+            "Security-relevant change",
+            message,
+        ));
+    }
+
+    if reboot_pending {
+        annotations.push(Annotation::new(
+            Level::Notice,
+            "Reboot required",
+            "the booted kernel differs from the kernel of the current system".into(),
+        ));
+    }
+
+    annotations
+}
+
+/// Escapes the message of a workflow command.
+fn escape_data(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escapes a property of a workflow command, which also can't contain the separators between properties.
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn changes(outcome: Outcome, downgrades: &[&str]) -> Changes {
+        Changes {
+            downgrades: downgrades
+                .iter()
+                .map(|&downgrade| downgrade.into())
+                .collect(),
+            outcome,
+            ..Changes::default()
+        }
+    }
+
+    #[test]
+    fn collect_notable_findings() {
+        let critical = [CriticalChange {
+            name: "glibc".into(),
+            ver_from: "2.38".into(),
+            ver_to: "2.39".into(),
+            as_package: false,
+            dependents: vec!["curl".into(), "git".into()],
+            hidden: true,
+        }];
+
+        let lines = collect(
+            &changes(
+                Outcome::Changed,
+                &["nss (dependency of firefox): 3.98 -> 3.97"],
+            ),
+            &critical,
+            true,
+        )
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            [
+                "::warning title=Downgrade::nss (dependency of firefox): 3.98 -> 3.97",
+                "::warning title=Security-relevant change::glibc: 2.38 -> 2.39 (dependency of curl, git)",
+                "::notice title=Reboot required::the booted kernel differs from the kernel of the current system",
+            ]
+        );
+
+        assert!(collect(&changes(Outcome::Changed, &[]), &[], false).is_empty());
+        assert_eq!(
+            collect(&changes(Outcome::EmptyBaseline, &[]), &[], false)[0].level,
+            Level::Warning
+        );
+    }
+
+    #[test]
+    fn escape_commands() {
+        let annotation = Annotation {
+            level: Level::Notice,
+            title: "a: b, c",
+            message: "100%\nmore\r".into(),
+        };
+
+        assert_eq!(
+            annotation.to_string(),
+            "::notice title=a%3A b%2C c::100%25%0Amore%0D"
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use crate::profile;
+use crate::rejects::{Rejects, MAX_REJECTS};
+use crate::state::{PackageState, StateMeta};
+use crate::store::budget::Budget;
+use crate::store::database::SystemDatabase;
+use crate::store::dedup::DedupPolicy;
+use crate::store::outputs::{self, OutputPolicy};
+use crate::store::scan::Watermark;
+use crate::store::{DepOptions, Derivation, Store};
+use anyhow::{anyhow, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What the system looked like the last time nixup ran, which is recorded so a system that changed
+/// in between runs can be noticed.
+///
+/// Only the generation of the profile and the newest path in the Nix database are recorded, so checking
+/// whether anything changed never needs a full scan.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LastSeen {
+    pub generation: u32,
+    /// The id of the newest path in the Nix database.
+    pub max_id: i32,
+    /// The newest registration time in the Nix database.
+    pub max_register_time: i32,
+}
+
+impl LastSeen {
+    /// Returns what the system looks like now, going by the generation of the profile at `profile`.
+    pub fn current(profile: &Path, db: &SystemDatabase) -> Result<Self> {
+        let generation = profile::current_generation(profile)?;
+        let watermark = Watermark::current(db)?;
+
+        Ok(Self {
+            generation,
+            max_id: watermark.max_id,
+            max_register_time: watermark.max_register_time,
+        })
+    }
+
+    /// Loads the record in `data_dir`.
+    ///
+    /// A record that can't be read is treated as missing, since it will simply be replaced.
+    pub fn load(data_dir: &Path) -> Option<Self> {
+        let contents = fs::read(Self::path(data_dir)).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    /// Saves the record to `data_dir`.
+    ///
+    /// The record is written to a temporary file that is then moved over the old one, so an interrupted
+    /// write never leaves a truncated record behind.
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let path = Self::path(data_dir);
+        let temp_path = path.with_extension("json.tmp");
+
+        let contents = serde_json::to_vec(self)?;
+
+        fs::write(&temp_path, contents)
+            .with_context(|| anyhow!("failed to write {}", temp_path.display()))?;
+
+        fs::rename(&temp_path, &path).with_context(|| {
+            anyhow!(
+                "failed to move {} to {}",
+                temp_path.display(),
+                path.display()
+            )
+        })
+    }
+
+    pub fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join("last-seen.json")
+    }
+
+    /// Returns the point in the Nix database this record was taken at.
+    fn watermark(&self) -> Watermark {
+        Watermark {
+            max_id: self.max_id,
+            max_register_time: self.max_register_time,
+            count: 0,
+        }
+    }
+
+    /// Returns true if the state described by `meta` was saved during or after the system this record describes.
+    ///
+    /// States saved before generations were recorded are compared by when they were saved instead.
+    fn covered_by(&self, meta: &StateMeta) -> bool {
+        match meta.generation {
+            Some(generation) => generation >= self.generation,
+            None => meta.saved_at >= self.max_register_time.max(0) as u64,
+        }
+    }
+}
+
+/// Options that control how the system is saved automatically.
+#[derive(Copy, Clone, Debug)]
+pub struct AutosaveOptions<'a> {
+    pub policy: DedupPolicy,
+    /// Which output represents each package.
+    pub outputs: &'a OutputPolicy,
+    pub deps: DepOptions,
+    /// Record what was left out of the saved state.
+    pub record_rejects: bool,
+}
+
+/// Saves the system as it was on the previous run as the baseline in `data_dir`, if the system changed since
+/// then and the baseline wasn't saved during or after the previous run's generation. This makes sure there is
+/// always a baseline to diff against, even if the state was never saved by hand.
+///
+/// Nothing is saved on the first run, since nothing is known about the system before it. The record of what
+/// was last seen is updated either way, and the generation that was saved is returned.
+pub fn run(
+    data_dir: &Path,
+    profile: &Path,
+    db: &SystemDatabase,
+    opts: AutosaveOptions<'_>,
+    budget: &Budget,
+) -> Result<Option<u32>> {
+    let current = LastSeen::current(profile, db)?;
+    let last = LastSeen::load(data_dir);
+
+    let saved = match last {
+        Some(last) if last != current && !baseline_covers(data_dir, &last)? => {
+            save_previous(data_dir, db, &last, opts, budget)?;
+            Some(last.generation)
+        }
+        _ => None,
+    };
+
+    if last != Some(current) {
+        current
+            .save(data_dir)
+            .context("failed to record the current system")?;
+    }
+
+    Ok(saved)
+}
+
+fn baseline_covers(data_dir: &Path, last: &LastSeen) -> Result<bool> {
+    let path = PackageState::save_path(data_dir);
+
+    if !path.exists() {
+        return Ok(false);
+    }
+
+    let meta = PackageState::load_meta(&path)?;
+    Ok(last.covered_by(&meta))
+}
+
+/// Saves the stores that were registered by the time `last` was recorded as the baseline.
+///
+/// The previous baseline is moved to the snapshot directory like any other save.
+fn save_previous(
+    data_dir: &Path,
+    db: &SystemDatabase,
+    last: &LastSeen,
+    opts: AutosaveOptions<'_>,
+    budget: &Budget,
+) -> Result<()> {
+    let mut unparsed = Vec::new();
+
+    let (stores, shadowed) = Store::all_from_system_until(
+        db,
+        last.watermark(),
+        budget,
+        opts.policy,
+        opts.record_rejects.then_some(&mut unparsed),
+    )
+    .context("failed to parse system stores")?;
+
+    let stores = outputs::select(stores, &shadowed, opts.outputs).stores;
+
+    let (pkgs, _) = Derivation::all_from_stores(stores, db, opts.deps, budget)
+        .context("failed to parse system derivations")?;
+
+    if budget.is_partial() {
+        return Err(anyhow!("refusing to save an incomplete package state"));
+    }
+
+    let message = format!("generation {}, saved automatically", last.generation);
+
+    let mut state = PackageState::new(pkgs, Some(message)).context("invalid package state")?;
+
+    if opts.record_rejects {
+        state.rejects = Some(Rejects::new(unparsed, &shadowed, MAX_REJECTS));
+    }
+
+    state.shadowed = shadowed;
+    state.meta.generation = Some(last.generation);
+
+    state.save(data_dir).with_context(|| {
+        anyhow!(
+            "failed to save generation {} automatically",
+            last.generation
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::database::fixture;
+    use std::collections::HashSet;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    struct System {
+        profiles: TempDir,
+        data_dir: TempDir,
+        db: SystemDatabase,
+    }
+
+    impl System {
+        fn new() -> Self {
+            Self {
+                profiles: tempfile::tempdir().unwrap(),
+                data_dir: tempfile::tempdir().unwrap(),
+                db: fixture::empty(),
+            }
+        }
+
+        fn profile(&self) -> PathBuf {
+            self.profiles.path().join("system")
+        }
+
+        fn switch_to(&self, generation: u32) {
+            let profile = self.profile();
+
+            if fs::symlink_metadata(&profile).is_ok() {
+                fs::remove_file(&profile).unwrap();
+            }
+
+            symlink(format!("system-{}-link", generation), profile).unwrap();
+        }
+
+        fn run(&self) -> Option<u32> {
+            let opts = AutosaveOptions {
+                policy: DedupPolicy::default(),
+                outputs: &OutputPolicy::default(),
+                deps: DepOptions::default(),
+                record_rejects: false,
+            };
+
+            run(
+                self.data_dir.path(),
+                &self.profile(),
+                &self.db,
+                opts,
+                &Budget::unlimited(),
+            )
+            .unwrap()
+        }
+
+        fn baseline(&self) -> (StateMeta, HashSet<(String, String)>) {
+            let state = PackageState::load(self.data_dir.path()).unwrap();
+
+            let packages = state
+                .packages
+                .into_iter()
+                .map(|pkg| (pkg.store.name, pkg.store.version))
+                .collect();
+
+            (state.meta, packages)
+        }
+    }
+
+    fn packages(list: &[(&str, &str)]) -> HashSet<(String, String)> {
+        list.iter()
+            .map(|&(name, version)| (name.into(), version.into()))
+            .collect()
+    }
+
+    #[test]
+    fn save_previous_generation() {
+        let system = System::new();
+
+        fixture::add_path(&system.db, 1, "firefox-121.0", 100);
+        fixture::add_path(&system.db, 2, "zsh-5.9", 100);
+        system.switch_to(1);
+
+        assert_eq!(system.run(), None, "first run");
+        assert!(!PackageState::save_path(system.data_dir.path()).exists());
+        assert_eq!(system.run(), None, "unchanged");
+
+        fixture::add_path(&system.db, 3, "firefox-122.0", 10_000);
+        fixture::add_path(&system.db, 4, "neovim-0.10.0", 10_000);
+        system.switch_to(2);
+
+        assert_eq!(system.run(), Some(1), "switched generation");
+
+        // Only the paths that existed when generation 1 was last seen are saved
+        let (meta, saved) = system.baseline();
+        assert_eq!(meta.generation, Some(1));
+        assert_eq!(
+            meta.message.as_deref(),
+            Some("generation 1, saved automatically")
+        );
+        assert_eq!(saved, packages(&[("firefox", "121.0"), ("zsh", "5.9")]));
+
+        assert_eq!(system.run(), None, "already saved");
+
+        assert_eq!(
+            LastSeen::load(system.data_dir.path()),
+            Some(LastSeen {
+                generation: 2,
+                max_id: 4,
+                max_register_time: 10_000,
+            })
+        );
+    }
+
+    #[test]
+    fn keep_newer_baselines() {
+        let system = System::new();
+
+        fixture::add_path(&system.db, 1, "firefox-121.0", 100);
+        system.switch_to(1);
+        assert_eq!(system.run(), None);
+
+        // A baseline saved by hand during generation 1 is newer than anything that could be saved automatically
+        let mut state = PackageState::new(HashSet::new(), Some("by hand".into())).unwrap();
+        state.meta.generation = Some(1);
+        state.save(system.data_dir.path()).unwrap();
+
+        fixture::add_path(&system.db, 2, "firefox-122.0", 10_000);
+        system.switch_to(2);
+
+        assert_eq!(system.run(), None);
+        assert_eq!(system.baseline().0.message.as_deref(), Some("by hand"));
+        assert_eq!(
+            LastSeen::load(system.data_dir.path()).map(|seen| seen.generation),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn replace_unreadable_records() {
+        let system = System::new();
+
+        fixture::add_path(&system.db, 1, "firefox-121.0", 100);
+        system.switch_to(1);
+
+        let path = LastSeen::path(system.data_dir.path());
+        fs::write(&path, "{ \"generation\": ").unwrap();
+
+        assert_eq!(system.run(), None);
+        assert_eq!(
+            LastSeen::load(system.data_dir.path()).unwrap().generation,
+            1
+        );
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn cover_baselines() {
+        let seen = LastSeen {
+            generation: 5,
+            max_id: 10,
+            max_register_time: 1000,
+        };
+
+        let meta = |generation, saved_at| StateMeta {
+            saved_at,
+            message: None,
+            generation,
+            ..StateMeta::default()
+        };
+
+        assert!(seen.covered_by(&meta(Some(5), 0)), "same generation");
+        assert!(seen.covered_by(&meta(Some(6), 0)), "newer generation");
+        assert!(!seen.covered_by(&meta(Some(4), 2000)), "older generation");
+        assert!(seen.covered_by(&meta(None, 1000)), "saved after");
+        assert!(!seen.covered_by(&meta(None, 999)), "saved before");
+    }
+}
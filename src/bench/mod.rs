@@ -0,0 +1,363 @@
+use crate::state::PackageState;
+use crate::store::dedup::DedupPolicy;
+use crate::store::diff::{self, DiffOptions, StoreDiff};
+use crate::store::{Derivation, Store};
+use crate::testing::{self, Rng};
+use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// The seed every workload is generated from, so each run measures exactly the same work.
+const SEED: u64 = 0x006e_6978_7570;
+
+/// Names from the store parsing tests, which cover the shapes of names found on real systems.
+const REAL_NAMES: [&str; 26] = [
+    "glxinfo-8.4.0",
+    "pcre-8.42",
+    "dxvk-v1.4.6",
+    "dxvk-c47095a8dcfa4c376d8e9c4276865b7f298137d8",
+    "rpcs3-9165-8ca53f9",
+    "wine-wow-4.21-staging",
+    "wine-wow-4.0-rc5-staging",
+    "ffmpeg-3.4.5-bin",
+    "vulkan-loader-1.1.85",
+    "vpnc-0.5.3-post-r550",
+    "gcc-13.2.0-lib64",
+    "glibc-2.39-dev-bin",
+    "linux-headers-6.6-dev",
+    "hello-2.12-out-2",
+    "mesa-24.0.1-dev-3",
+    "openssl-3.2.0-rc2-bin",
+    "perl-5.38.2-2",
+    "tzdata-2021_03",
+    "cargo-about-1.0.0+build.5",
+    "libusb1-compat-0.1.8",
+    "sqlite3-editor-2024.1.1",
+    "qt-5-compat-shim-2.1.0-dev",
+    "gnome-2-style-v3.1",
+    "hyprland-0-unstable-2024-05-01",
+    "fix-static.patch",
+    "some-deriv.drv",
+];
+
+/// Parts that generated names are made of.
+const NAME_PARTS: [&str; 16] = [
+    "lib", "gtk", "python3", "core", "x11", "font", "kde", "gnome", "rust", "node", "qt", "utils",
+    "net", "media", "sys", "tools",
+];
+
+/// Suffixes of generated stores, with no suffix being the most common.
+const SUFFIXES: [&str; 8] = ["", "", "", "", "bin", "dev", "man", "lib"];
+
+/// The characters Nix uses in the hashes of store paths.
+const HASH_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Returns `count` store paths generated from `seed`, with every eighth one being a name from a real system.
+pub fn corpus(seed: u64, count: usize) -> Vec<String> {
+    let mut rng = Rng::new(seed);
+
+    (0..count)
+        .map(|i| {
+            let hash = (0..32)
+                .map(|_| HASH_CHARS[rng.below(HASH_CHARS.len() as u64) as usize] as char)
+                .collect::<String>();
+
+            let name = if i % 8 == 0 {
+                REAL_NAMES[(i / 8) % REAL_NAMES.len()].to_string()
+            } else {
+                generated_name(&mut rng, i)
+            };
+
+            format!("/nix/store/{}-{}", hash, name)
+        })
+        .collect()
+}
+
+fn generated_name(rng: &mut Rng, i: usize) -> String {
+    let parts = (0..=rng.below(2))
+        .map(|_| NAME_PARTS[rng.below(NAME_PARTS.len() as u64) as usize])
+        .collect::<Vec<_>>();
+
+    let version = format!("{}.{}.{}", rng.below(5), rng.below(40), rng.below(20));
+
+    let mut name = format!("{}{}-{}", parts.join("-"), i, version);
+
+    match SUFFIXES[rng.below(SUFFIXES.len() as u64) as usize] {
+        "" => (),
+        suffix => {
+            name.push('-');
+            name.push_str(suffix);
+        }
+    }
+
+    name
+}
+
+/// Returns a store called `name`, whose version is bumped in every `generation` for a quarter of ids.
+fn store(id: usize, name: String, generation: usize) -> Store {
+    let bump = if id.is_multiple_of(4) { generation } else { 0 };
+
+    Store {
+        id: id as u32,
+        register_time: 1_700_000_000,
+        ..testing::store(&name, &format!("1.{}.{}", id % 97, bump))
+    }
+}
+
+/// Returns a state of `count` packages with `deps` dependencies each, as of `generation`.
+fn packages(count: usize, deps: usize, generation: usize) -> HashSet<Derivation> {
+    (0..count)
+        .map(|i| Derivation {
+            store: store(i, format!("pkg-{}", i), generation),
+            deps: (0..deps)
+                .map(|j| {
+                    let dep = (i * 31 + j * 7) % (count * 2);
+                    store(count + dep, format!("lib-{}", dep), generation)
+                })
+                .collect(),
+            paths: HashMap::new(),
+        })
+        .collect()
+}
+
+fn parse(size: usize) -> Duration {
+    let paths = corpus(SEED, size);
+    let start = Instant::now();
+
+    for (i, path) in paths.iter().enumerate() {
+        black_box(Store::parse(i as u32, 0, path));
+    }
+
+    start.elapsed()
+}
+
+/// Deduplicates stores where every name has about four stores spread over two duplicate windows.
+fn get_unique(size: usize) -> Duration {
+    let mut rng = Rng::new(SEED);
+
+    let stores = (0..size)
+        .map(|i| {
+            let mut store = store(i, format!("pkg-{}", rng.below(size as u64 / 4 + 1)), 0);
+            store.version = format!("1.{}", rng.below(3));
+            store.register_time = rng.below(Store::DUPLICATE_WINDOW as u64 * 2) as u32;
+            store
+        })
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    black_box(Store::partition_unique(
+        stores.into_iter(),
+        Store::DUPLICATE_WINDOW,
+        DedupPolicy::Drop,
+    ));
+    start.elapsed()
+}
+
+fn from_store_list(size: usize) -> Duration {
+    let stores = |generation| {
+        (0..size)
+            .map(|i| store(i, format!("lib-{}", i), generation))
+            .collect::<HashSet<_>>()
+    };
+
+    let (old, new) = (stores(0), stores(1));
+    let start = Instant::now();
+
+    black_box(StoreDiff::from_store_list(&new, &old, false));
+    start.elapsed()
+}
+
+fn package_diffs(size: usize) -> Duration {
+    let (old, new) = (packages(size, 20, 0), packages(size, 20, 1));
+    let start = Instant::now();
+
+    black_box(diff::get_package_diffs(&new, &old, DiffOptions::default()));
+    start.elapsed()
+}
+
+fn state_round_trip(size: usize) -> Duration {
+    let dir = tempfile::tempdir().unwrap();
+    let state = PackageState::new(packages(size, 20, 0), None).unwrap();
+    let start = Instant::now();
+
+    state.save(dir.path()).unwrap();
+    let loaded = PackageState::load(dir.path()).unwrap();
+
+    let elapsed = start.elapsed();
+    assert_eq!(loaded.packages.len(), size);
+    elapsed
+}
+
+/// A hot path measured by the benchmarks and the regression guard.
+struct Workload {
+    name: &'static str,
+    /// The size of the workload when it's benchmarked.
+    size: usize,
+    /// Sets up a workload of the given size, and returns how long the part being measured took.
+    run: fn(usize) -> Duration,
+}
+
+const WORKLOADS: [Workload; 5] = [
+    Workload {
+        name: "parse",
+        size: 10_000,
+        run: parse,
+    },
+    Workload {
+        name: "get_unique",
+        size: 10_000,
+        run: get_unique,
+    },
+    Workload {
+        name: "from_store_list",
+        size: 2_000,
+        run: from_store_list,
+    },
+    Workload {
+        name: "get_package_diffs",
+        size: 2_000,
+        run: package_diffs,
+    },
+    Workload {
+        name: "state_round_trip",
+        size: 2_000,
+        run: state_round_trip,
+    },
+];
+
+impl Workload {
+    /// Returns the fastest of `runs` runs at `size`, which is the least affected by whatever else is running.
+    fn fastest(&self, size: usize, runs: u32) -> Duration {
+        (0..runs)
+            .map(|_| (self.run)(size))
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+/// The times recorded for the scaled-down workloads the guard runs, and how far they can be exceeded.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Baseline {
+    /// How many times smaller the workloads the guard runs are than the benchmarked ones.
+    scale: usize,
+    /// How many times its recorded time a workload can take before the guard fails.
+    max_slowdown: f64,
+    /// The least time any workload is allowed, so very fast workloads can't fail from noise alone.
+    min_allowance_ms: f64,
+    /// The fastest time of each scaled-down workload when the baseline was recorded, in milliseconds.
+    workloads: HashMap<String, f64>,
+}
+
+impl Baseline {
+    fn load() -> Self {
+        serde_json::from_str(include_str!("baseline.json")).unwrap()
+    }
+
+    fn allowance(&self, workload: &Workload) -> Duration {
+        let recorded = self.workloads[workload.name];
+        let allowed = (recorded * self.max_slowdown).max(self.min_allowance_ms);
+
+        Duration::from_secs_f64(allowed / 1000.0)
+    }
+}
+
+/// The number of times the guard runs each workload.
+const GUARD_RUNS: u32 = 3;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn generate_corpus() {
+        let paths = corpus(SEED, 1000);
+
+        assert_eq!(paths, corpus(SEED, 1000), "deterministic");
+        assert_ne!(paths, corpus(SEED + 1, 1000), "seeded");
+
+        let parsed = paths
+            .iter()
+            .filter_map(|path| Store::parse(0, 0, path))
+            .collect::<Vec<_>>();
+
+        // Only the real names without a version can't be parsed
+        assert!(parsed.len() > paths.len() * 95 / 100, "{}", parsed.len());
+        assert!(parsed.iter().any(|store| store.suffix.is_some()));
+        assert!(parsed.iter().any(|store| store.name == "wine-wow"));
+    }
+
+    /// Fails when a scaled-down workload takes far longer than it did when the baseline was recorded.
+    ///
+    /// The allowance is deliberately generous, since tests run in parallel on machines of any speed, so this only
+    /// catches gross regressions. The benchmarks are for anything finer.
+    #[test]
+    fn guard_against_regressions() {
+        let baseline = Baseline::load();
+
+        for workload in &WORKLOADS {
+            assert!(
+                baseline.workloads.contains_key(workload.name),
+                "no baseline for {}",
+                workload.name
+            );
+
+            let elapsed = workload.fastest(workload.size / baseline.scale, GUARD_RUNS);
+            let allowance = baseline.allowance(workload);
+
+            assert!(
+                elapsed <= allowance,
+                "{} took {:?}, which is more than the {:?} allowed",
+                workload.name,
+                elapsed,
+                allowance
+            );
+        }
+    }
+
+    /// Prints the time of each scaled-down workload as the `workloads` of `baseline.json`.
+    ///
+    /// Run with `cargo test -- --ignored --nocapture --test-threads 1 record_baseline` after a change that is
+    /// expected to make a workload slower or faster.
+    #[test]
+    #[ignore]
+    fn record_baseline() {
+        let baseline = Baseline::load();
+
+        let workloads = WORKLOADS
+            .iter()
+            .map(|workload| {
+                let elapsed = workload.fastest(workload.size / baseline.scale, GUARD_RUNS * 3);
+                let millis = (elapsed.as_secs_f64() * 1000.0 * 100.0).round() / 100.0;
+                (workload.name, millis)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        println!("{}", serde_json::to_string_pretty(&workloads).unwrap());
+    }
+
+    /// Measures every workload at full size.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture --test-threads 1 bench_workloads`.
+    #[test]
+    #[ignore]
+    fn bench_workloads() {
+        const RUNS: u32 = 20;
+
+        for workload in &WORKLOADS {
+            let times = (0..RUNS)
+                .map(|_| (workload.run)(workload.size))
+                .collect::<Vec<_>>();
+
+            let fastest = times.iter().min().unwrap();
+            let mean = times.iter().sum::<Duration>() / RUNS;
+
+            println!(
+                "{:<18} {:>6} items: fastest {:?}, mean {:?}",
+                workload.name, workload.size, fastest, mean
+            );
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::store::Store;
+use std::sync::Once;
+
+/// The epoch time of the start of 2003, when Nix was first developed. Nothing can have been registered before it.
+pub const EARLIEST: u64 = 1_041_379_200;
+
+/// How far into the future a timestamp can be before it's considered wrong, to allow for small differences
+/// between clocks.
+pub const FUTURE_TOLERANCE: u64 = 10 * 60;
+
+/// Why a timestamp can't be trusted.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Anomaly {
+    /// Later than `now` by more than `FUTURE_TOLERANCE`.
+    Future,
+    /// Earlier than `EARLIEST`, such as a timestamp of zero.
+    TooOld,
+}
+
+/// Returns what is wrong with `time` as of `now`, if anything.
+pub fn check(time: u64, now: u64) -> Option<Anomaly> {
+    if time > now.saturating_add(FUTURE_TOLERANCE) {
+        Some(Anomaly::Future)
+    } else if time < EARLIEST {
+        Some(Anomaly::TooOld)
+    } else {
+        None
+    }
+}
+
+/// Returns the number of seconds between `time` and `now`.
+///
+/// Every age should be computed through this so anomalous timestamps are handled the same way everywhere.
+/// Times in the future have an age of zero, while times before `EARLIEST` are treated as if they were `EARLIEST`.
+pub fn age(time: u64, now: u64) -> u64 {
+    now.saturating_sub(time.max(EARLIEST))
+}
+
+/// The number of timestamps of each kind of anomaly.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Anomalies {
+    pub future: usize,
+    pub too_old: usize,
+}
+
+impl Anomalies {
+    /// Counts the anomalous registration times of `stores` as of `now`.
+    ///
+    /// The registration times themselves are left as is.
+    pub fn in_stores<'a, I>(stores: I, now: u64) -> Self
+    where
+        I: IntoIterator<Item = &'a Store>,
+    {
+        let mut anomalies = Self::default();
+
+        for store in stores {
+            match check(store.register_time.into(), now) {
+                Some(Anomaly::Future) => anomalies.future += 1,
+                Some(Anomaly::TooOld) => anomalies.too_old += 1,
+                None => (),
+            }
+        }
+
+        anomalies
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.future == 0 && self.too_old == 0
+    }
+}
+
+/// Calls `warn` with `anomalies` if there are any, but only the first time it's called with anomalies.
+///
+/// This keeps repeated scans, such as with `--watch`, from repeating the warning.
+pub fn warn_once<F>(anomalies: Anomalies, warn: F)
+where
+    F: FnOnce(Anomalies),
+{
+    static WARNED: Once = Once::new();
+
+    if !anomalies.is_empty() {
+        WARNED.call_once(|| warn(anomalies));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn check_timestamps() {
+        let cases = [
+            (NOW - 3600, None),
+            (NOW, None),
+            (NOW + FUTURE_TOLERANCE, None),
+            (NOW + FUTURE_TOLERANCE + 1, Some(Anomaly::Future)),
+            (NOW + 3 * 86_400, Some(Anomaly::Future)),
+            (EARLIEST, None),
+            (EARLIEST - 1, Some(Anomaly::TooOld)),
+            (0, Some(Anomaly::TooOld)),
+            (u64::MAX, Some(Anomaly::Future)),
+        ];
+
+        for (time, expected) in &cases {
+            assert_eq!(check(*time, NOW), *expected, "{}", time);
+        }
+    }
+
+    #[test]
+    fn clamp_ages() {
+        let cases = [
+            (NOW - 3600, 3600),
+            (NOW, 0),
+            (NOW + 3 * 86_400, 0),
+            (u64::MAX, 0),
+            (0, NOW - EARLIEST),
+            (EARLIEST - 1, NOW - EARLIEST),
+        ];
+
+        for (time, expected) in &cases {
+            assert_eq!(age(*time, NOW), *expected, "{}", time);
+        }
+
+        // A clock that is behind every timestamp never underflows
+        assert_eq!(age(NOW, 0), 0);
+    }
+
+    #[test]
+    fn count_anomalies() {
+        let store = |register_time: u64| Store {
+            register_time: register_time as u32,
+            ..testing::store(&register_time.to_string(), "1.0")
+        };
+
+        let stores = [store(NOW - 60), store(NOW + 86_400), store(0), store(100)];
+
+        assert_eq!(
+            Anomalies::in_stores(&stores, NOW),
+            Anomalies {
+                future: 1,
+                too_old: 2,
+            }
+        );
+
+        assert!(Anomalies::in_stores(&stores[..1], NOW).is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+use crate::critical::CriticalList;
+use crate::display::format::{DateFormat, Locale};
+use crate::display::theme::{Preset, Theme, ThemeSpec};
+use crate::store::dedup::DedupPolicy;
+use crate::store::ecosystem::{RuleSpec, Rules};
+use crate::store::outputs::OutputPolicy;
+use anyhow::{anyhow, Context, Result};
+use serde_derive::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Settings read from `config.toml` in the data directory.
+///
+/// Every setting is optional, and a missing file is the same as an empty one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Append a summary of every diff to the local run log.
+    pub record_runs: bool,
+    /// Include the names of updated packages in the run log.
+    pub record_names: bool,
+    /// Record what was left out of each saved state, so `nixup audit` can show it later.
+    pub record_rejects: bool,
+    /// What to do with packages that had differing versions registered in the same update.
+    pub duplicate_policy: DedupPolicy,
+    /// Whether dates are shown as YYYY-MM-DD or in the style of the system's locale.
+    pub date_format: DateFormat,
+    /// The character to separate the whole and fractional parts of sizes with. Defaults to a period.
+    pub decimal_separator: Option<char>,
+    /// The character to group the digits of large counts with, if any.
+    pub digit_grouping: Option<char>,
+    /// Names of packages whose changes are always shown first, which may use `*` and `?` wildcards.
+    /// Uses `critical::DEFAULT_CRITICAL` when unset, while an empty list disables the section.
+    pub critical: Option<Vec<String>>,
+    /// Extra rules for finding the ecosystem of a store from its name, which are tried before the built-in ones.
+    pub ecosystems: Vec<RuleSpec>,
+    /// The preset the colors and styles of the human formats start from, and the roles it overrides.
+    pub theme: ThemeSpec,
+    /// Which output represents a package when several of its outputs were registered in the same update.
+    pub outputs: OutputPolicy,
+}
+
+impl Config {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = Self::path(data_dir);
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| anyhow!("failed to read config at {}", path.display()))
+            }
+        };
+
+        toml::from_str(&contents)
+            .with_context(|| anyhow!("failed to parse config at {}", path.display()))
+    }
+
+    pub fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join("config.toml")
+    }
+
+    /// Returns the locale described by the config, with ISO dates forced when `iso_dates` is set.
+    pub fn locale(&self, iso_dates: bool) -> Locale {
+        let dates = if iso_dates {
+            DateFormat::Iso
+        } else {
+            self.date_format
+        };
+
+        Locale::new(dates, self.decimal_separator, self.digit_grouping)
+    }
+
+    pub fn critical_list(&self) -> CriticalList {
+        match &self.critical {
+            Some(patterns) => CriticalList::new(patterns.clone()),
+            None => CriticalList::default(),
+        }
+    }
+
+    /// Returns the theme described by the config, with `preset` taking priority over the one it picks.
+    pub fn theme(&self, preset: Option<Preset>) -> Result<Theme> {
+        Theme::new(preset, &self.theme).context("invalid theme in config")
+    }
+
+    pub fn ecosystem_rules(&self) -> Result<Rules> {
+        Rules::with_extra(&self.ecosystems).context("invalid ecosystem rule in config")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::display::format::DateStyle;
+    use crate::display::theme::{Role, Style};
+
+    #[test]
+    fn load_config() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = Config::load(dir.path()).unwrap();
+        assert!(!config.record_runs && !config.record_names, "missing file");
+        assert_eq!(config.critical_list(), CriticalList::default());
+
+        fs::write(Config::path(dir.path()), "record_runs = true\n").unwrap();
+        let config = Config::load(dir.path()).unwrap();
+        assert!(config.record_runs && !config.record_names, "partial file");
+
+        fs::write(
+            Config::path(dir.path()),
+            "date_format = \"locale\"\ndecimal_separator = \",\"\ndigit_grouping = \" \"\n",
+        )
+        .unwrap();
+        let config = Config::load(dir.path()).unwrap();
+        assert_eq!(config.date_format, DateFormat::Locale);
+        assert_eq!(
+            config.locale(true).dates,
+            DateStyle::Iso,
+            "forced iso dates"
+        );
+        assert_eq!(config.locale(true).decimal_separator, ',');
+        assert_eq!(config.locale(true).digit_grouping, Some(' '));
+
+        fs::write(
+            Config::path(dir.path()),
+            "duplicate_policy = \"keep-all-tagged\"\n",
+        )
+        .unwrap();
+        let config = Config::load(dir.path()).unwrap();
+        assert_eq!(config.duplicate_policy, DedupPolicy::KeepAllTagged);
+
+        fs::write(
+            Config::path(dir.path()),
+            "critical = [\"openssl*\", \"gnutls\"]\n",
+        )
+        .unwrap();
+        let critical = Config::load(dir.path()).unwrap().critical_list();
+        assert!(critical.matches("openssl") && critical.matches("gnutls"));
+        assert!(!critical.matches("sudo"), "replaces the defaults");
+
+        fs::write(Config::path(dir.path()), "critical = []\n").unwrap();
+        let critical = Config::load(dir.path()).unwrap().critical_list();
+        assert!(!critical.matches("openssl"), "disabled");
+
+        fs::write(
+            Config::path(dir.path()),
+            "[[ecosystems]]\necosystem = \"python\"\npattern = \"python{eco}-{rest}\"\nrewrite = \"{rest}\"\n",
+        )
+        .unwrap();
+        let rules = Config::load(dir.path()).unwrap().ecosystem_rules().unwrap();
+        let (name, ecosystem) = rules.apply("python3.11-requests-2.31.0").unwrap();
+        assert_eq!(name, "requests-2.31.0");
+        assert_eq!(ecosystem.version, "3.11");
+
+        fs::write(
+            Config::path(dir.path()),
+            "[[ecosystems]]\necosystem = \"python\"\npattern = \"python-{rest}\"\n",
+        )
+        .unwrap();
+        let config = Config::load(dir.path()).unwrap();
+        assert!(config.ecosystem_rules().is_err(), "no ecosystem version");
+
+        fs::write(
+            Config::path(dir.path()),
+            "[theme]\npreset = \"colorblind\"\nold-version = \"bright-red bold\"\n",
+        )
+        .unwrap();
+        let config = Config::load(dir.path()).unwrap();
+        let theme = config.theme(None).unwrap();
+        assert_eq!(
+            theme.style(Role::OldVersion),
+            "bright-red bold".parse().unwrap()
+        );
+        assert_eq!(
+            theme.style(Role::NewVersion),
+            Theme::preset(Preset::Colorblind).style(Role::NewVersion)
+        );
+        assert_eq!(
+            config
+                .theme(Some(Preset::Mono))
+                .unwrap()
+                .style(Role::NewVersion),
+            Style::default(),
+            "preset from the command line"
+        );
+
+        fs::write(
+            Config::path(dir.path()),
+            "[theme]\nold-version = \"crimson\"\n",
+        )
+        .unwrap();
+        let err = format!(
+            "{:#}",
+            Config::load(dir.path()).unwrap().theme(None).unwrap_err()
+        );
+        assert!(err.contains("invalid theme in config"), "{}", err);
+        assert!(err.contains("\"crimson\""), "{}", err);
+
+        fs::write(
+            Config::path(dir.path()),
+            "[outputs]\norder = [\"bin\", \"\"]\n\n[outputs.packages]\nmesa = [\"drivers\"]\n",
+        )
+        .unwrap();
+        let outputs = Config::load(dir.path()).unwrap().outputs;
+        assert_eq!(outputs.order_for("glib"), ["bin", ""]);
+        assert_eq!(outputs.order_for("mesa"), ["drivers"]);
+
+        fs::write(Config::path(dir.path()), "date_format = \"us\"\n").unwrap();
+        assert!(Config::load(dir.path()).is_err(), "unknown date format");
+
+        fs::write(Config::path(dir.path()), "record_run = true\n").unwrap();
+        assert!(Config::load(dir.path()).is_err(), "unknown setting");
+    }
+}
@@ -0,0 +1,253 @@
+use crate::store::diff::PackageDiff;
+use std::collections::{BTreeMap, HashSet};
+
+/// The packages that are treated as critical when the config doesn't list any.
+pub const DEFAULT_CRITICAL: [&str; 6] = ["openssl", "openssh", "sudo", "glibc", "linux", "xz"];
+
+/// Patterns matching the names of packages whose changes should never be missed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CriticalList {
+    patterns: Vec<String>,
+}
+
+impl CriticalList {
+    pub fn new(patterns: Vec<String>) -> Self {
+        Self { patterns }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| glob_match(pattern, name))
+    }
+}
+
+impl Default for CriticalList {
+    fn default() -> Self {
+        Self::new(DEFAULT_CRITICAL.iter().map(|&name| name.into()).collect())
+    }
+}
+
+/// Returns true if `pattern` matches all of `text`, where `*` matches any number of characters and `?`
+/// matches exactly one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*` in the pattern, and of the text it was tried against
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&ch) if ch == '?' || ch == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last `*` swallow one more character and try again
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&ch| ch == '*')
+}
+
+/// A single version change of a critical package, gathered from every diff it appears in.
+#[derive(Debug, PartialEq)]
+pub struct CriticalChange {
+    pub name: String,
+    pub ver_from: String,
+    pub ver_to: String,
+    /// Whether the package itself changed, rather than only appearing as a dependency.
+    pub as_package: bool,
+    /// The packages this is a changed dependency of, sorted by name.
+    pub dependents: Vec<String>,
+    /// Whether the diff options left this change out of the regular diff.
+    pub hidden: bool,
+}
+
+/// Gathers every change to a package matching `list` from `all`, which should be diffed without any filters.
+///
+/// Changes are grouped by name and version, so a dependency shared by many packages is only listed once.
+/// A change is marked as hidden if it doesn't appear anywhere in `visible`, which are the diffs that are
+/// actually reported.
+pub fn changes(
+    all: &[PackageDiff],
+    visible: &[PackageDiff],
+    list: &CriticalList,
+) -> Vec<CriticalChange> {
+    let mut changes = BTreeMap::<(&str, &str, &str), CriticalChange>::new();
+
+    for diff in all {
+        let pkg = diff
+            .pkg
+            .iter()
+            .filter(|pkg| list.matches(&pkg.name))
+            .map(|pkg| (pkg, None));
+
+        let deps = diff
+            .deps
+            .iter()
+            .filter(|dep| list.matches(&dep.name))
+            .map(|dep| (dep, Some(&diff.name)));
+
+        for (store, dependent) in pkg.chain(deps) {
+            let key = (
+                store.name.as_str(),
+                store.ver_from.as_str(),
+                store.ver_to.as_str(),
+            );
+
+            let change = changes.entry(key).or_insert_with(|| CriticalChange {
+                name: store.name.clone(),
+                ver_from: store.ver_from.clone(),
+                ver_to: store.ver_to.clone(),
+                as_package: false,
+                dependents: Vec::new(),
+                hidden: true,
+            });
+
+            match dependent {
+                Some(dependent) => change.dependents.push(dependent.clone()),
+                None => change.as_package = true,
+            }
+        }
+    }
+
+    let shown = visible
+        .iter()
+        .flat_map(|diff| diff.pkg.iter().chain(&diff.deps))
+        .map(|store| {
+            (
+                store.name.as_str(),
+                store.ver_from.as_str(),
+                store.ver_to.as_str(),
+            )
+        })
+        .collect::<HashSet<_>>();
+
+    changes
+        .into_iter()
+        .map(|(key, mut change)| {
+            change.hidden = !shown.contains(&key);
+            change.dependents.sort_unstable();
+            change.dependents.dedup();
+            change
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::diff::StoreDiff;
+
+    fn store_diff(name: &str, from: &str, to: &str) -> StoreDiff {
+        StoreDiff {
+            name: name.into(),
+            suffix: None,
+            suffix_from: None,
+            ver_from: from.into(),
+            ver_to: to.into(),
+            register_time: 0,
+        }
+    }
+
+    fn diff(name: &str, pkg: Option<(&str, &str)>, deps: &[(&str, &str, &str)]) -> PackageDiff {
+        PackageDiff {
+            name: name.into(),
+            pkg: pkg.map(|(from, to)| store_diff(name, from, to)),
+            deps: deps
+                .iter()
+                .map(|&(name, from, to)| store_diff(name, from, to))
+                .collect(),
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: None,
+        }
+    }
+
+    #[test]
+    fn match_globs() {
+        let cases = [
+            ("openssl", "openssl", true),
+            ("openssl", "openssl-dev", false),
+            ("openssl*", "openssl", true),
+            ("openssl*", "openssl_3", true),
+            ("linux", "linux-firmware", false),
+            ("linux*", "linux-firmware", true),
+            ("*ssh*", "openssh", true),
+            ("*ssh*", "libssh2", true),
+            ("python3?", "python31", true),
+            ("python3?", "python3", false),
+            ("*", "", true),
+            ("", "", true),
+            ("", "xz", false),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYbZ", false),
+        ];
+
+        for &(pattern, name, expected) in &cases {
+            assert_eq!(glob_match(pattern, name), expected, "{} {}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn gather_critical_changes() {
+        let list = CriticalList::new(vec!["openssl".into(), "glibc".into(), "linux*".into()]);
+
+        let all = vec![
+            diff(
+                "curl",
+                Some(("8.5.0", "8.6.0")),
+                &[("openssl", "3.0.12", "3.0.13"), ("glibc", "2.38", "2.39")],
+            ),
+            diff("openssl", Some(("3.0.12", "3.0.13")), &[]),
+            diff("git", None, &[("glibc", "2.38", "2.39")]),
+            diff("linux-firmware", Some(("20240115", "20240220")), &[]),
+        ];
+
+        // Only the packages themselves are visible, which hides the dependency-only glibc change
+        let visible = vec![
+            diff("curl", Some(("8.5.0", "8.6.0")), &[]),
+            diff("openssl", Some(("3.0.12", "3.0.13")), &[]),
+            diff("linux-firmware", Some(("20240115", "20240220")), &[]),
+        ];
+
+        let found = changes(&all, &visible, &list);
+
+        let summary = found
+            .iter()
+            .map(|change| {
+                (
+                    change.name.as_str(),
+                    change.as_package,
+                    change.dependents.clone(),
+                    change.hidden,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            summary,
+            [
+                ("glibc", false, vec!["curl".into(), "git".into()], true),
+                ("linux-firmware", true, vec![], false),
+                ("openssl", true, vec!["curl".to_string()], false),
+            ]
+        );
+
+        assert!(changes(&all, &all, &CriticalList::new(Vec::new())).is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::store::diff::{PackageDiff, StoreDiff};
+use anyhow::Result;
+use std::borrow::Cow;
+use std::io::Write;
+
+/// The columns of every CSV diff export, in order.
+///
+/// Other tools rely on these, so columns should only ever be appended.
+pub const DIFF_COLUMNS: [&str; 9] = [
+    "package",
+    "change_kind",
+    "old_version",
+    "new_version",
+    "suffix",
+    "is_dependency",
+    "parent_package",
+    "register_time_new",
+    "nar_size_delta",
+];
+
+/// A minimal RFC 4180 CSV writer.
+pub struct CsvWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    /// Writes a single record, quoting any fields that need it.
+    pub fn write_record<I, S>(&mut self, fields: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for (i, field) in fields.into_iter().enumerate() {
+            if i > 0 {
+                self.out.write_all(b",")?;
+            }
+
+            self.out.write_all(escape(field.as_ref()).as_bytes())?;
+        }
+
+        self.out.write_all(b"\r\n")?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush().map_err(Into::into)
+    }
+}
+
+/// Quotes `field` if it contains a delimiter, quote, or line break, doubling any quotes inside of it.
+pub fn escape(field: &str) -> Cow<'_, str> {
+    if !field.contains(&[',', '"', '\r', '\n'][..]) {
+        return Cow::Borrowed(field);
+    }
+
+    Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+}
+
+/// Writes `diffs` with a header row of `DIFF_COLUMNS`, which is written even if there are no diffs.
+///
+/// Each package gets a row for its own version change, followed by a row for each of its changed dependencies.
+/// The NAR size delta is left blank since sizes aren't recorded in saved states.
+pub fn write_package_diffs<W: Write>(out: W, diffs: &[PackageDiff]) -> Result<()> {
+    let mut writer = CsvWriter::new(out);
+    writer.write_record(DIFF_COLUMNS.iter())?;
+
+    for diff in diffs {
+        if let Some(pkg) = &diff.pkg {
+            writer.write_record(store_diff_record(pkg, None))?;
+        }
+
+        let mut deps = diff.deps.iter().collect::<Vec<_>>();
+        deps.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+
+        for dep in deps {
+            writer.write_record(store_diff_record(dep, Some(&diff.name)))?;
+        }
+    }
+
+    writer.flush()
+}
+
+fn store_diff_record(diff: &StoreDiff, parent: Option<&str>) -> [String; 9] {
+    [
+        diff.name.clone(),
+        if diff.suffix_changed() {
+            "suffix_changed"
+        } else {
+            "updated"
+        }
+        .into(),
+        diff.ver_from.clone(),
+        diff.ver_to.clone(),
+        diff.suffix.clone().unwrap_or_default(),
+        parent.is_some().to_string(),
+        parent.unwrap_or_default().into(),
+        diff.register_time.to_string(),
+        String::new(),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escape_fields() {
+        let fields = [
+            ("firefox", "firefox"),
+            ("", ""),
+            ("a,b", "\"a,b\""),
+            ("say \"hi\"", "\"say \"\"hi\"\"\""),
+            ("\"", "\"\"\"\""),
+            ("multi\nline", "\"multi\nline\""),
+            ("carriage\rreturn", "\"carriage\rreturn\""),
+            ("  spaces  ", "  spaces  "),
+        ];
+
+        for &(field, expected) in &fields {
+            assert_eq!(escape(field), expected, "field {:?}", field);
+        }
+    }
+
+    #[test]
+    fn write_records() {
+        let mut out = Vec::new();
+        let mut writer = CsvWriter::new(&mut out);
+
+        writer.write_record(vec!["a", "b,c", ""]).unwrap();
+        writer.write_record(vec![""]).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "a,\"b,c\",\r\n\r\n");
+    }
+
+    #[test]
+    fn write_fixture_diffs() {
+        let store_diff = |name: &str, from: &str, to: &str, suffix: Option<&str>| StoreDiff {
+            name: name.into(),
+            suffix: suffix.map(Into::into),
+            suffix_from: suffix.map(Into::into),
+            ver_from: from.into(),
+            ver_to: to.into(),
+            register_time: 1_709_337_600,
+        };
+
+        let diffs = vec![
+            PackageDiff {
+                name: "firefox".into(),
+                pkg: Some(store_diff("firefox", "122.0", "123.0", None)),
+                deps: vec![
+                    store_diff("nss", "3.97", "3.98", None),
+                    store_diff("gtk+3", "3.24.40", "3.24.41", Some("dev")),
+                ],
+                wrapper: None,
+                split_outputs: Vec::new(),
+                referrer_count: None,
+            },
+            PackageDiff {
+                name: "odd,\"name\"".into(),
+                pkg: None,
+                deps: vec![store_diff("glibc", "2.38-27", "2.38-44", Some("bin"))],
+                wrapper: None,
+                split_outputs: Vec::new(),
+                referrer_count: None,
+            },
+        ];
+
+        let mut out = Vec::new();
+        write_package_diffs(&mut out, &diffs).unwrap();
+
+        let expected = concat!(
+            "package,change_kind,old_version,new_version,suffix,is_dependency,parent_package,register_time_new,nar_size_delta\r\n",
+            "firefox,updated,122.0,123.0,,false,,1709337600,\r\n",
+            "gtk+3,updated,3.24.40,3.24.41,dev,true,firefox,1709337600,\r\n",
+            "nss,updated,3.97,3.98,,true,firefox,1709337600,\r\n",
+            "glibc,updated,2.38-27,2.38-44,bin,true,\"odd,\"\"name\"\"\",1709337600,\r\n",
+        );
+
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn header_without_diffs() {
+        let mut out = Vec::new();
+        write_package_diffs(&mut out, &[]).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("{}\r\n", DIFF_COLUMNS.join(","))
+        );
+    }
+}
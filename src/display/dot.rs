@@ -0,0 +1,235 @@
+use crate::store::diff::{PackageDiff, StoreDiff};
+use crate::store::Derivation;
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::Write;
+
+/// What a node of the graph is, which decides how it's colored.
+///
+/// Later kinds take precedence when a store is reached in several ways, such as a changed dependency
+/// that is also a changed package.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum NodeKind {
+    /// An unchanged store that a changed dependency was discovered through.
+    Path,
+    Dependency,
+    /// A package whose own version didn't change, but whose dependencies did.
+    DepsChanged,
+    Upgraded,
+    Downgraded,
+}
+
+impl NodeKind {
+    fn attributes(self) -> &'static str {
+        match self {
+            Self::Path => "shape=ellipse, color=gray50, fontcolor=gray30",
+            Self::Dependency => "shape=ellipse, style=filled, fillcolor=lightblue",
+            Self::DepsChanged => "shape=box, style=filled, fillcolor=lightgoldenrod1",
+            Self::Upgraded => "shape=box, style=filled, fillcolor=palegreen",
+            Self::Downgraded => "shape=box, style=filled, fillcolor=lightcoral",
+        }
+    }
+}
+
+struct Node {
+    kind: NodeKind,
+    /// The version change of the store, if it changed.
+    change: Option<String>,
+}
+
+/// The graph of every changed package, its changed dependencies, and the stores they were discovered through.
+#[derive(Default)]
+struct Graph {
+    nodes: BTreeMap<String, Node>,
+    edges: BTreeSet<(String, String)>,
+}
+
+impl Graph {
+    fn new(diffs: &[PackageDiff], cur_state: &HashSet<Derivation>) -> Self {
+        let mut graph = Self::default();
+
+        for diff in diffs {
+            let kind = match &diff.pkg {
+                Some(pkg) if pkg.is_downgrade() => NodeKind::Downgraded,
+                Some(_) => NodeKind::Upgraded,
+                None => NodeKind::DepsChanged,
+            };
+
+            graph.add_node(&diff.name, kind, diff.pkg.as_ref());
+
+            // The dependencies of a merged wrapper pair were found through the wrapper
+            let store_name = diff
+                .wrapper
+                .as_ref()
+                .map_or(&diff.name, |pair| &pair.wrapper);
+
+            let paths = cur_state.get(store_name.as_str()).map(|pkg| &pkg.paths);
+
+            for dep in &diff.deps {
+                graph.add_node(&dep.name, NodeKind::Dependency, Some(dep));
+
+                let through = paths
+                    .and_then(|paths| paths.get(&dep.name))
+                    .map_or(&[][..], Vec::as_slice);
+
+                let mut from = &diff.name;
+
+                for name in through {
+                    graph.add_node(name, NodeKind::Path, None);
+                    graph.edges.insert((from.clone(), name.clone()));
+                    from = name;
+                }
+
+                graph.edges.insert((from.clone(), dep.name.clone()));
+            }
+        }
+
+        graph
+    }
+
+    fn add_node(&mut self, name: &str, kind: NodeKind, diff: Option<&StoreDiff>) {
+        let change = diff.map(|diff| format!("{} -> {}", diff.ver_from, diff.ver_to));
+
+        match self.nodes.get_mut(name) {
+            Some(node) if node.kind >= kind => (),
+            Some(node) => {
+                node.kind = kind;
+                node.change = change.or(node.change.take());
+            }
+            None => {
+                self.nodes.insert(name.into(), Node { kind, change });
+            }
+        }
+    }
+}
+
+/// Writes `diffs` to `out` as a Graphviz graph, where an edge goes from each store to the stores it references.
+///
+/// Only changed packages, their changed dependencies, and the unchanged stores between them are included, as the
+/// graph of the entire system would be unreadable. The stores between a package and its dependencies are only known
+/// when dependencies were resolved deeper than the direct references, and are taken from `cur_state`.
+pub fn write_graph<W: Write>(
+    mut out: W,
+    diffs: &[PackageDiff],
+    cur_state: &HashSet<Derivation>,
+) -> Result<()> {
+    let graph = Graph::new(diffs, cur_state);
+
+    writeln!(out, "digraph nixup {{")?;
+    writeln!(out, "    rankdir=LR;")?;
+    writeln!(out, "    node [fontname=\"monospace\"];")?;
+
+    for (name, node) in &graph.nodes {
+        let label = match &node.change {
+            Some(change) => format!("{}\\n{}", escape(name), escape(change)),
+            None => escape(name),
+        };
+
+        writeln!(
+            out,
+            "    \"{}\" [label=\"{}\", {}];",
+            escape(name),
+            label,
+            node.kind.attributes()
+        )?;
+    }
+
+    for (from, to) in &graph.edges {
+        writeln!(out, "    \"{}\" -> \"{}\";", escape(from), escape(to))?;
+    }
+
+    writeln!(out, "}}")?;
+    out.flush().map_err(Into::into)
+}
+
+/// Escapes `text` so it can be put between double quotes in the DOT language.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::Store;
+    use std::collections::HashMap;
+
+    fn store_diff(name: &str, ver_from: &str, ver_to: &str) -> StoreDiff {
+        StoreDiff {
+            name: name.into(),
+            suffix: None,
+            suffix_from: None,
+            ver_from: ver_from.into(),
+            ver_to: ver_to.into(),
+            register_time: 0,
+        }
+    }
+
+    fn pkg_diff(name: &str, pkg: Option<StoreDiff>, deps: Vec<StoreDiff>) -> PackageDiff {
+        PackageDiff {
+            name: name.into(),
+            pkg,
+            deps,
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: None,
+        }
+    }
+
+    #[test]
+    fn write_changed_subgraph() {
+        let diffs = vec![
+            pkg_diff(
+                "firefox",
+                Some(store_diff("firefox", "122.0", "123.0")),
+                vec![
+                    store_diff("nss", "3.97", "3.98"),
+                    store_diff("glibc", "2.38", "2.39"),
+                ],
+            ),
+            pkg_diff(
+                "mpv",
+                Some(store_diff("mpv", "0.37", "0.36")),
+                vec![store_diff("glibc", "2.38", "2.39")],
+            ),
+            pkg_diff("my\"app", None, vec![store_diff("nss", "3.97", "3.98")]),
+        ];
+
+        // glibc was found through gtk+3 from firefox, but is a direct reference of mpv
+        let mut firefox = Derivation {
+            store: Store::parse_stripped("firefox-123.0").unwrap(),
+            deps: HashSet::new(),
+            paths: HashMap::new(),
+        };
+
+        firefox
+            .paths
+            .insert("glibc".into(), vec!["gtk+3".into(), "pango".into()]);
+
+        let cur_state = vec![firefox].into_iter().collect::<HashSet<_>>();
+
+        let mut out = Vec::new();
+        write_graph(&mut out, &diffs, &cur_state).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"digraph nixup {
+    rankdir=LR;
+    node [fontname="monospace"];
+    "firefox" [label="firefox\n122.0 -> 123.0", shape=box, style=filled, fillcolor=palegreen];
+    "glibc" [label="glibc\n2.38 -> 2.39", shape=ellipse, style=filled, fillcolor=lightblue];
+    "gtk+3" [label="gtk+3", shape=ellipse, color=gray50, fontcolor=gray30];
+    "mpv" [label="mpv\n0.37 -> 0.36", shape=box, style=filled, fillcolor=lightcoral];
+    "my\"app" [label="my\"app", shape=box, style=filled, fillcolor=lightgoldenrod1];
+    "nss" [label="nss\n3.97 -> 3.98", shape=ellipse, style=filled, fillcolor=lightblue];
+    "pango" [label="pango", shape=ellipse, color=gray50, fontcolor=gray30];
+    "firefox" -> "gtk+3";
+    "firefox" -> "nss";
+    "gtk+3" -> "pango";
+    "mpv" -> "glibc";
+    "my\"app" -> "nss";
+    "pango" -> "glibc";
+}
+"#
+        );
+    }
+}
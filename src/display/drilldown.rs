@@ -0,0 +1,185 @@
+use super::format;
+use super::theme::{Paint, Role};
+use crate::store::drilldown::Drilldown;
+use crate::store::Store;
+
+/// Prints everything that changed about the single package in `drilldown`, with versions cut off after `short`
+/// characters if set.
+pub fn display(drilldown: &Drilldown, short: Option<usize>) {
+    for line in format_drilldown(drilldown, short) {
+        println!("{}", line);
+    }
+}
+
+fn format_drilldown(drilldown: &Drilldown, short: Option<usize>) -> Vec<String> {
+    let mut lines = vec![format_header(drilldown, short)];
+
+    if let Some(old_name) = drilldown.renamed_from() {
+        lines.push(format!(
+            "renamed from {}",
+            old_name.paint(Role::PackageName)
+        ));
+    }
+
+    if !drilldown.deps.is_empty() {
+        lines.push(String::new());
+        lines.push("changed dependencies:".paint(Role::Heading));
+
+        for dep in &drilldown.deps {
+            lines.push(format!(
+                "{} {}",
+                "^".paint(Role::DepMarker),
+                super::format_store_diff(dep, short)
+            ));
+        }
+    }
+
+    let sections = [
+        ("added", "+", Role::Added, &drilldown.added_deps),
+        ("removed", "-", Role::Removed, &drilldown.removed_deps),
+    ];
+
+    for (what, marker, role, deps) in &sections {
+        if deps.is_empty() {
+            continue;
+        }
+
+        lines.push(String::new());
+        lines.push(format!("{} dependencies:", what).paint(Role::Heading));
+
+        for dep in deps.iter() {
+            lines.push(format!(
+                "{} {}",
+                marker.paint(*role),
+                format_store(dep, short)
+            ));
+        }
+    }
+
+    if drilldown.unchanged_deps > 0 {
+        lines.push(String::new());
+        lines.push(
+            format!(
+                "{} unchanged",
+                format::locale().plural(drilldown.unchanged_deps, "dependency", "dependencies")
+            )
+            .paint(Role::Note),
+        );
+    }
+
+    lines
+}
+
+/// Formats the package's own version change, or what happened to it when there's nothing to compare it with.
+fn format_header(drilldown: &Drilldown, short: Option<usize>) -> String {
+    if let Some(pkg) = &drilldown.pkg {
+        return super::format_store_diff(pkg, short);
+    }
+
+    let (store, status) = match (&drilldown.new, &drilldown.old) {
+        (Some(new), Some(_)) if drilldown.is_empty() => (new, "unchanged"),
+        (Some(new), Some(_)) => (new, "same version as in the saved state"),
+        (Some(new), None) => (new, "added since the saved state"),
+        (None, Some(old)) => (old, "removed since the saved state"),
+        (None, None) => return drilldown.name().paint(Role::PackageName),
+    };
+
+    format!(
+        "{} ({})",
+        format_store(store, short),
+        status.paint(Role::Note)
+    )
+}
+
+/// Formats `store` as `name {suffix} version`.
+fn format_store(store: &Store, short: Option<usize>) -> String {
+    let suffix = match &store.suffix {
+        Some(suffix) => format!(" {{{}}}", suffix).paint(Role::SuffixTag),
+        None => String::new(),
+    };
+
+    format!(
+        "{}{} {}",
+        store.name.paint(Role::PackageName),
+        suffix,
+        super::shorten_version(&store.version, short).paint(Role::Value)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::Derivation;
+    use std::collections::HashMap;
+
+    fn package(name: &str, deps: &[&str]) -> Derivation {
+        Derivation {
+            store: Store::parse_stripped(name).unwrap(),
+            deps: deps
+                .iter()
+                .map(|dep| Store::parse_stripped(dep).unwrap())
+                .collect(),
+            paths: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn format_drilldowns() {
+        colored::control::set_override(false);
+
+        let old = package(
+            "firefox-123.0",
+            &["nss-3.97", "glibc-2.39", "libvpx-1.13.1", "zlib-1.3"],
+        );
+        let new = package(
+            "firefox-124.0",
+            &["nss-3.98", "glibc-2.39", "dav1d-1.4.0", "zlib-1.3.1"],
+        );
+
+        let drilldown = Drilldown::new(Some(&new), Some(&old), false);
+
+        assert_eq!(
+            format_drilldown(&drilldown, None),
+            [
+                "firefox: 123.0 -> 124.0",
+                "",
+                "changed dependencies:",
+                "^ nss: 3.97 -> 3.98",
+                "^ zlib: 1.3 -> 1.3.1",
+                "",
+                "added dependencies:",
+                "+ dav1d 1.4.0",
+                "",
+                "removed dependencies:",
+                "- libvpx 1.13.1",
+                "",
+                "1 dependency unchanged",
+            ]
+        );
+
+        let drilldown = Drilldown::new(None, Some(&package("firefox-123.0", &[])), false);
+
+        assert_eq!(
+            format_drilldown(&drilldown, None),
+            ["firefox 123.0 (removed since the saved state)"]
+        );
+
+        let drilldown = Drilldown::new(Some(&old), Some(&old), false);
+
+        assert_eq!(
+            format_drilldown(&drilldown, None),
+            ["firefox 123.0 (unchanged)", "", "4 dependencies unchanged"]
+        );
+
+        let renamed = package("firefox-bin-123.0", &["nss-3.97"]);
+        let drilldown = Drilldown::new(Some(&renamed), Some(&old), false);
+
+        assert_eq!(
+            format_drilldown(&drilldown, None)[..2],
+            [
+                "firefox-bin 123.0 (same version as in the saved state)",
+                "renamed from firefox"
+            ]
+        );
+    }
+}
@@ -0,0 +1,394 @@
+use serde_derive::Deserialize;
+use std::env;
+use std::sync::OnceLock;
+
+/// The locale used by every human-facing format, set once at startup.
+static LOCALE: OnceLock<Locale> = OnceLock::new();
+
+/// Sets the locale returned by `locale`.
+///
+/// Only the first call has any effect, so this should be called before anything is displayed.
+pub fn init(locale: Locale) {
+    let _ = LOCALE.set(locale);
+}
+
+/// Returns the locale set with `init`, or the default one if it was never set.
+pub fn locale() -> &'static Locale {
+    LOCALE.get_or_init(Locale::default)
+}
+
+/// How dates are shown, as set in the config file.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DateFormat {
+    /// Always in the form of YYYY-MM-DD.
+    #[default]
+    Iso,
+    /// In the order and with the separator commonly used by the system's locale.
+    Locale,
+}
+
+/// The order of the parts of a numeric date.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DateOrder {
+    DayMonthYear,
+    MonthDayYear,
+    YearMonthDay,
+}
+
+/// How dates are written once the locale has been resolved.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DateStyle {
+    Iso,
+    Numeric { order: DateOrder, separator: char },
+}
+
+impl DateStyle {
+    /// Returns the date style commonly used with `locale`, such as `en_US.UTF-8` or `de_DE`.
+    ///
+    /// This only covers enough locales to get the order and separator of dates right for most users,
+    /// and anything unrecognized falls back to ISO dates.
+    pub fn for_locale(locale: &str) -> Self {
+        let name = locale.split(&['.', '@'][..]).next().unwrap_or(locale);
+        let mut parts = name.splitn(2, '_');
+        let language = parts.next().unwrap_or_default();
+        let territory = parts.next().unwrap_or_default();
+
+        let (order, separator) = match (language, territory) {
+            ("C", _) | ("POSIX", _) | ("", _) => return Self::Iso,
+            (_, "US") | (_, "PH") => (DateOrder::MonthDayYear, '/'),
+            ("zh", _) | ("ja", _) => (DateOrder::YearMonthDay, '/'),
+            ("ko", _) | ("hu", _) => (DateOrder::YearMonthDay, '.'),
+            ("de", _)
+            | ("ru", _)
+            | ("pl", _)
+            | ("cs", _)
+            | ("fi", _)
+            | ("nb", _)
+            | ("tr", _)
+            | ("uk", _) => (DateOrder::DayMonthYear, '.'),
+            ("nl", _) | ("da", _) => (DateOrder::DayMonthYear, '-'),
+            _ => (DateOrder::DayMonthYear, '/'),
+        };
+
+        Self::Numeric { order, separator }
+    }
+}
+
+/// Settings for every human-facing format, such as dates, sizes, and counts.
+///
+/// Machine-readable output such as JSON and CSV never goes through these.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Locale {
+    pub dates: DateStyle,
+    /// The character separating the whole and fractional parts of a number.
+    pub decimal_separator: char,
+    /// The character placed between every group of three digits in counts, if any.
+    pub digit_grouping: Option<char>,
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self {
+            dates: DateStyle::Iso,
+            decimal_separator: '.',
+            digit_grouping: None,
+        }
+    }
+}
+
+impl Locale {
+    /// Creates a locale from the settings in the config file.
+    ///
+    /// The date format of the system's locale is only looked up when `dates` asks for it.
+    pub fn new(
+        dates: DateFormat,
+        decimal_separator: Option<char>,
+        digit_grouping: Option<char>,
+    ) -> Self {
+        let dates = match dates {
+            DateFormat::Iso => DateStyle::Iso,
+            DateFormat::Locale => system_locale("LC_TIME")
+                .map_or(DateStyle::Iso, |locale| DateStyle::for_locale(&locale)),
+        };
+
+        Self {
+            dates,
+            decimal_separator: decimal_separator.unwrap_or('.'),
+            digit_grouping,
+        }
+    }
+
+    /// Formats the epoch time `secs` as a UTC date.
+    pub fn date(&self, secs: u64) -> String {
+        let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+
+        match self.dates {
+            DateStyle::Iso => format!("{:04}-{:02}-{:02}", year, month, day),
+            DateStyle::Numeric { order, separator } => {
+                let (first, second, third) = match order {
+                    DateOrder::DayMonthYear => (day, month, year),
+                    DateOrder::MonthDayYear => (month, day, year),
+                    DateOrder::YearMonthDay => (year, month, day),
+                };
+
+                format!(
+                    "{:02}{sep}{:02}{sep}{:02}",
+                    first,
+                    second,
+                    third,
+                    sep = separator
+                )
+            }
+        }
+    }
+
+    /// Formats the epoch time `secs` as a UTC date and time, with the time in the form of HH:MM.
+    pub fn datetime(&self, secs: u64) -> String {
+        let secs_of_day = secs % 86_400;
+
+        format!(
+            "{} {:02}:{:02}",
+            self.date(secs),
+            secs_of_day / 3600,
+            (secs_of_day % 3600) / 60
+        )
+    }
+
+    /// Formats `num` with its digits grouped in threes, if grouping is enabled.
+    pub fn count(&self, num: usize) -> String {
+        let digits = num.to_string();
+
+        let separator = match self.digit_grouping {
+            Some(separator) => separator,
+            None => return digits,
+        };
+
+        let mut result = String::with_capacity(digits.len() + digits.len() / 3);
+
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                result.push(separator);
+            }
+
+            result.push(digit);
+        }
+
+        result
+    }
+
+    /// Formats `num` followed by `singular` when it is 1, or `plural` otherwise.
+    pub fn plural(&self, num: usize, singular: &str, plural: &str) -> String {
+        format!("{} {}", self.count(num), noun(num, singular, plural))
+    }
+
+    /// Formats a number of bytes using binary units, such as `12.3 MiB`.
+    ///
+    /// Sizes are rounded to a single decimal, and move to the next unit when rounding would reach 1024.
+    pub fn size(&self, bytes: u64) -> String {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+        if bytes < 1024 {
+            return format!("{} B", self.count(bytes as usize));
+        }
+
+        let mut size = bytes as f64 / 1024.0;
+        let mut unit = 0;
+
+        while (size * 10.0).round() / 10.0 >= 1024.0 && unit + 1 < UNITS.len() {
+            size /= 1024.0;
+            unit += 1;
+        }
+
+        let size = format!("{:.1}", size).replace('.', &self.decimal_separator.to_string());
+        format!("{} {}", size, UNITS[unit])
+    }
+
+    /// Formats a number of seconds as a short, approximate age, such as `3d` or `5h`.
+    pub fn age(&self, secs: u64) -> String {
+        match secs {
+            0..=3599 => format!("{}m", secs / 60),
+            3600..=86_399 => format!("{}h", secs / 3600),
+            _ => format!("{}d", self.count((secs / 86_400) as usize)),
+        }
+    }
+}
+
+/// Returns `singular` when `num` is 1, or `plural` otherwise.
+///
+/// This is for when the count is formatted separately, such as to color it.
+pub fn noun<'a>(num: usize, singular: &'a str, plural: &'a str) -> &'a str {
+    if num == 1 {
+        singular
+    } else {
+        plural
+    }
+}
+
+/// Formats the epoch time `secs` as a UTC date in the form of YYYY-MM-DD, regardless of the locale.
+///
+/// This is meant for keys and file names, which shouldn't change with the locale.
+pub fn iso_date(secs: u64) -> String {
+    Locale::default().date(secs)
+}
+
+/// Returns the system's locale for the given `category`, such as `LC_TIME`.
+///
+/// Like with setlocale, `LC_ALL` takes priority over the category, which takes priority over `LANG`.
+pub fn system_locale(category: &str) -> Option<String> {
+    ["LC_ALL", category, "LANG"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// Converts the number of days since the Unix epoch to a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // Adapted from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn grouped(separator: char) -> Locale {
+        Locale {
+            digit_grouping: Some(separator),
+            ..Locale::default()
+        }
+    }
+
+    #[test]
+    fn format_dates() {
+        let us = Locale {
+            dates: DateStyle::for_locale("en_US.UTF-8"),
+            ..Locale::default()
+        };
+
+        let de = Locale {
+            dates: DateStyle::for_locale("de_DE.UTF-8@euro"),
+            ..Locale::default()
+        };
+
+        let ja = Locale {
+            dates: DateStyle::for_locale("ja_JP"),
+            ..Locale::default()
+        };
+
+        let iso = Locale::default();
+
+        let cases = [
+            (&iso, 0, "1970-01-01"),
+            (&iso, 1_709_337_600, "2024-03-02"),
+            (&iso, 951_782_400, "2000-02-29"),
+            (&us, 1_709_337_600, "03/02/2024"),
+            (&de, 1_709_337_600, "02.03.2024"),
+            (&ja, 1_709_337_600, "2024/03/02"),
+        ];
+
+        for (locale, secs, expected) in &cases {
+            assert_eq!(locale.date(*secs), *expected, "{:?}", locale.dates);
+        }
+
+        assert_eq!(iso.datetime(1_709_387_130), "2024-03-02 13:45");
+        assert_eq!(de.datetime(1_709_387_130), "02.03.2024 13:45");
+        assert_eq!(DateStyle::for_locale("C"), DateStyle::Iso);
+        assert_eq!(DateStyle::for_locale("POSIX"), DateStyle::Iso);
+    }
+
+    #[test]
+    fn format_counts() {
+        let cases = [
+            (Locale::default(), 1_234_567, "1234567"),
+            (grouped(','), 0, "0"),
+            (grouped(','), 999, "999"),
+            (grouped(','), 1000, "1,000"),
+            (grouped(','), 123_456, "123,456"),
+            (grouped(','), 1_234_567, "1,234,567"),
+            (grouped(' '), 12_345, "12 345"),
+        ];
+
+        for (locale, num, expected) in &cases {
+            assert_eq!(locale.count(*num), *expected);
+        }
+    }
+
+    #[test]
+    fn format_plurals() {
+        let locale = grouped(',');
+
+        let cases = [
+            (0, "0 packages"),
+            (1, "1 package"),
+            (2, "2 packages"),
+            (1000, "1,000 packages"),
+        ];
+
+        for (num, expected) in &cases {
+            assert_eq!(locale.plural(*num, "package", "packages"), *expected);
+        }
+    }
+
+    #[test]
+    fn format_sizes() {
+        let comma = Locale {
+            decimal_separator: ',',
+            ..Locale::default()
+        };
+
+        let cases = [
+            (Locale::default(), 0, "0 B"),
+            (Locale::default(), 1023, "1023 B"),
+            (Locale::default(), 1024, "1.0 KiB"),
+            (Locale::default(), 1075, "1.0 KiB"),
+            (Locale::default(), 1076, "1.1 KiB"),
+            // Rounding up to 1024 of a unit should use the next one instead
+            (Locale::default(), 1024 * 1024 - 1, "1.0 MiB"),
+            (Locale::default(), 1024 * 1024 - 52, "1023.9 KiB"),
+            (Locale::default(), 12_897_485, "12.3 MiB"),
+            (Locale::default(), 3 * 1024 * 1024 * 1024, "3.0 GiB"),
+            (Locale::default(), u64::MAX, "16777216.0 TiB"),
+            (comma, 12_897_485, "12,3 MiB"),
+        ];
+
+        for (locale, bytes, expected) in &cases {
+            assert_eq!(locale.size(*bytes), *expected, "{} bytes", bytes);
+        }
+    }
+
+    #[test]
+    fn format_ages() {
+        let locale = grouped(',');
+
+        let cases = [
+            (0, "0m"),
+            (59, "0m"),
+            (60, "1m"),
+            (3599, "59m"),
+            (3600, "1h"),
+            (86_399, "23h"),
+            (86_400, "1d"),
+            (1000 * 86_400, "1,000d"),
+        ];
+
+        for (secs, expected) in &cases {
+            assert_eq!(locale.age(*secs), *expected, "{} seconds", secs);
+        }
+    }
+}
@@ -0,0 +1,3288 @@
+mod dot;
+pub mod drilldown;
+pub mod format;
+mod notify;
+pub mod theme;
+
+use crate::ack::Acks;
+use crate::clock::Anomalies;
+use crate::critical::CriticalChange;
+use crate::fleet::{Cell, Matrix};
+use crate::json;
+use crate::nixpkgs;
+use crate::prune::Removal;
+use crate::query::Filter;
+use crate::rejects::{CappedList, Rejects};
+use crate::rollback::{Orientation, Rollback};
+use crate::runs::{self, Run, RunMode};
+use crate::specialisation::Comparison;
+use crate::staleness::{Manifest, Staleness};
+use crate::state::{self, PackageState, Snapshot, StateMeta};
+use crate::store::budget::Budget;
+use crate::store::cache::{CacheReport, CacheStatus, CacheSummary};
+use crate::store::diff::{
+    self, DiffCounts, DiffOptions, DiffScope, EcosystemTransition, LocalFilter, Outcome,
+    PackageDiff, RemovedPackage, StoreDiff,
+};
+use crate::store::explain::{self, Explanation, Link};
+use crate::store::fingerprint::ParseChange;
+use crate::store::outputs::Outputs;
+use crate::store::trace::{FragmentKind, ParseTrace};
+use crate::store::verify::Verification;
+use crate::store::version::Version;
+use crate::store::waves::{self, Wave};
+use crate::store::{Derivation, Heuristic, Store};
+use anyhow::{anyhow, Error, Result};
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::iter;
+use std::path::Path;
+use std::str::FromStr;
+use theme::{Paint, Role};
+
+/// The number of dependency names to show for a package in the compact format when context is enabled.
+const COMPACT_CONTEXT_DEPS: usize = 3;
+
+/// The number of characters versions are cut off after by `--short` when no length is given, which is
+/// enough to tell git hashes apart.
+pub const SHORT_VERSION_LEN: usize = 12;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    /// Each package on its own line, followed by a line for each of its dependencies.
+    Human,
+    /// Each package on a single line, with its dependencies summarized.
+    HumanCompact,
+    /// Each package as a JSON object on its own line, without any other output.
+    Ndjson,
+    /// A Graphviz graph of the changed packages and dependencies, without any other output.
+    Dot,
+    /// A title and a single line naming the first few changed packages, to be shown as a desktop notification.
+    Notify,
+}
+
+impl Format {
+    /// Returns true if nothing but the diff in this format can be written to stdout.
+    pub fn is_exclusive(self) -> bool {
+        matches!(self, Self::Ndjson | Self::Dot | Self::Notify)
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "human" => Ok(Self::Human),
+            "human-compact" => Ok(Self::HumanCompact),
+            "ndjson" => Ok(Self::Ndjson),
+            "dot" => Ok(Self::Dot),
+            "notify" => Ok(Self::Notify),
+            _ => Err(anyhow!(
+                "unknown format \"{}\", expected human, human-compact, ndjson, dot, or notify",
+                value
+            )),
+        }
+    }
+}
+
+/// How the dependencies of each package are ordered.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DepSort {
+    /// Alphabetically by name.
+    Name,
+    /// By the magnitude of their version change, from largest to smallest.
+    Jump,
+}
+
+impl FromStr for DepSort {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "name" => Ok(Self::Name),
+            "jump" => Ok(Self::Jump),
+            "size" => Err(anyhow!(
+                "dependencies cannot be sorted by size, as saved states don't record store sizes"
+            )),
+            _ => Err(anyhow!(
+                "unknown dependency order \"{}\", expected name or jump",
+                value
+            )),
+        }
+    }
+}
+
+pub struct DisplayOptions {
+    pub format: Format,
+    /// Show the names of changed dependencies in formats that would otherwise only show a count.
+    pub context: bool,
+    /// Show packages that were rebuilt from a different derivation without their version changing.
+    pub rebuilds: bool,
+    pub sort_deps: DepSort,
+    /// Show the dependencies of each package as a tree following the stores they were discovered through.
+    pub tree: bool,
+    /// List every changed package without printing its dependency changes.
+    pub quiet_deps: bool,
+    /// Whether to present the diff as changes reverted by a rollback.
+    pub orientation: Orientation,
+    /// The versions available from a channel, to show how far behind each package is.
+    pub staleness: Option<Manifest>,
+    /// Show how many paths reference each package.
+    pub referrers: bool,
+    /// List which dependencies left with each removed package, rather than only counting them.
+    pub removed_deps: bool,
+    /// Split the package diffs into waves of updates separated by gaps of more than this many seconds.
+    pub waves: Option<u32>,
+    /// Cut off versions longer than this many characters in the human formats.
+    pub short: Option<usize>,
+    /// Nest the variants of a package, such as `nss@3.96` and `nss@3.98`, under a single header in the human formats.
+    pub group_variants: bool,
+}
+
+/// What a diff found besides the updates themselves.
+pub struct Findings<'a> {
+    pub counts: DiffCounts,
+    pub critical: &'a [CriticalChange],
+    pub removals: &'a [RemovedPackage],
+    /// Whether a binary cache has the new version of each changed package, if it was checked.
+    pub cache: Option<&'a CacheReport>,
+    /// The changes acknowledged with `nixup ack`, which are left out of the human formats.
+    pub acked: Option<&'a Acks>,
+    /// The other outputs of each package that has more than one, if they were grouped.
+    pub outputs: Option<&'a HashMap<String, Outputs>>,
+    /// The added package each removed package was likely renamed to, by the removed package's name, if renames
+    /// were looked for.
+    pub renames: Option<&'a HashMap<String, String>>,
+}
+
+/// What to note about a package on the line that starts it in the human formats.
+#[derive(Copy, Clone, Default)]
+struct Header<'a> {
+    /// Present the package as a change that was reverted by a rollback.
+    reverted: bool,
+    /// How far the current version is behind the newest one available, if known.
+    staleness: Option<&'a Staleness>,
+    /// Show how many paths reference the package, if it was counted.
+    referrers: bool,
+    /// The nixpkgs checkout to show where the package is defined in.
+    nixpkgs: Option<&'a nixpkgs::Index>,
+    /// Cut off versions of the package and its dependencies that are longer than this many characters.
+    short: Option<usize>,
+    /// Whether the binary cache has the new version of the package, if it was checked.
+    cache: Option<CacheStatus>,
+    /// The outputs of the package other than the one its versions come from.
+    outputs: Option<&'a Outputs>,
+}
+
+/// Prints the diff between `cur_state` and `old_state` to stdout in the format selected by `opts`.
+///
+/// The human formats use the counts in `findings` to explain why a diff is empty. Only writing NDJSON can fail,
+/// as each line is flushed as soon as it's written.
+pub fn package_diffs(
+    cur_state: HashSet<Derivation>,
+    old_state: PackageState,
+    diff_opts: DiffOptions,
+    opts: &DisplayOptions,
+    findings: Findings,
+    filter: Option<Filter>,
+    rollback: Option<&Rollback>,
+) -> Result<()> {
+    let Findings {
+        counts,
+        critical,
+        removals,
+        cache,
+        acked,
+        outputs,
+        renames,
+    } = findings;
+
+    let pkg_diffs = collect_diffs(&cur_state, &old_state.packages, diff_opts, filter);
+
+    match opts.format {
+        Format::Ndjson => {
+            let mut out = io::stdout().lock();
+
+            for diff in &pkg_diffs {
+                json::write_package_line(&mut out, diff)?;
+            }
+
+            return Ok(());
+        }
+        Format::Dot => return dot::write_graph(io::stdout().lock(), &pkg_diffs, &cur_state),
+        Format::Notify => {
+            return notify::write_notification(io::stdout().lock(), &pkg_diffs, removals.len())
+        }
+        Format::Human | Format::HumanCompact => (),
+    }
+
+    let (pkg_diffs, removals, num_acked) = match acked {
+        Some(acks) => {
+            let (acked, pkg_diffs): (Vec<_>, Vec<_>) =
+                pkg_diffs.into_iter().partition(|diff| acks.covers(diff));
+
+            let (acked_removals, removals): (Vec<_>, Vec<_>) = removals
+                .iter()
+                .partition(|removal| acks.covers_removal(removal));
+
+            (pkg_diffs, removals, acked.len() + acked_removals.len())
+        }
+        None => (pkg_diffs, removals.iter().collect(), 0),
+    };
+
+    // Nothing but acknowledged changes is the same as the diff that was acknowledged, even if filters
+    // made its digest differ
+    if num_acked > 0 && pkg_diffs.is_empty() && removals.is_empty() {
+        pending_acks(num_acked);
+        return Ok(());
+    }
+
+    let locale = format::locale();
+    let saved_at = locale.datetime(old_state.meta.saved_at);
+
+    match &old_state.meta.message {
+        Some(message) => println!(
+            "diffing against state saved on {}: \"{}\"",
+            saved_at,
+            state::sanitize_message(message).paint(Role::Message)
+        ),
+        None => println!("diffing against state saved on {}", saved_at),
+    }
+
+    let reverted = opts.orientation.is_reversed(rollback);
+
+    if let Some(rollback) = rollback {
+        rollback_banner(rollback, reverted);
+    }
+
+    let transitions = diff::get_ecosystem_transitions(&cur_state, &old_state.packages);
+
+    if !transitions.is_empty() {
+        ecosystem_transitions(&transitions);
+    }
+
+    // Critical changes are never hidden by the diff options, so they go before everything else
+    if !critical.is_empty() {
+        critical_changes(critical, diff_opts, filter.is_some());
+    }
+
+    match counts.outcome() {
+        Outcome::EmptyBaseline => {
+            let notice = "the saved state has no packages, so it was likely saved incorrectly\nplease save it again with the -s flag";
+            println!("{}", notice.paint(Role::Warning));
+            return Ok(());
+        }
+        Outcome::Identical => println!(
+            "{}\n",
+            "no changes, as every package has the same version as in the saved state"
+                .paint(Role::Success)
+        ),
+        Outcome::Changed | Outcome::Suppressed => println!(
+            "{} {}\n",
+            locale.count(pkg_diffs.len()).paint(Role::Value),
+            format::noun(pkg_diffs.len(), "package update", "package updates")
+        ),
+    }
+
+    if num_acked > 0 {
+        println!("{}\n", format_pending_acks(num_acked));
+    }
+
+    let mut rebuilds = diff::get_rebuilds(&cur_state, &old_state.packages);
+    let tally = Tally::new(&pkg_diffs, counts, rebuilds.len());
+
+    let tree_chars = TreeChars::detect();
+    let no_paths = HashMap::new();
+
+    let format_diff = |diff: PackageDiff| {
+        let cur_pkg = cur_state.get(diff.name.as_str());
+
+        let staleness = opts.staleness.as_ref().and_then(|manifest| {
+            cur_pkg.and_then(|pkg| manifest.staleness(&diff.name, &pkg.store.version))
+        });
+
+        // Only a new version of the package itself has a path that could be downloaded
+        let cache_status = cache.and_then(|report| {
+            cur_pkg
+                .filter(|_| diff.pkg.is_some())
+                .and_then(|pkg| report.status(&pkg.store))
+        });
+
+        let header = Header {
+            reverted,
+            staleness: staleness.as_ref(),
+            referrers: opts.referrers,
+            nixpkgs: nixpkgs::index(),
+            short: opts.short,
+            cache: cache_status,
+            outputs: outputs.and_then(|outputs| outputs.get(&diff.name)),
+        };
+
+        match opts.format {
+            Format::Human if opts.tree => {
+                let paths = cur_pkg.map_or(&no_paths, |pkg| &pkg.paths);
+
+                format_pkg_tree(diff, paths, opts.sort_deps, tree_chars, header)
+            }
+            Format::Human => format_pkg_diff(diff, opts.sort_deps, header, opts.quiet_deps),
+            Format::HumanCompact => {
+                vec![format_compact(diff, opts.context, opts.sort_deps, header)]
+            }
+            Format::Ndjson | Format::Dot | Format::Notify => unreachable!(),
+        }
+    };
+
+    let show_diffs = |diffs: Vec<PackageDiff>| {
+        let groups = if opts.group_variants {
+            group_variants(diffs)
+        } else {
+            diffs.into_iter().map(|diff| vec![diff]).collect()
+        };
+
+        for group in groups {
+            if group.len() == 1 {
+                group
+                    .into_iter()
+                    .flat_map(format_diff)
+                    .for_each(|line| println!("{}", line));
+
+                continue;
+            }
+
+            let base = variant_base(&group[0].name);
+            println!("{}", format_variants_header(base, group.len()));
+
+            for line in group.into_iter().flat_map(format_diff) {
+                println!("  {}", line);
+            }
+        }
+    };
+
+    match opts.waves {
+        Some(gap) => {
+            for (i, wave) in waves::group(pkg_diffs, gap).into_iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+
+                println!("{}\n", format_wave_header(i + 1, &wave));
+                show_diffs(wave.diffs);
+            }
+        }
+        None => show_diffs(pkg_diffs),
+    }
+
+    if opts.rebuilds {
+        rebuilds.sort_unstable();
+
+        println!(
+            "\n{} {}\n",
+            locale.count(rebuilds.len()).paint(Role::Value),
+            format::noun(rebuilds.len(), "package rebuild", "package rebuilds")
+        );
+
+        for name in rebuilds {
+            println!(
+                "{} {}",
+                name.paint(Role::PackageName),
+                "(rebuilt)".paint(Role::Rebuilt)
+            );
+        }
+    }
+
+    if !removals.is_empty() {
+        println!(
+            "\n{} {}\n",
+            locale.count(removals.len()).paint(Role::Value),
+            format::noun(removals.len(), "removed package", "removed packages")
+        );
+
+        for line in removals.iter().flat_map(|removal| {
+            let renamed_to = renames
+                .and_then(|renames| renames.get(&removal.name))
+                .map(String::as_str);
+
+            format_removal(removal, renamed_to, opts.removed_deps)
+        }) {
+            println!("{}", line);
+        }
+    }
+
+    if counts.outcome() == Outcome::Suppressed {
+        println!(
+            "{}",
+            format!(
+                "{} hidden by {}",
+                locale.plural(
+                    counts.hidden(),
+                    "package update was",
+                    "package updates were"
+                ),
+                filter_flags(diff_opts, filter.is_some()).join(" and ")
+            )
+            .paint(Role::Detail)
+        );
+    }
+
+    if let Some(tally) = tally.format() {
+        println!("\n{}", tally);
+    }
+
+    if let Some(report) = cache {
+        println!("\n{}", format_cache_summary(&report.url, report.summary()));
+    }
+
+    Ok(())
+}
+
+/// Formats how many of the new paths a binary cache has, such as
+/// `binary cache https://cache.nixos.org: 12 of 14 new paths are cached with 87.5 MiB to download, 2 have to be built`.
+fn format_cache_summary(url: &str, summary: CacheSummary) -> String {
+    let locale = format::locale();
+    let total = summary.cached + summary.not_cached + summary.unknown;
+
+    if total == 0 {
+        return format!("binary cache {}: no new paths to check", url).paint(Role::Detail);
+    }
+
+    let mut line = format!(
+        "binary cache {}: {} of {} are cached",
+        url,
+        locale.count(summary.cached).paint(Role::Value),
+        locale.plural(total, "new path", "new paths")
+    );
+
+    if summary.cached > 0 {
+        line.push_str(&format!(
+            " with {} to download",
+            locale.size(summary.download_size).paint(Role::Value)
+        ));
+    }
+
+    if summary.not_cached > 0 {
+        let built = format!(
+            "{} to be built",
+            locale.plural(summary.not_cached, "has", "have")
+        );
+
+        line.push_str(&format!(", {}", built.paint(Role::Note)));
+    }
+
+    if summary.unknown > 0 {
+        line.push_str(&format!(
+            ", {} couldn't be checked",
+            locale.count(summary.unknown).paint(Role::Warning)
+        ));
+    }
+
+    line
+}
+
+/// Prints that `num` updates were acknowledged and won't be shown again.
+pub fn acknowledged(num: usize) {
+    println!(
+        "acknowledged {}, which will only be shown again with --show-acked until they change or a new baseline is saved",
+        format::locale().plural(num, "update", "updates")
+    );
+}
+
+/// Prints the line that stands in for a diff that was entirely acknowledged.
+pub fn pending_acks(num: usize) {
+    println!("{}", format_pending_acks(num));
+}
+
+fn format_pending_acks(num: usize) -> String {
+    format!(
+        "{} pending — run with --show-acked to display",
+        format::locale().plural(
+            num,
+            "previously acknowledged update",
+            "previously acknowledged updates"
+        )
+    )
+    .paint(Role::Detail)
+}
+
+/// Formats the heading of the `num`th wave of updates, such as `wave 2 — 2024-03-05 21:14, 37 packages`.
+fn format_wave_header(num: usize, wave: &Wave) -> String {
+    let locale = format::locale();
+
+    format!(
+        "wave {} — {}, {}",
+        num,
+        locale.datetime(wave.start.into()),
+        locale.plural(wave.diffs.len(), "package", "packages")
+    )
+    .paint(Role::Heading)
+}
+
+/// Returns the sorted diffs between `cur_state` and `old_state` with wrappers merged and split outputs grouped.
+///
+/// Diffs that were left empty by any of the steps are dropped here, so a package is never shown without a change under it.
+pub fn collect_diffs(
+    cur_state: &HashSet<Derivation>,
+    old_state: &HashSet<Derivation>,
+    diff_opts: DiffOptions,
+    filter: Option<Filter>,
+) -> Vec<PackageDiff> {
+    let mut diffs = diff::get_package_diffs(cur_state, old_state, diff_opts);
+
+    if let Some(filter) = filter {
+        filter.apply(&mut diffs);
+    }
+
+    let mut diffs = diff::merge_wrappers(diffs, cur_state, old_state);
+    diffs.retain(|diff| !diff.is_empty());
+
+    diff::group_split_outputs(&mut diffs, cur_state, old_state);
+    diffs.sort_unstable_by(sys_pkg_sorter);
+    diffs
+}
+
+/// How many packages went through each kind of change, which is summarized at the end of the human formats.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct Tally {
+    upgraded: usize,
+    downgraded: usize,
+    added: usize,
+    removed: usize,
+    rebuilt: usize,
+}
+
+impl Tally {
+    /// Counts every diff in `diffs` as an upgrade, unless the version of the package itself clearly went down.
+    fn new(diffs: &[PackageDiff], counts: DiffCounts, rebuilt: usize) -> Self {
+        let downgraded = diffs
+            .iter()
+            .filter(|diff| diff.pkg.as_ref().is_some_and(StoreDiff::is_downgrade))
+            .count();
+
+        Self {
+            upgraded: diffs.len() - downgraded,
+            downgraded,
+            added: counts.added,
+            removed: counts.removed,
+            rebuilt,
+        }
+    }
+
+    /// Formats the tally as `3 upgraded, 1 added`, leaving out the kinds of changes no package went through.
+    ///
+    /// Returns `None` if nothing changed at all.
+    fn format(&self) -> Option<String> {
+        let locale = format::locale();
+
+        let categories = [
+            (self.upgraded, "upgraded", Role::NewVersion),
+            (self.downgraded, "downgraded", Role::OldVersion),
+            (self.added, "added", Role::Added),
+            (self.removed, "removed", Role::Removed),
+            (self.rebuilt, "rebuilt", Role::Rebuilt),
+        ];
+
+        let parts = categories
+            .iter()
+            .filter(|(count, _, _)| *count > 0)
+            .map(|&(count, label, role)| format!("{} {}", locale.count(count), label).paint(role))
+            .collect::<Vec<_>>();
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(", "))
+        }
+    }
+}
+
+/// Formats a removed package along with how many of its dependencies left the system with it, and lists
+/// them when `list_deps` is set. A package that was likely renamed to `renamed_to` is shown as such.
+fn format_removal(
+    removal: &RemovedPackage,
+    renamed_to: Option<&str>,
+    list_deps: bool,
+) -> Vec<String> {
+    let locale = format::locale();
+
+    let mut header = format!(
+        "{} {}",
+        removal.name.paint(Role::PackageName),
+        removal.version.paint(Role::OldVersion)
+    );
+
+    if let Some(renamed_to) = renamed_to {
+        header.push_str(&format!(
+            " -> {} {}",
+            renamed_to.paint(Role::PackageName),
+            "(renamed?)".paint(Role::Warning)
+        ));
+    }
+
+    if removal.gone.is_empty() && removal.retained.is_empty() {
+        return vec![header];
+    }
+
+    header.push_str(&format!(
+        "  {}",
+        format!(
+            "({} also gone, {} retained)",
+            locale.plural(removal.gone.len(), "dependency", "dependencies"),
+            locale.count(removal.retained.len())
+        )
+        .paint(Role::Detail)
+    ));
+
+    let mut lines = vec![header];
+
+    if list_deps {
+        for (label, names, role) in &[
+            ("gone", &removal.gone, Role::Removed),
+            ("retained", &removal.retained, Role::Detail),
+        ] {
+            if !names.is_empty() {
+                lines.push(format!("  {}: {}", label, names.join(", ").paint(*role)));
+            }
+        }
+    }
+
+    lines
+}
+
+/// Prints a notice explaining why the system appears to have been rolled back, and how the diff is presented.
+fn rollback_banner(rollback: &Rollback, reverted: bool) {
+    let reason = match rollback {
+        Rollback::Generation { saved, current } => format!(
+            "the system profile is at generation {}, but the state was saved at generation {}",
+            current, saved
+        ),
+        Rollback::RegistrationTimes { older, dated } => format!(
+            "{} of {} changed packages were registered before the state was saved",
+            older, dated
+        ),
+    };
+
+    let presentation = if reverted {
+        "showing changes as reverted, use --orientation forward to show them as updates"
+    } else {
+        "use --orientation auto to show changes as reverted"
+    };
+
+    println!(
+        "{}\n{}\n",
+        format!("rollback detected: {}", reason).paint(Role::Warning),
+        presentation.paint(Role::Detail)
+    );
+}
+
+/// Prints the changes to each specialisation in `comparison` in a section of its own, followed by the
+/// specialisations that were added or removed.
+pub fn specialisations(comparison: Comparison, opts: &DisplayOptions) {
+    let header = Header {
+        short: opts.short,
+        ..Header::default()
+    };
+
+    for (name, diffs) in comparison.changed {
+        println!("\n{}", format_specialisation_heading(&name, diffs.len()));
+
+        for diff in diffs {
+            let lines = match opts.format {
+                Format::HumanCompact => {
+                    vec![format_compact(diff, opts.context, opts.sort_deps, header)]
+                }
+                _ => format_pkg_diff(diff, opts.sort_deps, header, opts.quiet_deps),
+            };
+
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+    }
+
+    let lists = [
+        ("added", Role::Added, &comparison.added),
+        ("removed", Role::Removed, &comparison.removed),
+    ];
+
+    for (what, role, names) in &lists {
+        if names.is_empty() {
+            continue;
+        }
+
+        let names = names
+            .iter()
+            .map(|name| name.paint(*role))
+            .collect::<Vec<_>>();
+
+        println!(
+            "\n{} {}",
+            format!("{} specialisations:", what).paint(Role::Heading),
+            names.join(", ")
+        );
+    }
+}
+
+/// Formats the heading of a specialisation's section, such as `specialisation 'on-the-go': 3 changes`.
+fn format_specialisation_heading(name: &str, num_changes: usize) -> String {
+    let changes = match num_changes {
+        0 => "no changes".to_string(),
+        num => format::locale().plural(num, "change", "changes"),
+    };
+
+    format!(
+        "{} {}",
+        format!("specialisation '{}':", name).paint(Role::Heading),
+        changes.paint(Role::Detail)
+    )
+}
+
+/// Prints every ecosystem whose version changed, which the packages built for it no longer show in their names.
+fn ecosystem_transitions(transitions: &[EcosystemTransition]) {
+    println!("{}", "ecosystem changes:".paint(Role::Heading));
+
+    for transition in transitions {
+        let packages = format!(
+            "({})",
+            format::locale().plural(transition.packages, "package", "packages")
+        );
+
+        println!(
+            "  {}: {} -> {} {}",
+            transition.ecosystem.paint(Role::PackageName),
+            transition.from.paint(Role::OldVersion),
+            transition.to.paint(Role::NewVersion),
+            packages.paint(Role::Detail)
+        );
+    }
+
+    println!();
+}
+
+/// Prints every change to a critical package, noting the ones `diff_opts` keeps out of the regular diff.
+fn critical_changes(critical: &[CriticalChange], diff_opts: DiffOptions, queried: bool) {
+    println!(
+        "{}",
+        "security-relevant changes:".paint(Role::CriticalSection)
+    );
+
+    let flags = filter_flags(diff_opts, queried).join(" and ");
+
+    for change in critical {
+        let mut line = format!(
+            "  {}: {} -> {}",
+            change.name.paint(Role::PackageName),
+            change.ver_from.paint(Role::OldVersion),
+            change.ver_to.paint(Role::NewVersion)
+        );
+
+        if !change.dependents.is_empty() {
+            let note = format!(
+                "({}dependency of {})",
+                if change.as_package { "and " } else { "" },
+                format_dependents(&change.dependents)
+            );
+
+            line = format!("{} {}", line, note.paint(Role::Detail));
+        }
+
+        if change.hidden {
+            line = format!(
+                "{} {}",
+                line,
+                format!("(shown despite {})", flags).paint(Role::Note)
+            );
+        }
+
+        println!("{}", line);
+    }
+
+    println!();
+}
+
+/// Lists the first few `dependents` by name, followed by how many more there are.
+fn format_dependents(dependents: &[String]) -> String {
+    const SHOWN: usize = 3;
+
+    if dependents.len() <= SHOWN {
+        return dependents.join(", ");
+    }
+
+    format!(
+        "{} and {} more",
+        dependents[..SHOWN].join(", "),
+        format::locale().count(dependents.len() - SHOWN)
+    )
+}
+
+/// Returns the flags that selected the filters of `opts`, along with --where if the diff was `queried`.
+fn filter_flags(opts: DiffOptions, queried: bool) -> Vec<&'static str> {
+    let mut flags = Vec::with_capacity(3);
+
+    match opts.scope {
+        DiffScope::All => (),
+        DiffScope::PackagesOnly => flags.push("--packages-only"),
+        DiffScope::DepsOnly => flags.push("--diff-only-deps"),
+    }
+
+    match opts.local {
+        LocalFilter::All => (),
+        LocalFilter::OnlyLocal => flags.push("--only-local"),
+        LocalFilter::NoLocal => flags.push("--no-local"),
+    }
+
+    if queried {
+        flags.push("--where");
+    }
+
+    flags
+}
+
+/// Prints `err` to stderr as a single line of every cause, or with each cause on its own line and the
+/// backtrace (if one was captured) when `detailed` is set.
+pub fn error(err: &Error, detailed: bool) {
+    eprintln!(
+        "{} {}",
+        "error:".paint(Role::Error),
+        format_error(err, detailed)
+    );
+}
+
+fn format_error(err: &Error, detailed: bool) -> String {
+    if detailed {
+        format!("{:?}", err)
+    } else {
+        format!("{:#}", err)
+    }
+}
+
+/// Prints a warning to stderr for every phase of `budget` that was cut short.
+pub fn cutoffs(budget: &Budget) {
+    for cutoff in budget.cutoffs() {
+        let notice = format!(
+            "timed out while {}: {} left unresolved, so the results are incomplete",
+            cutoff.phase,
+            format::locale().plural(cutoff.remaining, "store was", "stores were")
+        );
+
+        eprintln!("{}", notice.paint(Role::Warning));
+    }
+}
+
+/// Prints a notice to stderr that removed packages are left out, as not every store was scanned.
+pub fn removals_skipped() {
+    let notice =
+        "removed packages aren't shown, as a package that wasn't scanned would look removed";
+    eprintln!("{}", notice.paint(Role::Warning));
+}
+
+/// Prints a notice to stderr that `generation` was saved as the baseline automatically.
+pub fn autosaved(generation: u32) {
+    let notice = format!(
+        "the system changed since the last run, so generation {} was saved automatically",
+        generation
+    );
+
+    eprintln!("{}", notice.paint(Role::Warning));
+}
+
+/// Prints a warning to stderr that checking for an unsaved generation failed.
+pub fn autosave_failed(err: &Error) {
+    let notice = format!(
+        "failed to check for an unsaved generation: {:#}\nrun with --no-autosave to skip the check",
+        err
+    );
+
+    eprintln!("{}", notice.paint(Role::Warning));
+}
+
+/// Prints a warning to stderr that the Nix database was opened mutably while the store is in use.
+pub fn inconsistent_database() {
+    let notice = "the Nix database couldn't be opened immutably and Nix appears to be running, so results may be inconsistent";
+    eprintln!("{}", notice.paint(Role::Warning));
+}
+
+/// Prints a warning to stderr that the baseline was saved with a parser that splits store paths differently,
+/// along with every entry of the corpus it parsed differently.
+pub fn parser_changed(saved_with: Option<&str>, changes: &[ParseChange]) {
+    for line in format_parser_changed(saved_with, changes) {
+        eprintln!("{}", line);
+    }
+}
+
+fn format_parser_changed(saved_with: Option<&str>, changes: &[ParseChange]) -> Vec<String> {
+    let saved_with = match saved_with {
+        Some(version) => format!("nixup {}", version),
+        None => "an older nixup".into(),
+    };
+
+    let notice = format!(
+        "the baseline was saved with {}, which parses {} differently than nixup {}, so some packages may show up as changed when they aren't",
+        saved_with,
+        format::locale().plural(changes.len(), "store name", "store names"),
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let mut lines = vec![notice.paint(Role::Warning).to_string()];
+
+    for change in changes {
+        lines.push(format!(
+            "  {}: {} -> {}",
+            change.entry,
+            change.old.paint(Role::OldVersion),
+            change.new.paint(Role::NewVersion)
+        ));
+    }
+
+    lines.push(
+        "save the state again with -s to use the current parser for the baseline"
+            .paint(Role::Warning)
+            .to_string(),
+    );
+
+    lines
+}
+
+/// Prints a warning to stderr summarizing how many stores have registration times that can't be right.
+pub fn clock_skew(anomalies: Anomalies) {
+    let locale = format::locale();
+    let mut kinds = Vec::with_capacity(2);
+
+    if anomalies.future > 0 {
+        kinds.push(format!("{} in the future", locale.count(anomalies.future)));
+    }
+
+    if anomalies.too_old > 0 {
+        kinds.push(format!(
+            "{} before Nix existed",
+            locale.count(anomalies.too_old)
+        ));
+    }
+
+    let total = anomalies.future + anomalies.too_old;
+
+    let notice = format!(
+        "{} registered at impossible times ({}), so the system clock may have been wrong. Ages are clamped, but registration times are used as is",
+        locale.plural(total, "store was", "stores were"),
+        kinds.join(", ")
+    );
+
+    eprintln!("{}", notice.paint(Role::Warning));
+}
+
+/// Prints every downgrade to stderr so it stands out from the diff.
+pub fn downgrades(downgrades: &[String]) {
+    eprintln!(
+        "\n{}",
+        format!(
+            "{} found:",
+            format::locale().plural(downgrades.len(), "downgrade", "downgrades")
+        )
+        .paint(Role::Error)
+    );
+
+    for downgrade in downgrades {
+        eprintln!("  {}", downgrade.paint(Role::OldVersion));
+    }
+}
+
+/// Prints what the state at `path` left out when it was saved, as recorded in `rejects`.
+pub fn audit(path: &Path, meta: &StateMeta, rejects: &Rejects) {
+    for line in format_audit(path, meta, rejects) {
+        println!("{}", line);
+    }
+}
+
+fn format_audit(path: &Path, meta: &StateMeta, rejects: &Rejects) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{} {} left out when it was saved on {}",
+        "audit:".paint(Role::Heading),
+        path.display(),
+        format::locale().datetime(meta.saved_at)
+    )];
+
+    let categories = [
+        ("paths that couldn't be parsed", &rejects.unparsed),
+        (
+            "packages with discarded duplicates or older versions",
+            &rejects.deduplicated,
+        ),
+    ];
+
+    for (what, list) in &categories {
+        lines.push(String::new());
+        lines.extend(format_capped_list(what, list));
+    }
+
+    lines
+}
+
+fn format_capped_list(what: &str, list: &CappedList) -> Vec<String> {
+    let locale = format::locale();
+
+    let mut lines = vec![format!(
+        "{} ({}):",
+        what.paint(Role::Heading),
+        locale.count(list.total() as usize)
+    )];
+
+    if list.entries.is_empty() {
+        lines.push(format!("  {}", "none".paint(Role::Detail)));
+    }
+
+    for entry in &list.entries {
+        lines.push(format!("  {}", state::sanitize_message(entry)));
+    }
+
+    if list.overflow > 0 {
+        let more = format!(
+            "...and {} more that weren't recorded",
+            locale.count(list.overflow as usize)
+        );
+
+        lines.push(format!("  {}", more.paint(Role::Detail)));
+    }
+
+    lines
+}
+
+/// Prints how many stores of the state at `path` are no longer in the Nix store, and which ones if `list` is set.
+pub fn verification(path: &Path, verification: &Verification, list: bool) {
+    for line in format_verification(path, verification, list) {
+        println!("{}", line);
+    }
+}
+
+fn format_verification(path: &Path, verification: &Verification, list: bool) -> Vec<String> {
+    let locale = format::locale();
+
+    if verification.missing() == 0 {
+        return vec![format!(
+            "{} every one of the {} and {} in {} is still in the nix store",
+            "verify:".paint(Role::Heading),
+            locale.plural(verification.packages, "package", "packages"),
+            locale.plural(verification.deps, "dependency", "dependencies"),
+            path.display()
+        )];
+    }
+
+    let mut lines = vec![format!(
+        "{} {} of {} and {} of {} in {} {} no longer in the nix store",
+        "verify:".paint(Role::Heading),
+        locale
+            .count(verification.missing_packages.len())
+            .paint(Role::Value),
+        locale.plural(verification.packages, "package", "packages"),
+        locale
+            .count(verification.missing_deps.len())
+            .paint(Role::Value),
+        locale.plural(verification.deps, "dependency", "dependencies"),
+        path.display(),
+        format::noun(verification.missing(), "is", "are"),
+    )];
+
+    if !list {
+        lines.push("run with --verbose to list them".paint(Role::Detail));
+
+        return lines;
+    }
+
+    let sections = [
+        ("packages", &verification.missing_packages),
+        ("dependencies", &verification.missing_deps),
+    ];
+
+    for (what, stores) in &sections {
+        if stores.is_empty() {
+            continue;
+        }
+
+        lines.push(String::new());
+        lines.push(format!("missing {}:", what).paint(Role::Heading));
+
+        for store in stores.iter() {
+            let suffix = match &store.suffix {
+                Some(suffix) => format!(" {{{}}}", suffix).paint(Role::SuffixTag),
+                None => String::new(),
+            };
+
+            lines.push(format!(
+                "  {}{} {}",
+                store.name.paint(Role::PackageName),
+                suffix,
+                store.version.paint(Role::OldVersion)
+            ));
+        }
+    }
+
+    lines
+}
+
+/// The widest a column of the fleet table can be before its text is cut off.
+const MAX_FLEET_COLUMN: usize = 24;
+
+/// Prints a warning to stderr that the state of the host `name` couldn't be loaded.
+pub fn fleet_host_failed(name: &str, err: &Error) {
+    let notice = format!(
+        "failed to load the state of {}, so its versions are shown as ?: {:#}",
+        state::sanitize_message(name),
+        err
+    );
+
+    eprintln!("{}", notice.paint(Role::Warning));
+}
+
+/// Prints the versions in `matrix` as a table, with as many hosts as fit within `width` characters.
+pub fn fleet(matrix: &Matrix, width: usize) {
+    for line in format_fleet(matrix, width, TreeChars::detect()) {
+        println!("{}", line);
+    }
+}
+
+fn format_fleet(matrix: &Matrix, width: usize, chars: TreeChars) -> Vec<String> {
+    let locale = format::locale();
+
+    if matrix.rows.is_empty() {
+        return vec![format!(
+            "{} every host that was loaded has the same packages",
+            "fleet:".paint(Role::Heading)
+        )];
+    }
+
+    let lagging = matrix.lagging_hosts();
+
+    let mut lines = vec![
+        format!(
+            "{} {} {} across {}, and {} behind on at least one",
+            "fleet:".paint(Role::Heading),
+            locale.plural(matrix.rows.len(), "package", "packages"),
+            format::noun(matrix.rows.len(), "differs", "differ"),
+            locale.plural(matrix.hosts.len(), "host", "hosts"),
+            locale.plural(lagging, "host is", "hosts are"),
+        ),
+        String::new(),
+    ];
+
+    let hosts = matrix
+        .hosts
+        .iter()
+        .map(|(name, _)| state::sanitize_message(name))
+        .collect::<Vec<_>>();
+
+    let cells = matrix
+        .rows
+        .iter()
+        .map(|row| row.cells.iter().map(fleet_cell).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let name_width = matrix
+        .rows
+        .iter()
+        .map(|row| row.name.chars().count())
+        .chain(iter::once("package".len()))
+        .max()
+        .unwrap_or(0)
+        .min(MAX_FLEET_COLUMN);
+
+    let column_widths = hosts.iter().enumerate().map(|(i, host)| {
+        cells
+            .iter()
+            .map(|row| row[i].0.chars().count())
+            .chain(iter::once(host.chars().count()))
+            .max()
+            .unwrap_or(0)
+            .min(MAX_FLEET_COLUMN)
+    });
+
+    // At least one host is always shown, even if it doesn't fit
+    let mut used = name_width;
+    let mut widths = Vec::new();
+
+    for column_width in column_widths {
+        if !widths.is_empty() && used + 2 + column_width > width {
+            break;
+        }
+
+        used += 2 + column_width;
+        widths.push(column_width);
+    }
+
+    let pad = |text: &str, role: Role, width: usize| {
+        let text = truncate(text, width, chars.ellipsis);
+        let padding = width - text.chars().count();
+        format!("{}{}", text.paint(role), " ".repeat(padding))
+    };
+
+    let mut header = pad("package", Role::Heading, name_width);
+
+    for (host, &width) in hosts.iter().zip(&widths) {
+        header.push_str("  ");
+        header.push_str(&pad(host, Role::Heading, width));
+    }
+
+    lines.push(header.trim_end().to_string());
+
+    for (row, cells) in matrix.rows.iter().zip(&cells) {
+        let mut line = pad(&row.name, Role::PackageName, name_width);
+
+        for ((text, role), &width) in cells.iter().zip(&widths) {
+            line.push_str("  ");
+            line.push_str(&pad(text, *role, width));
+        }
+
+        lines.push(line.trim_end().to_string());
+    }
+
+    lines.push(String::new());
+
+    let mut legend = vec![
+        "* is behind the newest version in the fleet",
+        "- is missing",
+    ];
+
+    if matrix.hosts.iter().any(|(_, loaded)| !loaded) {
+        legend.push("? couldn't be loaded");
+    }
+
+    lines.push(legend.join(", ").paint(Role::Detail));
+
+    let hidden = hosts.len() - widths.len();
+
+    if hidden > 0 {
+        let more = format!(
+            "...and {} that didn't fit, which --json includes",
+            locale.plural(hidden, "more host", "more hosts")
+        );
+
+        lines.push(more.paint(Role::Detail));
+    }
+
+    lines
+}
+
+/// Returns the text of a cell in the fleet table, and how it's styled.
+fn fleet_cell(cell: &Cell) -> (String, Role) {
+    match cell {
+        Cell::Unknown => ("?".into(), Role::Warning),
+        Cell::Missing => ("-".into(), Role::Detail),
+        Cell::Version {
+            version,
+            lagging: true,
+        } => (format!("{}*", version), Role::OldVersion),
+        Cell::Version { version, .. } => (version.clone(), Role::NewVersion),
+    }
+}
+
+/// Cuts `text` off with `ellipsis` if it's longer than `width` characters.
+fn truncate<'a>(text: &'a str, width: usize, ellipsis: &str) -> Cow<'a, str> {
+    if text.chars().count() <= width {
+        return Cow::Borrowed(text);
+    }
+
+    let kept = width.saturating_sub(ellipsis.chars().count());
+
+    if kept == 0 {
+        return text.chars().take(width).collect();
+    }
+
+    let mut result = text.chars().take(kept).collect::<String>();
+    result.push_str(ellipsis);
+    Cow::Owned(result)
+}
+
+/// Prints every step of parsing a store path, as recorded in `trace`.
+pub fn parse_trace(trace: &ParseTrace) {
+    for line in format_parse_trace(trace) {
+        println!("{}", line);
+    }
+}
+
+fn format_parse_trace(trace: &ParseTrace) -> Vec<String> {
+    let stripped = match &trace.stripped {
+        Some(stripped) => stripped,
+        None => {
+            return vec![
+                "not a store path, as it doesn't start with a store directory and hash"
+                    .paint(Role::Error),
+            ]
+        }
+    };
+
+    let mut lines = vec![format!("{} {}", "stripped:".paint(Role::Heading), stripped)];
+
+    if let Some((rewritten, ecosystem)) = &trace.ecosystem {
+        lines.push(format!(
+            "{} {} {}, parsed as {}",
+            "ecosystem:".paint(Role::Heading),
+            ecosystem.name.paint(Role::PackageName),
+            ecosystem.version,
+            rewritten
+        ));
+    }
+
+    lines.push("fragments:".paint(Role::Heading));
+
+    let width = trace
+        .fragments
+        .iter()
+        .map(|(text, _)| text.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    for (text, kind) in &trace.fragments {
+        let kind = match kind {
+            FragmentKind::Name => "name".paint(Role::PackageName),
+            FragmentKind::VersionStart => "version start".paint(Role::NewVersion),
+            FragmentKind::Version => "version continuation".paint(Role::NewVersion),
+            FragmentKind::Suffix => "suffix".paint(Role::Note),
+        };
+
+        lines.push(format!("  {:width$}  {}", text, kind, width = width));
+    }
+
+    lines.push("heuristics:".paint(Role::Heading));
+
+    if trace.heuristics.is_empty() {
+        lines.push(format!("  {}", "none".paint(Role::Detail)));
+    }
+
+    for heuristic in &trace.heuristics {
+        let description = match heuristic {
+            Heuristic::FastPath => "fast path: there is only one dash, so everything after it is the version",
+            Heuristic::KnownOutputs => "the trailing fragments are output names, so they are the suffix",
+            Heuristic::NumberedOutput => "suffix digit rule: a number directly follows an output name at the end, so it is part of the suffix",
+            Heuristic::UnversionedSuffix => "the last fragment has no digits, so it is the suffix",
+            Heuristic::VersionPrefix => "v-prefix rule: the version starts with a v followed by a digit",
+            Heuristic::LaterVersion => "later-version rule: an earlier fragment looked like a version, but a fragment that is clearly part of the name followed it",
+        };
+
+        lines.push(format!("  {}", description));
+    }
+
+    match &trace.store {
+        Some(store) => {
+            lines.push(format!(
+                "{} {}",
+                "name:".paint(Role::Heading),
+                store.name.paint(Role::PackageName)
+            ));
+            lines.push(format!(
+                "{} {}",
+                "version:".paint(Role::Heading),
+                store.version.paint(Role::NewVersion)
+            ));
+
+            if let Some(suffix) = &store.suffix {
+                lines.push(format!(
+                    "{} {}",
+                    "suffix:".paint(Role::Heading),
+                    suffix.paint(Role::Note)
+                ));
+            }
+        }
+        None => lines.push("not parsed as a package, as no version was found".paint(Role::Error)),
+    }
+
+    lines
+}
+
+/// Prints the chains of references in `explanation` as a tree, starting from the current system.
+pub fn explanation(explanation: &Explanation) {
+    for line in format_explanation(explanation) {
+        println!("{}", line);
+    }
+}
+
+/// Formats every chain as a line per path, indented by how far along the chain it is. Chains that share a
+/// beginning only show it once.
+fn format_explanation(explanation: &Explanation) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut previous: &[Link] = &[];
+
+    for chain in &explanation.chains {
+        let shared = chain
+            .iter()
+            .zip(previous)
+            .take_while(|(link, prev)| link.id == prev.id)
+            .count();
+
+        for (depth, link) in chain.iter().enumerate().skip(shared) {
+            lines.push(format!("{}{}", "  ".repeat(depth), format_link(link)));
+        }
+
+        previous = chain;
+    }
+
+    if explanation.truncated {
+        lines.push(
+            format!("only the first {} chains are shown", explain::MAX_CHAINS).paint(Role::Note),
+        );
+    }
+
+    lines
+}
+
+fn format_link(link: &Link) -> String {
+    let store = match &link.store {
+        Some(store) => store,
+        None => return link.name.paint(Role::Detail),
+    };
+
+    let suffix = match &store.suffix {
+        Some(suffix) => format!(" {{{}}}", suffix).paint(Role::SuffixTag),
+        None => String::new(),
+    };
+
+    format!(
+        "{} {}{}",
+        store.name.paint(Role::PackageName),
+        store.version.paint(Role::Value),
+        suffix
+    )
+}
+
+pub fn snapshot(snapshot: &Snapshot) {
+    let marker = if snapshot.current { "*" } else { " " };
+
+    let message = match &snapshot.meta.message {
+        Some(message) => state::sanitize_message(message),
+        None => "(no message)".paint(Role::Detail),
+    };
+
+    println!(
+        "{} {}  {}  {}",
+        marker.paint(Role::Success),
+        format::locale()
+            .datetime(snapshot.meta.saved_at)
+            .paint(Role::Value),
+        message,
+        snapshot.path.display().to_string().paint(Role::Detail)
+    );
+}
+
+pub fn run(run: &Run) {
+    let locale = format::locale();
+
+    let mut line = format!(
+        "{}  {} updated, {} added, {} removed  {}  {}",
+        locale.datetime(run.time).paint(Role::Value),
+        locale.count(run.updated).paint(Role::Success),
+        locale.count(run.added),
+        locale.count(run.removed),
+        format!("baseline {} old", locale.age(run.baseline_age)).paint(Role::Detail),
+        format!("scan {}ms", locale.count(run.scan_ms as usize)).paint(Role::Detail)
+    );
+
+    // Older runs didn't record their total duration
+    if run.duration_ms > 0 {
+        line.push_str(&format!(
+            "  {}",
+            format!("total {}ms", locale.count(run.duration_ms as usize)).paint(Role::Detail)
+        ));
+    }
+
+    if run.mode != RunMode::Diff {
+        line.push_str(&format!("  {}", run.mode.as_str().paint(Role::Note)));
+    }
+
+    if let Some(names) = &run.names {
+        if !names.is_empty() {
+            line.push_str(&format!("\n  {}", names.join(", ")));
+        }
+    }
+
+    println!("{}", line);
+}
+
+pub fn run_summary(runs: &[Run]) {
+    let locale = format::locale();
+
+    println!(
+        "{} recorded",
+        locale.plural(runs.len(), "run", "runs").paint(Role::Value)
+    );
+
+    if let Some(median) = runs::median_updated(runs) {
+        println!(
+            "median of {} per run",
+            locale.plural(median, "updated package", "updated packages")
+        );
+    }
+
+    println!("\nruns per month:");
+
+    for (month, count) in runs::runs_per_month(runs) {
+        println!("  {}  {}", month.paint(Role::Value), locale.count(count));
+    }
+}
+
+pub fn removal(removal: &Removal, dry_run: bool) {
+    let action = if dry_run { "would remove" } else { "removed" };
+
+    println!(
+        "{} {} {}",
+        action,
+        removal.path.display(),
+        format!("({})", format::locale().size(removal.size)).paint(Role::Detail)
+    );
+}
+
+pub fn prune_summary(removed: usize, freed: u64, dry_run: bool) {
+    let locale = format::locale();
+
+    let (files, space) = if dry_run {
+        (
+            format::noun(removed, "file would be removed", "files would be removed"),
+            "would be freed",
+        )
+    } else {
+        (
+            format::noun(removed, "file removed", "files removed"),
+            "freed",
+        )
+    };
+
+    println!(
+        "\n{} {}, {} {}",
+        locale.count(removed).paint(Role::Value),
+        files,
+        locale.size(freed).paint(Role::Value),
+        space
+    );
+}
+
+/// Formats `diff` as `name {suffix}: old -> new`, with versions longer than `short` characters cut off.
+fn format_store_diff(diff: &StoreDiff, short: Option<usize>) -> String {
+    if diff.suffix_changed() {
+        return format_suffix_change(diff, short);
+    }
+
+    let suffix = match &diff.suffix {
+        Some(suffix) => Cow::Owned(format!(" {{{}}}", suffix).paint(Role::SuffixTag)),
+        None => Cow::Borrowed(""),
+    };
+
+    format!(
+        "{}{}: {}",
+        diff.name.paint(Role::PackageName),
+        suffix,
+        format_ver_change(diff, short)
+    )
+}
+
+/// Formats a diff whose suffix changed as `name: old suffix -> new suffix (version)`.
+fn format_suffix_change(diff: &StoreDiff, short: Option<usize>) -> String {
+    let suffix = |suffix: &Option<String>| suffix.clone().unwrap_or_else(|| "(none)".into());
+
+    let version = if diff.ver_from == diff.ver_to {
+        shorten_version(&diff.ver_to, short).into_owned()
+    } else {
+        format_ver_change(diff, short)
+    };
+
+    format!(
+        "{}: {} -> {} ({})",
+        diff.name.paint(Role::PackageName),
+        suffix(&diff.suffix_from).paint(Role::OldVersion),
+        suffix(&diff.suffix).paint(Role::NewVersion),
+        version
+    )
+}
+
+/// Formats `diff` followed by each of its changed dependencies, unless `quiet_deps` is set.
+fn format_pkg_diff(
+    mut diff: PackageDiff,
+    sort: DepSort,
+    header: Header,
+    quiet_deps: bool,
+) -> Vec<String> {
+    let mut lines = vec![format_pkg_header(&diff, header)];
+
+    if quiet_deps || diff.deps.is_empty() {
+        return lines;
+    }
+
+    sort_deps(&mut diff.deps, sort);
+
+    lines.extend(diff.deps.iter().map(|dep| {
+        format!(
+            "{} {}",
+            "^".paint(Role::DepMarker),
+            format_store_diff(dep, header.short)
+        )
+    }));
+
+    lines
+}
+
+/// Formats `diff` with its dependencies as a tree that follows the stores each one was discovered through.
+///
+/// `paths` holds the stores each dependency was discovered through, as found when resolving them.
+fn format_pkg_tree(
+    mut diff: PackageDiff,
+    paths: &HashMap<String, Vec<String>>,
+    sort: DepSort,
+    chars: TreeChars,
+    header: Header,
+) -> Vec<String> {
+    let mut lines = vec![format_pkg_header(&diff, header)];
+
+    sort_deps(&mut diff.deps, sort);
+
+    lines.extend(format_dep_tree(&diff.deps, paths, chars, |dep| {
+        format_store_diff(dep, header.short)
+    }));
+
+    lines
+}
+
+/// Returns the name a package shares with its other variants, such as `nss` for `nss@3.96`.
+///
+/// Packages are told apart by name alone, so variants that only differ in their suffix never make it into the
+/// same state. The only variants a diff can have are the ones tagged by the `keep-all-tagged` duplicate policy.
+fn variant_base(name: &str) -> &str {
+    name.split_once('@').map_or(name, |(base, _)| base)
+}
+
+/// Groups `diffs` by the name their variants share, as returned by `variant_base`.
+///
+/// Each group takes the place of its first diff, and keeps its diffs in the order they were in.
+fn group_variants(diffs: Vec<PackageDiff>) -> Vec<Vec<PackageDiff>> {
+    let mut groups = Vec::<Vec<PackageDiff>>::new();
+    let mut positions = HashMap::<String, usize>::new();
+
+    for diff in diffs {
+        let base = variant_base(&diff.name);
+
+        match positions.get(base) {
+            Some(&pos) => groups[pos].push(diff),
+            None => {
+                positions.insert(base.to_string(), groups.len());
+                groups.push(vec![diff]);
+            }
+        }
+    }
+
+    groups
+}
+
+/// Formats the header the variants of a package are nested under, such as `nss (2 variants)`.
+fn format_variants_header(base: &str, num: usize) -> String {
+    let note = format!("({})", format::locale().plural(num, "variant", "variants"));
+
+    format!(
+        "{} {}",
+        base.paint(Role::PackageName),
+        note.paint(Role::Detail)
+    )
+}
+
+/// The characters used to draw a dependency tree.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TreeChars {
+    branch: &'static str,
+    last: &'static str,
+    pipe: &'static str,
+    blank: &'static str,
+    ellipsis: &'static str,
+}
+
+impl TreeChars {
+    pub const UNICODE: Self = Self {
+        branch: "├── ",
+        last: "└── ",
+        pipe: "│   ",
+        blank: "    ",
+        ellipsis: "…",
+    };
+
+    pub const ASCII: Self = Self {
+        branch: "|-- ",
+        last: "`-- ",
+        pipe: "|   ",
+        blank: "    ",
+        ellipsis: "...",
+    };
+
+    /// Returns the box-drawing characters if the locale uses UTF-8, or the ASCII ones otherwise.
+    pub fn detect() -> Self {
+        Self::for_locale(format::system_locale("LC_CTYPE").as_deref())
+    }
+
+    fn for_locale(locale: Option<&str>) -> Self {
+        let encoding = locale
+            .and_then(|locale| locale.split('.').nth(1))
+            .map(|encoding| {
+                encoding
+                    .split('@')
+                    .next()
+                    .unwrap_or(encoding)
+                    .to_lowercase()
+            });
+
+        match encoding.as_deref() {
+            Some("utf-8") | Some("utf8") => Self::UNICODE,
+            _ => Self::ASCII,
+        }
+    }
+}
+
+/// A store in a dependency tree, which is either a changed dependency or a store one was discovered through.
+#[derive(Default)]
+struct TreeNode<'a> {
+    diff: Option<&'a StoreDiff>,
+    children: Vec<(&'a str, TreeNode<'a>)>,
+}
+
+impl<'a> TreeNode<'a> {
+    fn child(&mut self, name: &'a str) -> &mut Self {
+        let pos = match self.children.iter().position(|(child, _)| *child == name) {
+            Some(pos) => pos,
+            None => {
+                self.children.push((name, Self::default()));
+                self.children.len() - 1
+            }
+        };
+
+        &mut self.children[pos].1
+    }
+
+    fn render<F>(&self, prefix: &str, chars: TreeChars, label: &F, lines: &mut Vec<String>)
+    where
+        F: Fn(&StoreDiff) -> String,
+    {
+        for (i, (name, child)) in self.children.iter().enumerate() {
+            let (mut branch, indent) = if i + 1 == self.children.len() {
+                (chars.last, chars.blank)
+            } else {
+                (chars.branch, chars.pipe)
+            };
+
+            // Unchanged stores that only lead to a single store aren't interesting on their own
+            let mut hops = Vec::new();
+            let (mut name, mut node) = (*name, child);
+
+            while node.diff.is_none() && node.children.len() == 1 {
+                hops.push(name);
+                let (next_name, next) = &node.children[0];
+                name = next_name;
+                node = next;
+            }
+
+            let mut line_prefix = prefix.to_string();
+            let mut child_prefix = format!("{}{}", prefix, indent);
+
+            if !hops.is_empty() {
+                let hop = match hops.as_slice() {
+                    [hop] => hop.to_string(),
+                    hops => format!(
+                        "{} {}",
+                        chars.ellipsis,
+                        format::locale().plural(hops.len(), "store", "stores")
+                    ),
+                };
+
+                lines.push(format!("{}{}{}", line_prefix, branch, hop));
+
+                line_prefix = child_prefix.clone();
+                child_prefix.push_str(chars.blank);
+                branch = chars.last;
+            }
+
+            let text = match node.diff {
+                Some(diff) => label(diff),
+                None => name.to_string(),
+            };
+
+            lines.push(format!("{}{}{}", line_prefix, branch, text));
+            node.render(&child_prefix, chars, label, lines);
+        }
+    }
+}
+
+/// Formats `deps` as the lines of a tree, where each dependency is placed under the stores it was
+/// discovered through in `paths`, and `label` formats each dependency.
+///
+/// Unchanged stores are only shown where the tree branches. Any other run of them is collapsed into a
+/// single line, which names the store when there is only one.
+fn format_dep_tree<F>(
+    deps: &[StoreDiff],
+    paths: &HashMap<String, Vec<String>>,
+    chars: TreeChars,
+    label: F,
+) -> Vec<String>
+where
+    F: Fn(&StoreDiff) -> String,
+{
+    let mut root = TreeNode::default();
+
+    for dep in deps {
+        let mut node = &mut root;
+
+        for name in paths.get(&dep.name).into_iter().flatten() {
+            node = node.child(name);
+        }
+
+        node.child(&dep.name).diff = Some(dep);
+    }
+
+    let mut lines = Vec::new();
+    root.render("", chars, &label, &mut lines);
+    lines
+}
+
+/// Formats `diff` on a single line, with its dependency changes summarized at the end.
+///
+/// The dependencies are summarized as a count, or as a short list of their names when `context` is set.
+fn format_compact(mut diff: PackageDiff, context: bool, sort: DepSort, header: Header) -> String {
+    let mut line = format_pkg_header(&diff, header);
+
+    if diff.deps.is_empty() {
+        return line;
+    }
+
+    let summary = if context {
+        sort_deps(&mut diff.deps, sort);
+
+        let mut names = diff
+            .deps
+            .iter()
+            .take(COMPACT_CONTEXT_DEPS)
+            .map(|dep| dep.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        if diff.deps.len() > COMPACT_CONTEXT_DEPS {
+            names.push_str(&format!(
+                ", +{} more",
+                format::locale().count(diff.deps.len() - COMPACT_CONTEXT_DEPS)
+            ));
+        }
+
+        names
+    } else {
+        format!(
+            "+{}",
+            format::locale().plural(diff.deps.len(), "dep", "deps")
+        )
+    };
+
+    line.push_str(&format!(
+        " {}",
+        format!("[{}]", summary).paint(Role::DepMarker)
+    ));
+    line
+}
+
+/// Formats the line that starts each package in the human formats, with the notes `header` asks for.
+fn format_pkg_header(diff: &PackageDiff, header: Header) -> String {
+    let mut line = match &diff.pkg {
+        Some(pkg) => format_store_diff(pkg, header.short),
+        None => diff.name.paint(Role::PackageName),
+    };
+
+    if header.reverted {
+        line = format!("{} {}", "reverted".paint(Role::Note), line);
+    }
+
+    let notes = format_notes(diff);
+
+    if !notes.is_empty() {
+        line.push_str(&format!(" {}", notes));
+    }
+
+    if let Some(outputs) = header.outputs {
+        line.push_str(&format_outputs(outputs));
+    }
+
+    if header.reverted {
+        line.push_str(&format!(" {}", "(rollback)".paint(Role::Note)));
+    }
+
+    if let Some(staleness) = header.staleness {
+        let note = format!(
+            "({} behind {})",
+            format::locale().plural(staleness.behind, "release", "releases"),
+            staleness.latest
+        );
+
+        line.push_str(&format!(" {}", note.paint(Role::Detail)));
+    }
+
+    if let Some(count) = diff.referrer_count.filter(|_| header.referrers) {
+        let note = format!("(referenced by {})", format::locale().count(count as usize));
+
+        // Nothing but a profile or GC root keeps an unreferenced package from being collected
+        let note = if count == 0 {
+            note.paint(Role::Note)
+        } else {
+            note.paint(Role::Detail)
+        };
+
+        line.push_str(&format!(" {}", note));
+    }
+
+    if let Some(status) = header.cache {
+        let note = match status {
+            CacheStatus::Cached {
+                file_size: Some(size),
+            } => format!("(cached, {})", format::locale().size(size)).paint(Role::Detail),
+            CacheStatus::Cached { file_size: None } => "(cached)".paint(Role::Detail),
+            CacheStatus::NotCached => "(not cached)".paint(Role::Note),
+            CacheStatus::Unknown => "(cache unknown)".paint(Role::Warning),
+        };
+
+        line.push_str(&format!(" {}", note));
+    }
+
+    if let Some(index) = header.nixpkgs {
+        if let Some(definition) = index.find(&diff.name) {
+            line.push_str(&format!(
+                " {}",
+                format_definition(index, definition).paint(Role::Detail)
+            ));
+        }
+    }
+
+    line
+}
+
+/// Formats where a package is defined in a nixpkgs checkout, with the line so editors can jump straight to it.
+fn format_definition(index: &nixpkgs::Index, definition: &nixpkgs::Definition) -> String {
+    let location = format!(
+        "{}:{}",
+        index.path_of(definition).display(),
+        definition.line
+    );
+
+    match &definition.attr {
+        Some(attr) => format!("({} at {})", attr, location),
+        None => format!("(defined at {})", location),
+    }
+}
+
+/// Returns every note about how `diff` was grouped with other packages, separated by spaces.
+fn format_notes(diff: &PackageDiff) -> String {
+    let mut notes = Vec::new();
+
+    if let Some(note) = format_wrapper_note(diff) {
+        notes.push(note);
+    }
+
+    if !diff.split_outputs.is_empty() {
+        let note = format!("(split into {})", diff.split_outputs.join(", "));
+        notes.push(note.paint(Role::Detail));
+    }
+
+    notes.join(" ")
+}
+
+/// Lists the other outputs of a package after its header, with the ones that have a different version than
+/// the output the header shows flagged on their own.
+fn format_outputs(outputs: &Outputs) -> String {
+    let name = |output: &Store| match &output.suffix {
+        Some(suffix) => format!("{}-{}", output.name, suffix),
+        None => output.name.clone(),
+    };
+
+    let (same, differing): (Vec<_>, Vec<_>) = outputs
+        .others
+        .iter()
+        .partition(|output| output.version == outputs.version);
+
+    let mut notes = String::new();
+
+    if !same.is_empty() {
+        let names = same.iter().map(|&output| name(output)).collect::<Vec<_>>();
+        let note = format!("(also {})", names.join(", "));
+        notes.push_str(&format!(" {}", note.paint(Role::Detail)));
+    }
+
+    if !differing.is_empty() {
+        let versions = differing
+            .iter()
+            .map(|&output| format!("{} is {}", name(output), output.version))
+            .collect::<Vec<_>>();
+
+        let note = format!("(inconsistent outputs: {})", versions.join(", "));
+        notes.push_str(&format!(" {}", note.paint(Role::Warning)));
+    }
+
+    notes
+}
+
+/// Describes which part of a merged wrapper pair changed.
+fn format_wrapper_note(diff: &PackageDiff) -> Option<String> {
+    let pair = diff.wrapper.as_ref()?;
+
+    let note = if pair.wrapped_changed {
+        let other = if pair.wrapper == diff.name {
+            &pair.wrapped
+        } else {
+            &pair.wrapper
+        };
+
+        format!("(merged with {})", other).paint(Role::Detail)
+    } else {
+        "(wrapper rebuilt)".paint(Role::Note)
+    };
+
+    Some(note)
+}
+
+fn sort_deps(deps: &mut [StoreDiff], sort: DepSort) {
+    match sort {
+        DepSort::Name => deps.sort_unstable_by(|x, y| x.name.cmp(&y.name)),
+        DepSort::Jump => deps.sort_by_cached_key(|dep| {
+            let jump = Version::parse(&dep.ver_from).jump(&Version::parse(&dep.ver_to));
+            (Reverse(jump), dep.name.clone())
+        }),
+    }
+}
+
+fn sys_pkg_sorter(new: &PackageDiff, old: &PackageDiff) -> Ordering {
+    match (&new.pkg, &old.pkg) {
+        (Some(_), Some(_)) | (None, None) => new
+            .deps
+            .len()
+            .cmp(&old.deps.len())
+            .then_with(|| new.name.cmp(&old.name)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+    }
+}
+
+fn format_ver_change(diff: &StoreDiff, short: Option<usize>) -> String {
+    let mut from = shorten_version(&diff.ver_from, short);
+    let mut to = shorten_version(&diff.ver_to, short);
+
+    // Versions that only differ after where they're cut off are shown in full, rather than as `x -> x`
+    if from == to {
+        from = Cow::Borrowed(&diff.ver_from);
+        to = Cow::Borrowed(&diff.ver_to);
+    }
+
+    format!(
+        "{} -> {}",
+        from.paint(Role::OldVersion),
+        bolden_str_diff(from.as_ref(), to.as_ref())
+    )
+}
+
+/// Cuts `version` off after `short` characters with an ellipsis, such as for versions that are git hashes.
+fn shorten_version(version: &str, short: Option<usize>) -> Cow<'_, str> {
+    match short {
+        Some(len) if version.chars().count() > len => {
+            let mut shortened = version.chars().take(len).collect::<String>();
+            shortened.push_str("...");
+            Cow::Owned(shortened)
+        }
+        _ => Cow::Borrowed(version),
+    }
+}
+
+/// Highlights the fields of `to` that differ from `from`, such as the `10` in `1.9.0 -> 1.10.0`.
+fn bolden_str_diff<S>(from: S, to: S) -> String
+where
+    S: AsRef<str>,
+{
+    diff_runs(from.as_ref(), to.as_ref())
+        .into_iter()
+        .map(|(text, changed)| {
+            let role = if changed {
+                Role::NewVersionChangedRun
+            } else {
+                Role::NewVersion
+            };
+
+            text.paint(role)
+        })
+        .collect()
+}
+
+/// Splits `to` into runs of fields that either all differ from `from` or all match it.
+///
+/// Fields are aligned with the fewest insertions, removals, and replacements, so a field that got longer doesn't
+/// shift every field after it, and fields like the year, month, and day of a date are compared on their own.
+fn diff_runs<'a>(from: &str, to: &'a str) -> Vec<(&'a str, bool)> {
+    let from_fields = version_fields(from);
+    let to_fields = version_fields(to);
+
+    // The number of edits needed to turn the first i fields of `from` into the first j fields of `to`
+    let mut edits = vec![vec![0; to_fields.len() + 1]; from_fields.len() + 1];
+    edits[0] = (0..=to_fields.len()).collect();
+
+    for (i, row) in edits.iter_mut().enumerate() {
+        row[0] = i;
+    }
+
+    for i in 1..=from_fields.len() {
+        for j in 1..=to_fields.len() {
+            let replace = edits[i - 1][j - 1] + (from_fields[i - 1] != to_fields[j - 1]) as usize;
+            edits[i][j] = replace.min(edits[i - 1][j] + 1).min(edits[i][j - 1] + 1);
+        }
+    }
+
+    // Walk back from the end, preferring replacements so fields are compared with the one in the same place
+    let mut changed = vec![false; to_fields.len()];
+    let (mut i, mut j) = (from_fields.len(), to_fields.len());
+
+    while j > 0 {
+        if i > 0 && from_fields[i - 1] == to_fields[j - 1] && edits[i][j] == edits[i - 1][j - 1] {
+            i -= 1;
+            j -= 1;
+        } else if i > 0 && edits[i][j] == edits[i - 1][j - 1] + 1 {
+            changed[j - 1] = true;
+            i -= 1;
+            j -= 1;
+        } else if edits[i][j] == edits[i][j - 1] + 1 {
+            changed[j - 1] = true;
+            j -= 1;
+        } else {
+            i -= 1;
+        }
+    }
+
+    // Neighbouring fields that are both changed or both unchanged are painted together
+    let mut runs = Vec::new();
+    let mut run_start = 0;
+    let mut pos = 0;
+
+    for (index, field) in to_fields.iter().enumerate() {
+        pos += field.len();
+
+        if changed.get(index + 1) != Some(&changed[index]) {
+            runs.push((&to[run_start..pos], changed[index]));
+            run_start = pos;
+        }
+    }
+
+    runs
+}
+
+/// Splits a version into its fields, which are runs of digits, runs of letters, and every other character on its own.
+fn version_fields(version: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+
+    for (pos, ch) in version.char_indices().skip(1) {
+        let prev = version[..pos].chars().next_back().unwrap_or(ch);
+
+        let same_field = (prev.is_ascii_digit() && ch.is_ascii_digit())
+            || (prev.is_alphabetic() && ch.is_alphabetic());
+
+        if !same_field {
+            fields.push(&version[start..pos]);
+            start = pos;
+        }
+    }
+
+    if start < version.len() {
+        fields.push(&version[start..]);
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::critical::CriticalList;
+    use crate::fleet;
+    use crate::query::Query;
+    use crate::store::Store;
+    use theme::{Preset, Theme};
+
+    type Case<'a> = (&'a str, &'a str, &'a [(&'a str, bool)]);
+
+    #[test]
+    fn format_errors() {
+        let err = Error::msg("permission denied")
+            .context("failed to open /nix/var/nix/db/db.sqlite")
+            .context("failed to open nix database");
+
+        assert_eq!(
+            format_error(&err, false),
+            "failed to open nix database: failed to open /nix/var/nix/db/db.sqlite: permission denied"
+        );
+
+        let detailed = format_error(&err, true);
+        assert!(detailed.starts_with("failed to open nix database\n"));
+        assert!(detailed.contains("Caused by:"));
+        assert!(detailed.contains("permission denied"));
+    }
+
+    #[test]
+    fn format_parse_traces() {
+        colored::control::set_override(false);
+
+        let trace =
+            ParseTrace::new("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-wine-wow-4.0-rc5-staging");
+
+        assert_eq!(
+            format_parse_trace(&trace),
+            [
+                "stripped: wine-wow-4.0-rc5-staging",
+                "fragments:",
+                "  wine     name",
+                "  wow      name",
+                "  4.0      version start",
+                "  rc5      version continuation",
+                "  staging  suffix",
+                "heuristics:",
+                "  the last fragment has no digits, so it is the suffix",
+                "name: wine-wow",
+                "version: 4.0-rc5",
+                "suffix: staging",
+            ]
+        );
+
+        let trace = ParseTrace::new("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-lib64");
+        let lines = format_parse_trace(&trace);
+        assert_eq!(
+            lines[lines.len() - 3..],
+            [
+                "heuristics:",
+                "  none",
+                "not parsed as a package, as no version was found"
+            ]
+        );
+
+        assert_eq!(
+            format_parse_trace(&ParseTrace::new("wine-wow-4.0")),
+            ["not a store path, as it doesn't start with a store directory and hash"]
+        );
+    }
+
+    #[test]
+    fn drop_emptied_diffs() {
+        let deriv = |name: &str, version: &str, deps: &[&str]| Derivation {
+            store: Store::parse_stripped(&format!("{}-{}", name, version)).unwrap(),
+            deps: deps
+                .iter()
+                .map(|dep| Store::parse_stripped(dep).unwrap())
+                .collect(),
+            paths: HashMap::new(),
+        };
+
+        let new = vec![
+            deriv("firefox", "123.0", &["firefox-unwrapped-123.0", "gtk-3.0"]),
+            deriv("firefox-unwrapped", "123.0", &["gtk-3.0"]),
+            deriv("mpv", "0.37", &["ffmpeg-6.1"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let old = vec![
+            deriv("firefox", "122.0", &["firefox-unwrapped-122.0", "gtk-3.0"]),
+            deriv("firefox-unwrapped", "122.0", &["gtk-3.0"]),
+            deriv("mpv", "0.37", &["ffmpeg-6.0"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let opts = DiffOptions {
+            scope: DiffScope::DepsOnly,
+            ..DiffOptions::default()
+        };
+
+        // The only dependency change of firefox is firefox-unwrapped, which merging the pair leaves out
+        let diffs = diff::get_package_diffs(&new, &old, opts);
+        assert_eq!(diffs.len(), 2);
+
+        let names = collect_diffs(&new, &old, opts, None)
+            .into_iter()
+            .map(|diff| diff.name)
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, ["mpv"]);
+
+        // Version changes are never dropped
+        let names = collect_diffs(&new, &old, DiffOptions::default(), None)
+            .into_iter()
+            .map(|diff| diff.name)
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, ["firefox", "mpv"]);
+    }
+
+    #[test]
+    fn filter_diffs_with_query() {
+        let deriv = |name: &str, version: &str, deps: &[&str]| Derivation {
+            store: Store::parse_stripped(&format!("{}-{}", name, version)).unwrap(),
+            deps: deps
+                .iter()
+                .map(|dep| Store::parse_stripped(dep).unwrap())
+                .collect(),
+            paths: HashMap::new(),
+        };
+
+        let new = vec![
+            deriv("pyright", "1.1.350", &["glibc-2.39", "nodejs-20.11.1"]),
+            deriv("openssl", "3.1.0", &[]),
+            deriv("mesa", "23.3.5", &["glibc-2.39"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let old = vec![
+            deriv("pyright", "1.1.349", &["glibc-2.38", "nodejs-20.11.0"]),
+            deriv("openssl", "3.0.13", &[]),
+            deriv("mesa", "24.0.1", &["glibc-2.38"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let critical = CriticalList::default();
+
+        let filtered = |expr: &str| {
+            let query = expr.parse::<Query>().unwrap();
+            let filter = Filter {
+                query: &query,
+                critical: &critical,
+            };
+
+            collect_diffs(&new, &old, DiffOptions::default(), Some(filter))
+                .into_iter()
+                .map(|diff| {
+                    let pkg = diff.pkg.map(|pkg| pkg.ver_to);
+                    let deps = diff.deps.into_iter().map(|dep| dep.name).collect();
+                    (diff.name, pkg, deps)
+                })
+                .collect::<Vec<(String, Option<String>, Vec<String>)>>()
+        };
+
+        let entry = |name: &str, pkg: Option<&str>, deps: &[&str]| {
+            let deps = deps.iter().map(|&dep| dep.to_string()).collect();
+            (name.to_string(), pkg.map(String::from), deps)
+        };
+
+        // Dependencies are tested on their own, so mesa is only kept for its critical glibc update
+        assert_eq!(
+            filtered("name ~ 'py*' or (critical and minor)"),
+            [
+                entry("openssl", Some("3.1.0"), &[]),
+                entry("pyright", Some("1.1.350"), &["glibc"]),
+                entry("mesa", None, &["glibc"]),
+            ]
+        );
+
+        assert_eq!(
+            filtered("kind = downgraded and not is_dep"),
+            [entry("mesa", Some("23.3.5"), &[])]
+        );
+
+        assert!(filtered("name = nodejs and not is_dep").is_empty());
+    }
+
+    #[test]
+    fn format_audits() {
+        colored::control::set_override(false);
+
+        let meta = StateMeta {
+            saved_at: 1_709_673_240,
+            ..StateMeta::default()
+        };
+
+        let rejects = Rejects {
+            unparsed: CappedList::new(
+                vec!["/nix/store/abc-fix\nline.patch".into(), "lib64".into()],
+                1,
+            ),
+            deduplicated: CappedList::default(),
+        };
+
+        assert_eq!(
+            format_audit(Path::new("packages.bin"), &meta, &rejects),
+            [
+                "audit: packages.bin left out when it was saved on 2024-03-05 21:14",
+                "",
+                "paths that couldn't be parsed (2):",
+                "  /nix/store/abc-fix line.patch",
+                "  ...and 1 more that weren't recorded",
+                "",
+                "packages with discarded duplicates or older versions (0):",
+                "  none",
+            ]
+        );
+    }
+
+    #[test]
+    fn format_verifications() {
+        colored::control::set_override(false);
+
+        let path = Path::new("packages.bin");
+        let store = |name: &str| Store::parse_stripped(name).unwrap();
+
+        let verification = Verification {
+            packages: 120,
+            deps: 2_000,
+            missing_packages: vec![store("openssl-3.0.12")],
+            missing_deps: vec![store("glibc-2.38-bin"), store("zlib-1.3")],
+        };
+
+        assert_eq!(
+            format_verification(path, &verification, false),
+            [
+                "verify: 1 of 120 packages and 2 of 2000 dependencies in packages.bin are no longer in the nix store",
+                "run with --verbose to list them",
+            ]
+        );
+
+        assert_eq!(
+            format_verification(path, &verification, true)[1..],
+            [
+                "",
+                "missing packages:",
+                "  openssl 3.0.12",
+                "",
+                "missing dependencies:",
+                "  glibc {bin} 2.38",
+                "  zlib 1.3",
+            ]
+        );
+
+        let intact = Verification {
+            packages: 1,
+            deps: 0,
+            ..Verification::default()
+        };
+
+        assert_eq!(
+            format_verification(path, &intact, true),
+            ["verify: every one of the 1 package and 0 dependencies in packages.bin is still in the nix store"]
+        );
+    }
+
+    #[test]
+    fn format_fleets() {
+        colored::control::set_override(false);
+
+        let mut hosts = fleet::fixture::fleet();
+        hosts.push(fleet::fixture::broken("delta"));
+
+        let matrix = Matrix::new(&hosts);
+
+        assert_eq!(
+            format_fleet(&matrix, 80, TreeChars::UNICODE),
+            [
+                "fleet: 4 packages differ across 4 hosts, and 2 hosts are behind on at least one",
+                "",
+                "package  alpha   beta    gamma   delta",
+                "firefox  124.0   123.0*  124.0   ?",
+                "git      2.44.0  2.44.0  -       ?",
+                "openssl  3.0.13  3.0.13  3.0.9*  ?",
+                "vim      -       -       9.1     ?",
+                "",
+                "* is behind the newest version in the fleet, - is missing, ? couldn't be loaded",
+            ]
+        );
+
+        // Hosts that don't fit are left out, but the first one is always shown
+        let narrow = format_fleet(&matrix, 24, TreeChars::ASCII);
+        assert_eq!(narrow[2], "package  alpha   beta");
+        assert_eq!(narrow[3], "firefox  124.0   123.0*");
+        assert_eq!(
+            narrow[9],
+            "...and 2 more hosts that didn't fit, which --json includes"
+        );
+
+        assert_eq!(
+            format_fleet(&matrix, 0, TreeChars::ASCII)[2],
+            "package  alpha"
+        );
+
+        let same = Matrix::new(&[fleet::fixture::host("alpha", &[("git", "2.44.0")])]);
+        assert_eq!(
+            format_fleet(&same, 80, TreeChars::ASCII),
+            ["fleet: every host that was loaded has the same packages"]
+        );
+    }
+
+    #[test]
+    fn truncate_columns() {
+        assert_eq!(truncate("unstable-2024-01-01", 10, "…"), "unstable-…");
+        assert_eq!(truncate("unstable-2024-01-01", 10, "..."), "unstabl...");
+        assert_eq!(truncate("9.1", 10, "..."), "9.1");
+        assert_eq!(truncate("unstable", 2, "..."), "un");
+    }
+
+    #[test]
+    fn format_wave_headers() {
+        colored::control::set_override(false);
+
+        let wave = Wave {
+            start: 1_709_673_240,
+            diffs: Vec::new(),
+        };
+
+        assert_eq!(
+            format_wave_header(2, &wave),
+            "wave 2 — 2024-03-05 21:14, 0 packages"
+        );
+    }
+
+    #[test]
+    fn format_parser_changes() {
+        colored::control::set_override(false);
+
+        let changes = [ParseChange {
+            entry: "ffmpeg-3.4.5-bin".into(),
+            old: "ffmpeg 3.4.5-bin".into(),
+            new: "ffmpeg 3.4.5 {bin}".into(),
+        }];
+
+        let lines = format_parser_changed(Some("0.1.0"), &changes);
+        assert_eq!(lines.len(), 3);
+        assert!(
+            lines[0].starts_with(
+                "the baseline was saved with nixup 0.1.0, which parses 1 store name differently"
+            ),
+            "{}",
+            lines[0]
+        );
+        assert_eq!(
+            lines[1],
+            "  ffmpeg-3.4.5-bin: ffmpeg 3.4.5-bin -> ffmpeg 3.4.5 {bin}"
+        );
+        assert!(lines[2].contains("with -s"));
+
+        let lines = format_parser_changed(None, &changes);
+        assert!(lines[0].starts_with("the baseline was saved with an older nixup,"));
+    }
+
+    #[test]
+    fn format_explanations() {
+        colored::control::set_override(false);
+
+        let link = |id, name: &str| Link {
+            id,
+            name: name.into(),
+            store: Store::parse(
+                id as u32,
+                0,
+                format!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-{}", name),
+            ),
+        };
+
+        let system = link(1, "nixos-system-host-24.05");
+        let path = link(2, "system-path");
+        let wrapper = link(3, "firefox-121.0");
+        let unwrapped = link(4, "firefox-unwrapped-121.0");
+        let lib = link(5, "firefox-121.0-lib");
+
+        let explanation = Explanation {
+            chains: vec![
+                vec![system.clone(), path.clone(), wrapper.clone(), unwrapped],
+                vec![system.clone(), path, wrapper, lib],
+                vec![system, link(6, "etc")],
+            ],
+            truncated: true,
+        };
+
+        assert_eq!(
+            format_explanation(&explanation),
+            [
+                "nixos-system-host 24.05",
+                "  system-path",
+                "    firefox 121.0",
+                "      firefox-unwrapped 121.0",
+                "      firefox 121.0 {lib}",
+                "  etc",
+                "only the first 50 chains are shown",
+            ]
+        );
+    }
+
+    #[test]
+    fn format_removals() {
+        colored::control::set_override(false);
+
+        let removal = RemovedPackage {
+            name: "chromium".into(),
+            version: "120.0".into(),
+            gone: vec!["libva".into()],
+            retained: vec!["glibc".into(), "nss".into()],
+        };
+
+        assert_eq!(
+            format_removal(&removal, None, false),
+            ["chromium 120.0  (1 dependency also gone, 2 retained)"]
+        );
+
+        assert_eq!(
+            format_removal(&removal, None, true),
+            [
+                "chromium 120.0  (1 dependency also gone, 2 retained)",
+                "  gone: libva",
+                "  retained: glibc, nss",
+            ]
+        );
+
+        let removal = RemovedPackage {
+            gone: Vec::new(),
+            retained: Vec::new(),
+            ..removal
+        };
+
+        assert_eq!(format_removal(&removal, None, true), ["chromium 120.0"]);
+
+        assert_eq!(
+            format_removal(&removal, Some("chromium-ng"), true),
+            ["chromium 120.0 -> chromium-ng (renamed?)"]
+        );
+    }
+
+    #[test]
+    fn diff_version_fields() {
+        let cases: [Case; 6] = [
+            (
+                "8.4.0",
+                "8.5.0",
+                &[("8.", false), ("5", true), (".0", false)],
+            ),
+            (
+                "1.9.0",
+                "1.10.0",
+                &[("1.", false), ("10", true), (".0", false)],
+            ),
+            (
+                "2016-08-26",
+                "2019-02-15",
+                &[
+                    ("2019", true),
+                    ("-", false),
+                    ("02", true),
+                    ("-", false),
+                    ("15", true),
+                ],
+            ),
+            (
+                "2019-02-15",
+                "2019-03-15",
+                &[("2019-", false), ("03", true), ("-15", false)],
+            ),
+            (
+                "4.0-rc5",
+                "4.0.1-rc5",
+                &[("4.0", false), (".1", true), ("-rc5", false)],
+            ),
+            ("1.0", "1.0", &[("1.0", false)]),
+        ];
+
+        for (from, to, expected) in &cases {
+            assert_eq!(diff_runs(from, to), *expected, "{} -> {}", from, to);
+        }
+
+        assert!(diff_runs("1.0", "").is_empty());
+        assert_eq!(diff_runs("", "1.0"), [("1.0", true)]);
+    }
+
+    #[test]
+    fn paint_date_version_diff() {
+        let theme = Theme::preset(Preset::Default);
+
+        let diff = StoreDiff {
+            name: "steam-runtime".into(),
+            suffix: None,
+            suffix_from: None,
+            ver_from: "2016-08-26".into(),
+            ver_to: "2019-02-15".into(),
+            register_time: 0,
+        };
+
+        let line = theme::scoped(theme, || format_ver_change(&diff, None));
+
+        let red = |text| format!("\x1b[31m{}\x1b[0m", text);
+        let green = |text| format!("\x1b[32m{}\x1b[0m", text);
+        let changed = |text| format!("\x1b[4;92m{}\x1b[0m", text);
+
+        assert_eq!(
+            line,
+            format!(
+                "{} -> {}{}{}{}{}",
+                red("2016-08-26"),
+                changed("2019"),
+                green("-"),
+                changed("02"),
+                green("-"),
+                changed("15")
+            )
+        );
+    }
+
+    #[test]
+    fn tally_changes() {
+        colored::control::set_override(false);
+
+        let diff = |name: &str, from: &str, to: &str| PackageDiff {
+            name: name.into(),
+            pkg: Some(StoreDiff {
+                name: name.into(),
+                suffix: None,
+                suffix_from: None,
+                ver_from: from.into(),
+                ver_to: to.into(),
+                register_time: 0,
+            }),
+            deps: Vec::new(),
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: None,
+        };
+
+        let deps_only = PackageDiff {
+            pkg: None,
+            ..diff("hello", "", "")
+        };
+
+        let diffs = [
+            diff("firefox", "121.0", "122.0"),
+            diff("mesa", "24.0.1", "23.3.5"),
+            deps_only,
+        ];
+
+        let counts = DiffCounts {
+            added: 2,
+            ..DiffCounts::default()
+        };
+
+        let tally = Tally::new(&diffs, counts, 0);
+
+        assert_eq!(
+            tally,
+            Tally {
+                upgraded: 2,
+                downgraded: 1,
+                added: 2,
+                removed: 0,
+                rebuilt: 0,
+            }
+        );
+
+        assert_eq!(
+            tally.format().as_deref(),
+            Some("2 upgraded, 1 downgraded, 2 added")
+        );
+
+        let rebuilt = Tally::new(&[], DiffCounts::default(), 3);
+        assert_eq!(rebuilt.format().as_deref(), Some("3 rebuilt"));
+        assert_eq!(Tally::default().format(), None);
+    }
+
+    #[test]
+    fn specialisation_headings() {
+        colored::control::set_override(false);
+
+        assert_eq!(
+            format_specialisation_heading("on-the-go", 3),
+            "specialisation 'on-the-go': 3 changes"
+        );
+        assert_eq!(
+            format_specialisation_heading("gaming", 1),
+            "specialisation 'gaming': 1 change"
+        );
+        assert_eq!(
+            format_specialisation_heading("gaming", 0),
+            "specialisation 'gaming': no changes"
+        );
+    }
+
+    #[test]
+    fn group_package_variants() {
+        colored::control::set_override(false);
+
+        let diff = |name: &str| PackageDiff {
+            name: name.into(),
+            pkg: None,
+            deps: Vec::new(),
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: None,
+        };
+
+        let diffs = vec![
+            diff("nss@3.98"),
+            diff("firefox"),
+            diff("nss@3.96"),
+            diff("nss-tools"),
+        ];
+
+        let groups = group_variants(diffs)
+            .into_iter()
+            .map(|group| group.into_iter().map(|diff| diff.name).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            groups,
+            [
+                vec!["nss@3.98", "nss@3.96"],
+                vec!["firefox"],
+                vec!["nss-tools"]
+            ]
+        );
+
+        assert_eq!(format_variants_header("nss", 2), "nss (2 variants)");
+    }
+
+    #[test]
+    fn shorten_versions() {
+        colored::control::set_override(false);
+
+        let store_diff = |from: &str, to: &str| StoreDiff {
+            name: "helix".into(),
+            suffix: None,
+            suffix_from: None,
+            ver_from: from.into(),
+            ver_to: to.into(),
+            register_time: 0,
+        };
+
+        let hashes = store_diff(
+            "0c5f8e2d4b7a91e3f6d8c2b5a7e9f1d3c6b8a0e2",
+            "9a1b7c3d5e2f4a6b8c0d1e3f5a7b9c2d4e6f8a1b",
+        );
+
+        assert_eq!(
+            format_store_diff(&hashes, Some(SHORT_VERSION_LEN)),
+            "helix: 0c5f8e2d4b7a... -> 9a1b7c3d5e2f..."
+        );
+        assert_eq!(
+            format_store_diff(&hashes, None),
+            "helix: 0c5f8e2d4b7a91e3f6d8c2b5a7e9f1d3c6b8a0e2 -> 9a1b7c3d5e2f4a6b8c0d1e3f5a7b9c2d4e6f8a1b"
+        );
+
+        // Short versions are left alone, even when the other one is cut off
+        assert_eq!(
+            format_store_diff(&store_diff("23.10", "unstable-2024-03-05"), Some(8)),
+            "helix: 23.10 -> unstable..."
+        );
+
+        // Versions that would look the same once cut off are shown in full
+        assert_eq!(
+            format_store_diff(
+                &store_diff("unstable-2024-03-05", "unstable-2024-03-09"),
+                Some(8)
+            ),
+            "helix: unstable-2024-03-05 -> unstable-2024-03-09"
+        );
+
+        let mut suffix_change = store_diff("0c5f8e2d4b7a91e3", "0c5f8e2d4b7a91e3");
+        suffix_change.suffix = Some("bin".into());
+
+        assert_eq!(
+            format_store_diff(&suffix_change, Some(4)),
+            "helix: (none) -> bin (0c5f...)"
+        );
+    }
+
+    #[test]
+    fn format_package_headers() {
+        colored::control::set_override(false);
+
+        let diff = PackageDiff {
+            name: "firefox".into(),
+            pkg: Some(StoreDiff {
+                name: "firefox".into(),
+                suffix: None,
+                suffix_from: None,
+                ver_from: "121.0".into(),
+                ver_to: "120.0".into(),
+                register_time: 0,
+            }),
+            deps: Vec::new(),
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: Some(0),
+        };
+
+        let staleness = Staleness {
+            behind: 2,
+            latest: "122.0".into(),
+        };
+
+        let reverted = Header {
+            reverted: true,
+            ..Header::default()
+        };
+
+        assert_eq!(
+            format_pkg_header(&diff, Header::default()),
+            "firefox: 121.0 -> 120.0"
+        );
+        assert_eq!(
+            format_pkg_header(&diff, reverted),
+            "reverted firefox: 121.0 -> 120.0 (rollback)"
+        );
+        assert_eq!(
+            format_pkg_header(
+                &diff,
+                Header {
+                    staleness: Some(&staleness),
+                    referrers: true,
+                    ..Header::default()
+                }
+            ),
+            "firefox: 121.0 -> 120.0 (2 releases behind 122.0) (referenced by 0)"
+        );
+        assert_eq!(
+            format_compact(diff, false, DepSort::Name, reverted),
+            "reverted firefox: 121.0 -> 120.0 (rollback)"
+        );
+    }
+
+    #[test]
+    fn format_cache_statuses() {
+        colored::control::set_override(false);
+
+        let diff = PackageDiff {
+            name: "firefox".into(),
+            pkg: Some(StoreDiff {
+                name: "firefox".into(),
+                suffix: None,
+                suffix_from: None,
+                ver_from: "121.0".into(),
+                ver_to: "122.0".into(),
+                register_time: 0,
+            }),
+            deps: Vec::new(),
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: None,
+        };
+
+        let header = |status| Header {
+            cache: Some(status),
+            ..Header::default()
+        };
+
+        assert_eq!(
+            format_pkg_header(
+                &diff,
+                header(CacheStatus::Cached {
+                    file_size: Some(3 * 1024 * 1024)
+                })
+            ),
+            "firefox: 121.0 -> 122.0 (cached, 3.0 MiB)"
+        );
+        assert_eq!(
+            format_pkg_header(&diff, header(CacheStatus::NotCached)),
+            "firefox: 121.0 -> 122.0 (not cached)"
+        );
+        assert_eq!(
+            format_pkg_header(&diff, header(CacheStatus::Unknown)),
+            "firefox: 121.0 -> 122.0 (cache unknown)"
+        );
+
+        let url = "https://cache.nixos.org";
+
+        assert_eq!(
+            format_cache_summary(
+                url,
+                CacheSummary {
+                    cached: 12,
+                    not_cached: 1,
+                    unknown: 2,
+                    download_size: 5 * 1024,
+                }
+            ),
+            "binary cache https://cache.nixos.org: 12 of 15 new paths are cached with 5.0 KiB to download, 1 has to be built, 2 couldn't be checked"
+        );
+        assert_eq!(
+            format_cache_summary(
+                url,
+                CacheSummary {
+                    not_cached: 2,
+                    ..CacheSummary::default()
+                }
+            ),
+            "binary cache https://cache.nixos.org: 0 of 2 new paths are cached, 2 have to be built"
+        );
+        assert_eq!(
+            format_cache_summary(url, CacheSummary::default()),
+            "binary cache https://cache.nixos.org: no new paths to check"
+        );
+    }
+
+    #[test]
+    fn format_output_notes() {
+        colored::control::set_override(false);
+
+        let diff = PackageDiff {
+            name: "openssl".into(),
+            pkg: Some(StoreDiff {
+                name: "openssl".into(),
+                suffix: None,
+                suffix_from: None,
+                ver_from: "3.0.12".into(),
+                ver_to: "3.0.13".into(),
+                register_time: 0,
+            }),
+            deps: Vec::new(),
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: None,
+        };
+
+        let output = |name: &str| Store::parse_stripped(name).unwrap();
+
+        let consistent = Outputs {
+            version: "3.0.13".into(),
+            others: vec![output("openssl-3.0.13-bin"), output("openssl-3.0.13-dev")],
+            inconsistent: false,
+        };
+
+        assert_eq!(
+            format_pkg_header(
+                &diff,
+                Header {
+                    outputs: Some(&consistent),
+                    ..Header::default()
+                }
+            ),
+            "openssl: 3.0.12 -> 3.0.13 (also openssl-bin, openssl-dev)"
+        );
+
+        // The headline only has the version of one output, so the others that disagree with it are flagged
+        let inconsistent = Outputs {
+            version: "3.0.13".into(),
+            others: vec![output("openssl-3.0.12-bin"), output("openssl-3.0.13-dev")],
+            inconsistent: true,
+        };
+
+        assert_eq!(
+            format_pkg_header(
+                &diff,
+                Header {
+                    outputs: Some(&inconsistent),
+                    ..Header::default()
+                }
+            ),
+            "openssl: 3.0.12 -> 3.0.13 (also openssl-dev) (inconsistent outputs: openssl-bin is 3.0.12)"
+        );
+    }
+
+    /// Returns the parameters of every escape sequence in `text`.
+    fn escape_params(text: &str) -> Vec<&str> {
+        text.split("\x1b[")
+            .skip(1)
+            .filter_map(|seq| seq.split('m').next())
+            .flat_map(|params| params.split(';'))
+            .collect()
+    }
+
+    fn themed_diff() -> PackageDiff {
+        PackageDiff {
+            name: "firefox".into(),
+            pkg: Some(StoreDiff {
+                name: "firefox".into(),
+                suffix: Some("bin".into()),
+                suffix_from: Some("bin".into()),
+                ver_from: "121.0".into(),
+                ver_to: "122.0".into(),
+                register_time: 0,
+            }),
+            deps: Vec::new(),
+            wrapper: None,
+            split_outputs: vec!["firefox-bin".into()],
+            referrer_count: Some(0),
+        }
+    }
+
+    #[test]
+    fn paint_colorblind_theme() {
+        let diff = themed_diff();
+        let theme = Theme::preset(Preset::Colorblind);
+
+        let header = Header {
+            reverted: true,
+            ..Header::default()
+        };
+
+        let line = theme::scoped(theme, || format_pkg_header(&diff, header));
+
+        let bold = |text| format!("\x1b[1m{}\x1b[0m", text);
+        let orange = |text| format!("\x1b[38;2;230;159;0m{}\x1b[0m", text);
+        let blue = |text| format!("\x1b[38;2;0;114;178m{}\x1b[0m", text);
+        let changed = |text| format!("\x1b[1;4;38;2;86;180;233m{}\x1b[0m", text);
+
+        let expected = format!(
+            "{} {}{}: {} -> {}{} {} {}",
+            orange("reverted"),
+            bold("firefox"),
+            bold(" {bin}"),
+            orange("121.0"),
+            changed("122"),
+            blue(".0"),
+            "\x1b[2m(split into firefox-bin)\x1b[0m",
+            orange("(rollback)")
+        );
+
+        assert_eq!(line, expected);
+    }
+
+    #[test]
+    fn paint_mono_theme() {
+        let theme = Theme::preset(Preset::Mono);
+
+        let lines = theme::scoped(theme, || {
+            let header = Header {
+                reverted: true,
+                referrers: true,
+                ..Header::default()
+            };
+
+            let tally = Tally {
+                upgraded: 1,
+                downgraded: 1,
+                added: 1,
+                removed: 1,
+                rebuilt: 1,
+            };
+
+            let trace = ParseTrace::new(
+                "/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-wine-wow-4.0-rc5-staging",
+            );
+
+            let mut lines = format_parse_trace(&trace);
+            lines.push(format_pkg_header(&themed_diff(), header));
+            lines.push(format_compact(themed_diff(), true, DepSort::Name, header));
+            lines.push(tally.format().unwrap());
+            lines
+        });
+
+        let params = lines
+            .iter()
+            .flat_map(|line| escape_params(line))
+            .collect::<Vec<_>>();
+
+        // Bold and underlined text is still styled, but nothing has a color
+        assert!(params.contains(&"1") && params.contains(&"4"));
+        assert!(
+            params.iter().all(|param| ["0", "1", "4"].contains(param)),
+            "{:?}",
+            params
+        );
+    }
+
+    #[test]
+    fn tree_chars_for_locale() {
+        assert_eq!(
+            TreeChars::for_locale(Some("en_US.UTF-8")),
+            TreeChars::UNICODE
+        );
+        assert_eq!(
+            TreeChars::for_locale(Some("de_DE.utf8@euro")),
+            TreeChars::UNICODE
+        );
+        assert_eq!(
+            TreeChars::for_locale(Some("en_US.ISO-8859-1")),
+            TreeChars::ASCII
+        );
+        assert_eq!(TreeChars::for_locale(Some("C")), TreeChars::ASCII);
+        assert_eq!(TreeChars::for_locale(None), TreeChars::ASCII);
+    }
+
+    #[test]
+    fn render_dep_tree() {
+        use crate::store::database::fixture;
+        use crate::store::dedup::DedupPolicy;
+        use crate::store::{DepOptions, Store};
+
+        let db = fixture::empty();
+
+        let paths = [
+            (1, "firefox-123.0"),
+            (2, "nss-3.98"),
+            (3, "gtk+3-3.24"),
+            (4, "pango-1.50"),
+            (5, "glibc-2.39"),
+            (6, "cairo-1.18"),
+            (7, "libx-1.0"),
+            (8, "liby-1.0"),
+            (9, "zlib-1.3"),
+        ];
+
+        for (id, name) in &paths {
+            fixture::add_path(&db, *id, name, 100);
+        }
+
+        // firefox -> nss is direct, firefox -> gtk -> pango -> glibc is 3 deep, and
+        // firefox -> libx -> liby -> zlib has two unchanged stores in between
+        for (referrer, reference) in &[
+            (1, 2),
+            (1, 3),
+            (3, 4),
+            (4, 5),
+            (3, 6),
+            (1, 7),
+            (7, 8),
+            (8, 9),
+        ] {
+            fixture::add_ref(&db, *referrer, *reference);
+        }
+
+        let opts = DepOptions {
+            max_depth: None,
+            ..DepOptions::default()
+        };
+
+        let stores = Store::all_from_system(&db, &Budget::unlimited(), DedupPolicy::Drop).unwrap();
+        let firefox = stores.get("firefox").cloned().into_iter().collect();
+
+        let (cur, _) =
+            Derivation::all_from_stores(firefox, &db, opts, &Budget::unlimited()).unwrap();
+        let pkg = cur.get("firefox").unwrap();
+
+        // Every dependency except the unchanged intermediate ones had an older version
+        let old_deps = pkg
+            .deps
+            .iter()
+            .map(|dep| {
+                let version = match dep.name.as_str() {
+                    "gtk+3" | "pango" | "libx" | "liby" => dep.version.clone(),
+                    _ => "0.1".into(),
+                };
+
+                Store {
+                    version,
+                    ..dep.clone()
+                }
+            })
+            .collect();
+
+        let mut old = HashSet::new();
+        old.insert(Derivation {
+            store: pkg.store.clone(),
+            deps: old_deps,
+            paths: HashMap::new(),
+        });
+
+        let mut diffs = diff::get_package_diffs(&cur, &old, DiffOptions::default());
+        assert_eq!(diffs.len(), 1);
+
+        let mut deps = diffs.remove(0).deps;
+        sort_deps(&mut deps, DepSort::Name);
+
+        let label = |dep: &StoreDiff| format!("{}: {} -> {}", dep.name, dep.ver_from, dep.ver_to);
+
+        assert_eq!(
+            format_dep_tree(&deps, &pkg.paths, TreeChars::ASCII, label),
+            [
+                "|-- gtk+3",
+                "|   |-- cairo: 0.1 -> 1.18",
+                "|   `-- pango",
+                "|       `-- glibc: 0.1 -> 2.39",
+                "|-- nss: 0.1 -> 3.98",
+                "`-- ... 2 stores",
+                "    `-- zlib: 0.1 -> 1.3",
+            ]
+        );
+
+        assert_eq!(
+            format_dep_tree(&deps, &pkg.paths, TreeChars::UNICODE, label)[..2],
+            ["├── gtk+3", "│   ├── cairo: 0.1 -> 1.18"]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use super::format;
+use crate::store::diff::PackageDiff;
+use anyhow::Result;
+use std::io::Write;
+
+/// The most packages named in the body of a notification.
+const MAX_ENTRIES: usize = 5;
+
+/// The most characters in the body of a notification, as notification daemons tend to cut off or hide
+/// anything past a few lines.
+const MAX_BODY_LEN: usize = 200;
+
+/// Writes a summary of `diffs` as exactly two lines: a title, and a body naming the first few changed packages.
+///
+/// This is meant to be passed straight to a notifier, such as with `notify-send "$(head -1)" "$(tail -n +2)"`.
+pub fn write_notification<W: Write>(
+    mut out: W,
+    diffs: &[PackageDiff],
+    removed: usize,
+) -> Result<()> {
+    let (title, body) = format_notification(diffs, removed);
+
+    writeln!(out, "{}", title)?;
+    writeln!(out, "{}", body)?;
+    Ok(())
+}
+
+fn format_notification(diffs: &[PackageDiff], removed: usize) -> (String, String) {
+    let locale = format::locale();
+
+    let mut title = match diffs.len() {
+        0 => "no package updates".to_string(),
+        num => format!("{} updated", locale.plural(num, "package", "packages")),
+    };
+
+    if removed > 0 {
+        title.push_str(&format!(", {} removed", locale.count(removed)));
+    }
+
+    if diffs.is_empty() {
+        let body = if removed > 0 {
+            "no package has a different version than in the saved state"
+        } else {
+            "every package has the same version as in the saved state"
+        };
+
+        return (title, body.into());
+    }
+
+    // Packages whose own version changed are more interesting than ones where only a dependency did
+    let entries = diffs
+        .iter()
+        .filter(|diff| diff.pkg.is_some())
+        .chain(diffs.iter().filter(|diff| diff.pkg.is_none()))
+        .map(format_entry)
+        .collect::<Vec<_>>();
+
+    (title, format_body(&entries))
+}
+
+/// Describes a single package, such as `firefox 123.0 -> 124.0`, or `glibc (2 dependencies)`.
+fn format_entry(diff: &PackageDiff) -> String {
+    match &diff.pkg {
+        Some(pkg) => format!("{} {} -> {}", diff.name, pkg.ver_from, pkg.ver_to),
+        None => format!(
+            "{} ({})",
+            diff.name,
+            format::locale().plural(diff.deps.len(), "dependency", "dependencies")
+        ),
+    }
+}
+
+/// Joins as many of `entries` as fit within `MAX_ENTRIES` and `MAX_BODY_LEN`, followed by how many were left out.
+fn format_body(entries: &[String]) -> String {
+    let overflow = |num: usize| format!(", and {} more", format::locale().count(num));
+
+    let mut body = String::new();
+    let mut shown = 0;
+
+    for entry in entries.iter().take(MAX_ENTRIES) {
+        let separator = if shown > 0 { ", " } else { "" };
+
+        // Room has to be left to say how many were left out, unless this is the last entry
+        let reserved = match entries.len() - shown - 1 {
+            0 => 0,
+            left_out => overflow(left_out).chars().count(),
+        };
+
+        let len = body.chars().count() + separator.len() + entry.chars().count() + reserved;
+
+        if len > MAX_BODY_LEN {
+            // A first entry that doesn't fit is cut off, rather than leaving nothing but the overflow
+            if shown == 0 {
+                body.push_str(&super::truncate(entry, MAX_BODY_LEN - reserved, "..."));
+                shown = 1;
+            }
+
+            break;
+        }
+
+        body.push_str(separator);
+        body.push_str(entry);
+        shown += 1;
+    }
+
+    if shown < entries.len() {
+        body.push_str(&overflow(entries.len() - shown));
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::diff::StoreDiff;
+
+    fn store_diff(name: &str, ver_from: &str, ver_to: &str) -> StoreDiff {
+        StoreDiff {
+            name: name.into(),
+            suffix: None,
+            suffix_from: None,
+            ver_from: ver_from.into(),
+            ver_to: ver_to.into(),
+            register_time: 0,
+        }
+    }
+
+    fn diff(name: &str, versions: Option<(&str, &str)>, num_deps: usize) -> PackageDiff {
+        PackageDiff {
+            name: name.into(),
+            pkg: versions.map(|(from, to)| store_diff(name, from, to)),
+            deps: (0..num_deps)
+                .map(|i| store_diff(&format!("dep{}", i), "1.0", "1.1"))
+                .collect(),
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: None,
+        }
+    }
+
+    fn notification(diffs: &[PackageDiff], removed: usize) -> String {
+        let mut out = Vec::new();
+        write_notification(&mut out, diffs, removed).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn format_notifications() {
+        let diffs = [
+            diff("glibc", None, 2),
+            diff("firefox", Some(("123.0", "124.0")), 1),
+            diff("git", Some(("2.43.0", "2.44.0")), 0),
+        ];
+
+        assert_eq!(
+            notification(&diffs, 0),
+            "3 packages updated\nfirefox 123.0 -> 124.0, git 2.43.0 -> 2.44.0, glibc (2 dependencies)\n"
+        );
+
+        assert_eq!(
+            notification(&diffs[..1], 2),
+            "1 package updated, 2 removed\nglibc (2 dependencies)\n"
+        );
+
+        assert_eq!(
+            notification(&[], 0),
+            "no package updates\nevery package has the same version as in the saved state\n"
+        );
+    }
+
+    #[test]
+    fn summarize_overflow() {
+        let diffs = (0..8)
+            .map(|i| diff(&format!("pkg{}", i), Some(("1.0", "2.0")), 0))
+            .collect::<Vec<_>>();
+
+        let (title, body) = format_notification(&diffs, 0);
+
+        assert_eq!(title, "8 packages updated");
+        assert_eq!(
+            body,
+            "pkg0 1.0 -> 2.0, pkg1 1.0 -> 2.0, pkg2 1.0 -> 2.0, pkg3 1.0 -> 2.0, pkg4 1.0 -> 2.0, and 3 more"
+        );
+
+        // Long versions leave room for fewer entries
+        let long = "0".repeat(60);
+
+        let diffs = (0..4)
+            .map(|i| diff(&format!("pkg{}", i), Some(("1.0", long.as_str())), 0))
+            .collect::<Vec<_>>();
+
+        let (_, body) = format_notification(&diffs, 0);
+
+        assert!(body.chars().count() <= MAX_BODY_LEN);
+        assert!(body.ends_with(", and 2 more"), "{}", body);
+
+        // A single entry that's too long on its own is cut off
+        let huge = "0".repeat(MAX_BODY_LEN * 2);
+        let (_, body) = format_notification(&[diff("pkg", Some(("1.0", &huge)), 0)], 0);
+
+        assert_eq!(body.chars().count(), MAX_BODY_LEN);
+        assert!(body.ends_with("..."));
+
+        // Every line ends up on its own, whatever the body holds
+        let lines = notification(&diffs, 0);
+        assert_eq!(lines.lines().count(), 2);
+    }
+}
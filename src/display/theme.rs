@@ -0,0 +1,546 @@
+use anyhow::{anyhow, Context, Error, Result};
+use colored::Color;
+use serde_derive::Deserialize;
+use std::borrow::Cow;
+#[cfg(test)]
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// The theme used by every human-facing format, set once at startup.
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// Sets the theme returned by `theme`.
+///
+/// Only the first call has any effect, so this should be called before anything is displayed.
+pub fn init(theme: Theme) {
+    let _ = THEME.set(theme);
+}
+
+/// Returns the theme set with `init`, or the default one if it was never set.
+pub fn theme() -> &'static Theme {
+    THEME.get_or_init(Theme::default)
+}
+
+/// Returns true if styles should be written at all, which is decided by the `no_colors` feature, the
+/// `NO_COLOR` and `CLICOLOR` environment variables, and whether stdout is a terminal.
+fn colorize() -> bool {
+    cfg!(not(feature = "no_colors")) && colored::control::SHOULD_COLORIZE.should_colorize()
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The theme set with `scoped` on the current thread.
+    static SCOPED: RefCell<Option<Theme>> = const { RefCell::new(None) };
+}
+
+/// Runs `func` with the current theme, and whether styles should be written.
+#[cfg(not(test))]
+fn with_current<F>(func: F) -> String
+where
+    F: FnOnce(&Theme, bool) -> String,
+{
+    func(theme(), colorize())
+}
+
+#[cfg(test)]
+fn with_current<F>(func: F) -> String
+where
+    F: FnOnce(&Theme, bool) -> String,
+{
+    SCOPED.with(|scoped| match &*scoped.borrow() {
+        Some(theme) => func(theme, true),
+        None => func(theme(), colorize()),
+    })
+}
+
+/// Runs `func` with everything painted on the current thread using `theme`, with styles always written.
+///
+/// This keeps tests from depending on the global theme and whether stdout is a terminal.
+#[cfg(test)]
+pub fn scoped<F, T>(theme: Theme, func: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    SCOPED.with(|scoped| *scoped.borrow_mut() = Some(theme));
+    let result = func();
+    SCOPED.with(|scoped| *scoped.borrow_mut() = None);
+    result
+}
+
+/// Styles text with `role`, as set by the current theme.
+pub trait Paint {
+    fn paint(&self, role: Role) -> String;
+}
+
+impl<T> Paint for T
+where
+    T: AsRef<str> + ?Sized,
+{
+    fn paint(&self, role: Role) -> String {
+        with_current(|theme, colorize| theme.style(role).paint_with(self.as_ref(), colorize))
+    }
+}
+
+/// What a piece of displayed text is, which decides how the theme styles it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Role {
+    PackageName,
+    OldVersion,
+    NewVersion,
+    /// The characters of a new version that differ from the old one.
+    NewVersionChangedRun,
+    /// The suffix of a store, such as `{bin}`.
+    SuffixTag,
+    /// What marks a line as a dependency, and the summary of a package's dependencies.
+    DepMarker,
+    /// Notes that deserve attention, such as a package being rebuilt or rolled back.
+    Note,
+    Warning,
+    Error,
+    /// The heading of the security-relevant changes.
+    CriticalSection,
+    Heading,
+    /// Notes that are only there for context, such as paths and timings.
+    Detail,
+    /// Counts and dates.
+    Value,
+    Success,
+    /// A message saved with a state.
+    Message,
+    Added,
+    Removed,
+    Rebuilt,
+}
+
+impl Role {
+    pub const ALL: [Self; 18] = [
+        Self::PackageName,
+        Self::OldVersion,
+        Self::NewVersion,
+        Self::NewVersionChangedRun,
+        Self::SuffixTag,
+        Self::DepMarker,
+        Self::Note,
+        Self::Warning,
+        Self::Error,
+        Self::CriticalSection,
+        Self::Heading,
+        Self::Detail,
+        Self::Value,
+        Self::Success,
+        Self::Message,
+        Self::Added,
+        Self::Removed,
+        Self::Rebuilt,
+    ];
+
+    /// Returns the name of the role in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PackageName => "package-name",
+            Self::OldVersion => "old-version",
+            Self::NewVersion => "new-version",
+            Self::NewVersionChangedRun => "new-version-changed-run",
+            Self::SuffixTag => "suffix-tag",
+            Self::DepMarker => "dep-marker",
+            Self::Note => "note",
+            Self::Warning => "warning",
+            Self::Error => "error",
+            Self::CriticalSection => "critical-section",
+            Self::Heading => "heading",
+            Self::Detail => "detail",
+            Self::Value => "value",
+            Self::Success => "success",
+            Self::Message => "message",
+            Self::Added => "added",
+            Self::Removed => "removed",
+            Self::Rebuilt => "rebuilt",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|role| role.name() == value)
+            .ok_or_else(|| {
+                let names = Self::ALL.iter().map(|role| role.name()).collect::<Vec<_>>();
+
+                anyhow!(
+                    "unknown role \"{}\", expected one of {}",
+                    value,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// The color and attributes of a role.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Style {
+    pub color: Option<Color>,
+    pub bold: bool,
+    pub dimmed: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl Style {
+    /// Returns the escape sequence that starts this style, or `None` if it's plain.
+    fn escape(&self) -> Option<String> {
+        let attributes = [
+            (self.bold, "1"),
+            (self.dimmed, "2"),
+            (self.italic, "3"),
+            (self.underline, "4"),
+        ];
+
+        let codes = attributes
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|&(_, code)| Cow::Borrowed(code))
+            .chain(self.color.map(|color| color.to_fg_str()))
+            .collect::<Vec<_>>();
+
+        if codes.is_empty() {
+            None
+        } else {
+            Some(format!("\x1b[{}m", codes.join(";")))
+        }
+    }
+
+    /// Styles `text`, or returns it as is if `colorize` isn't set.
+    pub fn paint_with(&self, text: &str, colorize: bool) -> String {
+        match self.escape().filter(|_| colorize) {
+            Some(escape) => format!("{}{}\x1b[0m", escape, text),
+            None => text.into(),
+        }
+    }
+}
+
+impl FromStr for Style {
+    type Err = Error;
+
+    /// Parses a style written as space-separated words, such as `bright-green underline` or `#e69f00 bold`.
+    ///
+    /// `plain` and an empty string are both a style without any color or attributes.
+    fn from_str(value: &str) -> Result<Self> {
+        let mut style = Self::default();
+
+        for word in value.split_whitespace() {
+            match word {
+                "plain" => (),
+                "bold" => style.bold = true,
+                "dimmed" => style.dimmed = true,
+                "italic" => style.italic = true,
+                "underline" => style.underline = true,
+                word => {
+                    let color = parse_color(word)?;
+
+                    if style.color.replace(color).is_some() {
+                        return Err(anyhow!("\"{}\" has more than one color", value));
+                    }
+                }
+            }
+        }
+
+        Ok(style)
+    }
+}
+
+fn parse_color(word: &str) -> Result<Color> {
+    if let Some(hex) = word.strip_prefix('#') {
+        let channel = |range| {
+            hex.get(range)
+                .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+        };
+
+        return match (hex.len(), channel(0..2), channel(2..4), channel(4..6)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Color::TrueColor { r, g, b }),
+            _ => Err(anyhow!(
+                "\"{}\" is not a hex color, which must be in the form of #rrggbb",
+                word
+            )),
+        };
+    }
+
+    let color = match word {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "white" => Color::White,
+        "bright-black" => Color::BrightBlack,
+        "bright-red" => Color::BrightRed,
+        "bright-green" => Color::BrightGreen,
+        "bright-yellow" => Color::BrightYellow,
+        "bright-blue" => Color::BrightBlue,
+        "bright-magenta" => Color::BrightMagenta,
+        "bright-cyan" => Color::BrightCyan,
+        "bright-white" => Color::BrightWhite,
+        _ => {
+            return Err(anyhow!(
+                "unknown color or attribute \"{}\", expected a color such as blue, bright-blue, or #0072b2, or one of bold, dimmed, italic, underline, and plain",
+                word
+            ))
+        }
+    };
+
+    Ok(color)
+}
+
+/// A built-in set of styles for every role.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    #[default]
+    Default,
+    /// Blue and orange instead of green and red, which stay distinct with the most common kinds of color blindness.
+    Colorblind,
+    /// Bold and underlined text only, without any colors.
+    Mono,
+}
+
+impl Preset {
+    /// Returns the style of every role that isn't plain.
+    fn styles(self) -> &'static [(Role, &'static str)] {
+        use Role::*;
+
+        match self {
+            Self::Default => &[
+                (PackageName, "blue"),
+                (OldVersion, "red"),
+                (NewVersion, "green"),
+                (NewVersionChangedRun, "bright-green underline"),
+                (SuffixTag, "blue bold"),
+                (DepMarker, "yellow"),
+                (Note, "yellow"),
+                (Warning, "yellow bold"),
+                (Error, "red bold"),
+                (CriticalSection, "red bold"),
+                (Heading, "bold"),
+                (Detail, "dimmed"),
+                (Value, "blue"),
+                (Success, "green"),
+                (Message, "italic"),
+                (Added, "blue"),
+                (Removed, "magenta"),
+                (Rebuilt, "yellow"),
+            ],
+            // The Okabe-Ito palette, which is distinguishable with every common kind of color blindness
+            Self::Colorblind => &[
+                (PackageName, "bold"),
+                (OldVersion, "#e69f00"),
+                (NewVersion, "#0072b2"),
+                (NewVersionChangedRun, "#56b4e9 bold underline"),
+                (SuffixTag, "bold"),
+                (DepMarker, "#e69f00"),
+                (Note, "#e69f00"),
+                (Warning, "#e69f00 bold"),
+                (Error, "#d55e00 bold"),
+                (CriticalSection, "#d55e00 bold"),
+                (Heading, "bold"),
+                (Detail, "dimmed"),
+                (Value, "#56b4e9"),
+                (Success, "#0072b2"),
+                (Message, "italic"),
+                (Added, "#56b4e9"),
+                (Removed, "#d55e00"),
+                (Rebuilt, "#f0e442"),
+            ],
+            Self::Mono => &[
+                (PackageName, "bold"),
+                (NewVersionChangedRun, "underline"),
+                (SuffixTag, "bold"),
+                (DepMarker, "bold"),
+                (Warning, "bold"),
+                (Error, "bold"),
+                (CriticalSection, "bold underline"),
+                (Heading, "bold"),
+            ],
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "default" => Ok(Self::Default),
+            "colorblind" => Ok(Self::Colorblind),
+            "mono" => Ok(Self::Mono),
+            _ => Err(anyhow!(
+                "unknown theme \"{}\", expected default, colorblind, or mono",
+                value
+            )),
+        }
+    }
+}
+
+/// The `[theme]` section of the config file, which picks a preset and overrides the style of any role.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ThemeSpec {
+    pub preset: Option<Preset>,
+    /// The style of each role by its name, such as `old-version = "#e69f00 bold"`.
+    #[serde(flatten)]
+    pub roles: BTreeMap<String, String>,
+}
+
+/// The style of every role.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    styles: [Style; Role::ALL.len()],
+}
+
+impl Theme {
+    pub fn preset(preset: Preset) -> Self {
+        let mut styles = [Style::default(); Role::ALL.len()];
+
+        for &(role, style) in preset.styles() {
+            styles[role as usize] = style.parse().expect("built-in style is invalid");
+        }
+
+        Self { styles }
+    }
+
+    /// Creates a theme from `spec`, where `preset` takes priority over the preset it picks.
+    pub fn new(preset: Option<Preset>, spec: &ThemeSpec) -> Result<Self> {
+        let mut theme = Self::preset(preset.or(spec.preset).unwrap_or_default());
+
+        for (name, style) in &spec.roles {
+            let role = name.parse::<Role>()?;
+
+            theme.styles[role as usize] = style
+                .parse()
+                .with_context(|| anyhow!("invalid style for {}", name))?;
+        }
+
+        Ok(theme)
+    }
+
+    pub fn style(&self, role: Role) -> Style {
+        self.styles[role as usize]
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::preset(Preset::Default)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_styles() {
+        assert_eq!(
+            "bright-green underline".parse::<Style>().unwrap(),
+            Style {
+                color: Some(Color::BrightGreen),
+                underline: true,
+                ..Style::default()
+            }
+        );
+
+        assert_eq!(
+            "#E69F00 bold".parse::<Style>().unwrap(),
+            Style {
+                color: Some(Color::TrueColor {
+                    r: 230,
+                    g: 159,
+                    b: 0
+                }),
+                bold: true,
+                ..Style::default()
+            }
+        );
+
+        assert_eq!("plain".parse::<Style>().unwrap(), Style::default());
+        assert_eq!("".parse::<Style>().unwrap(), Style::default());
+
+        let err = |style: &str| style.parse::<Style>().unwrap_err().to_string();
+
+        assert!(err("orange").contains("unknown color or attribute \"orange\""));
+        assert!(err("#e69f0").contains("not a hex color"));
+        assert!(err("#e69f0g").contains("not a hex color"));
+        assert!(err("red blue").contains("more than one color"));
+    }
+
+    #[test]
+    fn override_presets() {
+        let spec: ThemeSpec = toml::from_str(
+            "preset = \"mono\"\nold-version = \"red underline\"\nwarning = \"plain\"\n",
+        )
+        .unwrap();
+
+        let theme = Theme::new(None, &spec).unwrap();
+        assert_eq!(theme.style(Role::OldVersion).color, Some(Color::Red));
+        assert_eq!(theme.style(Role::Warning), Style::default());
+        assert_eq!(theme.style(Role::Heading), "bold".parse().unwrap());
+
+        let theme = Theme::new(Some(Preset::Colorblind), &spec).unwrap();
+        assert_eq!(theme.style(Role::Heading), "bold".parse().unwrap());
+        assert_eq!(theme.style(Role::OldVersion).color, Some(Color::Red));
+        assert_eq!(
+            theme.style(Role::NewVersion).color,
+            Some(Color::TrueColor {
+                r: 0,
+                g: 114,
+                b: 178
+            })
+        );
+
+        let spec: ThemeSpec = toml::from_str("new-version = \"orange\"\n").unwrap();
+        let err = format!("{:#}", Theme::new(None, &spec).unwrap_err());
+        assert!(err.contains("invalid style for new-version"), "{}", err);
+        assert!(err.contains("\"orange\""), "{}", err);
+
+        let spec: ThemeSpec = toml::from_str("package = \"blue\"\n").unwrap();
+        let err = Theme::new(None, &spec).unwrap_err().to_string();
+        assert!(err.contains("unknown role \"package\""), "{}", err);
+        assert!(err.contains("package-name"), "{}", err);
+
+        assert!("solarized".parse::<Preset>().is_err());
+    }
+
+    #[test]
+    fn paint_roles() {
+        let theme = Theme::preset(Preset::Colorblind);
+
+        assert_eq!(
+            theme.style(Role::OldVersion).paint_with("1.0", true),
+            "\x1b[38;2;230;159;0m1.0\x1b[0m"
+        );
+
+        assert_eq!(
+            theme
+                .style(Role::NewVersionChangedRun)
+                .paint_with("1", true),
+            "\x1b[1;4;38;2;86;180;233m1\x1b[0m"
+        );
+
+        assert_eq!(
+            theme.style(Role::OldVersion).paint_with("1.0", false),
+            "1.0"
+        );
+        assert_eq!(Style::default().paint_with("1.0", true), "1.0");
+
+        // Every role in every preset is valid
+        for preset in &[Preset::Default, Preset::Colorblind, Preset::Mono] {
+            Theme::preset(*preset);
+        }
+    }
+}
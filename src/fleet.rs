@@ -0,0 +1,365 @@
+use crate::state::PackageState;
+use crate::store::version::Version;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// The packages saved in the state of a single machine in a fleet.
+pub struct Host {
+    /// The name of the host, which is the name of its state file without the extension.
+    pub name: String,
+    /// The version of every package on the host by name, or the error its state failed to load with.
+    pub packages: Result<HashMap<String, String>>,
+}
+
+impl Host {
+    pub fn new(name: String, state: &PackageState) -> Self {
+        let mut packages = HashMap::<String, String>::new();
+
+        // A host can keep several versions of a package, in which case only the newest one is compared
+        for pkg in &state.packages {
+            let store = &pkg.store;
+
+            match packages.get_mut(&store.name) {
+                Some(version) if Version::parse(version) >= Version::parse(&store.version) => (),
+                Some(version) => *version = store.version.clone(),
+                None => {
+                    packages.insert(store.name.clone(), store.version.clone());
+                }
+            }
+        }
+
+        Self {
+            name,
+            packages: Ok(packages),
+        }
+    }
+
+    /// Loads the state of every host in `dir`, sorted by name.
+    ///
+    /// Every regular file in `dir` is treated as the state of the host it's named after. A state that
+    /// fails to load is kept with its error, so one bad file doesn't hide the rest of the fleet.
+    pub fn load_dir(dir: &Path) -> Result<Vec<Self>> {
+        let entries = fs::read_dir(dir)
+            .with_context(|| anyhow!("failed to read fleet directory at {}", dir.display()))?;
+
+        let mut hosts = Vec::new();
+
+        for entry in entries {
+            let entry = entry
+                .with_context(|| anyhow!("failed to read fleet directory at {}", dir.display()))?;
+
+            let path = entry.path();
+
+            if !path.is_file() {
+                continue;
+            }
+
+            let name = match path.file_stem() {
+                Some(stem) => stem.to_string_lossy().into_owned(),
+                None => continue,
+            };
+
+            let host = match PackageState::load_explicit(&path) {
+                Ok(state) => Self::new(name, &state),
+                Err(err) => Self {
+                    name,
+                    packages: Err(err),
+                },
+            };
+
+            hosts.push(host);
+        }
+
+        hosts.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+        Ok(hosts)
+    }
+}
+
+/// A host's entry for a package in a `Matrix`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cell {
+    /// The host's state couldn't be loaded.
+    Unknown,
+    /// The host doesn't have the package.
+    Missing,
+    Version {
+        version: String,
+        /// Whether the version is older than the newest one in the fleet.
+        lagging: bool,
+    },
+}
+
+/// A package that differs between hosts.
+#[derive(Debug, PartialEq)]
+pub struct Row {
+    pub name: String,
+    /// The newest version of the package on any host.
+    pub newest: String,
+    /// The entry of each host, in the same order as `Matrix::hosts`.
+    pub cells: Vec<Cell>,
+}
+
+/// The versions of every package that differs anywhere in a fleet, pivoted by host.
+#[derive(Debug, PartialEq)]
+pub struct Matrix {
+    /// The name of every host, and whether its state was loaded.
+    pub hosts: Vec<(String, bool)>,
+    /// Every package that's missing from a host or has a different version on one, sorted by name.
+    pub rows: Vec<Row>,
+}
+
+impl Matrix {
+    pub fn new(hosts: &[Host]) -> Self {
+        let loaded = hosts
+            .iter()
+            .filter_map(|host| host.packages.as_ref().ok())
+            .collect::<Vec<_>>();
+
+        let names = loaded
+            .iter()
+            .flat_map(|packages| packages.keys())
+            .collect::<BTreeSet<_>>();
+
+        let mut rows = Vec::new();
+
+        for name in names {
+            let versions = loaded
+                .iter()
+                .map(|packages| packages.get(name))
+                .collect::<Vec<_>>();
+
+            let same_everywhere = versions.windows(2).all(|pair| pair[0] == pair[1]);
+
+            if same_everywhere {
+                continue;
+            }
+
+            let newest = versions
+                .iter()
+                .flatten()
+                .max_by(|x, y| Version::parse(x).cmp(&Version::parse(y)))
+                .map(|version| version.to_string())
+                .unwrap_or_default();
+
+            let cells = hosts
+                .iter()
+                .map(|host| match &host.packages {
+                    Ok(packages) => match packages.get(name) {
+                        Some(version) => Cell::Version {
+                            version: version.clone(),
+                            lagging: Version::parse(version) < Version::parse(&newest),
+                        },
+                        None => Cell::Missing,
+                    },
+                    Err(_) => Cell::Unknown,
+                })
+                .collect();
+
+            rows.push(Row {
+                name: name.clone(),
+                newest,
+                cells,
+            });
+        }
+
+        Self {
+            hosts: hosts
+                .iter()
+                .map(|host| (host.name.clone(), host.packages.is_ok()))
+                .collect(),
+            rows,
+        }
+    }
+
+    /// Returns the number of hosts that lag behind the newest version of at least one package.
+    pub fn lagging_hosts(&self) -> usize {
+        (0..self.hosts.len())
+            .filter(|&i| {
+                self.rows
+                    .iter()
+                    .any(|row| matches!(row.cells[i], Cell::Version { lagging: true, .. }))
+            })
+            .count()
+    }
+}
+
+/// Helpers for building fleets of hosts in tests.
+#[cfg(test)]
+pub mod fixture {
+    use super::*;
+
+    /// Creates a loaded host with the given packages and versions.
+    pub fn host(name: &str, packages: &[(&str, &str)]) -> Host {
+        let packages = packages
+            .iter()
+            .map(|&(name, version)| (name.to_string(), version.to_string()))
+            .collect();
+
+        Host {
+            name: name.into(),
+            packages: Ok(packages),
+        }
+    }
+
+    /// Creates a host whose state failed to load.
+    pub fn broken(name: &str) -> Host {
+        Host {
+            name: name.into(),
+            packages: Err(anyhow!("invalid state")),
+        }
+    }
+
+    /// Three hosts with overlapping packages, where `beta` and `gamma` each lag behind on a different one.
+    pub fn fleet() -> Vec<Host> {
+        vec![
+            host(
+                "alpha",
+                &[
+                    ("firefox", "124.0"),
+                    ("openssl", "3.0.13"),
+                    ("git", "2.44.0"),
+                ],
+            ),
+            host(
+                "beta",
+                &[
+                    ("firefox", "123.0"),
+                    ("openssl", "3.0.13"),
+                    ("git", "2.44.0"),
+                ],
+            ),
+            host(
+                "gamma",
+                &[("firefox", "124.0"), ("openssl", "3.0.9"), ("vim", "9.1")],
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::fixture::{broken, fleet};
+    use super::*;
+    use crate::store::{Derivation, Store};
+    use std::collections::HashSet;
+
+    fn version(version: &str, lagging: bool) -> Cell {
+        Cell::Version {
+            version: version.into(),
+            lagging,
+        }
+    }
+
+    #[test]
+    fn pivot_versions() {
+        let mut hosts = fleet();
+        hosts.push(broken("delta"));
+
+        let matrix = Matrix::new(&hosts);
+
+        assert_eq!(
+            matrix.hosts,
+            [
+                ("alpha".into(), true),
+                ("beta".into(), true),
+                ("gamma".into(), true),
+                ("delta".into(), false)
+            ]
+        );
+
+        // Packages with the same version everywhere are left out
+        let names = matrix
+            .rows
+            .iter()
+            .map(|row| row.name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, ["firefox", "git", "openssl", "vim"]);
+
+        assert_eq!(
+            matrix.rows[0],
+            Row {
+                name: "firefox".into(),
+                newest: "124.0".into(),
+                cells: vec![
+                    version("124.0", false),
+                    version("123.0", true),
+                    version("124.0", false),
+                    Cell::Unknown
+                ],
+            }
+        );
+
+        assert_eq!(
+            matrix.rows[1].cells,
+            [
+                version("2.44.0", false),
+                version("2.44.0", false),
+                Cell::Missing,
+                Cell::Unknown
+            ]
+        );
+
+        // Versions are ordered numerically rather than by their text
+        assert_eq!(matrix.rows[2].newest, "3.0.13");
+        assert_eq!(matrix.rows[2].cells[2], version("3.0.9", true));
+
+        assert_eq!(matrix.lagging_hosts(), 2);
+    }
+
+    #[test]
+    fn load_hosts_from_dir() {
+        let fleet_dir = tempfile::tempdir().unwrap();
+
+        let package = |name: &str| Derivation {
+            store: Store::parse_stripped(name).unwrap(),
+            deps: HashSet::new(),
+            paths: HashMap::new(),
+        };
+
+        let states = [
+            ("beta", vec!["firefox-123.0", "git-2.44.0"]),
+            ("alpha", vec!["firefox-124.0", "firefox-123.0"]),
+        ];
+
+        for (host, names) in &states {
+            let data_dir = tempfile::tempdir().unwrap();
+            let packages = names.iter().map(|name| package(name)).collect();
+
+            PackageState::new(packages, None)
+                .unwrap()
+                .save(data_dir.path())
+                .unwrap();
+
+            fs::copy(
+                PackageState::save_path(data_dir.path()),
+                fleet_dir.path().join(format!("{}.bin", host)),
+            )
+            .unwrap();
+        }
+
+        fs::write(fleet_dir.path().join("gamma.bin"), b"not a state").unwrap();
+        fs::create_dir(fleet_dir.path().join("archive")).unwrap();
+
+        let hosts = Host::load_dir(fleet_dir.path()).unwrap();
+        let names = hosts
+            .iter()
+            .map(|host| host.name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, ["alpha", "beta", "gamma"]);
+        assert!(hosts[2].packages.is_err());
+
+        // Only the newest version of a package on a host is compared
+        let alpha = hosts[0].packages.as_ref().unwrap();
+        assert_eq!(alpha.len(), 1);
+        assert_eq!(alpha["firefox"], "124.0");
+
+        let matrix = Matrix::new(&hosts);
+        assert_eq!(matrix.rows.len(), 2);
+        assert_eq!(matrix.rows[0].cells[1], version("123.0", true));
+        assert_eq!(matrix.rows[1].cells[0], Cell::Missing);
+    }
+}
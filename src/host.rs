@@ -0,0 +1,41 @@
+use std::ffi::CStr;
+use std::fs;
+
+/// The file systemd stores the machine's unique id in.
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+
+/// Returns the hostname of the machine, if it can be retrieved.
+pub fn hostname() -> Option<String> {
+    let mut buf = [0 as libc::c_char; 256];
+
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) };
+
+    if result != 0 {
+        return None;
+    }
+
+    // The name isn't guaranteed to be null-terminated if it was truncated
+    buf[buf.len() - 1] = 0;
+
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Returns the unique id of the machine, if it has one.
+pub fn machine_id() -> Option<String> {
+    let id = fs::read_to_string(MACHINE_ID_PATH).ok()?;
+    let id = id.trim();
+
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.into())
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{anyhow, Context, Result};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::io;
+use std::thread;
+
+/// The most threads used by default, so running from a hook doesn't take over every core of a large machine.
+pub const MAX_DEFAULT_JOBS: usize = 4;
+
+/// The nice value set by `--low-priority`, which is the lowest priority there is.
+const LOW_PRIORITY_NICE: libc::c_int = 19;
+
+/// How much of the machine nixup is allowed to use.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ScanOptions {
+    /// The number of threads work such as encoding states is split across.
+    pub jobs: usize,
+    /// Lower the CPU and I/O priority of the process before doing any work.
+    pub low_priority: bool,
+}
+
+impl ScanOptions {
+    /// Returns the number of jobs to use when none are given, which is the number of cores up to `MAX_DEFAULT_JOBS`.
+    pub fn default_jobs() -> usize {
+        thread::available_parallelism()
+            .map_or(1, |cores| cores.get())
+            .min(MAX_DEFAULT_JOBS)
+    }
+
+    /// Lowers the priority of the process if asked to, and builds the thread pool that parallel work should
+    /// be run in with `ThreadPool::install`.
+    ///
+    /// The priority is lowered first, since threads only inherit the priority of the thread that spawns them.
+    pub fn prepare(&self) -> Result<ThreadPool> {
+        if self.low_priority {
+            lower_priority()?;
+        }
+
+        self.thread_pool()
+    }
+
+    /// Builds a thread pool with `jobs` threads, rather than relying on the size of rayon's global pool.
+    pub fn thread_pool(&self) -> Result<ThreadPool> {
+        if self.jobs == 0 {
+            return Err(anyhow!("the number of jobs must be at least 1"));
+        }
+
+        ThreadPoolBuilder::new()
+            .num_threads(self.jobs)
+            .thread_name(|i| format!("nixup-{}", i))
+            .build()
+            .with_context(|| anyhow!("failed to start {} worker threads", self.jobs))
+    }
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            jobs: Self::default_jobs(),
+            low_priority: false,
+        }
+    }
+}
+
+/// Sets the nice value of the calling thread to the lowest priority, and its I/O scheduling class to idle
+/// where the kernel supports it.
+fn lower_priority() -> Result<()> {
+    // On Linux this only applies to the calling thread, which is why it has to happen before any are spawned
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, LOW_PRIORITY_NICE) };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error()).context("failed to lower the process priority");
+    }
+
+    lower_io_priority();
+    Ok(())
+}
+
+/// Puts the calling thread in the idle I/O scheduling class, so it only touches the disk when nothing else is.
+///
+/// This is best-effort, since not every kernel or I/O scheduler supports it and the CPU priority matters more.
+#[cfg(target_os = "linux")]
+fn lower_io_priority() {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+    unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_io_priority() {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rayon::prelude::*;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[test]
+    fn respect_pool_size() {
+        for &jobs in &[1, 3] {
+            let opts = ScanOptions {
+                jobs,
+                low_priority: false,
+            };
+
+            let pool = opts.thread_pool().unwrap();
+
+            // Sleeping keeps every thread busy long enough for the work to be spread across all of them
+            let threads = pool.install(|| {
+                (0..64)
+                    .into_par_iter()
+                    .map(|_| {
+                        thread::sleep(Duration::from_millis(2));
+                        thread::current().id()
+                    })
+                    .collect::<HashSet<_>>()
+            });
+
+            assert!(
+                !threads.is_empty() && threads.len() <= jobs,
+                "{} jobs ran on {} threads",
+                jobs,
+                threads.len()
+            );
+        }
+    }
+
+    #[test]
+    fn reject_zero_jobs() {
+        let opts = ScanOptions {
+            jobs: 0,
+            low_priority: false,
+        };
+
+        assert!(opts.thread_pool().is_err());
+        assert!((1..=MAX_DEFAULT_JOBS).contains(&ScanOptions::default_jobs()));
+    }
+}
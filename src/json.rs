@@ -0,0 +1,895 @@
+use crate::critical::CriticalChange;
+use crate::fleet::{Cell, Matrix};
+use crate::host;
+use crate::profile;
+use crate::query::Query;
+use crate::state::StateMeta;
+use crate::store::diff::{Outcome, PackageDiff, RemovedPackage, StoreDiff};
+use crate::store::drilldown::Drilldown;
+use crate::store::Store;
+use anyhow::Result;
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Information about where and when a JSON document was produced.
+///
+/// Fields that could not be determined are omitted entirely rather than being null.
+#[derive(Debug, Default, Serialize)]
+pub struct Meta<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    /// The generation of the current system profile.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u32>,
+    /// The epoch time the state being diffed against was saved at.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_message: Option<&'a str>,
+    pub nixup_version: &'a str,
+    /// The --where expression the changes were filtered with.
+    #[serde(rename = "where", skip_serializing_if = "Option::is_none")]
+    pub query: Option<&'a str>,
+}
+
+impl<'a> Meta<'a> {
+    /// Gathers the metadata of the current machine, diffing against the state described by `snapshot` with
+    /// the changes filtered by `query`.
+    pub fn current(snapshot: &'a StateMeta, query: Option<&'a Query>) -> Self {
+        Self {
+            hostname: host::hostname(),
+            machine_id: host::machine_id(),
+            generation: profile::current_generation(profile::SYSTEM_PROFILE).ok(),
+            snapshot_time: Some(snapshot.saved_at),
+            snapshot_message: snapshot.message.as_deref(),
+            nixup_version: env!("CARGO_PKG_VERSION"),
+            query: query.map(Query::source),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    meta: &'a Meta<'a>,
+    packages: Vec<Package<'a>>,
+    change_kind: Outcome,
+    critical_changed: usize,
+    critical: Vec<Critical<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    removed: Vec<Removed<'a>>,
+}
+
+#[derive(Serialize)]
+struct Package<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suffix: Option<&'a str>,
+    /// Only present when the suffix changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_suffix: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_version: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_version: Option<&'a str>,
+    deps: Vec<Dependency<'a>>,
+    /// Newly added packages that were split off from this one as separate outputs.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    split_outputs: &'a [String],
+    /// The number of paths referencing the package, which is only present when it was counted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    referrer_count: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Dependency<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suffix: Option<&'a str>,
+    /// Only present when the suffix changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_suffix: Option<&'a str>,
+    old_version: &'a str,
+    new_version: &'a str,
+}
+
+impl<'a> From<&'a StoreDiff> for Dependency<'a> {
+    fn from(diff: &'a StoreDiff) -> Self {
+        Self {
+            name: &diff.name,
+            suffix: diff.suffix.as_deref(),
+            old_suffix: changed_suffix(diff),
+            old_version: &diff.ver_from,
+            new_version: &diff.ver_to,
+        }
+    }
+}
+
+impl<'a> From<&'a PackageDiff> for Package<'a> {
+    fn from(diff: &'a PackageDiff) -> Self {
+        let mut deps = diff.deps.iter().map(Dependency::from).collect::<Vec<_>>();
+        deps.sort_unstable_by(|x, y| x.name.cmp(y.name));
+
+        Self {
+            name: &diff.name,
+            suffix: diff.pkg.as_ref().and_then(|pkg| pkg.suffix.as_deref()),
+            old_suffix: diff.pkg.as_ref().and_then(changed_suffix),
+            old_version: diff.pkg.as_ref().map(|pkg| pkg.ver_from.as_str()),
+            new_version: diff.pkg.as_ref().map(|pkg| pkg.ver_to.as_str()),
+            deps,
+            split_outputs: &diff.split_outputs,
+            referrer_count: diff.referrer_count,
+        }
+    }
+}
+
+/// A change to a package on the critical list, which is also listed when the diff options hid it.
+#[derive(Serialize)]
+struct Critical<'a> {
+    name: &'a str,
+    old_version: &'a str,
+    new_version: &'a str,
+    /// Whether the package itself changed, and not only as a dependency.
+    package: bool,
+    dependency_of: &'a [String],
+    /// Whether the change is missing from `packages` because of the diff options.
+    hidden: bool,
+}
+
+impl<'a> From<&'a CriticalChange> for Critical<'a> {
+    fn from(change: &'a CriticalChange) -> Self {
+        Self {
+            name: &change.name,
+            old_version: &change.ver_from,
+            new_version: &change.ver_to,
+            package: change.as_package,
+            dependency_of: &change.dependents,
+            hidden: change.hidden,
+        }
+    }
+}
+
+/// A package that is no longer in the current system.
+#[derive(Serialize)]
+struct Removed<'a> {
+    name: &'a str,
+    version: &'a str,
+    /// The dependencies nothing in the current system uses anymore.
+    gone_deps: &'a [String],
+    /// The dependencies that are still used by something in the current system.
+    retained_deps: &'a [String],
+}
+
+impl<'a> From<&'a RemovedPackage> for Removed<'a> {
+    fn from(removal: &'a RemovedPackage) -> Self {
+        Self {
+            name: &removal.name,
+            version: &removal.version,
+            gone_deps: &removal.gone,
+            retained_deps: &removal.retained,
+        }
+    }
+}
+
+fn changed_suffix(diff: &StoreDiff) -> Option<&str> {
+    if diff.suffix_changed() {
+        diff.suffix_from.as_deref().or(Some(""))
+    } else {
+        None
+    }
+}
+
+/// The version of the layout of each line written by `StreamWriter`.
+pub const STREAM_SCHEMA_VERSION: u32 = 1;
+
+/// A single line of a streamed document.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamLine<'a> {
+    Header {
+        schema_version: u32,
+        meta: &'a Meta<'a>,
+    },
+    Package(Package<'a>),
+    Summary {
+        packages: usize,
+        deps: usize,
+        change_kind: Outcome,
+        critical_changed: usize,
+        critical: Vec<Critical<'a>>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        removed: Vec<Removed<'a>>,
+    },
+}
+
+/// Writes diffs as newline-delimited JSON, one line at a time.
+///
+/// The first line is a header with the metadata, followed by a line for each package, and finally a summary.
+/// Each line is flushed as soon as it is written so a reader on the other end of a pipe can process it right away.
+pub struct StreamWriter<W: Write> {
+    out: W,
+    packages: usize,
+    deps: usize,
+}
+
+impl<W: Write> StreamWriter<W> {
+    /// Creates a writer and writes the header line.
+    pub fn new(out: W, meta: &Meta) -> Result<Self> {
+        let mut writer = Self {
+            out,
+            packages: 0,
+            deps: 0,
+        };
+
+        writer.write_line(&StreamLine::Header {
+            schema_version: STREAM_SCHEMA_VERSION,
+            meta,
+        })?;
+
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, diff: &PackageDiff) -> Result<()> {
+        self.packages += 1;
+        self.deps += diff.deps.len();
+        self.write_line(&StreamLine::Package(Package::from(diff)))
+    }
+
+    /// Writes the summary line, with `outcome` describing what the diff found as a whole,
+    /// `critical` listing every change to a critical package, and `removals` listing every removed package.
+    pub fn finish(
+        mut self,
+        critical: &[CriticalChange],
+        removals: &[RemovedPackage],
+        outcome: Outcome,
+    ) -> Result<()> {
+        let summary = StreamLine::Summary {
+            packages: self.packages,
+            deps: self.deps,
+            change_kind: outcome,
+            critical_changed: critical.len(),
+            critical: critical.iter().map(Critical::from).collect(),
+            removed: removals.iter().map(Removed::from).collect(),
+        };
+
+        self.write_line(&summary)
+    }
+
+    fn write_line(&mut self, line: &StreamLine) -> Result<()> {
+        serde_json::to_writer(&mut self.out, line)?;
+        writeln!(self.out)?;
+        self.out.flush().map_err(Into::into)
+    }
+}
+
+/// Streams every diff in `diffs` to `out` as newline-delimited JSON.
+pub fn stream_package_diffs<'a, W, I>(
+    out: W,
+    meta: &Meta,
+    diffs: I,
+    critical: &[CriticalChange],
+    removals: &[RemovedPackage],
+    outcome: Outcome,
+) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a PackageDiff>,
+{
+    let mut writer = StreamWriter::new(out, meta)?;
+
+    for diff in diffs {
+        writer.write(diff)?;
+    }
+
+    writer.finish(critical, removals, outcome)
+}
+
+/// Writes `diff` as a single line of JSON, with the same layout as each package of a JSON document.
+///
+/// The line is flushed right away, so consumers on the other end of a pipe see each package as it's written.
+pub fn write_package_line<W: Write>(mut out: W, diff: &PackageDiff) -> Result<()> {
+    serde_json::to_writer(&mut out, &Package::from(diff))?;
+    writeln!(out)?;
+    out.flush().map_err(Into::into)
+}
+
+/// Writes `diffs` as a pretty-printed JSON document with a top-level `meta` object, `packages` array,
+/// `change_kind` describing what the diff found as a whole, `critical` array of changes to critical packages,
+/// and `removed` array of removed packages, which is left out when nothing was removed.
+pub fn write_package_diffs<W: Write>(
+    mut out: W,
+    meta: &Meta,
+    diffs: &[PackageDiff],
+    critical: &[CriticalChange],
+    removals: &[RemovedPackage],
+    outcome: Outcome,
+) -> Result<()> {
+    let doc = Document {
+        meta,
+        packages: diffs.iter().map(Package::from).collect(),
+        change_kind: outcome,
+        critical_changed: critical.len(),
+        critical: critical.iter().map(Critical::from).collect(),
+        removed: removals.iter().map(Removed::from).collect(),
+    };
+
+    serde_json::to_writer_pretty(&mut out, &doc)?;
+    writeln!(out)?;
+    out.flush().map_err(Into::into)
+}
+
+/// A dependency that only one of the states has.
+#[derive(Serialize)]
+struct ListedStore<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suffix: Option<&'a str>,
+    version: &'a str,
+}
+
+impl<'a> From<&'a Store> for ListedStore<'a> {
+    fn from(store: &'a Store) -> Self {
+        Self {
+            name: &store.name,
+            suffix: store.suffix.as_deref(),
+            version: &store.version,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DrilldownDocument<'a> {
+    meta: &'a Meta<'a>,
+    name: &'a str,
+    /// Only present when the package had a different name in the saved state.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_version: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_version: Option<&'a str>,
+    deps: Vec<Dependency<'a>>,
+    added_deps: Vec<ListedStore<'a>>,
+    removed_deps: Vec<ListedStore<'a>>,
+    unchanged_deps: usize,
+}
+
+/// Writes `drilldown` as a pretty-printed JSON document with a top-level `meta` object, the package's versions
+/// in each state, which are left out for a state that doesn't have it, and its changed, added, and removed
+/// dependencies.
+pub fn write_drilldown<W: Write>(mut out: W, meta: &Meta, drilldown: &Drilldown) -> Result<()> {
+    let doc = DrilldownDocument {
+        meta,
+        name: drilldown.name(),
+        old_name: drilldown.renamed_from(),
+        old_version: drilldown.old.as_ref().map(|store| store.version.as_str()),
+        new_version: drilldown.new.as_ref().map(|store| store.version.as_str()),
+        deps: drilldown.deps.iter().map(Dependency::from).collect(),
+        added_deps: drilldown.added_deps.iter().map(ListedStore::from).collect(),
+        removed_deps: drilldown
+            .removed_deps
+            .iter()
+            .map(ListedStore::from)
+            .collect(),
+        unchanged_deps: drilldown.unchanged_deps,
+    };
+
+    serde_json::to_writer_pretty(&mut out, &doc)?;
+    writeln!(out)?;
+    out.flush().map_err(Into::into)
+}
+
+#[derive(Serialize)]
+struct Fleet<'a> {
+    hosts: Vec<FleetHost<'a>>,
+    packages: Vec<FleetPackage<'a>>,
+}
+
+#[derive(Serialize)]
+struct FleetHost<'a> {
+    name: &'a str,
+    /// False when the host's state couldn't be loaded, in which case it's left out of every package.
+    loaded: bool,
+}
+
+#[derive(Serialize)]
+struct FleetPackage<'a> {
+    name: &'a str,
+    newest: &'a str,
+    /// The version on each loaded host, or null when the host doesn't have the package.
+    versions: BTreeMap<&'a str, Option<&'a str>>,
+    /// The hosts with a version older than `newest`.
+    lagging: Vec<&'a str>,
+}
+
+impl<'a> From<&'a Matrix> for Fleet<'a> {
+    fn from(matrix: &'a Matrix) -> Self {
+        let hosts = matrix
+            .hosts
+            .iter()
+            .map(|(name, loaded)| FleetHost {
+                name,
+                loaded: *loaded,
+            })
+            .collect();
+
+        let packages = matrix
+            .rows
+            .iter()
+            .map(|row| {
+                let mut versions = BTreeMap::new();
+                let mut lagging = Vec::new();
+
+                for ((host, _), cell) in matrix.hosts.iter().zip(&row.cells) {
+                    match cell {
+                        Cell::Unknown => (),
+                        Cell::Missing => {
+                            versions.insert(host.as_str(), None);
+                        }
+                        Cell::Version {
+                            version,
+                            lagging: behind,
+                        } => {
+                            versions.insert(host.as_str(), Some(version.as_str()));
+
+                            if *behind {
+                                lagging.push(host.as_str());
+                            }
+                        }
+                    }
+                }
+
+                FleetPackage {
+                    name: &row.name,
+                    newest: &row.newest,
+                    versions,
+                    lagging,
+                }
+            })
+            .collect();
+
+        Self { hosts, packages }
+    }
+}
+
+/// Writes `matrix` as a pretty-printed JSON document with a `hosts` array and a `packages` array of
+/// every package that differs between them.
+pub fn write_fleet<W: Write>(mut out: W, matrix: &Matrix) -> Result<()> {
+    serde_json::to_writer_pretty(&mut out, &Fleet::from(matrix))?;
+    writeln!(out)?;
+    out.flush().map_err(Into::into)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::critical::{self, CriticalList};
+
+    fn fixture_diffs() -> Vec<PackageDiff> {
+        let store_diff = |name: &str, from: &str, to: &str| StoreDiff {
+            name: name.into(),
+            suffix: None,
+            suffix_from: None,
+            ver_from: from.into(),
+            ver_to: to.into(),
+            register_time: 0,
+        };
+
+        vec![
+            PackageDiff {
+                name: "firefox".into(),
+                pkg: Some(store_diff("firefox", "122.0", "123.0")),
+                deps: vec![store_diff("nss", "3.97", "3.98")],
+                wrapper: None,
+                split_outputs: Vec::new(),
+                referrer_count: Some(3),
+            },
+            PackageDiff {
+                name: "mesa".into(),
+                pkg: None,
+                deps: vec![
+                    store_diff("llvm", "16.0.6", "17.0.6"),
+                    store_diff("libdrm", "2.4.119", "2.4.120"),
+                ],
+                wrapper: None,
+                split_outputs: vec!["mesa-dev".into()],
+                referrer_count: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn stream_matches_document() {
+        let meta = Meta {
+            hostname: Some("workstation".into()),
+            nixup_version: "0.0.0",
+            ..Meta::default()
+        };
+
+        let diffs = fixture_diffs();
+
+        let mut document = Vec::new();
+        write_package_diffs(&mut document, &meta, &diffs, &[], &[], Outcome::Changed).unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&document).unwrap();
+
+        let mut stream = Vec::new();
+        stream_package_diffs(&mut stream, &meta, &diffs, &[], &[], Outcome::Changed).unwrap();
+        let stream = String::from_utf8(stream).unwrap();
+
+        let mut lines = stream
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), diffs.len() + 2);
+
+        let summary = lines.pop().unwrap();
+        assert_eq!(
+            summary,
+            serde_json::json!({
+                "type": "summary",
+                "packages": 2,
+                "deps": 3,
+                "change_kind": "changed",
+                "critical_changed": 0,
+                "critical": [],
+            })
+        );
+
+        assert_eq!(document["packages"][0]["referrer_count"], 3);
+        assert!(document["packages"][1].get("referrer_count").is_none());
+
+        let header = lines.remove(0);
+        assert_eq!(header["type"], "header");
+        assert_eq!(header["schema_version"], STREAM_SCHEMA_VERSION);
+
+        let packages = lines
+            .into_iter()
+            .map(|mut line| {
+                assert_eq!(line["type"], "package");
+                line.as_object_mut().unwrap().remove("type");
+                line
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            serde_json::json!({
+                "meta": header["meta"],
+                "packages": packages,
+                "change_kind": summary["change_kind"],
+                "critical_changed": summary["critical_changed"],
+                "critical": summary["critical"],
+            }),
+            document
+        );
+    }
+
+    #[test]
+    fn package_lines_match_document() {
+        let diffs = fixture_diffs();
+
+        let mut document = Vec::new();
+        write_package_diffs(
+            &mut document,
+            &Meta::default(),
+            &diffs,
+            &[],
+            &[],
+            Outcome::Changed,
+        )
+        .unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&document).unwrap();
+
+        let mut out = Vec::new();
+
+        for diff in &diffs {
+            write_package_line(&mut out, diff).unwrap();
+        }
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.ends_with('\n'));
+
+        let packages = out
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(serde_json::Value::from(packages), document["packages"]);
+    }
+
+    #[test]
+    fn omit_unavailable_meta() {
+        let meta = Meta {
+            hostname: Some("workstation".into()),
+            generation: Some(42),
+            nixup_version: "0.0.0",
+            ..Meta::default()
+        };
+
+        let diffs = vec![PackageDiff {
+            name: "firefox".into(),
+            pkg: None,
+            deps: vec![StoreDiff {
+                name: "nss".into(),
+                suffix: None,
+                suffix_from: None,
+                ver_from: "3.97".into(),
+                ver_to: "3.98".into(),
+                register_time: 0,
+            }],
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: None,
+        }];
+
+        let mut out = Vec::new();
+        write_package_diffs(&mut out, &meta, &diffs, &[], &[], Outcome::Changed).unwrap();
+
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "meta": {
+                    "hostname": "workstation",
+                    "generation": 42,
+                    "nixup_version": "0.0.0",
+                },
+                "packages": [{
+                    "name": "firefox",
+                    "deps": [{ "name": "nss", "old_version": "3.97", "new_version": "3.98" }],
+                }],
+                "change_kind": "changed",
+                "critical_changed": 0,
+                "critical": [],
+            })
+        );
+    }
+
+    #[test]
+    fn record_query() {
+        let query = "kind != removed and (major or critical)"
+            .parse::<Query>()
+            .unwrap();
+
+        let snapshot = StateMeta::default();
+        let meta = Meta::current(&snapshot, Some(&query));
+
+        let mut out = Vec::new();
+        write_package_diffs(&mut out, &meta, &[], &[], &[], Outcome::Identical).unwrap();
+
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["meta"]["where"], query.source());
+    }
+
+    #[test]
+    fn write_fleet_matrix() {
+        let mut hosts = crate::fleet::fixture::fleet();
+        hosts.push(crate::fleet::fixture::broken("delta"));
+
+        let mut document = Vec::new();
+        write_fleet(&mut document, &Matrix::new(&hosts)).unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&document).unwrap();
+
+        assert_eq!(
+            document["hosts"],
+            serde_json::json!([
+                { "name": "alpha", "loaded": true },
+                { "name": "beta", "loaded": true },
+                { "name": "gamma", "loaded": true },
+                { "name": "delta", "loaded": false },
+            ])
+        );
+
+        assert_eq!(document["packages"].as_array().unwrap().len(), 4);
+
+        assert_eq!(
+            document["packages"][0],
+            serde_json::json!({
+                "name": "firefox",
+                "newest": "124.0",
+                "versions": { "alpha": "124.0", "beta": "123.0", "gamma": "124.0" },
+                "lagging": ["beta"],
+            })
+        );
+
+        assert_eq!(
+            document["packages"][3]["versions"],
+            serde_json::json!({ "alpha": null, "beta": null, "gamma": "9.1" })
+        );
+    }
+
+    #[test]
+    fn change_kinds() {
+        let kinds = [
+            (Outcome::Changed, "changed"),
+            (Outcome::Identical, "identical"),
+            (Outcome::Suppressed, "suppressed"),
+            (Outcome::EmptyBaseline, "empty_baseline"),
+        ];
+
+        for &(outcome, expected) in &kinds {
+            let mut out = Vec::new();
+            write_package_diffs(&mut out, &Meta::default(), &[], &[], &[], outcome).unwrap();
+
+            let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+            assert_eq!(value["change_kind"], expected);
+        }
+    }
+
+    #[test]
+    fn report_hidden_critical_changes() {
+        let diffs = fixture_diffs();
+        let list = CriticalList::new(vec!["nss".into(), "llvm".into()]);
+
+        // Leaving out every dependency hides both critical changes from the packages
+        let mut visible = fixture_diffs();
+        visible.retain(|diff| diff.pkg.is_some());
+        visible[0].deps.clear();
+
+        let critical = critical::changes(&diffs, &visible, &list);
+
+        let mut document = Vec::new();
+        write_package_diffs(
+            &mut document,
+            &Meta::default(),
+            &visible,
+            &critical,
+            &[],
+            Outcome::Changed,
+        )
+        .unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&document).unwrap();
+
+        assert_eq!(document["critical_changed"], 2);
+        assert_eq!(
+            document["critical"],
+            serde_json::json!([
+                {
+                    "name": "llvm",
+                    "old_version": "16.0.6",
+                    "new_version": "17.0.6",
+                    "package": false,
+                    "dependency_of": ["mesa"],
+                    "hidden": true,
+                },
+                {
+                    "name": "nss",
+                    "old_version": "3.97",
+                    "new_version": "3.98",
+                    "package": false,
+                    "dependency_of": ["firefox"],
+                    "hidden": true,
+                },
+            ])
+        );
+
+        let mut stream = Vec::new();
+        stream_package_diffs(
+            &mut stream,
+            &Meta::default(),
+            &visible,
+            &critical,
+            &[],
+            Outcome::Changed,
+        )
+        .unwrap();
+
+        let summary = String::from_utf8(stream).unwrap();
+        let summary: serde_json::Value =
+            serde_json::from_str(summary.lines().last().unwrap()).unwrap();
+
+        assert_eq!(summary["critical_changed"], document["critical_changed"]);
+        assert_eq!(summary["critical"], document["critical"]);
+    }
+
+    #[test]
+    fn report_removed_packages() {
+        let removals = [RemovedPackage {
+            name: "chromium".into(),
+            version: "120.0".into(),
+            gone: vec!["libva".into()],
+            retained: vec!["glibc".into(), "nss".into()],
+        }];
+
+        let mut document = Vec::new();
+        write_package_diffs(
+            &mut document,
+            &Meta::default(),
+            &[],
+            &[],
+            &removals,
+            Outcome::Changed,
+        )
+        .unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&document).unwrap();
+
+        assert_eq!(
+            document["removed"],
+            serde_json::json!([{
+                "name": "chromium",
+                "version": "120.0",
+                "gone_deps": ["libva"],
+                "retained_deps": ["glibc", "nss"],
+            }])
+        );
+
+        let mut stream = Vec::new();
+        stream_package_diffs(
+            &mut stream,
+            &Meta::default(),
+            &[],
+            &[],
+            &removals,
+            Outcome::Changed,
+        )
+        .unwrap();
+
+        let summary = String::from_utf8(stream).unwrap();
+        let summary: serde_json::Value =
+            serde_json::from_str(summary.lines().last().unwrap()).unwrap();
+
+        assert_eq!(summary["removed"], document["removed"]);
+    }
+
+    #[test]
+    fn write_drilldown_document() {
+        use crate::store::Derivation;
+        use std::collections::HashMap;
+
+        let package = |name: &str, deps: &[&str]| Derivation {
+            store: Store::parse_stripped(name).unwrap(),
+            deps: deps
+                .iter()
+                .map(|dep| Store::parse_stripped(dep).unwrap())
+                .collect(),
+            paths: HashMap::new(),
+        };
+
+        let old = package("firefox-123.0", &["nss-3.97", "libvpx-1.13.1", "zlib-1.3"]);
+        let new = package(
+            "firefox-bin-124.0",
+            &["nss-3.98", "dav1d-1.4.0", "zlib-1.3"],
+        );
+
+        let mut document = Vec::new();
+        write_drilldown(
+            &mut document,
+            &Meta::default(),
+            &Drilldown::new(Some(&new), Some(&old), false),
+        )
+        .unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&document).unwrap();
+
+        assert_eq!(
+            document,
+            serde_json::json!({
+                "meta": { "nixup_version": "" },
+                "name": "firefox-bin",
+                "old_name": "firefox",
+                "old_version": "123.0",
+                "new_version": "124.0",
+                "deps": [{ "name": "nss", "old_version": "3.97", "new_version": "3.98" }],
+                "added_deps": [{ "name": "dav1d", "version": "1.4.0" }],
+                "removed_deps": [{ "name": "libvpx", "version": "1.13.1" }],
+                "unchanged_deps": 1,
+            })
+        );
+
+        // A removed package has no current version
+        let mut document = Vec::new();
+        write_drilldown(
+            &mut document,
+            &Meta::default(),
+            &Drilldown::new(None, Some(&old), false),
+        )
+        .unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&document).unwrap();
+
+        assert_eq!(document["name"], "firefox");
+        assert!(document.get("new_version").is_none());
+        assert_eq!(document["removed_deps"].as_array().unwrap().len(), 3);
+    }
+}
@@ -1,130 +1,2493 @@
 #[macro_use]
 extern crate diesel;
 
+mod ack;
+mod annotation;
+mod autosave;
+mod clock;
+mod config;
+mod critical;
+mod csv;
 mod display;
+mod fleet;
+mod host;
+mod jobs;
+mod json;
+mod motd;
+mod nixpkgs;
+mod open;
+mod patch;
+mod profile;
+mod prune;
+mod query;
+mod rejects;
+mod rollback;
+mod runs;
+mod specialisation;
+mod staleness;
+mod state;
 mod store;
+mod unit;
 
-use crate::store::database::SystemDatabase;
-use crate::store::Derivation;
+#[cfg(test)]
+mod bench;
+#[cfg(test)]
+mod testing;
+
+use crate::ack::Acks;
+use crate::autosave::AutosaveOptions;
+use crate::config::Config;
+use crate::critical::CriticalList;
+use crate::display::theme::{self, Preset};
+use crate::display::{DepSort, DisplayOptions, Format};
+use crate::fleet::{Host, Matrix};
+use crate::jobs::ScanOptions;
+use crate::open::{OpenOptions, StorePath};
+use crate::prune::PruneOptions;
+use crate::query::{Filter, Query};
+use crate::rejects::{Rejects, MAX_REJECTS};
+use crate::runs::{Run, RunLog, RunMode};
+use crate::staleness::Manifest;
+use crate::state::{PackageState, StateMeta};
+use crate::store::budget::Budget;
+use crate::store::cache::{self, CacheReport};
+use crate::store::closure::{self, ClosureStats};
+use crate::store::database::{OpenMode, SystemDatabase};
+use crate::store::dedup::DedupPolicy;
+use crate::store::diff::{self, DiffCounts, DiffOptions, DiffScope, LocalFilter};
+use crate::store::drilldown::{self, Drilldown};
+use crate::store::ecosystem;
+use crate::store::explain::{self, Explanation};
+use crate::store::fingerprint::ParserFingerprint;
+use crate::store::input::InputPaths;
+use crate::store::outputs::{self, Selection};
+use crate::store::remote::RemoteStore;
+use crate::store::scan::IncrementalScanner;
+use crate::store::toplevel;
+use crate::store::trace::ParseTrace;
+use crate::store::verify::Verification;
+use crate::store::waves;
+use crate::store::{DepMode, DepOptions, Derivation, Store};
+use crate::unit::{UnitOptions, UnitScope};
 use anyhow::{anyhow, Context, Result};
-use serde_derive::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::{self, File};
-use std::path::PathBuf;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// A mode that replaces diffing entirely.
+enum Subcommand {
+    /// Show the run log.
+    Runs,
+    /// Acknowledge every change in the current diff, so it isn't shown in full again.
+    Ack,
+    /// Remove old snapshots from the data directory.
+    Prune(PruneOptions),
+    /// Open or print the store path of a package.
+    Open(OpenOptions),
+    /// Print or install systemd units that run nixup automatically.
+    GenerateUnit(UnitOptions),
+    /// Show how a store path is parsed.
+    ParsePath(String),
+    /// Show what the state at the given path, or the current state, left out when it was saved.
+    Audit(Option<PathBuf>),
+    /// Compare the versions of packages across the states of several hosts in a directory.
+    Fleet(PathBuf),
+    /// Check which stores of the state to diff against are no longer in the Nix store.
+    VerifyState,
+    /// Show why the current system depends on a package.
+    Explain {
+        name: String,
+        /// Show every chain of references instead of only the shortest one.
+        all_paths: bool,
+    },
+}
 
 struct CmdOptions {
+    command: Option<Subcommand>,
     save_state: bool,
+    message: Option<String>,
+    list: bool,
+    data_dir: Option<PathBuf>,
+    /// The nixpkgs checkout to show where each package is defined in.
+    nixpkgs: Option<PathBuf>,
+    diff: DiffOptions,
+    /// Only report the changes matching this query.
+    query: Option<Query>,
+    /// The name of the single package to show every change to, along with its dependencies.
+    only: Option<String>,
+    /// Only diff the packages installed through `environment.systemPackages`.
+    system_packages: bool,
+    /// Show removed packages that were likely renamed to an added one.
+    detect_renames: bool,
+    deps: DepOptions,
+    verbose: bool,
+    display: DisplayOptions,
+    motd: bool,
+    width: usize,
+    /// Where to export the diff as CSV to, or `Some(None)` to write it to stdout instead of the usual output.
+    csv: Option<Option<PathBuf>>,
+    json: bool,
+    json_stream: bool,
+    after_command: Option<String>,
+    /// The number of seconds to wait between checking for new stores.
+    watch: Option<u64>,
+    emit_patch: Option<PathBuf>,
+    apply_patch: Option<PathBuf>,
+    always: bool,
+    /// Exit with an error if any version was downgraded.
+    fail_on_downgrade: bool,
+    /// Exit with a code describing what the diff found.
+    exit_code: bool,
+    /// Diff the closure of our own store path instead of every package.
+    diff_self: bool,
+    /// The state file to diff against instead of the current state in the data directory.
+    state_file: Option<PathBuf>,
+    /// Save the current packages as the known-good state instead of the baseline.
+    promote: bool,
+    /// Diff against the known-good state instead of the baseline.
+    diff_good: bool,
+    /// The number of seconds scanning stores and resolving dependencies can take before partial results are used.
+    timeout: Option<u64>,
+    /// How many rows to read from the Nix database at a time when scanning every path.
+    batch_size: Option<usize>,
+    /// How many threads to use, and whether to lower the priority of the process first.
+    scan: ScanOptions,
+    /// The URI of the store to read packages from instead of the local Nix database.
+    store: Option<String>,
+    /// A file of store paths to read packages from instead of the local Nix database.
+    input_paths: Option<PathBuf>,
+    /// The URL of the binary cache to check for the new version of every changed package.
+    check_cache: Option<String>,
+    /// The number of seconds each request to the binary cache can take.
+    cache_timeout: u64,
+    /// The window to deduplicate both the saved state and the current packages with before diffing them.
+    dedup_across_states: Option<u32>,
+    /// Keep only the highest version of every name, instead of deduplicating with the policy in the config file.
+    newest_only: bool,
+    /// The policy to resolve duplicate names with instead of the one in the config file.
+    dup_policy: Option<DedupPolicy>,
+    /// Show dates as YYYY-MM-DD regardless of the config file.
+    iso_dates: bool,
+    /// The preset to style the human formats with instead of the one in the config file.
+    theme: Option<Preset>,
+    /// Record a summary of each diff in the run log, even if record_runs isn't set in the config file.
+    log_summary: bool,
+    /// Print notable findings as GitHub Actions workflow commands after the diff.
+    ci_annotations: bool,
+    /// Show changes that were acknowledged with `nixup ack` in full.
+    show_acked: bool,
+    /// Save the previous generation as the baseline when the system changed since the last run.
+    autosave: bool,
 }
 
 impl CmdOptions {
-    fn from_env() -> Self {
-        let mut args = pico_args::Arguments::from_env();
+    fn from_env() -> Result<Self> {
+        let mut raw = env::args_os().skip(1).collect();
+        let bare = take_bare_options(&mut raw, OPTIONAL_VALUES);
+        let mut args = pico_args::Arguments::from_vec(raw);
 
         if args.contains(["-h", "--help"]) {
             Self::print_help();
         }
 
-        Self {
+        let command = match args.subcommand()?.as_deref() {
+            Some("runs") => Some(Subcommand::Runs),
+            Some("ack") => Some(Subcommand::Ack),
+            Some("prune") => {
+                let older_than = args
+                    .opt_value_from_fn("--older-than", prune::parse_duration)?
+                    .ok_or_else(|| anyhow!("prune requires --older-than <duration>"))?;
+
+                Some(Subcommand::Prune(PruneOptions {
+                    older_than,
+                    dry_run: args.contains("--dry-run"),
+                    current: args.contains("--include-current"),
+                }))
+            }
+            Some("open") => {
+                let output = args.opt_value_from_str("--output")?;
+                let name = args
+                    .subcommand()?
+                    .ok_or_else(|| anyhow!("open requires the name of a package"))?;
+
+                Some(Subcommand::Open(OpenOptions {
+                    name,
+                    output,
+                    print_only: false,
+                }))
+            }
+            Some("parse-path") => {
+                let path = args
+                    .subcommand()?
+                    .ok_or_else(|| anyhow!("parse-path requires a store path"))?;
+
+                Some(Subcommand::ParsePath(path))
+            }
+            Some("audit") => Some(Subcommand::Audit(args.subcommand()?.map(PathBuf::from))),
+            Some("fleet") => {
+                let dir = args
+                    .subcommand()?
+                    .ok_or_else(|| anyhow!("fleet requires a directory of state files"))?;
+
+                Some(Subcommand::Fleet(dir.into()))
+            }
+            Some("generate-unit") => {
+                let mode = args.opt_value_from_str("--mode")?.ok_or_else(|| {
+                    anyhow!("generate-unit requires --mode <mode>, such as post-rebuild")
+                })?;
+
+                let profile = args
+                    .opt_value_from_str::<_, String>("--profile")?
+                    .map(|name| UnitOptions::profile_path(&name))
+                    .unwrap_or_else(UnitOptions::default_profile);
+
+                let scope = if args.contains("--system") {
+                    UnitScope::System
+                } else {
+                    UnitScope::User
+                };
+
+                let opts = UnitOptions {
+                    mode,
+                    profile,
+                    scope,
+                    diff_args: args.values_from_str("--diff-arg")?,
+                    install: args.contains("--install-user"),
+                    force: args.contains("--force"),
+                };
+
+                if opts.install && opts.scope == UnitScope::System {
+                    return Err(anyhow!("--install-user cannot be used with --system"));
+                }
+
+                Some(Subcommand::GenerateUnit(opts))
+            }
+            Some(cmd) => return Err(anyhow!("unknown command \"{}\"", cmd)),
+            None => None,
+        };
+
+        let command = match (args.opt_value_from_str("--print-path")?, command) {
+            (Some(name), None) => Some(Subcommand::Open(OpenOptions {
+                name,
+                output: args.opt_value_from_str("--output")?,
+                print_only: true,
+            })),
+            (Some(_), Some(_)) => {
+                return Err(anyhow!("--print-path cannot be used with a command"));
+            }
+            (None, command) => command,
+        };
+
+        let all_paths = args.contains("--all-paths");
+
+        let command = match (args.opt_value_from_str("--explain")?, command) {
+            (Some(name), None) => Some(Subcommand::Explain { name, all_paths }),
+            (Some(_), Some(_)) => {
+                return Err(anyhow!("--explain cannot be used with a command"));
+            }
+            (None, _) if all_paths => {
+                return Err(anyhow!("--all-paths can only be used with --explain"));
+            }
+            (None, command) => command,
+        };
+
+        let command = match (args.contains("--verify-state"), command) {
+            (true, None) => Some(Subcommand::VerifyState),
+            (true, Some(_)) => {
+                return Err(anyhow!("--verify-state cannot be used with a command"));
+            }
+            (false, command) => command,
+        };
+
+        let scope = match (
+            args.contains("--packages-only"),
+            args.contains("--diff-only-deps"),
+        ) {
+            (false, false) => DiffScope::All,
+            (true, false) => DiffScope::PackagesOnly,
+            (false, true) => DiffScope::DepsOnly,
+            (true, true) => {
+                return Err(anyhow!(
+                    "--packages-only and --diff-only-deps cannot be used together"
+                ))
+            }
+        };
+
+        // The path is optional, so a missing value means the CSV should go to stdout
+        let csv = opt_optional_value::<PathBuf>(&mut args, &bare, "--csv")?
+            .map(|path| path.filter(|path| path != Path::new("-")));
+
+        let local = match (args.contains("--only-local"), args.contains("--no-local")) {
+            (false, false) => LocalFilter::All,
+            (true, false) => LocalFilter::OnlyLocal,
+            (false, true) => LocalFilter::NoLocal,
+            (true, true) => {
+                return Err(anyhow!(
+                    "--only-local and --no-local cannot be used together"
+                ))
+            }
+        };
+
+        let json = args.contains("--json");
+        let json_stream = args.contains("--json-stream");
+
+        if json && json_stream {
+            return Err(anyhow!("--json and --json-stream cannot be used together"));
+        }
+
+        if (json || json_stream) && csv == Some(None) {
+            return Err(anyhow!(
+                "JSON output cannot be used while writing CSV to stdout"
+            ));
+        }
+
+        let store: Option<String> = args.opt_value_from_str("--store")?;
+
+        // The window is optional, so a missing value means the default one should be used
+        let dedup_across_states =
+            opt_optional_value::<u32>(&mut args, &bare, "--dedup-across-states")?
+                .map(|window| window.unwrap_or(Store::DUPLICATE_WINDOW));
+
+        if let Some(window) = dedup_across_states {
+            if window < Store::DUPLICATE_WINDOW {
+                return Err(anyhow!(
+                    "the window for --dedup-across-states must be at least {} seconds",
+                    Store::DUPLICATE_WINDOW
+                ));
+            }
+        }
+
+        if let Some(uri) = &store {
+            store::remote::check_uri(uri)?;
+        }
+
+        // The gap is optional, so a missing value means the default one should be used
+        let waves = opt_optional_value::<u32>(&mut args, &bare, "--waves")?
+            .map(|gap| gap.unwrap_or(waves::DEFAULT_GAP));
+
+        // The length is optional, so a missing value means the default one should be used
+        let short = opt_optional_value::<usize>(&mut args, &bare, "--short")?
+            .map(|len| len.unwrap_or(display::SHORT_VERSION_LEN));
+
+        if short == Some(0) {
+            return Err(anyhow!("--short must be at least 1"));
+        }
+
+        // The URL is optional, so a missing value means the default cache should be used
+        let check_cache = opt_optional_value::<String>(&mut args, &bare, "--check-cache")?
+            .map(|url| url.unwrap_or_else(|| cache::DEFAULT_CACHE.into()));
+
+        if check_cache.is_some() && !cfg!(feature = "binary-cache") {
+            return Err(anyhow!(
+                "--check-cache requires nixup to be built with the binary-cache feature"
+            ));
+        }
+
+        let cache_timeout = args
+            .opt_value_from_str("--cache-timeout")?
+            .unwrap_or(cache::DEFAULT_TIMEOUT);
+
+        if cache_timeout == 0 {
+            return Err(anyhow!("--cache-timeout must be at least 1"));
+        }
+
+        let data_dir: Option<PathBuf> = args.opt_value_from_str("--data-dir")?;
+
+        let verbose = args.contains(["-v", "--verbose"]);
+
+        let cmd = Self {
+            command,
             save_state: args.contains(["-s", "--save-state"]),
+            message: args.opt_value_from_str(["-m", "--message"])?,
+            list: args.contains(["-l", "--list"]),
+            data_dir,
+            nixpkgs: args.opt_value_from_str("--nixpkgs")?,
+            diff: DiffOptions {
+                scope,
+                suffix_as_version: args.contains("--diff-suffix-as-version"),
+                local,
+            },
+            query: args
+                .opt_value_from_str::<_, String>("--where")?
+                .map(|expr| expr.parse())
+                .transpose()?,
+            only: args.opt_value_from_str("--only")?,
+            system_packages: args.contains("--diff-system-packages"),
+            detect_renames: args.contains("--detect-renames"),
+            deps: DepOptions {
+                max_depth: match (
+                    args.opt_value_from_str::<_, DepMode>("--deps")?,
+                    args.opt_value_from_str("--depth")?,
+                    args.opt_value_from_str::<_, usize>("--max-depth")?,
+                ) {
+                    (Some(_), Some(_), _) => {
+                        return Err(anyhow!("--deps and --depth cannot be used together"))
+                    }
+                    (_, Some(_), Some(_)) => {
+                        return Err(anyhow!("--depth and --max-depth cannot be used together"))
+                    }
+                    (Some(DepMode::Closure), None, Some(0)) => {
+                        return Err(anyhow!("--max-depth must be at least 1"))
+                    }
+                    (Some(DepMode::Closure), None, Some(depth)) => Some(depth),
+                    (_, None, Some(_)) => {
+                        return Err(anyhow!("--max-depth can only be used with --deps closure"))
+                    }
+                    (Some(mode), None, None) => mode.max_depth(),
+                    // A depth of 0 is the only way to ask for every reference
+                    (None, Some(0), None) => None,
+                    (None, Some(depth), None) => Some(depth),
+                    (None, None, None) => DepOptions::default().max_depth,
+                },
+                max_nodes: args
+                    .opt_value_from_str("--max-closure-size")?
+                    .unwrap_or(closure::DEFAULT_MAX_NODES),
+            },
+            verbose,
+            display: DisplayOptions {
+                format: args
+                    .opt_value_from_str("--format")?
+                    .unwrap_or(Format::Human),
+                context: args.contains("--context"),
+                rebuilds: args.contains("--rebuilds"),
+                sort_deps: args
+                    .opt_value_from_str("--sort-deps")?
+                    .unwrap_or(DepSort::Name),
+                tree: args.contains("--tree"),
+                quiet_deps: args.contains("--quiet-deps"),
+                orientation: args
+                    .opt_value_from_str("--orientation")?
+                    .unwrap_or_default(),
+                staleness: args
+                    .opt_value_from_str::<_, PathBuf>("--show-staleness")?
+                    .map(|path| Manifest::load(&path))
+                    .transpose()?,
+                referrers: args.contains("--show-referrers"),
+                removed_deps: verbose,
+                waves,
+                short,
+                group_variants: args.contains("--group-variants"),
+            },
+            motd: args.contains("--motd"),
+            width: args.opt_value_from_str("--width")?.unwrap_or(80),
+            csv,
+            json,
+            json_stream,
+            after_command: args.opt_value_from_str("--after-command")?,
+            watch: args.opt_value_from_str("--watch")?,
+            emit_patch: args.opt_value_from_str("--emit-patch")?,
+            apply_patch: args.opt_value_from_str("--apply-patch")?,
+            always: args.contains("--always"),
+            fail_on_downgrade: args.contains("--fail-on-downgrade"),
+            exit_code: args.contains("--exit-code"),
+            diff_self: args.contains("--self"),
+            state_file: args.opt_value_from_str("--state-file")?,
+            promote: args.contains("--promote"),
+            diff_good: args.contains("--diff-good"),
+            timeout: args.opt_value_from_str("--timeout")?,
+            batch_size: args.opt_value_from_str("--batch-size")?,
+            scan: ScanOptions {
+                jobs: args
+                    .opt_value_from_str("--jobs")?
+                    .unwrap_or_else(ScanOptions::default_jobs),
+                low_priority: args.contains("--low-priority"),
+            },
+            store,
+            input_paths: args.opt_value_from_str("--input-paths")?,
+            check_cache,
+            cache_timeout,
+            dedup_across_states,
+            newest_only: args.contains("--newest-only"),
+            dup_policy: args.opt_value_from_str("--dup-policy")?,
+            iso_dates: args.contains("--iso-dates"),
+            theme: args.opt_value_from_str("--theme")?,
+            log_summary: args.contains("--log-summary"),
+            ci_annotations: args.contains("--ci-annotations"),
+            show_acked: args.contains("--show-acked"),
+            autosave: !args.contains("--no-autosave"),
+        };
+
+        // Anything left over is either unknown or a command that came after an option, and would otherwise be ignored
+        match args.finish() {
+            Ok(()) => (),
+            Err(pico_args::Error::UnusedArgsLeft(left)) if COMMANDS.contains(&left[0].as_str()) => {
+                return Err(anyhow!(
+                    "the {} command must come before any options",
+                    left[0]
+                ));
+            }
+            Err(pico_args::Error::UnusedArgsLeft(left)) => {
+                return Err(anyhow!("unknown arguments: {}", left.join(" ")));
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        if cmd.batch_size == Some(0) {
+            return Err(anyhow!("--batch-size must be at least 1"));
+        }
+
+        if cmd.scan.jobs == 0 {
+            return Err(anyhow!("--jobs must be at least 1"));
+        }
+
+        if cmd.store.is_some()
+            && (cmd.after_command.is_some() || cmd.watch.is_some() || cmd.emit_patch.is_some())
+        {
+            return Err(anyhow!(
+                "--store cannot be used with --after-command, --watch, or --emit-patch"
+            ));
+        }
+
+        if cmd.input_paths.is_some()
+            && (cmd.store.is_some()
+                || cmd.after_command.is_some()
+                || cmd.watch.is_some()
+                || cmd.emit_patch.is_some())
+        {
+            return Err(anyhow!(
+                "--input-paths cannot be used with --store, --after-command, --watch, or --emit-patch"
+            ));
+        }
+
+        if cmd.display.tree && cmd.display.format != Format::Human {
+            return Err(anyhow!("--tree can only be used with the human format"));
+        }
+
+        if cmd.display.quiet_deps && (cmd.display.tree || cmd.display.format != Format::Human) {
+            return Err(anyhow!(
+                "--quiet-deps can only be used with the human format, and not with --tree"
+            ));
+        }
+
+        if cmd.display.waves.is_some()
+            && (cmd.display.format.is_exclusive() || cmd.json || cmd.json_stream)
+        {
+            return Err(anyhow!(
+                "--waves cannot be used with --format ndjson, dot, or notify, --json, or --json-stream"
+            ));
+        }
+
+        if cmd.display.group_variants
+            && (cmd.display.format.is_exclusive() || cmd.json || cmd.json_stream)
+        {
+            return Err(anyhow!(
+                "--group-variants cannot be used with --format ndjson, dot, or notify, --json, or --json-stream"
+            ));
+        }
+
+        if cmd.display.format.is_exclusive() && (cmd.json || cmd.json_stream) {
+            return Err(anyhow!(
+                "--format ndjson, dot, and notify cannot be used with --json or --json-stream"
+            ));
+        }
+
+        if cmd.diff_self
+            && (cmd.save_state
+                || cmd.store.is_some()
+                || cmd.after_command.is_some()
+                || cmd.watch.is_some()
+                || cmd.emit_patch.is_some())
+        {
+            return Err(anyhow!(
+                "--self cannot be used with --save-state, --store, --after-command, --watch, or --emit-patch"
+            ));
         }
+
+        if cmd.state_file.is_some() && (cmd.save_state || cmd.apply_patch.is_some()) {
+            return Err(anyhow!(
+                "--state-file cannot be used with --save-state or --apply-patch"
+            ));
+        }
+
+        if cmd.promote
+            && (cmd.save_state
+                || cmd.command.is_some()
+                || cmd.after_command.is_some()
+                || cmd.watch.is_some()
+                || cmd.emit_patch.is_some()
+                || cmd.apply_patch.is_some()
+                || cmd.diff_self)
+        {
+            return Err(anyhow!(
+                "--promote cannot be used with a command, --save-state, --after-command, --watch, --emit-patch, --apply-patch, or --self"
+            ));
+        }
+
+        if cmd.diff_good && (cmd.state_file.is_some() || cmd.save_state || cmd.promote) {
+            return Err(anyhow!(
+                "--diff-good cannot be used with --state-file, --save-state, or --promote"
+            ));
+        }
+
+        if cmd.only.is_some()
+            && (cmd.saves()
+                || cmd.command.is_some()
+                || cmd.after_command.is_some()
+                || cmd.watch.is_some()
+                || cmd.emit_patch.is_some()
+                || cmd.apply_patch.is_some()
+                || cmd.diff_self
+                || cmd.json_stream
+                || cmd.csv.is_some()
+                || cmd.dedup_across_states.is_some()
+                || cmd.display.format.is_exclusive())
+        {
+            return Err(anyhow!(
+                "--only cannot be used with a command, --save-state, --promote, --after-command, --watch, --emit-patch, --apply-patch, --self, --json-stream, --csv, --dedup-across-states, or --format ndjson, dot, or notify"
+            ));
+        }
+
+        if cmd.system_packages
+            && (cmd.saves()
+                || cmd.store.is_some()
+                || cmd.input_paths.is_some()
+                || cmd.watch.is_some()
+                || cmd.emit_patch.is_some()
+                || cmd.diff_self
+                || cmd.motd
+                || cmd.only.is_some())
+        {
+            return Err(anyhow!(
+                "--diff-system-packages cannot be used with --save-state, --promote, --store, --input-paths, --watch, --emit-patch, --self, --motd, or --only"
+            ));
+        }
+
+        if cmd.exit_code && cmd.watch.is_some() {
+            return Err(anyhow!("--exit-code cannot be used with --watch"));
+        }
+
+        if cmd.ci_annotations
+            && (cmd.json
+                || cmd.json_stream
+                || cmd.csv == Some(None)
+                || cmd.display.format.is_exclusive())
+        {
+            return Err(anyhow!(
+                "--ci-annotations cannot be used with --json, --json-stream, --format ndjson, dot, or notify, or --csv without a path"
+            ));
+        }
+
+        if matches!(cmd.command, Some(Subcommand::Ack))
+            && (cmd.save_state
+                || cmd.motd
+                || cmd.after_command.is_some()
+                || cmd.watch.is_some()
+                || cmd.emit_patch.is_some()
+                || cmd.apply_patch.is_some()
+                || cmd.diff_self
+                || cmd.state_file.is_some()
+                || cmd.diff_good)
+        {
+            return Err(anyhow!(
+                "ack cannot be used with --save-state, --motd, --after-command, --watch, --emit-patch, --apply-patch, --self, --state-file, or --diff-good"
+            ));
+        }
+
+        if cmd.newest_only && cmd.dup_policy.is_some() {
+            return Err(anyhow!(
+                "--newest-only and --dup-policy cannot be used together"
+            ));
+        }
+
+        if cmd.dedup_across_states.is_some() && cmd.watch.is_some() {
+            return Err(anyhow!("--dedup-across-states cannot be used with --watch"));
+        }
+
+        if matches!(cmd.command, Some(Subcommand::VerifyState))
+            && (cmd.store.is_some() || cmd.input_paths.is_some())
+        {
+            return Err(anyhow!(
+                "--verify-state can only check the local nix store, and cannot be used with --store or --input-paths"
+            ));
+        }
+
+        if cmd.check_cache.is_some()
+            && (cmd.json
+                || cmd.json_stream
+                || cmd.csv == Some(None)
+                || cmd.display.format.is_exclusive()
+                || cmd.store.is_some()
+                || cmd.diff_self)
+        {
+            return Err(anyhow!(
+                "--check-cache cannot be used with --json, --json-stream, --format ndjson, dot, or notify, --csv without a path, --store, or --self"
+            ));
+        }
+
+        if matches!(cmd.command, Some(Subcommand::Fleet(_)))
+            && (cmd.json_stream || cmd.csv.is_some())
+        {
+            return Err(anyhow!("fleet cannot be used with --json-stream or --csv"));
+        }
+
+        Ok(cmd)
     }
 
     fn print_help() {
-        println!(concat!("Usage: ", env!("CARGO_PKG_NAME"), " [OPTIONS]\n"));
+        println!(concat!(
+            "Usage: ",
+            env!("CARGO_PKG_NAME"),
+            " [COMMAND] [OPTIONS]\n"
+        ));
+
+        println!("Commands:");
+        println!("  runs                show recent entries of the run log and a summary of them. Runs are only recorded when record_runs is set in config.toml in the data directory, or with --log-summary");
+        println!("  ack                 acknowledge every change in the current diff, so later diffs with exactly the same changes are summarized in a single line");
+        println!("  prune               remove snapshots and rotated run logs older than the duration given to --older-than, such as 30d or 12h. The current state is always kept unless --include-current is given, and --dry-run only lists what would be removed");
+        println!("  open <name>         start $SHELL in the store path of the current version of the given package, or open it with xdg-open if it isn't a directory. When the package has multiple outputs, --output <output> picks one, such as bin or out, and otherwise you're asked which one to open");
+        println!("  generate-unit       print systemd units that run nixup automatically, as chosen by --mode, such as post-rebuild");
+        println!("  audit [state]       show what the state file at the given path, or the current state if none is given, left out when it was saved: the paths that couldn't be parsed as packages, and the names of packages that had a store discarded as a duplicate or an older version. Only recorded for states saved with record_rejects = true in config.toml, and only the first {} of each are kept, sorted, along with how many more there were", MAX_REJECTS);
+        println!("  fleet <dir>         compare the package versions in the state files of several hosts in the given directory, such as web1.bin");
+        println!("  parse-path <path>   show how the given store path is parsed, such as /nix/store/<hash>-foo-1.2-bin: the name without its prefix, what each fragment between dashes was taken to be, which heuristics decided it, and the resulting name, version, and suffix. Useful for reporting packages that are parsed incorrectly\n");
 
         println!("Optional arguments:");
-        println!("  -h, --help        print this message");
-        println!("  -s, --save-state  save the current system package state. Run with this flag before a system update and without this flag after updating to see what was updated");
+        println!("  -h, --help          print this message");
+        println!("  -s, --save-state    save the current system package state. Run with this flag before a system update and without this flag after updating to see what was updated. The packages of each NixOS specialisation are saved too, and are diffed in a section of their own after the human formats");
+        println!("  -m, --message <msg> a message describing why the state is being saved. Shown when listing snapshots and when diffing against the state");
+        println!(
+            "  -l, --list          list the current package state and previously saved snapshots"
+        );
+        println!(
+            "  -v, --verbose       print additional information, such as how long each step took, the full cause of errors, and which dependencies left with each removed package"
+        );
+        println!("  --packages-only     only show packages whose own version changed, and hide their dependency changes. Cannot be used with --diff-only-deps");
+        println!("  --diff-only-deps    only show dependency changes, and hide packages whose only change was their own version. Cannot be used with --packages-only");
+        println!("  --quiet-deps        list every changed package, including those whose only change was a dependency, but don't print the dependency changes under them. Unlike --packages-only and --diff-only-deps, nothing is filtered out, so counts and JSON or CSV output are unaffected. Only applies to the human format, and cannot be used with --tree");
+        println!("  --diff-suffix-as-version  report packages whose suffix changed, such as staging to stable, even if their version didn't change");
+        println!("  --only-local        only show packages that were built on this machine rather than downloaded from a binary cache. Cannot be used with --no-local");
+        println!("  --no-local          hide packages that were built on this machine. Packages not known to be built locally are always shown");
+        println!("  --where <expr>      only show the changes matching the given expression, such as \"name ~ 'python*' and major\"");
+        println!("  --only <name>       show every change to a single package, including its dependencies that stayed the same");
+        println!("  --diff-system-packages  only diff the packages installed through environment.systemPackages and their dependencies, rather than every package in the store. The packages are the ones the sw link of the current system refers to, so this only works on NixOS with the local Nix database. The saved state is restricted to the same packages, so packages that were removed from environment.systemPackages since it was saved aren't shown as removed");
+        println!("  --detect-renames    show removed packages that were likely renamed to an added package, such as foo -> foo-ng (renamed?). A pair needs the same version, and names that share a run of at least 3 characters covering most of the shorter name. This is only a guess: unrelated packages like foo and foo-tools can be paired when their versions happen to match, and a rename that also changed the version is never found. Only shown by the human formats");
+        println!("  --format <format>   the output format to use. Can be human (default), human-compact, ndjson, dot, or notify");
+        println!("  --context           show the names of changed dependencies in formats that would otherwise only show a count");
+        println!("  --sort-deps <order> how to order the dependencies of each package. Can be name (default) or jump, which shows the largest version changes first");
+        println!("  --orientation <mode>  how to show the diff when the system was rolled back since the state was saved. Can be auto (default), which shows changes as reverted when a rollback is detected, forward, or reverse");
+        println!("  --show-staleness <manifest>  show how many releases behind the newest version each package is, using a manifest written by `nix-env -qa --json` for a channel. Packages missing from the manifest or with versions that can't be ordered, such as unstable-2024-01-01, are left unannotated. Only shown by the human formats");
+        println!("  --show-referrers    show how many other paths reference each package, highlighting packages that nothing references, as they can be garbage collected once no profile or GC root refers to them. Only counted for packages read from the local Nix database");
+        println!("  --nixpkgs <path>    show the attribute and file each package is defined in, found by scanning the nixpkgs checkout at the given path for pname declarations. The scan is cached in the data directory until the checkout's commit changes. Only shown by the human formats");
+        println!("  --show-acked        show changes that were acknowledged with the ack command in full");
+        println!("  --check-cache [url] show whether the binary cache at the given URL has the new version of each changed package, or whether it will have to be built, along with a summary of how many new package and dependency paths are cached and how much there is to download. Defaults to {}. Each path is requested with curl, up to {} at a time, and paths the cache didn't answer for in time are shown as unknown. Only applies to the human formats, and cannot be used with --store. Requires nixup to be built with the binary-cache feature", cache::DEFAULT_CACHE, cache::MAX_REQUESTS);
+        println!("  --cache-timeout <secs>  how long each request made by --check-cache can take. Defaults to {} seconds", cache::DEFAULT_TIMEOUT);
+        println!("  --short [chars]     cut off versions longer than the given number of characters with ..., such as the git hashes of packages pinned to a commit. Defaults to {} characters. Only applies to the human formats, so --json, --json-stream, --csv, and --format ndjson always have the full versions", display::SHORT_VERSION_LEN);
+        println!("  --group-variants    nest the variants of a package under a single header, with each variant indented below it. Variants are the packages kept under names like nss@3.96 by the keep-all-tagged duplicate policy; packages that only differ in their suffix are treated as one package, so they are never shown apart. Each variant still counts as its own update. Cannot be used with --format ndjson, dot, or notify, --json, or --json-stream");
+        println!("  --waves [secs]      split the package updates into waves by when their stores were registered, with a heading for each wave showing when it started and how many packages it has. Registrations more than the given number of seconds apart start a new wave, which defaults to {} seconds. Useful after several rebuilds between diffs. Packages whose own version didn't change go into the wave of their newest changed dependency. Cannot be used with --format ndjson, dot, or notify, --json, or --json-stream", waves::DEFAULT_GAP);
+        println!("  --tree              show the changed dependencies of each package as a tree that follows the stores they were found through, with runs of unchanged stores collapsed. Most useful with --depth");
+        println!("  --deps <mode>       which references count as a package's dependencies. direct (the default) only includes the paths the package refers to itself, so dependency changes are things it links against or uses directly. closure includes every path it transitively refers to, so dependency changes also include the dependencies of its dependencies. closure has to walk the entire closure of every changed package, so it's much slower. Cannot be used with --depth");
+        println!("  --max-depth <n>     limit --deps closure to the given number of levels of references, where 1 is the same as --deps direct. A limited closure is faster to walk, but misses changes to dependencies deeper than the limit");
+        println!("  --depth <n>         how many levels of references to follow when resolving dependencies, where 1 (the default) only includes direct references and 0 follows every reference. Deeper levels are slower and find more changes. Cannot be used with --deps");
+        println!("  --motd              print a single uncolored line summarizing the updates, suitable for a MOTD or login banner. Dependencies are not resolved, and any error results in a generic message");
+        println!("  --width <chars>     the maximum length of the line printed by --motd, and of the table printed by fleet. Defaults to 80");
+        println!("  --rebuilds          show packages that were rebuilt from a different derivation without their version changing");
+        println!("  --csv [path]        export the diff as CSV to the given path. When the path is omitted or is -, the CSV is written to stdout instead of the usual output");
+        println!("  --json              print the diff as a JSON document instead of the usual output, along with metadata such as the hostname and system generation");
+        println!("  --json-stream       like --json, but print a line of JSON for each package instead of a single document, preceded by a header line with the metadata and followed by a summary line");
+        println!("  --after-command <cmd>  save the current state, run the given shell command, and then show the diff against the saved state. Useful with commands like \"nixos-rebuild switch\"");
+        println!(
+            "  --always            show the diff from --after-command even if the command failed"
+        );
+        println!("  --fail-on-downgrade exit with an error after showing the diff if any package or dependency was downgraded. Only versions made up entirely of numbers are compared, and calendar versions such as 23.11 or 2024.02 are compared as dates. A change that could be read either way, such as 1.08 -> 1.8, is never counted as a downgrade");
+        println!("  --self              diff the dependencies of this executable's own store path against the saved state. Every dependency in its closure is resolved, but only dependencies recorded in the saved state can be compared, so save the state with --deps closure to compare all of them. Fails if the executable isn't in the Nix store");
+        println!("  --promote           save the current system package state as the known-good state, which is kept apart from the state saved with --save-state and is only replaced by promoting again. Combine with --store or --input-paths to promote those packages instead");
+        println!("  --diff-good         diff against the known-good state saved with --promote instead of the current state, to see how far the system has drifted from it regardless of how many times the state was saved since");
+        println!("  --state-file <path> diff against the state file at the given path instead of the current state in the data directory, such as a snapshot listed by --list. The file must be a state saved by nixup");
+        println!("  --verify-state      check whether every package and dependency of the state to diff against, which is the current state or the one given to --state-file, is still in the nix store, and show how many were garbage collected since it was saved. States don't keep the hashes of their paths, so a store counts as present when any valid path has the same name, version, and suffix. --verbose lists the missing ones");
+        println!("  --exit-code         exit with 0 if the packages are identical to the saved state, 2 if something changed, 3 if every update was hidden by --packages-only, --diff-only-deps, --only-local, or --no-local, and 4 if the saved state has no packages. Errors exit with 1");
+        println!("  --watch <secs>      keep running and show the diff again whenever new stores are registered, checking every given number of seconds");
+        println!("  --emit-patch <path> write a patch describing every change from the saved state to the current system, instead of showing the diff");
+        println!("  --apply-patch <path>  apply a patch made with --emit-patch to the saved state, and save the result as the new state. The saved state must be the one the patch was made from");
+        println!("  --store <uri>       read packages from the given Nix store through `nix path-info` instead of the local database. Supported stores are local paths, daemon, local, unix://<socket>, and ssh-ng://<host>. Binary caches (s3://, http(s)://, file://) and ssh:// stores can't list their paths, so they aren't supported. Dependencies are limited to direct references, and it cannot be used with --after-command, --watch, or --emit-patch");
+        println!("  --input-paths <path>  read packages from a file with a store path on each line, such as the output of `nix-store --gc --print-dead`, instead of the local database. Lines that aren't packages are skipped, and listed with --verbose. Nothing but the paths is known, so dependencies aren't resolved and every name listed with multiple versions is treated as a duplicate. Combine with --save-state to diff the current system against the list. Cannot be used with --store, --after-command, --watch, or --emit-patch");
+        println!("  --dup-policy <policy>  how to resolve a package name with differing versions registered within {} seconds of each other, instead of duplicate_policy in config.toml. drop (the default) leaves the name out, since there's no way to tell which version is in use. newest keeps the most recently registered version. keep-all-tagged keeps every version with the version appended to the name, such as nss@3.96, and highest-version is the same as --newest-only. Cannot be used with --newest-only", Store::DUPLICATE_WINDOW);
+        println!("  --newest-only       keep only the highest version of every package name, the same as setting duplicate_policy to highest-version");
+        println!("  --dedup-across-states [secs]  hide packages that had duplicate versions registered within the given number of seconds of each other in either the saved state or the current system, rather than only in the current system. Useful when the state was saved on a different machine. Defaults to and can't be less than {} seconds", Store::DUPLICATE_WINDOW);
+        println!("  --print-path <name> print the store path of the current version of the given package. Like the open command, but never asks which output to use, so packages with multiple outputs need --output <output>");
+        println!("  --explain <name>    show why the current system depends on the current version of the given package, as the chain of paths that refer to it, from the system profile down to the package. Only the shortest chain is shown, unless --all-paths is given, which shows every chain that doesn't go around a cycle, up to {}. Walking the referrers stops after --max-closure-size paths", explain::MAX_CHAINS);
+        println!("  --iso-dates         show dates as YYYY-MM-DD, even if date_format is set to locale in config.toml");
+        println!("  --theme <preset>    the colors and styles of the human formats, which can be default, colorblind (blue and orange instead of green and red), or mono (bold and underlined text only). Overrides the preset in the [theme] section of config.toml, where the style of each role can also be set, such as old-version = \"#e69f00 bold\"");
+        println!("  --ci-annotations    after the diff, print downgrades, changes to critical packages, and a pending reboot as GitHub Actions workflow commands so they show up in the checks of a run. Cannot be used with output that is only JSON, CSV, or a graph");
+        println!("  --log-summary       append a summary of the diff to the run log in the data directory, even if record_runs isn't set in config.toml");
+        println!("  --timeout <secs>    stop scanning stores and resolving dependencies after the given number of seconds, and show what was found so far. Saving a state is refused if time runs out");
+        println!("  --batch-size <rows>  how many paths to read from the Nix database at a time while scanning it. Smaller batches use less memory but take more queries. Defaults to 1024");
+        println!("  --jobs <n>          the most threads to split work such as saving and loading states across. Defaults to the number of cores, up to {}", jobs::MAX_DEFAULT_JOBS);
+        println!("  --low-priority      lower the CPU priority of nixup to the lowest nice value, and its disk priority to the idle class where the kernel supports it, before doing any work. Useful when running from a hook while the system is still busy");
+        println!("  --max-closure-size <n>  the maximum number of dependencies a single package can have before resolving them is aborted. Defaults to {}", closure::DEFAULT_MAX_NODES);
+        println!("  --no-autosave       don't save the previous generation automatically. Normally, every diff checks whether the system generation or the newest path in the Nix database changed since the last run, and if the saved state is older than the previous generation, that generation is saved as the new state before diffing, so there is always something to diff against. The previous state is kept as a snapshot. Generations are never saved automatically with --save-state, --after-command, --apply-patch, or --store");
+        println!("  --data-dir <path>   the directory to store all program state in. Overrides the NIXUP_DATA_DIR environment variable and the default of ~/.local/share/nixup");
 
         std::process::exit(0);
     }
+
+    /// Returns the filter of the query given with --where, if any.
+    fn filter<'a>(&'a self, critical: &'a CriticalList) -> Option<Filter<'a>> {
+        self.query.as_ref().map(|query| Filter { query, critical })
+    }
+
+    fn budget(&self) -> Budget {
+        Budget::new(self.timeout.map(Duration::from_secs))
+    }
+
+    /// Loads the state to diff against, which is the file given to `--state-file`, the known-good state with
+    /// `--diff-good`, or the current state in `data_dir`.
+    fn load_baseline(&self, data_dir: &Path) -> Result<PackageState> {
+        match &self.state_file {
+            Some(path) => PackageState::load_explicit(path),
+            None if self.diff_good => PackageState::load_good(data_dir).context(
+                "failed to load the known-good package state\nplease run with --promote first",
+            ),
+            None => PackageState::load(data_dir)
+                .context("failed to load system package state\nplease run with the -s flag first"),
+        }
+    }
+
+    /// Returns the path of the state `load_baseline` loads.
+    fn baseline_path(&self, data_dir: &Path) -> PathBuf {
+        match &self.state_file {
+            Some(path) => path.clone(),
+            None if self.diff_good => PackageState::good_path(data_dir),
+            None => PackageState::save_path(data_dir),
+        }
+    }
+
+    /// Returns true if the packages are saved rather than diffed.
+    fn saves(&self) -> bool {
+        self.save_state || self.promote
+    }
+
+    /// Returns true if the diff is shown in a human format, which is the only one that shows where packages are
+    /// defined in the checkout given to --nixpkgs.
+    fn shows_definitions(&self) -> bool {
+        matches!(self.display.format, Format::Human | Format::HumanCompact)
+            && self.command.is_none()
+            && !self.saves()
+            && !self.json
+            && !self.json_stream
+            && self.csv != Some(None)
+            && self.emit_patch.is_none()
+    }
+
+    /// Returns the policy to resolve names with multiple stores with, which is the one in `config` unless
+    /// --newest-only or --dup-policy was given.
+    fn dedup_policy(&self, config: &Config) -> DedupPolicy {
+        if self.newest_only {
+            DedupPolicy::HighestVersion
+        } else {
+            self.dup_policy.unwrap_or(config.duplicate_policy)
+        }
+    }
+
+    fn records_runs(&self, config: &Config) -> bool {
+        self.log_summary || config.record_runs
+    }
+
+    /// Returns true if the local system should be checked for a generation that was never saved.
+    ///
+    /// Modes that save the baseline themselves, or that don't read the local system, never save one automatically.
+    fn autosaves(&self) -> bool {
+        self.autosave
+            && !self.save_state
+            && self.store.is_none()
+            && self.input_paths.is_none()
+            && self.after_command.is_none()
+            && self.apply_patch.is_none()
+    }
+}
+
+fn main() {
+    // A MOTD should never disturb a login, so not even invalid arguments are reported
+    let motd = motd_requested();
+
+    let args = match CmdOptions::from_env() {
+        Ok(args) => args,
+        Err(_) if motd => {
+            println!("{}", motd::UNAVAILABLE);
+            return;
+        }
+        Err(err) => {
+            display::error(&err, backtrace_requested());
+            std::process::exit(1);
+        }
+    };
+
+    // Everything runs in our own pool so parallel work never uses more threads than --jobs allows
+    let result = args
+        .scan
+        .prepare()
+        .and_then(|pool| pool.install(|| run(&args)));
+
+    match result {
+        Ok(()) => (),
+        Err(_) if motd => println!("{}", motd::UNAVAILABLE),
+        Err(err) => {
+            display::error(&err, args.verbose || backtrace_requested());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The commands `nixup` takes as its first argument.
+const COMMANDS: &[&str] = &[
+    "runs",
+    "ack",
+    "prune",
+    "open",
+    "parse-path",
+    "audit",
+    "fleet",
+    "generate-unit",
+];
+
+/// Options whose value can be left out, such as `--csv` on its own to write the CSV to stdout.
+const OPTIONAL_VALUES: &[&str] = &[
+    "--csv",
+    "--dedup-across-states",
+    "--waves",
+    "--short",
+    "--check-cache",
+];
+
+/// Removes each option in `keys` that was passed without a value from `args`, and returns the ones that were.
+///
+/// pico_args would otherwise take whatever follows these options as their value, even when it's another option.
+/// A lone `-` is still a value, since it stands for stdout.
+fn take_bare_options(args: &mut Vec<OsString>, keys: &[&'static str]) -> HashSet<&'static str> {
+    let mut bare = HashSet::new();
+    let mut i = 0;
+
+    while i < args.len() && args[i] != "--" {
+        let key = keys.iter().find(|&&key| args[i] == key);
+
+        let has_value = args
+            .get(i + 1)
+            .is_some_and(|next| next == "-" || !next.to_string_lossy().starts_with('-'));
+
+        match key {
+            Some(&key) if !has_value => {
+                args.remove(i);
+                bare.insert(key);
+            }
+            _ => i += 1,
+        }
+    }
+
+    bare
+}
+
+/// Reads an option whose value can be left out, which is `Some(None)` when it was passed on its own.
+fn opt_optional_value<T>(
+    args: &mut pico_args::Arguments,
+    bare: &HashSet<&str>,
+    key: &'static str,
+) -> Result<Option<Option<T>>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match args.opt_value_from_str(key)? {
+        Some(value) => Ok(Some(Some(value))),
+        None if bare.contains(key) => Ok(Some(None)),
+        None => Ok(None),
+    }
+}
+
+/// Returns true if `--motd` was passed, which has to be known before the rest of the arguments are parsed.
+fn motd_requested() -> bool {
+    env::args_os()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == "--motd")
+}
+
+/// Returns true if `RUST_BACKTRACE` asks for backtraces, in which case errors are shown in full.
+fn backtrace_requested() -> bool {
+    env::var_os("RUST_BACKTRACE").is_some_and(|value| value != "0")
 }
 
-fn main() -> Result<()> {
-    let args = CmdOptions::from_env();
+fn run(args: &CmdOptions) -> Result<()> {
+    // A MOTD should never disturb a login, so we don't want to report any errors
+    if args.motd {
+        match motd_line(args) {
+            Ok(line) => println!("{}", line),
+            Err(_) => println!("{}", motd::UNAVAILABLE),
+        }
+
+        return Ok(());
+    }
+
+    let data_dir =
+        get_data_dir(args.data_dir.as_deref()).context("failed to get local data directory")?;
+
+    let config = Config::load(&data_dir)?;
+    display::format::init(config.locale(args.iso_dates));
+    theme::init(config.theme(args.theme)?);
+    ecosystem::init(config.ecosystem_rules()?);
+
+    if args.list {
+        return list_snapshots(&data_dir);
+    }
+
+    match &args.command {
+        Some(Subcommand::Runs) => return show_runs(&data_dir),
+        // Acknowledging needs the same diff as running without a command
+        Some(Subcommand::Ack) => (),
+        Some(Subcommand::Prune(opts)) => return prune_data_dir(&data_dir, opts),
+        Some(Subcommand::Open(opts)) => return open_package(args, opts),
+        Some(Subcommand::GenerateUnit(opts)) => return generate_unit(args, opts),
+        Some(Subcommand::ParsePath(path)) => {
+            display::parse_trace(&ParseTrace::new(path));
+            return Ok(());
+        }
+        Some(Subcommand::Audit(path)) => return audit_state(path.as_deref(), &data_dir),
+        Some(Subcommand::Fleet(dir)) => return fleet_summary(args, dir),
+        Some(Subcommand::VerifyState) => return verify_state(args, &data_dir),
+        Some(Subcommand::Explain { name, all_paths }) => {
+            return explain_package(args, name, *all_paths)
+        }
+        None => (),
+    }
+
+    // Scanning a nixpkgs checkout can take a while, so it's skipped when nothing would show what it found
+    if let Some(root) = args.nixpkgs.as_ref().filter(|_| args.shows_definitions()) {
+        nixpkgs::init(nixpkgs::Index::load(root, &data_dir)?);
+    }
+
+    if args.autosaves() {
+        autosave(args, &data_dir, &config);
+    }
+
+    if let Some(path) = &args.apply_patch {
+        return apply_patch(args, path, &data_dir);
+    }
+
+    if let Some(command) = &args.after_command {
+        return run_after_command(args, command, &data_dir);
+    }
+
+    if args.diff_self {
+        return diff_self(args, &data_dir);
+    }
+
+    if let Some(path) = &args.emit_patch {
+        return emit_patch(args, path, &data_dir);
+    }
+
+    if let Some(secs) = args.watch {
+        return watch(args, &data_dir, Duration::from_secs(secs));
+    }
+
+    if let Some(uri) = &args.store {
+        let remote = timed(args.verbose, "querying store", || RemoteStore::query(uri))?;
+        let source = Source::Remote(&remote);
+
+        return if args.saves() {
+            save_state(args, &data_dir, &source)
+        } else {
+            show_diff(args, &data_dir, &source)
+        };
+    }
+
+    if let Some(path) = &args.input_paths {
+        let input = InputPaths::load(path)?;
 
-    let system_db = SystemDatabase::open().context("failed to open nix database")?;
+        if args.verbose {
+            for (line, skipped) in &input.skipped {
+                eprintln!("skipping line {}, as it isn't a package: {}", line, skipped);
+            }
+        }
+
+        let source = Source::Input(&input);
+
+        return if args.saves() {
+            save_state(args, &data_dir, &source)
+        } else {
+            show_diff(args, &data_dir, &source)
+        };
+    }
 
-    if args.save_state {
-        let pkgs = Derivation::all_from_system(&system_db)
-            .context("failed to parse system derivations")?;
+    let system_db = open_database(args).context("failed to open nix database")?;
+    let source = Source::System(&system_db);
 
-        let state = PackageState::new(pkgs);
-        state.save().context("failed to save system package state")
+    if args.verbose {
+        match profile::resolve_profile(profile::SYSTEM_PROFILE) {
+            Ok(system) => eprintln!("current system is {}", system.display()),
+            Err(err) => eprintln!("failed to resolve current system: {}", err),
+        }
+    }
+
+    if args.saves() {
+        save_state(args, &data_dir, &source)
     } else {
-        let old_state = PackageState::load()
-            .context("failed to load system package state\nplease run with the -s flag first")?;
+        show_diff(args, &data_dir, &source)
+    }
+}
+
+/// Where the current packages are read from.
+enum Source<'a> {
+    System(&'a SystemDatabase),
+    Remote(&'a RemoteStore),
+    Input(&'a InputPaths),
+}
 
-        let cur_state = Derivation::all_from_system(&system_db)
-            .context("failed to parse system derivations")?;
+impl<'a> Source<'a> {
+    /// Returns every unique top-level store with duplicates resolved by `policy`, along with every store
+    /// that was left out of them.
+    ///
+    /// Every path that couldn't be parsed is added to `unparsed` if it's given, which isn't known for remote stores.
+    fn stores(
+        &self,
+        budget: &Budget,
+        policy: DedupPolicy,
+        unparsed: Option<&mut Vec<String>>,
+    ) -> Result<(HashSet<Store>, Vec<Store>)> {
+        match self {
+            Self::System(db) => Store::all_from_system_with_shadowed(db, budget, policy, unparsed),
+            Self::Remote(remote) => Ok(remote.stores(policy)),
+            Self::Input(input) => {
+                if let Some(unparsed) = unparsed {
+                    unparsed.extend(input.skipped.iter().map(|(_, line)| line.clone()));
+                }
 
-        display::package_diffs(cur_state, old_state.take());
-        Ok(())
+                Ok(input.stores(policy))
+            }
+        }
+    }
+
+    fn derivations(
+        &self,
+        stores: HashSet<Store>,
+        opts: DepOptions,
+        budget: &Budget,
+    ) -> Result<(HashSet<Derivation>, ClosureStats)> {
+        match self {
+            Self::System(db) => Derivation::all_from_stores(stores, db, opts, budget),
+            Self::Remote(remote) => Ok((remote.derivations(stores), ClosureStats::default())),
+            Self::Input(_) => Ok((InputPaths::derivations(stores), ClosureStats::default())),
+        }
+    }
+
+    /// Returns every store in `stores` along with its path, leaving out stores whose path can't be found.
+    ///
+    /// Paths aren't known for remote stores, so none are returned for them.
+    fn paths(&self, stores: Vec<Store>) -> Result<Vec<(Store, String)>> {
+        match self {
+            Self::System(db) => {
+                let ids = stores
+                    .iter()
+                    .map(|store| store.id as i32)
+                    .collect::<Vec<_>>();
+                let paths = cache::system_paths(db, &ids)?;
+
+                Ok(stores
+                    .into_iter()
+                    .filter_map(|store| {
+                        let path = paths.get(&store.id)?.clone();
+                        Some((store, path))
+                    })
+                    .collect())
+            }
+            Self::Input(input) => Ok(stores
+                .into_iter()
+                .filter_map(|store| {
+                    let path = input.path_of(store.id)?.to_string();
+                    Some((store, path))
+                })
+                .collect()),
+            Self::Remote(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Returns the generation of the system profile, if the packages come from this system and it can be read.
+    fn current_generation(&self) -> Option<u32> {
+        match self {
+            Self::System(_) => profile::current_generation(profile::SYSTEM_PROFILE).ok(),
+            Self::Remote(_) | Self::Input(_) => None,
+        }
+    }
+}
+
+fn save_state(args: &CmdOptions, data_dir: &Path, source: &Source) -> Result<()> {
+    let config = Config::load(data_dir)?;
+    let budget = args.budget();
+
+    let mut unparsed = Vec::new();
+
+    let (stores, shadowed) = source
+        .stores(
+            &budget,
+            args.dedup_policy(&config),
+            config.record_rejects.then_some(&mut unparsed),
+        )
+        .context("failed to parse system stores")?;
+
+    let stores = outputs::select(stores, &shadowed, &config.outputs).stores;
+
+    warn_clock_skew(&stores);
+
+    let (pkgs, stats) = source
+        .derivations(stores, args.deps, &budget)
+        .context("failed to parse system derivations")?;
+
+    let specialisations = match source {
+        Source::System(db) => current_specialisations(args, db)?
+            .map(|found| specialisation::resolve(db, found, args.deps, &budget))
+            .transpose()?,
+        Source::Remote(_) | Source::Input(_) => None,
+    };
+
+    if budget.is_partial() {
+        display::cutoffs(&budget);
+        return Err(anyhow!("refusing to save an incomplete package state"));
+    }
+
+    if args.verbose {
+        print_closure_stats(stats);
+    }
+
+    let mut state =
+        PackageState::new(pkgs, args.message.clone()).context("invalid package state")?;
+
+    if config.record_rejects {
+        state.rejects = Some(Rejects::new(unparsed, &shadowed, MAX_REJECTS));
+    }
+
+    state.shadowed = shadowed;
+    state.specialisations = specialisations;
+    state.meta.generation = source.current_generation();
+
+    if args.promote {
+        return state
+            .promote(data_dir)
+            .context("failed to promote system package state");
     }
+
+    state
+        .save(data_dir)
+        .context("failed to save system package state")
 }
 
-#[derive(Serialize, Deserialize)]
-struct PackageState(HashSet<Derivation>);
+/// Returns the stores of every specialisation of the current system by name, or `None` if there is no system profile.
+fn current_specialisations(
+    args: &CmdOptions,
+    db: &SystemDatabase,
+) -> Result<Option<BTreeMap<String, HashSet<Store>>>> {
+    let system = match profile::resolve_profile(profile::SYSTEM_PROFILE) {
+        Ok(system) => system,
+        Err(_) => return Ok(None),
+    };
+
+    timed(args.verbose, "scanning specialisations", || {
+        specialisation::find(db, &system, args.deps.max_nodes)
+    })
+    .map(Some)
+    .context("failed to scan the specialisations of the current system")
+}
 
-impl PackageState {
-    fn new(packages: HashSet<Derivation>) -> Self {
-        PackageState(packages)
+/// Saves the previous generation as the baseline if the system changed since the last run and it was never saved.
+///
+/// This is only a safety net, so failing to do so is a warning rather than an error.
+fn autosave(args: &CmdOptions, data_dir: &Path, config: &Config) {
+    // Systems without a system profile have no generations to save
+    if fs::symlink_metadata(profile::SYSTEM_PROFILE).is_err() {
+        return;
     }
 
-    fn save(&self) -> Result<()> {
-        let path = Self::save_path().context("failed to get system package state path")?;
+    let opts = AutosaveOptions {
+        policy: args.dedup_policy(config),
+        outputs: &config.outputs,
+        deps: args.deps,
+        record_rejects: config.record_rejects,
+    };
 
-        let mut file = File::create(&path).with_context(|| {
-            anyhow!("failed to create package state file at {}", path.display())
-        })?;
+    let result = open_database(args)
+        .context("failed to open nix database")
+        .and_then(|db| {
+            timed(args.verbose, "checking for an unsaved generation", || {
+                autosave::run(
+                    data_dir,
+                    Path::new(profile::SYSTEM_PROFILE),
+                    &db,
+                    opts,
+                    &args.budget(),
+                )
+            })
+        });
 
-        bincode::serialize_into(&mut file, self).with_context(|| {
-            anyhow!(
-                "failed to encode system package state to {}",
-                path.display()
-            )
+    match result {
+        Ok(Some(generation)) => display::autosaved(generation),
+        Ok(None) => (),
+        Err(err) => display::autosave_failed(&err),
+    }
+}
+
+/// Saves the current state, runs `command` through the shell, and shows the diff against the saved state.
+///
+/// The diff is only shown if the command succeeded, unless `--always` was specified.
+fn run_after_command(args: &CmdOptions, command: &str, data_dir: &Path) -> Result<()> {
+    {
+        let system_db = open_database(args).context("failed to open nix database")?;
+        save_state(args, data_dir, &Source::System(&system_db))?;
+    }
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => anyhow!("failed to run command: sh was not found in PATH"),
+            _ => anyhow!(err).context(format!("failed to run command: {}", command)),
         })?;
 
-        Ok(())
+    if !status.success() && !args.always {
+        return Err(anyhow!(
+            "command \"{}\" failed with {}\nrun with --always to show the diff anyway",
+            command,
+            status
+        ));
     }
 
-    fn load() -> Result<Self> {
-        let path = Self::save_path().context("failed to get system package state path")?;
+    // The database is opened as immutable, so it has to be reopened to see any changes the command made
+    let system_db = open_database(args).context("failed to reopen nix database")?;
+    show_diff(args, data_dir, &Source::System(&system_db))
+}
 
-        let file = File::open(&path)
-            .with_context(|| anyhow!("failed to open package state file at {}", path.display()))?;
+fn show_diff(args: &CmdOptions, data_dir: &Path, source: &Source) -> Result<()> {
+    if let Some(name) = &args.only {
+        return show_only(args, data_dir, source, name);
+    }
 
-        let state = bincode::deserialize_from(file).with_context(|| {
-            anyhow!(
-                "failed to decode system package state from {}",
-                path.display()
-            )
-        })?;
+    let start = Instant::now();
+    let config = Config::load(data_dir)?;
 
-        Ok(state)
+    let mut old_state = args.load_baseline(data_dir)?;
+
+    let baseline_time = old_state.meta.saved_at;
+    let scan_start = Instant::now();
+
+    let budget = args.budget();
+
+    let (stores, shadowed) = timed(args.verbose, "scanning system stores", || {
+        source.stores(&budget, args.dedup_policy(&config), None)
+    })
+    .context("failed to parse system stores")?;
+
+    let mut selection = outputs::select(stores, &shadowed, &config.outputs);
+
+    if args.system_packages {
+        let names = system_packages(source)?;
+
+        selection.stores.retain(|store| names.contains(&store.name));
+        old_state
+            .packages
+            .retain(|pkg| names.contains(&pkg.store.name));
+    }
+
+    let scan_time = scan_start.elapsed();
+
+    if let Some(window) = args.dedup_across_states {
+        let removed = diff::dedup_across_states(
+            &mut selection.stores,
+            &shadowed,
+            &mut old_state.packages,
+            &old_state.shadowed,
+            window,
+        );
+
+        if args.verbose {
+            eprintln!(
+                "removed {} with duplicates in either state: {}",
+                display::format::locale().plural(removed.len(), "package", "packages"),
+                removed.join(", ")
+            );
+        }
+    }
+
+    let changes = diff_stores(
+        args,
+        data_dir,
+        old_state,
+        selection,
+        source,
+        &budget,
+        &config.critical_list(),
+    )?;
+
+    if matches!(args.command, Some(Subcommand::Ack)) {
+        return Ok(());
     }
 
-    fn save_path() -> Result<PathBuf> {
-        let path = get_data_dir()
-            .context("failed to get local data directory")?
-            .join("packages.bin");
+    let downgrades = changes.downgrades.clone();
+    let outcome = changes.outcome;
+
+    if args.records_runs(&config) {
+        let mode = if args.after_command.is_some() {
+            RunMode::AfterCommand
+        } else {
+            RunMode::Diff
+        };
+
+        let run = Run::new(
+            state::now(),
+            mode,
+            baseline_time,
+            scan_time,
+            start.elapsed(),
+            changes,
+            config.record_names,
+        );
 
-        Ok(path)
+        record_run(data_dir, &run);
     }
 
-    #[inline(always)]
-    fn take(self) -> HashSet<Derivation> {
-        self.0
+    if args.fail_on_downgrade && !downgrades.is_empty() {
+        display::downgrades(&downgrades);
+        return Err(anyhow!(
+            "{} downgraded",
+            display::format::locale().plural(downgrades.len(), "version was", "versions were")
+        ));
     }
+
+    if args.exit_code {
+        // Exiting right away skips destructors, so anything left in the stdout buffer has to be flushed first
+        io::stdout().flush().context("failed to flush stdout")?;
+        std::process::exit(outcome.exit_code());
+    }
+
+    Ok(())
+}
+
+/// Returns the names of the packages installed through `environment.systemPackages` in the current system.
+fn system_packages(source: &Source) -> Result<HashSet<String>> {
+    let db = match source {
+        Source::System(db) => db,
+        _ => {
+            return Err(anyhow!(
+                "--diff-system-packages can only be used with the local nix database"
+            ))
+        }
+    };
+
+    let link = Path::new(profile::SYSTEM_PROFILE).join(toplevel::SYSTEM_PATH_LINK);
+
+    let env_path = profile::resolve_profile(&link)
+        .context("failed to find the packages installed through environment.systemPackages")?;
+
+    toplevel::package_names(db, &env_path).with_context(|| {
+        anyhow!(
+            "failed to get the packages installed through {}",
+            env_path.display()
+        )
+    })
 }
 
-fn get_data_dir() -> Result<PathBuf> {
-    let dir = dirs_next::data_local_dir()
-        .unwrap_or_else(|| PathBuf::from("~/.local/share/"))
-        .join(env!("CARGO_PKG_NAME"));
+/// Shows every change to the package called `name` and its dependencies since the saved state.
+///
+/// Only the dependencies of the package are resolved, so this is much faster than a full diff, especially with
+/// `--deps closure`.
+fn show_only(args: &CmdOptions, data_dir: &Path, source: &Source, name: &str) -> Result<()> {
+    let config = Config::load(data_dir)?;
+    let old_state = args.load_baseline(data_dir)?;
+    let budget = args.budget();
+
+    let (stores, shadowed) = timed(args.verbose, "scanning system stores", || {
+        source.stores(&budget, args.dedup_policy(&config), None)
+    })
+    .context("failed to parse system stores")?;
+
+    // A package that wasn't scanned would look removed, and one that wasn't resolved would lose every dependency
+    let refuse_partial = || {
+        if budget.is_partial() {
+            display::cutoffs(&budget);
+            return Err(anyhow!("refusing to show {} from incomplete results", name));
+        }
+
+        Ok(())
+    };
+
+    refuse_partial()?;
+
+    let selection = outputs::select(stores, &shadowed, &config.outputs);
+    let target = drilldown::resolve(name, &selection.stores, &old_state.packages)?;
+
+    let new = match target.new {
+        Some(store) => {
+            let stores = iter::once(store.clone()).collect();
+
+            let (mut derivations, _) = timed(args.verbose, "resolving dependencies", || {
+                source.derivations(stores, args.deps, &budget)
+            })
+            .with_context(|| anyhow!("failed to resolve the dependencies of {}", store.name))?;
+
+            refuse_partial()?;
+
+            derivations.take(store.name.as_str())
+        }
+        None => None,
+    };
+
+    let drilldown = Drilldown::new(new.as_ref(), target.old, args.diff.suffix_as_version);
+
+    if args.json {
+        let meta = json::Meta::current(&old_state.meta, args.query.as_ref());
+
+        return json::write_drilldown(io::stdout().lock(), &meta, &drilldown)
+            .context("failed to write diff as JSON");
+    }
+
+    display::drilldown::display(&drilldown, args.display.short);
+    Ok(())
+}
+
+/// Diffs the entire closure of our own store path against our dependencies in the saved state.
+///
+/// Only dependencies recorded in the saved state can be compared, so states saved with the default
+/// of direct dependencies only show changes to our direct dependencies.
+fn diff_self(args: &CmdOptions, data_dir: &Path) -> Result<()> {
+    let config = Config::load(data_dir)?;
+    let mut old_state = args.load_baseline(data_dir)?;
+
+    let exe = env::current_exe()
+        .and_then(fs::canonicalize)
+        .context("failed to get the path of the current executable")?;
+
+    let root = profile::store_path_of(&exe).ok_or_else(|| {
+        anyhow!(
+            "{} is not in {}, so it has no closure to diff\n--self only works when {} was installed through Nix",
+            exe.display(),
+            profile::STORE_DIR,
+            env!("CARGO_PKG_NAME")
+        )
+    })?;
+
+    let system_db = open_database(args).context("failed to open nix database")?;
+
+    let store = Store::from_system_path(&system_db, &root)?
+        .ok_or_else(|| anyhow!("{} is not a valid path in the nix database", root.display()))?;
+
+    old_state
+        .packages
+        .retain(|pkg| pkg.store.name == store.name);
+
+    if old_state.packages.is_empty() {
+        return Err(anyhow!(
+            "{} is not in the saved state, so there is nothing to diff its closure against",
+            store.name
+        ));
+    }
+
+    let opts = DepOptions {
+        max_depth: DepMode::Closure.max_depth(),
+        ..args.deps
+    };
+
+    let budget = args.budget();
+    let stores = iter::once(store).collect();
+
+    let (cur_state, stats) = timed(args.verbose, "resolving our own closure", || {
+        Derivation::all_from_stores(stores, &system_db, opts, &budget)
+    })
+    .context("failed to resolve our own closure")?;
+
+    if budget.is_partial() {
+        display::cutoffs(&budget);
+    }
+
+    if args.verbose {
+        print_closure_stats(stats);
+    }
+
+    let critical_list = config.critical_list();
+    let filter = args.filter(&critical_list);
+
+    let all = diff::get_package_diffs(&cur_state, &old_state.packages, args.diff.unfiltered());
+    let mut reported = diff::get_package_diffs(&cur_state, &old_state.packages, args.diff);
+
+    if let Some(filter) = filter {
+        filter.apply(&mut reported);
+    }
+
+    let critical = critical::changes(&all, &reported, &critical_list);
+
+    let counts = DiffCounts {
+        baseline: old_state.packages.len(),
+        added: 0,
+        removed: 0,
+        unfiltered: all.len(),
+        reported: reported.len(),
+    };
+
+    let findings = display::Findings {
+        counts,
+        critical: &critical,
+        removals: &[],
+        cache: None,
+        acked: None,
+        outputs: None,
+        renames: None,
+    };
+
+    display::package_diffs(
+        cur_state,
+        old_state,
+        args.diff,
+        &args.display,
+        findings,
+        filter,
+        None,
+    )
+    .context("failed to write diff")
+}
+
+/// Asks the binary cache at `url` whether it has the new version of every package and dependency in `diffs`.
+fn check_cache(
+    args: &CmdOptions,
+    url: &str,
+    diffs: &[diff::PackageDiff],
+    cur_state: &HashSet<Derivation>,
+    source: &Source,
+) -> Result<CacheReport> {
+    let mut stores = HashMap::new();
+
+    for diff in diffs {
+        let pkg = match cur_state.get(diff.name.as_str()) {
+            Some(pkg) => pkg,
+            None => continue,
+        };
+
+        if diff.pkg.is_some() {
+            stores
+                .entry(pkg.store.key())
+                .or_insert_with(|| pkg.store.clone());
+        }
+
+        for dep in diff
+            .deps
+            .iter()
+            .filter_map(|dep| pkg.deps.get(dep.name.as_str()))
+        {
+            stores.entry(dep.key()).or_insert_with(|| dep.clone());
+        }
+    }
+
+    let stores = source
+        .paths(stores.into_values().collect())
+        .context("failed to find the paths of new stores")?;
+
+    let timeout = Duration::from_secs(args.cache_timeout);
+
+    Ok(timed(args.verbose, "checking binary cache", || {
+        CacheReport::check(url, stores, timeout)
+    }))
+}
+
+/// Describes every package and dependency in `diffs` whose version clearly went down.
+fn find_downgrades(diffs: &[diff::PackageDiff]) -> Vec<String> {
+    let mut downgrades = Vec::new();
+
+    for diff in diffs {
+        if let Some(pkg) = diff.pkg.as_ref().filter(|pkg| pkg.is_downgrade()) {
+            downgrades.push(format!("{}: {} -> {}", pkg.name, pkg.ver_from, pkg.ver_to));
+        }
+
+        for dep in diff.deps.iter().filter(|dep| dep.is_downgrade()) {
+            downgrades.push(format!(
+                "{} (dependency of {}): {} -> {}",
+                dep.name, diff.name, dep.ver_from, dep.ver_to
+            ));
+        }
+    }
+
+    downgrades
+}
+
+/// Shows the diff of the stores in `selection` against `old_state` in the formats specified by `args`, and returns what changed.
+///
+/// If `budget` expired while getting the stores or resolving their dependencies, a notice is shown before the diff.
+/// Changes to packages matching `critical_list` are always reported, even when the diff options would hide them.
+fn diff_stores(
+    args: &CmdOptions,
+    data_dir: &Path,
+    mut old_state: PackageState,
+    selection: Selection,
+    source: &Source,
+    budget: &Budget,
+    critical_list: &CriticalList,
+) -> Result<runs::Changes> {
+    let Selection { stores, outputs } = selection;
+
+    warn_clock_skew(&stores);
+    warn_parser_changed(&old_state.meta);
+
+    let added = stores
+        .iter()
+        .filter(|store| !old_state.packages.contains(store.name.as_str()))
+        .count();
+
+    let top_level = stores
+        .iter()
+        .map(|store| store.name.clone())
+        .collect::<HashSet<_>>();
+
+    let rollback = rollback::detect(
+        &old_state.meta,
+        source.current_generation(),
+        &stores,
+        &old_state.packages,
+        state::now(),
+    );
+
+    // Resolving dependencies is by far the slowest step, so we only want to do it for
+    // packages that could actually have a diff
+    let num_stores = stores.len();
+    let changed = diff::changed_stores(stores, &old_state.packages);
+    let num_changed = changed.len();
+
+    let (cur_state, stats) = timed(args.verbose, "resolving changed dependencies", || {
+        source.derivations(changed, args.deps, budget)
+    })
+    .context("failed to parse system derivations")?;
+
+    if budget.is_partial() {
+        display::cutoffs(budget);
+    }
+
+    if args.verbose {
+        eprintln!(
+            "resolved dependencies for {} of {} packages",
+            num_changed, num_stores
+        );
+
+        print_closure_stats(stats);
+    }
+
+    let filter = args.filter(critical_list);
+
+    let mut diffs = diff::get_package_diffs(&cur_state, &old_state.packages, args.diff);
+
+    if let Some(filter) = filter {
+        filter.apply(&mut diffs);
+    }
+
+    diffs.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+
+    let mut removals =
+        match diff::find_removals(&old_state.packages, &top_level, &cur_state, budget) {
+            Some(removals) => removals,
+            None => {
+                display::removals_skipped();
+                Vec::new()
+            }
+        };
+
+    let acking = matches!(args.command, Some(Subcommand::Ack));
+
+    let acked = if acking || args.show_acked {
+        None
+    } else {
+        Acks::load(data_dir)
+    };
+
+    // Acknowledgments are made of every change before filtering, so filters can't change which diffs count as the same
+    let current_acks = (acking || acked.is_some()).then(|| {
+        let all = display::collect_diffs(
+            &cur_state,
+            &old_state.packages,
+            args.diff.unfiltered(),
+            None,
+        );
+
+        Acks::new(&all, &removals)
+    });
+
+    if let Some(filter) = filter {
+        removals.retain(|removal| filter.matches_removal(removal));
+    }
+
+    let removed = removals.len();
+
+    // Updates left out by the diff options are counted so an empty diff can say why it's empty,
+    // and critical packages are picked out of them so they can't be hidden
+    let (unfiltered, critical) = if args.diff.is_filtered() || filter.is_some() {
+        let all = diff::get_package_diffs(&cur_state, &old_state.packages, args.diff.unfiltered());
+        (all.len(), critical::changes(&all, &diffs, critical_list))
+    } else {
+        (
+            diffs.len(),
+            critical::changes(&diffs, &diffs, critical_list),
+        )
+    };
+
+    // Outputs split off from an updated package aren't really new packages
+    let split = diff::group_split_outputs(&mut diffs, &cur_state, &old_state.packages);
+
+    let renames = args.detect_renames.then(|| {
+        let split_outputs = diffs
+            .iter()
+            .flat_map(|diff| &diff.split_outputs)
+            .collect::<HashSet<_>>();
+
+        let added = cur_state.iter().map(|pkg| &pkg.store).filter(|store| {
+            !old_state.packages.contains(store.name.as_str())
+                && !split_outputs.contains(&store.name)
+        });
+
+        diff::detect_renames(&removals, added)
+    });
+
+    let counts = DiffCounts {
+        baseline: old_state.packages.len(),
+        added: added - split,
+        removed,
+        unfiltered,
+        reported: diffs.len(),
+    };
+
+    let changes = runs::Changes {
+        updated: diffs.iter().map(|diff| diff.name.clone()).collect(),
+        added: counts.added,
+        removed,
+        downgrades: find_downgrades(&diffs),
+        outcome: counts.outcome(),
+    };
+
+    if args.csv.is_some() || args.json || args.json_stream {
+        if let Some(path) = &args.csv {
+            export_csv(path.as_deref(), &diffs).context("failed to export diff as CSV")?;
+        }
+
+        if args.json {
+            let meta = json::Meta::current(&old_state.meta, args.query.as_ref());
+
+            json::write_package_diffs(
+                io::stdout().lock(),
+                &meta,
+                &diffs,
+                &critical,
+                &removals,
+                changes.outcome,
+            )
+            .context("failed to write diff as JSON")?;
+        }
+
+        if args.json_stream {
+            let meta = json::Meta::current(&old_state.meta, args.query.as_ref());
+
+            json::stream_package_diffs(
+                io::stdout().lock(),
+                &meta,
+                &diffs,
+                &critical,
+                &removals,
+                changes.outcome,
+            )
+            .context("failed to stream diff as JSON")?;
+        }
+
+        // Machine-readable output on stdout shouldn't be mixed with the usual output
+        if args.json || args.json_stream || args.csv == Some(None) {
+            return Ok(changes);
+        }
+    }
+
+    if let Some(current) = current_acks {
+        if acking {
+            current
+                .save(data_dir)
+                .context("failed to save acknowledged changes")?;
+            display::acknowledged(current.updates);
+            return Ok(changes);
+        }
+
+        if let Some(acked) = acked.as_ref().filter(|acked| acked.is_identical(&current)) {
+            display::pending_acks(acked.updates);
+            return Ok(changes);
+        }
+    }
+
+    let cache = match &args.check_cache {
+        Some(url) => Some(check_cache(args, url, &diffs, &cur_state, source)?),
+        None => None,
+    };
+
+    let findings = display::Findings {
+        counts,
+        critical: &critical,
+        removals: &removals,
+        cache: cache.as_ref(),
+        acked: acked.as_ref(),
+        outputs: Some(&outputs),
+        renames: renames.as_ref(),
+    };
+
+    let old_specialisations = old_state.specialisations.take();
+
+    timed(args.verbose, "diffing packages", || {
+        display::package_diffs(
+            cur_state,
+            old_state,
+            args.diff,
+            &args.display,
+            findings,
+            filter,
+            rollback.as_ref(),
+        )
+    })
+    .context("failed to write diff")?;
+
+    // States that didn't look for specialisations have nothing to compare them against
+    if let (Some(old), Source::System(db)) = (&old_specialisations, source) {
+        if let Some(found) = current_specialisations(args, db)? {
+            let comparison =
+                specialisation::Comparison::new(db, old, found, args.deps, args.diff, budget)?;
+
+            display::specialisations(comparison, &args.display);
+        }
+    }
+
+    if args.ci_annotations {
+        let annotations = annotation::collect(&changes, &critical, motd::is_reboot_pending());
+
+        for annotation in annotations {
+            println!("{}", annotation);
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Returns the stores `scanner` found with duplicates resolved by `policy`, and the output `config` prefers
+/// picked for each package.
+fn scanned(scanner: &IncrementalScanner, policy: DedupPolicy, config: &Config) -> Selection {
+    let (stores, shadowed) = scanner.stores(policy);
+    outputs::select(stores, &shadowed, &config.outputs)
+}
+
+/// Shows the diff, and then shows it again every time new stores are registered.
+///
+/// Only newly registered paths are scanned on each check, unless paths were garbage collected.
+fn watch(args: &CmdOptions, data_dir: &Path, interval: Duration) -> Result<()> {
+    let config = Config::load(data_dir)?;
+
+    let old_state = args.load_baseline(data_dir)?;
+
+    let system_db = open_database(args).context("failed to open nix database")?;
+
+    let start = Instant::now();
+
+    let mut scanner = timed(args.verbose, "scanning system stores", || {
+        IncrementalScanner::new(&system_db)
+    })
+    .context("failed to parse system stores")?;
+
+    let scan_time = start.elapsed();
+    let baseline_time = old_state.meta.saved_at;
+
+    let changes = diff_stores(
+        args,
+        data_dir,
+        old_state,
+        scanned(&scanner, args.dedup_policy(&config), &config),
+        &Source::System(&system_db),
+        &args.budget(),
+        &config.critical_list(),
+    )?;
+
+    if args.records_runs(&config) {
+        let run = Run::new(
+            state::now(),
+            RunMode::Watch,
+            baseline_time,
+            scan_time,
+            start.elapsed(),
+            changes,
+            config.record_names,
+        );
+
+        record_run(data_dir, &run);
+    }
+
+    loop {
+        thread::sleep(interval);
+
+        let start = Instant::now();
+
+        // The database is opened as immutable, so it has to be reopened to see any new paths
+        let system_db = open_database(args).context("failed to reopen nix database")?;
+
+        let refresh = timed(args.verbose, "refreshing system stores", || {
+            scanner.refresh(&system_db)
+        })
+        .context("failed to refresh system stores")?;
+
+        if args.verbose {
+            eprintln!("store refresh: {:?}", refresh);
+        }
+
+        if !refresh.changed() {
+            continue;
+        }
+
+        let scan_time = start.elapsed();
+
+        // The baseline may have been saved again while we were waiting
+        let old_state = args.load_baseline(data_dir)?;
+
+        let baseline_time = old_state.meta.saved_at;
+
+        println!();
+        let changes = diff_stores(
+            args,
+            data_dir,
+            old_state,
+            scanned(&scanner, args.dedup_policy(&config), &config),
+            &Source::System(&system_db),
+            &args.budget(),
+            &config.critical_list(),
+        )?;
+
+        if args.records_runs(&config) {
+            let run = Run::new(
+                state::now(),
+                RunMode::Watch,
+                baseline_time,
+                scan_time,
+                start.elapsed(),
+                changes,
+                config.record_names,
+            );
+
+            record_run(data_dir, &run);
+        }
+    }
+}
+
+/// Appends `run` to the run log in `data_dir`.
+fn record_run(data_dir: &Path, run: &Run) {
+    // The log is only for the user's own curiosity, so it shouldn't get in the way of the diff
+    if let Err(err) = RunLog::new(data_dir).append(run) {
+        eprintln!("failed to record run: {:?}", err);
+    }
+}
+
+/// Writes a patch from the saved state to the current system to `path`.
+///
+/// Unlike diffing, every package needs to have its dependencies resolved so the patch can recreate them.
+fn emit_patch(args: &CmdOptions, path: &Path, data_dir: &Path) -> Result<()> {
+    let config = Config::load(data_dir)?;
+
+    let old_state = args.load_baseline(data_dir)?;
+
+    let system_db = open_database(args).context("failed to open nix database")?;
+
+    let budget = args.budget();
+
+    let (cur_state, _) = timed(args.verbose, "resolving all dependencies", || {
+        Derivation::all_from_system(&system_db, args.deps, args.dedup_policy(&config), &budget)
+    })
+    .context("failed to parse system derivations")?;
+
+    // A patch made from partial results would remove every dependency that wasn't resolved
+    if budget.is_partial() {
+        display::cutoffs(&budget);
+        return Err(anyhow!("refusing to write a patch from incomplete results"));
+    }
+
+    let patch = patch::Patch::new(&old_state.packages, &cur_state);
+    patch.save(path).context("failed to save patch")?;
+
+    let locale = display::format::locale();
+
+    println!(
+        "wrote patch with {} added, {} removed, and {} to {}",
+        locale.count(patch.added.len()),
+        locale.count(patch.removed.len()),
+        locale.plural(patch.changed.len(), "changed package", "changed packages"),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Applies the patch at `path` to the saved state and saves the result as the new state.
+fn apply_patch(args: &CmdOptions, path: &Path, data_dir: &Path) -> Result<()> {
+    let patch = patch::Patch::load(path)?;
+
+    let old_state = PackageState::load(data_dir).context("failed to load system package state")?;
+
+    let packages = patch
+        .apply(old_state.packages)
+        .context("failed to apply patch")?;
+
+    let state =
+        PackageState::new(packages, args.message.clone()).context("invalid package state")?;
+
+    state
+        .save(data_dir)
+        .context("failed to save patched package state")
+}
+
+fn export_csv(path: Option<&Path>, diffs: &[diff::PackageDiff]) -> Result<()> {
+    match path {
+        Some(path) => {
+            let file = File::create(path)
+                .with_context(|| anyhow!("failed to create CSV file at {}", path.display()))?;
+
+            csv::write_package_diffs(BufWriter::new(file), diffs)
+        }
+        None => csv::write_package_diffs(io::stdout().lock(), diffs),
+    }
+}
+
+fn list_snapshots(data_dir: &Path) -> Result<()> {
+    let snapshots = state::list_snapshots(data_dir).context("failed to list snapshots")?;
+
+    if snapshots.is_empty() {
+        println!("no package states have been saved yet");
+        return Ok(());
+    }
+
+    for snapshot in snapshots {
+        display::snapshot(&snapshot);
+    }
+
+    Ok(())
+}
+
+fn prune_data_dir(data_dir: &Path, opts: &PruneOptions) -> Result<()> {
+    let removals =
+        prune::prune(data_dir, opts, state::now()).context("failed to prune data directory")?;
+
+    if removals.is_empty() {
+        println!("nothing to prune");
+        return Ok(());
+    }
+
+    for removal in &removals {
+        display::removal(removal, opts.dry_run);
+    }
+
+    let freed = removals.iter().map(|removal| removal.size).sum();
+    display::prune_summary(removals.len(), freed, opts.dry_run);
+
+    Ok(())
+}
+
+/// Opens or prints the store path of the current version of the package described by `opts`.
+///
+/// The user is only asked which output to use when stdin is a terminal.
+fn open_package(args: &CmdOptions, opts: &OpenOptions) -> Result<()> {
+    let system_db = open_database(args).context("failed to open nix database")?;
+    let paths = open::find_current(&system_db, &opts.name)?;
+
+    if paths.is_empty() {
+        return Err(anyhow!("no package named {} was found", opts.name));
+    }
+
+    let prompt = if !opts.print_only && io::stdin().is_terminal() {
+        Some(|paths: &[StorePath]| open::prompt(paths, io::stdin().lock(), io::stderr()))
+    } else {
+        None
+    };
+
+    let path = open::choose(paths, opts.output.as_deref(), prompt)
+        .with_context(|| anyhow!("failed to pick a path of {}", opts.name))?;
+
+    if opts.print_only {
+        println!("{}", path.path.display());
+        return Ok(());
+    }
+
+    open::open_path(&path.path, &mut open::ProcessSpawner)
+}
+
+/// Prints what the state at `path`, or the current state in `data_dir` if it isn't given, left out when it was saved.
+fn audit_state(path: Option<&Path>, data_dir: &Path) -> Result<()> {
+    let (path, state) = match path {
+        Some(path) => (path.to_path_buf(), PackageState::load_explicit(path)?),
+        None => {
+            let path = PackageState::save_path(data_dir);
+            let state = PackageState::load_from(&path)?;
+            (path, state)
+        }
+    };
+
+    let rejects = state.rejects.as_ref().ok_or_else(|| {
+        anyhow!(
+            "{} has no record of what it left out, as record_rejects wasn't set in config.toml when it was saved",
+            path.display()
+        )
+    })?;
+
+    display::audit(&path, &state.meta, rejects);
+    Ok(())
+}
+
+/// Prints the versions of every package that differs between the hosts whose states are in `dir`.
+fn fleet_summary(args: &CmdOptions, dir: &Path) -> Result<()> {
+    let hosts = Host::load_dir(dir)?;
+
+    if hosts.is_empty() {
+        return Err(anyhow!("no state files were found in {}", dir.display()));
+    }
+
+    for host in &hosts {
+        if let Err(err) = &host.packages {
+            display::fleet_host_failed(&host.name, err);
+        }
+    }
+
+    let matrix = Matrix::new(&hosts);
+
+    if args.json {
+        let stdout = io::stdout();
+        return json::write_fleet(stdout.lock(), &matrix);
+    }
+
+    display::fleet(&matrix, args.width);
+    Ok(())
+}
+
+/// Prints how many stores of the state to diff against were garbage collected since it was saved.
+fn verify_state(args: &CmdOptions, data_dir: &Path) -> Result<()> {
+    let state = args.load_baseline(data_dir)?;
+
+    let path = args.baseline_path(data_dir);
+
+    let system_db = open_database(args).context("failed to open nix database")?;
+
+    let verification = timed(args.verbose, "checking stores", || {
+        Verification::check(&system_db, &state.packages)
+    })?;
+
+    display::verification(&path, &verification, args.verbose);
+    Ok(())
+}
+
+/// Prints the chain of references that makes the current system depend on the current version of the package
+/// named `name`, or every chain if `all_paths` is set.
+fn explain_package(args: &CmdOptions, name: &str, all_paths: bool) -> Result<()> {
+    let system_db = open_database(args).context("failed to open nix database")?;
+    let paths = open::find_current(&system_db, name)?;
+
+    if paths.is_empty() {
+        return Err(anyhow!("no package named {} was found", name));
+    }
+
+    let system = profile::resolve_profile(profile::SYSTEM_PROFILE)
+        .context("failed to find the current system")?;
+
+    let root = explain::id_of(&system_db, &system)?.ok_or_else(|| {
+        anyhow!(
+            "{} is not a valid path in the nix database",
+            system.display()
+        )
+    })?;
+
+    let targets = paths
+        .iter()
+        .map(|path| path.store.id as i32)
+        .collect::<Vec<_>>();
+
+    let explanation = timed(args.verbose, "walking referrers", || {
+        Explanation::find(&system_db, root, &targets, all_paths, args.deps.max_nodes)
+    })
+    .with_context(|| anyhow!("failed to find what refers to {}", name))?;
+
+    if explanation.chains.is_empty() {
+        return Err(anyhow!(
+            "{} is not in the closure of the current system at {}",
+            name,
+            system.display()
+        ));
+    }
+
+    display::explanation(&explanation);
+    Ok(())
+}
+
+/// Prints the units described by `opts`, or installs them as the user's own units.
+///
+/// The units run the current executable with the same data directory that was passed to us.
+fn generate_unit(args: &CmdOptions, opts: &UnitOptions) -> Result<()> {
+    let exe = env::current_exe().context("failed to get the path of the current executable")?;
+    let units = unit::generate(&exe, args.data_dir.as_deref(), opts)?;
+
+    if !opts.install {
+        for (i, unit) in units.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+
+            println!("# {}", unit.name);
+            print!("{}", unit.contents);
+        }
+
+        return Ok(());
+    }
+
+    let dir = unit::user_unit_dir()?;
+
+    for path in unit::install(&dir, &units, opts.force)? {
+        println!("wrote {}", path.display());
+    }
+
+    println!(
+        "\nrun systemctl --user daemon-reload and then systemctl --user enable --now {} to start watching {}",
+        unit::trigger_name(opts.mode),
+        opts.profile.display()
+    );
+
+    Ok(())
+}
+
+/// The number of runs to show individually when showing the run log.
+const RECENT_RUNS: usize = 10;
+
+fn show_runs(data_dir: &Path) -> Result<()> {
+    let runs = RunLog::new(data_dir)
+        .read()
+        .context("failed to read run log")?;
+
+    if runs.is_empty() {
+        println!("no runs have been recorded yet");
+        return Ok(());
+    }
+
+    for run in &runs[runs.len().saturating_sub(RECENT_RUNS)..] {
+        display::run(run);
+    }
+
+    println!();
+    display::run_summary(&runs);
+
+    Ok(())
+}
+
+fn motd_line(args: &CmdOptions) -> Result<String> {
+    let data_dir = get_data_dir(args.data_dir.as_deref())?;
+
+    // A broken config shouldn't hide the summary, so the built-in settings are used in its place
+    let config = Config::load(&data_dir).unwrap_or_default();
+    display::format::init(config.locale(args.iso_dates));
+    ecosystem::init(config.ecosystem_rules().unwrap_or_default());
+
+    let old_state = PackageState::load(&data_dir)?;
+
+    let (system_db, _) = SystemDatabase::open()?;
+    let stores =
+        Store::all_from_system(&system_db, &Budget::unlimited(), args.dedup_policy(&config))?;
+
+    let summary = motd::MotdSummary::new(
+        &stores,
+        &old_state.packages,
+        Some(old_state.meta.saved_at),
+        &config.critical_list(),
+    );
+    Ok(motd::compose(&summary, args.width))
+}
+
+fn print_closure_stats(stats: ClosureStats) {
+    let locale = display::format::locale();
+
+    eprintln!(
+        "walked {}, with {} and {}",
+        locale.plural(stats.walked, "dependency path", "dependency paths"),
+        locale.plural(stats.self_refs, "self-reference", "self-references"),
+        locale.plural(stats.cycles, "cycle", "cycles")
+    );
+}
+
+/// Opens the Nix database, and reports how it was opened when `verbose` is set.
+///
+/// A mutable connection can read the database while Nix is writing to it, so a warning is printed if
+/// one was needed while the store is in use.
+fn open_database(args: &CmdOptions) -> Result<SystemDatabase> {
+    let (mut db, mode) = SystemDatabase::open()?;
+
+    if let Some(rows) = args.batch_size {
+        db.set_batch_size(rows);
+    }
+
+    if args.verbose {
+        match mode {
+            OpenMode::Immutable => eprintln!("opened DB read-only immutable"),
+            OpenMode::Mutable => eprintln!("opened DB read-write as root"),
+        }
+    }
+
+    if mode == OpenMode::Mutable && SystemDatabase::in_use() {
+        display::inconsistent_database();
+    }
+
+    Ok(db)
+}
+
+/// Warns about stores registered in the future or before Nix existed, which usually means the system
+/// clock was wrong at some point. This is only done once per run.
+fn warn_clock_skew(stores: &HashSet<Store>) {
+    let anomalies = clock::Anomalies::in_stores(stores, state::now());
+    clock::warn_once(anomalies, display::clock_skew);
+}
+
+/// Warns when the baseline was saved with a parser that splits the built-in corpus of store names differently
+/// than the current one, since the diff would then show packages as changed that weren't.
+fn warn_parser_changed(meta: &StateMeta) {
+    let saved = match &meta.parser {
+        Some(saved) => saved,
+        None => return,
+    };
+
+    let changes = ParserFingerprint::current().changes_since(saved);
+
+    if !changes.is_empty() {
+        display::parser_changed(meta.nixup_version.as_deref(), &changes);
+    }
+}
+
+/// Runs `func` and prints how long it took to stderr when `verbose` is set.
+fn timed<F, T>(verbose: bool, desc: &str, func: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let start = Instant::now();
+    let result = func();
+
+    if verbose {
+        eprintln!("{} took {:.2?}", desc, start.elapsed());
+    }
+
+    result
+}
+
+/// Returns the directory all program state is stored in, creating it if it doesn't exist.
+///
+/// The `custom` path takes priority, followed by the `NIXUP_DATA_DIR` environment variable.
+/// The default location is only used (and created) when neither of these are set.
+fn get_data_dir(custom: Option<&Path>) -> Result<PathBuf> {
+    let custom = custom
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("NIXUP_DATA_DIR").map(PathBuf::from));
+
+    let dir = match custom {
+        Some(dir) => dir,
+        None => dirs_next::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("~/.local/share/"))
+            .join(env!("CARGO_PKG_NAME")),
+    };
 
     if !dir.exists() {
-        fs::create_dir_all(&dir).context("failed to create directory")?;
+        fs::create_dir_all(&dir)
+            .with_context(|| anyhow!("failed to create directory at {}", dir.display()))?;
     }
 
     Ok(dir)
@@ -0,0 +1,295 @@
+use crate::critical::CriticalList;
+use crate::display::format;
+use crate::profile;
+use crate::store::diff::{DiffCounts, Outcome};
+use crate::store::version;
+use crate::store::{Derivation, Store};
+use std::collections::HashSet;
+
+/// The name of the store that contains the kernel.
+const KERNEL_NAME: &str = "linux";
+
+/// The message to show when a summary could not be created for any reason.
+pub const UNAVAILABLE: &str = "nixup: status unavailable";
+
+/// The facts shown in a MOTD line.
+#[derive(Debug, Default)]
+pub struct MotdSummary {
+    /// The number of packages whose version changed.
+    pub updated: usize,
+    /// How many of the updated packages are on the critical list.
+    pub critical: usize,
+    /// The new kernel version, if it changed.
+    pub kernel: Option<String>,
+    /// Whether the running kernel differs from the one in the current system.
+    pub reboot_pending: bool,
+    /// The epoch time the baseline was saved at.
+    pub baseline_time: Option<u64>,
+    pub outcome: Outcome,
+}
+
+impl MotdSummary {
+    /// Creates a summary from the top-level stores only, so dependencies never need to be resolved.
+    ///
+    /// Since dependencies aren't resolved, only packages that are themselves on the `critical` list are counted as critical.
+    pub fn new(
+        stores: &HashSet<Store>,
+        old: &HashSet<Derivation>,
+        baseline_time: Option<u64>,
+        critical: &CriticalList,
+    ) -> Self {
+        let mut summary = Self {
+            baseline_time,
+            reboot_pending: is_reboot_pending(),
+            ..Self::default()
+        };
+
+        let mut added = 0;
+
+        for store in stores {
+            let old = match old.get(store.name.as_str()) {
+                Some(old) => old,
+                None => {
+                    added += 1;
+                    continue;
+                }
+            };
+
+            if version::strip_build_metadata(&old.store.version)
+                == version::strip_build_metadata(&store.version)
+            {
+                continue;
+            }
+
+            summary.updated += 1;
+
+            if critical.matches(&store.name) {
+                summary.critical += 1;
+            }
+
+            if store.name == KERNEL_NAME {
+                summary.kernel = Some(store.version.clone());
+            }
+        }
+
+        // Nothing is filtered, so every update is reported
+        let counts = DiffCounts {
+            baseline: old.len(),
+            added,
+            removed: old
+                .iter()
+                .filter(|pkg| !stores.contains(pkg.store.name.as_str()))
+                .count(),
+            unfiltered: summary.updated,
+            reported: summary.updated,
+        };
+
+        summary.outcome = counts.outcome();
+        summary
+    }
+}
+
+/// Composes a single uncolored line describing `summary` that is no longer than `width` characters.
+///
+/// Less important facts are dropped first to fit within `width`, in the following order:
+/// the baseline date, followed by the number of updated packages.
+/// The line is truncated if even the kernel information doesn't fit.
+pub fn compose(summary: &MotdSummary, width: usize) -> String {
+    let kernel = {
+        let mut parts = Vec::with_capacity(2);
+
+        if let Some(kernel) = &summary.kernel {
+            parts.push(format!("kernel {}", kernel));
+        }
+
+        if summary.reboot_pending {
+            parts.push("reboot pending".into());
+        }
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(", "))
+        }
+    };
+
+    let locale = format::locale();
+
+    let count = match (summary.outcome, summary.updated) {
+        (Outcome::EmptyBaseline, _) => "saved state is empty".into(),
+        (Outcome::Identical, _) => "no changes".into(),
+        (_, 0) => "no package updates".into(),
+        (_, num) if summary.critical > 0 => format!(
+            "{}, {} critical",
+            locale.plural(num, "package updated", "packages updated"),
+            locale.count(summary.critical)
+        ),
+        (_, num) => locale.plural(num, "package updated", "packages updated"),
+    };
+
+    // The date of an empty baseline isn't worth keeping around
+    let since = summary
+        .baseline_time
+        .filter(|_| summary.outcome != Outcome::EmptyBaseline)
+        .map(|time| format!(" since baseline {}", locale.date(time)));
+
+    let mut candidates = Vec::with_capacity(3);
+
+    match &kernel {
+        Some(kernel) => {
+            if let Some(since) = &since {
+                candidates.push(format!("nixup: {}{} ({})", count, since, kernel));
+            }
+
+            candidates.push(format!("nixup: {} ({})", count, kernel));
+            candidates.push(format!("nixup: {}", kernel));
+        }
+        None => {
+            if let Some(since) = &since {
+                candidates.push(format!("nixup: {}{}", count, since));
+            }
+
+            candidates.push(format!("nixup: {}", count));
+        }
+    }
+
+    let fallback = candidates
+        .last()
+        .map(|last| truncate(last, width))
+        .unwrap_or_default();
+
+    candidates
+        .into_iter()
+        .find(|candidate| candidate.chars().count() <= width)
+        .unwrap_or(fallback)
+}
+
+fn truncate(line: &str, width: usize) -> String {
+    match width {
+        0 => String::new(),
+        1..=3 => line.chars().take(width).collect(),
+        _ => {
+            let mut result = line.chars().take(width - 3).collect::<String>();
+            result.push_str("...");
+            result
+        }
+    }
+}
+
+/// Returns true if the kernel of the booted system is different from the kernel of the current system.
+pub fn is_reboot_pending() -> bool {
+    let booted = profile::resolve_profile("/run/booted-system/kernel");
+    let current = profile::resolve_profile("/run/current-system/kernel");
+
+    match (booted, current) {
+        (Ok(booted), Ok(current)) => booted != current,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 2024-03-02
+    const BASELINE: u64 = 1_709_337_600;
+
+    fn summary(kernel: bool) -> MotdSummary {
+        MotdSummary {
+            updated: 14,
+            critical: 0,
+            kernel: if kernel { Some("6.6.13".into()) } else { None },
+            reboot_pending: kernel,
+            baseline_time: Some(BASELINE),
+            outcome: Outcome::Changed,
+        }
+    }
+
+    #[test]
+    fn compose_with_kernel() {
+        let summary = summary(true);
+
+        let widths = [
+            (
+                84,
+                "nixup: 14 packages updated since baseline 2024-03-02 (kernel 6.6.13, reboot pending)",
+            ),
+            (
+                83,
+                "nixup: 14 packages updated (kernel 6.6.13, reboot pending)",
+            ),
+            (
+                58,
+                "nixup: 14 packages updated (kernel 6.6.13, reboot pending)",
+            ),
+            (57, "nixup: kernel 6.6.13, reboot pending"),
+            (36, "nixup: kernel 6.6.13, reboot pending"),
+            (20, "nixup: kernel 6.6..."),
+            (3, "nix"),
+            (0, ""),
+        ];
+
+        for &(width, expected) in &widths {
+            assert_eq!(compose(&summary, width), expected, "width {}", width);
+        }
+    }
+
+    #[test]
+    fn compose_without_kernel() {
+        let summary = summary(false);
+
+        assert_eq!(
+            compose(&summary, 80),
+            "nixup: 14 packages updated since baseline 2024-03-02"
+        );
+        assert_eq!(compose(&summary, 30), "nixup: 14 packages updated");
+        assert_eq!(compose(&summary, 20), "nixup: 14 package...");
+
+        let empty = MotdSummary {
+            updated: 0,
+            baseline_time: None,
+            ..summary
+        };
+
+        assert_eq!(compose(&empty, 80), "nixup: no package updates");
+    }
+
+    #[test]
+    fn compose_without_changes() {
+        let identical = MotdSummary {
+            updated: 0,
+            outcome: Outcome::Identical,
+            ..summary(false)
+        };
+
+        assert_eq!(
+            compose(&identical, 80),
+            "nixup: no changes since baseline 2024-03-02"
+        );
+
+        let empty = MotdSummary {
+            updated: 0,
+            outcome: Outcome::EmptyBaseline,
+            ..summary(false)
+        };
+
+        assert_eq!(compose(&empty, 80), "nixup: saved state is empty");
+    }
+
+    #[test]
+    fn compose_with_critical() {
+        let summary = MotdSummary {
+            critical: 2,
+            ..summary(true)
+        };
+
+        assert_eq!(
+            compose(&summary, 100),
+            "nixup: 14 packages updated, 2 critical since baseline 2024-03-02 (kernel 6.6.13, reboot pending)"
+        );
+        assert_eq!(
+            compose(&summary, 80),
+            "nixup: 14 packages updated, 2 critical (kernel 6.6.13, reboot pending)"
+        );
+    }
+}
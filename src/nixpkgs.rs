@@ -0,0 +1,567 @@
+use anyhow::{anyhow, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// The interpreters whose packages are named after the interpreter and its version in the Nix store.
+const INTERPRETERS: [&str; 5] = ["python", "perl", "ruby", "lua", "php"];
+
+/// The version of the cached index's layout, which invalidates the cache whenever it changes.
+const CACHE_VERSION: u32 = 1;
+
+/// The files that map attributes to the files defining them through `callPackage`, along with the prefix of
+/// the attributes they define.
+const CALL_PACKAGE_FILES: [(&str, &str); 2] = [
+    ("pkgs/top-level/all-packages.nix", ""),
+    ("pkgs/top-level/python-packages.nix", "python3Packages."),
+];
+
+/// The index of the checkout given to --nixpkgs, set once it's known to be needed.
+static INDEX: OnceLock<Index> = OnceLock::new();
+
+/// Sets the index returned by `index`.
+///
+/// Only the first call has any effect.
+pub fn init(index: Index) {
+    let _ = INDEX.set(index);
+}
+
+/// Returns the index set with `init`, if any.
+pub fn index() -> Option<&'static Index> {
+    INDEX.get()
+}
+
+/// Where a package is defined in a nixpkgs checkout.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Definition {
+    /// The attribute path of the package, if it could be found without evaluating nixpkgs.
+    pub attr: Option<String>,
+    /// The file declaring the package's `pname`, relative to the root of the checkout.
+    pub file: PathBuf,
+    pub line: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cache {
+    version: u32,
+    rev: String,
+    definitions: HashMap<String, Definition>,
+}
+
+/// Where each package in a nixpkgs checkout is defined, found by scanning for `pname` declarations.
+///
+/// This is a purely textual scan rather than an evaluation, so packages whose `pname` isn't a plain string
+/// are left out, and attributes are only known for packages in `pkgs/by-name` or added with `callPackage`.
+pub struct Index {
+    root: PathBuf,
+    /// Every package by its `pname`.
+    definitions: HashMap<String, Definition>,
+    /// The `pname` of every package by its normalized name.
+    normalized: HashMap<String, String>,
+}
+
+impl Index {
+    /// Loads the index of the checkout at `root`, scanning it only if the index cached in `data_dir` was built
+    /// from a different commit.
+    ///
+    /// The index is never cached when the commit of the checkout can't be found.
+    pub fn load(root: &Path, data_dir: &Path) -> Result<Self> {
+        if !root.join("pkgs").is_dir() {
+            return Err(anyhow!(
+                "{} is not a nixpkgs checkout, as it has no pkgs directory",
+                root.display()
+            ));
+        }
+
+        Self::load_at_rev(root, data_dir, git_rev(root).as_deref())
+    }
+
+    fn load_at_rev(root: &Path, data_dir: &Path, rev: Option<&str>) -> Result<Self> {
+        let cache_path = Self::cache_path(data_dir);
+
+        if let Some(definitions) = rev.and_then(|rev| read_cache(&cache_path, rev)) {
+            return Ok(Self::new(root, definitions));
+        }
+
+        let definitions = scan(root)
+            .with_context(|| anyhow!("failed to scan nixpkgs checkout at {}", root.display()))?;
+
+        if let Some(rev) = rev {
+            let cache = Cache {
+                version: CACHE_VERSION,
+                rev: rev.into(),
+                definitions,
+            };
+
+            let bytes = bincode::serialize(&cache)?;
+
+            fs::write(&cache_path, bytes).with_context(|| {
+                anyhow!("failed to write nixpkgs index to {}", cache_path.display())
+            })?;
+
+            return Ok(Self::new(root, cache.definitions));
+        }
+
+        Ok(Self::new(root, definitions))
+    }
+
+    fn new(root: &Path, definitions: HashMap<String, Definition>) -> Self {
+        let normalized = definitions
+            .keys()
+            .map(|pname| (normalize(pname), pname.clone()))
+            .collect();
+
+        Self {
+            root: root.into(),
+            definitions,
+            normalized,
+        }
+    }
+
+    pub fn cache_path(data_dir: &Path) -> PathBuf {
+        data_dir.join("nixpkgs-index.bin")
+    }
+
+    /// Returns where the package named `name` in the Nix store is defined.
+    ///
+    /// When no package has exactly that `pname`, names are compared regardless of case and separators, and
+    /// then without an interpreter prefix such as `python3.11-` or a wrapper suffix such as `-unwrapped`.
+    pub fn find(&self, name: &str) -> Option<&Definition> {
+        self.find_normalized(name).or_else(|| {
+            let stripped = strip_interpreter_prefix(name).unwrap_or(name);
+
+            let stripped = ["-unwrapped", "-wrapped"]
+                .iter()
+                .find_map(|suffix| stripped.strip_suffix(suffix))
+                .unwrap_or(stripped);
+
+            if stripped == name {
+                None
+            } else {
+                self.find_normalized(stripped)
+            }
+        })
+    }
+
+    fn find_normalized(&self, name: &str) -> Option<&Definition> {
+        self.definitions.get(name).or_else(|| {
+            self.normalized
+                .get(&normalize(name))
+                .and_then(|pname| self.definitions.get(pname))
+        })
+    }
+
+    /// Returns the full path to the file `definition` is in.
+    pub fn path_of(&self, definition: &Definition) -> PathBuf {
+        self.root.join(&definition.file)
+    }
+}
+
+/// Returns the commit the checkout at `root` is on, if it's a git repository.
+fn git_rev(root: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let rev = String::from_utf8(output.stdout).ok()?;
+    let rev = rev.trim();
+
+    if rev.is_empty() {
+        None
+    } else {
+        Some(rev.into())
+    }
+}
+
+/// Returns the definitions cached at `path` if they were built from `rev` with the current layout.
+fn read_cache(path: &Path, rev: &str) -> Option<HashMap<String, Definition>> {
+    let bytes = fs::read(path).ok()?;
+    let cache = bincode::deserialize::<Cache>(&bytes).ok()?;
+
+    if cache.version == CACHE_VERSION && cache.rev == rev {
+        Some(cache.definitions)
+    } else {
+        None
+    }
+}
+
+/// Finds the definition of every package in the checkout at `root`.
+///
+/// When several files declare the same `pname`, the first one with a known attribute wins, and otherwise the
+/// first one in path order.
+fn scan(root: &Path) -> Result<HashMap<String, Definition>> {
+    let mut attrs = HashMap::new();
+
+    for (file, prefix) in &CALL_PACKAGE_FILES {
+        let path = root.join(file);
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+
+        let dir = Path::new(file).parent().unwrap_or_else(|| Path::new(""));
+
+        for (attr, target) in parse_call_packages(&contents) {
+            let mut target = resolve(dir, target);
+
+            if root.join(&target).is_dir() {
+                target.push("default.nix");
+            }
+
+            attrs
+                .entry(target)
+                .or_insert_with(|| format!("{}{}", prefix, attr));
+        }
+    }
+
+    let mut files = Vec::new();
+    find_nix_files(root, Path::new("pkgs"), &mut files)?;
+
+    let mut definitions = HashMap::<String, Definition>::new();
+
+    for file in files {
+        let contents = match fs::read_to_string(root.join(&file)) {
+            Ok(contents) => contents,
+            // Files that aren't UTF-8 can't declare anything we can read
+            Err(_) => continue,
+        };
+
+        let attr = attrs.get(&file).cloned().or_else(|| by_name_attr(&file));
+
+        for (pname, line) in parse_pnames(&contents) {
+            let definition = Definition {
+                attr: attr.clone(),
+                file: file.clone(),
+                line,
+            };
+
+            match definitions.get(pname) {
+                Some(existing) if existing.attr.is_some() || attr.is_none() => (),
+                _ => {
+                    definitions.insert(pname.into(), definition);
+                }
+            }
+        }
+    }
+
+    Ok(definitions)
+}
+
+/// Pushes every `.nix` file under `dir` to `files` in path order, relative to `root`.
+fn find_nix_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = fs::read_dir(root.join(dir))
+        .with_context(|| anyhow!("failed to read directory {}", root.join(dir).display()))?;
+
+    let mut entries = entries.filter_map(|entry| entry.ok()).collect::<Vec<_>>();
+
+    entries.sort_unstable_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = dir.join(entry.file_name());
+
+        // Symlinks are skipped so a link back up the tree can't make the scan go on forever
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => find_nix_files(root, &path, files)?,
+            Ok(kind) if kind.is_file() && path.extension().is_some_and(|ext| ext == "nix") => {
+                files.push(path)
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns every `pname = "...";` declaration in `contents` with a plain string, along with its line number.
+fn parse_pnames(contents: &str) -> impl Iterator<Item = (&str, u32)> {
+    contents.lines().enumerate().filter_map(|(i, line)| {
+        let rest = line.trim_start().strip_prefix("pname")?;
+        let rest = rest.trim_start().strip_prefix('=')?;
+        let rest = rest.trim_start().strip_prefix('"')?;
+
+        let end = rest.find('"')?;
+        let (pname, rest) = rest.split_at(end);
+
+        if pname.is_empty() || pname.contains("${") || !rest[1..].trim_start().starts_with(';') {
+            return None;
+        }
+
+        Some((pname, i as u32 + 1))
+    })
+}
+
+/// Returns every `attr = callPackage <path> ...` line in `contents` as its attribute and relative path.
+fn parse_call_packages(contents: &str) -> impl Iterator<Item = (&str, &str)> {
+    contents.lines().filter_map(|line| {
+        let (attr, rest) = line.trim().split_once('=')?;
+        let attr = attr.trim();
+
+        let is_ident = !attr.is_empty()
+            && attr
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '_' | '-' | '\''));
+
+        if !is_ident {
+            return None;
+        }
+
+        let target = rest
+            .trim_start()
+            .strip_prefix("callPackage ")?
+            .split_whitespace()
+            .next()?;
+
+        if target.starts_with("./") || target.starts_with("../") {
+            Some((attr, target.trim_end_matches(';')))
+        } else {
+            None
+        }
+    })
+}
+
+/// Resolves `target` relative to `dir` without touching the filesystem.
+fn resolve(dir: &Path, target: &str) -> PathBuf {
+    let mut resolved = PathBuf::new();
+
+    for component in dir.join(target).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => (),
+            component => resolved.push(component),
+        }
+    }
+
+    resolved
+}
+
+/// Returns the attribute of a package defined in `pkgs/by-name`, which is always the name of its directory.
+fn by_name_attr(file: &Path) -> Option<String> {
+    let parts = file
+        .iter()
+        .map(|part| part.to_str())
+        .collect::<Option<Vec<_>>>()?;
+
+    match parts.as_slice() {
+        ["pkgs", "by-name", _, attr, "package.nix"] => Some((*attr).into()),
+        _ => None,
+    }
+}
+
+/// Lowercases `name` and treats `_` and `.` the same as `-`, which nixpkgs and store paths don't always agree on.
+pub(crate) fn normalize(name: &str) -> String {
+    name.chars()
+        .map(|ch| match ch {
+            '_' | '.' => '-',
+            ch => ch.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// Strips the name and version of the interpreter a package was built for, such as the `python3.11-` in
+/// `python3.11-requests` or the `perl5.38.2-` in `perl5.38.2-JSON`.
+pub(crate) fn strip_interpreter_prefix(name: &str) -> Option<&str> {
+    let (prefix, rest) = name.split_once('-')?;
+    let version_start = prefix.find(|ch: char| ch.is_ascii_digit())?;
+    let (interpreter, version) = prefix.split_at(version_start);
+
+    let is_prefix = INTERPRETERS.contains(&interpreter)
+        && version.chars().all(|ch| ch.is_ascii_digit() || ch == '.');
+
+    if is_prefix && !rest.is_empty() {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Writes a miniature nixpkgs checkout to `root`.
+    fn fake_nixpkgs(root: &Path) {
+        let files = [
+            (
+                "pkgs/top-level/all-packages.nix",
+                "{ lib, callPackage }:\n\n{\n  firefox-unwrapped = callPackage ../applications/networking/browsers/firefox { };\n  openssl_3 = callPackage ../development/libraries/openssl/3.nix { };\n  inherit (callPackage ../misc { }) misc;\n}\n",
+            ),
+            (
+                "pkgs/top-level/python-packages.nix",
+                "{\n  requests = callPackage ../development/python-modules/requests { };\n}\n",
+            ),
+            (
+                "pkgs/applications/networking/browsers/firefox/default.nix",
+                "{ stdenv }:\n\nstdenv.mkDerivation {\n  pname = \"firefox\";\n  version = \"121.0\";\n}\n",
+            ),
+            (
+                "pkgs/development/libraries/openssl/3.nix",
+                "{\n  pname=\"openssl\";\n}\n",
+            ),
+            (
+                "pkgs/development/libraries/openssl/1.1.nix",
+                "{\n  pname = \"openssl\";\n}\n",
+            ),
+            (
+                "pkgs/development/python-modules/requests/default.nix",
+                "buildPythonPackage rec {\n  pname = \"requests\";\n  version = \"2.31.0\";\n}\n",
+            ),
+            (
+                "pkgs/by-name/he/hello/package.nix",
+                "{\n  pname = \"hello\";\n}\n",
+            ),
+            (
+                "pkgs/by-name/gt/gtk_3/package.nix",
+                "{\n  pname = \"gtk+3\";\n}\n",
+            ),
+            (
+                "pkgs/tools/misc/interpolated.nix",
+                "{\n  pname = \"${name}-tools\";\n  pname = \"unterminated;\n}\n",
+            ),
+            ("pkgs/tools/misc/README.md", "pname = \"not-nix\";\n"),
+        ];
+
+        for (file, contents) in &files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    fn definition(attr: Option<&str>, file: &str, line: u32) -> Definition {
+        Definition {
+            attr: attr.map(Into::into),
+            file: file.into(),
+            line,
+        }
+    }
+
+    #[test]
+    fn scan_fake_nixpkgs() {
+        let root = tempfile::tempdir().unwrap();
+        fake_nixpkgs(root.path());
+
+        let definitions = scan(root.path()).unwrap();
+
+        assert_eq!(
+            definitions.get("firefox"),
+            Some(&definition(
+                Some("firefox-unwrapped"),
+                "pkgs/applications/networking/browsers/firefox/default.nix",
+                4
+            ))
+        );
+
+        // The file with a known attribute wins over the one that comes first
+        assert_eq!(
+            definitions.get("openssl"),
+            Some(&definition(
+                Some("openssl_3"),
+                "pkgs/development/libraries/openssl/3.nix",
+                2
+            ))
+        );
+
+        assert_eq!(
+            definitions.get("requests").unwrap().attr.as_deref(),
+            Some("python3Packages.requests")
+        );
+        assert_eq!(
+            definitions.get("hello"),
+            Some(&definition(
+                Some("hello"),
+                "pkgs/by-name/he/hello/package.nix",
+                2
+            ))
+        );
+
+        let mut pnames = definitions.keys().map(String::as_str).collect::<Vec<_>>();
+        pnames.sort_unstable();
+        assert_eq!(pnames, ["firefox", "gtk+3", "hello", "openssl", "requests"]);
+    }
+
+    #[test]
+    fn find_fuzzy_matches() {
+        let root = tempfile::tempdir().unwrap();
+        fake_nixpkgs(root.path());
+
+        let index = Index::new(root.path(), scan(root.path()).unwrap());
+        let attr = |name| index.find(name).and_then(|def| def.attr.as_deref());
+
+        assert_eq!(attr("firefox"), Some("firefox-unwrapped"));
+        assert_eq!(attr("firefox-unwrapped"), Some("firefox-unwrapped"));
+        assert_eq!(
+            attr("python3.11-requests"),
+            Some("python3Packages.requests")
+        );
+        assert_eq!(attr("Hello"), Some("hello"));
+        assert_eq!(attr("gtk+3"), Some("gtk_3"));
+        assert_eq!(attr("python3.11-missing"), None);
+        assert_eq!(attr("missing"), None);
+
+        assert_eq!(
+            index.path_of(index.find("hello").unwrap()),
+            root.path().join("pkgs/by-name/he/hello/package.nix")
+        );
+    }
+
+    #[test]
+    fn invalidate_cache_by_rev() {
+        let root = tempfile::tempdir().unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        fake_nixpkgs(root.path());
+
+        let index = Index::load_at_rev(root.path(), data_dir.path(), Some("abc")).unwrap();
+        assert!(index.find("hello").is_some());
+        assert!(Index::cache_path(data_dir.path()).exists());
+
+        let world = root.path().join("pkgs/by-name/wo/world");
+        fs::create_dir_all(&world).unwrap();
+        fs::write(world.join("package.nix"), "{\n  pname = \"world\";\n}\n").unwrap();
+
+        // The same commit uses the cache, even though the checkout changed
+        let cached = Index::load_at_rev(root.path(), data_dir.path(), Some("abc")).unwrap();
+        assert!(cached.find("world").is_none());
+
+        let rescanned = Index::load_at_rev(root.path(), data_dir.path(), Some("def")).unwrap();
+        assert!(rescanned.find("world").is_some());
+
+        // Without a commit, the checkout is always scanned and the cache is left alone
+        fs::write(Index::cache_path(data_dir.path()), b"corrupt").unwrap();
+        let uncached = Index::load_at_rev(root.path(), data_dir.path(), None).unwrap();
+        assert!(uncached.find("world").is_some());
+        assert_eq!(
+            fs::read(Index::cache_path(data_dir.path())).unwrap(),
+            b"corrupt"
+        );
+
+        // A corrupt cache is rebuilt
+        let rebuilt = Index::load_at_rev(root.path(), data_dir.path(), Some("def")).unwrap();
+        assert!(rebuilt.find("world").is_some());
+    }
+
+    #[test]
+    fn strip_interpreter_prefixes() {
+        assert_eq!(
+            strip_interpreter_prefix("python3.11-requests"),
+            Some("requests")
+        );
+        assert_eq!(strip_interpreter_prefix("perl5.38.2-JSON"), Some("JSON"));
+        assert_eq!(strip_interpreter_prefix("firefox-unwrapped"), None);
+        assert_eq!(strip_interpreter_prefix("libp11-kit"), None);
+        assert_eq!(strip_interpreter_prefix("ruby-3.3"), None);
+        assert_eq!(strip_interpreter_prefix("python3-"), None);
+    }
+}
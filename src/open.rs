@@ -0,0 +1,425 @@
+use crate::store::database::SystemDatabase;
+use crate::store::Store;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::{BufRead, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+/// The directory every store path is directly inside of.
+pub const STORE_DIR: &str = "/nix/store";
+
+#[derive(Debug)]
+pub struct OpenOptions {
+    /// The name of the package to open, as it appears in diffs.
+    pub name: String,
+    /// The output to open when the package has several, where `out` is the one without a suffix.
+    pub output: Option<String>,
+    /// Only print the path, and never prompt for an output.
+    pub print_only: bool,
+}
+
+/// A store path along with the store parsed from it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StorePath {
+    pub store: Store,
+    pub path: PathBuf,
+}
+
+impl StorePath {
+    /// Returns the name of the output of the path, where the output without a suffix is `out`.
+    pub fn output(&self) -> &str {
+        self.store.suffix.as_deref().unwrap_or("out")
+    }
+}
+
+/// Returns the newest path of every output of the current version of the package named `name`.
+///
+/// The current version is the version of the most recently registered path with that name. The paths
+/// are sorted by their output.
+pub fn find_current(db: &SystemDatabase, name: &str) -> Result<Vec<StorePath>> {
+    use crate::store::database::schema::ValidPaths::dsl::*;
+    use diesel::prelude::*;
+
+    let rows = ValidPaths
+        .filter(path.like(like_pattern(name)).escape('\\'))
+        .select((id, path, registrationTime))
+        .order((registrationTime.desc(), id.desc()))
+        .get_results::<(i32, String, i32)>(db.conn())
+        .with_context(|| anyhow!("failed to look up paths of {}", name))?;
+
+    // LIKE also matches longer names that start with the same text, as well as names in a different case
+    let mut paths = rows
+        .into_iter()
+        .filter_map(|(store_id, store_path, reg)| {
+            let store = Store::parse(store_id as u32, reg as u32, &store_path)?;
+
+            if store.name == name {
+                Some(StorePath {
+                    store,
+                    path: store_path.into(),
+                })
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let version = match paths.first() {
+        Some(newest) => newest.store.version.clone(),
+        None => return Ok(Vec::new()),
+    };
+
+    // The rows are sorted from newest to oldest, so only the first path of each output is kept
+    let mut outputs = HashSet::new();
+    paths.retain(|candidate| {
+        candidate.store.version == version && outputs.insert(candidate.output().to_string())
+    });
+
+    paths.sort_unstable_by(|x, y| x.output().cmp(y.output()));
+    Ok(paths)
+}
+
+/// Returns a LIKE pattern matching every store path of a package named `name`, with `\` as the escape character.
+fn like_pattern(name: &str) -> String {
+    let mut pattern = format!("{}/%-", STORE_DIR);
+
+    for ch in name.chars() {
+        if matches!(ch, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+
+        pattern.push(ch);
+    }
+
+    pattern.push_str("-%");
+    pattern
+}
+
+/// Picks the path to open out of `paths`.
+///
+/// `output` selects a path by its output. Otherwise, a single path is picked as is, while several are either
+/// chosen between through `prompt` or result in an error listing the outputs when there's no prompt.
+pub fn choose<P>(
+    mut paths: Vec<StorePath>,
+    output: Option<&str>,
+    prompt: Option<P>,
+) -> Result<StorePath>
+where
+    P: FnOnce(&[StorePath]) -> Result<usize>,
+{
+    let outputs = || {
+        paths
+            .iter()
+            .map(StorePath::output)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    if let Some(output) = output {
+        return match paths.iter().position(|path| path.output() == output) {
+            Some(index) => Ok(paths.swap_remove(index)),
+            None => Err(anyhow!(
+                "output {} not found, available outputs are {}",
+                output,
+                outputs()
+            )),
+        };
+    }
+
+    match (paths.len(), prompt) {
+        (0, _) => Err(anyhow!("no paths to choose from")),
+        (1, _) => Ok(paths.swap_remove(0)),
+        (_, Some(prompt)) => {
+            let index = prompt(&paths)?;
+            Ok(paths.swap_remove(index))
+        }
+        (_, None) => Err(anyhow!(
+            "package has multiple outputs: {}\nuse --output to pick one",
+            outputs()
+        )),
+    }
+}
+
+/// Asks which of `paths` to open by writing a numbered list to `output` and reading a number from `input`.
+///
+/// An output name is also accepted in place of its number.
+pub fn prompt<R, W>(paths: &[StorePath], mut input: R, mut output: W) -> Result<usize>
+where
+    R: BufRead,
+    W: Write,
+{
+    for (i, path) in paths.iter().enumerate() {
+        writeln!(
+            output,
+            "{}) {}  {}",
+            i + 1,
+            path.output(),
+            path.path.display()
+        )?;
+    }
+
+    write!(output, "output to open: ")?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    let answer = answer.trim();
+
+    let index = match answer.parse::<usize>() {
+        Ok(num) if num >= 1 && num <= paths.len() => Some(num - 1),
+        Ok(_) => None,
+        Err(_) => paths.iter().position(|path| path.output() == answer),
+    };
+
+    index.ok_or_else(|| anyhow!("invalid choice \"{}\"", answer))
+}
+
+/// A command that opens a store path, described so it can be checked without running it.
+#[derive(Debug, PartialEq)]
+pub struct Launch {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    /// The working directory of the command, which is set on the child rather than changing our own.
+    pub dir: PathBuf,
+}
+
+impl Launch {
+    /// Returns the command that opens `path`.
+    ///
+    /// Directories are opened with `shell` started inside of them, falling back to `/bin/sh`. Anything else is
+    /// opened with `xdg-open` from the store directory. `path` must be directly inside of the store directory,
+    /// so that a corrupt or malicious database can't point anywhere else.
+    pub fn for_path(path: &Path, is_dir: bool, shell: Option<OsString>) -> Result<Self> {
+        check_store_path(path)?;
+
+        if is_dir {
+            Ok(Self {
+                program: shell.unwrap_or_else(|| "/bin/sh".into()),
+                args: Vec::new(),
+                dir: path.to_path_buf(),
+            })
+        } else {
+            Ok(Self {
+                program: "xdg-open".into(),
+                args: vec![path.into()],
+                dir: STORE_DIR.into(),
+            })
+        }
+    }
+}
+
+fn check_store_path(path: &Path) -> Result<()> {
+    let inside_store = path.parent() == Some(Path::new(STORE_DIR))
+        && path
+            .components()
+            .all(|component| matches!(component, Component::RootDir | Component::Normal(_)));
+
+    if inside_store {
+        Ok(())
+    } else {
+        Err(anyhow!("{} is not a store path", path.display()))
+    }
+}
+
+/// Runs a `Launch`, which lets tests check the command that would run without running it.
+pub trait Spawner {
+    fn spawn(&mut self, launch: &Launch) -> Result<()>;
+}
+
+/// Runs commands as child processes and waits for them to exit.
+pub struct ProcessSpawner;
+
+impl Spawner for ProcessSpawner {
+    fn spawn(&mut self, launch: &Launch) -> Result<()> {
+        let program = launch.program.to_string_lossy();
+
+        let status = Command::new(&launch.program)
+            .args(&launch.args)
+            .current_dir(&launch.dir)
+            .status()
+            .with_context(|| anyhow!("failed to run {}", program))?;
+
+        // A shell exits with the status of the last command run in it, so only xdg-open failing is an error
+        if status.success() || launch.args.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("{} failed with {}", program, status))
+        }
+    }
+}
+
+/// Opens `path` with `spawner`, using the shell in `$SHELL` for directories.
+pub fn open_path<S: Spawner>(path: &Path, spawner: &mut S) -> Result<()> {
+    let launch = Launch::for_path(path, path.is_dir(), std::env::var_os("SHELL"))?;
+    spawner.spawn(&launch)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::database::fixture;
+
+    fn outputs(paths: &[StorePath]) -> Vec<(&str, &str, u32)> {
+        paths
+            .iter()
+            .map(|path| (path.output(), path.store.version.as_str(), path.store.id))
+            .collect()
+    }
+
+    #[test]
+    fn find_current_outputs() {
+        let db = fixture::empty();
+        fixture::add_path(&db, 1, "firefox-122.0", 100);
+        fixture::add_path(&db, 2, "firefox-123.0", 200);
+        fixture::add_path(&db, 3, "firefox-123.0-bin", 200);
+        fixture::add_path(&db, 4, "firefox-unwrapped-123.0", 300);
+        fixture::add_path(&db, 5, "Firefox-124.0", 300);
+        fixture::add_path(&db, 6, "firefox-122.0-man", 100);
+
+        // Outputs of older versions are left out, even if the current version doesn't have them
+        assert_eq!(
+            outputs(&find_current(&db, "firefox").unwrap()),
+            [("bin", "123.0", 3), ("out", "123.0", 2)]
+        );
+
+        assert!(find_current(&db, "chromium").unwrap().is_empty());
+
+        // Wildcards in the name shouldn't match anything else
+        fixture::add_path(&db, 7, "gtk_3-3.24", 100);
+        fixture::add_path(&db, 8, "gtkx3-3.24", 100);
+        assert_eq!(
+            outputs(&find_current(&db, "gtk_3").unwrap()),
+            [("out", "3.24", 7)]
+        );
+    }
+
+    #[test]
+    fn escape_like_patterns() {
+        assert_eq!(like_pattern("firefox"), "/nix/store/%-firefox-%");
+        assert_eq!(like_pattern("a_b%c\\d"), "/nix/store/%-a\\_b\\%c\\\\d-%");
+    }
+
+    fn store_path(suffix: Option<&str>) -> StorePath {
+        let name = match suffix {
+            Some(suffix) => format!("firefox-123.0-{}", suffix),
+            None => "firefox-123.0".into(),
+        };
+
+        let path = format!("{}/zx6vs1b6xf07cprslk9is1fhwih21ix5-{}", STORE_DIR, name);
+
+        StorePath {
+            store: Store::parse(0, 0, &path).unwrap(),
+            path: path.into(),
+        }
+    }
+
+    type Prompt = fn(&[StorePath]) -> Result<usize>;
+
+    #[test]
+    fn choose_output() {
+        let paths = || vec![store_path(Some("bin")), store_path(None)];
+
+        let chosen = choose::<Prompt>(paths(), Some("out"), None).unwrap();
+        assert_eq!(chosen.output(), "out");
+
+        let err = choose::<Prompt>(paths(), Some("dev"), None).unwrap_err();
+        assert!(err.to_string().contains("bin, out"), "{}", err);
+
+        let err = choose::<Prompt>(paths(), None, None).unwrap_err();
+        assert!(err.to_string().contains("--output"), "{}", err);
+
+        let chosen = choose(paths(), None, Some(|_: &[StorePath]| Ok(0))).unwrap();
+        assert_eq!(chosen.output(), "bin");
+
+        // A single output never needs a prompt
+        let chosen = choose(
+            vec![store_path(None)],
+            None,
+            Some(|_: &[StorePath]| -> Result<usize> { panic!("prompted") }),
+        )
+        .unwrap();
+        assert_eq!(chosen.output(), "out");
+    }
+
+    #[test]
+    fn prompt_for_output() {
+        let paths = [store_path(Some("bin")), store_path(None)];
+
+        let mut written = Vec::new();
+        assert_eq!(prompt(&paths, &b"2\n"[..], &mut written).unwrap(), 1);
+
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.starts_with("1) bin  /nix/store/"), "{}", written);
+
+        assert_eq!(prompt(&paths, &b"bin\n"[..], Vec::new()).unwrap(), 0);
+
+        for invalid in &[&b"0\n"[..], b"3\n", b"dev\n", b""] {
+            assert!(prompt(&paths, *invalid, Vec::new()).is_err());
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingSpawner(Vec<Launch>);
+
+    impl Spawner for RecordingSpawner {
+        fn spawn(&mut self, launch: &Launch) -> Result<()> {
+            self.0.push(Launch {
+                program: launch.program.clone(),
+                args: launch.args.clone(),
+                dir: launch.dir.clone(),
+            });
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn launch_store_paths() {
+        let dir = Path::new("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-firefox-123.0");
+
+        assert_eq!(
+            Launch::for_path(dir, true, Some("/bin/zsh".into())).unwrap(),
+            Launch {
+                program: "/bin/zsh".into(),
+                args: Vec::new(),
+                dir: dir.into(),
+            }
+        );
+
+        assert_eq!(
+            Launch::for_path(dir, true, None).unwrap().program,
+            "/bin/sh"
+        );
+
+        let file = Path::new("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-notes.txt");
+        assert_eq!(
+            Launch::for_path(file, false, None).unwrap(),
+            Launch {
+                program: "xdg-open".into(),
+                args: vec![file.into()],
+                dir: STORE_DIR.into(),
+            }
+        );
+
+        for outside in &[
+            "/tmp/firefox",
+            "/nix/store",
+            "/nix/store/../../etc",
+            "/nix/store/abc-firefox/bin",
+            "nix/store/abc-firefox",
+        ] {
+            assert!(
+                Launch::for_path(Path::new(outside), true, None).is_err(),
+                "{}",
+                outside
+            );
+        }
+
+        // Nothing is run for paths outside of the store
+        let mut spawner = RecordingSpawner::default();
+        assert!(open_path(Path::new("/etc"), &mut spawner).is_err());
+        assert!(spawner.0.is_empty());
+    }
+}
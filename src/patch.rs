@@ -0,0 +1,420 @@
+use crate::store::{Derivation, Store};
+use anyhow::{anyhow, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The bytes every patch file starts with.
+const MAGIC: &[u8; 8] = b"NIXUPPT\0";
+
+/// The current version of the patch file format.
+const VERSION: u32 = 2;
+
+/// The changes needed to turn one set of packages into another.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Patch {
+    /// The checksum of the packages the patch can be applied to.
+    pub base_checksum: u64,
+    /// The checksum of the packages after the patch has been applied.
+    pub result_checksum: u64,
+    pub removed: Vec<String>,
+    pub added: Vec<Derivation>,
+    pub changed: Vec<PackagePatch>,
+}
+
+/// The changes to a single package that exists in both sets.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PackagePatch {
+    pub name: String,
+    /// The package's new store, if anything about it changed.
+    pub store: Option<Store>,
+    pub removed_deps: Vec<String>,
+    /// Dependencies that were either added, or replace an existing dependency with the same name.
+    pub replaced_deps: Vec<Store>,
+}
+
+impl Patch {
+    /// Creates a patch that turns `old` into `new`.
+    pub fn new(old: &HashSet<Derivation>, new: &HashSet<Derivation>) -> Self {
+        let mut removed = old
+            .iter()
+            .filter(|pkg| !new.contains(pkg.store.name.as_str()))
+            .map(|pkg| pkg.store.name.clone())
+            .collect::<Vec<_>>();
+
+        let mut added = new
+            .iter()
+            .filter(|pkg| !old.contains(pkg.store.name.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut changed = new
+            .iter()
+            .filter_map(|new_pkg| {
+                let old_pkg = old.get(new_pkg.store.name.as_str())?;
+                PackagePatch::new(old_pkg, new_pkg)
+            })
+            .collect::<Vec<_>>();
+
+        removed.sort_unstable();
+        added.sort_unstable_by(|x, y| x.store.name.cmp(&y.store.name));
+        changed.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+
+        Self {
+            base_checksum: checksum(old),
+            result_checksum: checksum(new),
+            removed,
+            added,
+            changed,
+        }
+    }
+
+    /// Applies the patch to `packages`, returning the resulting packages.
+    ///
+    /// An error is returned if `packages` isn't the set the patch was created from,
+    /// or if the result isn't the set the patch was created for.
+    pub fn apply(&self, mut packages: HashSet<Derivation>) -> Result<HashSet<Derivation>> {
+        if checksum(&packages) != self.base_checksum {
+            return Err(anyhow!(
+                "packages do not match the state the patch was created from"
+            ));
+        }
+
+        for name in &self.removed {
+            if !packages.remove(name.as_str()) {
+                return Err(anyhow!("removed package {} does not exist", name));
+            }
+        }
+
+        for pkg in &self.added {
+            if !packages.insert(pkg.clone()) {
+                return Err(anyhow!("added package {} already exists", pkg.store.name));
+            }
+        }
+
+        for change in &self.changed {
+            let mut pkg = packages
+                .take(change.name.as_str())
+                .ok_or_else(|| anyhow!("changed package {} does not exist", change.name))?;
+
+            change.apply_to(&mut pkg)?;
+            packages.insert(pkg);
+        }
+
+        if checksum(&packages) != self.result_checksum {
+            return Err(anyhow!(
+                "patched packages do not match the state the patch was created for"
+            ));
+        }
+
+        Ok(packages)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| anyhow!("failed to create patch file at {}", path.display()))?;
+
+        let mut file = BufWriter::new(file);
+
+        file.write_all(MAGIC)
+            .and_then(|_| file.write_all(&VERSION.to_le_bytes()))
+            .with_context(|| anyhow!("failed to write patch to {}", path.display()))?;
+
+        bincode::serialize_into(&mut file, self)
+            .with_context(|| anyhow!("failed to encode patch to {}", path.display()))?;
+
+        file.flush()
+            .with_context(|| anyhow!("failed to write patch to {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path)
+            .with_context(|| anyhow!("failed to read patch file at {}", path.display()))?;
+
+        let header_len = MAGIC.len() + 4;
+
+        if bytes.len() < header_len || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(anyhow!("{} is not a patch file", path.display()));
+        }
+
+        let mut version = [0; 4];
+        version.copy_from_slice(&bytes[MAGIC.len()..header_len]);
+        let version = u32::from_le_bytes(version);
+
+        if version != VERSION {
+            return Err(anyhow!(
+                "patch at {} was created with unsupported version {}",
+                path.display(),
+                version
+            ));
+        }
+
+        bincode::deserialize(&bytes[header_len..])
+            .with_context(|| anyhow!("failed to decode patch from {}", path.display()))
+    }
+}
+
+impl PackagePatch {
+    fn new(old: &Derivation, new: &Derivation) -> Option<Self> {
+        let store = if stores_match(&old.store, &new.store) {
+            None
+        } else {
+            Some(new.store.clone())
+        };
+
+        let mut removed_deps = old
+            .deps
+            .iter()
+            .filter(|dep| !new.deps.contains(dep.name.as_str()))
+            .map(|dep| dep.name.clone())
+            .collect::<Vec<_>>();
+
+        let mut replaced_deps = new
+            .deps
+            .iter()
+            .filter(|dep| match old.deps.get(dep.name.as_str()) {
+                Some(old_dep) => !stores_match(old_dep, dep),
+                None => true,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if store.is_none() && removed_deps.is_empty() && replaced_deps.is_empty() {
+            return None;
+        }
+
+        removed_deps.sort_unstable();
+        replaced_deps.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+
+        Some(Self {
+            name: new.store.name.clone(),
+            store,
+            removed_deps,
+            replaced_deps,
+        })
+    }
+
+    fn apply_to(&self, pkg: &mut Derivation) -> Result<()> {
+        if let Some(store) = &self.store {
+            pkg.store = store.clone();
+        }
+
+        for name in &self.removed_deps {
+            if !pkg.deps.remove(name.as_str()) {
+                return Err(anyhow!(
+                    "removed dependency {} of {} does not exist",
+                    name,
+                    self.name
+                ));
+            }
+        }
+
+        for dep in &self.replaced_deps {
+            pkg.deps.replace(dep.clone());
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns true if every field of `a` and `b` is the same, as stores are normally only compared by name.
+fn stores_match(a: &Store, b: &Store) -> bool {
+    a.id == b.id
+        && a.name == b.name
+        && a.version == b.version
+        && a.suffix == b.suffix
+        && a.register_time == b.register_time
+        && a.deriver == b.deriver
+        && a.locally_built == b.locally_built
+        && a.ecosystem == b.ecosystem
+}
+
+/// Returns a checksum of every field of every package in `packages`, regardless of their order.
+///
+/// This is only meant to catch patches being applied to the wrong state, and is not cryptographically secure.
+pub fn checksum(packages: &HashSet<Derivation>) -> u64 {
+    let mut sorted = packages.iter().collect::<Vec<_>>();
+    sorted.sort_unstable_by(|x, y| x.store.name.cmp(&y.store.name));
+
+    let mut hasher = Fnv1a::default();
+
+    for pkg in sorted {
+        let mut deps = pkg.deps.iter().collect::<Vec<_>>();
+        deps.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+
+        // Writing to the hasher can't fail
+        bincode::serialize_into(&mut hasher, &(&pkg.store, deps)).ok();
+    }
+
+    hasher.0
+}
+
+/// A 64-bit FNV-1a hasher, which unlike the standard library's hasher is guaranteed to be stable.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Write for Fnv1a {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::ecosystem::Ecosystem;
+    use crate::testing::{self, Rng};
+    use std::collections::HashMap;
+    use std::iter;
+
+    const NAMES: [&str; 6] = ["firefox", "glibc", "nss", "mesa", "wine-wow", "python3"];
+    const VERSIONS: [&str; 3] = ["1.0", "1.1", "2.0"];
+    const SUFFIXES: [Option<&str>; 3] = [None, Some("bin"), Some("staging")];
+
+    fn random_store(rng: &mut Rng, name: &str) -> Store {
+        let id = rng.below(4) as u32;
+        let version = VERSIONS[rng.below(VERSIONS.len() as u64) as usize];
+
+        Store {
+            id,
+            suffix: SUFFIXES[rng.below(SUFFIXES.len() as u64) as usize].map(Into::into),
+            register_time: rng.below(3) as u32,
+            ..testing::store(name, version)
+        }
+    }
+
+    fn random_packages(rng: &mut Rng) -> HashSet<Derivation> {
+        let mut packages = HashSet::new();
+
+        for name in &NAMES {
+            if rng.below(4) == 0 {
+                continue;
+            }
+
+            let mut deps = HashSet::new();
+
+            for dep in NAMES.iter().filter(|dep| *dep != name) {
+                if rng.below(2) == 0 {
+                    deps.insert(random_store(rng, dep));
+                }
+            }
+
+            packages.insert(Derivation {
+                store: random_store(rng, name),
+                deps,
+                paths: HashMap::new(),
+            });
+        }
+
+        packages
+    }
+
+    /// Returns every field of every package in `packages` in a comparable form.
+    fn contents(packages: &HashSet<Derivation>) -> Vec<String> {
+        let mut contents = packages
+            .iter()
+            .map(|pkg| {
+                let mut deps = pkg
+                    .deps
+                    .iter()
+                    .map(|dep| format!("{:?}", dep))
+                    .collect::<Vec<_>>();
+
+                deps.sort_unstable();
+                format!("{:?} {:?}", pkg.store, deps)
+            })
+            .collect::<Vec<_>>();
+
+        contents.sort_unstable();
+        contents
+    }
+
+    #[test]
+    fn apply_roundtrip() {
+        let mut rng = Rng::new(0x9e37_79b9_7f4a_7c15);
+
+        for _ in 0..500 {
+            let old = random_packages(&mut rng);
+            let new = random_packages(&mut rng);
+
+            let patch = Patch::new(&old, &new);
+            let patched = patch.apply(old).unwrap();
+
+            assert_eq!(contents(&patched), contents(&new));
+        }
+    }
+
+    #[test]
+    fn apply_ecosystem_change() {
+        let package = |ecosystem: Option<Ecosystem>| Derivation {
+            store: Store {
+                ecosystem,
+                ..testing::store("zarith", "1.13")
+            },
+            deps: HashSet::new(),
+            paths: HashMap::new(),
+        };
+
+        let old = iter::once(package(None)).collect::<HashSet<_>>();
+        let new = iter::once(package(Some(Ecosystem {
+            name: "ocaml".into(),
+            version: "4.14.1".into(),
+        })))
+        .collect::<HashSet<_>>();
+
+        let patched = Patch::new(&old, &new).apply(old).unwrap();
+        assert_eq!(contents(&patched), contents(&new));
+    }
+
+    #[test]
+    fn refuse_wrong_base() {
+        let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+
+        let old = random_packages(&mut rng);
+        let mut new = random_packages(&mut rng);
+        new.insert(Derivation {
+            store: random_store(&mut rng, "added"),
+            deps: HashSet::new(),
+            paths: HashMap::new(),
+        });
+
+        let patch = Patch::new(&old, &new);
+
+        let err = patch.apply(new).unwrap_err();
+        assert!(err.to_string().contains("created from"), "{}", err);
+    }
+
+    #[test]
+    fn save_and_load_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes.nixpatch");
+
+        let mut rng = Rng::new(0x1234_5678_9abc_def1);
+        let old = random_packages(&mut rng);
+        let new = random_packages(&mut rng);
+
+        Patch::new(&old, &new).save(&path).unwrap();
+
+        let patched = Patch::load(&path).unwrap().apply(old).unwrap();
+        assert_eq!(contents(&patched), contents(&new));
+
+        fs::write(&path, b"not a patch").unwrap();
+        assert!(Patch::load(&path).is_err());
+    }
+}
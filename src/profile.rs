@@ -0,0 +1,316 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// The profile that points to the currently active system.
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// The directory every store path lives in.
+pub const STORE_DIR: &str = "/nix/store";
+
+/// Resolves the profile at `path` to the store path it points to.
+///
+/// Profiles such as `system-<N>-link` are usually chains of relative and absolute symlinks,
+/// so every link is followed until the final target is reached.
+pub fn resolve_profile<P>(path: P) -> Result<PathBuf>
+where
+    P: AsRef<Path>,
+{
+    resolve_profile_in(path.as_ref(), Path::new(STORE_DIR))
+}
+
+fn resolve_profile_in(path: &Path, store_dir: &Path) -> Result<PathBuf> {
+    let resolved = match fs::canonicalize(path) {
+        Ok(resolved) => resolved,
+        // If the link itself exists, then something along its chain does not
+        Err(err) if err.kind() == io::ErrorKind::NotFound && fs::symlink_metadata(path).is_ok() => {
+            return Err(anyhow!("profile link at {} is dangling", path.display()))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(anyhow!("profile at {} does not exist", path.display()))
+        }
+        Err(err) => {
+            return Err(err)
+                .with_context(|| anyhow!("failed to resolve profile at {}", path.display()))
+        }
+    };
+
+    if !resolved.starts_with(store_dir) || resolved == store_dir {
+        return Err(anyhow!(
+            "profile at {} resolves to {}, which is not in {}",
+            path.display(),
+            resolved.display(),
+            store_dir.display()
+        ));
+    }
+
+    Ok(resolved)
+}
+
+/// Returns the generation number the profile at `path` currently points to.
+///
+/// Profiles point to a link in the form of `<profile>-<N>-link`, where `N` is the generation.
+pub fn current_generation<P>(path: P) -> Result<u32>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+
+    let target = fs::read_link(path)
+        .with_context(|| anyhow!("failed to read profile link at {}", path.display()))?;
+
+    target
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(parse_generation)
+        .ok_or_else(|| {
+            anyhow!(
+                "profile at {} points to {}, which is not a generation link",
+                path.display(),
+                target.display()
+            )
+        })
+}
+
+fn parse_generation(link_name: &str) -> Option<u32> {
+    let name = link_name.strip_suffix("-link")?;
+    let (_, generation) = name.rsplit_once('-')?;
+    generation.parse().ok()
+}
+
+/// The directory of a NixOS system that links to the system of each of its specialisations by name.
+pub const SPECIALISATION_DIR: &str = "specialisation";
+
+/// Returns the name and store path of every specialisation of the NixOS system at `system`, sorted by name.
+///
+/// Each specialisation is a complete system of its own. Systems without any specialisations have no
+/// specialisation directory, which isn't an error.
+pub fn specialisations(system: &Path) -> Result<Vec<(String, PathBuf)>> {
+    specialisations_in(system, Path::new(STORE_DIR))
+}
+
+fn specialisations_in(system: &Path, store_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let dir = system.join(SPECIALISATION_DIR);
+
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| anyhow!("failed to read {}", dir.display())),
+    };
+
+    let mut specialisations = Vec::new();
+
+    for entry in entries {
+        let entry = entry.with_context(|| anyhow!("failed to read {}", dir.display()))?;
+
+        let name = entry.file_name().into_string().map_err(|name| {
+            anyhow!(
+                "specialisation {} in {} is not valid UTF-8",
+                name.to_string_lossy(),
+                dir.display()
+            )
+        })?;
+
+        let path = resolve_profile_in(&entry.path(), store_dir)
+            .with_context(|| anyhow!("failed to resolve specialisation {}", name))?;
+
+        specialisations.push((name, path));
+    }
+
+    specialisations.sort_unstable();
+    Ok(specialisations)
+}
+
+/// Returns the top-level store path `path` is inside of, such as `/nix/store/<hash>-nixup-0.4.0`
+/// for `/nix/store/<hash>-nixup-0.4.0/bin/nixup`.
+///
+/// `path` should already be canonical, as symlinks into the store aren't followed.
+pub fn store_path_of(path: &Path) -> Option<PathBuf> {
+    store_path_in(path, Path::new(STORE_DIR))
+}
+
+fn store_path_in(path: &Path, store_dir: &Path) -> Option<PathBuf> {
+    match path.strip_prefix(store_dir).ok()?.components().next()? {
+        Component::Normal(entry) => Some(store_dir.join(entry)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    struct Profiles {
+        root: TempDir,
+        store: PathBuf,
+    }
+
+    impl Profiles {
+        fn new() -> Self {
+            let root = tempfile::tempdir().unwrap();
+            let store = root.path().join("store");
+
+            fs::create_dir_all(store.join("abc-nixos-system-23.11")).unwrap();
+
+            // Canonicalize so comparisons aren't thrown off by a symlinked temp dir
+            let store = fs::canonicalize(store).unwrap();
+
+            Self { root, store }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.root.path().join(name)
+        }
+
+        fn resolve(&self, name: &str) -> Result<PathBuf> {
+            resolve_profile_in(&self.path(name), &self.store)
+        }
+    }
+
+    #[test]
+    fn resolve_profile_links() {
+        let profiles = Profiles::new();
+        let target = profiles.store.join("abc-nixos-system-23.11");
+
+        symlink(&target, profiles.path("system-1-link")).unwrap();
+        symlink(
+            "store/abc-nixos-system-23.11",
+            profiles.path("system-2-link"),
+        )
+        .unwrap();
+        symlink("system-2-link", profiles.path("system")).unwrap();
+
+        assert_eq!(
+            profiles.resolve("system-1-link").unwrap(),
+            target,
+            "absolute"
+        );
+        assert_eq!(
+            profiles.resolve("system-2-link").unwrap(),
+            target,
+            "relative"
+        );
+        assert_eq!(profiles.resolve("system").unwrap(), target, "chained");
+    }
+
+    #[test]
+    fn resolve_bad_profile_links() {
+        let profiles = Profiles::new();
+
+        symlink("store/missing", profiles.path("system-1-link")).unwrap();
+        symlink("system-1-link", profiles.path("system-2-link")).unwrap();
+        symlink(profiles.root.path(), profiles.path("system-3-link")).unwrap();
+        symlink("store", profiles.path("system-4-link")).unwrap();
+
+        let err = |name| profiles.resolve(name).unwrap_err().to_string();
+
+        assert!(err("system-1-link").contains("dangling"), "dangling");
+        assert!(
+            err("system-2-link").contains("dangling"),
+            "chained dangling"
+        );
+        assert!(err("system-3-link").contains("not in"), "outside store");
+        assert!(err("system-4-link").contains("not in"), "store itself");
+        assert!(err("system-5-link").contains("does not exist"), "missing");
+    }
+
+    #[test]
+    fn find_specialisations() {
+        let profiles = Profiles::new();
+        let system = profiles.store.join("abc-nixos-system-23.11");
+
+        // A system without specialisations
+        assert!(specialisations_in(&system, &profiles.store)
+            .unwrap()
+            .is_empty());
+
+        for name in &[
+            "def-nixos-system-on-the-go-23.11",
+            "ghi-nixos-system-gaming-23.11",
+        ] {
+            fs::create_dir(profiles.store.join(name)).unwrap();
+        }
+
+        let dir = system.join(SPECIALISATION_DIR);
+        fs::create_dir(&dir).unwrap();
+
+        symlink(
+            "../../def-nixos-system-on-the-go-23.11",
+            dir.join("on-the-go"),
+        )
+        .unwrap();
+        symlink(
+            profiles.store.join("ghi-nixos-system-gaming-23.11"),
+            dir.join("gaming"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            specialisations_in(&system, &profiles.store).unwrap(),
+            [
+                (
+                    "gaming".to_string(),
+                    profiles.store.join("ghi-nixos-system-gaming-23.11")
+                ),
+                (
+                    "on-the-go".to_string(),
+                    profiles.store.join("def-nixos-system-on-the-go-23.11")
+                ),
+            ]
+        );
+
+        symlink("../../missing", dir.join("broken")).unwrap();
+
+        let err = format!(
+            "{:#}",
+            specialisations_in(&system, &profiles.store).unwrap_err()
+        );
+        assert!(err.contains("specialisation broken"), "{}", err);
+    }
+
+    #[test]
+    fn profile_generations() {
+        let profiles = Profiles::new();
+
+        symlink("system-42-link", profiles.path("system")).unwrap();
+        symlink("store", profiles.path("not-a-generation")).unwrap();
+
+        assert_eq!(current_generation(profiles.path("system")).unwrap(), 42);
+        assert!(current_generation(profiles.path("not-a-generation")).is_err());
+        assert!(current_generation(profiles.path("missing")).is_err());
+
+        assert_eq!(parse_generation("system-1-link"), Some(1));
+        assert_eq!(parse_generation("per-user-7-link"), Some(7));
+        assert_eq!(parse_generation("system-link"), None);
+        assert_eq!(parse_generation("system-x-link"), None);
+    }
+
+    #[test]
+    fn find_store_paths() {
+        let cases = [
+            (
+                "/nix/store/abc-nixup-0.4.0/bin/nixup",
+                Some("/nix/store/abc-nixup-0.4.0"),
+            ),
+            (
+                "/nix/store/abc-nixup-0.4.0",
+                Some("/nix/store/abc-nixup-0.4.0"),
+            ),
+            ("/nix/store", None),
+            ("/nix/storefront/abc-nixup-0.4.0", None),
+            ("/usr/bin/nixup", None),
+        ];
+
+        for (path, expected) in &cases {
+            assert_eq!(
+                store_path_of(Path::new(path)).as_deref(),
+                expected.map(Path::new),
+                "{}",
+                path
+            );
+        }
+    }
+}
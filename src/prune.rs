@@ -0,0 +1,199 @@
+use crate::clock;
+use crate::runs::RunLog;
+use crate::state;
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug)]
+pub struct PruneOptions {
+    /// The age in seconds a file must be older than to be removed.
+    pub older_than: u64,
+    /// Only report what would be removed.
+    pub dry_run: bool,
+    /// Allow the current state to be removed as well, which leaves nothing to diff against.
+    pub current: bool,
+}
+
+/// A file that was removed, or would be removed during a dry run.
+#[derive(Debug, PartialEq)]
+pub struct Removal {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Removes every snapshot and rotated run log in `data_dir` that is older than allowed by `opts`, as of `now`.
+///
+/// The current state is never removed unless `opts.current` is set. Returns every removed file,
+/// from oldest to newest.
+pub fn prune(data_dir: &Path, opts: &PruneOptions, now: u64) -> Result<Vec<Removal>> {
+    let mut candidates = Vec::new();
+
+    for snapshot in state::list_snapshots(data_dir)? {
+        if snapshot.current && !opts.current {
+            continue;
+        }
+
+        if clock::age(snapshot.meta.saved_at, now) > opts.older_than {
+            candidates.push((snapshot.meta.saved_at, snapshot.path));
+        }
+    }
+
+    let rotated = RunLog::new(data_dir).rotated_path();
+
+    if let Some(modified) = modified_time(&rotated) {
+        if clock::age(modified, now) > opts.older_than {
+            candidates.push((modified, rotated));
+        }
+    }
+
+    candidates.sort_unstable();
+
+    let mut removals = Vec::with_capacity(candidates.len());
+
+    for (_, path) in candidates {
+        let size = fs::metadata(&path)
+            .with_context(|| anyhow!("failed to read metadata of {}", path.display()))?
+            .len();
+
+        if !opts.dry_run {
+            fs::remove_file(&path)
+                .with_context(|| anyhow!("failed to remove {}", path.display()))?;
+        }
+
+        removals.push(Removal { path, size });
+    }
+
+    Ok(removals)
+}
+
+fn modified_time(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some(since_epoch.as_secs())
+}
+
+/// Parses a duration such as `90d`, `12h`, or `2w` into seconds.
+///
+/// The supported units are `s`, `m`, `h`, `d`, and `w`. A number without a unit is treated as seconds.
+pub fn parse_duration(value: &str) -> Result<u64> {
+    let split = value
+        .find(|ch: char| !ch.is_ascii_digit())
+        .unwrap_or(value.len());
+
+    let (num, unit) = value.split_at(split);
+
+    let num = num.parse::<u64>().map_err(|_| {
+        anyhow!(
+            "invalid duration \"{}\", expected a number and unit such as 30d",
+            value
+        )
+    })?;
+
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(anyhow!(
+                "unknown duration unit \"{}\", expected s, m, h, d, or w",
+                unit
+            ))
+        }
+    };
+
+    num.checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("duration \"{}\" is too long", value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::PackageState;
+    use crate::store::Derivation;
+    use std::collections::HashSet;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    /// Saves an empty state as the current one in `data_dir`, as if it was saved at `saved_at`.
+    fn write_state(data_dir: &Path, saved_at: u64) {
+        let mut state = PackageState::new(HashSet::<Derivation>::new(), None).unwrap();
+        state.meta.saved_at = saved_at;
+        state.save(data_dir).unwrap();
+    }
+
+    #[test]
+    fn prune_old_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = 1_700_000_000;
+
+        // Saving again moves the previous state into the snapshot directory
+        for days_ago in &[60, 40, 10, 50] {
+            write_state(dir.path(), now - days_ago * DAY);
+        }
+
+        let opts = |dry_run, current| PruneOptions {
+            older_than: 30 * DAY,
+            dry_run,
+            current,
+        };
+
+        let names = |removals: &[Removal]| {
+            removals
+                .iter()
+                .map(|removal| {
+                    removal
+                        .path
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let preview = prune(dir.path(), &opts(true, false), now).unwrap();
+        assert_eq!(
+            names(&preview),
+            [
+                format!("packages-{}.bin", now - 60 * DAY),
+                format!("packages-{}.bin", now - 40 * DAY),
+            ]
+        );
+        assert!(preview.iter().all(|removal| removal.size > 0));
+        assert_eq!(
+            state::list_snapshots(dir.path()).unwrap().len(),
+            4,
+            "dry run"
+        );
+
+        // The current state is older than the cutoff, but should be kept
+        let removed = prune(dir.path(), &opts(false, false), now).unwrap();
+        assert_eq!(removed, preview);
+
+        let remaining = state::list_snapshots(dir.path()).unwrap();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining[0].current);
+
+        let removed = prune(dir.path(), &opts(false, true), now).unwrap();
+        assert_eq!(names(&removed), ["packages.bin"]);
+        assert!(!PackageState::save_path(dir.path()).exists());
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90").unwrap(), 90);
+        assert_eq!(parse_duration("45s").unwrap(), 45);
+        assert_eq!(parse_duration("15m").unwrap(), 15 * 60);
+        assert_eq!(parse_duration("12h").unwrap(), 12 * 60 * 60);
+        assert_eq!(parse_duration("30d").unwrap(), 30 * DAY);
+        assert_eq!(parse_duration("2w").unwrap(), 14 * DAY);
+
+        for invalid in &["", "d", "30 d", "30y", "-1d", "99999999999999999999w"] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
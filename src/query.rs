@@ -0,0 +1,883 @@
+use crate::critical::{glob_match, CriticalList};
+use crate::store::diff::{PackageDiff, RemovedPackage, StoreDiff};
+use crate::store::version::{Jump, Version};
+use anyhow::{anyhow, Error, Result};
+use std::str::FromStr;
+
+/// A filter over the changes of a diff, written as a single expression such as
+/// `name ~ 'python*' and kind != removed and (major or critical)`.
+///
+/// Every changed package and dependency is tested on its own, so a package whose own change doesn't match
+/// can still be reported for the dependency changes that do.
+#[derive(Debug)]
+pub struct Query {
+    source: String,
+    expr: Expr,
+}
+
+impl Query {
+    /// Returns the expression the query was parsed from.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+impl FromStr for Query {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let expr = Parser::new(source)
+            .and_then(Parser::parse)
+            .map_err(|err| anyhow!("{}", err.render(source)))?;
+
+        Ok(Self {
+            source: source.into(),
+            expr,
+        })
+    }
+}
+
+/// A query along with what it needs to evaluate every condition.
+#[derive(Copy, Clone)]
+pub struct Filter<'a> {
+    pub query: &'a Query,
+    /// The packages the `critical` condition matches.
+    pub critical: &'a CriticalList,
+}
+
+impl<'a> Filter<'a> {
+    /// Removes every change in `diffs` that doesn't match, along with the diffs that are left empty.
+    pub fn apply(&self, diffs: &mut Vec<PackageDiff>) {
+        diffs.iter_mut().for_each(|diff| self.retain_matching(diff));
+        diffs.retain(|diff| !diff.is_empty());
+    }
+
+    /// Removes the package change and every dependency change of `diff` that doesn't match.
+    ///
+    /// This can leave `diff` empty, and dropping it is left to the caller.
+    fn retain_matching(&self, diff: &mut PackageDiff) {
+        if let Some(pkg) = &diff.pkg {
+            if !self.matches(&Entry::change(pkg, false)) {
+                diff.pkg = None;
+            }
+        }
+
+        diff.deps
+            .retain(|dep| self.matches(&Entry::change(dep, true)));
+    }
+
+    pub fn matches_removal(&self, removal: &RemovedPackage) -> bool {
+        self.matches(&Entry::removal(removal))
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        self.query.expr.eval(entry, self.critical)
+    }
+}
+
+/// A single change as the conditions of a query see it.
+struct Entry<'a> {
+    name: &'a str,
+    kind: &'static str,
+    suffix: &'a str,
+    ver_from: &'a str,
+    ver_to: &'a str,
+    significance: &'static str,
+    /// The change in size of the store, which is never known as saved states don't record sizes.
+    size_delta: Option<i64>,
+    is_dep: bool,
+}
+
+impl<'a> Entry<'a> {
+    fn change(diff: &'a StoreDiff, is_dep: bool) -> Self {
+        let significance = match Version::parse(&diff.ver_from).jump(&Version::parse(&diff.ver_to))
+        {
+            Jump::None => "none",
+            Jump::Other => "other",
+            Jump::Patch => "patch",
+            Jump::Minor => "minor",
+            Jump::Major => "major",
+        };
+
+        Self {
+            name: &diff.name,
+            kind: diff.kind(),
+            suffix: diff.suffix.as_deref().unwrap_or_default(),
+            ver_from: &diff.ver_from,
+            ver_to: &diff.ver_to,
+            significance,
+            size_delta: None,
+            is_dep,
+        }
+    }
+
+    fn removal(removal: &'a RemovedPackage) -> Self {
+        Self {
+            name: &removal.name,
+            kind: "removed",
+            suffix: "",
+            ver_from: &removal.version,
+            ver_to: "",
+            significance: "none",
+            size_delta: None,
+            is_dep: false,
+        }
+    }
+
+    /// Returns the value of `field`, or None if it isn't known.
+    fn get(&self, field: Field) -> Option<Value<'a>> {
+        let value = match field {
+            Field::Name => Value::Text(self.name),
+            Field::Kind => Value::Text(self.kind),
+            Field::Suffix => Value::Text(self.suffix),
+            Field::VerFrom => Value::Text(self.ver_from),
+            Field::VerTo => Value::Text(self.ver_to),
+            Field::Significance => Value::Text(self.significance),
+            Field::SizeDelta => Value::Number(self.size_delta?),
+            Field::IsDep => Value::Bool(self.is_dep),
+        };
+
+        Some(value)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Field {
+    Name,
+    Kind,
+    Suffix,
+    VerFrom,
+    VerTo,
+    Significance,
+    SizeDelta,
+    IsDep,
+}
+
+impl Field {
+    const NAMES: [(&'static str, Self); 8] = [
+        ("name", Self::Name),
+        ("kind", Self::Kind),
+        ("suffix", Self::Suffix),
+        ("ver_from", Self::VerFrom),
+        ("ver_to", Self::VerTo),
+        ("significance", Self::Significance),
+        ("size_delta", Self::SizeDelta),
+        ("is_dep", Self::IsDep),
+    ];
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(field_name, _)| *field_name == name)
+            .map(|&(_, field)| field)
+    }
+
+    fn name(self) -> &'static str {
+        Self::NAMES
+            .iter()
+            .find(|(_, field)| *field == self)
+            .map_or("", |(name, _)| name)
+    }
+
+    fn ty(self) -> Type {
+        match self {
+            Self::SizeDelta => Type::Number,
+            Self::IsDep => Type::Bool,
+            _ => Type::Text,
+        }
+    }
+
+    /// Returns every value the field can have, if there is a fixed set of them.
+    fn known_values(self) -> Option<&'static [&'static str]> {
+        match self {
+            Self::Kind => Some(&["updated", "downgraded", "suffix_changed", "removed"]),
+            Self::Significance => Some(&["major", "minor", "patch", "other", "none"]),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Type {
+    Text,
+    Number,
+    Bool,
+}
+
+impl Type {
+    fn describe(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Number => "a number",
+            Self::Bool => "true or false",
+        }
+    }
+}
+
+/// The value of a field of an entry.
+#[derive(Debug, PartialEq)]
+enum Value<'a> {
+    Text(&'a str),
+    Number(i64),
+    Bool(bool),
+}
+
+/// A value written in a query.
+#[derive(Debug, PartialEq)]
+enum Literal {
+    Text(String),
+    Number(i64),
+    Bool(bool),
+}
+
+impl Literal {
+    fn equals(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Self::Text(x), Value::Text(y)) => x == y,
+            (Self::Number(x), Value::Number(y)) => x == y,
+            (Self::Bool(x), Value::Bool(y)) => x == y,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Glob,
+}
+
+/// A shorthand condition that is written without a field.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Flag {
+    Major,
+    Minor,
+    Patch,
+    Critical,
+}
+
+impl Flag {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "major" => Some(Self::Major),
+            "minor" => Some(Self::Minor),
+            "patch" => Some(Self::Patch),
+            "critical" => Some(Self::Critical),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Flag(Flag),
+    Compare {
+        field: Field,
+        op: Op,
+        value: Literal,
+    },
+}
+
+impl Expr {
+    fn eval(&self, entry: &Entry, critical: &CriticalList) -> bool {
+        match self {
+            Self::Or(x, y) => x.eval(entry, critical) || y.eval(entry, critical),
+            Self::And(x, y) => x.eval(entry, critical) && y.eval(entry, critical),
+            Self::Not(expr) => !expr.eval(entry, critical),
+            Self::Flag(Flag::Major) => entry.significance == "major",
+            Self::Flag(Flag::Minor) => entry.significance == "minor",
+            Self::Flag(Flag::Patch) => entry.significance == "patch",
+            Self::Flag(Flag::Critical) => critical.matches(entry.name),
+            Self::Compare { field, op, value } => {
+                // An unknown value matches neither a value nor its opposite
+                let actual = match entry.get(*field) {
+                    Some(actual) => actual,
+                    None => return false,
+                };
+
+                match (op, value, actual) {
+                    (Op::Glob, Literal::Text(pattern), Value::Text(text)) => {
+                        glob_match(pattern, text)
+                    }
+                    (Op::Glob, _, _) => false,
+                    (Op::Eq, value, actual) => value.equals(&actual),
+                    (Op::Ne, value, actual) => !value.equals(&actual),
+                }
+            }
+        }
+    }
+}
+
+/// An error at a position of a query, counted in characters.
+#[derive(Debug, PartialEq)]
+struct ParseError {
+    pos: usize,
+    message: String,
+}
+
+impl ParseError {
+    fn new<S>(pos: usize, message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            pos,
+            message: message.into(),
+        }
+    }
+
+    /// Formats the error with a caret under the position it occurred at in `source`.
+    fn render(&self, source: &str) -> String {
+        format!(
+            "invalid --where expression: {}\n  {}\n  {}^",
+            self.message,
+            source,
+            " ".repeat(self.pos)
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    /// An unquoted word, which is either a keyword, a field, a flag, or a value.
+    Word(String),
+    Quoted(String),
+    Op(Op),
+    Open,
+    Close,
+    End,
+}
+
+impl TokenKind {
+    fn describe(&self) -> String {
+        match self {
+            Self::Word(word) => format!("'{}'", word),
+            Self::Quoted(text) => format!("the string '{}'", text),
+            Self::Op(Op::Eq) => "'='".into(),
+            Self::Op(Op::Ne) => "'!='".into(),
+            Self::Op(Op::Glob) => "'~'".into(),
+            Self::Open => "'('".into(),
+            Self::Close => "')'".into(),
+            Self::End => "the end of the expression".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    pos: usize,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ParseError> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let start = i;
+
+        let kind = match chars[i] {
+            ch if ch.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => TokenKind::Open,
+            ')' => TokenKind::Close,
+            '=' => TokenKind::Op(Op::Eq),
+            '~' => TokenKind::Op(Op::Glob),
+            '!' if chars.get(i + 1) == Some(&'=') => {
+                i += 1;
+                TokenKind::Op(Op::Ne)
+            }
+            '!' => return Err(ParseError::new(i, "expected '!=', or 'not' to negate")),
+            quote @ '\'' | quote @ '"' => {
+                let len = chars[i + 1..]
+                    .iter()
+                    .position(|&ch| ch == quote)
+                    .ok_or_else(|| ParseError::new(start, "unterminated string"))?;
+
+                let text = chars[i + 1..=i + len].iter().collect();
+                i += len + 1;
+                TokenKind::Quoted(text)
+            }
+            _ => {
+                let len = chars[i..]
+                    .iter()
+                    .position(|&ch| ch.is_whitespace() || "()=!~'\"".contains(ch))
+                    .unwrap_or(chars.len() - i);
+
+                let word = chars[i..i + len].iter().collect();
+                i += len - 1;
+                TokenKind::Word(word)
+            }
+        };
+
+        i += 1;
+        tokens.push(Token { kind, pos: start });
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::End,
+        pos: chars.len(),
+    });
+
+    Ok(tokens)
+}
+
+/// A recursive descent parser, where `or` binds looser than `and`, which binds looser than `not`.
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Self, ParseError> {
+        let tokens = tokenize(source)?;
+        Ok(Self { tokens, next: 0 })
+    }
+
+    fn parse(mut self) -> Result<Expr, ParseError> {
+        let expr = self.parse_or()?;
+        let token = self.peek();
+
+        match token.kind {
+            TokenKind::End => Ok(expr),
+            TokenKind::Close => Err(ParseError::new(token.pos, "unmatched ')'")),
+            _ => Err(ParseError::new(
+                token.pos,
+                format!("expected 'and' or 'or', found {}", token.kind.describe()),
+            )),
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        // The last token is always the end, which is never consumed
+        &self.tokens[self.next.min(self.tokens.len() - 1)]
+    }
+
+    fn bump(&mut self) -> Token {
+        let token = self.peek().clone();
+        self.next += 1;
+        token
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match &self.peek().kind {
+            TokenKind::Word(word) if word == keyword => {
+                self.next += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_and()?;
+
+        while self.eat_keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_not()?;
+
+        while self.eat_keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        let Token { kind, pos } = self.bump();
+
+        let word = match kind {
+            TokenKind::Open => {
+                let expr = self.parse_or()?;
+                let close = self.bump();
+
+                return match close.kind {
+                    TokenKind::Close => Ok(expr),
+                    _ => Err(ParseError::new(
+                        close.pos,
+                        format!(
+                            "expected ')' to close the '(' at column {}, found {}",
+                            pos + 1,
+                            close.kind.describe()
+                        ),
+                    )),
+                };
+            }
+            TokenKind::Word(word) if !["and", "or"].contains(&word.as_str()) => word,
+            kind => {
+                return Err(ParseError::new(
+                    pos,
+                    format!("expected a condition, found {}", kind.describe()),
+                ))
+            }
+        };
+
+        let field = Field::from_name(&word);
+
+        let op = match self.peek().kind {
+            TokenKind::Op(op) => op,
+            _ => {
+                return match (Flag::from_name(&word), field) {
+                    (Some(flag), _) => Ok(Expr::Flag(flag)),
+                    // A true or false field is a condition on its own
+                    (None, Some(field)) if field.ty() == Type::Bool => Ok(Expr::Compare {
+                        field,
+                        op: Op::Eq,
+                        value: Literal::Bool(true),
+                    }),
+                    (None, Some(_)) => Err(ParseError::new(
+                        self.peek().pos,
+                        format!(
+                            "expected '=', '!=', or '~' after {}, found {}",
+                            word,
+                            self.peek().kind.describe()
+                        ),
+                    )),
+                    (None, None) => Err(unknown_field(pos, &word)),
+                };
+            }
+        };
+
+        let field = field.ok_or_else(|| unknown_field(pos, &word))?;
+        let op_pos = self.bump().pos;
+
+        if op == Op::Glob && field.ty() != Type::Text {
+            return Err(ParseError::new(
+                op_pos,
+                format!(
+                    "'~' only matches text, but {} is {}",
+                    field.name(),
+                    field.ty().describe()
+                ),
+            ));
+        }
+
+        let value = self.parse_value(field, op)?;
+        Ok(Expr::Compare { field, op, value })
+    }
+
+    /// Parses the value `field` is compared to, which must be of the same type as the field.
+    fn parse_value(&mut self, field: Field, op: Op) -> Result<Literal, ParseError> {
+        let token = self.bump();
+
+        let type_error = || {
+            ParseError::new(
+                token.pos,
+                format!(
+                    "{} is {} and can't be compared to {}",
+                    field.name(),
+                    field.ty().describe(),
+                    token.kind.describe()
+                ),
+            )
+        };
+
+        let value = match (&token.kind, field.ty()) {
+            (TokenKind::Word(text), Type::Text) | (TokenKind::Quoted(text), Type::Text) => {
+                Literal::Text(text.clone())
+            }
+            (TokenKind::Word(word), Type::Number) => {
+                Literal::Number(word.parse().map_err(|_| type_error())?)
+            }
+            (TokenKind::Word(word), Type::Bool) => match word.as_str() {
+                "true" => Literal::Bool(true),
+                "false" => Literal::Bool(false),
+                _ => return Err(type_error()),
+            },
+            (TokenKind::Quoted(_), _) => return Err(type_error()),
+            (kind, _) => {
+                return Err(ParseError::new(
+                    token.pos,
+                    format!("expected a value, found {}", kind.describe()),
+                ))
+            }
+        };
+
+        // Catch misspelled kinds, which would otherwise silently never match
+        if let (Literal::Text(text), Some(known), Op::Eq | Op::Ne) =
+            (&value, field.known_values(), op)
+        {
+            if !known.contains(&text.as_str()) {
+                return Err(ParseError::new(
+                    token.pos,
+                    format!(
+                        "unknown {} '{}', expected one of {}",
+                        field.name(),
+                        text,
+                        known.join(", ")
+                    ),
+                ));
+            }
+        }
+
+        Ok(value)
+    }
+}
+
+fn unknown_field(pos: usize, word: &str) -> ParseError {
+    let fields = Field::NAMES
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+
+    ParseError::new(
+        pos,
+        format!(
+            "unknown field or condition '{}', expected one of {}, major, minor, patch, or critical",
+            word,
+            fields.join(", ")
+        ),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(source: &str) -> Expr {
+        Parser::new(source)
+            .and_then(Parser::parse)
+            .unwrap_or_else(|err| panic!("{}", err.render(source)))
+    }
+
+    fn error(source: &str) -> ParseError {
+        Parser::new(source)
+            .and_then(Parser::parse)
+            .expect_err(source)
+    }
+
+    fn name(pattern: &str) -> Expr {
+        Expr::Compare {
+            field: Field::Name,
+            op: Op::Glob,
+            value: Literal::Text(pattern.into()),
+        }
+    }
+
+    fn store_diff(name: &str, from: &str, to: &str) -> StoreDiff {
+        StoreDiff {
+            name: name.into(),
+            suffix: None,
+            suffix_from: None,
+            ver_from: from.into(),
+            ver_to: to.into(),
+            register_time: 0,
+        }
+    }
+
+    fn matches(source: &str, diff: &StoreDiff, is_dep: bool) -> bool {
+        let query = source.parse::<Query>().unwrap();
+
+        let filter = Filter {
+            query: &query,
+            critical: &CriticalList::default(),
+        };
+
+        filter.matches(&Entry::change(diff, is_dep))
+    }
+
+    #[test]
+    fn parse_precedence() {
+        use Expr::*;
+
+        // and binds tighter than or, and not tighter than and
+        assert_eq!(
+            parse("name ~ a or not name ~ b and name ~ c"),
+            Or(
+                Box::new(name("a")),
+                Box::new(And(Box::new(Not(Box::new(name("b")))), Box::new(name("c"))))
+            )
+        );
+
+        assert_eq!(
+            parse("(name ~ a or name ~ b) and not not major"),
+            And(
+                Box::new(Or(Box::new(name("a")), Box::new(name("b")))),
+                Box::new(Not(Box::new(Not(Box::new(Flag(super::Flag::Major))))))
+            )
+        );
+
+        // Chains of the same operator group to the left
+        assert_eq!(
+            parse("major or minor or patch"),
+            Or(
+                Box::new(Or(
+                    Box::new(Flag(super::Flag::Major)),
+                    Box::new(Flag(super::Flag::Minor))
+                )),
+                Box::new(Flag(super::Flag::Patch))
+            )
+        );
+    }
+
+    #[test]
+    fn parse_values() {
+        assert_eq!(
+            parse("ver_to = \"1.0 beta\""),
+            Expr::Compare {
+                field: Field::VerTo,
+                op: Op::Eq,
+                value: Literal::Text("1.0 beta".into()),
+            }
+        );
+
+        // Numbers are text when compared to a text field
+        assert_eq!(
+            parse("ver_from!=5"),
+            Expr::Compare {
+                field: Field::VerFrom,
+                op: Op::Ne,
+                value: Literal::Text("5".into()),
+            }
+        );
+
+        assert_eq!(
+            parse("size_delta = -1024"),
+            Expr::Compare {
+                field: Field::SizeDelta,
+                op: Op::Eq,
+                value: Literal::Number(-1024),
+            }
+        );
+
+        assert_eq!(parse("is_dep"), parse("is_dep = true"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("name ~ 'python*", 7, "unterminated string"),
+            ("name ! 'x'", 5, "expected '!=', or 'not' to negate"),
+            ("(major or minor", 15, "expected ')' to close the '(' at column 1, found the end of the expression"),
+            ("major)", 5, "unmatched ')'"),
+            ("major minor", 6, "expected 'and' or 'or', found 'minor'"),
+            ("major and", 9, "expected a condition, found the end of the expression"),
+            ("and major", 0, "expected a condition, found 'and'"),
+            ("name", 4, "expected '=', '!=', or '~' after name, found the end of the expression"),
+            ("name = ", 7, "expected a value, found the end of the expression"),
+            ("kind != remove", 8, "unknown kind 'remove', expected one of updated, downgraded, suffix_changed, removed"),
+            ("size_delta = 'big'", 13, "size_delta is a number and can't be compared to the string 'big'"),
+            ("size_delta = big", 13, "size_delta is a number and can't be compared to 'big'"),
+            ("is_dep = yes", 9, "is_dep is true or false and can't be compared to 'yes'"),
+            ("size_delta ~ '1*'", 11, "'~' only matches text, but size_delta is a number"),
+        ];
+
+        for &(source, pos, message) in &cases {
+            assert_eq!(error(source), ParseError::new(pos, message), "{}", source);
+        }
+
+        assert!(error("version = 1.0")
+            .message
+            .starts_with("unknown field or condition 'version'"));
+    }
+
+    #[test]
+    fn render_caret() {
+        let err = "name = 'a' and kind = upgraded"
+            .parse::<Query>()
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            concat!(
+                "invalid --where expression: unknown kind 'upgraded', expected one of updated, downgraded, suffix_changed, removed\n",
+                "  name = 'a' and kind = upgraded\n",
+                "                        ^"
+            )
+        );
+
+        // Positions are counted in characters rather than bytes
+        let err = "name = 'é' !".parse::<Query>().unwrap_err();
+        assert!(err.to_string().ends_with("\n             ^"));
+    }
+
+    #[test]
+    fn evaluate_conditions() {
+        let python = store_diff("python3.11-requests", "2.31.0", "2.32.0");
+        let openssl = store_diff("openssl", "3.0.13", "3.1.0");
+        let mesa = store_diff("mesa", "24.0.1", "23.3.5");
+
+        assert!(matches("name ~ 'python*'", &python, false));
+        assert!(matches("name ~ '*requests'", &python, false));
+        assert!(!matches("name ~ 'requests'", &python, false));
+        assert!(!matches("name = 'python*'", &python, false));
+        assert!(matches("name ~ 'python3.1?-*'", &python, false));
+
+        assert!(matches("minor and not major", &python, false));
+        assert!(matches("significance = minor", &openssl, false));
+        assert!(matches("kind = downgraded and major", &mesa, false));
+        assert!(matches("kind = updated", &python, false));
+
+        assert!(matches("critical", &openssl, false));
+        assert!(!matches("critical", &mesa, false));
+
+        assert!(matches("is_dep", &openssl, true));
+        assert!(matches("is_dep = false", &openssl, false));
+        assert!(matches("not is_dep or name = mesa", &mesa, true));
+
+        assert!(matches(
+            "ver_from = 3.0.13 and ver_to = '3.1.0'",
+            &openssl,
+            false
+        ));
+        assert!(matches("suffix = ''", &openssl, false));
+
+        // Sizes are never known, so neither a size nor its opposite matches
+        assert!(!matches("size_delta = 0", &openssl, false));
+        assert!(!matches("size_delta != 0", &openssl, false));
+        assert!(matches("not size_delta = 0", &openssl, false));
+    }
+
+    #[test]
+    fn match_suffix_changes_and_removals() {
+        let mut gcc = store_diff("gcc", "13.2.0", "13.2.0");
+        gcc.suffix = Some("lib".into());
+
+        assert!(matches(
+            "kind = suffix_changed and suffix = lib",
+            &gcc,
+            false
+        ));
+        assert!(matches("significance = none", &gcc, false));
+
+        let query = "kind = removed and name ~ 'chrom*'"
+            .parse::<Query>()
+            .unwrap();
+
+        let filter = Filter {
+            query: &query,
+            critical: &CriticalList::default(),
+        };
+
+        let removal = |name: &str| RemovedPackage {
+            name: name.into(),
+            version: "120.0".into(),
+            gone: Vec::new(),
+            retained: Vec::new(),
+        };
+
+        assert!(filter.matches_removal(&removal("chromium")));
+        assert!(!filter.matches_removal(&removal("firefox")));
+    }
+}
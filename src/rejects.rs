@@ -0,0 +1,176 @@
+use crate::store::Store;
+use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// The most entries each category of rejects keeps before only counting the rest.
+pub const MAX_REJECTS: usize = 1000;
+
+/// What was left out of a saved state, recorded when `record_rejects` is set so audits can show what a
+/// baseline excluded as well as what it contained.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Rejects {
+    /// Raw paths that couldn't be parsed as packages.
+    pub unparsed: CappedList,
+    /// Names of packages that had a store discarded as a duplicate or an older version.
+    pub deduplicated: CappedList,
+}
+
+impl Rejects {
+    /// Records the `unparsed` paths of a scan along with the names of its `shadowed` stores, keeping up to `cap`
+    /// of each.
+    pub fn new(unparsed: Vec<String>, shadowed: &[Store], cap: usize) -> Self {
+        let deduplicated = shadowed.iter().map(|store| store.name.clone()).collect();
+
+        Self {
+            unparsed: CappedList::new(unparsed, cap),
+            deduplicated: CappedList::new(deduplicated, cap),
+        }
+    }
+}
+
+/// A sorted list of unique entries that is cut off after a limit, with a count of what was cut off.
+///
+/// Entries are encoded with the prefix they share with the previous entry left out, since rejects
+/// are mostly store paths that only differ after the store directory.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(into = "FrontCoded", try_from = "FrontCoded")]
+pub struct CappedList {
+    pub entries: Vec<String>,
+    /// How many entries were left out after the first `entries`.
+    pub overflow: u64,
+}
+
+impl CappedList {
+    /// Sorts and deduplicates `entries`, and keeps the first `cap` of them.
+    ///
+    /// Entries are sorted before being cut off, so the same entries are always kept no matter what
+    /// order they were found in.
+    pub fn new(mut entries: Vec<String>, cap: usize) -> Self {
+        entries.sort_unstable();
+        entries.dedup();
+
+        let overflow = entries.len().saturating_sub(cap) as u64;
+        entries.truncate(cap);
+
+        Self { entries, overflow }
+    }
+
+    /// Returns the number of entries there were before the list was cut off.
+    pub fn total(&self) -> u64 {
+        self.entries.len() as u64 + self.overflow
+    }
+}
+
+/// The encoded form of a `CappedList`, where each entry is the length in bytes of the prefix it shares
+/// with the previous entry, followed by the rest of it.
+#[derive(Serialize, Deserialize)]
+struct FrontCoded {
+    entries: Vec<(u32, String)>,
+    overflow: u64,
+}
+
+impl From<CappedList> for FrontCoded {
+    fn from(list: CappedList) -> Self {
+        let mut prev = "";
+        let mut entries = Vec::with_capacity(list.entries.len());
+
+        for entry in &list.entries {
+            let shared = prev
+                .char_indices()
+                .zip(entry.chars())
+                .find(|((_, x), y)| x != y)
+                .map_or_else(|| prev.len().min(entry.len()), |((i, _), _)| i);
+
+            entries.push((shared as u32, entry[shared..].to_string()));
+            prev = entry;
+        }
+
+        Self {
+            entries,
+            overflow: list.overflow,
+        }
+    }
+}
+
+impl TryFrom<FrontCoded> for CappedList {
+    type Error = String;
+
+    fn try_from(coded: FrontCoded) -> Result<Self, Self::Error> {
+        let mut entries = Vec::<String>::with_capacity(coded.entries.len());
+
+        for (shared, rest) in coded.entries {
+            let prev = entries.last().map_or("", String::as_str);
+
+            // A corrupt state could point past the previous entry or into the middle of a character
+            let prefix = prev
+                .get(..shared as usize)
+                .ok_or_else(|| format!("reject shares {} bytes with a shorter entry", shared))?;
+
+            entries.push(format!("{}{}", prefix, rest));
+        }
+
+        Ok(Self {
+            entries,
+            overflow: coded.overflow,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|&entry| entry.into()).collect()
+    }
+
+    #[test]
+    fn cap_deterministically() {
+        let entries = strings(&["zsh", "bash", "fish", "bash", "dash", "ion"]);
+
+        let list = CappedList::new(entries.clone(), 3);
+        assert_eq!(list.entries, ["bash", "dash", "fish"]);
+        assert_eq!(list.overflow, 2);
+        assert_eq!(list.total(), 5);
+
+        // The order entries were found in doesn't change which are kept
+        let mut reversed = entries;
+        reversed.reverse();
+        assert_eq!(CappedList::new(reversed, 3), list);
+
+        let list = CappedList::new(strings(&["zsh"]), 3);
+        assert_eq!((list.entries.len(), list.overflow), (1, 0));
+    }
+
+    #[test]
+    fn front_code_entries() {
+        let list = CappedList::new(
+            strings(&[
+                "/nix/store/0a1b-source",
+                "/nix/store/0a1c-fix.patch",
+                "/nix/store/9z-é-data",
+                "/nix/store/9z-éa",
+                "",
+            ]),
+            10,
+        );
+
+        let coded = FrontCoded::from(list.clone());
+        let prefixes = coded
+            .entries
+            .iter()
+            .map(|(shared, _)| *shared)
+            .collect::<Vec<_>>();
+
+        assert_eq!(prefixes, [0, 0, 14, 11, 16]);
+        assert_eq!(coded.entries[2].1, "c-fix.patch");
+        assert_eq!(CappedList::try_from(coded), Ok(list));
+
+        let corrupt = FrontCoded {
+            entries: vec![(0, "a".into()), (5, "b".into())],
+            overflow: 0,
+        };
+
+        assert!(CappedList::try_from(corrupt).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::clock;
+use crate::state::StateMeta;
+use crate::store::version;
+use crate::store::{Derivation, Store};
+use anyhow::{anyhow, Error, Result};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// The fewest changed packages with trustworthy registration times needed to detect a rollback from them.
+pub const MIN_DATED_CHANGES: usize = 3;
+
+/// The percentage of changed packages that must have been registered before the baseline was saved to
+/// detect a rollback from registration times alone.
+pub const BULK_PERCENT: usize = 90;
+
+/// How to present a diff against a baseline that is newer than the current system.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Orientation {
+    /// Present the diff as reverted changes only when a rollback is detected.
+    #[default]
+    Auto,
+    /// Always present the diff as updates.
+    Forward,
+    /// Always present the diff as reverted changes.
+    Reverse,
+}
+
+impl Orientation {
+    /// Returns true if the diff should be presented as reverted changes, given the detected `rollback`.
+    pub fn is_reversed(self, rollback: Option<&Rollback>) -> bool {
+        match self {
+            Self::Auto => rollback.is_some(),
+            Self::Forward => false,
+            Self::Reverse => true,
+        }
+    }
+}
+
+impl FromStr for Orientation {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(Self::Auto),
+            "forward" => Ok(Self::Forward),
+            "reverse" => Ok(Self::Reverse),
+            _ => Err(anyhow!(
+                "unknown orientation \"{}\", expected auto, forward, or reverse",
+                value
+            )),
+        }
+    }
+}
+
+/// Why the current system appears to be older than the baseline.
+#[derive(Debug, PartialEq)]
+pub enum Rollback {
+    /// The system profile is at an earlier generation than when the baseline was saved.
+    Generation { saved: u32, current: u32 },
+    /// The bulk of the changed packages were registered before the baseline was saved.
+    RegistrationTimes { older: usize, dated: usize },
+}
+
+/// Returns why the current `stores` appear to have been rolled back from the baseline described by `meta`
+/// and containing `old`, if they do.
+///
+/// A lower generation of the system profile than when the baseline was saved is always a rollback.
+/// Otherwise, the registration times of the changed stores are compared to the time the baseline was saved,
+/// as updates are registered after it while rollbacks go back to stores registered before it. Times that
+/// can't be right as of `now`, including the baseline's own, are never trusted, so a skewed clock can't
+/// make an ordinary update look like a rollback.
+pub fn detect(
+    meta: &StateMeta,
+    current_generation: Option<u32>,
+    stores: &HashSet<Store>,
+    old: &HashSet<Derivation>,
+    now: u64,
+) -> Option<Rollback> {
+    if let (Some(saved), Some(current)) = (meta.generation, current_generation) {
+        if current < saved {
+            return Some(Rollback::Generation { saved, current });
+        }
+    }
+
+    if clock::check(meta.saved_at, now).is_some() {
+        return None;
+    }
+
+    let dated = stores
+        .iter()
+        .filter(|store| {
+            old.get(store.name.as_str()).is_some_and(|old| {
+                version::strip_build_metadata(&old.store.version)
+                    != version::strip_build_metadata(&store.version)
+            })
+        })
+        .map(|store| u64::from(store.register_time))
+        .filter(|&time| clock::check(time, now).is_none())
+        .collect::<Vec<_>>();
+
+    if dated.len() < MIN_DATED_CHANGES {
+        return None;
+    }
+
+    // Allow for stores registered right around when the baseline was saved
+    let older = dated
+        .iter()
+        .filter(|&&time| time.saturating_add(clock::FUTURE_TOLERANCE) < meta.saved_at)
+        .count();
+
+    if older * 100 >= dated.len() * BULK_PERCENT {
+        Some(Rollback::RegistrationTimes {
+            older,
+            dated: dated.len(),
+        })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+    use std::collections::HashMap;
+
+    const NOW: u64 = 1_700_000_000;
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn store(name: &str, version: &str, register_time: u64) -> Store {
+        Store {
+            register_time: register_time as u32,
+            ..testing::store(name, version)
+        }
+    }
+
+    fn meta(saved_at: u64, generation: Option<u32>) -> StateMeta {
+        StateMeta {
+            saved_at,
+            message: None,
+            generation,
+            ..StateMeta::default()
+        }
+    }
+
+    /// Returns a baseline with newer versions of `count` packages, and current stores registered `age` seconds
+    /// before `saved_at` with older versions.
+    fn states(count: usize, saved_at: u64, age: i64) -> (HashSet<Store>, HashSet<Derivation>) {
+        let register_time = (saved_at as i64 - age) as u64;
+        let name = |i| format!("pkg-{}", i);
+
+        let stores = (0..count)
+            .map(|i| store(&name(i), "1.0", register_time))
+            .collect();
+
+        let old = (0..count)
+            .map(|i| Derivation {
+                store: store(&name(i), "2.0", saved_at - DAY),
+                deps: HashSet::new(),
+                paths: HashMap::new(),
+            })
+            .collect();
+
+        (stores, old)
+    }
+
+    #[test]
+    fn detect_generation_rollbacks() {
+        let (stores, old) = states(0, NOW - DAY, 0);
+
+        assert_eq!(
+            detect(&meta(NOW - DAY, Some(42)), Some(41), &stores, &old, NOW),
+            Some(Rollback::Generation {
+                saved: 42,
+                current: 41
+            })
+        );
+
+        assert_eq!(
+            detect(&meta(NOW - DAY, Some(42)), Some(43), &stores, &old, NOW),
+            None
+        );
+        assert_eq!(
+            detect(&meta(NOW - DAY, None), Some(41), &stores, &old, NOW),
+            None,
+            "unknown saved generation"
+        );
+        assert_eq!(
+            detect(&meta(NOW - DAY, Some(42)), None, &stores, &old, NOW),
+            None,
+            "unknown current generation"
+        );
+    }
+
+    #[test]
+    fn detect_registration_rollbacks() {
+        let saved_at = NOW - DAY;
+
+        // Rolling back goes to versions registered a week before the baseline was saved
+        let (stores, old) = states(10, saved_at, 7 * DAY as i64);
+        assert_eq!(
+            detect(&meta(saved_at, None), None, &stores, &old, NOW),
+            Some(Rollback::RegistrationTimes {
+                older: 10,
+                dated: 10
+            })
+        );
+
+        // An ordinary update registers the new versions after the baseline
+        let (stores, old) = states(10, saved_at, -3600);
+        assert_eq!(
+            detect(&meta(saved_at, None), None, &stores, &old, NOW),
+            None
+        );
+
+        // Stores registered right before the baseline was saved are within the tolerance
+        let (stores, old) = states(10, saved_at, 60);
+        assert_eq!(
+            detect(&meta(saved_at, None), None, &stores, &old, NOW),
+            None
+        );
+
+        // Too few changes to tell
+        let (stores, old) = states(MIN_DATED_CHANGES - 1, saved_at, 7 * DAY as i64);
+        assert_eq!(
+            detect(&meta(saved_at, None), None, &stores, &old, NOW),
+            None
+        );
+    }
+
+    #[test]
+    fn ignore_clock_skew() {
+        let saved_at = NOW - DAY;
+        let (stores, old) = states(10, saved_at, 7 * DAY as i64);
+
+        // A baseline saved while the clock was ahead can't be compared against
+        let future = NOW + 30 * DAY;
+        assert_eq!(detect(&meta(future, None), None, &stores, &old, NOW), None);
+
+        // Stores registered at impossible times don't count toward a rollback
+        let skewed = stores
+            .iter()
+            .map(|store| Store {
+                register_time: 0,
+                ..store.clone()
+            })
+            .collect();
+
+        assert_eq!(
+            detect(&meta(saved_at, None), None, &skewed, &old, NOW),
+            None
+        );
+
+        // A few stale stores among an ordinary update aren't the bulk of it
+        let (mut stores, mut old) = states(10, saved_at, -3600);
+        let (stale, stale_old) = states(2, saved_at, 7 * DAY as i64);
+
+        for (i, (store, old_pkg)) in stale.into_iter().zip(stale_old).enumerate() {
+            let name = format!("stale-{}", i);
+
+            stores.insert(Store {
+                name: name.clone(),
+                ..store
+            });
+
+            old.insert(Derivation {
+                store: Store {
+                    name,
+                    ..old_pkg.store
+                },
+                ..old_pkg
+            });
+        }
+
+        assert_eq!(
+            detect(&meta(saved_at, None), None, &stores, &old, NOW),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_orientations() {
+        assert_eq!("auto".parse::<Orientation>().unwrap(), Orientation::Auto);
+        assert_eq!(
+            "reverse".parse::<Orientation>().unwrap(),
+            Orientation::Reverse
+        );
+        assert!("backward".parse::<Orientation>().is_err());
+
+        let rollback = Rollback::Generation {
+            saved: 2,
+            current: 1,
+        };
+
+        assert!(Orientation::Auto.is_reversed(Some(&rollback)));
+        assert!(!Orientation::Auto.is_reversed(None));
+        assert!(!Orientation::Forward.is_reversed(Some(&rollback)));
+        assert!(Orientation::Reverse.is_reversed(None));
+    }
+}
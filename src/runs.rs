@@ -0,0 +1,394 @@
+use crate::clock;
+use crate::display::format::iso_date;
+use crate::store::diff::Outcome;
+use anyhow::{anyhow, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The size the run log can grow to before it is rotated.
+pub const MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+/// What changed in a single diff.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// The names of the packages whose version changed.
+    pub updated: Vec<String>,
+    pub added: usize,
+    pub removed: usize,
+    /// Descriptions of every version that clearly went down.
+    pub downgrades: Vec<String>,
+    pub outcome: Outcome,
+}
+
+/// What kind of run produced a diff.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunMode {
+    /// Runs recorded before the mode was recorded are all plain diffs.
+    #[default]
+    Diff,
+    AfterCommand,
+    Watch,
+}
+
+impl RunMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Diff => "diff",
+            Self::AfterCommand => "after-command",
+            Self::Watch => "watch",
+        }
+    }
+}
+
+/// A summary of a single diff, as recorded in the run log.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    /// The epoch time the diff was run at.
+    pub time: u64,
+    #[serde(default)]
+    pub mode: RunMode,
+    /// The number of seconds between the baseline being saved and the diff being run.
+    pub baseline_age: u64,
+    pub updated: usize,
+    pub added: usize,
+    pub removed: usize,
+    /// How long scanning the system's stores took, in milliseconds.
+    pub scan_ms: u64,
+    /// How long the whole diff took, in milliseconds. This is zero for runs recorded before it was.
+    #[serde(default)]
+    pub duration_ms: u64,
+    /// The names of the updated packages, which are only recorded when `record_names` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub names: Option<Vec<String>>,
+}
+
+impl Run {
+    pub fn new(
+        time: u64,
+        mode: RunMode,
+        baseline_time: u64,
+        scan_time: Duration,
+        duration: Duration,
+        changes: Changes,
+        record_names: bool,
+    ) -> Self {
+        Self {
+            time,
+            mode,
+            baseline_age: clock::age(baseline_time, time),
+            updated: changes.updated.len(),
+            added: changes.added,
+            removed: changes.removed,
+            scan_ms: scan_time.as_millis() as u64,
+            duration_ms: duration.as_millis() as u64,
+            names: if record_names {
+                Some(changes.updated)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// An append-only log of runs, stored as one JSON object per line.
+///
+/// Once the log would grow past its maximum size, it is moved aside and a new one is started,
+/// so at most the current log and a single previous one exist at any time.
+pub struct RunLog {
+    path: PathBuf,
+    max_size: u64,
+}
+
+impl RunLog {
+    pub fn new(data_dir: &Path) -> Self {
+        Self::with_max_size(data_dir.join("runs.jsonl"), MAX_LOG_SIZE)
+    }
+
+    fn with_max_size(path: PathBuf, max_size: u64) -> Self {
+        Self { path, max_size }
+    }
+
+    /// Returns the path the log is moved to when it is rotated.
+    pub fn rotated_path(&self) -> PathBuf {
+        self.path.with_extension("jsonl.1")
+    }
+
+    /// Returns the path of the file locked while appending, which is separate from the log so it
+    /// stays the same when the log is rotated.
+    fn lock_path(&self) -> PathBuf {
+        self.path.with_extension("lock")
+    }
+
+    /// Takes an exclusive advisory lock that is held until the returned file is dropped.
+    fn lock(&self) -> Result<File> {
+        let path = self.lock_path();
+
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| anyhow!("failed to open run log lock at {}", path.display()))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| anyhow!("failed to lock run log at {}", path.display()));
+        }
+
+        Ok(file)
+    }
+
+    /// Appends `run` to the log.
+    ///
+    /// Each line is written with a single write to a file opened in append mode, so a crash can
+    /// only ever leave the last line incomplete. An incomplete line is terminated before the next
+    /// one is written so the new line stays intact. Concurrent runs take turns through an advisory
+    /// lock, so one can't rotate the log while another is writing to it.
+    pub fn append(&self, run: &Run) -> Result<()> {
+        let mut line = serde_json::to_vec(run).context("failed to encode run")?;
+        line.push(b'\n');
+
+        let _lock = self.lock()?;
+
+        let (len, torn) = self.tail()?;
+
+        if len > 0 && len + line.len() as u64 > self.max_size {
+            fs::rename(&self.path, self.rotated_path())
+                .with_context(|| anyhow!("failed to rotate run log at {}", self.path.display()))?;
+        } else if torn {
+            line.insert(0, b'\n');
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| anyhow!("failed to open run log at {}", self.path.display()))?;
+
+        file.write_all(&line)
+            .with_context(|| anyhow!("failed to write to run log at {}", self.path.display()))
+    }
+
+    /// Returns the length of the log, and whether its last line is missing its newline.
+    fn tail(&self) -> Result<(u64, bool)> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((0, false)),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| anyhow!("failed to open run log at {}", self.path.display()))
+            }
+        };
+
+        let len = file.seek(SeekFrom::End(0))?;
+
+        if len == 0 {
+            return Ok((0, false));
+        }
+
+        let mut last = [0];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+
+        Ok((len, last[0] != b'\n'))
+    }
+
+    /// Reads every run in the log, from oldest to newest.
+    ///
+    /// Lines that can't be parsed, such as one left incomplete by a crash, are skipped.
+    pub fn read(&self) -> Result<Vec<Run>> {
+        let mut runs = Vec::new();
+
+        for path in &[self.rotated_path(), self.path.clone()] {
+            let contents = match fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| anyhow!("failed to read run log at {}", path.display()))
+                }
+            };
+
+            runs.extend(
+                contents
+                    .lines()
+                    .filter_map(|line| serde_json::from_str(line).ok()),
+            );
+        }
+
+        Ok(runs)
+    }
+}
+
+/// Returns the number of runs in each month, in the form of YYYY-MM, from oldest to newest.
+pub fn runs_per_month(runs: &[Run]) -> Vec<(String, usize)> {
+    let mut months = BTreeMap::new();
+
+    for run in runs {
+        let month = iso_date(run.time)[..7].to_string();
+        *months.entry(month).or_insert(0) += 1;
+    }
+
+    months.into_iter().collect()
+}
+
+/// Returns the median number of updated packages per run.
+///
+/// When there are an even number of runs, the larger of the two middle values is used.
+pub fn median_updated(runs: &[Run]) -> Option<usize> {
+    let mut updated = runs.iter().map(|run| run.updated).collect::<Vec<_>>();
+    updated.sort_unstable();
+    updated.get(updated.len() / 2).copied()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(time: u64, updated: usize) -> Run {
+        Run {
+            time,
+            mode: RunMode::Diff,
+            baseline_age: 3600,
+            updated,
+            added: 1,
+            removed: 0,
+            scan_ms: 250,
+            duration_ms: 900,
+            names: None,
+        }
+    }
+
+    #[test]
+    fn append_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = RunLog::new(dir.path());
+
+        assert!(log.read().unwrap().is_empty());
+
+        let runs = vec![run(1_709_337_600, 3), run(1_709_424_000, 12)];
+
+        for run in &runs {
+            log.append(run).unwrap();
+        }
+
+        assert_eq!(log.read().unwrap(), runs);
+    }
+
+    #[test]
+    fn append_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let threads = (0..8)
+            .map(|thread| {
+                let path = dir.path().to_path_buf();
+
+                std::thread::spawn(move || {
+                    let log = RunLog::new(&path);
+
+                    for i in 0..25 {
+                        log.append(&run(thread * 100 + i, 0)).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut times = RunLog::new(dir.path())
+            .read()
+            .unwrap()
+            .into_iter()
+            .map(|run| run.time)
+            .collect::<Vec<_>>();
+
+        times.sort_unstable();
+
+        let expected = (0..8)
+            .flat_map(|thread| (0..25).map(move |i| thread * 100 + i))
+            .collect::<Vec<_>>();
+
+        assert_eq!(times, expected);
+    }
+
+    #[test]
+    fn read_runs_without_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = RunLog::new(dir.path());
+
+        let line =
+            r#"{"time":100,"baseline_age":60,"updated":2,"added":0,"removed":1,"scan_ms":40}"#;
+        fs::write(&log.path, format!("{}\n", line)).unwrap();
+
+        let runs = log.read().unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].mode, RunMode::Diff);
+        assert_eq!(runs[0].duration_ms, 0);
+    }
+
+    #[test]
+    fn recover_from_torn_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = RunLog::new(dir.path());
+
+        log.append(&run(100, 1)).unwrap();
+
+        // Simulate a crash partway through writing a line
+        let mut file = OpenOptions::new().append(true).open(&log.path).unwrap();
+        file.write_all(br#"{"time":200,"baseli"#).unwrap();
+
+        assert_eq!(log.read().unwrap(), [run(100, 1)], "torn final line");
+
+        log.append(&run(300, 2)).unwrap();
+        assert_eq!(
+            log.read().unwrap(),
+            [run(100, 1), run(300, 2)],
+            "line after torn line"
+        );
+    }
+
+    #[test]
+    fn rotate_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let line_len = serde_json::to_vec(&run(0, 0)).unwrap().len() as u64 + 1;
+
+        // Room for exactly two runs per file
+        let log = RunLog::with_max_size(dir.path().join("runs.jsonl"), line_len * 2);
+
+        for time in 0..5 {
+            log.append(&run(time, 0)).unwrap();
+        }
+
+        let times = |runs: Vec<Run>| runs.into_iter().map(|run| run.time).collect::<Vec<_>>();
+
+        // Only the current and previous logs are kept
+        assert_eq!(times(log.read().unwrap()), [2, 3, 4]);
+        assert!(fs::metadata(&log.path).unwrap().len() <= line_len * 2);
+    }
+
+    #[test]
+    fn aggregate_runs() {
+        let runs = [
+            run(1_709_337_600, 3),  // 2024-03-02
+            run(1_709_424_000, 12), // 2024-03-03
+            run(1_711_929_600, 5),  // 2024-04-01
+        ];
+
+        assert_eq!(
+            runs_per_month(&runs),
+            [("2024-03".to_string(), 2), ("2024-04".to_string(), 1)]
+        );
+
+        assert_eq!(median_updated(&runs), Some(5));
+        assert_eq!(median_updated(&runs[..2]), Some(12));
+        assert_eq!(median_updated(&[]), None);
+    }
+}
@@ -0,0 +1,265 @@
+use crate::profile;
+use crate::state::Specialisations;
+use crate::store::budget::Budget;
+use crate::store::database::SystemDatabase;
+use crate::store::diff::{self, DiffOptions, PackageDiff};
+use crate::store::{DepOptions, Derivation, Store};
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// Returns the stores in the closure of every specialisation of the NixOS system at `system`, by name.
+///
+/// See `Store::all_in_closure` for how each closure is scanned.
+pub fn find(
+    db: &SystemDatabase,
+    system: &Path,
+    max_nodes: usize,
+) -> Result<BTreeMap<String, HashSet<Store>>> {
+    profile::specialisations(system)?
+        .into_iter()
+        .map(|(name, root)| {
+            let stores = Store::all_in_closure(db, &root, max_nodes)
+                .with_context(|| anyhow!("failed to scan specialisation {}", name))?;
+
+            Ok((name, stores))
+        })
+        .collect()
+}
+
+/// Resolves the dependencies of every store of each specialisation in `found`, so they can be saved.
+pub fn resolve(
+    db: &SystemDatabase,
+    found: BTreeMap<String, HashSet<Store>>,
+    opts: DepOptions,
+    budget: &Budget,
+) -> Result<Specialisations> {
+    found
+        .into_iter()
+        .map(|(name, stores)| {
+            let (pkgs, _) = Derivation::all_from_stores(stores, db, opts, budget)
+                .with_context(|| anyhow!("failed to resolve specialisation {}", name))?;
+
+            Ok((name, pkgs))
+        })
+        .collect()
+}
+
+/// How the specialisations of the current system differ from the ones in a saved state.
+#[derive(Debug, Default)]
+pub struct Comparison {
+    /// The names of the specialisations that weren't in the saved state.
+    pub added: Vec<String>,
+    /// The names of the specialisations that are no longer in the current system.
+    pub removed: Vec<String>,
+    /// The changed packages of each specialisation in both, sorted by the name of the specialisation.
+    pub changed: Vec<(String, Vec<PackageDiff>)>,
+}
+
+impl Comparison {
+    /// Compares the specialisations in `found` against the saved ones in `old`.
+    ///
+    /// Like with the packages of the system, dependencies are only resolved for the stores that changed.
+    pub fn new(
+        db: &SystemDatabase,
+        old: &Specialisations,
+        found: BTreeMap<String, HashSet<Store>>,
+        opts: DepOptions,
+        diff_opts: DiffOptions,
+        budget: &Budget,
+    ) -> Result<Self> {
+        let mut comparison = Self {
+            removed: old
+                .keys()
+                .filter(|name| !found.contains_key(*name))
+                .cloned()
+                .collect(),
+            ..Self::default()
+        };
+
+        for (name, stores) in found {
+            let old_pkgs = match old.get(&name) {
+                Some(old_pkgs) => old_pkgs,
+                None => {
+                    comparison.added.push(name);
+                    continue;
+                }
+            };
+
+            let changed = diff::changed_stores(stores, old_pkgs);
+
+            let (new_pkgs, _) = Derivation::all_from_stores(changed, db, opts, budget)
+                .with_context(|| anyhow!("failed to resolve specialisation {}", name))?;
+
+            let mut diffs = diff::get_package_diffs(&new_pkgs, old_pkgs, diff_opts);
+            diffs.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+
+            comparison.changed.push((name, diffs));
+        }
+
+        Ok(comparison)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::closure::DEFAULT_MAX_NODES;
+    use crate::store::database::fixture;
+
+    const HASH: &str = "/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-";
+
+    /// Returns a database with a base system and an on-the-go specialisation that swaps its driver for `driver`.
+    fn system(driver: &str) -> SystemDatabase {
+        let db = fixture::empty();
+
+        let paths = [
+            "nixos-system-nixos-23.11",
+            "nixos-system-on-the-go-23.11",
+            "firefox-124.0",
+            "mesa-24.0.1",
+            "nvidia-x11-545.29.06",
+            driver,
+            "libdrm-2.4.120",
+        ];
+
+        for (id, name) in paths.iter().enumerate() {
+            fixture::add_path(&db, id as i32 + 1, name, 0);
+        }
+
+        let refs = [(1, 3), (1, 4), (1, 5), (2, 3), (2, 4), (2, 6), (6, 7)];
+
+        for (referrer, reference) in &refs {
+            fixture::add_ref(&db, *referrer, *reference);
+        }
+
+        db
+    }
+
+    fn root(name: &str) -> String {
+        format!("{}nixos-system-{}-23.11", HASH, name)
+    }
+
+    fn names(stores: &HashSet<Store>) -> Vec<(String, String)> {
+        let mut names = stores
+            .iter()
+            .map(|store| (store.name.clone(), store.version.clone()))
+            .collect::<Vec<_>>();
+
+        names.sort_unstable();
+        names
+    }
+
+    fn found(db: &SystemDatabase, names: &[&str]) -> BTreeMap<String, HashSet<Store>> {
+        names
+            .iter()
+            .map(|name| {
+                let stores =
+                    Store::all_in_closure(db, Path::new(&root(name)), DEFAULT_MAX_NODES).unwrap();
+
+                (name.to_string(), stores)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scan_specialisation_closures() {
+        let db = system("nouveau-1.0.17");
+
+        let base = Store::all_in_closure(&db, Path::new(&root("nixos")), DEFAULT_MAX_NODES);
+        let on_the_go =
+            Store::all_in_closure(&db, Path::new(&root("on-the-go")), DEFAULT_MAX_NODES);
+
+        let pair = |name: &str, version: &str| (name.to_string(), version.to_string());
+
+        assert_eq!(
+            names(&base.unwrap()),
+            [
+                pair("firefox", "124.0"),
+                pair("mesa", "24.0.1"),
+                pair("nvidia-x11", "545.29.06"),
+            ]
+        );
+
+        // The specialisation has its own driver, along with what the driver depends on
+        assert_eq!(
+            names(&on_the_go.unwrap()),
+            [
+                pair("firefox", "124.0"),
+                pair("libdrm", "2.4.120"),
+                pair("mesa", "24.0.1"),
+                pair("nouveau", "1.0.17"),
+            ]
+        );
+
+        let err = Store::all_in_closure(&db, Path::new(&root("missing")), DEFAULT_MAX_NODES)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("is not a valid path"), "{}", err);
+    }
+
+    #[test]
+    fn compare_specialisations() {
+        let budget = Budget::unlimited();
+        let opts = DepOptions::default();
+
+        let old_db = system("nouveau-1.0.17");
+        let old = resolve(
+            &old_db,
+            found(&old_db, &["nixos", "on-the-go"]),
+            opts,
+            &budget,
+        )
+        .unwrap();
+
+        assert_eq!(old["on-the-go"].len(), 4);
+
+        // The base system is a specialisation here only to have one that is removed
+        let new_db = system("nouveau-1.0.18");
+        let comparison = Comparison::new(
+            &new_db,
+            &old,
+            found(&new_db, &["on-the-go"]),
+            opts,
+            DiffOptions::default(),
+            &budget,
+        )
+        .unwrap();
+
+        assert!(comparison.added.is_empty());
+        assert_eq!(comparison.removed, ["nixos"]);
+        assert_eq!(comparison.changed.len(), 1);
+
+        let (name, diffs) = &comparison.changed[0];
+        assert_eq!(name, "on-the-go");
+
+        let updated = diffs
+            .iter()
+            .map(|diff| {
+                let pkg = diff.pkg.as_ref().unwrap();
+                (
+                    diff.name.as_str(),
+                    pkg.ver_from.as_str(),
+                    pkg.ver_to.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(updated, [("nouveau", "1.0.17", "1.0.18")]);
+
+        // Nothing was recorded for a specialisation that's new
+        let comparison = Comparison::new(
+            &new_db,
+            &Specialisations::new(),
+            found(&new_db, &["on-the-go"]),
+            opts,
+            DiffOptions::default(),
+            &budget,
+        )
+        .unwrap();
+
+        assert_eq!(comparison.added, ["on-the-go"]);
+        assert!(comparison.changed.is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::store::version::Version;
+use anyhow::{anyhow, Context, Result};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// A single package in the output of `nix-env -qa --json`.
+#[derive(Debug, Deserialize)]
+struct ManifestEntry {
+    /// Missing from the output of Nix versions before 2.4.
+    #[serde(default)]
+    pname: Option<String>,
+    #[serde(default)]
+    version: Option<String>,
+}
+
+/// Every version of each package that a channel makes available.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    versions: HashMap<String, Vec<String>>,
+}
+
+impl Manifest {
+    /// Reads a manifest written by `nix-env -qa --json`, which maps each attribute to its package.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| anyhow!("failed to read manifest at {}", path.display()))?;
+
+        Self::parse(&contents)
+            .with_context(|| anyhow!("failed to parse manifest at {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let entries: HashMap<String, ManifestEntry> = serde_json::from_str(contents)?;
+        let mut versions = HashMap::<String, Vec<String>>::new();
+
+        // Several attributes can provide different versions of the same package, such as `firefox` and `firefox-esr-115`
+        for entry in entries.into_values() {
+            if let (Some(pname), Some(version)) = (entry.pname, entry.version) {
+                versions.entry(pname).or_default().push(version);
+            }
+        }
+
+        for available in versions.values_mut() {
+            available.sort_unstable_by(|x, y| Version::parse(x).cmp(&Version::parse(y)));
+            available.dedup_by(|x, y| Version::parse(x) == Version::parse(y));
+        }
+
+        Ok(Self { versions })
+    }
+
+    /// Returns how far `version` of the package `name` is behind the newest version in the manifest.
+    ///
+    /// Nothing is returned when the package is up to date, isn't in the manifest, or when any of its versions
+    /// aren't purely numeric, since those can't be reliably ordered.
+    pub fn staleness(&self, name: &str, version: &str) -> Option<Staleness> {
+        let available = self.versions.get(name)?;
+        let installed = Version::parse(version);
+
+        let orderable = installed.is_numeric()
+            && available
+                .iter()
+                .all(|version| Version::parse(version).is_numeric());
+
+        if !orderable {
+            return None;
+        }
+
+        let behind = available
+            .iter()
+            .filter(|version| Version::parse(version) > installed)
+            .count();
+
+        if behind == 0 {
+            return None;
+        }
+
+        Some(Staleness {
+            behind,
+            latest: available.last()?.clone(),
+        })
+    }
+}
+
+/// How many releases an installed package is behind the newest one available.
+#[derive(Debug, PartialEq)]
+pub struct Staleness {
+    /// The number of distinct versions in the manifest that are newer than the installed one.
+    pub behind: usize,
+    pub latest: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MANIFEST: &str = r#"{
+        "nixpkgs.firefox": { "name": "firefox-122.0", "pname": "firefox", "version": "122.0" },
+        "nixpkgs.firefox-bin": { "name": "firefox-bin-122.0", "pname": "firefox", "version": "122.0" },
+        "nixpkgs.firefox_121": { "name": "firefox-121.0", "pname": "firefox", "version": "121.0" },
+        "nixpkgs.firefox_120": { "name": "firefox-120.0", "pname": "firefox", "version": "120.0" },
+        "nixpkgs.neovim": { "name": "neovim-0.10.0", "pname": "neovim", "version": "0.10.0" },
+        "nixpkgs.zsh": { "name": "zsh-5.9", "pname": "zsh", "version": "5.9" },
+        "nixpkgs.zsh-git": { "name": "zsh-unstable", "pname": "zsh", "version": "unstable-2024-01-01" },
+        "nixpkgs.old": { "name": "old-1.0" }
+    }"#;
+
+    #[test]
+    fn find_stale_packages() {
+        let manifest = Manifest::parse(MANIFEST).unwrap();
+
+        assert_eq!(
+            manifest.staleness("firefox", "120.0"),
+            Some(Staleness {
+                behind: 2,
+                latest: "122.0".into()
+            })
+        );
+
+        assert_eq!(
+            manifest.staleness("firefox", "119"),
+            Some(Staleness {
+                behind: 3,
+                latest: "122.0".into()
+            })
+        );
+
+        // Build metadata doesn't make a version newer
+        assert_eq!(
+            manifest.staleness("neovim", "0.9.5+build.3"),
+            Some(Staleness {
+                behind: 1,
+                latest: "0.10.0".into()
+            })
+        );
+
+        assert_eq!(manifest.staleness("firefox", "122.0"), None, "up to date");
+        assert_eq!(manifest.staleness("firefox", "123.0"), None, "newer");
+        assert_eq!(manifest.staleness("firefox", "120.0rc1"), None, "unordered");
+        assert_eq!(manifest.staleness("zsh", "5.8"), None, "unordered");
+        assert_eq!(manifest.staleness("old", "0.9"), None, "no pname");
+        assert_eq!(manifest.staleness("missing", "1.0"), None);
+    }
+
+    #[test]
+    fn reject_invalid_manifests() {
+        assert!(Manifest::parse("[]").is_err());
+        assert!(Manifest::parse("{ \"nixpkgs.a\": 1 }").is_err());
+    }
+}
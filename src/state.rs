@@ -0,0 +1,2129 @@
+use crate::ack::Acks;
+use crate::rejects::{CappedList, Rejects};
+use crate::store::fingerprint::ParserFingerprint;
+use crate::store::{Derivation, Store};
+use anyhow::{anyhow, Context, Result};
+use bincode::Options;
+use rayon::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The bytes every versioned state file starts with.
+/// State files without these bytes are assumed to be from before states had any metadata.
+const MAGIC: &[u8; 8] = b"NIXUPST\0";
+
+/// The current version of the state file format.
+const VERSION: u32 = 10;
+
+/// The number of shards the packages of a state are split into when saving it.
+const SHARDS: usize = 16;
+
+/// The most shards a state can have before it's assumed to be garbage.
+const MAX_SHARDS: usize = 4096;
+
+/// The maximum number of characters a state message can have.
+pub const MAX_MESSAGE_LEN: usize = 200;
+
+/// The most packages, or dependencies of a single package, a state can have before it's assumed to be garbage.
+/// Even large systems only have tens of thousands of paths.
+pub const MAX_PACKAGES: usize = 1_000_000;
+
+/// The longest any single string in a state can be before it's assumed to be garbage, in bytes.
+/// Nix limits the names of store paths to 211 characters, and the longest strings are paths to derivations.
+pub const MAX_STRING_LEN: usize = 4096;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StateMeta {
+    /// The epoch time the state was saved at.
+    pub saved_at: u64,
+    /// A message describing why the state was saved.
+    /// This should be sanitized with `sanitize_message` before being displayed.
+    pub message: Option<String>,
+    /// The generation of the system profile when the state was saved, if it was saved from the local system.
+    pub generation: Option<u32>,
+    /// The version of nixup the state was saved with, if it was recorded.
+    pub nixup_version: Option<String>,
+    /// How the parser of the nixup the state was saved with parsed the built-in corpus, if it was recorded.
+    pub parser: Option<ParserFingerprint>,
+}
+
+pub struct PackageState {
+    pub meta: StateMeta,
+    pub packages: HashSet<Derivation>,
+    /// Every top-level store that was left out of `packages` for being a duplicate or an older version.
+    /// This allows the state to be deduplicated again against another one with a wider window.
+    pub shadowed: Vec<Store>,
+    /// What was left out of the state when it was saved, if `record_rejects` was set.
+    pub rejects: Option<Rejects>,
+    /// The packages in the closure of each NixOS specialisation of the system, by the name of the specialisation.
+    /// This is `None` when specialisations weren't looked for, such as in states read from another store.
+    pub specialisations: Option<Specialisations>,
+}
+
+/// The packages of each specialisation of a system, by its name.
+pub type Specialisations = BTreeMap<String, HashSet<Derivation>>;
+
+impl PackageState {
+    pub fn new(packages: HashSet<Derivation>, message: Option<String>) -> Result<Self> {
+        if let Some(message) = &message {
+            let len = message.chars().count();
+
+            if len > MAX_MESSAGE_LEN {
+                return Err(anyhow!(
+                    "message is {} characters long, but can only be {} at most",
+                    len,
+                    MAX_MESSAGE_LEN
+                ));
+            }
+        }
+
+        let meta = StateMeta {
+            saved_at: now(),
+            message,
+            generation: None,
+            nixup_version: Some(env!("CARGO_PKG_VERSION").into()),
+            parser: Some(ParserFingerprint::current()),
+        };
+
+        Ok(Self {
+            meta,
+            packages,
+            shadowed: Vec::new(),
+            rejects: None,
+            specialisations: None,
+        })
+    }
+
+    /// Saves the state as the current baseline in `data_dir`.
+    ///
+    /// The previous baseline is kept in the snapshot directory rather than being overwritten. The state is
+    /// written to a temporary file that replaces the baseline once it's complete, so an interrupted save
+    /// never leaves a baseline that's cut off.
+    ///
+    /// Any diff that was acknowledged with `nixup ack` was against the previous baseline, so the acknowledgments
+    /// are cleared once the new one is in place.
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let path = Self::save_path(data_dir);
+
+        let body = self.encode(SHARDS).with_context(|| {
+            anyhow!(
+                "failed to encode system package state to {}",
+                path.display()
+            )
+        })?;
+
+        let temp = Self::temp_path(data_dir);
+
+        let result = write_state(&temp, &body).and_then(|_| {
+            if path.exists() {
+                rotate(&path, data_dir).context("failed to move previous package state")?;
+            }
+
+            fs::rename(&temp, &path)
+                .with_context(|| anyhow!("failed to move {} to {}", temp.display(), path.display()))
+        });
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+            return result;
+        }
+
+        Acks::clear(data_dir)
+    }
+
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        Self::load_from(&Self::save_path(data_dir))
+    }
+
+    /// Saves the state as the known-good state in `data_dir`, replacing the one that was promoted before.
+    ///
+    /// Unlike the baseline, the known-good state is only ever replaced by promoting another state, so it isn't
+    /// kept as a snapshot and acknowledgments are left alone.
+    pub fn promote(&self, data_dir: &Path) -> Result<()> {
+        let path = Self::good_path(data_dir);
+
+        let body = self.encode(SHARDS).with_context(|| {
+            anyhow!(
+                "failed to encode system package state to {}",
+                path.display()
+            )
+        })?;
+
+        let temp = Self::temp_path(data_dir);
+
+        let result = write_state(&temp, &body).and_then(|_| {
+            fs::rename(&temp, &path)
+                .with_context(|| anyhow!("failed to move {} to {}", temp.display(), path.display()))
+        });
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+
+        result
+    }
+
+    /// Loads the known-good state in `data_dir`.
+    pub fn load_good(data_dir: &Path) -> Result<Self> {
+        Self::load_from(&Self::good_path(data_dir))
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path)
+            .with_context(|| anyhow!("failed to read package state file at {}", path.display()))?;
+
+        Self::decode(&bytes, path)
+    }
+
+    /// Loads the state at a `path` given by the user, such as with `--state-file`.
+    ///
+    /// Unlike `load_from`, the path has to be a regular file and the file has to start with the state
+    /// header, so pointing at the wrong file fails instead of decoding garbage into a plausible state.
+    pub fn load_explicit(path: &Path) -> Result<Self> {
+        let path = validate_path(path)?;
+
+        let bytes = fs::read(&path)
+            .with_context(|| anyhow!("failed to read package state file at {}", path.display()))?;
+
+        if read_header(&bytes).is_none() {
+            return Err(anyhow!(
+                "{} is not a nixup state file, as it doesn't start with the state file header",
+                path.display()
+            ));
+        }
+
+        Self::decode(&bytes, &path)
+    }
+
+    /// Decodes the state file at `path` that contains `bytes`, and checks that it's within sane bounds.
+    fn decode(bytes: &[u8], path: &Path) -> Result<Self> {
+        let state = match read_header(bytes) {
+            Some((VERSION, body)) => Self::decode_sharded::<OwnedShardedHeader, Derivation>(body),
+            Some((9, body)) => Self::decode_sharded::<legacy::ShardedHeaderV9, Derivation>(body),
+            Some((8, body)) => Self::decode_sharded::<legacy::ShardedHeaderV8, Derivation>(body),
+            Some((7, body)) => Self::decode_sharded::<legacy::ShardedHeaderV7, Derivation>(body),
+            Some((6, body)) => {
+                Self::decode_sharded::<legacy::ShardedHeaderV6, legacy::DerivationV3>(body)
+            }
+            Some((5, body)) => {
+                Self::decode_sharded::<legacy::ShardedHeaderV5, legacy::DerivationV3>(body)
+            }
+            Some((4, body)) => decode::<legacy::PackageStateV4>(body).map(Into::into),
+            Some((3, body)) => decode::<legacy::PackageStateV3>(body).map(Into::into),
+            Some((2, body)) => decode::<legacy::PackageStateV2>(body).map(Into::into),
+            Some((1, body)) => decode::<legacy::PackageStateV1>(body).map(Into::into),
+            Some((version, _)) => Err(anyhow!(
+                "state was saved with unsupported version {}",
+                version
+            )),
+            None => Self::from_legacy(bytes, path),
+        };
+
+        let state = state.with_context(|| {
+            anyhow!(
+                "failed to decode system package state from {}",
+                path.display()
+            )
+        })?;
+
+        state
+            .check_bounds()
+            .with_context(|| anyhow!("package state at {} is invalid", path.display()))?;
+
+        Ok(state)
+    }
+
+    /// Encodes the body of a state file, with the packages split into `shards` shards by the hash of their name.
+    ///
+    /// Shards are encoded in parallel, and both the packages in each shard and their dependencies are sorted by name,
+    /// so the same packages are always encoded to the same bytes.
+    fn encode(&self, shards: usize) -> Result<Vec<u8>> {
+        let mut assigned = vec![Vec::new(); shards];
+
+        for pkg in &self.packages {
+            assigned[shard_of(&pkg.store.name, shards)].push(pkg);
+        }
+
+        let encoded = assigned
+            .into_par_iter()
+            .map(|mut pkgs| {
+                pkgs.sort_unstable_by(|x, y| x.store.name.cmp(&y.store.name));
+
+                let pkgs = pkgs
+                    .into_iter()
+                    .map(SortedDerivation::new)
+                    .collect::<Vec<_>>();
+
+                options().serialize(&pkgs)
+            })
+            .collect::<bincode::Result<Vec<_>>>()?;
+
+        // Specialisations are small next to the packages of the system, so they aren't worth sharding
+        let specialisations = self.specialisations.as_ref().map(|specialisations| {
+            specialisations
+                .iter()
+                .map(|(name, pkgs)| {
+                    let mut pkgs = pkgs.iter().map(SortedDerivation::new).collect::<Vec<_>>();
+                    pkgs.sort_unstable_by(|x, y| x.store.name.cmp(&y.store.name));
+                    (name.as_str(), pkgs)
+                })
+                .collect()
+        });
+
+        let header = ShardedHeader {
+            meta: &self.meta,
+            shadowed: &self.shadowed,
+            rejects: self.rejects.as_ref(),
+            specialisations,
+            shard_lens: encoded.iter().map(|shard| shard.len() as u64).collect(),
+        };
+
+        let mut body = options().serialize(&header)?;
+
+        for shard in encoded {
+            body.extend(shard);
+        }
+
+        Ok(body)
+    }
+
+    /// Decodes the body of a state file whose packages are split into shards, decoding each shard in parallel.
+    ///
+    /// `H` and `D` are the layouts of the header and packages for the version of the state file.
+    fn decode_sharded<H, D>(body: &[u8]) -> Result<Self>
+    where
+        H: serde::de::DeserializeOwned + Into<OwnedShardedHeader>,
+        D: serde::de::DeserializeOwned + Into<Derivation> + Send,
+    {
+        let mut rest = body;
+        let header = decode_prefix::<H>(&mut rest)?.into();
+
+        let num_shards = header.shard_lens.len();
+
+        if num_shards > MAX_SHARDS {
+            return Err(anyhow!(
+                "state has {} shards, which is more than the limit of {}",
+                num_shards,
+                MAX_SHARDS
+            ));
+        }
+
+        let mut shards = Vec::with_capacity(num_shards);
+
+        for (i, &len) in header.shard_lens.iter().enumerate() {
+            let len = usize::try_from(len)
+                .ok()
+                .filter(|&len| len <= rest.len())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "shard {} of {} is {} bytes long, but only {} bytes are left",
+                        i + 1,
+                        num_shards,
+                        len,
+                        rest.len()
+                    )
+                })?;
+
+            let (shard, remaining) = rest.split_at(len);
+            shards.push(shard);
+            rest = remaining;
+        }
+
+        if !rest.is_empty() {
+            return Err(anyhow!(
+                "found {} unexpected trailing bytes after the state",
+                rest.len()
+            ));
+        }
+
+        let shards = shards
+            .into_par_iter()
+            .enumerate()
+            .map(|(i, shard)| {
+                decode::<Vec<D>>(shard)
+                    .with_context(|| anyhow!("shard {} of {} is corrupt", i + 1, num_shards))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut packages = HashSet::with_capacity(shards.iter().map(Vec::len).sum());
+        packages.extend(shards.into_iter().flatten().map(Into::into));
+
+        Ok(Self {
+            meta: header.meta,
+            packages,
+            shadowed: header.shadowed,
+            rejects: header.rejects,
+            specialisations: header.specialisations.map(|specialisations| {
+                specialisations
+                    .into_iter()
+                    .map(|(name, pkgs)| (name, pkgs.into_iter().collect()))
+                    .collect()
+            }),
+        })
+    }
+
+    /// Returns an error naming the first count or string in the state that is too large to be real.
+    fn check_bounds(&self) -> Result<()> {
+        check_count("packages", self.packages.len())?;
+        check_count("shadowed stores", self.shadowed.len())?;
+
+        if let Some(message) = &self.meta.message {
+            let len = message.chars().count();
+
+            if len > MAX_MESSAGE_LEN {
+                return Err(anyhow!(
+                    "message is {} characters long, which is more than the limit of {}",
+                    len,
+                    MAX_MESSAGE_LEN
+                ));
+            }
+        }
+
+        if let Some(version) = &self.meta.nixup_version {
+            check_string("nixup version", version)?;
+        }
+
+        if let Some(parser) = &self.meta.parser {
+            check_count("parser fingerprint entries", parser.parses.len())?;
+
+            for (entry, parse) in &parser.parses {
+                check_string("parser fingerprint entry", entry)?;
+                check_string("parser fingerprint parse", parse)?;
+            }
+        }
+
+        let specialisations = self.specialisations.iter().flatten();
+
+        if let Some(specialisations) = &self.specialisations {
+            check_count("specialisations", specialisations.len())?;
+        }
+
+        for (name, pkgs) in specialisations.clone() {
+            check_string("specialisation name", name)?;
+            check_count("packages of a specialisation", pkgs.len())?;
+        }
+
+        let all_packages = self
+            .packages
+            .iter()
+            .chain(specialisations.flat_map(|(_, pkgs)| pkgs));
+
+        for pkg in all_packages {
+            check_store("package", &pkg.store)?;
+            check_count("dependencies of a package", pkg.deps.len())?;
+            check_count("dependency paths of a package", pkg.paths.len())?;
+
+            for dep in &pkg.deps {
+                check_store("dependency", dep)?;
+            }
+
+            for (name, path) in &pkg.paths {
+                check_string("dependency path name", name)?;
+                check_count("stores in a dependency path", path.len())?;
+
+                for step in path {
+                    check_string("dependency path step", step)?;
+                }
+            }
+        }
+
+        for store in &self.shadowed {
+            check_store("shadowed store", store)?;
+        }
+
+        if let Some(rejects) = &self.rejects {
+            check_rejects("unparsed path", &rejects.unparsed)?;
+            check_rejects("deduplicated name", &rejects.deduplicated)?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads only the metadata of the state at `path`, without decoding any of its packages.
+    pub fn load_meta(path: &Path) -> Result<StateMeta> {
+        let file = File::open(path)
+            .with_context(|| anyhow!("failed to open package state file at {}", path.display()))?;
+
+        // The metadata can't be larger than the file, which keeps a garbage message length from being allocated
+        let len = file
+            .metadata()
+            .with_context(|| anyhow!("failed to read metadata of {}", path.display()))?
+            .len();
+
+        let mut file = BufReader::new(file);
+
+        let mut header = [0; MAGIC.len() + 4];
+
+        let version = match file.read_exact(&mut header) {
+            Ok(()) => read_header(&header).map(|(version, _)| version),
+            Err(_) => None,
+        };
+
+        let options = options().with_limit(len).allow_trailing_bytes();
+
+        // Every version so far has started with the metadata, which gained the generation in version 6, and the
+        // version of nixup and its parser in version 8
+        let meta = match version {
+            Some(version) if version >= 8 => options.deserialize_from(file),
+            Some(6) | Some(7) => options
+                .deserialize_from::<_, legacy::StateMetaV6>(file)
+                .map(Into::into),
+            Some(_) => options
+                .deserialize_from::<_, legacy::StateMetaV1>(file)
+                .map(Into::into),
+            None => {
+                return Ok(StateMeta {
+                    saved_at: modified_time(path),
+                    ..StateMeta::default()
+                })
+            }
+        };
+
+        meta.with_context(|| {
+            anyhow!(
+                "failed to decode package state metadata from {}",
+                path.display()
+            )
+        })
+    }
+
+    /// Decodes a state file that was saved before states had a header or metadata.
+    fn from_legacy(bytes: &[u8], path: &Path) -> Result<Self> {
+        let packages = decode::<Vec<legacy::DerivationV1>>(bytes)?;
+
+        let meta = StateMeta {
+            saved_at: modified_time(path),
+            ..StateMeta::default()
+        };
+
+        Ok(Self {
+            meta,
+            packages: packages.into_iter().map(Into::into).collect(),
+            shadowed: Vec::new(),
+            rejects: None,
+            specialisations: None,
+        })
+    }
+
+    pub fn save_path(data_dir: &Path) -> PathBuf {
+        data_dir.join("packages.bin")
+    }
+
+    /// Returns the path of the known-good state, which is only saved with `--promote`.
+    pub fn good_path(data_dir: &Path) -> PathBuf {
+        data_dir.join("good.bin")
+    }
+
+    /// Returns the path a state is written to before it replaces the baseline, which is unique to this
+    /// process so concurrent saves don't write to the same file.
+    fn temp_path(data_dir: &Path) -> PathBuf {
+        data_dir.join(format!(".packages.bin.{}.tmp", std::process::id()))
+    }
+
+    pub fn snapshot_dir(data_dir: &Path) -> PathBuf {
+        data_dir.join("snapshots")
+    }
+}
+
+/// Returns the options every state file is encoded with, which are the same as `bincode::serialize`'s.
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
+
+/// Decodes `body` as the entire contents of a state file, without trailing bytes.
+///
+/// Reading is limited to the length of `body`, so a garbage length can't cause a huge allocation.
+fn decode<T>(body: &[u8]) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let mut rest = body;
+    let value = decode_prefix(&mut rest)?;
+
+    let trailing = rest.len();
+
+    if trailing > 0 {
+        return Err(anyhow!(
+            "found {} unexpected trailing bytes after the state",
+            trailing
+        ));
+    }
+
+    Ok(value)
+}
+
+fn check_count(what: &str, count: usize) -> Result<()> {
+    if count > MAX_PACKAGES {
+        return Err(anyhow!(
+            "found {} {}, which is more than the limit of {}",
+            count,
+            what,
+            MAX_PACKAGES
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_string(what: &str, value: &str) -> Result<()> {
+    if value.len() > MAX_STRING_LEN {
+        return Err(anyhow!(
+            "a {} is {} bytes long, which is more than the limit of {}",
+            what,
+            value.len(),
+            MAX_STRING_LEN
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_rejects(what: &str, list: &CappedList) -> Result<()> {
+    check_count(&format!("{}s", what), list.entries.len())?;
+
+    for entry in &list.entries {
+        check_string(what, entry)?;
+    }
+
+    Ok(())
+}
+
+fn check_store(what: &str, store: &Store) -> Result<()> {
+    check_string(&format!("{} name", what), &store.name)?;
+    check_string(&format!("{} version", what), &store.version)?;
+
+    if let Some(suffix) = &store.suffix {
+        check_string(&format!("{} suffix", what), suffix)?;
+    }
+
+    if let Some(deriver) = &store.deriver {
+        check_string(&format!("{} deriver", what), deriver)?;
+    }
+
+    if let Some(ecosystem) = &store.ecosystem {
+        check_string(&format!("{} ecosystem", what), &ecosystem.name)?;
+        check_string(&format!("{} ecosystem version", what), &ecosystem.version)?;
+    }
+
+    Ok(())
+}
+
+/// Resolves a state file `path` given by the user, and checks that it's a regular file.
+///
+/// Directories and special files such as FIFOs or devices are rejected, as reading them either fails
+/// in a confusing way or never finishes.
+fn validate_path(path: &Path) -> Result<PathBuf> {
+    let resolved = fs::canonicalize(path)
+        .with_context(|| anyhow!("failed to resolve state file path {}", path.display()))?;
+
+    let file_type = fs::metadata(&resolved)
+        .with_context(|| anyhow!("failed to read metadata of {}", resolved.display()))?
+        .file_type();
+
+    if file_type.is_dir() {
+        return Err(anyhow!(
+            "{} is a directory rather than a state file",
+            resolved.display()
+        ));
+    }
+
+    if !file_type.is_file() {
+        return Err(anyhow!(
+            "{} is a special file rather than a regular state file",
+            resolved.display()
+        ));
+    }
+
+    Ok(resolved)
+}
+
+/// Decodes a value from the start of `bytes`, and advances `bytes` past it.
+///
+/// Like `decode`, reading is limited to the length of `bytes`.
+fn decode_prefix<T>(bytes: &mut &[u8]) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    options()
+        .with_limit(bytes.len() as u64)
+        .allow_trailing_bytes()
+        .deserialize_from(bytes)
+        .map_err(Into::into)
+}
+
+/// Returns the shard a package named `name` belongs in out of `shards`.
+///
+/// This uses FNV-1a rather than the standard library's hasher, since the hash has to be the same on every run.
+fn shard_of(name: &str, shards: usize) -> usize {
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    (hash % shards as u64) as usize
+}
+
+/// Everything in a state file besides the packages, which follow it as separately encoded shards.
+#[derive(Serialize)]
+struct ShardedHeader<'a> {
+    meta: &'a StateMeta,
+    shadowed: &'a [Store],
+    rejects: Option<&'a Rejects>,
+    specialisations: Option<Vec<(&'a str, Vec<SortedDerivation<'a>>)>>,
+    /// The length of each encoded shard in bytes, in the order they follow the header in.
+    shard_lens: Vec<u64>,
+}
+
+/// The decoded counterpart of `ShardedHeader`.
+#[derive(Deserialize)]
+struct OwnedShardedHeader {
+    meta: StateMeta,
+    shadowed: Vec<Store>,
+    rejects: Option<Rejects>,
+    specialisations: Option<Vec<(String, Vec<Derivation>)>>,
+    shard_lens: Vec<u64>,
+}
+
+/// A derivation with its dependencies sorted by name, which is encoded the same way as a `Derivation`.
+#[derive(Serialize)]
+struct SortedDerivation<'a> {
+    store: &'a Store,
+    deps: Vec<&'a Store>,
+}
+
+impl<'a> SortedDerivation<'a> {
+    fn new(deriv: &'a Derivation) -> Self {
+        let mut deps = deriv.deps.iter().collect::<Vec<_>>();
+        deps.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+
+        Self {
+            store: &deriv.store,
+            deps,
+        }
+    }
+}
+
+/// Returns the version and body of a state file if `bytes` starts with a header.
+fn read_header(bytes: &[u8]) -> Option<(u32, &[u8])> {
+    if bytes.len() < MAGIC.len() + 4 || &bytes[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+
+    let mut version = [0; 4];
+    version.copy_from_slice(&bytes[MAGIC.len()..MAGIC.len() + 4]);
+
+    Some((u32::from_le_bytes(version), &bytes[MAGIC.len() + 4..]))
+}
+
+/// The layouts of previous state file versions, used to migrate them to the current one.
+///
+/// Sets are decoded as `Vec`'s since they share the same encoding and the old types don't need to be hashed.
+/// Stores from every previous version are normalized with the ecosystem rules, so the first diff against them
+/// doesn't report every store built for an ecosystem as removed and added again.
+mod legacy {
+    use super::{OwnedShardedHeader, PackageState, StateMeta};
+    use crate::rejects::Rejects;
+    use crate::store::{Derivation, Store};
+    use serde_derive::Deserialize;
+    use std::collections::HashMap;
+
+    /// The metadata of a state from before the generation of the system profile was recorded.
+    #[derive(Deserialize)]
+    pub struct StateMetaV1 {
+        saved_at: u64,
+        message: Option<String>,
+    }
+
+    impl From<StateMetaV1> for StateMeta {
+        fn from(meta: StateMetaV1) -> Self {
+            Self {
+                saved_at: meta.saved_at,
+                message: meta.message,
+                ..Self::default()
+            }
+        }
+    }
+
+    /// The metadata of a state from before the version of nixup and its parser were recorded.
+    #[derive(Deserialize)]
+    pub struct StateMetaV6 {
+        saved_at: u64,
+        message: Option<String>,
+        generation: Option<u32>,
+    }
+
+    impl From<StateMetaV6> for StateMeta {
+        fn from(meta: StateMetaV6) -> Self {
+            Self {
+                saved_at: meta.saved_at,
+                message: meta.message,
+                generation: meta.generation,
+                ..Self::default()
+            }
+        }
+    }
+
+    /// A store from before its ecosystem was recorded.
+    #[derive(Deserialize)]
+    pub struct StoreV3 {
+        id: u32,
+        name: String,
+        version: String,
+        suffix: Option<String>,
+        register_time: u32,
+        deriver: Option<String>,
+        locally_built: Option<bool>,
+    }
+
+    impl From<StoreV3> for Store {
+        fn from(store: StoreV3) -> Self {
+            Self {
+                id: store.id,
+                name: store.name,
+                version: store.version,
+                suffix: store.suffix,
+                register_time: store.register_time,
+                deriver: store.deriver,
+                locally_built: store.locally_built,
+                referrer_count: None,
+                ecosystem: None,
+            }
+            .normalize()
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct DerivationV3 {
+        store: StoreV3,
+        deps: Vec<StoreV3>,
+    }
+
+    impl From<DerivationV3> for Derivation {
+        fn from(deriv: DerivationV3) -> Self {
+            Self {
+                store: deriv.store.into(),
+                deps: deriv.deps.into_iter().map(Into::into).collect(),
+                paths: HashMap::new(),
+            }
+        }
+    }
+
+    /// A header from before the specialisations of the system were recorded.
+    #[derive(Deserialize)]
+    pub struct ShardedHeaderV9 {
+        meta: StateMeta,
+        shadowed: Vec<Store>,
+        rejects: Option<Rejects>,
+        shard_lens: Vec<u64>,
+    }
+
+    impl From<ShardedHeaderV9> for OwnedShardedHeader {
+        fn from(header: ShardedHeaderV9) -> Self {
+            Self {
+                meta: header.meta,
+                shadowed: header.shadowed,
+                rejects: header.rejects,
+                specialisations: None,
+                shard_lens: header.shard_lens,
+            }
+        }
+    }
+
+    /// A header from before what was left out of the state could be recorded.
+    #[derive(Deserialize)]
+    pub struct ShardedHeaderV8 {
+        meta: StateMeta,
+        shadowed: Vec<Store>,
+        shard_lens: Vec<u64>,
+    }
+
+    impl From<ShardedHeaderV8> for OwnedShardedHeader {
+        fn from(header: ShardedHeaderV8) -> Self {
+            Self {
+                meta: header.meta,
+                shadowed: header.shadowed,
+                rejects: None,
+                specialisations: None,
+                shard_lens: header.shard_lens,
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct ShardedHeaderV7 {
+        meta: StateMetaV6,
+        shadowed: Vec<Store>,
+        shard_lens: Vec<u64>,
+    }
+
+    impl From<ShardedHeaderV7> for OwnedShardedHeader {
+        fn from(header: ShardedHeaderV7) -> Self {
+            Self {
+                meta: header.meta.into(),
+                shadowed: header.shadowed,
+                rejects: None,
+                specialisations: None,
+                shard_lens: header.shard_lens,
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct ShardedHeaderV6 {
+        meta: StateMetaV6,
+        shadowed: Vec<StoreV3>,
+        shard_lens: Vec<u64>,
+    }
+
+    impl From<ShardedHeaderV6> for OwnedShardedHeader {
+        fn from(header: ShardedHeaderV6) -> Self {
+            Self {
+                meta: header.meta.into(),
+                shadowed: header.shadowed.into_iter().map(Into::into).collect(),
+                rejects: None,
+                specialisations: None,
+                shard_lens: header.shard_lens,
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct ShardedHeaderV5 {
+        meta: StateMetaV1,
+        shadowed: Vec<StoreV3>,
+        shard_lens: Vec<u64>,
+    }
+
+    impl From<ShardedHeaderV5> for OwnedShardedHeader {
+        fn from(header: ShardedHeaderV5) -> Self {
+            Self {
+                meta: header.meta.into(),
+                shadowed: header.shadowed.into_iter().map(Into::into).collect(),
+                rejects: None,
+                specialisations: None,
+                shard_lens: header.shard_lens,
+            }
+        }
+    }
+
+    /// A state from before its packages were split into shards.
+    #[derive(Deserialize)]
+    pub struct PackageStateV4 {
+        meta: StateMetaV1,
+        packages: Vec<DerivationV3>,
+        shadowed: Vec<StoreV3>,
+    }
+
+    impl From<PackageStateV4> for PackageState {
+        fn from(state: PackageStateV4) -> Self {
+            Self {
+                meta: state.meta.into(),
+                packages: state.packages.into_iter().map(Into::into).collect(),
+                shadowed: state.shadowed.into_iter().map(Into::into).collect(),
+                rejects: None,
+                specialisations: None,
+            }
+        }
+    }
+
+    /// A store from before the deriver was recorded.
+    #[derive(Deserialize)]
+    pub struct StoreV1 {
+        id: u32,
+        name: String,
+        version: String,
+        suffix: Option<String>,
+        register_time: u32,
+    }
+
+    impl From<StoreV1> for Store {
+        fn from(store: StoreV1) -> Self {
+            Self {
+                id: store.id,
+                name: store.name,
+                version: store.version,
+                suffix: store.suffix,
+                register_time: store.register_time,
+                deriver: None,
+                locally_built: None,
+                referrer_count: None,
+                ecosystem: None,
+            }
+            .normalize()
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct DerivationV1 {
+        store: StoreV1,
+        deps: Vec<StoreV1>,
+    }
+
+    impl From<DerivationV1> for Derivation {
+        fn from(deriv: DerivationV1) -> Self {
+            Self {
+                store: deriv.store.into(),
+                deps: deriv.deps.into_iter().map(Into::into).collect(),
+                paths: HashMap::new(),
+            }
+        }
+    }
+
+    /// A store from before whether it was built locally was recorded.
+    #[derive(Deserialize)]
+    pub struct StoreV2 {
+        id: u32,
+        name: String,
+        version: String,
+        suffix: Option<String>,
+        register_time: u32,
+        deriver: Option<String>,
+    }
+
+    impl From<StoreV2> for Store {
+        fn from(store: StoreV2) -> Self {
+            Self {
+                id: store.id,
+                name: store.name,
+                version: store.version,
+                suffix: store.suffix,
+                register_time: store.register_time,
+                deriver: store.deriver,
+                locally_built: None,
+                referrer_count: None,
+                ecosystem: None,
+            }
+            .normalize()
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct PackageStateV3 {
+        meta: StateMetaV1,
+        packages: Vec<DerivationV3>,
+    }
+
+    impl From<PackageStateV3> for PackageState {
+        fn from(state: PackageStateV3) -> Self {
+            Self {
+                meta: state.meta.into(),
+                packages: state.packages.into_iter().map(Into::into).collect(),
+                shadowed: Vec::new(),
+                rejects: None,
+                specialisations: None,
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct DerivationV2 {
+        store: StoreV2,
+        deps: Vec<StoreV2>,
+    }
+
+    impl From<DerivationV2> for Derivation {
+        fn from(deriv: DerivationV2) -> Self {
+            Self {
+                store: deriv.store.into(),
+                deps: deriv.deps.into_iter().map(Into::into).collect(),
+                paths: HashMap::new(),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct PackageStateV2 {
+        meta: StateMetaV1,
+        packages: Vec<DerivationV2>,
+    }
+
+    impl From<PackageStateV2> for PackageState {
+        fn from(state: PackageStateV2) -> Self {
+            Self {
+                meta: state.meta.into(),
+                packages: state.packages.into_iter().map(Into::into).collect(),
+                shadowed: Vec::new(),
+                rejects: None,
+                specialisations: None,
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct PackageStateV1 {
+        meta: StateMetaV1,
+        packages: Vec<DerivationV1>,
+    }
+
+    impl From<PackageStateV1> for PackageState {
+        fn from(state: PackageStateV1) -> Self {
+            Self {
+                meta: state.meta.into(),
+                packages: state.packages.into_iter().map(Into::into).collect(),
+                shadowed: Vec::new(),
+                rejects: None,
+                specialisations: None,
+            }
+        }
+    }
+}
+
+/// Writes a state file with an encoded `body` to `path`, and waits for it to reach the disk.
+fn write_state(path: &Path, body: &[u8]) -> Result<()> {
+    let file = File::create(path)
+        .with_context(|| anyhow!("failed to create package state file at {}", path.display()))?;
+
+    let mut file = BufWriter::new(file);
+
+    file.write_all(MAGIC)
+        .and_then(|_| file.write_all(&VERSION.to_le_bytes()))
+        .and_then(|_| file.write_all(body))
+        .and_then(|_| file.flush())
+        .and_then(|_| file.get_ref().sync_all())
+        .with_context(|| anyhow!("failed to write package state to {}", path.display()))
+}
+
+/// Copies the state at `path` into the snapshot directory, named after the time it was saved.
+///
+/// The state is hard linked so it stays in place until the new one replaces it, and is only copied when
+/// that isn't possible, such as when the snapshot directory is on another file system. A snapshot is never
+/// overwritten, so states saved within the same second get a counter after their time, such as
+/// `packages-1700000000-1.bin`.
+fn rotate(path: &Path, data_dir: &Path) -> Result<()> {
+    let saved_at = PackageState::load_meta(path)
+        .map(|meta| meta.saved_at)
+        .unwrap_or_else(|_| modified_time(path));
+
+    let dir = PackageState::snapshot_dir(data_dir);
+
+    fs::create_dir_all(&dir)
+        .with_context(|| anyhow!("failed to create directory at {}", dir.display()))?;
+
+    for num in 0.. {
+        let name = match num {
+            0 => format!("packages-{}.bin", saved_at),
+            _ => format!("packages-{}-{}.bin", saved_at, num),
+        };
+
+        let dest = dir.join(name);
+
+        let result = match fs::hard_link(path, &dest) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => copy_new(path, &dest),
+            result => result,
+        };
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    anyhow!("failed to copy {} to {}", path.display(), dest.display())
+                })
+            }
+        }
+    }
+
+    unreachable!()
+}
+
+/// Copies the file at `from` to `to`, failing if `to` already exists.
+fn copy_new(from: &Path, to: &Path) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    let mut dest = OpenOptions::new().write(true).create_new(true).open(to)?;
+
+    let result = io::copy(&mut source, &mut dest).and_then(|_| dest.sync_all());
+
+    // A partial copy would look like a corrupt snapshot
+    if result.is_err() {
+        let _ = fs::remove_file(to);
+    }
+
+    result
+}
+
+/// A saved state that can be diffed against.
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub meta: StateMeta,
+    /// Whether this is the state that is diffed against by default.
+    pub current: bool,
+}
+
+/// Returns the current baseline and every rotated snapshot in `data_dir`, sorted from newest to oldest.
+pub fn list_snapshots(data_dir: &Path) -> Result<Vec<Snapshot>> {
+    let mut snapshots = Vec::new();
+    let current = PackageState::save_path(data_dir);
+
+    if current.exists() {
+        snapshots.push(Snapshot {
+            meta: PackageState::load_meta(&current)?,
+            path: current,
+            current: true,
+        });
+    }
+
+    let dir = PackageState::snapshot_dir(data_dir);
+
+    if dir.exists() {
+        let entries = fs::read_dir(&dir)
+            .with_context(|| anyhow!("failed to read snapshot directory at {}", dir.display()))?;
+
+        for entry in entries {
+            let path = entry
+                .context("failed to read snapshot directory entry")?
+                .path();
+
+            if path.extension().and_then(|ext| ext.to_str()) != Some("bin") {
+                continue;
+            }
+
+            snapshots.push(Snapshot {
+                meta: PackageState::load_meta(&path)?,
+                path,
+                current: false,
+            });
+        }
+    }
+
+    snapshots.sort_by(|x, y| {
+        y.current
+            .cmp(&x.current)
+            .then_with(|| y.meta.saved_at.cmp(&x.meta.saved_at))
+    });
+
+    Ok(snapshots)
+}
+
+/// Makes `message` safe to print to a terminal by replacing any control characters.
+///
+/// Whitespace is replaced with a space so multi-line messages stay readable on one line.
+pub fn sanitize_message<S>(message: S) -> String
+where
+    S: AsRef<str>,
+{
+    message
+        .as_ref()
+        .chars()
+        .map(|ch| match ch {
+            '\n' | '\r' | '\t' => ' ',
+            ch if ch.is_control() => '?',
+            ch => ch,
+        })
+        .collect()
+}
+
+fn modified_time(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Returns the current epoch time in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jobs::ScanOptions;
+    use crate::testing::{self, Rng};
+    use std::collections::HashMap;
+    use std::iter;
+
+    fn packages() -> HashSet<Derivation> {
+        let store = testing::store("glxinfo", "8.4.0");
+
+        let mut packages = HashSet::new();
+
+        packages.insert(Derivation {
+            store,
+            deps: HashSet::new(),
+            paths: HashMap::new(),
+        });
+
+        packages
+    }
+
+    #[test]
+    fn save_and_load_state() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut state =
+            PackageState::new(packages(), Some("before risky kernel 6.8 bump".into())).unwrap();
+        state.shadowed = packages().into_iter().map(|pkg| pkg.store).collect();
+        state.meta.generation = Some(42);
+        state.save(dir.path()).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.meta.message, state.meta.message);
+        assert_eq!(loaded.meta.saved_at, state.meta.saved_at);
+        assert_eq!(loaded.meta.generation, Some(42));
+        assert_eq!(loaded.packages, state.packages);
+        assert_eq!(loaded.shadowed, state.shadowed);
+
+        let meta = PackageState::load_meta(&PackageState::save_path(dir.path())).unwrap();
+        assert_eq!(meta.message, state.meta.message);
+        assert_eq!(meta.generation, Some(42));
+    }
+
+    #[test]
+    fn save_specialisations() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        let mut state = PackageState::new(packages(), None).unwrap();
+        state.save(dir.path()).unwrap();
+
+        // Not looking for specialisations is different from finding none
+        assert_eq!(
+            PackageState::load(dir.path()).unwrap().specialisations,
+            None
+        );
+
+        let specialisations = vec![
+            ("on-the-go".to_string(), synthetic_packages(20, 3)),
+            ("gaming".to_string(), HashSet::new()),
+        ]
+        .into_iter()
+        .collect::<Specialisations>();
+
+        state.specialisations = Some(specialisations.clone());
+        state.save(dir.path()).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        let loaded = loaded.specialisations.unwrap();
+
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), ["gaming", "on-the-go"]);
+        assert_eq!(
+            versions(&loaded["on-the-go"]),
+            versions(&specialisations["on-the-go"])
+        );
+        assert!(loaded["gaming"].is_empty());
+
+        // Version 9 had the same layout without the specialisations
+        let shard = bincode::serialize(&state.packages.iter().collect::<Vec<_>>()).unwrap();
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&9u32.to_le_bytes());
+        bytes.extend(
+            bincode::serialize(&(
+                &state.meta,
+                Vec::<Store>::new(),
+                None::<Rejects>,
+                vec![shard.len() as u64],
+            ))
+            .unwrap(),
+        );
+        bytes.extend(shard);
+
+        fs::write(&path, bytes).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.packages, state.packages);
+        assert_eq!(loaded.specialisations, None);
+    }
+
+    /// The layout of a `legacy::StateMetaV1`.
+    type StateMetaV1 = (u64, Option<&'static str>);
+
+    /// The layout of a `legacy::StoreV1`.
+    type StoreV1 = (u32, &'static str, &'static str, Option<String>, u32);
+
+    /// Returns the packages from `packages` encoded in the layout of a `legacy::DerivationV1`.
+    fn v1_packages() -> Vec<(StoreV1, Vec<StoreV1>)> {
+        vec![((0, "glxinfo", "8.4.0", None, 0), Vec::new())]
+    }
+
+    /// The layout of a `legacy::StoreV3`.
+    type StoreV3 = (
+        u32,
+        String,
+        String,
+        Option<String>,
+        u32,
+        Option<String>,
+        Option<bool>,
+    );
+
+    fn v3_store(store: &Store) -> StoreV3 {
+        (
+            store.id,
+            store.name.clone(),
+            store.version.clone(),
+            store.suffix.clone(),
+            store.register_time,
+            store.deriver.clone(),
+            store.locally_built,
+        )
+    }
+
+    /// Returns `packages` in the layout of a `legacy::DerivationV3`.
+    fn v3_packages(packages: &HashSet<Derivation>) -> Vec<(StoreV3, Vec<StoreV3>)> {
+        packages
+            .iter()
+            .map(|pkg| {
+                (
+                    v3_store(&pkg.store),
+                    pkg.deps.iter().map(v3_store).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn load_v1_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        let meta: StateMetaV1 = (1234, Some("v1"));
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend(bincode::serialize(&(&meta, v1_packages())).unwrap());
+
+        fs::write(&path, bytes).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.meta.message.as_deref(), Some("v1"));
+        assert_eq!(loaded.meta.saved_at, 1234);
+        assert_eq!(loaded.packages, packages());
+
+        let meta = PackageState::load_meta(&path).unwrap();
+        assert_eq!(meta.saved_at, 1234);
+        assert_eq!(meta.generation, None);
+    }
+
+    #[test]
+    fn load_v2_state() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let store = (
+            0u32,
+            "glxinfo",
+            "8.4.0",
+            None::<String>,
+            0u32,
+            Some("glxinfo.drv"),
+        );
+        let packages = vec![(store, Vec::<()>::new())];
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend(bincode::serialize(&((0u64, None::<&str>), packages)).unwrap());
+
+        fs::write(PackageState::save_path(dir.path()), bytes).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        let pkg = loaded.packages.get("glxinfo").unwrap();
+
+        assert_eq!(pkg.store.deriver.as_deref(), Some("glxinfo.drv"));
+        assert_eq!(pkg.store.locally_built, None);
+    }
+
+    #[test]
+    fn load_v3_state() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let packages = v3_packages(&packages());
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend(bincode::serialize(&((0u64, None::<&str>), packages)).unwrap());
+
+        fs::write(PackageState::save_path(dir.path()), bytes).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.packages, self::packages());
+        assert!(loaded.shadowed.is_empty());
+    }
+
+    #[test]
+    fn load_v4_state() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut state = PackageState::new(packages(), Some("unsharded".into())).unwrap();
+        state.shadowed = packages().into_iter().map(|pkg| pkg.store).collect();
+
+        let meta: StateMetaV1 = (state.meta.saved_at, Some("unsharded"));
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        let shadowed = state.shadowed.iter().map(v3_store).collect::<Vec<_>>();
+        bytes.extend(bincode::serialize(&(meta, v3_packages(&state.packages), shadowed)).unwrap());
+
+        fs::write(PackageState::save_path(dir.path()), bytes).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.meta.message, state.meta.message);
+        assert_eq!(loaded.packages, state.packages);
+        assert_eq!(loaded.shadowed, state.shadowed);
+    }
+
+    #[test]
+    fn load_v5_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        let mut state = PackageState::new(synthetic_packages(20, 3), None).unwrap();
+        state.shadowed = packages().into_iter().map(|pkg| pkg.store).collect();
+
+        let shard = bincode::serialize(&v3_packages(&state.packages)).unwrap();
+        let shadowed = state.shadowed.iter().map(v3_store).collect::<Vec<_>>();
+
+        let meta: StateMetaV1 = (state.meta.saved_at, Some("sharded"));
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&5u32.to_le_bytes());
+        bytes.extend(bincode::serialize(&(meta, shadowed, vec![shard.len() as u64])).unwrap());
+        bytes.extend(shard);
+
+        fs::write(&path, bytes).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.meta.message.as_deref(), Some("sharded"));
+        assert_eq!(loaded.meta.generation, None);
+        assert_eq!(versions(&loaded.packages), versions(&state.packages));
+        assert_eq!(loaded.shadowed, state.shadowed);
+
+        let meta = PackageState::load_meta(&path).unwrap();
+        assert_eq!(meta.message.as_deref(), Some("sharded"));
+    }
+
+    #[test]
+    fn load_v6_state() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let store = |name: &str, version: &str| -> StoreV3 {
+            (0, name.into(), version.into(), None, 0, None, Some(true))
+        };
+
+        let packages = vec![(
+            store("ocaml4.14.1-zarith", "1.13"),
+            vec![
+                store("ocaml4.14.1-stdlib-shims", "0.3.0"),
+                store("gmp", "6.3.0"),
+            ],
+        )];
+
+        let shard = bincode::serialize(&packages).unwrap();
+        let meta = (1234u64, Some("v6"), Some(7u32));
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&6u32.to_le_bytes());
+        bytes.extend(
+            bincode::serialize(&(meta, vec![store("ghc", "9.4.8")], vec![shard.len() as u64]))
+                .unwrap(),
+        );
+        bytes.extend(shard);
+
+        fs::write(PackageState::save_path(dir.path()), bytes).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.meta.generation, Some(7));
+
+        // Names from before ecosystems were recorded are normalized, so they still match newly parsed stores
+        let pkg = loaded.packages.get("zarith").unwrap();
+        assert_eq!(pkg.store.version, "1.13");
+        assert_eq!(pkg.store.locally_built, Some(true));
+
+        let ecosystem = pkg.store.ecosystem.as_ref().unwrap();
+        assert_eq!(
+            (ecosystem.name.as_str(), ecosystem.version.as_str()),
+            ("ocaml", "4.14.1")
+        );
+
+        assert!(pkg.deps.contains("stdlib-shims") && pkg.deps.contains("gmp"));
+        assert_eq!(pkg.deps.get("gmp").unwrap().ecosystem, None);
+
+        let ghc = &loaded.shadowed[0];
+        assert_eq!((ghc.name.as_str(), ghc.version.as_str()), ("ghc", "9.4.8"));
+        assert_eq!(ghc.ecosystem.as_ref().unwrap().name, "haskell");
+    }
+
+    #[test]
+    fn load_v7_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        let state = PackageState::new(packages(), None).unwrap();
+        let shard = bincode::serialize(&state.packages.iter().collect::<Vec<_>>()).unwrap();
+        let meta = (1234u64, Some("v7"), Some(7u32));
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.extend(
+            bincode::serialize(&(meta, Vec::<Store>::new(), vec![shard.len() as u64])).unwrap(),
+        );
+        bytes.extend(shard);
+
+        fs::write(&path, bytes).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.meta.message.as_deref(), Some("v7"));
+        assert_eq!(loaded.meta.generation, Some(7));
+        assert_eq!(loaded.meta.nixup_version, None);
+        assert_eq!(loaded.meta.parser, None);
+        assert_eq!(loaded.packages, state.packages);
+
+        let meta = PackageState::load_meta(&path).unwrap();
+        assert_eq!(meta.generation, Some(7));
+        assert_eq!(meta.parser, None);
+
+        // States saved now record the parser they were saved with
+        state.save(dir.path()).unwrap();
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.meta.parser, Some(ParserFingerprint::current()));
+        assert_eq!(
+            loaded.meta.nixup_version.as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn save_rejects() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        let mut state = PackageState::new(packages(), None).unwrap();
+        let shard = bincode::serialize(&state.packages.iter().collect::<Vec<_>>()).unwrap();
+
+        // A version 8 state has the same header without any rejects
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&8u32.to_le_bytes());
+        bytes.extend(
+            bincode::serialize(&(&state.meta, Vec::<Store>::new(), vec![shard.len() as u64]))
+                .unwrap(),
+        );
+        bytes.extend(shard);
+
+        fs::write(&path, bytes).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.packages, state.packages);
+        assert_eq!(loaded.rejects, None);
+
+        let unparsed = (0..5)
+            .map(|i| format!("/nix/store/{}-source", i))
+            .collect::<Vec<_>>();
+
+        let shadowed = vec![Store::parse_stripped("firefox-121.0").unwrap()];
+
+        state.rejects = Some(Rejects::new(unparsed, &shadowed, 3));
+        state.save(dir.path()).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        let rejects = loaded.rejects.unwrap();
+
+        assert_eq!(Some(&rejects), state.rejects.as_ref());
+        assert_eq!(rejects.unparsed.entries[2], "/nix/store/2-source");
+        assert_eq!(rejects.unparsed.overflow, 2);
+        assert_eq!(rejects.deduplicated.entries, ["firefox"]);
+
+        // Rejects don't get in the way of only reading the metadata
+        assert!(PackageState::load_meta(&path).unwrap().parser.is_some());
+    }
+
+    /// Returns `count` packages named `pkg-N` with `deps` dependencies each, drawn from a shared pool.
+    fn synthetic_packages(count: usize, deps: usize) -> HashSet<Derivation> {
+        let store = |id: usize, name: String| Store {
+            id: id as u32,
+            register_time: 1_700_000_000,
+            suffix: if id.is_multiple_of(5) {
+                Some("bin".into())
+            } else {
+                None
+            },
+            ..testing::store(&name, &format!("1.{}.0", id % 97))
+        };
+
+        (0..count)
+            .map(|i| Derivation {
+                store: store(i, format!("pkg-{}", i)),
+                deps: (0..deps)
+                    .map(|j| {
+                        let dep = (i * 31 + j * 7) % (count * 2);
+                        store(count + dep, format!("lib-{}", dep))
+                    })
+                    .collect(),
+                paths: HashMap::new(),
+            })
+            .collect()
+    }
+
+    /// Encodes `state` with `shards` shards as the full contents of a state file.
+    fn encode_file(state: &PackageState, shards: usize) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend(state.encode(shards).unwrap());
+        bytes
+    }
+
+    /// The name and version of a package, followed by the name and version of each of its dependencies.
+    type PackageVersions = (String, String, Vec<(String, String)>);
+
+    /// Returns every package and dependency of `packages` with its version, since derivations only compare names.
+    fn versions(packages: &HashSet<Derivation>) -> Vec<PackageVersions> {
+        let mut versions = packages
+            .iter()
+            .map(|pkg| {
+                let mut deps = pkg
+                    .deps
+                    .iter()
+                    .map(|dep| (dep.name.clone(), dep.version.clone()))
+                    .collect::<Vec<_>>();
+
+                deps.sort_unstable();
+                (pkg.store.name.clone(), pkg.store.version.clone(), deps)
+            })
+            .collect::<Vec<_>>();
+
+        versions.sort_unstable();
+        versions
+    }
+
+    #[test]
+    fn round_trip_shards() {
+        let path = Path::new("packages.bin");
+
+        let mut state =
+            PackageState::new(synthetic_packages(300, 12), Some("shards".into())).unwrap();
+        state.shadowed = synthetic_packages(3, 0)
+            .into_iter()
+            .map(|pkg| pkg.store)
+            .collect();
+
+        for &shards in &[1, 4, 16] {
+            let bytes = encode_file(&state, shards);
+            let loaded = PackageState::decode(&bytes, path).unwrap();
+
+            assert_eq!(loaded.meta.message, state.meta.message, "{} shards", shards);
+            assert_eq!(
+                versions(&loaded.packages),
+                versions(&state.packages),
+                "{} shards",
+                shards
+            );
+            assert_eq!(loaded.shadowed, state.shadowed, "{} shards", shards);
+
+            // The order packages and dependencies were inserted in never changes the encoding
+            let reversed = PackageState {
+                meta: StateMeta {
+                    saved_at: state.meta.saved_at,
+                    message: state.meta.message.clone(),
+                    generation: state.meta.generation,
+                    nixup_version: state.meta.nixup_version.clone(),
+                    parser: state.meta.parser.clone(),
+                },
+                packages: versions(&state.packages)
+                    .into_iter()
+                    .rev()
+                    .map(|(name, _, _)| state.packages.get(name.as_str()).unwrap().clone())
+                    .collect(),
+                shadowed: state.shadowed.clone(),
+                rejects: state.rejects.clone(),
+                specialisations: state.specialisations.clone(),
+            };
+
+            assert_eq!(encode_file(&reversed, shards), bytes, "{} shards", shards);
+        }
+
+        // Empty shards are still written, so a small state can have more shards than packages
+        let small = PackageState::new(packages(), None).unwrap();
+        let loaded = PackageState::decode(&encode_file(&small, 16), path).unwrap();
+        assert_eq!(loaded.packages, small.packages);
+    }
+
+    #[test]
+    fn encode_same_with_any_jobs() {
+        let path = Path::new("packages.bin");
+        let state = PackageState::new(synthetic_packages(300, 12), None).unwrap();
+
+        let encode_with = |jobs| {
+            let opts = ScanOptions {
+                jobs,
+                low_priority: false,
+            };
+
+            let pool = opts.thread_pool().unwrap();
+            let bytes = pool.install(|| encode_file(&state, SHARDS));
+            let loaded = pool.install(|| PackageState::decode(&bytes, path).unwrap());
+
+            (bytes, versions(&loaded.packages))
+        };
+
+        let (serial, serial_versions) = encode_with(1);
+        let (parallel, parallel_versions) = encode_with(8);
+
+        assert_eq!(serial, parallel);
+        assert_eq!(serial_versions, parallel_versions);
+        assert_eq!(serial_versions, versions(&state.packages));
+    }
+
+    #[test]
+    fn report_corrupt_shard() {
+        let state = PackageState::new(synthetic_packages(100, 4), None).unwrap();
+        let mut bytes = encode_file(&state, 4);
+
+        let mut rest = &bytes[MAGIC.len() + 4..];
+        let header = decode_prefix::<OwnedShardedHeader>(&mut rest).unwrap();
+        assert_eq!(header.shard_lens.len(), 4);
+
+        // Replace the package count at the start of the third shard with one that can't be real
+        let offset =
+            bytes.len() - rest.len() + (header.shard_lens[0] + header.shard_lens[1]) as usize;
+        bytes[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let err = PackageState::decode(&bytes, Path::new("packages.bin"))
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", err).contains("shard 3 of 4 is corrupt"),
+            "{:#}",
+            err
+        );
+
+        // Cutting off the last shard is reported before anything is decoded
+        let bytes = encode_file(&state, 4);
+        let err = PackageState::decode(&bytes[..bytes.len() - 1], Path::new("packages.bin"))
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", err).contains("shard 4 of 4 is"),
+            "{:#}",
+            err
+        );
+    }
+
+    /// Compares encoding and decoding a synthetic 5000 package state as a single shard and as the default
+    /// number of shards.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_shards`.
+    #[test]
+    #[ignore]
+    fn bench_shards() {
+        use std::time::Instant;
+
+        const RUNS: u32 = 10;
+
+        let state = PackageState::new(synthetic_packages(5000, 40), None).unwrap();
+        let path = Path::new("packages.bin");
+
+        for &shards in &[1, SHARDS] {
+            let start = Instant::now();
+
+            for _ in 0..RUNS {
+                encode_file(&state, shards);
+            }
+
+            let encode_time = start.elapsed() / RUNS;
+            let bytes = encode_file(&state, shards);
+            let start = Instant::now();
+
+            for _ in 0..RUNS {
+                PackageState::decode(&bytes, path).unwrap();
+            }
+
+            println!(
+                "{:>2} shards: encode {:?}, decode {:?}",
+                shards,
+                encode_time,
+                start.elapsed() / RUNS
+            );
+        }
+    }
+
+    #[test]
+    fn load_legacy_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        fs::write(&path, bincode::serialize(&v1_packages()).unwrap()).unwrap();
+
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.packages, packages());
+        assert_eq!(loaded.meta.message, None);
+        assert!(loaded.meta.saved_at > 0);
+
+        // Saving over a legacy state should keep it around as a snapshot
+        let state = PackageState::new(packages(), Some("migrated".into())).unwrap();
+        state.save(dir.path()).unwrap();
+
+        let snapshots = list_snapshots(dir.path()).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert!(snapshots[0].current);
+        assert_eq!(snapshots[0].meta.message.as_deref(), Some("migrated"));
+        assert_eq!(snapshots[1].meta.message, None);
+        assert_eq!(
+            PackageState::load_from(&snapshots[1].path)
+                .unwrap()
+                .packages,
+            packages()
+        );
+    }
+
+    #[test]
+    fn message_length_limit() {
+        let max = "a".repeat(MAX_MESSAGE_LEN);
+        assert!(PackageState::new(packages(), Some(max)).is_ok());
+
+        let too_long = "a".repeat(MAX_MESSAGE_LEN + 1);
+        assert!(PackageState::new(packages(), Some(too_long)).is_err());
+    }
+
+    /// Returns the full chain of an error from loading a state, which names the validation that failed.
+    fn load_error(path: &Path) -> String {
+        match PackageState::load_explicit(path) {
+            Ok(_) => panic!("{} loaded successfully", path.display()),
+            Err(err) => format!("{:#}", err),
+        }
+    }
+
+    #[test]
+    fn explicit_state_requires_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        PackageState::new(packages(), None)
+            .unwrap()
+            .save(dir.path())
+            .unwrap();
+
+        assert_eq!(
+            PackageState::load_explicit(&path).unwrap().packages,
+            packages()
+        );
+
+        // Headerless states are only trusted when they're in the data directory
+        fs::write(&path, bincode::serialize(&v1_packages()).unwrap()).unwrap();
+        assert!(PackageState::load_from(&path).is_ok());
+        assert!(load_error(&path).contains("doesn't start with the state file header"));
+
+        fs::write(&path, b"some other tool's cache").unwrap();
+        assert!(load_error(&path).contains("doesn't start with the state file header"));
+    }
+
+    #[test]
+    fn explicit_state_paths() {
+        let dir = tempfile::tempdir().unwrap();
+
+        PackageState::new(packages(), None)
+            .unwrap()
+            .save(dir.path())
+            .unwrap();
+
+        let link = dir.path().join("link.bin");
+        std::os::unix::fs::symlink(PackageState::save_path(dir.path()), &link).unwrap();
+        assert!(PackageState::load_explicit(&link).is_ok(), "symlink");
+
+        assert!(load_error(dir.path()).contains("is a directory"));
+        assert!(load_error(Path::new("/dev/null")).contains("is a special file"));
+        assert!(load_error(&dir.path().join("missing.bin")).contains("failed to resolve"));
+    }
+
+    #[test]
+    fn reject_out_of_bounds_states() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        let mut long_name = packages().into_iter().next().unwrap();
+        long_name.store.name = "a".repeat(MAX_STRING_LEN + 1);
+
+        let state = PackageState::new(iter::once(long_name).collect(), None).unwrap();
+        state.save(dir.path()).unwrap();
+        assert!(load_error(&path).contains("a package name is 4097 bytes long"));
+
+        let mut state = PackageState::new(packages(), None).unwrap();
+        state.meta.message = Some("a".repeat(MAX_MESSAGE_LEN + 1));
+        state.save(dir.path()).unwrap();
+        assert!(load_error(&path).contains("message is 201 characters long"));
+
+        assert!(check_count("packages", MAX_PACKAGES).is_ok());
+        assert!(check_count("packages", MAX_PACKAGES + 1).is_err());
+
+        // A count that doesn't match the contents fails before anything is allocated for it
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend(bincode::serialize(&StateMeta::default()).unwrap());
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        assert!(load_error(&path).contains("failed to decode"));
+    }
+
+    #[test]
+    fn keep_baseline_when_save_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        PackageState::new(packages(), Some("baseline".into()))
+            .unwrap()
+            .save(dir.path())
+            .unwrap();
+
+        let baseline = fs::read(&path).unwrap();
+        Acks::default().save(dir.path()).unwrap();
+
+        // A directory in the way of the temporary file makes the save fail before the baseline is touched
+        let temp = PackageState::temp_path(dir.path());
+        fs::create_dir(&temp).unwrap();
+
+        let state = PackageState::new(packages(), Some("interrupted".into())).unwrap();
+        assert!(state.save(dir.path()).is_err());
+
+        assert_eq!(fs::read(&path).unwrap(), baseline);
+        assert!(!PackageState::snapshot_dir(dir.path()).exists());
+        assert!(Acks::load(dir.path()).is_some());
+
+        fs::remove_dir(&temp).unwrap();
+        state.save(dir.path()).unwrap();
+
+        // The previous baseline is kept as a snapshot, and nothing is left behind
+        let loaded = PackageState::load(dir.path()).unwrap();
+        assert_eq!(loaded.meta.message.as_deref(), Some("interrupted"));
+        assert_eq!(list_snapshots(dir.path()).unwrap().len(), 2);
+        assert!(!temp.exists());
+
+        // Acknowledgments were of diffs against the old baseline
+        assert!(Acks::load(dir.path()).is_none());
+    }
+
+    #[test]
+    fn keep_snapshots_saved_in_same_second() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for message in &["first", "second", "third"] {
+            let mut state = PackageState::new(packages(), Some((*message).into())).unwrap();
+            state.meta.saved_at = 1234;
+            state.save(dir.path()).unwrap();
+        }
+
+        let mut messages = list_snapshots(dir.path())
+            .unwrap()
+            .into_iter()
+            .filter(|snapshot| !snapshot.current)
+            .map(|snapshot| snapshot.meta.message.unwrap())
+            .collect::<Vec<_>>();
+
+        messages.sort();
+        assert_eq!(messages, ["first", "second"]);
+
+        let snapshot_dir = PackageState::snapshot_dir(dir.path());
+        assert!(snapshot_dir.join("packages-1234.bin").exists());
+        assert!(snapshot_dir.join("packages-1234-1.bin").exists());
+    }
+
+    #[test]
+    fn promote_separately_from_baseline() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(PackageState::load_good(dir.path()).is_err());
+
+        PackageState::new(packages(), Some("baseline".into()))
+            .unwrap()
+            .save(dir.path())
+            .unwrap();
+
+        Acks::default().save(dir.path()).unwrap();
+
+        let good = PackageState::new(packages(), Some("known good".into())).unwrap();
+        good.promote(dir.path()).unwrap();
+
+        let loaded = PackageState::load_good(dir.path()).unwrap();
+        assert_eq!(loaded.meta.message.as_deref(), Some("known good"));
+        assert_eq!(loaded.packages, packages());
+
+        // Promoting doesn't touch the baseline, its snapshots, or what was acknowledged against it
+        let baseline = PackageState::load(dir.path()).unwrap();
+        assert_eq!(baseline.meta.message.as_deref(), Some("baseline"));
+        assert_eq!(list_snapshots(dir.path()).unwrap().len(), 1);
+        assert!(Acks::load(dir.path()).is_some());
+
+        // Promoting again replaces the known-good state without keeping the old one around
+        PackageState::new(packages(), Some("newer".into()))
+            .unwrap()
+            .promote(dir.path())
+            .unwrap();
+
+        let loaded = PackageState::load_good(dir.path()).unwrap();
+        assert_eq!(loaded.meta.message.as_deref(), Some("newer"));
+        assert_eq!(list_snapshots(dir.path()).unwrap().len(), 1);
+        assert!(!PackageState::temp_path(dir.path()).exists());
+    }
+
+    #[test]
+    fn reject_trailing_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        PackageState::new(packages(), None)
+            .unwrap()
+            .save(dir.path())
+            .unwrap();
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.extend_from_slice(b"junk");
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(load_error(&path).contains("4 unexpected trailing bytes"));
+    }
+
+    #[test]
+    fn fuzz_explicit_states() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PackageState::save_path(dir.path());
+
+        let mut state = PackageState::new(packages(), Some("fuzz".into())).unwrap();
+        state.shadowed = packages().into_iter().map(|pkg| pkg.store).collect();
+        state.save(dir.path()).unwrap();
+
+        let valid = fs::read(&path).unwrap();
+
+        // Every truncation of a valid state is missing something
+        for len in 0..valid.len() {
+            fs::write(&path, &valid[..len]).unwrap();
+            load_error(&path);
+        }
+
+        let mut rng = Rng::new(0x5eed_5eed_5eed_5eed);
+
+        for _ in 0..2000 {
+            let mut bytes = Vec::new();
+
+            // Most random files would fail on the header alone, so most should have one
+            if rng.below(4) > 0 {
+                bytes.extend_from_slice(MAGIC);
+                bytes.extend_from_slice(&(1 + rng.below(VERSION as u64) as u32).to_le_bytes());
+            }
+
+            for _ in 0..rng.below(256) {
+                bytes.push(rng.below(256) as u8);
+            }
+
+            fs::write(&path, &bytes).unwrap();
+            load_error(&path);
+        }
+    }
+
+    #[test]
+    fn sanitize_messages() {
+        let messages = [
+            ("before kernel bump", "before kernel bump"),
+            ("multi\nline\r\nmessage", "multi line  message"),
+            ("\x1b[31mred\x1b[0m", "?[31mred?[0m"),
+            ("bell\x07 and del\x7f", "bell? and del?"),
+            ("unicode → ok", "unicode → ok"),
+        ];
+
+        for &(message, expected) in &messages {
+            assert_eq!(sanitize_message(message), expected);
+        }
+    }
+}
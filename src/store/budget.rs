@@ -0,0 +1,72 @@
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+/// A phase of work that was cut short because its budget expired.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cutoff {
+    /// A short description of the phase, such as `scanning stores`.
+    pub phase: &'static str,
+    /// The number of stores the phase didn't get to.
+    pub remaining: usize,
+}
+
+/// A deadline that slow phases check between batches of work, so they can stop early and
+/// return what they have so far instead of running indefinitely.
+///
+/// Every phase that stops early records a `Cutoff`, so the results can be reported as partial.
+#[derive(Debug, Default)]
+pub struct Budget {
+    deadline: Option<Instant>,
+    cutoffs: RefCell<Vec<Cutoff>>,
+}
+
+impl Budget {
+    /// Creates a budget that never expires.
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// Creates a budget that expires after `timeout`, or never if it is `None`.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            cutoffs: RefCell::default(),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    /// Records that `phase` stopped early with `remaining` stores left to process.
+    pub fn cut_short(&self, phase: &'static str, remaining: usize) {
+        self.cutoffs.borrow_mut().push(Cutoff { phase, remaining });
+    }
+
+    /// Returns every phase that was cut short, in the order they happened.
+    pub fn cutoffs(&self) -> Vec<Cutoff> {
+        self.cutoffs.borrow().clone()
+    }
+
+    /// Returns true if `phase` was cut short.
+    pub fn was_cut_short(&self, phase: &str) -> bool {
+        self.cutoffs
+            .borrow()
+            .iter()
+            .any(|cutoff| cutoff.phase == phase)
+    }
+
+    /// Returns true if any phase was cut short.
+    pub fn is_partial(&self) -> bool {
+        !self.cutoffs.borrow().is_empty()
+    }
+
+    /// Makes the budget expire immediately.
+    #[cfg(test)]
+    pub fn expire(&mut self) {
+        self.deadline = Some(Instant::now());
+    }
+}
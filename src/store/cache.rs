@@ -0,0 +1,435 @@
+use super::database::SystemDatabase;
+use super::{Store, StoreKey};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// The binary cache that is checked when no other one is given.
+pub const DEFAULT_CACHE: &str = "https://cache.nixos.org";
+
+/// The number of seconds a single request to a binary cache can take by default.
+pub const DEFAULT_TIMEOUT: u64 = 5;
+
+/// The most requests that are made to a binary cache at once.
+pub const MAX_REQUESTS: usize = 8;
+
+/// Whether a binary cache has a store path.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CacheStatus {
+    /// The path can be downloaded, with the size of its compressed archive if the cache reported it.
+    Cached { file_size: Option<u64> },
+    /// The cache doesn't have the path, so it would have to be built.
+    NotCached,
+    /// The cache couldn't be reached, took too long to answer, or answered with something unexpected.
+    Unknown,
+}
+
+/// What a binary cache answered when asked for the narinfo of a path.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(not(feature = "binary-cache"), allow(dead_code))]
+enum Response {
+    Found(String),
+    NotFound,
+    Failed,
+}
+
+/// Whether a binary cache has each of a set of store paths.
+#[derive(Debug)]
+pub struct CacheReport {
+    /// The URL of the binary cache that was checked.
+    pub url: String,
+    statuses: HashMap<StoreKey, CacheStatus>,
+}
+
+/// How many store paths in a `CacheReport` can be downloaded and how many would have to be built.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheSummary {
+    pub cached: usize,
+    pub not_cached: usize,
+    pub unknown: usize,
+    /// The total size of every cached path whose size the cache reported, in bytes.
+    pub download_size: u64,
+}
+
+impl CacheReport {
+    /// Asks the binary cache at `url` whether it has each store at its path in `stores`, waiting at most
+    /// `timeout` for each answer.
+    ///
+    /// Up to `MAX_REQUESTS` requests are made at once. A request that fails for any reason makes the
+    /// store's status unknown, rather than failing the whole check.
+    pub fn check(url: &str, stores: Vec<(Store, String)>, timeout: Duration) -> Self {
+        Self::check_with(url, stores, MAX_REQUESTS, |narinfo| fetch(narinfo, timeout))
+    }
+
+    fn check_with<F>(url: &str, stores: Vec<(Store, String)>, concurrency: usize, fetch: F) -> Self
+    where
+        F: Fn(&str) -> Response + Sync,
+    {
+        let next = AtomicUsize::new(0);
+        let statuses = Mutex::new(HashMap::with_capacity(stores.len()));
+
+        let worker = || {
+            while let Some((store, path)) = stores.get(next.fetch_add(1, Ordering::Relaxed)) {
+                let status = match narinfo_url(url, path) {
+                    Some(narinfo) => status_of(path, fetch(&narinfo)),
+                    None => CacheStatus::Unknown,
+                };
+
+                statuses.lock().unwrap().insert(store.key(), status);
+            }
+        };
+
+        thread::scope(|scope| {
+            for _ in 0..concurrency.clamp(1, stores.len().max(1)) {
+                scope.spawn(worker);
+            }
+        });
+
+        Self {
+            url: url.into(),
+            statuses: statuses.into_inner().unwrap(),
+        }
+    }
+
+    /// Returns whether the cache has `store`, if it was checked.
+    pub fn status(&self, store: &Store) -> Option<CacheStatus> {
+        self.statuses.get(&store.key()).copied()
+    }
+
+    pub fn summary(&self) -> CacheSummary {
+        let mut summary = CacheSummary::default();
+
+        for status in self.statuses.values() {
+            match status {
+                CacheStatus::Cached { file_size } => {
+                    summary.cached += 1;
+                    summary.download_size += file_size.unwrap_or(0);
+                }
+                CacheStatus::NotCached => summary.not_cached += 1,
+                CacheStatus::Unknown => summary.unknown += 1,
+            }
+        }
+
+        summary
+    }
+}
+
+/// Returns the paths of the stores with the given `ids` in `db`, keyed by id.
+pub fn system_paths(db: &SystemDatabase, ids: &[i32]) -> Result<HashMap<u32, String>> {
+    let paths = db.paths(ids)?;
+    Ok(paths
+        .into_iter()
+        .map(|(id, path, _)| (id as u32, path))
+        .collect())
+}
+
+/// Returns the URL of the narinfo of the store at `path` in the binary cache at `cache`, which is named after
+/// the hash of the path.
+fn narinfo_url(cache: &str, path: &str) -> Option<String> {
+    let name = path.rsplit('/').next()?;
+    let hash = name.split('-').next()?;
+
+    if !Store::is_store_hash(hash.as_bytes()) {
+        return None;
+    }
+
+    Some(format!("{}/{}.narinfo", cache.trim_end_matches('/'), hash))
+}
+
+/// The fields of a narinfo that are needed to tell whether it describes a path, and how large it is.
+#[derive(Debug, PartialEq)]
+struct Narinfo<'a> {
+    store_path: &'a str,
+    file_size: Option<u64>,
+}
+
+/// Parses the `Key: value` lines of a narinfo, which must at least have a `StorePath`.
+fn parse_narinfo(text: &str) -> Option<Narinfo<'_>> {
+    let mut store_path = None;
+    let mut file_size = None;
+
+    for line in text.lines() {
+        match line.split_once(": ") {
+            Some(("StorePath", value)) => store_path = Some(value.trim()),
+            Some(("FileSize", value)) => file_size = value.trim().parse().ok(),
+            _ => (),
+        }
+    }
+
+    Some(Narinfo {
+        store_path: store_path?,
+        file_size,
+    })
+}
+
+fn status_of(path: &str, response: Response) -> CacheStatus {
+    match response {
+        Response::Found(text) => match parse_narinfo(&text) {
+            // A narinfo for another path means the hash collided or the cache is misbehaving
+            Some(narinfo) if narinfo.store_path == path => CacheStatus::Cached {
+                file_size: narinfo.file_size,
+            },
+            _ => CacheStatus::Unknown,
+        },
+        Response::NotFound => CacheStatus::NotCached,
+        Response::Failed => CacheStatus::Unknown,
+    }
+}
+
+/// Fetches the narinfo at `url` with `curl`, giving up after `timeout`.
+#[cfg(feature = "binary-cache")]
+fn fetch(url: &str, timeout: Duration) -> Response {
+    use std::process::Command;
+
+    let timeout = format!("{:.3}", timeout.as_secs_f64());
+
+    // The status code is written after the body, since HTTP errors don't make curl fail without --fail
+    let output = Command::new("curl")
+        .args([
+            "--silent",
+            "--location",
+            "--proto",
+            "=http,https,file",
+            "--max-time",
+            &timeout,
+            "--write-out",
+            "\n%{http_code}",
+            url,
+        ])
+        .output();
+
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        _ => return Response::Failed,
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    match stdout.rsplit_once('\n') {
+        Some((body, "200")) => Response::Found(body.into()),
+        // S3 answers with 403 rather than 404 for objects that don't exist
+        Some((_, "404")) | Some((_, "403")) => Response::NotFound,
+        _ => Response::Failed,
+    }
+}
+
+/// Without the `binary-cache` feature nothing is ever fetched, so every path is unknown.
+#[cfg(not(feature = "binary-cache"))]
+fn fetch(_url: &str, _timeout: Duration) -> Response {
+    Response::Failed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "zx6vs1b6xf07cprslk9is1fhwih21ix5";
+
+    fn entry(name: &str, hash: &str) -> (Store, String) {
+        let path = format!("/nix/store/{}-{}", hash, name);
+        (Store::parse(0, 0, &path).unwrap(), path)
+    }
+
+    fn narinfo(path: &str, file_size: Option<u64>) -> String {
+        let mut text = format!(
+            "StorePath: {}\nURL: nar/abc.nar.xz\nCompression: xz\n",
+            path
+        );
+
+        if let Some(size) = file_size {
+            text.push_str(&format!("FileSize: {}\nNarSize: {}\n", size, size * 3));
+        }
+
+        text
+    }
+
+    #[test]
+    fn parse_narinfos() {
+        let path = format!("/nix/store/{}-hello-2.12.1", HASH);
+
+        assert_eq!(
+            parse_narinfo(&narinfo(&path, Some(50_000))),
+            Some(Narinfo {
+                store_path: &path,
+                file_size: Some(50_000),
+            })
+        );
+
+        assert_eq!(
+            parse_narinfo(&narinfo(&path, None)).unwrap().file_size,
+            None
+        );
+        assert_eq!(parse_narinfo("FileSize: 12\n"), None);
+        assert_eq!(parse_narinfo("<html>not found</html>"), None);
+
+        assert_eq!(
+            status_of(&path, Response::Found(narinfo(&path, Some(12)))),
+            CacheStatus::Cached {
+                file_size: Some(12)
+            }
+        );
+
+        let other = format!("/nix/store/{}-other-1.0", HASH);
+        assert_eq!(
+            status_of(&path, Response::Found(narinfo(&other, None))),
+            CacheStatus::Unknown
+        );
+
+        assert_eq!(status_of(&path, Response::NotFound), CacheStatus::NotCached);
+        assert_eq!(status_of(&path, Response::Failed), CacheStatus::Unknown);
+    }
+
+    #[test]
+    fn build_narinfo_urls() {
+        let path = format!("/nix/store/{}-hello-2.12.1", HASH);
+
+        assert_eq!(
+            narinfo_url("https://cache.nixos.org/", &path).as_deref(),
+            Some(format!("https://cache.nixos.org/{}.narinfo", HASH).as_str())
+        );
+
+        assert_eq!(
+            narinfo_url(DEFAULT_CACHE, "/nix/store/short-hello-1.0"),
+            None
+        );
+    }
+
+    #[test]
+    fn check_with_bounded_requests() {
+        let stores = vec![
+            entry("hello-2.12.1", HASH),
+            entry("git-2.44.0", "0123456789abcdfghijklmnpqrsvwxyz"),
+            entry("vim-9.1", "1123456789abcdfghijklmnpqrsvwxyz"),
+            entry("nss-3.98", "2123456789abcdfghijklmnpqrsvwxyz"),
+            entry("zlib-1.3", "3123456789abcdfghijklmnpqrsvwxyz"),
+            entry("curl-8.6.0", "4123456789abcdfghijklmnpqrsvwxyz"),
+        ];
+
+        let in_flight = AtomicUsize::new(0);
+        let most_in_flight = AtomicUsize::new(0);
+
+        let report = CacheReport::check_with(DEFAULT_CACHE, stores.clone(), 2, |url| {
+            let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            most_in_flight.fetch_max(now, Ordering::SeqCst);
+
+            // Sleeping keeps requests in flight long enough to overlap
+            thread::sleep(Duration::from_millis(10));
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            let (_, path) = stores
+                .iter()
+                .find(|(_, path)| narinfo_url(DEFAULT_CACHE, path).as_deref() == Some(url))
+                .unwrap();
+
+            if path.ends_with("hello-2.12.1") || path.ends_with("git-2.44.0") {
+                Response::Found(narinfo(path, Some(1000)))
+            } else if path.ends_with("vim-9.1") {
+                Response::Found(narinfo(path, None))
+            } else if path.ends_with("nss-3.98") {
+                Response::Failed
+            } else {
+                Response::NotFound
+            }
+        });
+
+        assert!(most_in_flight.load(Ordering::SeqCst) <= 2);
+
+        assert_eq!(
+            report.status(&stores[0].0),
+            Some(CacheStatus::Cached {
+                file_size: Some(1000)
+            })
+        );
+
+        assert_eq!(report.status(&stores[4].0), Some(CacheStatus::NotCached));
+        assert_eq!(report.status(&stores[3].0), Some(CacheStatus::Unknown));
+
+        let other = Store::parse_stripped("firefox-124.0").unwrap();
+        assert_eq!(report.status(&other), None);
+
+        assert_eq!(
+            report.summary(),
+            CacheSummary {
+                cached: 3,
+                not_cached: 2,
+                unknown: 1,
+                download_size: 2000,
+            }
+        );
+    }
+
+    /// Serves narinfos for `found` and 404s for anything else over plain HTTP, answering `requests` requests.
+    #[cfg(feature = "binary-cache")]
+    fn serve(found: String, requests: usize) -> (String, thread::JoinHandle<()>) {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+
+                BufReader::new(&stream)
+                    .read_line(&mut request_line)
+                    .unwrap();
+
+                let response = if request_line.contains(&format!("/{}.narinfo ", HASH)) {
+                    let body = narinfo(&found, Some(4096));
+
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .into()
+                };
+
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (url, server)
+    }
+
+    #[test]
+    #[cfg(feature = "binary-cache")]
+    fn fetch_with_curl() {
+        use std::process::Command;
+
+        if Command::new("curl").arg("--version").output().is_err() {
+            eprintln!("skipping, as curl isn't installed");
+            return;
+        }
+
+        let stores = vec![
+            entry("hello-2.12.1", HASH),
+            entry("vim-9.1", "1123456789abcdfghijklmnpqrsvwxyz"),
+        ];
+
+        let (url, server) = serve(stores[0].1.clone(), stores.len());
+        let report = CacheReport::check(&url, stores.clone(), Duration::from_secs(5));
+
+        server.join().unwrap();
+
+        assert_eq!(
+            report.status(&stores[0].0),
+            Some(CacheStatus::Cached {
+                file_size: Some(4096)
+            })
+        );
+
+        assert_eq!(report.status(&stores[1].0), Some(CacheStatus::NotCached));
+
+        // Nothing is listening on port 1, so the request fails rather than reporting the path as missing
+        let unreachable = CacheReport::check("http://127.0.0.1:1", stores, Duration::from_secs(5));
+        assert_eq!(unreachable.summary().unknown, 2);
+    }
+}
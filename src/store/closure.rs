@@ -0,0 +1,246 @@
+use super::database::{RefEnd, SystemDatabase};
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+
+/// The default maximum number of paths a single closure can contain before walking it is aborted.
+pub const DEFAULT_MAX_NODES: usize = 100_000;
+
+/// SQLite limits the number of variables a single query can have, so queries that take a list of
+/// ids are split into chunks of this size.
+pub const QUERY_CHUNK_SIZE: usize = 900;
+
+/// Statistics about the references encountered while walking closures.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ClosureStats {
+    /// The number of paths that were walked, excluding the roots.
+    pub walked: usize,
+    /// The number of paths that referenced themselves.
+    pub self_refs: usize,
+    /// The number of references that pointed back to a path they were discovered from.
+    pub cycles: usize,
+}
+
+impl ClosureStats {
+    pub fn merge(&mut self, other: Self) {
+        self.walked += other.walked;
+        self.self_refs += other.self_refs;
+        self.cycles += other.cycles;
+    }
+}
+
+/// The paths referenced by a root path, either directly or transitively.
+#[derive(Debug)]
+pub struct Closure {
+    /// The ids of every path in the closure in the order they were discovered, excluding the root.
+    pub ids: Vec<i32>,
+    /// The path each path in the closure was first discovered from.
+    pub parents: HashMap<i32, i32>,
+    pub stats: ClosureStats,
+}
+
+impl Closure {
+    /// Walks the references of the path with the id of `root` breadth-first.
+    ///
+    /// Only references up to `max_depth` levels away from `root` are followed, where a depth of 1
+    /// only includes the paths `root` directly references. Every reference is followed when `max_depth` is `None`.
+    ///
+    /// Self-references and cycles are tolerated and recorded in the closure's stats.
+    /// An error is returned if the closure contains more than `max_nodes` paths.
+    pub fn walk(
+        db: &SystemDatabase,
+        root: i32,
+        max_depth: Option<usize>,
+        max_nodes: usize,
+    ) -> Result<Self> {
+        let mut closure = Self {
+            ids: Vec::new(),
+            parents: HashMap::new(),
+            stats: ClosureStats::default(),
+        };
+
+        let mut visited = HashSet::new();
+        visited.insert(root);
+
+        let mut frontier = vec![root];
+        let mut depth = 0;
+
+        while !frontier.is_empty() && depth < max_depth.unwrap_or(usize::MAX) {
+            depth += 1;
+
+            let mut next = Vec::new();
+
+            for (referrer, reference) in db.refs(&frontier, RefEnd::Referrer)? {
+                if referrer == reference {
+                    closure.stats.self_refs += 1;
+                    continue;
+                }
+
+                if !visited.insert(reference) {
+                    if closure.is_ancestor(reference, referrer) {
+                        closure.stats.cycles += 1;
+                    }
+
+                    continue;
+                }
+
+                if closure.ids.len() >= max_nodes {
+                    return Err(anyhow!(
+                        "closure of path {} is larger than {} paths",
+                        root,
+                        max_nodes
+                    ));
+                }
+
+                closure.parents.insert(reference, referrer);
+                closure.ids.push(reference);
+                next.push(reference);
+            }
+
+            frontier = next;
+        }
+
+        closure.stats.walked = closure.ids.len();
+        Ok(closure)
+    }
+
+    /// Returns the ids of the paths `id` was discovered through, from the root's direct reference
+    /// down to the parent of `id`.
+    pub fn path_to(&self, id: i32) -> Vec<i32> {
+        let mut path = Vec::new();
+        let mut id = id;
+
+        // The root is the only path without a parent, and isn't included
+        while let Some(&parent) = self.parents.get(&id) {
+            if !self.parents.contains_key(&parent) {
+                break;
+            }
+
+            path.push(parent);
+            id = parent;
+        }
+
+        path.reverse();
+        path
+    }
+
+    /// Returns true if `ancestor` is `id` or is a path `id` was discovered from.
+    fn is_ancestor(&self, ancestor: i32, mut id: i32) -> bool {
+        loop {
+            if id == ancestor {
+                return true;
+            }
+
+            match self.parents.get(&id) {
+                Some(&parent) => id = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::database::fixture;
+
+    fn db_with_paths(num: i32) -> SystemDatabase {
+        let db = fixture::empty();
+
+        for id in 1..=num {
+            fixture::add_path(&db, id, &format!("path{}-1.0", id), 0);
+        }
+
+        db
+    }
+
+    #[test]
+    fn walk_self_reference() {
+        let db = db_with_paths(3);
+        fixture::add_ref(&db, 1, 1);
+        fixture::add_ref(&db, 1, 2);
+        fixture::add_ref(&db, 2, 2);
+        fixture::add_ref(&db, 2, 3);
+
+        let closure = Closure::walk(&db, 1, None, DEFAULT_MAX_NODES).unwrap();
+        assert_eq!(closure.ids, vec![2, 3]);
+        assert_eq!(closure.stats.self_refs, 2);
+        assert_eq!(closure.stats.cycles, 0);
+    }
+
+    #[test]
+    fn walk_cycle() {
+        let db = db_with_paths(4);
+        fixture::add_ref(&db, 1, 2);
+        fixture::add_ref(&db, 2, 3);
+        fixture::add_ref(&db, 3, 2);
+        fixture::add_ref(&db, 3, 4);
+
+        let closure = Closure::walk(&db, 1, None, DEFAULT_MAX_NODES).unwrap();
+        assert_eq!(closure.ids, vec![2, 3, 4]);
+        assert_eq!(closure.parents[&4], 3);
+        assert_eq!(closure.stats.cycles, 1);
+
+        // A reference back to the root is also a cycle
+        fixture::add_ref(&db, 4, 1);
+
+        let closure = Closure::walk(&db, 1, None, DEFAULT_MAX_NODES).unwrap();
+        assert_eq!(closure.ids, vec![2, 3, 4]);
+        assert_eq!(closure.stats.cycles, 2);
+    }
+
+    #[test]
+    fn path_to_reference() {
+        let db = db_with_paths(5);
+        fixture::add_ref(&db, 1, 2);
+        fixture::add_ref(&db, 2, 3);
+        fixture::add_ref(&db, 3, 4);
+        fixture::add_ref(&db, 1, 5);
+
+        let closure = Closure::walk(&db, 1, None, DEFAULT_MAX_NODES).unwrap();
+        assert_eq!(closure.path_to(2), Vec::<i32>::new());
+        assert_eq!(closure.path_to(5), Vec::<i32>::new());
+        assert_eq!(closure.path_to(4), vec![2, 3]);
+    }
+
+    #[test]
+    fn walk_shared_reference_is_not_cycle() {
+        let db = db_with_paths(4);
+        fixture::add_ref(&db, 1, 2);
+        fixture::add_ref(&db, 1, 3);
+        fixture::add_ref(&db, 2, 4);
+        fixture::add_ref(&db, 3, 4);
+
+        let closure = Closure::walk(&db, 1, None, DEFAULT_MAX_NODES).unwrap();
+        assert_eq!(closure.ids, vec![2, 3, 4]);
+        assert_eq!(closure.stats.cycles, 0);
+    }
+
+    #[test]
+    fn walk_depth_limit() {
+        let db = db_with_paths(4);
+        fixture::add_ref(&db, 1, 2);
+        fixture::add_ref(&db, 2, 3);
+        fixture::add_ref(&db, 3, 4);
+
+        let ids = |depth| Closure::walk(&db, 1, depth, DEFAULT_MAX_NODES).unwrap().ids;
+
+        assert_eq!(ids(Some(0)), Vec::<i32>::new());
+        assert_eq!(ids(Some(1)), vec![2]);
+        assert_eq!(ids(Some(2)), vec![2, 3]);
+        assert_eq!(ids(None), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn walk_node_limit() {
+        let db = db_with_paths(5);
+
+        for id in 1..5 {
+            fixture::add_ref(&db, id, id + 1);
+        }
+
+        assert!(Closure::walk(&db, 1, None, 4).is_ok());
+
+        let err = Closure::walk(&db, 1, None, 3).unwrap_err();
+        assert!(err.to_string().contains("larger than 3 paths"), "{}", err);
+    }
+}
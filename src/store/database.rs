@@ -1,6 +1,13 @@
+use super::closure::QUERY_CHUNK_SIZE;
 use anyhow::{anyhow, Context, Result};
+use diesel::dsl;
+use diesel::expression::SqlLiteral;
 use diesel::prelude::*;
+use diesel::sql_types::{Integer, Nullable};
+use std::fs;
+use std::path::Path;
 
+#[allow(non_local_definitions)]
 pub mod schema {
     table! {
         #[allow(non_snake_case)]
@@ -28,17 +35,82 @@ pub mod schema {
     allow_tables_to_appear_in_same_query!(Refs, ValidPaths);
 }
 
-pub struct SystemDatabase(SqliteConnection);
+/// The tables and columns nixup can't work without, which every Nix release that stores its database in
+/// SQLite has had.
+const REQUIRED_COLUMNS: [(&str, &[&str]); 2] = [
+    ("ValidPaths", &["id", "path", "registrationTime", "deriver"]),
+    ("Refs", &["referrer", "reference"]),
+];
+
+/// The optional columns of `ValidPaths` that the database has.
+///
+/// `schema::ValidPaths` describes the newest layout, so queries must only use these columns when they exist.
+/// Newer Nix releases added them over time, so older databases lack some of them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Columns {
+    /// Whether a path was built locally.
+    pub ultimate: bool,
+    /// The content address of a path, which only fixed-output and content-addressed paths have.
+    pub ca: bool,
+}
+
+#[allow(non_local_definitions)]
+mod pragma {
+    use diesel::sql_types::Text;
+
+    /// A row of `PRAGMA table_info`, which describes a single column of a table.
+    #[derive(QueryableByName)]
+    pub struct ColumnInfo {
+        #[sql_type = "Text"]
+        pub name: String,
+    }
+}
+
+pub struct SystemDatabase {
+    conn: SqliteConnection,
+    /// How many rows queries over every path read at a time.
+    batch_size: usize,
+    columns: Columns,
+}
+
+/// How the Nix database was opened.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OpenMode {
+    /// Read-only and immutable, which takes no locks and sees the database as it was when opened.
+    Immutable,
+    /// A plain connection as root, which can read the database while Nix is in the middle of writing to it.
+    Mutable,
+}
+
+/// Which end of a reference paths are looked up by.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RefEnd {
+    /// The path holding the reference.
+    Referrer,
+    /// The path being referenced.
+    Reference,
+}
 
 impl SystemDatabase {
     pub const PATH: &'static str = "/nix/var/nix/db/db.sqlite";
 
-    pub fn open() -> Result<Self> {
+    /// The directory Nix keeps a file of temporary GC roots in for every process using the store, named by its pid.
+    pub const TEMP_ROOTS_DIR: &'static str = "/nix/var/nix/temproots";
+
+    /// The number of rows read at a time by queries over every path, unless another is set.
+    pub const DEFAULT_BATCH_SIZE: usize = 1024;
+
+    /// Opens the Nix database, preferring an immutable connection and falling back to a mutable one as root.
+    ///
+    /// Any database with the columns in `REQUIRED_COLUMNS` is supported, which covers every Nix release that
+    /// stores its database in SQLite. Columns that were added later, such as `ultimate` and `ca`, are only used
+    /// when they exist. Opening a database without the required columns fails.
+    pub fn open() -> Result<(Self, OpenMode)> {
         let immutable_conn = format!("file:{}?mode=ro&immutable=1", Self::PATH);
 
         // TODO: only try opening immutably if/when https://github.com/diesel-rs/diesel/pull/1292 is merged
         match SqliteConnection::establish(&immutable_conn) {
-            Ok(conn) => Ok(Self(conn)),
+            Ok(conn) => Ok((Self::new(conn)?, OpenMode::Immutable)),
             Err(_) => {
                 if !is_root_user() {
                     return Err(anyhow!("must run program as root to access the Nix database\nto avoid needing root access, compile SQLite with SQLITE_USE_URI=1"));
@@ -47,17 +119,341 @@ impl SystemDatabase {
                 let conn = SqliteConnection::establish(Self::PATH)
                     .context("failed to establish SQLite connection to nix database")?;
 
-                Ok(Self(conn))
+                Ok((Self::new(conn)?, OpenMode::Mutable))
             }
         }
     }
 
+    fn new(conn: SqliteConnection) -> Result<Self> {
+        let columns = probe_columns(&conn).context("unsupported nix database")?;
+
+        Ok(Self {
+            conn,
+            batch_size: Self::DEFAULT_BATCH_SIZE,
+            columns,
+        })
+    }
+
+    /// Returns true if a running process is using the Nix store, such as during a rebuild.
+    pub fn in_use() -> bool {
+        has_live_temp_roots(Path::new(Self::TEMP_ROOTS_DIR))
+    }
+
     #[inline(always)]
     pub fn conn(&self) -> &SqliteConnection {
-        &self.0
+        &self.conn
+    }
+
+    /// Returns how many rows queries over every path read at a time.
+    ///
+    /// Smaller batches use less memory, at the cost of more queries.
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Sets how many rows queries over every path read at a time, which must be at least 1.
+    pub fn set_batch_size(&mut self, rows: usize) {
+        self.batch_size = rows.max(1);
+    }
+
+    pub fn columns(&self) -> Columns {
+        self.columns
+    }
+
+    /// Returns every (referrer, reference) pair whose `end` is one of `ids`, sorted so walks are deterministic.
+    pub fn refs(&self, ids: &[i32], end: RefEnd) -> Result<Vec<(i32, i32)>> {
+        use schema::Refs::dsl::*;
+
+        let mut refs = Vec::new();
+
+        for chunk in ids.chunks(QUERY_CHUNK_SIZE) {
+            let query = match end {
+                RefEnd::Referrer => Refs.filter(referrer.eq_any(chunk)).into_boxed(),
+                RefEnd::Reference => Refs.filter(reference.eq_any(chunk)).into_boxed(),
+            };
+
+            let chunk_refs = query
+                .select((referrer, reference))
+                .get_results::<(i32, i32)>(self.conn())
+                .context("failed to look up references in nix database")?;
+
+            refs.extend(chunk_refs);
+        }
+
+        refs.sort_unstable();
+        Ok(refs)
+    }
+
+    /// Returns the id, path, and registration time of every path in `ids`, leaving out ids that aren't valid paths.
+    pub fn paths(&self, ids: &[i32]) -> Result<Vec<(i32, String, i32)>> {
+        use schema::ValidPaths::dsl::*;
+
+        let mut paths = Vec::with_capacity(ids.len());
+
+        for chunk in ids.chunks(QUERY_CHUNK_SIZE) {
+            let rows = ValidPaths
+                .filter(id.eq_any(chunk))
+                .select((id, path, registrationTime))
+                .get_results::<(i32, String, i32)>(self.conn())
+                .context("failed to look up paths in nix database")?;
+
+            paths.extend(rows);
+        }
+
+        Ok(paths)
+    }
+
+    /// Returns the `ultimate` column to select, which is always `NULL` for databases without it.
+    pub fn ultimate(&self) -> SqlLiteral<Nullable<Integer>> {
+        dsl::sql(if self.columns.ultimate {
+            "ultimate"
+        } else {
+            "NULL"
+        })
     }
 }
 
+/// Returns the names of every column of `table`, which is empty if the table doesn't exist.
+fn table_columns(conn: &SqliteConnection, table: &str) -> Result<Vec<String>> {
+    // Table names can't be bound as parameters, so only our own names are ever used here
+    let rows = diesel::sql_query(format!("PRAGMA table_info({})", table))
+        .load::<pragma::ColumnInfo>(conn)
+        .with_context(|| anyhow!("failed to read the columns of {}", table))?;
+
+    Ok(rows.into_iter().map(|row| row.name).collect())
+}
+
+/// Checks that the database behind `conn` has every required column, and returns which optional ones it has.
+fn probe_columns(conn: &SqliteConnection) -> Result<Columns> {
+    let mut valid_paths = Vec::new();
+
+    for (table, required) in &REQUIRED_COLUMNS {
+        let columns = table_columns(conn, table)?;
+
+        if columns.is_empty() {
+            return Err(anyhow!(
+                "the {} table is missing, so this is not a Nix database",
+                table
+            ));
+        }
+
+        if let Some(missing) = required
+            .iter()
+            .find(|&&name| !columns.iter().any(|col| col == name))
+        {
+            return Err(anyhow!(
+                "the {} table has no {} column, so the database layout is from a version of Nix that isn't supported",
+                table,
+                missing
+            ));
+        }
+
+        if *table == "ValidPaths" {
+            valid_paths = columns;
+        }
+    }
+
+    let has = |name: &str| valid_paths.iter().any(|col| col == name);
+
+    Ok(Columns {
+        ultimate: has("ultimate"),
+        ca: has("ca"),
+    })
+}
+
 fn is_root_user() -> bool {
     unsafe { libc::getuid() == 0 }
 }
+
+/// Returns true if any file in `dir` is named after the pid of a running process.
+///
+/// Nix only removes stale temporary roots during garbage collection, so files of exited processes are ignored.
+fn has_live_temp_roots(dir: &Path) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+
+    entries.filter_map(|entry| entry.ok()).any(|entry| {
+        let name = entry.file_name();
+
+        match name.to_str().map(str::parse::<u32>) {
+            Some(Ok(pid)) => Path::new("/proc").join(pid.to_string()).exists(),
+            _ => false,
+        }
+    })
+}
+
+/// Helpers for building in-memory Nix databases in tests.
+#[cfg(test)]
+pub mod fixture {
+    use super::*;
+    use diesel::connection::SimpleConnection;
+
+    const SCHEMA: &str = "
+        CREATE TABLE ValidPaths (
+            id               INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            path             TEXT UNIQUE NOT NULL,
+            hash             TEXT NOT NULL,
+            registrationTime INTEGER NOT NULL,
+            deriver          TEXT,
+            narSize          INTEGER,
+            ultimate         INTEGER,
+            sigs             TEXT,
+            ca               TEXT
+        );
+
+        CREATE TABLE Refs (
+            referrer  INTEGER NOT NULL,
+            reference INTEGER NOT NULL,
+            PRIMARY KEY (referrer, reference)
+        );
+    ";
+
+    /// The schema of databases from before the `ultimate`, `sigs`, and `ca` columns were added.
+    const LEGACY_SCHEMA: &str = "
+        CREATE TABLE ValidPaths (
+            id               INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            path             TEXT UNIQUE NOT NULL,
+            hash             TEXT NOT NULL,
+            registrationTime INTEGER NOT NULL,
+            deriver          TEXT,
+            narSize          INTEGER
+        );
+
+        CREATE TABLE Refs (
+            referrer  INTEGER NOT NULL,
+            reference INTEGER NOT NULL,
+            PRIMARY KEY (referrer, reference)
+        );
+    ";
+
+    /// Creates an empty database with the same schema as the Nix database.
+    pub fn empty() -> SystemDatabase {
+        with_schema(SCHEMA).unwrap()
+    }
+
+    /// Creates an empty database with the schema of Nix releases that predate the `ultimate`, `sigs`, and `ca` columns.
+    pub fn legacy() -> SystemDatabase {
+        with_schema(LEGACY_SCHEMA).unwrap()
+    }
+
+    /// Creates an empty database by running `schema`, failing if it isn't supported.
+    pub fn with_schema(schema: &str) -> Result<SystemDatabase> {
+        let conn = SqliteConnection::establish(":memory:").unwrap();
+        conn.batch_execute(schema).unwrap();
+        SystemDatabase::new(conn)
+    }
+
+    /// Adds a path with the given `id` to `db`.
+    /// `name` is the part of the path that comes after the store hash.
+    pub fn add_path(db: &SystemDatabase, id: i32, name: &str, register_time: i32) {
+        let sql = format!(
+            "INSERT INTO ValidPaths (id, path, hash, registrationTime) VALUES ({}, '/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-{}', 'sha256:0', {});",
+            id, name, register_time
+        );
+
+        db.conn().batch_execute(&sql).unwrap();
+    }
+
+    /// Sets the `ultimate` column of the path with the given `id`, where `None` is `NULL`.
+    pub fn set_ultimate(db: &SystemDatabase, id: i32, ultimate: Option<i32>) {
+        let value = ultimate.map_or_else(|| "NULL".into(), |ultimate| ultimate.to_string());
+        let sql = format!(
+            "UPDATE ValidPaths SET ultimate = {} WHERE id = {};",
+            value, id
+        );
+
+        db.conn().batch_execute(&sql).unwrap();
+    }
+
+    /// Removes the path with the given `id` from `db`, as if it was garbage collected.
+    pub fn remove_path(db: &SystemDatabase, id: i32) {
+        let sql = format!("DELETE FROM ValidPaths WHERE id = {};", id);
+        db.conn().batch_execute(&sql).unwrap();
+    }
+
+    /// Makes the path with the id of `referrer` reference the path with the id of `reference`.
+    pub fn add_ref(db: &SystemDatabase, referrer: i32, reference: i32) {
+        let sql = format!(
+            "INSERT INTO Refs (referrer, reference) VALUES ({}, {});",
+            referrer, reference
+        );
+
+        db.conn().batch_execute(&sql).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn probe_database_columns() {
+        let columns = |db: SystemDatabase| db.columns();
+
+        assert_eq!(
+            columns(fixture::empty()),
+            Columns {
+                ultimate: true,
+                ca: true
+            }
+        );
+        assert_eq!(
+            columns(fixture::legacy()),
+            Columns {
+                ultimate: false,
+                ca: false
+            }
+        );
+
+        let err = |schema| format!("{:#}", fixture::with_schema(schema).err().unwrap());
+
+        let no_refs = "CREATE TABLE ValidPaths (id INTEGER, path TEXT, registrationTime INTEGER, deriver TEXT);";
+        assert!(
+            err(no_refs).contains("Refs table is missing"),
+            "{}",
+            err(no_refs)
+        );
+
+        let no_deriver =
+            "CREATE TABLE ValidPaths (id INTEGER, path TEXT, registrationTime INTEGER);
+            CREATE TABLE Refs (referrer INTEGER, reference INTEGER);";
+        assert!(
+            err(no_deriver).contains("ValidPaths table has no deriver column"),
+            "{}",
+            err(no_deriver)
+        );
+        assert!(err("").starts_with("unsupported nix database"));
+    }
+
+    #[test]
+    fn look_up_refs_by_either_end() {
+        let db = fixture::empty();
+
+        for id in 1..=3 {
+            fixture::add_path(&db, id, &format!("path{}-1.0", id), 0);
+        }
+
+        fixture::add_ref(&db, 2, 3);
+        fixture::add_ref(&db, 1, 3);
+        fixture::add_ref(&db, 1, 2);
+
+        assert_eq!(db.refs(&[1], RefEnd::Referrer).unwrap(), [(1, 2), (1, 3)]);
+        assert_eq!(db.refs(&[3], RefEnd::Reference).unwrap(), [(1, 3), (2, 3)]);
+        assert!(db.refs(&[], RefEnd::Referrer).unwrap().is_empty());
+    }
+
+    #[test]
+    fn detect_live_temp_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!has_live_temp_roots(dir.path()), "empty");
+        assert!(!has_live_temp_roots(&dir.path().join("missing")), "missing");
+
+        fs::write(dir.path().join("gc-socket"), "").unwrap();
+        assert!(!has_live_temp_roots(dir.path()), "not a pid");
+
+        fs::write(dir.path().join(std::process::id().to_string()), "").unwrap();
+        assert!(has_live_temp_roots(dir.path()), "own pid");
+    }
+}
@@ -0,0 +1,390 @@
+use super::version::Version;
+use super::Store;
+use anyhow::{anyhow, Error, Result};
+use serde_derive::Deserialize;
+use smallvec::SmallVec;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+/// Every store sharing a single name.
+pub type Bucket = SmallVec<[Store; 2]>;
+
+/// What to do with a name that has differing versions registered within the duplicate window of its newest store.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DedupPolicy {
+    /// Leave the name out entirely, since there's no way to tell which version is the one in use.
+    #[default]
+    Drop,
+    /// Keep the most recently registered version.
+    KeepNewest,
+    /// Keep the newest store of every conflicting version, with its version appended to its name
+    /// after an `@`, such as `nss@3.96`.
+    KeepAllTagged,
+    /// Keep only the highest version of every name, no matter when each version was registered.
+    ///
+    /// Unlike the other policies, this applies to every name with more than one store rather than only those
+    /// in conflict, so an older version that is still installed alongside a newer one is always left out.
+    HighestVersion,
+}
+
+impl FromStr for DedupPolicy {
+    type Err = Error;
+
+    /// Parses the same names as `duplicate_policy` in the config file, along with `newest` as a shorthand for
+    /// `keep-newest`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop" => Ok(Self::Drop),
+            "newest" | "keep-newest" => Ok(Self::KeepNewest),
+            "keep-all-tagged" => Ok(Self::KeepAllTagged),
+            "highest-version" => Ok(Self::HighestVersion),
+            _ => Err(anyhow!(
+                "unknown duplicate policy \"{}\", expected drop, newest, keep-all-tagged, or highest-version",
+                value
+            )),
+        }
+    }
+}
+
+/// The result of deduplicating stores.
+#[derive(Debug, Default)]
+pub struct Resolved {
+    /// The stores that were kept, with at most one per name.
+    pub unique: HashSet<Store>,
+    /// Every store that was left out of `unique`, including the originals of tagged stores.
+    pub shadowed: Vec<Store>,
+}
+
+/// Groups `stores` by their name.
+pub fn group_by_name(stores: impl Iterator<Item = Store>) -> HashMap<String, Bucket> {
+    let mut buckets = HashMap::<String, Bucket>::new();
+
+    for store in stores {
+        add_to_bucket(&mut buckets, store);
+    }
+
+    buckets
+}
+
+/// Adds `store` to the bucket of its name in `buckets`, which lets stores be grouped as they're read.
+pub fn add_to_bucket(buckets: &mut HashMap<String, Bucket>, store: Store) {
+    match buckets.get_mut(&store.name) {
+        Some(bucket) => bucket.push(store),
+        None => {
+            buckets.insert(store.name.clone(), smallvec::smallvec![store]);
+        }
+    }
+}
+
+/// Groups `stores` by name and resolves each bucket with `policy`.
+///
+/// The result does not depend on the order of `stores`.
+pub fn dedup(stores: impl Iterator<Item = Store>, window: u32, policy: DedupPolicy) -> Resolved {
+    resolve_buckets(group_by_name(stores), window, policy)
+}
+
+/// Resolves every bucket of `buckets` with `policy`.
+pub fn resolve_buckets(
+    buckets: HashMap<String, Bucket>,
+    window: u32,
+    policy: DedupPolicy,
+) -> Resolved {
+    let mut resolved = Resolved::default();
+
+    for bucket in buckets.into_values() {
+        resolve(bucket, window, policy, &mut resolved);
+    }
+
+    resolved
+}
+
+/// Resolves a `bucket` of stores sharing the same name into `resolved`.
+///
+/// The newest store of the bucket is the one compared against, where ties in registration time are
+/// broken by the higher id. A bucket is only in conflict when another store within `window` of the
+/// newest one has a different version, as described by `Store::are_duplicates`:
+///
+/// * A single store, or a bucket without a conflict, keeps only its newest store.
+/// * A bucket in conflict is resolved with `policy`.
+///
+/// Stores outside of the window are older versions from a previous update, and are always shadowed,
+/// except with `DedupPolicy::HighestVersion`, which ignores the window and keeps the highest version of every bucket.
+pub fn resolve(mut bucket: Bucket, window: u32, policy: DedupPolicy, resolved: &mut Resolved) {
+    bucket.sort_unstable_by(|x, y| {
+        y.register_time
+            .cmp(&x.register_time)
+            .then_with(|| y.id.cmp(&x.id))
+            .then_with(|| y.version.cmp(&x.version))
+    });
+
+    let newest = &bucket[0];
+
+    let conflicts = bucket[1..]
+        .iter()
+        .any(|other| Store::are_duplicates(newest, other, window));
+
+    if !conflicts && policy != DedupPolicy::HighestVersion {
+        let mut bucket = bucket.into_iter();
+        resolved.unique.extend(bucket.next());
+        resolved.shadowed.extend(bucket);
+        return;
+    }
+
+    match policy {
+        DedupPolicy::Drop => resolved.shadowed.extend(bucket),
+        DedupPolicy::KeepNewest => {
+            let mut bucket = bucket.into_iter();
+            resolved.unique.extend(bucket.next());
+            resolved.shadowed.extend(bucket);
+        }
+        DedupPolicy::KeepAllTagged => {
+            let newest_time = newest.register_time;
+            let mut versions = HashSet::new();
+
+            for store in &bucket {
+                if newest_time - store.register_time >= window
+                    || !versions.insert(store.version.as_str())
+                {
+                    continue;
+                }
+
+                let mut tagged = store.clone();
+                tagged.name = format!("{}@{}", store.name, store.version);
+                resolved.unique.insert(tagged);
+            }
+
+            resolved.shadowed.extend(bucket);
+        }
+        DedupPolicy::HighestVersion => {
+            // The bucket is sorted from the newest store, and `max_by` returns the last of equal elements,
+            // so iterating in reverse keeps the newest store of the highest version
+            let highest = (0..bucket.len())
+                .rev()
+                .max_by(|&x, &y| {
+                    Version::parse(&bucket[x].version).cmp(&Version::parse(&bucket[y].version))
+                })
+                .unwrap_or(0);
+
+            resolved.unique.insert(bucket.remove(highest));
+            resolved.shadowed.extend(bucket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{self, Rng};
+
+    fn store(id: u32, name: &str, version: &str, register_time: u32) -> Store {
+        Store {
+            id,
+            register_time,
+            ..testing::store(name, version)
+        }
+    }
+
+    fn summarize(resolved: Resolved) -> (Vec<(String, u32)>, Vec<u32>) {
+        let mut unique = resolved
+            .unique
+            .into_iter()
+            .map(|store| (store.name, store.id))
+            .collect::<Vec<_>>();
+
+        let mut shadowed = resolved
+            .shadowed
+            .into_iter()
+            .map(|store| store.id)
+            .collect::<Vec<_>>();
+
+        unique.sort_unstable();
+        shadowed.sort_unstable();
+        (unique, shadowed)
+    }
+
+    fn run(stores: &[Store], policy: DedupPolicy) -> (Vec<(String, u32)>, Vec<u32>) {
+        summarize(dedup(
+            stores.iter().cloned(),
+            Store::DUPLICATE_WINDOW,
+            policy,
+        ))
+    }
+
+    const POLICIES: [DedupPolicy; 4] = [
+        DedupPolicy::Drop,
+        DedupPolicy::KeepNewest,
+        DedupPolicy::KeepAllTagged,
+        DedupPolicy::HighestVersion,
+    ];
+
+    #[test]
+    fn dedup_three_versions() {
+        // The oldest version is from a previous update, while the two newest are from the same one
+        let stores = [
+            store(1, "nss", "3.95", 100),
+            store(2, "nss", "3.96", 10_000),
+            store(3, "nss", "3.97", 10_010),
+            store(4, "firefox", "123.0", 10_000),
+        ];
+
+        assert_eq!(
+            run(&stores, DedupPolicy::Drop),
+            (vec![("firefox".into(), 4)], vec![1, 2, 3])
+        );
+
+        assert_eq!(
+            run(&stores, DedupPolicy::KeepNewest),
+            (vec![("firefox".into(), 4), ("nss".into(), 3)], vec![1, 2])
+        );
+
+        assert_eq!(
+            run(&stores, DedupPolicy::KeepAllTagged),
+            (
+                vec![
+                    ("firefox".into(), 4),
+                    ("nss@3.96".into(), 2),
+                    ("nss@3.97".into(), 3)
+                ],
+                vec![1, 2, 3]
+            )
+        );
+
+        // Only the newest version is within the window of itself, so the older ones never conflict
+        let stores = [
+            store(1, "nss", "3.95", 100),
+            store(2, "nss", "3.96", 5000),
+            store(3, "nss", "3.97", 10_000),
+        ];
+
+        for &policy in &POLICIES {
+            assert_eq!(
+                run(&stores, policy),
+                (vec![("nss".into(), 3)], vec![1, 2]),
+                "{:?}",
+                policy
+            );
+        }
+
+        // Re-registering the same version isn't a conflict, but another version alongside it is
+        let stores = [
+            store(1, "nss", "3.97", 10_000),
+            store(2, "nss", "3.96", 10_005),
+            store(3, "nss", "3.97", 10_010),
+        ];
+
+        assert_eq!(
+            run(&stores, DedupPolicy::KeepAllTagged).0,
+            [("nss@3.96".into(), 2), ("nss@3.97".into(), 3)]
+        );
+    }
+
+    #[test]
+    fn parse_policies_for_triple_versions() {
+        // Every version was registered by the same update, so the name is in conflict under any policy
+        let stores = [
+            store(1, "nss", "3.96", 10_000),
+            store(2, "nss", "3.97", 10_010),
+            store(3, "nss", "3.98", 10_020),
+            store(4, "firefox", "123.0", 10_000),
+        ];
+
+        let drop = "drop".parse().unwrap();
+        assert_eq!(drop, DedupPolicy::Drop);
+        assert_eq!(
+            run(&stores, drop),
+            (vec![("firefox".into(), 4)], vec![1, 2, 3])
+        );
+
+        let newest = "newest".parse().unwrap();
+        assert_eq!(newest, DedupPolicy::KeepNewest);
+        assert_eq!(
+            run(&stores, newest),
+            (vec![("firefox".into(), 4), ("nss".into(), 3)], vec![1, 2])
+        );
+
+        assert_eq!("keep-newest".parse::<DedupPolicy>().unwrap(), newest);
+        assert_eq!(
+            "highest-version".parse::<DedupPolicy>().unwrap(),
+            DedupPolicy::HighestVersion
+        );
+        assert!("oldest".parse::<DedupPolicy>().is_err());
+    }
+
+    #[test]
+    fn keep_highest_version() {
+        // An older version registered after a newer one, such as by a rollback, is still left out
+        let stores = [
+            store(1, "nss", "3.97", 100),
+            store(2, "nss", "3.96", 10_000),
+            store(3, "zsh", "5.9", 100),
+        ];
+
+        assert_eq!(
+            run(&stores, DedupPolicy::HighestVersion),
+            (vec![("nss".into(), 1), ("zsh".into(), 3)], vec![2])
+        );
+
+        assert_eq!(
+            run(&stores, DedupPolicy::KeepNewest).0[0],
+            ("nss".into(), 2)
+        );
+
+        // Versions are compared by their components rather than as text, and the newest store of the highest
+        // version is the one kept
+        let stores = [
+            store(1, "mesa", "9.2", 10_000),
+            store(2, "mesa", "24.0.1", 100),
+            store(3, "mesa", "24.0.1+b", 200),
+            store(4, "mesa", "24.0.1-rc1", 300),
+        ];
+
+        assert_eq!(
+            run(&stores, DedupPolicy::HighestVersion),
+            (vec![("mesa".into(), 3)], vec![1, 2, 4])
+        );
+    }
+
+    #[test]
+    fn dedup_is_order_independent() {
+        const NAMES: [&str; 3] = ["firefox", "glibc", "nss"];
+        const VERSIONS: [&str; 3] = ["1.0", "1.1", "2.0"];
+
+        let mut rng = Rng::new(0x9e37_79b9_7f4a_7c15);
+
+        for _ in 0..200 {
+            let num = 1 + rng.below(12) as u32;
+
+            let mut stores = (1..=num)
+                .map(|id| {
+                    let register_time = match rng.below(3) {
+                        0 => 100,
+                        1 => 100 + rng.below(Store::DUPLICATE_WINDOW as u64) as u32,
+                        _ => 100 + Store::DUPLICATE_WINDOW * 2,
+                    };
+
+                    store(
+                        id,
+                        NAMES[rng.below(NAMES.len() as u64) as usize],
+                        VERSIONS[rng.below(VERSIONS.len() as u64) as usize],
+                        register_time,
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            for &policy in &POLICIES {
+                let expected = run(&stores, policy);
+
+                for _ in 0..10 {
+                    // Fisher-Yates shuffle
+                    for i in (1..stores.len()).rev() {
+                        let j = rng.below(i as u64 + 1) as usize;
+                        stores.swap(i, j);
+                    }
+
+                    assert_eq!(run(&stores, policy), expected, "{:?}: {:?}", policy, stores);
+                }
+            }
+        }
+    }
+}
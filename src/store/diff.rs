@@ -1,36 +1,51 @@
+use super::budget::Budget;
+use super::dedup::DedupPolicy;
+use super::version::{self, Direction, Version};
 use super::{Derivation, Store};
-use std::collections::HashSet;
+use serde_derive::Serialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug)]
 pub struct StoreDiff {
     pub name: String,
     pub suffix: Option<String>,
+    /// The suffix of the old store, which only differs from `suffix` when suffix changes are reported.
+    pub suffix_from: Option<String>,
     pub ver_from: String,
     pub ver_to: String,
+    /// The epoch time the new store was registered on the system.
+    pub register_time: u32,
 }
 
 impl StoreDiff {
-    pub fn from_store(new: &Store, old: &Store) -> Option<StoreDiff> {
-        if new.version == old.version {
+    /// Returns the diff between `new` and `old` if their versions differ.
+    ///
+    /// Versions that only differ in build metadata, such as `1.0.0+a` and `1.0.0+b`, are considered to be the same.
+    ///
+    /// Stores with different suffixes are normally considered to be unrelated, but when `suffix_as_version`
+    /// is set, a suffix change is reported as a diff even if the version is the same.
+    pub fn from_store(new: &Store, old: &Store, suffix_as_version: bool) -> Option<StoreDiff> {
+        let suffix_changed = new.suffix != old.suffix;
+
+        if suffix_changed && !suffix_as_version {
             return None;
         }
 
-        // We only want stores with the same suffix
-        match (&new.suffix, &old.suffix) {
-            (Some(new_suffix), Some(old_suffix)) => {
-                if new_suffix != old_suffix {
-                    return None;
-                }
-            }
-            (Some(_), None) | (None, Some(_)) => return None,
-            (None, None) => (),
+        let same_version = version::strip_build_metadata(&new.version)
+            == version::strip_build_metadata(&old.version);
+
+        if same_version && !suffix_changed {
+            return None;
         }
 
         let diff = StoreDiff {
             name: new.name.clone(),
             suffix: new.suffix.clone(),
+            suffix_from: old.suffix.clone(),
             ver_from: old.version.clone(),
             ver_to: new.version.clone(),
+            register_time: new.register_time,
         };
 
         Some(diff)
@@ -39,16 +54,17 @@ impl StoreDiff {
     pub fn from_store_list(
         new_stores: &HashSet<Store>,
         old_stores: &HashSet<Store>,
+        suffix_as_version: bool,
     ) -> Vec<StoreDiff> {
         let mut diffs = Vec::new();
 
         for new in new_stores {
-            let old = match old_stores.get(&new) {
+            let old = match old_stores.get(new) {
                 Some(old) => old,
                 None => continue,
             };
 
-            let diff = match StoreDiff::from_store(new, old) {
+            let diff = match StoreDiff::from_store(new, old, suffix_as_version) {
                 Some(diff) => diff,
                 None => continue,
             };
@@ -60,59 +76,743 @@ impl StoreDiff {
     }
 }
 
+impl StoreDiff {
+    pub fn suffix_changed(&self) -> bool {
+        self.suffix != self.suffix_from
+    }
+
+    /// Returns true if the version clearly went down.
+    ///
+    /// See `version::direction` for how versions are compared. Changes to versions such as commit hashes or
+    /// pre-releases, and ones that could be read either way, are never considered downgrades.
+    pub fn is_downgrade(&self) -> bool {
+        version::direction(&self.ver_from, &self.ver_to) == Direction::Down
+    }
+
+    /// Returns the kind of change, as matched by the `kind` field of `--where`.
+    pub fn kind(&self) -> &'static str {
+        if self.suffix_changed() {
+            "suffix_changed"
+        } else if self.is_downgrade() {
+            "downgraded"
+        } else {
+            "updated"
+        }
+    }
+}
+
 impl PartialEq for StoreDiff {
     fn eq(&self, other: &StoreDiff) -> bool {
         self.name == other.name
     }
 }
 
+/// The parts of a package's changes that should be reported.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DiffScope {
+    /// Report changes to both the package itself and its dependencies.
+    All,
+    /// Only report packages whose own version changed, without their dependencies.
+    PackagesOnly,
+    /// Only report dependency changes, ignoring changes to the package's own version.
+    DepsOnly,
+}
+
+/// Which packages to report based on whether they were built locally.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LocalFilter {
+    All,
+    /// Only report packages that are known to have been built locally.
+    OnlyLocal,
+    /// Don't report packages that are known to have been built locally.
+    NoLocal,
+}
+
+impl LocalFilter {
+    pub fn matches(self, store: &Store) -> bool {
+        match self {
+            Self::All => true,
+            Self::OnlyLocal => store.locally_built == Some(true),
+            Self::NoLocal => store.locally_built != Some(true),
+        }
+    }
+}
+
+/// Options that control which changes are reported as diffs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DiffOptions {
+    pub scope: DiffScope,
+    /// Report a change in a store's suffix, such as `staging` to `stable`, even if its version didn't change.
+    pub suffix_as_version: bool,
+    pub local: LocalFilter,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            scope: DiffScope::All,
+            suffix_as_version: false,
+            local: LocalFilter::All,
+        }
+    }
+}
+
+impl DiffOptions {
+    /// Returns these options without the scope and local filter, so no change is left out.
+    pub fn unfiltered(self) -> Self {
+        Self {
+            scope: DiffScope::All,
+            local: LocalFilter::All,
+            ..self
+        }
+    }
+
+    /// Returns true if any change could be left out by these options.
+    pub fn is_filtered(self) -> bool {
+        self != self.unfiltered()
+    }
+}
+
+/// What a diff found, which matters most when it didn't report anything.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Something changed, even if it was only packages being added or removed.
+    Changed,
+    /// Every package has the same name and version in both states.
+    #[default]
+    Identical,
+    /// Packages were updated, but every update was left out by the diff options.
+    Suppressed,
+    /// The saved state has no packages at all, which usually means it was saved incorrectly.
+    EmptyBaseline,
+}
+
+impl Outcome {
+    /// Returns the exit code to use for `--exit-code`.
+    ///
+    /// An exit code of 1 is left for errors.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Identical => 0,
+            Self::Changed => 2,
+            Self::Suppressed => 3,
+            Self::EmptyBaseline => 4,
+        }
+    }
+}
+
+/// The number of changes a diff found, both before and after the diff options were applied.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DiffCounts {
+    /// The number of packages in the saved state.
+    pub baseline: usize,
+    pub added: usize,
+    pub removed: usize,
+    /// The number of updated packages before the scope and local filter were applied.
+    pub unfiltered: usize,
+    /// The number of updated packages that were reported.
+    pub reported: usize,
+}
+
+impl DiffCounts {
+    /// Decides what the diff found.
+    ///
+    /// An empty baseline takes priority, as every package in the current state would otherwise be
+    /// counted as added. Updates are what a diff shows, so hidden updates make a diff suppressed even
+    /// if packages were also added or removed.
+    pub fn outcome(&self) -> Outcome {
+        if self.baseline == 0 {
+            Outcome::EmptyBaseline
+        } else if self.reported > 0 {
+            Outcome::Changed
+        } else if self.unfiltered > 0 {
+            Outcome::Suppressed
+        } else if self.added > 0 || self.removed > 0 {
+            Outcome::Changed
+        } else {
+            Outcome::Identical
+        }
+    }
+
+    /// Returns the number of updated packages that were left out by the diff options.
+    pub fn hidden(&self) -> usize {
+        self.unfiltered.saturating_sub(self.reported)
+    }
+}
+
 #[derive(Debug)]
 pub struct PackageDiff {
     pub name: String,
     pub pkg: Option<StoreDiff>,
     pub deps: Vec<StoreDiff>,
+    /// The wrapper pair this diff was merged from, if any.
+    pub wrapper: Option<WrapperPair>,
+    /// Newly added packages that are outputs split off from this package, such as `foo-dev` for `foo`.
+    pub split_outputs: Vec<String>,
+    /// The number of paths referencing the current version of the package, if it was counted.
+    pub referrer_count: Option<u32>,
 }
 
-pub fn get_package_diffs(new: &HashSet<Derivation>, old: &HashSet<Derivation>) -> Vec<PackageDiff> {
-    let mut diffs = Vec::new();
+impl PackageDiff {
+    /// Returns true if neither the package nor any of its dependencies changed, which can be the case
+    /// once its dependency changes were filtered out.
+    pub fn is_empty(&self) -> bool {
+        self.pkg.is_none() && self.deps.is_empty()
+    }
+}
+
+/// A package that wraps another, such as `firefox` and `firefox-unwrapped`.
+#[derive(Debug, PartialEq)]
+pub struct WrapperPair {
+    pub wrapper: String,
+    pub wrapped: String,
+    /// Whether the wrapped package changed, rather than only the wrapper.
+    pub wrapped_changed: bool,
+}
+
+/// Name suffixes that mark a package as wrapping the package named without them.
+const WRAPPER_SUFFIXES: [&str; 2] = ["-wrapped", "-with-packages"];
+
+/// The name suffix that marks a package as being wrapped by the package named without it.
+const WRAPPED_SUFFIX: &str = "-unwrapped";
+
+/// Returns the user-facing name and the name of the package a package named `name` would wrap by convention.
+fn wrapped_name(name: &str) -> (&str, Cow<'_, str>) {
+    for suffix in &WRAPPER_SUFFIXES {
+        if let Some(base) = name.strip_suffix(suffix) {
+            return (base, Cow::Borrowed(base));
+        }
+    }
+
+    (name, Cow::Owned(format!("{}{}", name, WRAPPED_SUFFIX)))
+}
+
+/// Returns the stores in `new` that may have a diff against the packages in `old`.
+///
+/// A store whose version and registration time both match its old counterpart points to the
+/// exact same store path, so its dependencies cannot have changed and don't need to be resolved.
+/// Stores that are not present in `old` are kept as well.
+pub fn changed_stores(new: HashSet<Store>, old: &HashSet<Derivation>) -> HashSet<Store> {
+    new.into_iter()
+        .filter(|store| match old.get(store.name.as_str()) {
+            Some(old) => {
+                old.store.version != store.version || old.store.register_time != store.register_time
+            }
+            None => true,
+        })
+        .collect()
+}
+
+/// Removes every package that has duplicates in either `new` or `old` from both of them, so that states
+/// collected on different machines don't report each other's transient duplicates as added or removed.
+///
+/// Each side is deduplicated again along with the stores that were left out of it when it was collected,
+/// using the shared `window`. This should be at least `Store::DUPLICATE_WINDOW`, since packages that were
+/// already dropped can't be restored. Returns the names that were removed, sorted.
+pub fn dedup_across_states(
+    new: &mut HashSet<Store>,
+    new_shadowed: &[Store],
+    old: &mut HashSet<Derivation>,
+    old_shadowed: &[Store],
+    window: u32,
+) -> Vec<String> {
+    let mut names = duplicates_within(new.iter().cloned(), new_shadowed, window);
+    names.extend(duplicates_within(
+        old.iter().map(|pkg| pkg.store.clone()),
+        old_shadowed,
+        window,
+    ));
+
+    new.retain(|store| !names.contains(&store.name));
+    old.retain(|pkg| !names.contains(&pkg.store.name));
+
+    let mut names = names.into_iter().collect::<Vec<_>>();
+    names.sort_unstable();
+    names
+}
+
+/// Returns the names of the stores that have duplicates within `window` when `unique` and `shadowed`
+/// are deduplicated together.
+fn duplicates_within(
+    unique: impl Iterator<Item = Store>,
+    shadowed: &[Store],
+    window: u32,
+) -> HashSet<String> {
+    let stores = unique.chain(shadowed.iter().cloned());
+
+    // Packages with duplicates in either state are always removed, regardless of the configured policy
+    let (unique, shadowed) = Store::partition_unique(stores, window, DedupPolicy::Drop);
+
+    shadowed
+        .into_iter()
+        .map(|store| store.name)
+        .filter(|name| !unique.contains(name.as_str()))
+        .collect()
+}
+
+/// Returns the names of the packages in `new` that were rebuilt without their version changing.
+///
+/// A package is considered to be rebuilt when it was produced by a different derivation than its
+/// counterpart in `old`. Packages without a known deriver in either state are never reported.
+pub fn get_rebuilds(new: &HashSet<Derivation>, old: &HashSet<Derivation>) -> Vec<String> {
+    let mut rebuilds = Vec::new();
 
     for new_pkg in new {
-        let old_pkg = match old.get(&new_pkg) {
+        let old_pkg = match old.get(new_pkg) {
+            Some(old_pkg) => &old_pkg.store,
+            None => continue,
+        };
+
+        let new_pkg = &new_pkg.store;
+
+        if new_pkg.version != old_pkg.version || new_pkg.suffix != old_pkg.suffix {
+            continue;
+        }
+
+        match (&new_pkg.deriver, &old_pkg.deriver) {
+            (Some(new_deriver), Some(old_deriver)) if new_deriver != old_deriver => {
+                rebuilds.push(new_pkg.name.clone())
+            }
+            _ => (),
+        }
+    }
+
+    rebuilds
+}
+
+/// A package in the saved state that is no longer in the current system, along with which of its dependencies
+/// left the system with it.
+#[derive(Debug, PartialEq)]
+pub struct RemovedPackage {
+    pub name: String,
+    pub version: String,
+    /// The names of the dependencies nothing in the current system uses anymore, sorted.
+    pub gone: Vec<String>,
+    /// The names of the dependencies that are still used by something in the current system, sorted.
+    pub retained: Vec<String>,
+}
+
+/// Returns the name of every top-level store in the current system, along with the name of every dependency of them.
+///
+/// `new` only needs to hold the derivations that were resolved. Every other package in `top_level` is unchanged,
+/// so its dependencies are taken from `old` instead. When resolving dependencies was `partial`, a package in
+/// `new` without any dependencies may have never been resolved, so its dependencies are taken from `old` as well.
+pub fn current_names<'a>(
+    top_level: &'a HashSet<String>,
+    new: &'a HashSet<Derivation>,
+    old: &'a HashSet<Derivation>,
+    partial: bool,
+) -> HashSet<&'a str> {
+    let mut names = top_level.iter().map(String::as_str).collect::<HashSet<_>>();
+
+    for name in top_level {
+        let resolved = new
+            .get(name.as_str())
+            .filter(|pkg| !partial || !pkg.deps.is_empty());
+
+        let pkg = match resolved.or_else(|| old.get(name.as_str())) {
+            Some(pkg) => pkg,
+            None => continue,
+        };
+
+        names.extend(pkg.deps.iter().map(|dep| dep.name.as_str()));
+    }
+
+    names
+}
+
+/// Returns every package in `old` that was removed from the current system, as found by `get_removals`.
+///
+/// Returns `None` if `budget` cut the scan of the current system short, as every package it didn't get to
+/// would look removed.
+pub fn find_removals(
+    old: &HashSet<Derivation>,
+    top_level: &HashSet<String>,
+    new: &HashSet<Derivation>,
+    budget: &Budget,
+) -> Option<Vec<RemovedPackage>> {
+    if budget.was_cut_short(super::SCANNING_STORES) {
+        return None;
+    }
+
+    let current = current_names(top_level, new, old, budget.is_partial());
+    Some(get_removals(old, top_level, &current))
+}
+
+/// Returns every package in `old` that isn't in `top_level`, with its dependencies split by whether their name
+/// is still in `current`, as returned by `current_names`. The removals are sorted by name.
+pub fn get_removals(
+    old: &HashSet<Derivation>,
+    top_level: &HashSet<String>,
+    current: &HashSet<&str>,
+) -> Vec<RemovedPackage> {
+    let mut removals = old
+        .iter()
+        .filter(|pkg| !top_level.contains(&pkg.store.name))
+        .map(|pkg| {
+            // Every output of a dependency has the same name, so they only count once
+            let mut names = pkg
+                .deps
+                .iter()
+                .map(|dep| dep.name.as_str())
+                .collect::<Vec<_>>();
+
+            names.sort_unstable();
+            names.dedup();
+
+            let (retained, gone): (Vec<_>, Vec<_>) =
+                names.into_iter().partition(|name| current.contains(name));
+
+            RemovedPackage {
+                name: pkg.store.name.clone(),
+                version: pkg.store.version.clone(),
+                gone: gone.into_iter().map(Into::into).collect(),
+                retained: retained.into_iter().map(Into::into).collect(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    removals.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+    removals
+}
+
+/// A change to the version of an ecosystem, such as the OCaml compiler, that packages built for it went through.
+#[derive(Debug, PartialEq)]
+pub struct EcosystemTransition {
+    pub ecosystem: String,
+    pub from: String,
+    pub to: String,
+    /// The number of packages that were rebuilt for the new version.
+    pub packages: usize,
+}
+
+/// Returns every ecosystem transition between the packages in `old` and `new`, sorted by ecosystem and version.
+///
+/// Packages keep their names across a transition, so this lets it be reported once rather than being
+/// implied by the version of every package built for the ecosystem.
+pub fn get_ecosystem_transitions(
+    new: &HashSet<Derivation>,
+    old: &HashSet<Derivation>,
+) -> Vec<EcosystemTransition> {
+    let mut counts = HashMap::<_, usize>::new();
+
+    for new_pkg in new {
+        let old_pkg = match old.get(new_pkg) {
             Some(old_pkg) => old_pkg,
             None => continue,
         };
 
-        let pkg_diff = StoreDiff::from_store(&new_pkg.store, &old_pkg.store);
-        let dep_diffs = StoreDiff::from_store_list(&new_pkg.deps, &old_pkg.deps);
+        match (&new_pkg.store.ecosystem, &old_pkg.store.ecosystem) {
+            (Some(to), Some(from)) if to.name == from.name && to.version != from.version => {
+                *counts
+                    .entry((&to.name, &from.version, &to.version))
+                    .or_default() += 1
+            }
+            _ => (),
+        }
+    }
+
+    let mut transitions = counts
+        .into_iter()
+        .map(|((ecosystem, from, to), packages)| EcosystemTransition {
+            ecosystem: ecosystem.clone(),
+            from: from.clone(),
+            to: to.clone(),
+            packages,
+        })
+        .collect::<Vec<_>>();
+
+    transitions.sort_unstable_by(|x, y| {
+        (&x.ecosystem, Version::parse(&x.from), Version::parse(&x.to)).cmp(&(
+            &y.ecosystem,
+            Version::parse(&y.from),
+            Version::parse(&y.to),
+        ))
+    });
+
+    transitions
+}
+
+/// Returns the diffs of every package in `new` against its counterpart in `old`.
+///
+/// `new` does not need to contain every package in `old`, which allows it to only contain
+/// the packages returned by `changed_stores`. Packages in `new` that don't match `opts.local` are skipped.
+pub fn get_package_diffs(
+    new: &HashSet<Derivation>,
+    old: &HashSet<Derivation>,
+    opts: DiffOptions,
+) -> Vec<PackageDiff> {
+    let mut diffs = Vec::new();
 
-        if pkg_diff.is_none() && dep_diffs.is_empty() {
+    for new_pkg in new {
+        if !opts.local.matches(&new_pkg.store) {
             continue;
         }
 
+        let old_pkg = match old.get(new_pkg) {
+            Some(old_pkg) => old_pkg,
+            None => continue,
+        };
+
+        let pkg_diff = match opts.scope {
+            DiffScope::All | DiffScope::PackagesOnly => {
+                StoreDiff::from_store(&new_pkg.store, &old_pkg.store, opts.suffix_as_version)
+            }
+            DiffScope::DepsOnly => None,
+        };
+
+        let dep_diffs = match opts.scope {
+            DiffScope::All | DiffScope::DepsOnly => {
+                StoreDiff::from_store_list(&new_pkg.deps, &old_pkg.deps, opts.suffix_as_version)
+            }
+            DiffScope::PackagesOnly => Vec::new(),
+        };
+
         let diff = PackageDiff {
             name: new_pkg.store.name.clone(),
             pkg: pkg_diff,
             deps: dep_diffs,
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: new_pkg.store.referrer_count,
         };
 
-        diffs.push(diff);
+        if !diff.is_empty() {
+            diffs.push(diff);
+        }
     }
 
     diffs
 }
 
+/// Merges the diffs of packages that wrap another package with the diff of the package they wrap.
+///
+/// Wrappers are detected by their name, such as `firefox` and `firefox-unwrapped` or `python3-with-packages` and `python3`.
+/// To avoid merging packages that only happen to share a name, the wrapper in `new` must also directly reference
+/// the wrapped package, and the wrapped package must be a package in either `new` or `old`.
+///
+/// The merged diff uses the name without any wrapper suffix, and the wrapped package's own version change.
+/// The change of the wrapper's reference to the wrapped package is left out of its dependencies, so a merged
+/// diff can be empty when that was its only change.
+pub fn merge_wrappers(
+    diffs: Vec<PackageDiff>,
+    new: &HashSet<Derivation>,
+    old: &HashSet<Derivation>,
+) -> Vec<PackageDiff> {
+    let mut pairs = new
+        .iter()
+        .filter_map(|wrapper| {
+            let (base, wrapped) = wrapped_name(&wrapper.store.name);
+
+            let is_package = new.contains(wrapped.as_ref()) || old.contains(wrapped.as_ref());
+
+            if !is_package || !wrapper.deps.contains(wrapped.as_ref()) {
+                return None;
+            }
+
+            Some((
+                base.to_string(),
+                wrapper.store.name.clone(),
+                wrapped.into_owned(),
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    // Makes merging deterministic if a package is somehow part of multiple pairs
+    pairs.sort_unstable();
+
+    let mut diffs = diffs
+        .into_iter()
+        .map(|diff| (diff.name.clone(), diff))
+        .collect::<HashMap<_, _>>();
+
+    let mut merged = Vec::with_capacity(diffs.len());
+
+    for (base, wrapper, wrapped) in pairs {
+        let wrapper_diff = diffs.remove(&wrapper);
+        let wrapped_diff = diffs.remove(&wrapped);
+        let wrapped_changed = wrapped_diff.is_some();
+
+        let mut pkg = None;
+        let mut deps = Vec::<StoreDiff>::new();
+
+        // The wrapped package goes first so its version change takes priority
+        for diff in wrapped_diff.into_iter().chain(wrapper_diff) {
+            if pkg.is_none() {
+                pkg = diff.pkg;
+            }
+
+            for dep in diff.deps {
+                if dep.name == wrapper || dep.name == wrapped || deps.contains(&dep) {
+                    continue;
+                }
+
+                deps.push(dep);
+            }
+        }
+
+        if let Some(pkg) = &mut pkg {
+            pkg.name = base.clone();
+        }
+
+        // The wrapper is what profiles refer to, so its referrers are the ones that keep the package alive
+        let referrer_count = new
+            .get(wrapper.as_str())
+            .and_then(|pkg| pkg.store.referrer_count);
+
+        merged.push(PackageDiff {
+            name: base,
+            pkg,
+            deps,
+            wrapper: Some(WrapperPair {
+                wrapper,
+                wrapped,
+                wrapped_changed,
+            }),
+            split_outputs: Vec::new(),
+            referrer_count,
+        });
+    }
+
+    merged.extend(diffs.into_values());
+    merged
+}
+
+/// Groups packages that were newly added to `new` as an output split off from a diffed package,
+/// such as `foo-dev` appearing when `foo` is updated, under the diff of the package they were split from.
+///
+/// A package is only grouped when it doesn't exist in `old`, and its name is a diffed package's name
+/// followed by a known output name. Returns the number of packages that were grouped.
+pub fn group_split_outputs(
+    diffs: &mut [PackageDiff],
+    new: &HashSet<Derivation>,
+    old: &HashSet<Derivation>,
+) -> usize {
+    let parents = diffs
+        .iter()
+        .enumerate()
+        .map(|(i, diff)| (diff.name.clone(), i))
+        .collect::<HashMap<_, _>>();
+
+    let mut grouped = 0;
+
+    for pkg in new {
+        let name = &pkg.store.name;
+
+        if old.contains(name.as_str()) {
+            continue;
+        }
+
+        let parent = match name.rsplit_once('-') {
+            Some((base, output)) if Store::is_known_output(output.as_bytes()) => parents.get(base),
+            _ => None,
+        };
+
+        if let Some(&i) = parent {
+            diffs[i].split_outputs.push(name.clone());
+            grouped += 1;
+        }
+    }
+
+    for diff in diffs {
+        diff.split_outputs.sort_unstable();
+    }
+
+    grouped
+}
+
+/// The fewest characters in a row a removed and an added package's names have to share to be paired as a rename.
+const MIN_RENAME_OVERLAP: usize = 3;
+
+/// How much of the shorter of two names the characters they share in a row have to cover to be paired as a rename.
+const MIN_RENAME_SIMILARITY: f64 = 0.75;
+
+/// Pairs each removed package in `removals` with the package in `added` it was most likely renamed to, and returns
+/// the name of the added package by the name of the removed one.
+///
+/// This is only a guess. A pair has to have the same version, and names that share a run of characters covering
+/// most of the shorter name, such as `foo` and `foo-ng`. Each package is only paired once, with the one it shares
+/// the most with. Unrelated packages that share a version and a name prefix, such as `foo` and `foo-tools`, can
+/// still be paired, while a rename that also changed the version never is.
+pub fn detect_renames<'a>(
+    removals: &[RemovedPackage],
+    added: impl Iterator<Item = &'a Store>,
+) -> HashMap<String, String> {
+    let added = added.collect::<Vec<_>>();
+    let mut candidates = Vec::new();
+
+    for removal in removals {
+        for store in added
+            .iter()
+            .filter(|store| store.version == removal.version)
+        {
+            let overlap = longest_common_run(&removal.name, &store.name);
+            let shorter = removal.name.len().min(store.name.len());
+            let similarity = overlap as f64 / shorter as f64;
+
+            if overlap >= MIN_RENAME_OVERLAP && similarity >= MIN_RENAME_SIMILARITY {
+                candidates.push((similarity, removal.name.as_str(), store.name.as_str()));
+            }
+        }
+    }
+
+    // The closest pairs are picked first, and names break ties so the pairing never depends on hashing order
+    candidates.sort_unstable_by(|x, y| {
+        y.0.total_cmp(&x.0)
+            .then_with(|| x.1.cmp(y.1))
+            .then_with(|| x.2.cmp(y.2))
+    });
+
+    let mut renames = HashMap::new();
+    let mut taken = HashSet::new();
+
+    for (_, from, to) in candidates {
+        if renames.contains_key(from) || taken.contains(to) {
+            continue;
+        }
+
+        taken.insert(to);
+        renames.insert(from.to_string(), to.to_string());
+    }
+
+    renames
+}
+
+/// Returns the length of the longest run of bytes that `x` and `y` both contain.
+fn longest_common_run(x: &str, y: &str) -> usize {
+    let (x, y) = (x.as_bytes(), y.as_bytes());
+
+    // The length of the run ending at each byte of `y`, for the previous byte of `x`
+    let mut prev = vec![0; y.len() + 1];
+    let mut longest = 0;
+
+    for &a in x {
+        let mut cur = vec![0; y.len() + 1];
+
+        for (j, &b) in y.iter().enumerate() {
+            if a == b {
+                cur[j + 1] = prev[j] + 1;
+                longest = longest.max(cur[j + 1]);
+            }
+        }
+
+        prev = cur;
+    }
+
+    longest
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::testing;
 
     macro_rules! store {
         ($name:expr, $version:expr, $suffix:expr) => {
             Store {
-                id: 0,
-                register_time: 0,
-                name: $name.into(),
-                version: $version.into(),
                 suffix: $suffix,
+                ..testing::store(&$name, &$version)
             }
         };
     }
@@ -122,8 +822,10 @@ mod test {
             StoreDiff {
                 name: $name.into(),
                 suffix: None,
+                suffix_from: None,
                 ver_from: $ver_from.into(),
                 ver_to: $ver_to.into(),
+                register_time: 0,
             }
         };
     }
@@ -139,6 +841,8 @@ mod test {
             store!("diff-suffix", "3.4.6", Some("bin".into())),
             store!("same-suffix", "1.0.1", Some("bin".into())),
             store!("partial-suffix", "1.0.1", None),
+            store!("build-only", "1.0.0+b", None),
+            store!("build-and-version", "1.0.1+build.5", None),
         ]
         .into_iter()
         .collect::<HashSet<Store>>();
@@ -152,18 +856,22 @@ mod test {
             store!("diff-suffix", "3.4.5", Some("out".into())),
             store!("same-suffix", "1.0.0", Some("bin".into())),
             store!("partial-suffix", "1.0.0", Some("bin".into())),
+            store!("build-only", "1.0.0+a", None),
+            store!("build-and-version", "1.0.0+build.5", None),
         ]
         .into_iter()
         .collect::<HashSet<Store>>();
 
+        // Build metadata doesn't count as a version change on its own
         let expected_diffs = vec![
             diff!("glxinfo", "8.4.0", "8.5.0"),
+            diff!("build-and-version", "1.0.0+build.5", "1.0.1+build.5"),
             diff!("wine-wow", "4.0-rc5", "4.1"),
             diff!("steam-runtime", "2016-08-26", "2019-02-15"),
             diff!("same-suffix", "1.0.0", "1.0.1"),
         ];
 
-        let diffs = StoreDiff::from_store_list(&new_stores, &old_stores);
+        let diffs = StoreDiff::from_store_list(&new_stores, &old_stores, false);
 
         assert!(
             diffs.len() == expected_diffs.len(),
@@ -178,10 +886,768 @@ mod test {
             let expected = expected_diffs
                 .iter()
                 .find(|&x| x == &diff)
-                .expect(&format!("expected diff not found: {}", diff.name));
+                .unwrap_or_else(|| panic!("expected diff not found: {}", diff.name));
 
             assert_eq!(diff.ver_from, expected.ver_from, "old version mismatch");
             assert_eq!(diff.ver_to, expected.ver_to, "new version mismatch");
         }
     }
+
+    macro_rules! deriv {
+        ($name:expr, $version:expr, [$($dep_name:expr => $dep_ver:expr),*]) => {
+            deriv!($name, $version, 0, [$($dep_name => $dep_ver),*])
+        };
+
+        ($name:expr, $version:expr, $reg_time:expr, [$($dep_name:expr => $dep_ver:expr),*]) => {
+            Derivation {
+                store: Store {
+                    register_time: $reg_time,
+                    ..store!($name, $version, None)
+                },
+                deps: vec![$(store!($dep_name, $dep_ver, None)),*].into_iter().collect(),
+                paths: HashMap::new(),
+            }
+        };
+    }
+
+    #[test]
+    fn partition_removed_deps() {
+        let old = vec![
+            deriv!("firefox", "121.0", ["nss" => "3.95", "gtk+3" => "3.24", "glibc" => "2.38"]),
+            deriv!("chromium", "120.0", ["nss" => "3.95", "libva" => "2.20", "glibc" => "2.38"]),
+            deriv!("vlc", "3.0", ["libva" => "2.20", "ffmpeg" => "6.0"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        // firefox was updated and resolved again, vlc is unchanged, and chromium was removed
+        let new = vec![deriv!("firefox", "122.0", ["nss" => "3.96", "glibc" => "2.38"])]
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        let top_level = ["firefox", "vlc"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<HashSet<_>>();
+
+        let current = current_names(&top_level, &new, &old, false);
+        let removals = get_removals(&old, &top_level, &current);
+
+        assert_eq!(
+            removals,
+            [RemovedPackage {
+                name: "chromium".into(),
+                version: "120.0".into(),
+                gone: Vec::new(),
+                retained: vec!["glibc".into(), "libva".into(), "nss".into()],
+            }]
+        );
+
+        // Once vlc is removed as well, libva isn't used by anything that's left
+        let top_level = ["firefox"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<HashSet<_>>();
+
+        let current = current_names(&top_level, &new, &old, false);
+        let removals = get_removals(&old, &top_level, &current);
+
+        assert_eq!(
+            removals
+                .iter()
+                .map(|removal| removal.name.as_str())
+                .collect::<Vec<_>>(),
+            ["chromium", "vlc"]
+        );
+        assert_eq!(removals[0].gone, ["libva"]);
+        assert_eq!(removals[0].retained, ["glibc", "nss"]);
+        assert_eq!(removals[1].gone, ["ffmpeg", "libva"]);
+        assert!(removals[1].retained.is_empty());
+
+        // Dependencies the updated package dropped aren't current, even though the old state still has them
+        assert!(!current.contains("gtk+3"));
+    }
+
+    #[test]
+    fn package_diff_scopes() {
+        let new = vec![
+            deriv!("pkg-only", "1.1", ["dep" => "1.0"]),
+            deriv!("deps-only", "1.0", ["dep" => "1.1"]),
+            deriv!("both", "1.1", ["dep" => "1.1"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let old = vec![
+            deriv!("pkg-only", "1.0", ["dep" => "1.0"]),
+            deriv!("deps-only", "1.0", ["dep" => "1.0"]),
+            deriv!("both", "1.0", ["dep" => "1.0"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let summarize = |scope| {
+            let mut diffs = get_package_diffs(
+                &new,
+                &old,
+                DiffOptions {
+                    scope,
+                    ..DiffOptions::default()
+                },
+            )
+            .into_iter()
+            .map(|diff| (diff.name, diff.pkg.is_some(), diff.deps.len()))
+            .collect::<Vec<_>>();
+
+            diffs.sort_unstable();
+            diffs
+        };
+
+        assert_eq!(
+            summarize(DiffScope::All),
+            vec![
+                ("both".into(), true, 1),
+                ("deps-only".into(), false, 1),
+                ("pkg-only".into(), true, 0),
+            ]
+        );
+
+        assert_eq!(
+            summarize(DiffScope::PackagesOnly),
+            vec![("both".into(), true, 0), ("pkg-only".into(), true, 0)]
+        );
+
+        assert_eq!(
+            summarize(DiffScope::DepsOnly),
+            vec![("both".into(), false, 1), ("deps-only".into(), false, 1)]
+        );
+    }
+
+    #[test]
+    fn changed_stores_match_full_diff() {
+        let new = vec![
+            deriv!("unchanged", "1.0", 10, ["dep" => "1.0"]),
+            deriv!("updated", "1.1", 20, ["dep" => "1.0"]),
+            deriv!("rebuilt", "1.0", 20, ["dep" => "1.1"]),
+            deriv!("added", "1.0", 20, ["dep" => "1.1"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let old = vec![
+            deriv!("unchanged", "1.0", 10, ["dep" => "1.0"]),
+            deriv!("updated", "1.0", 10, ["dep" => "1.0"]),
+            deriv!("rebuilt", "1.0", 10, ["dep" => "1.0"]),
+            deriv!("removed", "1.0", 10, ["dep" => "1.0"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let stores = new
+            .iter()
+            .map(|pkg| Store {
+                register_time: pkg.store.register_time,
+                ..store!(pkg.store.name.clone(), pkg.store.version.clone(), None)
+            })
+            .collect();
+
+        let changed = changed_stores(stores, &old);
+
+        let mut changed_names = changed.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+        changed_names.sort_unstable();
+        assert_eq!(changed_names, ["added", "rebuilt", "updated"]);
+
+        // Simulate resolving dependencies for only the changed stores
+        let partial = new
+            .iter()
+            .filter(|pkg| changed.contains(pkg.store.name.as_str()))
+            .map(|pkg| Derivation {
+                store: store!(pkg.store.name.clone(), pkg.store.version.clone(), None),
+                deps: pkg
+                    .deps
+                    .iter()
+                    .map(|dep| store!(dep.name.clone(), dep.version.clone(), None))
+                    .collect(),
+                paths: HashMap::new(),
+            })
+            .collect::<HashSet<_>>();
+
+        let summarize = |diffs: Vec<PackageDiff>| {
+            let mut diffs = diffs
+                .into_iter()
+                .map(|diff| {
+                    let pkg = diff.pkg.map(|pkg| (pkg.ver_from, pkg.ver_to));
+                    let deps = diff
+                        .deps
+                        .into_iter()
+                        .map(|dep| (dep.name, dep.ver_from, dep.ver_to))
+                        .collect::<Vec<_>>();
+
+                    (diff.name, pkg, deps)
+                })
+                .collect::<Vec<_>>();
+
+            diffs.sort_unstable();
+            diffs
+        };
+
+        let full = summarize(get_package_diffs(&new, &old, DiffOptions::default()));
+        let two_phase = summarize(get_package_diffs(&partial, &old, DiffOptions::default()));
+
+        assert_eq!(full, two_phase);
+        assert_eq!(full.len(), 2);
+    }
+
+    #[test]
+    fn detect_rebuilds() {
+        let with_deriver = |name: &str, version: &str, drv: Option<&str>| Derivation {
+            store: Store {
+                deriver: drv.map(Into::into),
+                ..store!(name, version, None)
+            },
+            deps: HashSet::new(),
+            paths: HashMap::new(),
+        };
+
+        let new = vec![
+            with_deriver("rebuilt", "1.0", Some("/nix/store/b-rebuilt-1.0.drv")),
+            with_deriver("same", "1.0", Some("/nix/store/a-same-1.0.drv")),
+            with_deriver("updated", "1.1", Some("/nix/store/b-updated-1.1.drv")),
+            with_deriver("unknown", "1.0", None),
+            with_deriver("added", "1.0", Some("/nix/store/b-added-1.0.drv")),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let old = vec![
+            with_deriver("rebuilt", "1.0", Some("/nix/store/a-rebuilt-1.0.drv")),
+            with_deriver("same", "1.0", Some("/nix/store/a-same-1.0.drv")),
+            with_deriver("updated", "1.0", Some("/nix/store/a-updated-1.0.drv")),
+            with_deriver("unknown", "1.0", Some("/nix/store/a-unknown-1.0.drv")),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        assert_eq!(get_rebuilds(&new, &old), vec!["rebuilt".to_string()]);
+    }
+
+    #[test]
+    fn ecosystem_transitions() {
+        let parse = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| Derivation {
+                    store: Store::parse_stripped(name).unwrap(),
+                    deps: HashSet::new(),
+                    paths: HashMap::new(),
+                })
+                .collect::<HashSet<_>>()
+        };
+
+        let new = parse(&[
+            "ocaml5.1.1-zarith-1.13",
+            "ocaml5.1.1-lwt-5.7.0",
+            "ocaml-5.1.1",
+            "ghc-9.4.8",
+            "texlive-2024.20240312",
+            "hello-2.12.1",
+        ]);
+
+        let old = parse(&[
+            "ocaml4.14.1-zarith-1.13",
+            "ocaml4.14.1-lwt-5.6.1",
+            "ocaml-4.14.1",
+            "ghc-9.4.8",
+            "texlive-2023.20230401",
+            "hello-2.12",
+        ]);
+
+        let transition = |ecosystem: &str, from: &str, to: &str, packages| EcosystemTransition {
+            ecosystem: ecosystem.into(),
+            from: from.into(),
+            to: to.into(),
+            packages,
+        };
+
+        assert_eq!(
+            get_ecosystem_transitions(&new, &old),
+            vec![
+                transition("ocaml", "4.14.1", "5.1.1", 3),
+                transition("texlive", "2023", "2024", 1),
+            ]
+        );
+
+        // Libraries keep their names across the compiler update, so only actual version changes are reported
+        let mut names = get_package_diffs(&new, &old, DiffOptions::default())
+            .into_iter()
+            .map(|diff| diff.name)
+            .collect::<Vec<_>>();
+
+        names.sort_unstable();
+        assert_eq!(names, ["hello", "lwt", "ocaml", "texlive"]);
+    }
+
+    #[test]
+    fn merge_wrapper_pairs() {
+        let new = vec![
+            deriv!("firefox", "123.0", ["firefox-unwrapped" => "123.0", "gtk" => "3.1"]),
+            deriv!("firefox-unwrapped", "123.0", ["gtk" => "3.1", "nss" => "3.98"]),
+            deriv!("python3-with-packages", "3.11.7", ["python3" => "3.11.6", "numpy" => "1.27"]),
+            deriv!("python3", "3.11.6", ["glibc" => "2.38"]),
+            deriv!("foo", "2.0", ["bar" => "1.0"]),
+            deriv!("foo-unwrapped", "2.0", ["bar" => "1.0"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let old = vec![
+            deriv!("firefox", "122.0", ["firefox-unwrapped" => "122.0", "gtk" => "3.0"]),
+            deriv!("firefox-unwrapped", "122.0", ["gtk" => "3.0", "nss" => "3.97"]),
+            deriv!("python3-with-packages", "3.11.7", ["python3" => "3.11.6", "numpy" => "1.26"]),
+            deriv!("python3", "3.11.6", ["glibc" => "2.38"]),
+            deriv!("foo", "1.0", ["bar" => "1.0"]),
+            deriv!("foo-unwrapped", "1.0", ["bar" => "1.0"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let diffs = get_package_diffs(&new, &old, DiffOptions::default());
+
+        let mut merged = merge_wrappers(diffs, &new, &old)
+            .into_iter()
+            .map(|diff| {
+                let pkg = diff.pkg.map(|pkg| (pkg.name, pkg.ver_from, pkg.ver_to));
+                let mut deps = diff
+                    .deps
+                    .into_iter()
+                    .map(|dep| dep.name)
+                    .collect::<Vec<_>>();
+                deps.sort_unstable();
+
+                (diff.name, pkg, deps, diff.wrapper)
+            })
+            .collect::<Vec<_>>();
+
+        merged.sort_unstable_by(|x, y| x.0.cmp(&y.0));
+
+        let pair = |wrapper: &str, wrapped: &str, wrapped_changed| {
+            Some(WrapperPair {
+                wrapper: wrapper.into(),
+                wrapped: wrapped.into(),
+                wrapped_changed,
+            })
+        };
+
+        assert_eq!(
+            merged,
+            vec![
+                (
+                    "firefox".into(),
+                    Some(("firefox".into(), "122.0".into(), "123.0".into())),
+                    vec!["gtk".into(), "nss".into()],
+                    pair("firefox", "firefox-unwrapped", true),
+                ),
+                // Without a reference between them, these only share a name by coincidence
+                (
+                    "foo".into(),
+                    Some(("foo".into(), "1.0".into(), "2.0".into())),
+                    vec![],
+                    None,
+                ),
+                (
+                    "foo-unwrapped".into(),
+                    Some(("foo-unwrapped".into(), "1.0".into(), "2.0".into())),
+                    vec![],
+                    None,
+                ),
+                (
+                    "python3".into(),
+                    None,
+                    vec!["numpy".into()],
+                    pair("python3-with-packages", "python3", false),
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn group_split_output_packages() {
+        let new = vec![
+            deriv!("foo", "2.0", []),
+            deriv!("foo-dev", "2.0", []),
+            deriv!("foo-man", "2.0", []),
+            deriv!("bar", "1.0", []),
+            deriv!("bar-bin", "1.0", []),
+            deriv!("baz", "3.0", []),
+            deriv!("baz-docs", "3.0", []),
+            deriv!("qux", "2.0", []),
+            deriv!("qux-dev", "2.0", []),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let old = vec![
+            deriv!("foo", "1.0", []),
+            deriv!("bar", "1.0", []),
+            deriv!("baz", "2.0", []),
+            deriv!("qux", "1.0", []),
+            deriv!("qux-dev", "1.0", []),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let mut diffs = get_package_diffs(&new, &old, DiffOptions::default());
+        assert_eq!(group_split_outputs(&mut diffs, &new, &old), 2);
+
+        let mut grouped = diffs
+            .into_iter()
+            .map(|diff| (diff.name, diff.split_outputs))
+            .collect::<Vec<_>>();
+
+        grouped.sort_unstable();
+
+        // bar didn't change, baz-docs isn't an output, and qux-dev already existed
+        assert_eq!(
+            grouped,
+            vec![
+                ("baz".into(), vec![]),
+                ("foo".into(), vec!["foo-dev".into(), "foo-man".into()]),
+                ("qux".into(), vec![]),
+                ("qux-dev".into(), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn detect_likely_renames() {
+        let removal = |name: &str, version: &str| RemovedPackage {
+            name: name.into(),
+            version: version.into(),
+            gone: Vec::new(),
+            retained: Vec::new(),
+        };
+
+        let removals = [
+            removal("foo", "1.0"),
+            removal("libfoo", "1.0"),
+            removal("exa", "0.10.1"),
+            removal("vim", "9.1"),
+            removal("nodejs", "20.11.1"),
+        ];
+
+        let added = [
+            store!("foo-ng", "1.0", None),
+            store!("libfoo2", "1.0", None),
+            store!("eza", "0.10.1", None),
+            store!("neovim", "0.9.5", None),
+            store!("nodejs-slim", "20.11.1", None),
+            store!("nodejs_20", "20.11.1", None),
+        ];
+
+        let mut renames = detect_renames(&removals, added.iter())
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        renames.sort_unstable();
+
+        let pair = |from: &str, to: &str| (from.to_string(), to.to_string());
+
+        // exa and eza share too little, vim and neovim have different versions, and nodejs is only paired
+        // once, with the first of the equally close names
+        assert_eq!(
+            renames,
+            [
+                pair("foo", "foo-ng"),
+                pair("libfoo", "libfoo2"),
+                pair("nodejs", "nodejs-slim"),
+            ]
+        );
+
+        assert_eq!(longest_common_run("foo", "foo-ng"), 3);
+        assert_eq!(longest_common_run("libfoo", "foolib"), 3);
+        assert_eq!(longest_common_run("abc", ""), 0);
+    }
+
+    #[test]
+    fn filter_locally_built() {
+        let built = |name: &str, locally_built| {
+            let mut deriv = deriv!(name, "2.0", []);
+            deriv.store.locally_built = locally_built;
+            deriv
+        };
+
+        let new = vec![
+            built("local", Some(true)),
+            built("substituted", Some(false)),
+            built("unknown", None),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let old = vec![
+            deriv!("local", "1.0", []),
+            deriv!("substituted", "1.0", []),
+            deriv!("unknown", "1.0", []),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let names = |local| {
+            let opts = DiffOptions {
+                local,
+                ..DiffOptions::default()
+            };
+
+            let mut names = get_package_diffs(&new, &old, opts)
+                .into_iter()
+                .map(|diff| diff.name)
+                .collect::<Vec<_>>();
+
+            names.sort_unstable();
+            names
+        };
+
+        assert_eq!(names(LocalFilter::All), ["local", "substituted", "unknown"]);
+        assert_eq!(names(LocalFilter::OnlyLocal), ["local"]);
+        assert_eq!(names(LocalFilter::NoLocal), ["substituted", "unknown"]);
+    }
+
+    #[test]
+    fn detect_downgrades() {
+        let cases = [
+            ("1.2.3", "1.2.2", true),
+            ("2.0", "1.10", true),
+            ("1.2.3", "1.2.4", false),
+            ("1.0", "1.0.0", false),
+            ("1.0", "1.0rc1", false),
+            ("c47095a8dcfa4c376d8e9c4276865b7f298137d8", "1.0", false),
+            ("9165-8ca53f9", "9164-1b2c3d4", false),
+            ("2024.10", "2024.9", true),
+            ("2024.2", "2024.02", false),
+            ("22.05", "22.5", false),
+            ("1.8", "1.08", false),
+            ("1.10", "1.08", true),
+        ];
+
+        for &(from, to, expected) in &cases {
+            let diff = StoreDiff {
+                name: "pkg".into(),
+                suffix: None,
+                suffix_from: None,
+                ver_from: from.into(),
+                ver_to: to.into(),
+                register_time: 0,
+            };
+
+            assert_eq!(diff.is_downgrade(), expected, "{} -> {}", from, to);
+        }
+    }
+
+    #[test]
+    fn suffix_as_version() {
+        let staging = store!("wine-wow", "4.0", Some("staging".into()));
+        let stable = store!("wine-wow", "4.0", Some("stable".into()));
+
+        assert!(StoreDiff::from_store(&stable, &staging, false).is_none());
+
+        let diff = StoreDiff::from_store(&stable, &staging, true).unwrap();
+        assert!(diff.suffix_changed());
+        assert_eq!(diff.suffix_from.as_deref(), Some("staging"));
+        assert_eq!(diff.suffix.as_deref(), Some("stable"));
+        assert_eq!(diff.ver_from, diff.ver_to);
+
+        let unsuffixed = store!("wine-wow", "4.0", None);
+        assert!(StoreDiff::from_store(&unsuffixed, &staging, true).is_some());
+        assert!(StoreDiff::from_store(&staging, &staging, true).is_none());
+
+        // Version changes with the same suffix are unaffected
+        let updated = store!("wine-wow", "4.1", Some("staging".into()));
+        let diff = StoreDiff::from_store(&updated, &staging, true).unwrap();
+        assert!(!diff.suffix_changed());
+    }
+
+    #[test]
+    fn dedup_across_machines() {
+        let at = |name: &str, version: &str, register_time| Store {
+            register_time,
+            ..store!(name, version, None)
+        };
+
+        let collect = |stores: Vec<Store>| {
+            Store::partition_unique(
+                stores.into_iter(),
+                Store::DUPLICATE_WINDOW,
+                DedupPolicy::Drop,
+            )
+        };
+
+        // Machine A registered two versions of mesa in the same update, so it was dropped
+        let (old, old_shadowed) = collect(vec![
+            at("mesa", "24.1", 200),
+            at("mesa", "24.0", 100),
+            at("firefox", "123.0", 50),
+        ]);
+
+        let old = old
+            .into_iter()
+            .map(|store| Derivation {
+                store,
+                deps: HashSet::new(),
+                paths: HashMap::new(),
+            })
+            .collect::<HashSet<_>>();
+
+        // Machine B registered its versions of llvm further apart than the default window
+        let (new, new_shadowed) = collect(vec![
+            at("llvm", "17.0", 10_000),
+            at("mesa", "24.1", 6000),
+            at("llvm", "16.0", 5000),
+            at("firefox", "124.0", 4000),
+        ]);
+
+        assert!(!old.contains("mesa") && new.contains("mesa"));
+
+        let dedup = |window| {
+            let (mut new, mut old) = (new.clone(), old.clone());
+            let names =
+                dedup_across_states(&mut new, &new_shadowed, &mut old, &old_shadowed, window);
+
+            (names, new, old)
+        };
+
+        let (names, new, old) = dedup(Store::DUPLICATE_WINDOW);
+        assert_eq!(names, ["mesa"]);
+        assert!(!new.contains("mesa") && new.contains("llvm"));
+        assert!(old.contains("firefox"));
+
+        let (names, new, _) = dedup(Store::DUPLICATE_WINDOW * 2);
+        assert_eq!(names, ["llvm", "mesa"]);
+        assert_eq!(new.len(), 1);
+    }
+
+    #[test]
+    fn match_digit_embedded_names() {
+        const PREFIX: &str = "/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-";
+
+        let parse = |names: &[(&str, u32)]| {
+            let stores = names.iter().enumerate().map(|(id, (name, register_time))| {
+                Store::parse(id as u32, *register_time, format!("{}{}", PREFIX, name)).unwrap()
+            });
+
+            let (unique, shadowed) =
+                Store::partition_unique(stores, Store::DUPLICATE_WINDOW, DedupPolicy::Drop);
+
+            let derivs = unique
+                .into_iter()
+                .map(|store| Derivation {
+                    store,
+                    deps: HashSet::new(),
+                    paths: HashMap::new(),
+                })
+                .collect::<HashSet<_>>();
+
+            (derivs, shadowed.len())
+        };
+
+        let (old, _) = parse(&[
+            ("libusb1-1.0.26", 100),
+            ("libusb1-compat-0.1.7", 100),
+            ("sqlite3-editor-2023.12.1", 100),
+            ("open-3d-viewer-1.0", 100),
+            ("zlib1g-1.3", 100),
+        ]);
+
+        // The versions left behind by the previous update are far enough apart to be deduplicated
+        let (new, shadowed) = parse(&[
+            ("libusb1-1.0.26", 100),
+            ("libusb1-1.0.27", 10_000),
+            ("libusb1-compat-0.1.8", 10_000),
+            ("sqlite3-editor-2024.1.1", 10_000),
+            ("open-3d-viewer-1.0", 100),
+            ("open-3d-viewer-1.1", 10_000),
+            ("zlib1g-1.3", 100),
+        ]);
+
+        assert_eq!(new.len(), old.len());
+        assert_eq!(shadowed, 2);
+
+        let mut diffs = get_package_diffs(&new, &old, DiffOptions::default())
+            .into_iter()
+            .map(|diff| {
+                let pkg = diff.pkg.unwrap();
+                (diff.name, pkg.ver_from, pkg.ver_to)
+            })
+            .collect::<Vec<_>>();
+
+        diffs.sort_unstable();
+
+        assert_eq!(
+            diffs,
+            [
+                ("libusb1".into(), "1.0.26".into(), "1.0.27".into()),
+                ("libusb1-compat".into(), "0.1.7".into(), "0.1.8".into()),
+                ("open-3d-viewer".into(), "1.0".into(), "1.1".into()),
+                (
+                    "sqlite3-editor".into(),
+                    "2023.12.1".into(),
+                    "2024.1.1".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn classify_outcomes() {
+        let counts = |baseline, added, removed, unfiltered, reported| DiffCounts {
+            baseline,
+            added,
+            removed,
+            unfiltered,
+            reported,
+        };
+
+        let cases = [
+            (counts(50, 0, 0, 0, 0), Outcome::Identical),
+            (counts(50, 0, 0, 3, 3), Outcome::Changed),
+            (counts(50, 2, 1, 0, 0), Outcome::Changed),
+            (counts(50, 0, 0, 3, 1), Outcome::Changed),
+            (counts(50, 0, 0, 3, 0), Outcome::Suppressed),
+            (counts(50, 2, 0, 3, 0), Outcome::Suppressed),
+            (counts(0, 0, 0, 0, 0), Outcome::EmptyBaseline),
+            (counts(0, 40, 0, 0, 0), Outcome::EmptyBaseline),
+        ];
+
+        for (counts, expected) in &cases {
+            assert_eq!(counts.outcome(), *expected, "{:?}", counts);
+        }
+
+        assert_eq!(counts(50, 0, 0, 3, 1).hidden(), 2);
+
+        let codes = [
+            Outcome::Identical,
+            Outcome::Changed,
+            Outcome::Suppressed,
+            Outcome::EmptyBaseline,
+        ]
+        .iter()
+        .map(|outcome| outcome.exit_code())
+        .collect::<HashSet<_>>();
+
+        assert_eq!(codes.len(), 4);
+        assert!(!codes.contains(&1), "exit code 1 is for errors");
+    }
+
+    #[test]
+    fn unfiltered_options() {
+        let opts = DiffOptions {
+            scope: DiffScope::PackagesOnly,
+            suffix_as_version: true,
+            local: LocalFilter::NoLocal,
+        };
+
+        assert!(opts.is_filtered());
+        assert!(!opts.unfiltered().is_filtered());
+        assert!(opts.unfiltered().suffix_as_version);
+        assert!(!DiffOptions::default().is_filtered());
+    }
 }
@@ -0,0 +1,326 @@
+use super::diff::StoreDiff;
+use super::{Derivation, Store};
+use crate::nixpkgs;
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+
+/// The package asked for with `--only`, as found in each state.
+#[derive(Debug)]
+pub struct Target<'a> {
+    /// The current store of the package, if it's still installed.
+    pub new: Option<&'a Store>,
+    /// The package in the saved state, if it was there.
+    pub old: Option<&'a Derivation>,
+}
+
+/// Finds the package called `name` in both the current stores and the saved state.
+///
+/// Each state is searched on its own, so a package that was renamed between them is still found as long as both
+/// names match `name`. See `find` for how names are matched. Fails if `name` matches more than one package in
+/// either state, or no package in both.
+pub fn resolve<'a>(
+    name: &str,
+    new: &'a HashSet<Store>,
+    old: &'a HashSet<Derivation>,
+) -> Result<Target<'a>> {
+    let new_name = find(name, new.iter().map(|store| store.name.as_str()))?;
+    let old_name = find(name, old.iter().map(|pkg| pkg.store.name.as_str()))?;
+
+    if new_name.is_none() && old_name.is_none() {
+        return Err(anyhow!(
+            "no package named {} was found in the current or saved state",
+            name
+        ));
+    }
+
+    Ok(Target {
+        new: new_name.and_then(|name| new.get(name)),
+        old: old_name.and_then(|name| old.get(name)),
+    })
+}
+
+/// Returns the one name in `names` that matches `name`.
+///
+/// A name that is exactly `name` is always picked. Otherwise, names are compared regardless of case, separators,
+/// interpreter prefixes such as `python3.11-`, and the version tag of a duplicate such as `@3.96`.
+fn find<'a>(name: &str, names: impl Iterator<Item = &'a str>) -> Result<Option<&'a str>> {
+    let wanted = normalize(name);
+    let mut matches = Vec::new();
+
+    for candidate in names {
+        if candidate == name {
+            return Ok(Some(candidate));
+        }
+
+        if normalize(candidate) == wanted {
+            matches.push(candidate);
+        }
+    }
+
+    match matches.len() {
+        0 | 1 => Ok(matches.pop()),
+        _ => {
+            matches.sort_unstable();
+
+            Err(anyhow!(
+                "{} matches several packages: {}\nplease pass one of them exactly",
+                name,
+                matches.join(", ")
+            ))
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    let name = name.split('@').next().unwrap_or(name);
+    let name = nixpkgs::strip_interpreter_prefix(name).unwrap_or(name);
+    nixpkgs::normalize(name)
+}
+
+/// Everything that changed about a single package and its dependencies between the saved state and now.
+#[derive(Debug)]
+pub struct Drilldown {
+    /// The current store of the package, or `None` if it was removed.
+    pub new: Option<Store>,
+    /// The store of the package in the saved state, or `None` if it was added.
+    pub old: Option<Store>,
+    /// The change to the package's own version, if both states have it.
+    pub pkg: Option<StoreDiff>,
+    /// Every dependency in both states whose version changed, sorted by name.
+    pub deps: Vec<StoreDiff>,
+    /// Every dependency that only the current package has, sorted by name.
+    pub added_deps: Vec<Store>,
+    /// Every dependency that only the saved package has, sorted by name.
+    pub removed_deps: Vec<Store>,
+    /// The number of dependencies that are the same in both states.
+    pub unchanged_deps: usize,
+}
+
+impl Drilldown {
+    /// Diffs `new` against `old` with the same rules as a full diff, but also lists the dependencies that
+    /// were added or removed.
+    pub fn new(
+        new: Option<&Derivation>,
+        old: Option<&Derivation>,
+        suffix_as_version: bool,
+    ) -> Self {
+        let no_deps = HashSet::new();
+
+        let new_deps = new.map_or(&no_deps, |pkg| &pkg.deps);
+        let old_deps = old.map_or(&no_deps, |pkg| &pkg.deps);
+
+        let pkg = match (new, old) {
+            (Some(new), Some(old)) => {
+                StoreDiff::from_store(&new.store, &old.store, suffix_as_version)
+            }
+            _ => None,
+        };
+
+        let mut deps = StoreDiff::from_store_list(new_deps, old_deps, suffix_as_version);
+        deps.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+
+        let only_in = |deps: &HashSet<Store>, other: &HashSet<Store>| {
+            let mut only = deps
+                .iter()
+                .filter(|dep| !other.contains(dep.name.as_str()))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            only.sort_unstable_by_key(Store::key);
+            only
+        };
+
+        let added_deps = only_in(new_deps, old_deps);
+        let removed_deps = only_in(old_deps, new_deps);
+
+        let unchanged_deps = new_deps.len() - added_deps.len() - deps.len();
+
+        Self {
+            new: new.map(|pkg| pkg.store.clone()),
+            old: old.map(|pkg| pkg.store.clone()),
+            pkg,
+            deps,
+            added_deps,
+            removed_deps,
+            unchanged_deps,
+        }
+    }
+
+    /// Returns the name the package has now, or had in the saved state if it was removed.
+    pub fn name(&self) -> &str {
+        self.new
+            .as_ref()
+            .or(self.old.as_ref())
+            .map_or("", |store| store.name.as_str())
+    }
+
+    /// Returns the name the package had in the saved state, if it had a different one.
+    pub fn renamed_from(&self) -> Option<&str> {
+        match (&self.new, &self.old) {
+            (Some(new), Some(old)) if new.name != old.name => Some(&old.name),
+            _ => None,
+        }
+    }
+
+    /// Returns true if nothing about the package or its dependencies changed.
+    pub fn is_empty(&self) -> bool {
+        self.new.is_some()
+            && self.old.is_some()
+            && self.pkg.is_none()
+            && self.renamed_from().is_none()
+            && self.deps.is_empty()
+            && self.added_deps.is_empty()
+            && self.removed_deps.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn store(name: &str) -> Store {
+        Store::parse_stripped(name).unwrap()
+    }
+
+    /// Returns a store named exactly `name`, without any of the parsing rules applied to it.
+    fn named(name: &str, version: &str) -> Store {
+        let mut store = store(&format!("placeholder-{}", version));
+        store.name = name.into();
+        store
+    }
+
+    fn package(name: &str, deps: &[&str]) -> Derivation {
+        Derivation {
+            store: store(name),
+            deps: deps.iter().map(|dep| store(dep)).collect(),
+            paths: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn resolve_targets() {
+        let new = vec![
+            store("firefox-124.0"),
+            store("requests-2.31.0"),
+            named("nss@3.96", "3.96"),
+            named("nss@3.98", "3.98"),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        // A state saved before the ecosystem rules could have a name with the interpreter still in it
+        let old = vec![
+            package("firefox-123.0", &[]),
+            Derivation {
+                store: named("python3.11-requests", "2.30.0"),
+                deps: HashSet::new(),
+                paths: HashMap::new(),
+            },
+            package("Foo_Bar-1.0", &[]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let names = |target: Target| {
+            (
+                target.new.map(|store| store.name.clone()),
+                target.old.map(|pkg| pkg.store.name.clone()),
+            )
+        };
+
+        assert_eq!(
+            names(resolve("firefox", &new, &old).unwrap()),
+            (Some("firefox".into()), Some("firefox".into()))
+        );
+
+        // The saved name only matches once the interpreter prefix is ignored
+        assert_eq!(
+            names(resolve("requests", &new, &old).unwrap()),
+            (Some("requests".into()), Some("python3.11-requests".into()))
+        );
+
+        // Only in one of the states, and matched regardless of case and separators
+        assert_eq!(
+            names(resolve("foo-bar", &new, &old).unwrap()),
+            (None, Some("Foo_Bar".into()))
+        );
+
+        let err = resolve("nss", &new, &old).unwrap_err().to_string();
+        assert!(
+            err.contains("nss matches several packages: nss@3.96, nss@3.98"),
+            "{}",
+            err
+        );
+
+        // An exact name is never ambiguous
+        assert_eq!(
+            names(resolve("nss@3.98", &new, &old).unwrap()),
+            (Some("nss@3.98".into()), None)
+        );
+
+        let err = resolve("chromium", &new, &old).unwrap_err().to_string();
+        assert!(err.contains("no package named chromium"), "{}", err);
+    }
+
+    #[test]
+    fn drill_down_into_deps() {
+        let old = package(
+            "firefox-123.0",
+            &["nss-3.97", "glibc-2.39", "zlib-1.3", "libvpx-1.13.1"],
+        );
+        let new = package(
+            "firefox-124.0",
+            &["nss-3.98", "glibc-2.39", "zlib-1.3.1", "dav1d-1.4.0"],
+        );
+
+        let drilldown = Drilldown::new(Some(&new), Some(&old), false);
+
+        assert_eq!(drilldown.name(), "firefox");
+        assert_eq!(drilldown.renamed_from(), None);
+        assert_eq!(drilldown.pkg.as_ref().unwrap().ver_to, "124.0");
+
+        let changed = drilldown
+            .deps
+            .iter()
+            .map(|dep| {
+                (
+                    dep.name.as_str(),
+                    dep.ver_from.as_str(),
+                    dep.ver_to.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(changed, [("nss", "3.97", "3.98"), ("zlib", "1.3", "1.3.1")]);
+
+        let names = |stores: &[Store]| {
+            stores
+                .iter()
+                .map(|store| store.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(&drilldown.added_deps), ["dav1d"]);
+        assert_eq!(names(&drilldown.removed_deps), ["libvpx"]);
+        assert_eq!(drilldown.unchanged_deps, 1);
+        assert!(!drilldown.is_empty());
+
+        // A package that's gone has every dependency removed
+        let removed = Drilldown::new(None, Some(&old), false);
+
+        assert_eq!(removed.name(), "firefox");
+        assert!(removed.pkg.is_none());
+        assert_eq!(removed.removed_deps.len(), 4);
+        assert!(!removed.is_empty());
+
+        let same = Drilldown::new(Some(&old), Some(&old), false);
+        assert!(same.is_empty());
+        assert_eq!(same.unchanged_deps, 4);
+
+        let renamed = package("firefox-bin-123.0", &[]);
+        let drilldown = Drilldown::new(Some(&renamed), Some(&old), false);
+        assert_eq!(drilldown.renamed_from(), Some("firefox"));
+        assert!(!drilldown.is_empty());
+    }
+}
@@ -0,0 +1,435 @@
+use super::Store;
+use anyhow::{anyhow, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+/// The rules every store is parsed with, set once at startup.
+static RULES: OnceLock<Rules> = OnceLock::new();
+
+/// Sets the rules returned by `rules`.
+///
+/// Only the first call has any effect, so this should be called before any store is parsed.
+pub fn init(rules: Rules) {
+    let _ = RULES.set(rules);
+}
+
+/// Returns the rules set with `init`, or the built-in ones if it was never set.
+pub fn rules() -> &'static Rules {
+    RULES.get_or_init(Rules::default)
+}
+
+/// The built-in rules as the ecosystem, pattern, and rewrite of each one, tried in order.
+const DEFAULT_RULES: [(&str, &str, Option<&str>); 5] = [
+    // OCaml libraries start with the version of the compiler they were built with, such as `ocaml4.14.1-zarith-1.13`
+    ("ocaml", "ocaml{eco}-{rest}", Some("{rest}")),
+    ("ocaml", "ocaml-{eco}{rest}", None),
+    // GHC with packages has the compiler's version in the middle of its name, such as `ghc-9.4.8-with-packages`
+    (
+        "haskell",
+        "ghc-{eco}-with-packages{rest}",
+        Some("ghc-with-packages-{eco}{rest}"),
+    ),
+    ("haskell", "ghc-{eco}{rest}", None),
+    // TeX Live versions start with the year of the release they belong to, such as `texlive-2023.20230401`
+    ("texlive", "texlive{name}-{eco}.{rest}", None),
+];
+
+/// The language or distribution a store belongs to, along with the version of it the store was built for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Ecosystem {
+    pub name: String,
+    pub version: String,
+}
+
+/// A rule as written in the config file.
+///
+/// See `Rule` for how the pattern and rewrite are written.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSpec {
+    pub ecosystem: String,
+    pub pattern: String,
+    #[serde(default)]
+    pub rewrite: Option<String>,
+}
+
+/// A single piece of a pattern or rewrite.
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Text(String),
+    /// A named capture, written as `{name}`.
+    Capture(String),
+}
+
+/// A rule that finds the ecosystem of a store from its name, and rewrites the name so it doesn't change
+/// along with the ecosystem's version.
+///
+/// Patterns are matched against the entire name of a store path, without its hash. `{eco}` captures the
+/// version of the ecosystem, which has to look like a version, while any other `{name}` captures anything
+/// at all. The rewrite can use every capture of the pattern, and is parsed as the name of the store instead.
+/// A rule without a rewrite only tags the stores it matches.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    ecosystem: String,
+    pattern: Vec<Token>,
+    rewrite: Option<Vec<Token>>,
+}
+
+impl Rule {
+    /// The capture that holds the version of the ecosystem.
+    const VERSION_CAPTURE: &'static str = "eco";
+
+    pub fn new(ecosystem: &str, pattern: &str, rewrite: Option<&str>) -> Result<Self> {
+        if ecosystem.is_empty() {
+            return Err(anyhow!("the rule for {} has no ecosystem", pattern));
+        }
+
+        let pattern_tokens = tokenize(pattern)?;
+        let captures = pattern_tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Capture(name) => Some(name.as_str()),
+                Token::Text(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        if !matches!(pattern_tokens.first(), Some(Token::Text(_))) {
+            return Err(anyhow!("pattern {} has to start with text", pattern));
+        }
+
+        if !captures.contains(&Self::VERSION_CAPTURE) {
+            return Err(anyhow!(
+                "pattern {} has no {{{}}} capture for the version of the ecosystem",
+                pattern,
+                Self::VERSION_CAPTURE
+            ));
+        }
+
+        for (i, name) in captures.iter().enumerate() {
+            if captures[..i].contains(name) {
+                return Err(anyhow!("pattern {} captures {{{}}} twice", pattern, name));
+            }
+        }
+
+        // The version of the ecosystem can only contain certain characters, so it's the only capture that
+        // can directly follow or be followed by another one without making the match ambiguous
+        let ambiguous = pattern_tokens.windows(2).any(|pair| match pair {
+            [Token::Capture(x), Token::Capture(y)] => {
+                x != Self::VERSION_CAPTURE && y != Self::VERSION_CAPTURE
+            }
+            _ => false,
+        });
+
+        if ambiguous {
+            return Err(anyhow!(
+                "pattern {} has captures that aren't separated by text",
+                pattern
+            ));
+        }
+
+        let rewrite = match rewrite {
+            Some(rewrite) => {
+                let tokens = tokenize(rewrite)?;
+
+                for token in &tokens {
+                    if let Token::Capture(name) = token {
+                        if !captures.contains(&name.as_str()) {
+                            return Err(anyhow!(
+                                "rewrite {} uses {{{}}}, which pattern {} doesn't capture",
+                                rewrite,
+                                name,
+                                pattern
+                            ));
+                        }
+                    }
+                }
+
+                Some(tokens)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            ecosystem: ecosystem.into(),
+            pattern: pattern_tokens,
+            rewrite,
+        })
+    }
+
+    /// Returns the rewritten `name` and the ecosystem it belongs to if `name` matches the rule.
+    pub fn apply(&self, name: &str) -> Option<(String, Ecosystem)> {
+        let mut captures = Vec::new();
+
+        if !match_tokens(&self.pattern, name, &mut captures) {
+            return None;
+        }
+
+        let capture = |wanted: &str| {
+            captures
+                .iter()
+                .find(|(name, _)| *name == wanted)
+                .map_or("", |(_, value)| *value)
+        };
+
+        let rewritten = match &self.rewrite {
+            Some(tokens) => tokens
+                .iter()
+                .map(|token| match token {
+                    Token::Text(text) => text.as_str(),
+                    Token::Capture(name) => capture(name),
+                })
+                .collect(),
+            None => name.into(),
+        };
+
+        let ecosystem = Ecosystem {
+            name: self.ecosystem.clone(),
+            version: capture(Self::VERSION_CAPTURE).into(),
+        };
+
+        Some((rewritten, ecosystem))
+    }
+}
+
+/// Every rule stores are parsed with, which are tried in order until one matches.
+#[derive(Clone, Debug)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    /// Returns the built-in rules, preceded by `extra` so they take priority over them.
+    pub fn with_extra(extra: &[RuleSpec]) -> Result<Self> {
+        let mut rules = extra
+            .iter()
+            .map(|spec| Rule::new(&spec.ecosystem, &spec.pattern, spec.rewrite.as_deref()))
+            .collect::<Result<Vec<_>>>()?;
+
+        rules.extend(Self::default().0);
+        Ok(Self(rules))
+    }
+
+    /// Returns the rewritten `name` and the ecosystem it belongs to, according to the first rule it matches.
+    pub fn apply(&self, name: &str) -> Option<(String, Ecosystem)> {
+        self.0.iter().find_map(|rule| rule.apply(name))
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        let rules = DEFAULT_RULES
+            .iter()
+            .map(|&(ecosystem, pattern, rewrite)| {
+                Rule::new(ecosystem, pattern, rewrite).expect("built-in rule is invalid")
+            })
+            .collect();
+
+        Self(rules)
+    }
+}
+
+/// Splits `template` into its text and captures.
+fn tokenize(template: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = template;
+
+    while !rest.is_empty() {
+        match rest.find(['{', '}']) {
+            Some(0) if rest.starts_with('{') => {
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| anyhow!("{} has an unclosed {{", template))?;
+
+                let name = &rest[1..end];
+
+                if name.is_empty() || !name.bytes().all(|b| b.is_ascii_lowercase() || b == b'_') {
+                    return Err(anyhow!(
+                        "{} has an invalid capture name {:?}, which can only contain a-z and _",
+                        template,
+                        name
+                    ));
+                }
+
+                tokens.push(Token::Capture(name.into()));
+                rest = &rest[end + 1..];
+            }
+            Some(0) => return Err(anyhow!("{} has an unopened }}", template)),
+            Some(start) => {
+                tokens.push(Token::Text(rest[..start].into()));
+                rest = &rest[start..];
+            }
+            None => {
+                tokens.push(Token::Text(rest.into()));
+                rest = "";
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Returns true if all of `input` matches `tokens`, and records the value of each capture in `captures`.
+///
+/// The version of the ecosystem is matched with as many characters as possible, and every other capture with as few.
+fn match_tokens<'a>(
+    tokens: &'a [Token],
+    input: &'a str,
+    captures: &mut Vec<(&'a str, &'a str)>,
+) -> bool {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return input.is_empty(),
+    };
+
+    let name = match token {
+        Token::Text(text) => {
+            return match input.strip_prefix(text.as_str()) {
+                Some(input) => match_tokens(rest, input, captures),
+                None => false,
+            }
+        }
+        Token::Capture(name) => name.as_str(),
+    };
+
+    let is_version = name == Rule::VERSION_CAPTURE;
+
+    let mut ends = (0..=input.len())
+        .filter(|&end| input.is_char_boundary(end))
+        .collect::<Vec<_>>();
+
+    if is_version {
+        ends.reverse();
+    }
+
+    for end in ends {
+        let value = &input[..end];
+
+        if is_version && !Store::is_version_str(value.as_bytes()) {
+            continue;
+        }
+
+        captures.push((name, value));
+
+        if match_tokens(rest, &input[end..], captures) {
+            return true;
+        }
+
+        captures.pop();
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply_built_in_rules() {
+        let eco = |name: &str, version: &str| Ecosystem {
+            name: name.into(),
+            version: version.into(),
+        };
+
+        let cases = [
+            (
+                "ocaml4.14.1-zarith-1.13",
+                Some(("zarith-1.13", eco("ocaml", "4.14.1"))),
+            ),
+            (
+                "ocaml5.1.1-zarith-1.13-dev",
+                Some(("zarith-1.13-dev", eco("ocaml", "5.1.1"))),
+            ),
+            (
+                "ocaml-4.14.1",
+                Some(("ocaml-4.14.1", eco("ocaml", "4.14.1"))),
+            ),
+            (
+                "ghc-9.4.8-with-packages",
+                Some(("ghc-with-packages-9.4.8", eco("haskell", "9.4.8"))),
+            ),
+            (
+                "ghc-9.4.8-doc",
+                Some(("ghc-9.4.8-doc", eco("haskell", "9.4.8"))),
+            ),
+            (
+                "texlive-2023.20230401",
+                Some(("texlive-2023.20230401", eco("texlive", "2023"))),
+            ),
+            (
+                "texlive-combined-full-2023.20230401",
+                Some((
+                    "texlive-combined-full-2023.20230401",
+                    eco("texlive", "2023"),
+                )),
+            ),
+            ("aeson-2.1.2.1", None),
+            ("ghc-paths-0.1.0.12", None),
+            ("ocamlfind-1.9.6", None),
+            ("texlive-2023-env", None),
+        ];
+
+        let rules = Rules::default();
+
+        for (name, expected) in cases {
+            let expected = expected.map(|(rewritten, eco)| (rewritten.to_string(), eco));
+            assert_eq!(rules.apply(name), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn extra_rules_take_priority() {
+        let spec = |pattern: &str, rewrite: Option<&str>| RuleSpec {
+            ecosystem: "python".into(),
+            pattern: pattern.into(),
+            rewrite: rewrite.map(Into::into),
+        };
+
+        let rules = Rules::with_extra(&[
+            spec("python{eco}-{rest}", Some("python-{rest}")),
+            spec("ocaml{eco}-{rest}", None),
+        ])
+        .unwrap();
+
+        let (name, eco) = rules.apply("python3.11-requests-2.31.0").unwrap();
+        assert_eq!(name, "python-requests-2.31.0");
+        assert_eq!(
+            (eco.name.as_str(), eco.version.as_str()),
+            ("python", "3.11")
+        );
+
+        let (name, eco) = rules.apply("ocaml4.14.1-zarith-1.13").unwrap();
+        assert_eq!(
+            name, "ocaml4.14.1-zarith-1.13",
+            "overrides the built-in rule"
+        );
+        assert_eq!(eco.name, "python");
+
+        assert!(
+            rules.apply("ghc-9.4.8").is_some(),
+            "keeps the built-in rules"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_rules() {
+        let invalid = [
+            ("", "ocaml{eco}-{rest}", None),
+            ("ocaml", "ocaml-{rest}", None),
+            ("ocaml", "{eco}-ocaml", None),
+            ("ocaml", "ocaml{eco}-{name}{rest}", None),
+            ("ocaml", "ocaml{eco}-{eco}", None),
+            ("ocaml", "ocaml{eco-{rest}", None),
+            ("ocaml", "ocaml}{eco}-{rest}", None),
+            ("ocaml", "ocaml{Eco}-{rest}", None),
+            ("ocaml", "ocaml{eco}-{rest}", Some("{name}")),
+        ];
+
+        for (ecosystem, pattern, rewrite) in invalid {
+            assert!(
+                Rule::new(ecosystem, pattern, rewrite).is_err(),
+                "{} {:?}",
+                pattern,
+                rewrite
+            );
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use super::database::{RefEnd, SystemDatabase};
+use super::Store;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::str;
+
+/// The maximum number of chains that are found when looking for every chain to a path.
+pub const MAX_CHAINS: usize = 50;
+
+/// A path along a chain of references.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Link {
+    pub id: i32,
+    /// The name of the path without its store directory and hash.
+    pub name: String,
+    /// What `Store::parse` returns for the path, which is `None` for paths without a version.
+    pub store: Option<Store>,
+}
+
+/// The chains of references that make a root path depend on a set of target paths.
+#[derive(Debug)]
+pub struct Explanation {
+    /// Every chain that was found, each going from the root down to one of the targets.
+    ///
+    /// Chains that share a beginning are next to each other, so they can be shown as a tree.
+    pub chains: Vec<Vec<Link>>,
+    /// True if there were more than `MAX_CHAINS` chains, and the rest were left out.
+    pub truncated: bool,
+}
+
+impl Explanation {
+    /// Finds out why the path with the id of `root` depends on any of the paths in `targets`.
+    ///
+    /// The referrers of the targets are walked breadth-first until the root is reached, so the chain that is
+    /// found is as short as possible. When `all` is set, every chain without a repeated path is found instead,
+    /// up to `MAX_CHAINS` of them. Self-references and cycles are never followed.
+    ///
+    /// An error is returned if more than `max_nodes` paths have to be walked.
+    pub fn find(
+        db: &SystemDatabase,
+        root: i32,
+        targets: &[i32],
+        all: bool,
+        max_nodes: usize,
+    ) -> Result<Self> {
+        let walk = Walk::new(db, root, targets, all, max_nodes)?;
+
+        let (chains, truncated) = if all {
+            walk.every_chain(root)
+        } else {
+            (walk.shortest_chain(root).into_iter().collect(), false)
+        };
+
+        let ids = chains.iter().flatten().copied().collect::<Vec<_>>();
+        let links = links_of(db, &ids)?;
+
+        let chains = chains
+            .into_iter()
+            .map(|chain| chain.into_iter().map(|id| links[&id].clone()).collect())
+            .collect();
+
+        Ok(Self { chains, truncated })
+    }
+}
+
+/// Returns the id of `store_path` in `db`, or `None` if it isn't a valid path.
+pub fn id_of(db: &SystemDatabase, store_path: &Path) -> Result<Option<i32>> {
+    use super::database::schema::ValidPaths::dsl::*;
+    use diesel::prelude::*;
+
+    let path_str = store_path
+        .to_str()
+        .ok_or_else(|| anyhow!("{} is not valid UTF-8", store_path.display()))?;
+
+    ValidPaths
+        .filter(path.eq(path_str))
+        .select(id)
+        .get_result::<i32>(db.conn())
+        .optional()
+        .with_context(|| anyhow!("failed to look up {}", store_path.display()))
+}
+
+/// Every path that leads to a target, found by walking referrers breadth-first.
+struct Walk {
+    targets: HashSet<i32>,
+    /// The path each path was first discovered from, which is one step closer to a target.
+    next: HashMap<i32, i32>,
+    /// The references of every walked path that also lead to a target, sorted so chains are found in a stable order.
+    references: HashMap<i32, Vec<i32>>,
+}
+
+impl Walk {
+    /// Walks the referrers of `targets` until `root` is found, or until every referrer was walked if `all` is set.
+    fn new(
+        db: &SystemDatabase,
+        root: i32,
+        targets: &[i32],
+        all: bool,
+        max_nodes: usize,
+    ) -> Result<Self> {
+        let mut walk = Self {
+            targets: targets.iter().copied().collect(),
+            next: HashMap::new(),
+            references: HashMap::new(),
+        };
+
+        let mut visited = walk.targets.clone();
+        let mut frontier = targets.to_vec();
+
+        while !frontier.is_empty() && (all || !visited.contains(&root)) {
+            let mut next = Vec::new();
+
+            for (referrer, reference) in db.refs(&frontier, RefEnd::Reference)? {
+                if referrer == reference {
+                    continue;
+                }
+
+                // Only the chains found when walking every referrer need more than the first reference
+                if all {
+                    walk.references.entry(referrer).or_default().push(reference);
+                }
+
+                if !visited.insert(referrer) {
+                    continue;
+                }
+
+                if visited.len() > max_nodes {
+                    return Err(anyhow!("more than {} paths lead to the package", max_nodes));
+                }
+
+                walk.next.insert(referrer, reference);
+                next.push(referrer);
+            }
+
+            frontier = next;
+        }
+
+        for references in walk.references.values_mut() {
+            references.sort_unstable();
+            references.dedup();
+        }
+
+        Ok(walk)
+    }
+
+    fn shortest_chain(&self, root: i32) -> Option<Vec<i32>> {
+        let mut chain = vec![root];
+        let mut id = root;
+
+        while !self.targets.contains(&id) {
+            id = *self.next.get(&id)?;
+            chain.push(id);
+        }
+
+        Some(chain)
+    }
+
+    /// Returns every chain from `root` to a target that doesn't visit a path twice, up to `MAX_CHAINS` of them,
+    /// and whether any were left out.
+    fn every_chain(&self, root: i32) -> (Vec<Vec<i32>>, bool) {
+        let mut chains = Vec::new();
+        let mut truncated = false;
+
+        let mut chain = vec![root];
+        let mut on_chain = HashSet::new();
+        on_chain.insert(root);
+
+        self.extend(&mut chain, &mut on_chain, &mut chains, &mut truncated);
+        (chains, truncated)
+    }
+
+    fn extend(
+        &self,
+        chain: &mut Vec<i32>,
+        on_chain: &mut HashSet<i32>,
+        chains: &mut Vec<Vec<i32>>,
+        truncated: &mut bool,
+    ) {
+        let last = chain[chain.len() - 1];
+
+        if self.targets.contains(&last) {
+            if chains.len() < MAX_CHAINS {
+                chains.push(chain.clone());
+            } else {
+                *truncated = true;
+            }
+
+            return;
+        }
+
+        let references = match self.references.get(&last) {
+            Some(references) => references,
+            None => return,
+        };
+
+        for &reference in references {
+            if *truncated {
+                return;
+            }
+
+            // Following a path that is already on the chain would go around a cycle
+            if !on_chain.insert(reference) {
+                continue;
+            }
+
+            chain.push(reference);
+            self.extend(chain, on_chain, chains, truncated);
+            chain.pop();
+            on_chain.remove(&reference);
+        }
+    }
+}
+
+/// Looks up the path of every id in `ids`.
+fn links_of(db: &SystemDatabase, ids: &[i32]) -> Result<HashMap<i32, Link>> {
+    let mut links = HashMap::with_capacity(ids.len());
+
+    for (id, path, reg) in db.paths(ids)? {
+        let name = Store::strip_prefix(path.as_bytes())
+            .and_then(|name| str::from_utf8(name).ok())
+            .unwrap_or(&path)
+            .to_string();
+
+        let link = Link {
+            id,
+            name,
+            store: Store::parse(id as u32, reg as u32, &path),
+        };
+
+        links.insert(id, link);
+    }
+
+    Ok(links)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::database::fixture;
+
+    fn db_with_paths(num: i32) -> SystemDatabase {
+        let db = fixture::empty();
+
+        for id in 1..=num {
+            fixture::add_path(&db, id, &format!("path{}-1.0", id), 0);
+        }
+
+        db
+    }
+
+    fn ids(explanation: &Explanation) -> Vec<Vec<i32>> {
+        explanation
+            .chains
+            .iter()
+            .map(|chain| chain.iter().map(|link| link.id).collect())
+            .collect()
+    }
+
+    #[test]
+    fn explain_shortest_chain() {
+        let db = db_with_paths(6);
+
+        // 1 reaches 6 through 2 -> 3 -> 4 -> 6, and more directly through 5 -> 6
+        for (referrer, reference) in &[(1, 2), (2, 3), (3, 4), (4, 6), (1, 5), (5, 6), (6, 6)] {
+            fixture::add_ref(&db, *referrer, *reference);
+        }
+
+        let explanation = Explanation::find(&db, 1, &[6], false, 100).unwrap();
+        assert_eq!(ids(&explanation), vec![vec![1, 5, 6]]);
+        assert!(!explanation.truncated);
+
+        let link = &explanation.chains[0][2];
+        assert_eq!(link.name, "path6-1.0");
+        assert_eq!(link.store.as_ref().unwrap().name, "path6");
+
+        // The root can also be a target
+        let explanation = Explanation::find(&db, 6, &[6], false, 100).unwrap();
+        assert_eq!(ids(&explanation), vec![vec![6]]);
+
+        let explanation = Explanation::find(&db, 5, &[4], false, 100).unwrap();
+        assert!(explanation.chains.is_empty());
+    }
+
+    #[test]
+    fn explain_every_chain() {
+        let db = db_with_paths(5);
+
+        // 2 and 3 reference each other, and 5 is referenced by both
+        for (referrer, reference) in &[(1, 2), (1, 3), (2, 3), (3, 2), (2, 5), (3, 5), (1, 4)] {
+            fixture::add_ref(&db, *referrer, *reference);
+        }
+
+        let explanation = Explanation::find(&db, 1, &[5], true, 100).unwrap();
+        assert_eq!(
+            ids(&explanation),
+            vec![
+                vec![1, 2, 3, 5],
+                vec![1, 2, 5],
+                vec![1, 3, 2, 5],
+                vec![1, 3, 5]
+            ]
+        );
+        assert!(!explanation.truncated);
+    }
+
+    #[test]
+    fn explain_truncated_chains() {
+        let num = MAX_CHAINS as i32 + 2;
+        let db = db_with_paths(num + 1);
+
+        for id in 2..=num {
+            fixture::add_ref(&db, 1, id);
+            fixture::add_ref(&db, id, num + 1);
+        }
+
+        let explanation = Explanation::find(&db, 1, &[num + 1], true, 1000).unwrap();
+        assert_eq!(explanation.chains.len(), MAX_CHAINS);
+        assert!(explanation.truncated);
+
+        let err = Explanation::find(&db, 1, &[num + 1], true, 10).unwrap_err();
+        assert!(err.to_string().contains("more than 10 paths"), "{}", err);
+    }
+}
@@ -0,0 +1,245 @@
+use super::Store;
+use serde_derive::{Deserialize, Serialize};
+
+/// Store names that are representative of how store paths are parsed, including every tricky case the
+/// parser has had to handle. Parsing them with two different versions shows whether the versions would
+/// split the same store path differently.
+pub const CORPUS: &[&str] = &[
+    "fix-static.patch",
+    "some-deriv.drv",
+    "dash-edge-case-",
+    "glxinfo-8.4.0",
+    "pcre-8.42",
+    "dxvk-v1.4.6",
+    "dxvk-v1.4.6-bin",
+    "dxvk-c47095a8dcfa4c376d8e9c4276865b7f298137d8",
+    "rpcs3-9165-8ca53f9",
+    "single-version-8",
+    "single-4",
+    "wine-wow-4.21-staging",
+    "wine-wow-4.0-rc5-staging",
+    "ffmpeg-3.4.5-bin",
+    "vulkan-loader-1.1.85",
+    "vpnc-0.5.3-post-r550",
+    "gcc-13.2.0-lib64",
+    "glibc-2.39-dev-bin",
+    "linux-headers-6.6-dev",
+    "hello-2.12-out-2",
+    "mesa-24.0.1-dev-3",
+    "openssl-3.2.0-rc2",
+    "openssl-3.2.0-rc2-bin",
+    "perl-5.38.2-2",
+    "tzdata-2021_03",
+    "cargo-about-1.0.0+build.5",
+    "cargo-about-1.0.0+build.5-bin",
+    "steam-runtime-2019-02-15",
+    "only-lib64",
+    "lib64",
+    "no-version-dev-bin",
+    "ocaml4.14.1-zarith-1.13",
+    "ocaml5.1.1-zarith-1.13-dev",
+    "ocaml-4.14.1",
+    "ghc-9.4.8",
+    "ghc-9.4.8-doc",
+    "ghc-9.4.8-with-packages",
+    "ghc-paths-0.1.0.12",
+    "texlive-combined-full-2023.20230401",
+    "python3.11-requests-2.31.0",
+    "nixos-system-host-24.05.20240101.abcdef0",
+    "firefox-unwrapped-121.0",
+    "gtk+3-3.24.41-dev",
+    "libusb1-1.0.27",
+    "libusb1-compat-0.1.8",
+    "open-3d-viewer-1.0",
+    "hyprland-0-unstable-2024-05-01",
+];
+
+/// How a version of the parser parses every entry of `CORPUS`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ParserFingerprint {
+    /// A hash over every parse in `parses`.
+    pub hash: u64,
+    /// Every entry of the corpus along with how it was parsed, in the order of the corpus.
+    pub parses: Vec<(String, String)>,
+}
+
+/// An entry of the corpus that two versions of the parser parse differently.
+#[derive(Debug, PartialEq)]
+pub struct ParseChange {
+    pub entry: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl ParserFingerprint {
+    /// Returns the fingerprint of the parser this executable was built with.
+    pub fn current() -> Self {
+        Self::of(|name| describe(Store::parse_stripped(name).as_ref()))
+    }
+
+    /// Returns the fingerprint of a parser that describes each entry of the corpus with `parse`.
+    fn of<F>(parse: F) -> Self
+    where
+        F: Fn(&str) -> String,
+    {
+        let parses = CORPUS
+            .iter()
+            .map(|&entry| (entry.to_string(), parse(entry)))
+            .collect::<Vec<_>>();
+
+        let hash = parses.iter().fold(FNV_OFFSET, |hash, (entry, parse)| {
+            [entry.as_bytes(), &[0], parse.as_bytes(), &[0]]
+                .iter()
+                .fold(hash, |hash, bytes| fnv(hash, bytes))
+        });
+
+        Self { hash, parses }
+    }
+
+    /// Returns every entry of the corpus that `old` parses differently from this fingerprint.
+    ///
+    /// Entries that are only in one of the corpora are left out, since a newer corpus may have gained entries
+    /// without the parser changing at all.
+    pub fn changes_since(&self, old: &Self) -> Vec<ParseChange> {
+        if self.hash == old.hash {
+            return Vec::new();
+        }
+
+        self.parses
+            .iter()
+            .filter_map(|(entry, new)| {
+                let (_, old) = old
+                    .parses
+                    .iter()
+                    .find(|(old_entry, _)| old_entry == entry)?;
+
+                if old == new {
+                    return None;
+                }
+
+                Some(ParseChange {
+                    entry: entry.clone(),
+                    old: old.clone(),
+                    new: new.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Describes how a store was parsed, such as `ffmpeg 3.4.5 {bin}`.
+fn describe(store: Option<&Store>) -> String {
+    let store = match store {
+        Some(store) => store,
+        None => return "not parsed".into(),
+    };
+
+    let mut desc = format!("{} {}", store.name, store.version);
+
+    if let Some(suffix) = &store.suffix {
+        desc.push_str(&format!(" {{{}}}", suffix));
+    }
+
+    if let Some(ecosystem) = &store.ecosystem {
+        desc.push_str(&format!(" [{} {}]", ecosystem.name, ecosystem.version));
+    }
+
+    desc
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Hashes `bytes` with FNV-1a, which is used instead of the standard library's hasher as its output
+/// can change between Rust versions.
+pub fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stable_fingerprint() {
+        let fingerprint = ParserFingerprint::current();
+
+        assert_eq!(fingerprint, ParserFingerprint::current());
+        assert_eq!(fingerprint.parses.len(), CORPUS.len());
+        assert!(fingerprint.changes_since(&fingerprint).is_empty());
+
+        let parse = |entry| {
+            fingerprint
+                .parses
+                .iter()
+                .find(|(name, _)| name == entry)
+                .map(|(_, parse)| parse.as_str())
+        };
+
+        assert_eq!(parse("ffmpeg-3.4.5-bin"), Some("ffmpeg 3.4.5 {bin}"));
+        assert_eq!(
+            parse("ocaml4.14.1-zarith-1.13"),
+            Some("zarith 1.13 [ocaml 4.14.1]")
+        );
+        assert_eq!(parse("no-version-dev-bin"), Some("not parsed"));
+    }
+
+    #[test]
+    fn detect_perturbed_parse_rules() {
+        let current = ParserFingerprint::current();
+
+        // A parser that no longer knows bin is an output keeps it in the version instead
+        let perturbed = ParserFingerprint::of(|name| {
+            let mut store = Store::parse_stripped(name);
+
+            if let Some(store) = store
+                .as_mut()
+                .filter(|store| store.suffix.as_deref() == Some("bin"))
+            {
+                store.version.push_str("-bin");
+                store.suffix = None;
+            }
+
+            describe(store.as_ref())
+        });
+
+        assert_ne!(perturbed.hash, current.hash);
+
+        let changes = current.changes_since(&perturbed);
+        let entries = changes
+            .iter()
+            .map(|change| change.entry.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            entries,
+            [
+                "dxvk-v1.4.6-bin",
+                "ffmpeg-3.4.5-bin",
+                "openssl-3.2.0-rc2-bin",
+                "cargo-about-1.0.0+build.5-bin"
+            ]
+        );
+
+        assert_eq!(
+            changes[1],
+            ParseChange {
+                entry: "ffmpeg-3.4.5-bin".into(),
+                old: "ffmpeg 3.4.5-bin".into(),
+                new: "ffmpeg 3.4.5 {bin}".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn ignore_new_corpus_entries() {
+        let current = ParserFingerprint::current();
+
+        let mut old = current.clone();
+        old.parses.pop();
+        old.hash ^= 1;
+
+        assert!(current.changes_since(&old).is_empty());
+    }
+}
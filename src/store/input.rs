@@ -0,0 +1,134 @@
+use super::dedup::DedupPolicy;
+use super::{Derivation, Store};
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Store paths read from a file with one path per line, such as the output of `nix-store --gc --print-dead`.
+///
+/// Nothing but the paths themselves is known, so each store is given the number of its line as its id,
+/// and a registration time of 0.
+#[derive(Debug)]
+pub struct InputPaths {
+    stores: Vec<Store>,
+    /// The path on each line that was parsed as a store, keyed by line number.
+    paths: HashMap<u32, String>,
+    /// Every line that couldn't be parsed as a package, along with its line number.
+    pub skipped: Vec<(usize, String)>,
+}
+
+impl InputPaths {
+    /// Reads the store paths listed in the file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| anyhow!("failed to read store paths from {}", path.display()))?;
+
+        Ok(Self::parse(&contents))
+    }
+
+    /// Parses every line of `contents` as a store path, skipping blank lines.
+    fn parse(contents: &str) -> Self {
+        let mut stores = Vec::new();
+        let mut paths = HashMap::new();
+        let mut skipped = Vec::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let line_num = index + 1;
+
+            match Store::parse(line_num as u32, 0, line) {
+                Some(store) => {
+                    stores.push(store);
+                    paths.insert(line_num as u32, line.to_string());
+                }
+                None => skipped.push((line_num, line.to_string())),
+            }
+        }
+
+        Self {
+            stores,
+            paths,
+            skipped,
+        }
+    }
+
+    /// Returns the path of the store with the given `id`, which is the number of the line it was read from.
+    pub fn path_of(&self, id: u32) -> Option<&str> {
+        self.paths.get(&id).map(String::as_str)
+    }
+
+    /// Returns every unique store with duplicates resolved by `policy`, along with every store that was left out of them.
+    ///
+    /// Every store has the same registration time, so any name listed with differing versions is in conflict.
+    pub fn stores(&self, policy: DedupPolicy) -> (HashSet<Store>, Vec<Store>) {
+        Store::partition_unique(self.stores.iter().cloned(), Store::DUPLICATE_WINDOW, policy)
+    }
+
+    /// Returns the derivations of `stores`, which have no dependencies since the references of the paths aren't known.
+    pub fn derivations(stores: HashSet<Store>) -> HashSet<Derivation> {
+        stores
+            .into_iter()
+            .map(|store| Derivation {
+                store,
+                deps: HashSet::new(),
+                paths: HashMap::new(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PREFIX: &str = "/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-";
+
+    #[test]
+    fn parse_input_paths() {
+        let contents = [
+            format!("{}firefox-121.0", PREFIX),
+            String::new(),
+            format!("  {}zsh-5.9\r", PREFIX),
+            format!("{}nss-3.96", PREFIX),
+            format!("{}nss-3.98", PREFIX),
+            format!("{}lib64", PREFIX),
+            "firefox-122.0".into(),
+        ]
+        .join("\n");
+
+        let input = InputPaths::parse(&contents);
+
+        assert_eq!(
+            input.skipped,
+            [(6, format!("{}lib64", PREFIX)), (7, "firefox-122.0".into())]
+        );
+
+        let (stores, shadowed) = input.stores(DedupPolicy::Drop);
+
+        let mut kept = stores
+            .iter()
+            .map(|store| (store.id, store.name.as_str(), store.version.as_str()))
+            .collect::<Vec<_>>();
+
+        kept.sort_unstable();
+
+        // Both versions of nss were registered at the same time, so neither can be told apart
+        assert_eq!(kept, [(1, "firefox", "121.0"), (3, "zsh", "5.9")]);
+        assert_eq!(shadowed.len(), 2);
+
+        assert_eq!(
+            input.path_of(3),
+            Some(format!("{}zsh-5.9", PREFIX).as_str())
+        );
+        assert_eq!(input.path_of(6), None);
+
+        let derivs = InputPaths::derivations(stores);
+        assert!(derivs.iter().all(|deriv| deriv.deps.is_empty()));
+    }
+}
@@ -1,14 +1,47 @@
+pub mod budget;
+pub mod cache;
+pub mod closure;
 pub mod database;
+pub mod dedup;
 pub mod diff;
+pub mod drilldown;
+pub mod ecosystem;
+pub mod explain;
+pub mod fingerprint;
+pub mod input;
+pub mod outputs;
+pub mod remote;
+pub mod scan;
+pub mod toplevel;
+pub mod trace;
+pub mod verify;
+pub mod version;
+pub mod waves;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use budget::Budget;
+use closure::{Closure, ClosureStats};
 use database::SystemDatabase;
+use dedup::DedupPolicy;
+use diesel::sqlite::Sqlite;
+use ecosystem::Ecosystem;
 use serde_derive::{Deserialize, Serialize};
 use smallvec::SmallVec;
-use std::collections::HashSet;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::iter;
+use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, Eq, Serialize, Deserialize)]
+/// The name, version, and suffix of a store, which is what identifies it across states, since states don't keep
+/// the hashes of their paths.
+pub type StoreKey = (String, String, Option<String>);
+
+/// The phase a budget is cut short in when scanning the stores of the system.
+pub const SCANNING_STORES: &str = "scanning stores";
+
+#[derive(Clone, Debug, Eq, Serialize, Deserialize)]
 pub struct Store {
     /// The store's unique id.
     /// Note that this cannot be used to identify a store persisently.
@@ -18,94 +51,110 @@ pub struct Store {
     /// The store's version.
     pub version: String,
     /// The suffix of the store's name.
-    /// This can either be the derivation's output type, or a special variant of the store.
+    /// This can either be the derivation's output type(s), such as `bin` or `dev-bin`, or a special variant of the store.
     pub suffix: Option<String>,
     /// The epoch time the store was registered on the system.
     pub register_time: u32,
+    /// The path of the derivation that produced the store, if it is known.
+    /// This is only retrieved for top-level stores, and not their dependencies.
+    pub deriver: Option<String>,
+    /// Whether the store was built on this machine, according to the `ultimate` column of the Nix database.
+    ///
+    /// A value of 1 means it was built locally and 0 means it was substituted or imported, while `NULL`
+    /// is treated as unknown since not every version of Nix fills in the column for substituted paths.
+    pub locally_built: Option<bool>,
+    /// The number of other paths that reference the store, if it was counted.
+    ///
+    /// This is only counted for top-level stores scanned from the Nix database. It changes whenever paths
+    /// referencing the store are added or collected, so it isn't saved with a state.
+    #[serde(skip)]
+    pub referrer_count: Option<u32>,
+    /// The ecosystem the store was built for, if its name matched one of the ecosystem rules.
+    ///
+    /// The name of the store no longer contains the version of the ecosystem, so updating it doesn't
+    /// make every store built for it look like a different package.
+    pub ecosystem: Option<Ecosystem>,
 }
 
 impl Store {
+    /// Parses the name, version, and suffix of the store at `path`, applying the ecosystem rules to its name.
     pub fn parse<P>(id: u32, register_time: u32, path: P) -> Option<Self>
     where
         P: AsRef<str>,
     {
-        const DELIMETER: u8 = b'-';
-
-        let path = Self::strip_prefix(path.as_ref().as_bytes())?;
+        let name = Self::strip_prefix(path.as_ref().as_bytes())?;
 
-        // Get all of the indices for our delimeter
-        let fragments = path
-            .iter()
-            .enumerate()
-            .filter_map(|(i, &byte)| if byte == DELIMETER { Some(i) } else { None })
-            .collect::<SmallVec<[usize; 4]>>();
+        // This is safe because the prefix is only ever split off at an ASCII character of a &str
+        let name = unsafe { std::str::from_utf8_unchecked(name) };
 
-        match fragments.len() {
-            0 => return None,
-            // Only having one delimiter is usually indicative of a "{name}-{version}" format, so we can
-            // take a fast path here
-            1 => {
-                let version = &path[fragments[0] + 1..];
+        let mut store = Self::parse_stripped(name)?;
+        store.id = id;
+        store.register_time = register_time;
+        Some(store)
+    }
 
-                if !version.iter().any(|b| b.is_ascii_digit()) {
-                    return None;
-                }
+    /// Parses a bare `name-version[-suffix]` string, such as `ffmpeg-3.4.5-bin`, that doesn't come with the
+    /// `/nix/store/{hash}-` prefix of a store path.
+    ///
+    /// The ecosystem rules are applied the same way as with `parse`. The store's id and registration time are both 0.
+    pub fn parse_stripped(name: &str) -> Option<Self> {
+        match Self::apply_ecosystem_rules(name) {
+            Some((rewritten, ecosystem)) => {
+                let mut store = Self::parse_name(0, 0, rewritten.as_bytes())?;
+                store.ecosystem = Some(ecosystem);
+                Some(store)
+            }
+            None => Self::parse_name(0, 0, name.as_bytes()),
+        }
+    }
 
-                let name = &path[..fragments[0]];
-
-                // This is safe because we aren't modifying the path that we received,
-                // and we received the path as a &str
-                let store = unsafe {
-                    Self {
-                        id,
-                        register_time,
-                        name: String::from_utf8_unchecked(name.into()),
-                        version: String::from_utf8_unchecked(version.into()),
-                        suffix: None,
-                    }
-                };
+    /// Returns the name to parse instead of `name` and the ecosystem it belongs to, if `name` matches an
+    /// ecosystem rule and the rewritten name still has a version.
+    fn apply_ecosystem_rules(name: &str) -> Option<(String, Ecosystem)> {
+        ecosystem::rules()
+            .apply(name)
+            .filter(|(rewritten, _)| Layout::of(rewritten.as_bytes()).version_start.is_some())
+    }
 
-                return Some(store);
-            }
-            _ => (),
+    /// Applies the ecosystem rules to a store that was parsed without them, such as one from a state saved before they existed.
+    pub(crate) fn normalize(self) -> Self {
+        if self.ecosystem.is_some() {
+            return self;
         }
 
-        // The suffix is the last fragment if it does not contain any numbers
-        let (suffix, suffix_start) = {
-            let last_frag = fragments[fragments.len() - 1];
-            let slice = &path[last_frag + 1..];
+        let mut name = format!("{}-{}", self.name, self.version);
 
-            if !slice.iter().any(u8::is_ascii_digit) {
-                (Some(slice), last_frag)
-            } else {
-                (None, path.len())
-            }
+        if let Some(suffix) = &self.suffix {
+            name.push('-');
+            name.push_str(suffix);
+        }
+
+        let (rewritten, ecosystem) = match Self::apply_ecosystem_rules(&name) {
+            Some(applied) => applied,
+            None => return self,
         };
 
-        // The version will be all fragments that match `is_version_str`
-        let (version, version_start) = {
-            let mut version = None;
-            let mut version_start = 0;
-            let mut frag_iter = fragments.iter().peekable();
-
-            while let Some(&fragment) = frag_iter.next() {
-                // We need to check for a version string on a per-fragment basis, as
-                // `is_version_str` will disqualify our fragment character
-                let slice = match frag_iter.peek() {
-                    Some(&&next_frag) => &path[fragment + 1..next_frag],
-                    None => &path[fragment + 1..],
-                };
-
-                if !Self::is_version_str(slice) {
-                    continue;
-                }
+        match Self::parse_name(self.id, self.register_time, rewritten.as_bytes()) {
+            Some(parsed) => Self {
+                name: parsed.name,
+                version: parsed.version,
+                suffix: parsed.suffix,
+                ecosystem: Some(ecosystem),
+                ..self
+            },
+            None => self,
+        }
+    }
 
-                version = Some(&path[fragment + 1..suffix_start]);
-                version_start = fragment;
-                break;
-            }
+    /// Parses the name of a store path without its prefix, before any ecosystem rules are applied.
+    fn parse_name(id: u32, register_time: u32, path: &[u8]) -> Option<Self> {
+        let layout = Layout::of(path);
+        let version_start = layout.version_start?;
 
-            (version?, version_start)
+        let suffix = if layout.suffix_start < path.len() {
+            Some(&path[layout.suffix_start + 1..])
+        } else {
+            None
         };
 
         // This is safe because we aren't modifying the path that we received,
@@ -115,15 +164,88 @@ impl Store {
                 id,
                 register_time,
                 name: String::from_utf8_unchecked(path[..version_start].into()),
-                version: String::from_utf8_unchecked(version.into()),
+                version: String::from_utf8_unchecked(
+                    path[version_start + 1..layout.suffix_start].into(),
+                ),
                 suffix: suffix.map(|sfx| String::from_utf8_unchecked(sfx.into())),
+                deriver: None,
+                locally_built: None,
+                referrer_count: None,
+                ecosystem: None,
             }
         };
 
         Some(store)
     }
 
-    fn is_version_str(bytes: &[u8]) -> bool {
+    /// Returns the index of the delimiter that starts the suffix of `path`, or the length of `path` if it doesn't have one.
+    ///
+    /// The suffix is made up of the trailing fragments that are known output names, such as `dev-bin`.
+    /// A numeric fragment is also allowed at the very end if it directly follows a known output name, such as `out-2`.
+    /// If there aren't any trailing output names, the last fragment is the suffix if it does not contain any numbers.
+    /// Whichever of these applied is added to `heuristics`.
+    fn find_suffix_start(
+        path: &[u8],
+        fragments: &[usize],
+        heuristics: &mut SmallVec<[Heuristic; 3]>,
+    ) -> usize {
+        let fragment = |i: usize| {
+            let end = fragments.get(i + 1).copied().unwrap_or(path.len());
+            &path[fragments[i] + 1..end]
+        };
+
+        let last = fragments.len() - 1;
+        let mut start = None;
+        let mut numbered = false;
+
+        for i in (0..=last).rev() {
+            let slice = fragment(i);
+
+            let is_numbered = i == last
+                && i > 0
+                && !slice.is_empty()
+                && slice.iter().all(u8::is_ascii_digit)
+                && Self::is_known_output(fragment(i - 1));
+
+            if !Self::is_known_output(slice) && !is_numbered {
+                break;
+            }
+
+            numbered |= is_numbered;
+            start = Some(fragments[i]);
+        }
+
+        if let Some(start) = start {
+            heuristics.push(Heuristic::KnownOutputs);
+
+            if numbered {
+                heuristics.push(Heuristic::NumberedOutput);
+            }
+
+            return start;
+        }
+
+        if !fragment(last).iter().any(u8::is_ascii_digit) {
+            heuristics.push(Heuristic::UnversionedSuffix);
+            fragments[last]
+        } else {
+            path.len()
+        }
+    }
+
+    /// Returns true if `bytes` is the name of a common derivation output.
+    pub(crate) fn is_known_output(bytes: &[u8]) -> bool {
+        const KNOWN_OUTPUTS: [&[u8]; 10] = [
+            b"out", b"bin", b"dev", b"lib", b"lib64", b"doc", b"man", b"info", b"debug", b"static",
+        ];
+
+        KNOWN_OUTPUTS.contains(&bytes)
+    }
+
+    /// Returns true if `bytes` looks like (part of) a version, which must start with a digit or a `v` followed by one.
+    ///
+    /// This is applied to each fragment between delimiters, so a full version containing a `-` is never matched at once.
+    pub(crate) fn is_version_str(bytes: &[u8]) -> bool {
         let slice = match bytes {
             [b'v', b'0'..=b'9', rest @ ..] => rest,
             [b'0'..=b'9', rest @ ..] => rest,
@@ -132,7 +254,7 @@ impl Store {
 
         slice
             .iter()
-            .all(|c| matches!(c, b'0'..=b'9' | b'.' | b'a'..=b'z' | b'_'))
+            .all(|c| matches!(c, b'0'..=b'9' | b'.' | b'a'..=b'z' | b'_' | b'+'))
     }
 
     pub fn strip_prefix(bytes: &[u8]) -> Option<&[u8]> {
@@ -149,33 +271,304 @@ impl Store {
         // just in case the store path prefix is changed in the future
         let pos = bytes.iter().position(|b| *b == b'-')?;
 
-        if bytes.len() <= pos + 1 {
+        // Hashes never contain a dash, so the first one must directly follow a full hash
+        let hash_start = bytes[..pos]
+            .iter()
+            .rposition(|b| *b == b'/')
+            .map_or(0, |slash| slash + 1);
+
+        if !Self::is_store_hash(&bytes[hash_start..pos]) || bytes.len() <= pos + 1 {
             return None;
         }
 
         Some(&bytes[pos + 1..])
     }
 
-    pub fn all_from_system(db: &SystemDatabase) -> Result<HashSet<Self>> {
+    /// Returns true if `bytes` is a store path hash, which is 32 characters of Nix's base-32 alphabet.
+    fn is_store_hash(bytes: &[u8]) -> bool {
+        const HASH_LEN: usize = 32;
+
+        // Nix's base-32 alphabet leaves out e, o, u, and t
+        bytes.len() == HASH_LEN
+            && bytes
+                .iter()
+                .all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'd' | b'f'..=b'n' | b'p'..=b's' | b'v'..=b'z'))
+    }
+
+    /// Returns every unique top-level store in `db`, with names that have duplicates resolved by `policy`.
+    ///
+    /// If `budget` expires, only the newest stores parsed so far are considered.
+    pub fn all_from_system(
+        db: &SystemDatabase,
+        budget: &Budget,
+        policy: DedupPolicy,
+    ) -> Result<HashSet<Self>> {
+        Self::all_from_system_with_shadowed(db, budget, policy, None).map(|(unique, _)| unique)
+    }
+
+    /// Returns every unique top-level store in `db`, along with every store that was left out of them.
+    ///
+    /// See `partition_unique` for what is left out. If `budget` expires, only the newest stores parsed so far are considered.
+    /// Every path that couldn't be parsed as a store is added to `unparsed`, if given.
+    pub fn all_from_system_with_shadowed(
+        db: &SystemDatabase,
+        budget: &Budget,
+        policy: DedupPolicy,
+        unparsed: Option<&mut Vec<String>>,
+    ) -> Result<(HashSet<Self>, Vec<Self>)> {
+        Self::unique_until(db, None, budget, policy, unparsed)
+    }
+
+    /// Returns every unique top-level store in `db` that was registered at or before `until`, along with
+    /// every store that was left out of them.
+    ///
+    /// This approximates the stores `db` had when `until` was taken. Paths that were garbage collected
+    /// since then can't be recovered, and paths that were re-registered since then are left out. Every path that
+    /// couldn't be parsed as a store is added to `unparsed`, if given.
+    pub fn all_from_system_until(
+        db: &SystemDatabase,
+        until: scan::Watermark,
+        budget: &Budget,
+        policy: DedupPolicy,
+        unparsed: Option<&mut Vec<String>>,
+    ) -> Result<(HashSet<Self>, Vec<Self>)> {
+        Self::unique_until(db, Some(until), budget, policy, unparsed)
+    }
+
+    fn unique_until(
+        db: &SystemDatabase,
+        until: Option<scan::Watermark>,
+        budget: &Budget,
+        policy: DedupPolicy,
+        unparsed: Option<&mut Vec<String>>,
+    ) -> Result<(HashSet<Self>, Vec<Self>)> {
+        // Grouping each store as soon as it's parsed means every store never has to be held twice
+        let mut buckets = HashMap::new();
+        Self::scan_between(db, None, until, budget, unparsed, |store| {
+            dedup::add_to_bucket(&mut buckets, store)
+        })?;
+
+        let resolved = dedup::resolve_buckets(buckets, Self::DUPLICATE_WINDOW, policy);
+        Ok((resolved.unique, resolved.shadowed))
+    }
+
+    /// Returns the store registered at exactly `store_path` in `db`, if there is one that can be parsed.
+    pub fn from_system_path(db: &SystemDatabase, store_path: &Path) -> Result<Option<Self>> {
+        use database::schema::ValidPaths::dsl::*;
+        use diesel::prelude::*;
+
+        let path_str = store_path
+            .to_str()
+            .ok_or_else(|| anyhow!("{} is not valid UTF-8", store_path.display()))?;
+
+        let row = ValidPaths
+            .filter(path.eq(path_str))
+            .select((id, registrationTime, db.ultimate()))
+            .get_result::<(i32, i32, Option<i32>)>(db.conn())
+            .optional()
+            .with_context(|| anyhow!("failed to look up {}", store_path.display()))?;
+
+        Ok(row.and_then(|(store_id, reg, store_ultimate)| {
+            let mut store = Self::parse(store_id as u32, reg as u32, path_str)?;
+            store.locally_built = store_ultimate.map(|value| value != 0);
+            Some(store)
+        }))
+    }
+
+    /// Returns every store in the closure of the path at `root` in `db`, not including `root` itself.
+    ///
+    /// Names with more than one store in the closure are left out, like with dependencies. Fails if `root` isn't a
+    /// valid path, or its closure has more than `max_nodes` paths.
+    pub fn all_in_closure(
+        db: &SystemDatabase,
+        root: &Path,
+        max_nodes: usize,
+    ) -> Result<HashSet<Self>> {
+        let root_id = explain::id_of(db, root)?
+            .ok_or_else(|| anyhow!("{} is not a valid path in the nix database", root.display()))?;
+
+        let closure = Closure::walk(db, root_id, None, max_nodes)
+            .with_context(|| anyhow!("failed to walk references of {}", root.display()))?;
+
+        let stores = Derivation::stores_from_ids(db, &closure.ids)?;
+        Ok(Self::get_unique(stores.into_iter()))
+    }
+
+    /// Returns every top-level store in `db` that was added or re-registered after `since`, or every
+    /// store if it is `None`.
+    ///
+    /// See `scan_between` for how `budget` is used.
+    fn from_system_since(
+        db: &SystemDatabase,
+        since: Option<scan::Watermark>,
+        budget: &Budget,
+    ) -> Result<Vec<Self>> {
+        let mut stores = Vec::new();
+        Self::scan_between(db, since, None, budget, None, |store| stores.push(store))?;
+        Ok(stores)
+    }
+
+    /// Passes every top-level store in `db` that was added or re-registered after `since` and that was
+    /// registered at or before `until` to `each`. Either bound is left out when it is `None`.
+    ///
+    /// Paths are read in batches of `db.batch_size()` rows from the most recently added to the oldest, and each
+    /// batch is parsed before the next one is read, so the raw rows of every path are never held at once.
+    ///
+    /// `budget` is checked before every batch, and the scan stops early if it expired. Paths that can't be parsed
+    /// are added to `unparsed` if it's given, and are skipped otherwise.
+    fn scan_between<F>(
+        db: &SystemDatabase,
+        since: Option<scan::Watermark>,
+        until: Option<scan::Watermark>,
+        budget: &Budget,
+        mut unparsed: Option<&mut Vec<String>>,
+        mut each: F,
+    ) -> Result<()>
+    where
+        F: FnMut(Self),
+    {
+        use database::schema::ValidPaths::dsl::*;
+        use diesel::prelude::*;
+
+        let referrers = Self::referrer_counts(db)?;
+
+        // The id of the last path read, which the next batch starts after
+        let mut last_id = None;
+
+        loop {
+            if budget.expired() {
+                let remaining = Self::scan_query(db, since, until, last_id)
+                    .count()
+                    .get_result::<i64>(db.conn())
+                    .context("failed to count remaining stores in nix database")?;
+
+                budget.cut_short(SCANNING_STORES, remaining as usize);
+                break;
+            }
+
+            // Paging by id rather than with an offset keeps every batch a seek on the primary key
+            let rows = Self::scan_query(db, since, until, last_id)
+                .select((id, path, registrationTime, deriver, db.ultimate()))
+                .order(id.desc())
+                .limit(db.batch_size() as i64)
+                .get_results::<(i32, String, i32, Option<String>, Option<i32>)>(db.conn())
+                .context("failed to get stores from nix database")?;
+
+            let num_rows = rows.len();
+
+            if let Some((last, ..)) = rows.last() {
+                last_id = Some(*last);
+            }
+
+            for (store_id, store_path, reg, store_deriver, store_ultimate) in rows {
+                match Store::parse(store_id as u32, reg as u32, &store_path) {
+                    Some(mut store) => {
+                        store.deriver = store_deriver;
+                        store.locally_built = store_ultimate.map(|value| value != 0);
+                        store.referrer_count = Some(referrers.get(&store_id).copied().unwrap_or(0));
+                        each(store);
+                    }
+                    None => {
+                        if let Some(unparsed) = unparsed.as_deref_mut() {
+                            unparsed.push(store_path);
+                        }
+                    }
+                }
+            }
+
+            if num_rows < db.batch_size() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a query for the paths that could be top-level stores, limited to those added or re-registered
+    /// after `since`, registered at or before `until`, and with an id lower than `before`.
+    fn scan_query<'a>(
+        db: &SystemDatabase,
+        since: Option<scan::Watermark>,
+        until: Option<scan::Watermark>,
+        before: Option<i32>,
+    ) -> database::schema::ValidPaths::BoxedQuery<'a, Sqlite> {
         use database::schema::ValidPaths::dsl::*;
         use diesel::prelude::*;
 
-        let stores = ValidPaths
-            .filter(ca.is_null())
+        let mut query = ValidPaths
             .filter(path.not_like("%-completions"))
             .filter(path.not_like("%.tar.%"))
-            .select((id, path, registrationTime))
-            .order(registrationTime.desc())
-            .get_results::<(i32, String, i32)>(db.conn())
-            .context("failed to get stores from nix database")?
+            .into_boxed();
+
+        if db.columns().ca {
+            query = query.filter(ca.is_null());
+        }
+
+        if let Some(since) = since {
+            query = query.filter(
+                id.gt(since.max_id)
+                    .or(registrationTime.gt(since.max_register_time)),
+            );
+        }
+
+        if let Some(until) = until {
+            query = query.filter(
+                id.le(until.max_id)
+                    .and(registrationTime.le(until.max_register_time)),
+            );
+        }
+
+        if let Some(before) = before {
+            query = query.filter(id.lt(before));
+        }
+
+        query
+    }
+
+    /// Returns the number of paths referencing each path in `db` that is referenced by anything.
+    ///
+    /// Paths referencing themselves aren't counted, since nearly every path does.
+    fn referrer_counts(db: &SystemDatabase) -> Result<HashMap<i32, u32>> {
+        use database::schema::Refs::dsl::*;
+        use diesel::dsl;
+        use diesel::prelude::*;
+        use diesel::sql_types::{BigInt, Integer};
+
+        // Diesel can't select a column alongside an aggregate, so the grouped columns are written by hand
+        let rows = Refs
+            .filter(referrer.ne(reference))
+            .group_by(reference)
+            .select(dsl::sql::<(Integer, BigInt)>("reference, COUNT(*)"))
+            .get_results::<(i32, i64)>(db.conn())
+            .context("failed to count referrers in nix database")?;
+
+        Ok(rows
             .into_iter()
-            .filter_map(|(store_id, store_path, reg)| {
-                Store::parse(store_id as u32, reg as u32, store_path)
-            });
+            .map(|(path_id, count)| (path_id, count as u32))
+            .collect())
+    }
 
-        let unique = Self::get_unique(stores);
+    /// The number of seconds two versions of a store must be registered within to be considered duplicates.
+    pub const DUPLICATE_WINDOW: u32 = 3600;
 
-        Ok(unique)
+    /// Returns what identifies the store across states and machines, unlike its id.
+    pub fn key(&self) -> StoreKey {
+        (self.name.clone(), self.version.clone(), self.suffix.clone())
+    }
+
+    /// Returns true if `a` and `b` are differing versions of the same store that were registered
+    /// less than `window` seconds apart from each other.
+    ///
+    /// See `get_unique` for why this is considered a duplicate.
+    pub fn are_duplicates(a: &Self, b: &Self, window: u32) -> bool {
+        if a.name != b.name || a.version == b.version {
+            return false;
+        }
+
+        let newer_reg_time = a.register_time.max(b.register_time);
+        let older_reg_time = a.register_time.min(b.register_time);
+
+        newer_reg_time - older_reg_time < window
     }
 
     /// Returns a new `HashSet` containing `Store`'s that are not considered to have duplicates.
@@ -187,31 +580,26 @@ impl Store {
     /// false positives, as it likely means that the differing versions are from the same system update,
     /// rather than a separate one. We only want to filter out stores with differing versions from the same
     /// system update since there isn't a way to persistently identify a store across updates outside of its name.
+    ///
+    /// This always uses `DedupPolicy::Drop`, since it's used for dependencies, which are matched by name alone.
     fn get_unique(stores: impl Iterator<Item = Self>) -> HashSet<Self> {
-        let mut unique = HashSet::<Store>::new();
-        let mut duplicates = HashSet::new();
-
-        for store in stores {
-            if duplicates.contains(&store.name) {
-                continue;
-            }
-
-            if let Some(existing) = unique.get(&store) {
-                let newer_reg_time = existing.register_time.max(store.register_time);
-                let older_reg_time = existing.register_time.min(store.register_time);
-
-                if newer_reg_time - older_reg_time < 3600 && existing.version != store.version {
-                    unique.remove(&store);
-                    duplicates.insert(store.name);
-                }
-
-                continue;
-            }
-
-            unique.insert(store);
-        }
+        Self::partition_unique(stores, Self::DUPLICATE_WINDOW, DedupPolicy::Drop).0
+    }
 
-        unique
+    /// Splits `stores` into the stores that are kept after resolving duplicates within `window` with `policy`,
+    /// and every store that was left out of them.
+    ///
+    /// See `dedup::resolve` for how each name is resolved. With `DedupPolicy::Drop`, the stores left out are
+    /// the older versions of a store, as well as every version of a store that had duplicates, so running this
+    /// again over both halves with a wider window gives the same result as if that window had been used in
+    /// the first place. The order of `stores` doesn't matter.
+    pub(crate) fn partition_unique(
+        stores: impl Iterator<Item = Self>,
+        window: u32,
+        policy: DedupPolicy,
+    ) -> (HashSet<Self>, Vec<Self>) {
+        let resolved = dedup::dedup(stores, window, policy);
+        (resolved.unique, resolved.shadowed)
     }
 }
 
@@ -227,51 +615,359 @@ impl PartialEq for Store {
     }
 }
 
-#[derive(Debug, Eq, Serialize, Deserialize)]
+impl Borrow<str> for Store {
+    fn borrow(&self) -> &str {
+        &self.name
+    }
+}
+
+/// A rule of thumb that decided where the parts of a store name are.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Heuristic {
+    /// The name only has one delimiter, so everything after it is the version.
+    FastPath,
+    /// The trailing fragments are the names of derivation outputs, such as `dev-bin`, which make up the suffix.
+    KnownOutputs,
+    /// A number directly follows an output name at the very end, such as `out-2`, and is part of the suffix.
+    NumberedOutput,
+    /// The last fragment doesn't contain any numbers, so it's the suffix, such as `staging`.
+    UnversionedSuffix,
+    /// The version starts with a `v` followed by a digit, such as `v1.4.6`.
+    VersionPrefix,
+    /// An earlier fragment looked like a version, but a fragment that is clearly part of the name followed it,
+    /// so the version starts at a later one, such as the `1.0` in `open-3d-viewer-1.0`.
+    LaterVersion,
+}
+
+/// Where the name, version, and suffix of a store name without its prefix are.
+#[derive(Clone, Debug, PartialEq)]
+struct Layout {
+    /// The index of every delimiter in the name.
+    delimiters: SmallVec<[usize; 4]>,
+    /// The index of the delimiter the version follows, if the name has one.
+    version_start: Option<usize>,
+    /// The index of the delimiter the suffix follows, or the length of the name if it doesn't have one.
+    suffix_start: usize,
+    /// Every heuristic that applied, in the order they were applied in.
+    heuristics: SmallVec<[Heuristic; 3]>,
+}
+
+impl Layout {
+    const DELIMITER: u8 = b'-';
+
+    fn of(path: &[u8]) -> Self {
+        // Get all of the indices for our delimiter
+        let delimiters = path
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &byte)| {
+                if byte == Self::DELIMITER {
+                    Some(i)
+                } else {
+                    None
+                }
+            })
+            .collect::<SmallVec<[usize; 4]>>();
+
+        let mut layout = Self {
+            delimiters,
+            version_start: None,
+            suffix_start: path.len(),
+            heuristics: SmallVec::new(),
+        };
+
+        match layout.delimiters.len() {
+            0 => return layout,
+            // Only having one delimiter is usually indicative of a "{name}-{version}" format, so we can
+            // take a fast path here
+            1 => {
+                let start = layout.delimiters[0];
+                let version = &path[start + 1..];
+
+                layout.heuristics.push(Heuristic::FastPath);
+
+                if version.iter().any(u8::is_ascii_digit) && !Store::is_known_output(version) {
+                    layout.version_start = Some(start);
+                }
+
+                return layout;
+            }
+            _ => (),
+        }
+
+        layout.suffix_start =
+            Store::find_suffix_start(path, &layout.delimiters, &mut layout.heuristics);
+
+        // The version starts at the first fragment that matches `is_version_str`, unless a fragment that is
+        // clearly part of the name comes before the next one that matches, such as `viewer` in `open-3d-viewer-1.0`
+        let mut delimiters = layout.delimiters.iter().peekable();
+        let mut name_follows = false;
+        let mut later = false;
+
+        while let Some(&delimiter) = delimiters.next() {
+            // The suffix takes precedence over the version, so it can never be part of it
+            if delimiter >= layout.suffix_start {
+                break;
+            }
+
+            // We need to check for a version string on a per-fragment basis, as
+            // `is_version_str` will disqualify our delimiter character
+            let slice = match delimiters.peek() {
+                Some(&&next) => &path[delimiter + 1..next],
+                None => &path[delimiter + 1..],
+            };
+
+            if !Store::is_version_str(slice) {
+                name_follows |= layout.version_start.is_some() && Self::is_name_fragment(slice);
+                continue;
+            }
+
+            match layout.version_start {
+                None => layout.version_start = Some(delimiter),
+                Some(_) if name_follows => {
+                    layout.version_start = Some(delimiter);
+                    name_follows = false;
+                    later = true;
+                }
+                Some(_) => (),
+            }
+        }
+
+        if later {
+            layout.heuristics.push(Heuristic::LaterVersion);
+        }
+
+        if let Some(start) = layout.version_start {
+            if path[start + 1..].starts_with(b"v") {
+                layout.heuristics.push(Heuristic::VersionPrefix);
+            }
+        }
+
+        layout
+    }
+
+    /// Returns true if the fragment `bytes` can't be part of a version, as it doesn't contain any numbers and
+    /// isn't a word that versions use, such as `unstable` or `post`.
+    fn is_name_fragment(bytes: &[u8]) -> bool {
+        const VERSION_WORDS: [&[u8]; 9] = [
+            b"unstable",
+            b"pre",
+            b"post",
+            b"alpha",
+            b"beta",
+            b"rc",
+            b"git",
+            b"snapshot",
+            b"nightly",
+        ];
+
+        !bytes.is_empty()
+            && !bytes.iter().any(u8::is_ascii_digit)
+            && !VERSION_WORDS.contains(&bytes)
+            && !Store::is_known_output(bytes)
+    }
+}
+
+#[derive(Clone, Debug, Eq, Serialize, Deserialize)]
 pub struct Derivation {
     pub store: Store,
     pub deps: HashSet<Store>,
+    /// The names of the stores each dependency was discovered through, from the store's direct reference
+    /// down to the dependency's parent, keyed by the name of the dependency.
+    ///
+    /// Direct references and paths that can't be parsed as stores are left out. This is only known right
+    /// after resolving dependencies, so it isn't saved with states.
+    #[serde(skip)]
+    pub paths: HashMap<String, Vec<String>>,
+}
+
+/// Options that control how the dependencies of a derivation are resolved.
+#[derive(Copy, Clone, Debug)]
+pub struct DepOptions {
+    /// The maximum number of references to follow from a store, where 1 only includes its direct references.
+    /// Every reference is followed when this is `None`.
+    pub max_depth: Option<usize>,
+    /// The maximum number of paths a single store's dependencies can have before resolution is aborted.
+    pub max_nodes: usize,
+}
+
+impl Default for DepOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: DepMode::Direct.max_depth(),
+            max_nodes: closure::DEFAULT_MAX_NODES,
+        }
+    }
+}
+
+/// Which of a store's references count as its dependencies.
+///
+/// This changes what a dependency diff means. With `Direct`, a package only shows changes to paths it
+/// refers to itself, such as the libraries it links against. With `Closure`, it also shows changes to
+/// the dependencies of those dependencies, all the way down.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DepMode {
+    /// Only the paths a store directly references.
+    Direct,
+    /// Every path a store transitively references. Every changed package's entire closure has to be
+    /// walked, so this is much slower than `Direct`.
+    Closure,
+}
+
+impl DepMode {
+    /// Returns the `DepOptions::max_depth` that resolves dependencies in this mode.
+    pub fn max_depth(self) -> Option<usize> {
+        match self {
+            Self::Direct => Some(1),
+            Self::Closure => None,
+        }
+    }
+}
+
+impl FromStr for DepMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "direct" => Ok(Self::Direct),
+            "closure" => Ok(Self::Closure),
+            _ => Err(anyhow!(
+                "unknown dependency mode \"{}\", expected direct or closure",
+                value
+            )),
+        }
+    }
 }
 
 impl Derivation {
-    pub fn all_from_stores(stores: HashSet<Store>, db: &SystemDatabase) -> Result<HashSet<Self>> {
-        use database::schema::{Refs::dsl::*, ValidPaths::dsl::*};
-        use diesel::prelude::*;
+    /// Resolves the dependencies of every store in `stores`.
+    ///
+    /// `budget` is checked before each store. Once it expires, the remaining stores are returned
+    /// without any dependencies, which never show up as dependency changes when diffed.
+    pub fn all_from_stores(
+        stores: HashSet<Store>,
+        db: &SystemDatabase,
+        opts: DepOptions,
+        budget: &Budget,
+    ) -> Result<(HashSet<Self>, ClosureStats)> {
+        use diesel::Connection;
 
-        let mut packages = HashSet::with_capacity(stores.len());
+        let num_stores = stores.len();
+        let mut packages = HashSet::with_capacity(num_stores);
+        let mut stats = ClosureStats::default();
 
         db.conn()
-            .transaction::<_, diesel::result::Error, _>(|| {
-                for store in stores {
-                    let is_dependency =
-                        id.eq_any(Refs.filter(referrer.eq(store.id as i32)).select(reference));
-
-                    let all_deps = ValidPaths
-                        .filter(ca.is_null())
-                        .filter(id.ne(store.id as i32))
-                        .filter(is_dependency)
-                        .select((id, path, registrationTime))
-                        .order(registrationTime.desc())
-                        .get_results::<(i32, String, i32)>(db.conn())?
-                        .into_iter()
-                        .filter_map(|(store_id, store_path, reg)| {
-                            Store::parse(store_id as u32, reg as u32, store_path)
-                        });
-
-                    let deps = Store::get_unique(all_deps);
-                    packages.insert(Self { store, deps });
+            .transaction::<_, anyhow::Error, _>(|| {
+                let mut stores = stores.into_iter();
+
+                while let Some(store) = stores.next() {
+                    if budget.expired() {
+                        budget.cut_short("resolving dependencies", num_stores - packages.len());
+
+                        let unresolved = iter::once(store).chain(stores.by_ref());
+
+                        packages.extend(unresolved.map(|store| Self {
+                            store,
+                            deps: HashSet::new(),
+                            paths: HashMap::new(),
+                        }));
+
+                        break;
+                    }
+
+                    let closure =
+                        Closure::walk(db, store.id as i32, opts.max_depth, opts.max_nodes)
+                            .with_context(|| {
+                                anyhow!("failed to walk references of {}", store.name)
+                            })?;
+
+                    stats.merge(closure.stats);
+
+                    let all_deps = Self::stores_from_ids(db, &closure.ids)?;
+
+                    let names = all_deps
+                        .iter()
+                        .map(|dep| (dep.id as i32, dep.name.clone()))
+                        .collect::<HashMap<_, _>>();
+
+                    let deps = Store::get_unique(all_deps.into_iter());
+                    let paths = Self::dep_paths(&closure, &deps, &names);
+
+                    packages.insert(Self { store, deps, paths });
                 }
 
                 Ok(())
             })
             .context("failed to get dependencies of a nix store")?;
 
-        Ok(packages)
+        Ok((packages, stats))
+    }
+
+    /// Returns the names of the stores each of `deps` was discovered through in `closure`, for every
+    /// dependency that isn't a direct reference.
+    ///
+    /// `names` holds the name of every parsed store in the closure by its id.
+    fn dep_paths(
+        closure: &Closure,
+        deps: &HashSet<Store>,
+        names: &HashMap<i32, String>,
+    ) -> HashMap<String, Vec<String>> {
+        deps.iter()
+            .filter_map(|dep| {
+                let path = closure
+                    .path_to(dep.id as i32)
+                    .iter()
+                    .filter_map(|id| names.get(id).cloned())
+                    .collect::<Vec<_>>();
+
+                if path.is_empty() {
+                    None
+                } else {
+                    Some((dep.name.clone(), path))
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the parsed stores of the paths with the given `ids`.
+    fn stores_from_ids(db: &SystemDatabase, ids: &[i32]) -> Result<Vec<Store>> {
+        use database::schema::ValidPaths::dsl::*;
+        use diesel::prelude::*;
+
+        let mut stores = Vec::with_capacity(ids.len());
+
+        // Each chunk is parsed before the next one is read, so the raw rows of every path are never held at once
+        for chunk in ids.chunks(closure::QUERY_CHUNK_SIZE) {
+            let mut query = ValidPaths.filter(id.eq_any(chunk)).into_boxed();
+
+            if db.columns().ca {
+                query = query.filter(ca.is_null());
+            }
+
+            let rows = query
+                .select((id, path, registrationTime, db.ultimate()))
+                .get_results::<(i32, String, i32, Option<i32>)>(db.conn())?;
+
+            stores.extend(rows.into_iter().filter_map(
+                |(store_id, store_path, reg, store_ultimate)| {
+                    let mut store = Store::parse(store_id as u32, reg as u32, store_path)?;
+                    store.locally_built = store_ultimate.map(|value| value != 0);
+                    Some(store)
+                },
+            ));
+        }
+
+        Ok(stores)
     }
 
-    pub fn all_from_system(db: &SystemDatabase) -> Result<HashSet<Self>> {
-        let stores = Store::all_from_system(db)?;
-        Self::all_from_stores(stores, db)
+    pub fn all_from_system(
+        db: &SystemDatabase,
+        opts: DepOptions,
+        policy: DedupPolicy,
+        budget: &Budget,
+    ) -> Result<(HashSet<Self>, ClosureStats)> {
+        let stores = Store::all_from_system(db, budget, policy)?;
+        Self::all_from_stores(stores, db, opts, budget)
     }
 }
 
@@ -287,20 +983,24 @@ impl PartialEq for Derivation {
     }
 }
 
+impl Borrow<str> for Derivation {
+    fn borrow(&self) -> &str {
+        &self.store.name
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::testing;
 
     macro_rules! store_tuple {
         ($path:expr => $name:expr, $version:expr, $suffix:expr) => {
             (
                 $path,
                 Some(Store {
-                    id: 0,
-                    register_time: 0,
-                    name: $name.into(),
-                    version: $version.into(),
                     suffix: $suffix,
+                    ..testing::store($name, $version)
                 }),
             )
         };
@@ -318,7 +1018,8 @@ mod test {
             store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-dash-edge-case-"),
             store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-dash-short-"),
             store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-"),
-            store_tuple!("/nix/store/123shortprefix-short-prefix-1.0" => "short-prefix", "1.0", None),
+            store_tuple!("/nix/store/123shortprefix-short-prefix-1.0"),
+            store_tuple!("/mnt/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-custom-store-1.0" => "custom-store", "1.0", None),
             store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-glxinfo-8.4.0" => "glxinfo", "8.4.0", None),
             store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-pcre-8.42" => "pcre", "8.42", None),
             store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-dxvk-v1.4.6" => "dxvk", "v1.4.6", None),
@@ -331,9 +1032,42 @@ mod test {
             store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-ffmpeg-3.4.5-bin" => "ffmpeg", "3.4.5", Some("bin".into())),
             store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-vulkan-loader-1.1.85" => "vulkan-loader", "1.1.85", None),
             store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-vpnc-0.5.3-post-r550" => "vpnc", "0.5.3-post-r550", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-gcc-13.2.0-lib64" => "gcc", "13.2.0", Some("lib64".into())),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-glibc-2.39-dev-bin" => "glibc", "2.39", Some("dev-bin".into())),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-linux-headers-6.6-dev" => "linux-headers", "6.6", Some("dev".into())),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-hello-2.12-out-2" => "hello", "2.12", Some("out-2".into())),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-mesa-24.0.1-dev-3" => "mesa", "24.0.1", Some("dev-3".into())),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-openssl-3.2.0-rc2" => "openssl", "3.2.0-rc2", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-openssl-3.2.0-rc2-bin" => "openssl", "3.2.0-rc2", Some("bin".into())),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-perl-5.38.2-2" => "perl", "5.38.2-2", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-tzdata-2021_03" => "tzdata", "2021_03", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-cargo-about-1.0.0+build.5" => "cargo-about", "1.0.0+build.5", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-cargo-about-1.0.0+build.5-bin" => "cargo-about", "1.0.0+build.5", Some("bin".into())),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-only-lib64"),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-lib64"),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-no-version-dev-bin"),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-libusb1-1.0.27" => "libusb1", "1.0.27", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-zlib1g-1.3" => "zlib1g", "1.3", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-http2-4.1.0" => "http2", "4.1.0", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-libusb1-compat-0.1.8" => "libusb1-compat", "0.1.8", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-sqlite3-editor-2024.1.1" => "sqlite3-editor", "2024.1.1", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-open-3d-viewer-1.0" => "open-3d-viewer", "1.0", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-qt-5-compat-shim-2.1.0-dev" => "qt-5-compat-shim", "2.1.0", Some("dev".into())),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-gnome-2-style-v3.1" => "gnome-2-style", "v3.1", None),
+            store_tuple!("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-hyprland-0-unstable-2024-05-01" => "hyprland", "0-unstable-2024-05-01", None),
         ];
 
         for (path, expected_store) in &stores {
+            if let Some(name) = path.strip_prefix("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-") {
+                let bare = Store::parse_stripped(name)
+                    .map(|store| (store.name, store.version, store.suffix));
+
+                let full = Store::parse(0, 0, *path)
+                    .map(|store| (store.name, store.version, store.suffix));
+
+                assert_eq!(bare, full, "{}", name);
+            }
+
             match Store::parse(0, 0, *path) {
                 Some(parsed) => match expected_store {
                     Some(expected) => {
@@ -352,6 +1086,638 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_ecosystem_stores() {
+        let cases = [
+            (
+                "ocaml4.14.1-zarith-1.13",
+                "zarith",
+                "1.13",
+                None,
+                Some(("ocaml", "4.14.1")),
+            ),
+            (
+                "ocaml5.1.1-zarith-1.13-dev",
+                "zarith",
+                "1.13",
+                Some("dev"),
+                Some(("ocaml", "5.1.1")),
+            ),
+            (
+                "ocaml-4.14.1",
+                "ocaml",
+                "4.14.1",
+                None,
+                Some(("ocaml", "4.14.1")),
+            ),
+            (
+                "ghc-9.4.8",
+                "ghc",
+                "9.4.8",
+                None,
+                Some(("haskell", "9.4.8")),
+            ),
+            (
+                "ghc-9.4.8-doc",
+                "ghc",
+                "9.4.8",
+                Some("doc"),
+                Some(("haskell", "9.4.8")),
+            ),
+            (
+                "ghc-9.4.8-with-packages",
+                "ghc-with-packages",
+                "9.4.8",
+                None,
+                Some(("haskell", "9.4.8")),
+            ),
+            ("aeson-2.1.2.1", "aeson", "2.1.2.1", None, None),
+            ("ghc-paths-0.1.0.12", "ghc-paths", "0.1.0.12", None, None),
+            (
+                "texlive-2023.20230401",
+                "texlive",
+                "2023.20230401",
+                None,
+                Some(("texlive", "2023")),
+            ),
+            (
+                "texlive-combined-full-2023.20230401",
+                "texlive-combined-full",
+                "2023.20230401",
+                None,
+                Some(("texlive", "2023")),
+            ),
+        ];
+
+        for (name, expected_name, version, suffix, ecosystem) in cases {
+            let store = Store::parse_stripped(name).unwrap();
+
+            assert_eq!(store.name, expected_name, "{}", name);
+            assert_eq!(store.version, version, "{}", name);
+            assert_eq!(store.suffix.as_deref(), suffix, "{}", name);
+            assert_eq!(
+                store
+                    .ecosystem
+                    .as_ref()
+                    .map(|eco| (eco.name.as_str(), eco.version.as_str())),
+                ecosystem,
+                "{}",
+                name
+            );
+
+            // Stores from states saved before the rules existed are normalized to the same store
+            let raw = Store::parse_name(0, 0, name.as_bytes())
+                .unwrap()
+                .normalize();
+            assert_eq!(
+                (raw.name, raw.version, raw.ecosystem),
+                (store.name, store.version, store.ecosystem)
+            );
+        }
+    }
+
+    #[test]
+    fn parse_bare_names() {
+        let summarize = |name| {
+            Store::parse_stripped(name).map(|store| (store.name, store.version, store.suffix))
+        };
+
+        assert_eq!(
+            summarize("ffmpeg-3.4.5-bin"),
+            Some(("ffmpeg".into(), "3.4.5".into(), Some("bin".into())))
+        );
+        assert_eq!(
+            summarize("wine-wow-4.0-rc5-staging"),
+            Some(("wine-wow".into(), "4.0-rc5".into(), Some("staging".into())))
+        );
+        assert_eq!(
+            summarize("pcre-8.42"),
+            Some(("pcre".into(), "8.42".into(), None))
+        );
+        assert_eq!(summarize("no-version-dev-bin"), None);
+        assert_eq!(summarize("pcre"), None);
+        assert_eq!(summarize(""), None);
+
+        // Bare names aren't expected to have a prefix, so a hash is treated as part of the name
+        assert_eq!(
+            summarize("zx6vs1b6xf07cprslk9is1fhwih21ix5-pcre-8.42"),
+            Some((
+                "zx6vs1b6xf07cprslk9is1fhwih21ix5-pcre".into(),
+                "8.42".into(),
+                None
+            ))
+        );
+
+        let store = Store::parse_stripped("pcre-8.42").unwrap();
+        assert_eq!((store.id, store.register_time), (0, 0));
+    }
+
+    #[test]
+    fn version_strings() {
+        let cases = [
+            ("1.0", true),
+            ("v1.0", true),
+            ("1.0_beta", true),
+            ("1_0_0", true),
+            ("2019.02.15", true),
+            ("8", true),
+            ("1.0rc5", true),
+            ("abc", false),
+            ("", false),
+            ("v", false),
+            ("vx1.0", false),
+            (".1.0", false),
+            // Versions are checked one fragment at a time
+            ("1.2.3-rc1", false),
+            ("1.0RC1", false),
+            // Build metadata, as in SemVer
+            ("1.0+git", true),
+            ("1.0.0+build.5", true),
+        ];
+
+        for &(version, expected) in &cases {
+            assert_eq!(
+                Store::is_version_str(version.as_bytes()),
+                expected,
+                "{:?}",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn detect_duplicates() {
+        let store = |name: &str, version: &str, register_time| Store {
+            register_time,
+            ..testing::store(name, version)
+        };
+
+        let window = Store::DUPLICATE_WINDOW;
+        let base = store("ffmpeg", "3.4.5", 10_000);
+
+        let cases = [
+            (store("ffmpeg", "3.4.6", 10_000), true, "same time"),
+            (
+                store("ffmpeg", "3.4.6", 10_000 + window - 1),
+                true,
+                "inside window",
+            ),
+            (
+                store("ffmpeg", "3.4.6", 10_000 - window + 1),
+                true,
+                "inside window, older",
+            ),
+            (
+                store("ffmpeg", "3.4.6", 10_000 + window),
+                false,
+                "exactly window apart",
+            ),
+            (
+                store("ffmpeg", "3.4.6", 10_000 - window),
+                false,
+                "exactly window apart, older",
+            ),
+            (store("ffmpeg", "3.4.6", 0), false, "outside window"),
+            (store("ffmpeg", "3.4.5", 10_001), false, "same version"),
+            (store("glxinfo", "8.4.0", 10_000), false, "different name"),
+        ];
+
+        for (other, expected, desc) in &cases {
+            assert_eq!(
+                Store::are_duplicates(&base, other, window),
+                *expected,
+                "{}",
+                desc
+            );
+            assert_eq!(
+                Store::are_duplicates(other, &base, window),
+                *expected,
+                "{} (swapped)",
+                desc
+            );
+        }
+
+        assert!(
+            !Store::are_duplicates(&base, &cases[0].0, 0),
+            "empty window"
+        );
+    }
+
+    #[test]
+    fn resolve_direct_deps() {
+        use database::fixture;
+
+        let db = fixture::empty();
+        fixture::add_path(&db, 1, "firefox-120.0", 100);
+        fixture::add_path(&db, 2, "nss-3.95", 90);
+        fixture::add_path(&db, 3, "nspr-4.35", 80);
+        fixture::add_path(&db, 4, "glibc-2.38", 70);
+
+        fixture::add_ref(&db, 1, 1);
+        fixture::add_ref(&db, 1, 2);
+        fixture::add_ref(&db, 1, 4);
+        fixture::add_ref(&db, 2, 3);
+        fixture::add_ref(&db, 3, 4);
+
+        let dep_names = |opts| {
+            let budget = Budget::unlimited();
+            let stores = Store::all_from_system(&db, &budget, DedupPolicy::Drop).unwrap();
+            let (pkgs, stats) = Derivation::all_from_stores(stores, &db, opts, &budget).unwrap();
+
+            let mut names = pkgs
+                .get("firefox")
+                .unwrap()
+                .deps
+                .iter()
+                .map(|dep| dep.name.clone())
+                .collect::<Vec<_>>();
+
+            names.sort_unstable();
+            (names, stats)
+        };
+
+        // Only direct references should be included by default, without the self-reference
+        let (names, stats) = dep_names(DepOptions::default());
+        assert_eq!(names, ["glibc", "nss"]);
+        assert_eq!(stats.self_refs, 1);
+
+        let (names, _) = dep_names(DepOptions {
+            max_depth: None,
+            ..DepOptions::default()
+        });
+        assert_eq!(names, ["glibc", "nspr", "nss"]);
+    }
+
+    #[test]
+    fn find_store_by_path() {
+        use database::fixture;
+
+        let db = fixture::empty();
+        fixture::add_path(&db, 1, "nixup-0.4.0", 100);
+        fixture::add_path(&db, 2, "nixup-0.4.0-man", 100);
+        fixture::set_ultimate(&db, 1, Some(1));
+
+        let find = |path: &str| Store::from_system_path(&db, Path::new(path)).unwrap();
+
+        let store = find("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-nixup-0.4.0").unwrap();
+        assert_eq!((store.id, store.name.as_str()), (1, "nixup"));
+        assert_eq!(store.version, "0.4.0");
+        assert_eq!(store.locally_built, Some(true));
+
+        assert!(find("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-nixup-0.3.0").is_none());
+    }
+
+    #[test]
+    fn read_locally_built() {
+        use database::fixture;
+
+        let db = fixture::empty();
+        fixture::add_path(&db, 1, "firefox-121.0", 100);
+        fixture::add_path(&db, 2, "nss-3.96", 90);
+        fixture::add_path(&db, 3, "mesa-24.0", 80);
+        fixture::add_ref(&db, 1, 2);
+        fixture::add_ref(&db, 1, 3);
+
+        fixture::set_ultimate(&db, 1, Some(1));
+        fixture::set_ultimate(&db, 2, Some(0));
+        fixture::set_ultimate(&db, 3, None);
+
+        let budget = Budget::unlimited();
+        let stores = Store::all_from_system(&db, &budget, DedupPolicy::Drop).unwrap();
+
+        let locally_built = |name: &str| stores.get(name).unwrap().locally_built;
+        assert_eq!(locally_built("firefox"), Some(true), "set");
+        assert_eq!(locally_built("nss"), Some(false), "unset");
+        assert_eq!(locally_built("mesa"), None, "null");
+
+        // Dependencies should be read the same way
+        let (pkgs, _) =
+            Derivation::all_from_stores(stores, &db, DepOptions::default(), &budget).unwrap();
+        let deps = &pkgs.get("firefox").unwrap().deps;
+
+        assert_eq!(deps.get("nss").unwrap().locally_built, Some(false));
+        assert_eq!(deps.get("mesa").unwrap().locally_built, None);
+    }
+
+    #[test]
+    fn read_legacy_database() {
+        use database::fixture;
+
+        let db = fixture::legacy();
+        fixture::add_path(&db, 1, "firefox-121.0", 100);
+        fixture::add_path(&db, 2, "nss-3.96", 90);
+        fixture::add_ref(&db, 1, 2);
+
+        let budget = Budget::unlimited();
+        let stores = Store::all_from_system(&db, &budget, DedupPolicy::Drop).unwrap();
+        assert_eq!(stores.get("firefox").unwrap().locally_built, None);
+
+        let (pkgs, _) =
+            Derivation::all_from_stores(stores, &db, DepOptions::default(), &budget).unwrap();
+        let firefox = pkgs.get("firefox").unwrap();
+        assert_eq!(firefox.deps.get("nss").unwrap().version, "3.96");
+
+        let path = Path::new("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-nss-3.96");
+        assert_eq!(Store::from_system_path(&db, path).unwrap().unwrap().id, 2);
+    }
+
+    #[test]
+    fn collect_unparsed_paths() {
+        use database::fixture;
+
+        let db = fixture::empty();
+
+        for (i, name) in ["firefox-120.0", "lib64", "fix-static.patch"]
+            .iter()
+            .enumerate()
+        {
+            fixture::add_path(&db, i as i32 + 1, name, 100);
+        }
+
+        let mut unparsed = Vec::new();
+        let (unique, _) = Store::all_from_system_with_shadowed(
+            &db,
+            &Budget::unlimited(),
+            DedupPolicy::Drop,
+            Some(&mut unparsed),
+        )
+        .unwrap();
+
+        assert_eq!(unique.len(), 1);
+
+        // Paths are read from the newest to the oldest
+        assert_eq!(
+            unparsed,
+            [
+                "/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-fix-static.patch",
+                "/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-lib64",
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_in_batches() {
+        use database::fixture;
+
+        let mut db = fixture::empty();
+        let names = [
+            "firefox-120.0",
+            "firefox-121.0",
+            "nss-3.96",
+            "zsh-completions-0.35",
+            "source.tar.gz-1.0",
+            "mesa-24.0",
+            "mesa-24.0-dev",
+            "glibc-2.38",
+        ];
+
+        for (i, name) in names.iter().enumerate() {
+            fixture::add_path(&db, i as i32 + 1, name, 100 + (i as i32 % 3) * 10_000);
+        }
+
+        let scan = |db: &SystemDatabase| {
+            let (unique, shadowed) = Store::all_from_system_with_shadowed(
+                db,
+                &Budget::unlimited(),
+                DedupPolicy::Drop,
+                None,
+            )
+            .unwrap();
+
+            let mut unique = unique
+                .into_iter()
+                .map(|store| (store.id, store.name, store.version))
+                .collect::<Vec<_>>();
+            unique.sort_unstable();
+
+            let mut shadowed = shadowed
+                .into_iter()
+                .map(|store| store.id)
+                .collect::<Vec<_>>();
+            shadowed.sort_unstable();
+
+            (unique, shadowed)
+        };
+
+        let expected = scan(&db);
+        assert!(!expected.0.is_empty());
+        assert!(
+            !expected.1.is_empty(),
+            "the older firefox should be shadowed"
+        );
+
+        for &rows in &[1, 2, 3, names.len(), names.len() + 1] {
+            db.set_batch_size(rows);
+            assert_eq!(scan(&db), expected, "batches of {}", rows);
+        }
+    }
+
+    /// Returns the peak resident set size of this process in KiB, after resetting it when `reset` is set.
+    fn peak_rss(reset: bool) -> u64 {
+        use std::fs;
+
+        if reset {
+            fs::write("/proc/self/clear_refs", "5").unwrap();
+        }
+
+        fs::read_to_string("/proc/self/status")
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap()
+    }
+
+    /// Measures how much the peak memory use grows while scanning a database with 100,000 paths.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_scan_memory`.
+    #[test]
+    #[ignore]
+    fn bench_scan_memory() {
+        use database::fixture;
+        use diesel::connection::SimpleConnection;
+
+        const PATHS: usize = 100_000;
+
+        let db = fixture::empty();
+        let mut sql = String::from("BEGIN;");
+
+        for i in 0..PATHS {
+            sql.push_str(&format!(
+                "INSERT INTO ValidPaths (id, path, hash, registrationTime, deriver) VALUES ({0}, '/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-package{0}x-1.{1}', 'sha256:0', {0}, '/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-package{0}x-1.{1}.drv');",
+                i + 1,
+                i % 7
+            ));
+        }
+
+        sql.push_str("COMMIT;");
+        db.conn().batch_execute(&sql).unwrap();
+        drop(sql);
+
+        let before = peak_rss(true);
+        let stores = Store::all_from_system(&db, &Budget::unlimited(), DedupPolicy::Drop).unwrap();
+        let after = peak_rss(false);
+
+        assert_eq!(stores.len(), PATHS);
+        println!("peak RSS grew by {} KiB while scanning", after - before);
+    }
+
+    #[test]
+    fn count_referrers() {
+        use database::fixture;
+
+        let db = fixture::empty();
+        fixture::add_path(&db, 1, "firefox-121.0", 100);
+        fixture::add_path(&db, 2, "thunderbird-115.6", 100);
+        fixture::add_path(&db, 3, "glibc-2.38", 90);
+        fixture::add_path(&db, 4, "nss-3.96", 90);
+        fixture::add_path(&db, 5, "hello-2.12", 80);
+
+        // Self-references shouldn't make an orphan look referenced
+        for store_id in 1..=5 {
+            fixture::add_ref(&db, store_id, store_id);
+        }
+
+        fixture::add_ref(&db, 1, 3);
+        fixture::add_ref(&db, 2, 3);
+        fixture::add_ref(&db, 4, 3);
+        fixture::add_ref(&db, 1, 4);
+
+        let stores = Store::all_from_system(&db, &Budget::unlimited(), DedupPolicy::Drop).unwrap();
+        let referrers = |name: &str| stores.get(name).unwrap().referrer_count;
+
+        assert_eq!(referrers("glibc"), Some(3), "shared");
+        assert_eq!(referrers("nss"), Some(1), "single referrer");
+        assert_eq!(referrers("hello"), Some(0), "orphan");
+        assert_eq!(referrers("firefox"), Some(0), "orphan");
+    }
+
+    #[test]
+    fn partial_results_on_expiry() {
+        use database::fixture;
+        use diff::DiffOptions;
+
+        let db = fixture::empty();
+        fixture::add_path(&db, 1, "firefox-121.0", 100);
+        fixture::add_path(&db, 2, "nss-3.96", 90);
+        fixture::add_path(&db, 3, "mesa-24.0", 80);
+        fixture::add_ref(&db, 1, 2);
+
+        let expired = {
+            let mut budget = Budget::unlimited();
+            budget.expire();
+            budget
+        };
+
+        // Nothing should be parsed when the scan starts after the deadline
+        assert!(Store::all_from_system(&db, &expired, DedupPolicy::Drop)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            expired.cutoffs(),
+            [budget::Cutoff {
+                phase: "scanning stores",
+                remaining: 3
+            }]
+        );
+
+        // Expire the budget between scanning and resolving dependencies
+        let mut budget = Budget::unlimited();
+        let stores = Store::all_from_system(&db, &budget, DedupPolicy::Drop).unwrap();
+        assert!(!budget.is_partial());
+
+        budget.expire();
+
+        let (pkgs, _) =
+            Derivation::all_from_stores(stores, &db, DepOptions::default(), &budget).unwrap();
+
+        assert_eq!(pkgs.len(), 3);
+        assert!(pkgs.iter().all(|pkg| pkg.deps.is_empty()));
+        assert_eq!(budget.cutoffs()[0].remaining, 3);
+
+        // Unresolved dependencies shouldn't be reported, while the package's own version still is
+        let old = {
+            let mut deps = HashSet::new();
+            deps.insert(testing::store("nss", "3.95"));
+
+            let mut old = HashSet::new();
+            old.insert(Derivation {
+                store: testing::store("firefox", "120.0"),
+                deps,
+                paths: HashMap::new(),
+            });
+            old
+        };
+
+        let diffs = diff::get_package_diffs(&pkgs, &old, DiffOptions::default());
+
+        assert_eq!(diffs.len(), 1);
+        assert!(diffs[0].pkg.is_some());
+        assert!(diffs[0].deps.is_empty());
+    }
+
+    #[test]
+    fn removals_on_expiry() {
+        use database::fixture;
+
+        let db = fixture::empty();
+        fixture::add_path(&db, 1, "firefox-121.0", 100);
+        fixture::add_path(&db, 2, "nss-3.96", 90);
+        fixture::add_ref(&db, 1, 2);
+
+        let package = |name: &str, deps: &[&str]| Derivation {
+            store: Store::parse_stripped(name).unwrap(),
+            deps: deps
+                .iter()
+                .map(|dep| Store::parse_stripped(dep).unwrap())
+                .collect(),
+            paths: HashMap::new(),
+        };
+
+        // mesa was removed, and nss is still used by firefox while libdrm isn't
+        let old = vec![
+            package("firefox-120.0", &["nss-3.95"]),
+            package("mesa-24.0", &["nss-3.95", "libdrm-2.4.120"]),
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let top_level = |stores: &HashSet<Store>| {
+            stores
+                .iter()
+                .map(|store| store.name.clone())
+                .collect::<HashSet<_>>()
+        };
+
+        // Every package would look removed if the scan didn't get to any store
+        let expired = {
+            let mut budget = Budget::unlimited();
+            budget.expire();
+            budget
+        };
+
+        let stores = Store::all_from_system(&db, &expired, DedupPolicy::Drop).unwrap();
+        let found = diff::find_removals(&old, &top_level(&stores), &HashSet::new(), &expired);
+        assert!(found.is_none());
+
+        // firefox is never resolved, so what it depends on has to come from the saved state
+        let mut budget = Budget::unlimited();
+        let stores = Store::all_from_system(&db, &budget, DedupPolicy::Drop).unwrap();
+        let names = top_level(&stores);
+
+        budget.expire();
+
+        let (pkgs, _) =
+            Derivation::all_from_stores(stores, &db, DepOptions::default(), &budget).unwrap();
+
+        let removals = diff::find_removals(&old, &names, &pkgs, &budget).unwrap();
+
+        assert_eq!(removals.len(), 1);
+        assert_eq!(removals[0].name, "mesa");
+        assert_eq!(removals[0].gone, ["libdrm"]);
+        assert_eq!(removals[0].retained, ["nss"]);
+    }
+
     #[test]
     fn strip_store_path() {
         let store = "/nix/store/03lp4drizbh8cl3f9mjysrrzrg3ssakv-glxinfo-8.4.0".as_bytes();
@@ -363,5 +1729,29 @@ mod test {
 
         let dash_edge_case = "/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-".as_bytes();
         assert_eq!(Store::strip_prefix(dash_edge_case), None, "dash edge case");
+
+        let custom_store =
+            "/mnt/nix/store/03lp4drizbh8cl3f9mjysrrzrg3ssakv-glxinfo-8.4.0".as_bytes();
+        assert_eq!(
+            Store::strip_prefix(custom_store),
+            Some("glxinfo-8.4.0".as_bytes()),
+            "custom store directory"
+        );
+
+        for malformed in &[
+            "/nix/store/not-a-hash-glxinfo-8.4.0",
+            "/store/03lp4drizbh8cl3f9mjysrrzrg3ssak-glxinfo-8.4.0",
+            "/store/03lp4drizbh8cl3f9mjysrrzrg3ssakvz-glxinfo-8.4.0",
+            "/store/03lp4drizbh8cl3f9mjysrrzrg3ssake-glxinfo-8.4.0",
+            "/some-dir/03lp4drizbh8cl3f9mjysrrzrg3ssakv-glxinfo-8.4.0",
+            "glxinfo-8.4.0",
+        ] {
+            assert_eq!(
+                Store::strip_prefix(malformed.as_bytes()),
+                None,
+                "{}",
+                malformed
+            );
+        }
     }
 }
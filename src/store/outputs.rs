@@ -0,0 +1,342 @@
+use super::Store;
+use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+/// The suffixes outputs are preferred in when no order is configured, where an empty suffix is the store without one.
+pub const DEFAULT_ORDER: [&str; 3] = ["", "bin", "out"];
+
+/// Which output represents a package when several of its outputs were registered in the same update.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputPolicy {
+    /// The suffixes to prefer, in order, where an empty suffix is the store without one.
+    /// Uses `DEFAULT_ORDER` when unset.
+    pub order: Option<Vec<String>>,
+    /// Orders for single packages by name, which replace `order` for them.
+    pub packages: HashMap<String, Vec<String>>,
+}
+
+impl OutputPolicy {
+    /// Returns the order the outputs of the package called `name` are preferred in.
+    pub fn order_for(&self, name: &str) -> Vec<&str> {
+        match self.packages.get(name).or(self.order.as_ref()) {
+            Some(order) => order.iter().map(String::as_str).collect(),
+            None => DEFAULT_ORDER.to_vec(),
+        }
+    }
+}
+
+/// The outputs of a package that were registered along with the one representing it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Outputs {
+    /// The version of the output representing the package.
+    pub version: String,
+    /// Every other output, sorted by suffix.
+    pub others: Vec<Store>,
+    /// Whether any of `others` has a different version than the output representing the package.
+    pub inconsistent: bool,
+}
+
+/// Top-level stores where a single output was picked to represent each package.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub stores: HashSet<Store>,
+    /// The other outputs of every package that has more than one, by name.
+    pub outputs: HashMap<String, Outputs>,
+}
+
+/// Returns the position of the output in `outputs` that represents their package.
+///
+/// Outputs are preferred in the order their suffix appears in `order`. Outputs that aren't listed come after every
+/// listed one, sorted by their suffix. Returns `None` if `outputs` is empty.
+pub fn primary(outputs: &[Store], order: &[&str]) -> Option<usize> {
+    outputs
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, store)| {
+            let suffix = store.suffix.as_deref().unwrap_or("");
+
+            let rank = order
+                .iter()
+                .position(|&preferred| preferred == suffix)
+                .unwrap_or(order.len());
+
+            (rank, suffix)
+        })
+        .map(|(i, _)| i)
+}
+
+/// Replaces each store in `stores` with the output `policy` prefers for its package.
+///
+/// The outputs of a package are the store that was kept for it, and every store in `shadowed` with the same name
+/// that was registered within `Store::DUPLICATE_WINDOW` of it, which makes them part of the same update. Only the
+/// newest store of each suffix is considered.
+pub fn select(stores: HashSet<Store>, shadowed: &[Store], policy: &OutputPolicy) -> Selection {
+    let mut candidates = HashMap::<&str, Vec<&Store>>::new();
+
+    for store in shadowed {
+        candidates.entry(&store.name).or_default().push(store);
+    }
+
+    for stores in candidates.values_mut() {
+        stores.sort_unstable_by(|x, y| {
+            y.register_time
+                .cmp(&x.register_time)
+                .then_with(|| y.id.cmp(&x.id))
+        });
+    }
+
+    let mut selection = Selection::default();
+
+    for store in stores {
+        let mut outputs = vec![store];
+
+        for &other in candidates
+            .get(outputs[0].name.as_str())
+            .into_iter()
+            .flatten()
+        {
+            let kept = &outputs[0];
+
+            let newer = kept.register_time.max(other.register_time);
+            let older = kept.register_time.min(other.register_time);
+
+            if newer - older >= Store::DUPLICATE_WINDOW
+                || outputs.iter().any(|output| output.suffix == other.suffix)
+            {
+                continue;
+            }
+
+            outputs.push(other.clone());
+        }
+
+        if outputs.len() == 1 {
+            selection.stores.extend(outputs);
+            continue;
+        }
+
+        let order = policy.order_for(&outputs[0].name);
+        let primary = outputs.swap_remove(primary(&outputs, &order).unwrap_or(0));
+
+        outputs.sort_unstable_by(|x, y| x.suffix.cmp(&y.suffix));
+
+        let inconsistent = outputs
+            .iter()
+            .any(|output| output.version != primary.version);
+
+        selection.outputs.insert(
+            primary.name.clone(),
+            Outputs {
+                version: primary.version.clone(),
+                others: outputs,
+                inconsistent,
+            },
+        );
+
+        selection.stores.insert(primary);
+    }
+
+    selection
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::dedup::{self, DedupPolicy};
+
+    fn store(id: u32, register_time: u32, name: &str) -> Store {
+        let mut store = Store::parse_stripped(name).unwrap();
+        store.id = id;
+        store.register_time = register_time;
+        store
+    }
+
+    /// The name, version, and suffix of a selected store, and the suffixes of its other outputs.
+    type Summary = (String, String, Option<String>, Vec<String>);
+
+    /// Deduplicates `stores` with `dedup_policy` and selects the primary outputs of what's left with `policy`.
+    fn run(
+        stores: Vec<Store>,
+        dedup_policy: DedupPolicy,
+        policy: &OutputPolicy,
+    ) -> (Vec<Summary>, Selection) {
+        let resolved = dedup::dedup(stores.into_iter(), Store::DUPLICATE_WINDOW, dedup_policy);
+        let selection = select(resolved.unique, &resolved.shadowed, policy);
+
+        let mut summary = selection
+            .stores
+            .iter()
+            .map(|store| {
+                let others = selection
+                    .outputs
+                    .get(&store.name)
+                    .map(|outputs| {
+                        outputs
+                            .others
+                            .iter()
+                            .map(|other| other.suffix.clone().unwrap_or_default())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                let (name, version, suffix) = store.key();
+                (name, version, suffix, others)
+            })
+            .collect::<Vec<_>>();
+
+        summary.sort_unstable();
+        (summary, selection)
+    }
+
+    #[test]
+    fn select_primary_outputs() {
+        let suffixed = |suffixes: &[&str]| {
+            suffixes
+                .iter()
+                .map(|suffix| store(0, 0, &format!("foo-1.0-{}", suffix)))
+                .collect::<Vec<_>>()
+        };
+
+        let primary_suffix = |outputs: &[Store], order: &[&str]| {
+            primary(outputs, order).map(|i| outputs[i].suffix.clone().unwrap())
+        };
+
+        // Without an unsuffixed output, bin is next, and then out
+        let outputs = suffixed(&["dev", "out", "bin", "man"]);
+        assert_eq!(primary_suffix(&outputs, &DEFAULT_ORDER), Some("bin".into()));
+
+        let outputs = suffixed(&["dev", "out", "man"]);
+        assert_eq!(primary_suffix(&outputs, &DEFAULT_ORDER), Some("out".into()));
+
+        // Outputs that aren't listed are picked by their suffix
+        let outputs = suffixed(&["man", "lib", "dev"]);
+        assert_eq!(primary_suffix(&outputs, &DEFAULT_ORDER), Some("dev".into()));
+
+        assert_eq!(primary(&[], &DEFAULT_ORDER), None);
+
+        // The order stores were registered in doesn't matter
+        let stores = vec![
+            store(1, 100, "bash-5.2-man"),
+            store(2, 100, "bash-5.2"),
+            store(3, 100, "bash-5.2-dev"),
+            store(4, 200, "curl-8.6.0-man"),
+            store(5, 200, "curl-8.6.0-bin"),
+            store(6, 200, "curl-8.6.0-dev"),
+            store(7, 300, "zstd-1.5.5"),
+        ];
+
+        let (summary, selection) = run(stores, DedupPolicy::Drop, &OutputPolicy::default());
+
+        assert_eq!(
+            summary,
+            [
+                (
+                    "bash".into(),
+                    "5.2".into(),
+                    None,
+                    vec!["dev".into(), "man".into()]
+                ),
+                (
+                    "curl".into(),
+                    "8.6.0".into(),
+                    Some("bin".into()),
+                    vec!["dev".into(), "man".into()]
+                ),
+                ("zstd".into(), "1.5.5".into(), None, Vec::new()),
+            ]
+        );
+
+        assert!(!selection.outputs["curl"].inconsistent);
+        assert!(!selection.outputs.contains_key("zstd"));
+
+        // Outputs from an earlier update aren't part of the package anymore
+        let stores = vec![
+            store(1, 100, "git-2.43.0-doc"),
+            store(2, 100 + Store::DUPLICATE_WINDOW * 2, "git-2.44.0-bin"),
+        ];
+
+        let (summary, _) = run(stores, DedupPolicy::Drop, &OutputPolicy::default());
+
+        assert_eq!(
+            summary,
+            [(
+                "git".into(),
+                "2.44.0".into(),
+                Some("bin".into()),
+                Vec::new()
+            )]
+        );
+    }
+
+    #[test]
+    fn flag_inconsistent_outputs() {
+        let stores = vec![
+            store(1, 100, "openssl-3.0.12"),
+            store(2, 110, "openssl-3.0.13-bin"),
+            store(3, 110, "openssl-3.0.13-dev"),
+        ];
+
+        // Dropping the conflict leaves no outputs at all
+        let (summary, _) = run(stores.clone(), DedupPolicy::Drop, &OutputPolicy::default());
+        assert!(summary.is_empty());
+
+        // The unsuffixed output is still preferred over the newer one that was kept
+        let (summary, selection) = run(stores, DedupPolicy::KeepNewest, &OutputPolicy::default());
+
+        assert_eq!(
+            summary,
+            [(
+                "openssl".into(),
+                "3.0.12".into(),
+                None,
+                vec!["bin".into(), "dev".into()]
+            )]
+        );
+
+        let outputs = &selection.outputs["openssl"];
+        assert!(outputs.inconsistent);
+        assert_eq!(outputs.others[0].version, "3.0.13");
+    }
+
+    #[test]
+    fn override_order_per_package() {
+        let policy = OutputPolicy {
+            order: Some(vec!["out".into(), "".into()]),
+            packages: vec![("mesa".to_string(), vec!["drivers".to_string()])]
+                .into_iter()
+                .collect(),
+        };
+
+        assert_eq!(policy.order_for("mesa"), ["drivers"]);
+        assert_eq!(policy.order_for("glib"), ["out", ""]);
+        assert_eq!(OutputPolicy::default().order_for("glib"), DEFAULT_ORDER);
+
+        let stores = vec![
+            store(1, 100, "mesa-24.0.1"),
+            store(2, 100, "mesa-24.0.1-drivers"),
+            store(3, 100, "glib-2.78.4"),
+            store(4, 100, "glib-2.78.4-out"),
+            store(5, 100, "glib-2.78.4-bin"),
+        ];
+
+        let (summary, _) = run(stores, DedupPolicy::Drop, &policy);
+
+        assert_eq!(
+            summary,
+            [
+                (
+                    "glib".into(),
+                    "2.78.4".into(),
+                    Some("out".into()),
+                    vec!["".into(), "bin".into()]
+                ),
+                (
+                    "mesa".into(),
+                    "24.0.1".into(),
+                    Some("drivers".into()),
+                    vec!["".into()]
+                ),
+            ]
+        );
+    }
+}
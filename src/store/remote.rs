@@ -0,0 +1,308 @@
+use super::dedup::DedupPolicy;
+use super::{Derivation, Store};
+use anyhow::{anyhow, Context, Result};
+use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::process::Command;
+
+/// The URI schemes of stores that can list every path they contain, which is needed to find the packages in them.
+///
+/// Binary caches such as `s3://`, `http(s)://`, and `file://`, as well as the legacy `ssh://` protocol,
+/// can only look up paths that are already known, so they aren't supported.
+pub const SUPPORTED_SCHEMES: [&str; 4] = ["daemon", "local", "unix://", "ssh-ng://"];
+
+/// Returns an error if the store at `uri` can't list every path it contains.
+///
+/// Local store paths, such as `/mnt/nix`, are also supported.
+pub fn check_uri(uri: &str) -> Result<()> {
+    let supported = uri.starts_with('/')
+        || SUPPORTED_SCHEMES
+            .iter()
+            .any(|scheme| uri == *scheme || (scheme.ends_with("://") && uri.starts_with(scheme)));
+
+    if supported {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "store {} is not supported, as it can't list every path it contains\nsupported stores are local paths and {}",
+            uri,
+            SUPPORTED_SCHEMES.join(", ")
+        ))
+    }
+}
+
+/// The information `nix path-info --json` provides about a single path.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PathInfo {
+    /// Only present in the array layout used before Nix 2.19.
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    registration_time: Option<u32>,
+    #[serde(default)]
+    deriver: Option<String>,
+    #[serde(default)]
+    ultimate: Option<bool>,
+    #[serde(default)]
+    ca: Option<String>,
+    #[serde(default)]
+    references: Vec<String>,
+}
+
+/// Every path in a store, as reported by `nix path-info`.
+///
+/// Remote stores don't expose the ids of their paths, so each store is given the index of its path instead.
+pub struct RemoteStore {
+    paths: Vec<(String, PathInfo)>,
+    ids: HashMap<String, u32>,
+}
+
+impl RemoteStore {
+    /// Queries every path in the store at `uri` through the `nix` command.
+    pub fn query(uri: &str) -> Result<Self> {
+        check_uri(uri)?;
+
+        let output = Command::new("nix")
+            .args([
+                "--extra-experimental-features",
+                "nix-command",
+                "path-info",
+                "--json",
+                "--all",
+                "--store",
+                uri,
+            ])
+            .output()
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => {
+                    anyhow!("failed to query store: nix was not found in PATH")
+                }
+                _ => anyhow!(err).context(format!("failed to query store {}", uri)),
+            })?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "querying store {} failed with {}: {}",
+                uri,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        Self::parse(&output.stdout).with_context(|| anyhow!("failed to parse paths of {}", uri))
+    }
+
+    /// Parses the output of `nix path-info --json`.
+    ///
+    /// Both the array of objects used before Nix 2.19 and the object keyed by path used after it are accepted.
+    fn parse(json: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Layout {
+            Array(Vec<PathInfo>),
+            Object(HashMap<String, Option<PathInfo>>),
+        }
+
+        let mut paths: Vec<_> = match serde_json::from_slice(json)? {
+            Layout::Array(infos) => infos
+                .into_iter()
+                .filter_map(|info| Some((info.path.clone()?, info)))
+                .collect(),
+            // Paths that aren't valid are listed with a null value
+            Layout::Object(infos) => infos
+                .into_iter()
+                .filter_map(|(path, info)| Some((path, info?)))
+                .collect(),
+        };
+
+        paths.sort_unstable_by(|(x, _), (y, _)| x.cmp(y));
+
+        let ids = paths
+            .iter()
+            .enumerate()
+            .map(|(id, (path, _))| (path.clone(), id as u32))
+            .collect();
+
+        Ok(Self { paths, ids })
+    }
+
+    /// Returns every unique top-level store, filtered and deduplicated the same way as `Store::all_from_system`,
+    /// along with every store that was left out of them.
+    pub fn stores(&self, policy: DedupPolicy) -> (HashSet<Store>, Vec<Store>) {
+        let stores = self
+            .paths
+            .iter()
+            .enumerate()
+            .filter(|(_, (path, info))| {
+                info.ca.is_none() && !path.ends_with("-completions") && !path.contains(".tar.")
+            })
+            .filter_map(|(id, (path, info))| {
+                let mut store = Self::parse_store(id as u32, path, Some(info))?;
+                store.deriver = info.deriver.clone();
+                Some(store)
+            });
+
+        Store::partition_unique(stores, Store::DUPLICATE_WINDOW, policy)
+    }
+
+    /// Returns the derivations of `stores`, with their direct references as dependencies.
+    pub fn derivations(&self, stores: HashSet<Store>) -> HashSet<Derivation> {
+        stores
+            .into_iter()
+            .map(|store| {
+                let references = self
+                    .paths
+                    .get(store.id as usize)
+                    .map(|(_, info)| info.references.as_slice())
+                    .unwrap_or_default();
+
+                let deps = references
+                    .iter()
+                    .filter_map(|path| {
+                        let id = self.ids.get(path).copied();
+                        let info = id.map(|id| &self.paths[id as usize].1);
+                        Self::parse_store(id.unwrap_or(0), path, info)
+                    })
+                    .filter(|dep| dep.name != store.name);
+
+                let deps = Store::get_unique(deps);
+
+                Derivation {
+                    store,
+                    deps,
+                    paths: HashMap::new(),
+                }
+            })
+            .collect()
+    }
+
+    fn parse_store(id: u32, path: &str, info: Option<&PathInfo>) -> Option<Store> {
+        let register_time = info.and_then(|info| info.registration_time).unwrap_or(0);
+
+        let mut store = Store::parse(id, register_time, path)?;
+        store.locally_built = info.and_then(|info| info.ultimate);
+        Some(store)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn summarize(derivs: HashSet<Derivation>) -> Vec<(String, String, Vec<String>)> {
+        let mut derivs = derivs
+            .into_iter()
+            .map(|deriv| {
+                let mut deps = deriv
+                    .deps
+                    .into_iter()
+                    .map(|dep| format!("{}-{}", dep.name, dep.version))
+                    .collect::<Vec<_>>();
+
+                deps.sort_unstable();
+                (deriv.store.name, deriv.store.version, deps)
+            })
+            .collect::<Vec<_>>();
+
+        derivs.sort_unstable();
+        derivs
+    }
+
+    const PREFIX: &str = "/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-";
+
+    fn parse_both_layouts(paths: &[(&str, serde_json::Value)]) -> [RemoteStore; 2] {
+        let array = paths
+            .iter()
+            .map(|(name, info)| {
+                let mut info = info.clone();
+                info["path"] = format!("{}{}", PREFIX, name).into();
+                info
+            })
+            .collect::<Vec<_>>();
+
+        let object = paths
+            .iter()
+            .map(|(name, info)| (format!("{}{}", PREFIX, name), info.clone()))
+            .collect::<serde_json::Map<_, _>>();
+
+        let array = serde_json::to_vec(&array).unwrap();
+        let object = serde_json::to_vec(&object).unwrap();
+
+        [
+            RemoteStore::parse(&array).unwrap(),
+            RemoteStore::parse(&object).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn parse_path_info() {
+        use serde_json::json;
+
+        let nss = format!("{}nss-3.98", PREFIX);
+
+        let paths = [
+            (
+                "firefox-123.0",
+                json!({
+                    "registrationTime": 200,
+                    "deriver": "/nix/store/abc-firefox-123.0.drv",
+                    "ultimate": true,
+                    "references": [nss, format!("{}firefox-123.0", PREFIX)],
+                }),
+            ),
+            ("nss-3.98", json!({ "registrationTime": 190 })),
+            ("fixed-output-1.0", json!({ "ca": "fixed:r:sha256:abc" })),
+            ("bash-completions", json!({})),
+        ];
+
+        for remote in &parse_both_layouts(&paths) {
+            let (stores, shadowed) = remote.stores(DedupPolicy::Drop);
+            assert_eq!(stores.len(), 2);
+            assert!(shadowed.is_empty());
+
+            let firefox = stores.get("firefox").unwrap();
+            assert_eq!(firefox.register_time, 200);
+            assert_eq!(firefox.locally_built, Some(true));
+            assert_eq!(
+                firefox.deriver.as_deref(),
+                Some("/nix/store/abc-firefox-123.0.drv")
+            );
+
+            assert_eq!(
+                summarize(remote.derivations(stores)),
+                vec![
+                    ("firefox".into(), "123.0".into(), vec!["nss-3.98".into()]),
+                    ("nss".into(), "3.98".into(), vec![]),
+                ]
+            );
+        }
+
+        // Invalid paths are listed as null in the newer layout
+        let remote = RemoteStore::parse(br#"{"/nix/store/abc-gone-1.0": null}"#).unwrap();
+        assert!(remote.stores(DedupPolicy::Drop).0.is_empty());
+    }
+
+    #[test]
+    fn check_store_uris() {
+        for uri in &[
+            "daemon",
+            "local",
+            "/mnt/nix",
+            "ssh-ng://builder",
+            "unix:///run/nix",
+        ] {
+            assert!(check_uri(uri).is_ok(), "{}", uri);
+        }
+
+        for uri in &[
+            "s3://cache",
+            "https://cache.nixos.org",
+            "ssh://builder",
+            "file:///tmp/cache",
+        ] {
+            assert!(check_uri(uri).is_err(), "{}", uri);
+        }
+    }
+}
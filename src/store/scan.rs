@@ -0,0 +1,326 @@
+use super::budget::Budget;
+use super::database::SystemDatabase;
+use super::dedup::{self, DedupPolicy, Resolved};
+use super::Store;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+/// The point up to which the paths in the Nix database have been scanned.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Watermark {
+    pub max_id: i32,
+    pub max_register_time: i32,
+    /// The total number of paths in the database, used to detect removed paths.
+    pub count: i64,
+}
+
+impl Watermark {
+    pub fn current(db: &SystemDatabase) -> Result<Self> {
+        use super::database::schema::ValidPaths::dsl::*;
+        use diesel::dsl;
+        use diesel::prelude::*;
+
+        let max_id = ValidPaths
+            .select(dsl::max(id))
+            .first::<Option<i32>>(db.conn())
+            .context("failed to get the newest path id from nix database")?;
+
+        let max_register_time = ValidPaths
+            .select(dsl::max(registrationTime))
+            .first::<Option<i32>>(db.conn())
+            .context("failed to get the newest registration time from nix database")?;
+
+        let count = ValidPaths
+            .count()
+            .get_result(db.conn())
+            .context("failed to count paths in nix database")?;
+
+        Ok(Self {
+            max_id: max_id.unwrap_or(0),
+            max_register_time: max_register_time.unwrap_or(0),
+            count,
+        })
+    }
+
+    /// Returns the number of paths in `db` that were added after this watermark.
+    fn added_since(&self, db: &SystemDatabase) -> Result<i64> {
+        use super::database::schema::ValidPaths::dsl::*;
+        use diesel::prelude::*;
+
+        ValidPaths
+            .filter(id.gt(self.max_id))
+            .count()
+            .get_result(db.conn())
+            .context("failed to count new paths in nix database")
+    }
+}
+
+/// Every store that can still affect which store of a given name is considered unique.
+///
+/// This holds the same information `Store::partition_unique` uses, so that newly registered stores
+/// can be merged in without needing every store to be parsed again.
+#[derive(Clone, Debug, Default)]
+pub struct ScanState {
+    /// The stores of each name that were registered within `Store::DUPLICATE_WINDOW` of the
+    /// newest one, sorted from newest to oldest.
+    candidates: HashMap<String, Vec<Store>>,
+}
+
+impl ScanState {
+    /// Merges `stores` into the state.
+    ///
+    /// The resulting state is always the same as if every store ever merged in was passed to
+    /// `Store::partition_unique` at once. A store that was already merged in is replaced.
+    pub fn merge<I>(mut self, stores: I) -> Self
+    where
+        I: IntoIterator<Item = Store>,
+    {
+        for store in stores {
+            let candidates = self.candidates.entry(store.name.clone()).or_default();
+
+            candidates.retain(|existing| existing.id != store.id);
+            candidates.push(store);
+
+            candidates.sort_unstable_by(|x, y| {
+                y.register_time
+                    .cmp(&x.register_time)
+                    .then_with(|| y.id.cmp(&x.id))
+            });
+
+            // Only stores within the window of the newest can make it a duplicate, and since
+            // newer stores only move the window forward, older ones will never be needed again
+            let newest = candidates[0].register_time;
+            candidates.retain(|store| newest - store.register_time < Store::DUPLICATE_WINDOW);
+        }
+
+        self
+    }
+
+    /// Returns the stores of each name after resolving duplicates with `policy`, along with every store
+    /// that was left out of them.
+    pub fn stores(&self, policy: DedupPolicy) -> (HashSet<Store>, Vec<Store>) {
+        let mut resolved = Resolved::default();
+
+        for candidates in self.candidates.values() {
+            let bucket = candidates.iter().cloned().collect();
+            dedup::resolve(bucket, Store::DUPLICATE_WINDOW, policy, &mut resolved);
+        }
+
+        (resolved.unique, resolved.shadowed)
+    }
+}
+
+/// The outcome of refreshing an `IncrementalScanner`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Refresh {
+    /// No paths were added or removed.
+    Unchanged,
+    /// The given number of new stores were merged in.
+    Merged(usize),
+    /// Paths were removed, so every store had to be scanned again.
+    Rescanned,
+}
+
+impl Refresh {
+    pub fn changed(self) -> bool {
+        self != Self::Unchanged
+    }
+}
+
+/// Keeps track of the stores on the system while only scanning newly registered paths.
+pub struct IncrementalScanner {
+    state: ScanState,
+    watermark: Watermark,
+}
+
+impl IncrementalScanner {
+    /// Creates a scanner from a full scan of `db`.
+    pub fn new(db: &SystemDatabase) -> Result<Self> {
+        // The watermark is taken first so any path registered during the scan is picked up again
+        // on the next refresh, where it will simply replace itself
+        let watermark = Watermark::current(db)?;
+        let stores = Store::from_system_since(db, None, &Budget::unlimited())?;
+
+        Ok(Self {
+            state: ScanState::default().merge(stores),
+            watermark,
+        })
+    }
+
+    /// Merges in every path registered in `db` since the last refresh.
+    ///
+    /// Since paths can only be added with a higher id, a total count that doesn't match the number
+    /// of new paths means some were garbage collected, which triggers a full scan instead.
+    ///
+    /// Note that `db` must be opened after the paths were registered to see them, as the
+    /// database is opened as immutable.
+    pub fn refresh(&mut self, db: &SystemDatabase) -> Result<Refresh> {
+        let current = Watermark::current(db)?;
+
+        if current == self.watermark {
+            return Ok(Refresh::Unchanged);
+        }
+
+        let added = self.watermark.added_since(db)?;
+
+        if current.count != self.watermark.count + added {
+            *self = Self::new(db)?;
+            return Ok(Refresh::Rescanned);
+        }
+
+        let stores = Store::from_system_since(db, Some(self.watermark), &Budget::unlimited())?;
+        let num_stores = stores.len();
+
+        self.state = mem::take(&mut self.state).merge(stores);
+        self.watermark = current;
+
+        Ok(Refresh::Merged(num_stores))
+    }
+
+    pub fn stores(&self, policy: DedupPolicy) -> (HashSet<Store>, Vec<Store>) {
+        self.state.stores(policy)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::database::fixture;
+    use crate::testing::{self, Rng};
+
+    fn summarize(stores: HashSet<Store>) -> Vec<(String, String, u32)> {
+        let mut stores = stores
+            .into_iter()
+            .map(|store| (store.name, store.version, store.id))
+            .collect::<Vec<_>>();
+
+        stores.sort_unstable();
+        stores
+    }
+
+    /// Returns stores as they would be registered over time, with the same names and versions
+    /// showing up repeatedly so duplicates are common.
+    fn random_stores(rng: &mut Rng, num: u32) -> Vec<Store> {
+        const NAMES: [&str; 4] = ["firefox", "glibc", "nss", "mesa"];
+        const VERSIONS: [&str; 3] = ["1.0", "1.1", "2.0"];
+
+        let mut register_time = 100_000;
+
+        (1..=num)
+            .map(|id| {
+                // Mostly register paths close together, with the occasional update much later
+                register_time += match rng.below(4) {
+                    0 => Store::DUPLICATE_WINDOW + rng.below(100) as u32,
+                    1 => 0,
+                    _ => rng.below(Store::DUPLICATE_WINDOW as u64 / 2) as u32,
+                };
+
+                let name = NAMES[rng.below(NAMES.len() as u64) as usize];
+                let version = VERSIONS[rng.below(VERSIONS.len() as u64) as usize];
+
+                Store {
+                    id,
+                    register_time,
+                    ..testing::store(name, version)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merge_matches_full_scan() {
+        let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..200 {
+            let num = 1 + rng.below(30) as u32;
+            let stores = random_stores(&mut rng, num);
+
+            // Merge the stores in randomly sized batches, as they would be seen across refreshes
+            let mut state = ScanState::default();
+            let mut remaining = &stores[..];
+
+            while !remaining.is_empty() {
+                let len = 1 + rng.below(remaining.len() as u64) as usize;
+                let (batch, rest) = remaining.split_at(len);
+
+                state = state.merge(batch.to_vec());
+                remaining = rest;
+            }
+
+            let remerged = state.clone().merge(stores.clone());
+
+            for &policy in &[
+                DedupPolicy::Drop,
+                DedupPolicy::KeepNewest,
+                DedupPolicy::KeepAllTagged,
+            ] {
+                let full = Store::partition_unique(
+                    stores.iter().cloned(),
+                    Store::DUPLICATE_WINDOW,
+                    policy,
+                );
+                let full = summarize(full.0);
+
+                assert_eq!(
+                    summarize(state.stores(policy).0),
+                    full,
+                    "{:?}: {:?}",
+                    policy,
+                    stores
+                );
+
+                // Merging stores a second time shouldn't change anything
+                assert_eq!(
+                    summarize(remerged.stores(policy).0),
+                    full,
+                    "{:?} remerged: {:?}",
+                    policy,
+                    stores
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn refresh_scanner() {
+        let db = fixture::empty();
+        fixture::add_path(&db, 1, "firefox-120.0", 100);
+        fixture::add_path(&db, 2, "nss-3.95", 100);
+
+        let mut scanner = IncrementalScanner::new(&db).unwrap();
+        assert_eq!(scanner.refresh(&db).unwrap(), Refresh::Unchanged);
+
+        // A separate update
+        fixture::add_path(&db, 3, "firefox-121.0", 100_000);
+        assert_eq!(scanner.refresh(&db).unwrap(), Refresh::Merged(1));
+
+        // Two versions from the same update are duplicates
+        fixture::add_path(&db, 4, "nss-3.96", 100_000);
+        fixture::add_path(&db, 5, "nss-3.97", 100_010);
+        assert_eq!(scanner.refresh(&db).unwrap(), Refresh::Merged(2));
+
+        assert_eq!(
+            summarize(scanner.stores(DedupPolicy::Drop).0),
+            summarize(
+                Store::all_from_system(&db, &Budget::unlimited(), DedupPolicy::Drop).unwrap()
+            )
+        );
+        assert_eq!(
+            summarize(scanner.stores(DedupPolicy::Drop).0),
+            vec![("firefox".into(), "121.0".into(), 3)]
+        );
+
+        // Garbage collection should be detected even when a path is added at the same time
+        fixture::remove_path(&db, 4);
+        fixture::add_path(&db, 6, "mesa-24.0", 100_020);
+        assert_eq!(scanner.refresh(&db).unwrap(), Refresh::Rescanned);
+
+        assert_eq!(
+            summarize(scanner.stores(DedupPolicy::Drop).0),
+            summarize(
+                Store::all_from_system(&db, &Budget::unlimited(), DedupPolicy::Drop).unwrap()
+            )
+        );
+    }
+}
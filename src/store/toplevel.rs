@@ -0,0 +1,83 @@
+use super::database::SystemDatabase;
+use super::{explain, Store};
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// The link in a NixOS system that points to the environment built from `environment.systemPackages`.
+pub const SYSTEM_PATH_LINK: &str = "sw";
+
+/// Returns the names of the packages the environment at `env_path` was built from, such as the `system-path`
+/// that the `sw` link of a NixOS system points to.
+///
+/// An environment refers to every package it was built from, and nothing else, so its direct references are
+/// exactly the packages that were explicitly installed. References that can't be parsed as a package, such as
+/// generated files without a version, are left out.
+pub fn package_names(db: &SystemDatabase, env_path: &Path) -> Result<HashSet<String>> {
+    use super::database::schema::Refs::dsl::*;
+    use super::database::schema::ValidPaths::dsl::*;
+    use diesel::prelude::*;
+
+    let env_id = explain::id_of(db, env_path)?.ok_or_else(|| {
+        anyhow!(
+            "{} is not a valid path in the nix database",
+            env_path.display()
+        )
+    })?;
+
+    let paths = Refs
+        .inner_join(ValidPaths.on(id.eq(reference)))
+        .filter(referrer.eq(env_id))
+        .filter(reference.ne(env_id))
+        .select(path)
+        .get_results::<String>(db.conn())
+        .with_context(|| anyhow!("failed to get the references of {}", env_path.display()))?;
+
+    Ok(paths
+        .iter()
+        .filter_map(|store_path| Store::parse(0, 0, store_path))
+        .map(|store| store.name)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::database::fixture;
+
+    #[test]
+    fn names_of_installed_packages() {
+        let db = fixture::empty();
+
+        let names = [
+            "system-path",
+            "firefox-124.0",
+            "git-2.44.0",
+            "etc-profile",
+            "glibc-2.39",
+        ];
+
+        for (id, name) in names.iter().enumerate() {
+            fixture::add_path(&db, id as i32 + 1, name, 0);
+        }
+
+        // glibc is only a dependency of firefox, so it wasn't installed
+        for (referrer, reference) in &[(1, 1), (1, 2), (1, 3), (1, 4), (2, 5)] {
+            fixture::add_ref(&db, *referrer, *reference);
+        }
+
+        let env_path = Path::new("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-system-path");
+
+        let mut names = package_names(&db, env_path)
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        names.sort_unstable();
+        assert_eq!(names, ["firefox", "git"]);
+
+        let missing = Path::new("/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-other-path");
+        let err = package_names(&db, missing).unwrap_err().to_string();
+        assert!(err.contains("is not a valid path"), "{}", err);
+    }
+}
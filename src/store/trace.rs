@@ -0,0 +1,225 @@
+use super::ecosystem::Ecosystem;
+use super::{Heuristic, Layout, Store};
+use std::iter;
+use std::str;
+
+/// What a fragment of a store name was taken to be.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FragmentKind {
+    Name,
+    /// The fragment the version starts with.
+    VersionStart,
+    /// A fragment after the start of the version, such as the `rc5` in `4.0-rc5`.
+    Version,
+    Suffix,
+}
+
+/// Every step `Store::parse` takes to parse a path, for finding out why a path isn't parsed as expected.
+#[derive(Debug)]
+pub struct ParseTrace {
+    /// The name of the store path without its prefix, if there was a prefix to strip.
+    pub stripped: Option<String>,
+    /// The name the ecosystem rules rewrote the stripped name to, along with its ecosystem, if any rule matched.
+    pub ecosystem: Option<(String, Ecosystem)>,
+    /// Every fragment between the delimiters of the name that was parsed, in order.
+    pub fragments: Vec<(String, FragmentKind)>,
+    /// Every heuristic that decided where the parts of the name are, in the order they were applied in.
+    pub heuristics: Vec<Heuristic>,
+    /// What `Store::parse` returns for the path.
+    pub store: Option<Store>,
+}
+
+impl ParseTrace {
+    pub fn new(path: &str) -> Self {
+        let stripped =
+            Store::strip_prefix(path.as_bytes()).and_then(|name| str::from_utf8(name).ok());
+
+        let stripped = match stripped {
+            Some(stripped) => stripped,
+            None => {
+                return Self {
+                    stripped: None,
+                    ecosystem: None,
+                    fragments: Vec::new(),
+                    heuristics: Vec::new(),
+                    store: None,
+                }
+            }
+        };
+
+        let ecosystem = Store::apply_ecosystem_rules(stripped);
+        let name = ecosystem
+            .as_ref()
+            .map_or(stripped, |(rewritten, _)| rewritten.as_str());
+
+        let layout = Layout::of(name.as_bytes());
+
+        Self {
+            stripped: Some(stripped.into()),
+            fragments: classify(name, &layout),
+            heuristics: layout.heuristics.to_vec(),
+            ecosystem,
+            store: Store::parse(0, 0, path),
+        }
+    }
+}
+
+/// Splits `name` into its fragments, along with what `layout` says each of them is.
+fn classify(name: &str, layout: &Layout) -> Vec<(String, FragmentKind)> {
+    let starts = iter::once(0).chain(layout.delimiters.iter().map(|&delimiter| delimiter + 1));
+    let ends = layout
+        .delimiters
+        .iter()
+        .copied()
+        .chain(iter::once(name.len()));
+
+    // Every fragment besides the first follows a delimiter, which is what the version and suffix are found by
+    let preceding = iter::once(None).chain(layout.delimiters.iter().copied().map(Some));
+
+    starts
+        .zip(ends)
+        .zip(preceding)
+        .map(|((start, end), delimiter)| {
+            let kind = match (delimiter, layout.version_start) {
+                (Some(delimiter), _) if delimiter >= layout.suffix_start => FragmentKind::Suffix,
+                (Some(delimiter), Some(version)) if delimiter == version => {
+                    FragmentKind::VersionStart
+                }
+                (Some(delimiter), Some(version)) if delimiter > version => FragmentKind::Version,
+                _ => FragmentKind::Name,
+            };
+
+            (name[start..end].into(), kind)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use FragmentKind::*;
+
+    const PREFIX: &str = "/nix/store/zx6vs1b6xf07cprslk9is1fhwih21ix5-";
+
+    type Case<'a> = (&'a str, &'a [(&'a str, FragmentKind)], &'a [Heuristic]);
+
+    fn trace(name: &str) -> ParseTrace {
+        ParseTrace::new(&format!("{}{}", PREFIX, name))
+    }
+
+    fn fragments(trace: &ParseTrace) -> Vec<(&str, FragmentKind)> {
+        trace
+            .fragments
+            .iter()
+            .map(|(text, kind)| (text.as_str(), *kind))
+            .collect()
+    }
+
+    #[test]
+    fn trace_tricky_paths() {
+        let cases: [Case; 7] = [
+            (
+                "pcre-8.42",
+                &[("pcre", Name), ("8.42", VersionStart)],
+                &[Heuristic::FastPath],
+            ),
+            (
+                "dxvk-v1.4.6-bin",
+                &[("dxvk", Name), ("v1.4.6", VersionStart), ("bin", Suffix)],
+                &[Heuristic::KnownOutputs, Heuristic::VersionPrefix],
+            ),
+            (
+                "wine-wow-4.0-rc5-staging",
+                &[
+                    ("wine", Name),
+                    ("wow", Name),
+                    ("4.0", VersionStart),
+                    ("rc5", Version),
+                    ("staging", Suffix),
+                ],
+                &[Heuristic::UnversionedSuffix],
+            ),
+            (
+                "mesa-24.0.1-dev-3",
+                &[
+                    ("mesa", Name),
+                    ("24.0.1", VersionStart),
+                    ("dev", Suffix),
+                    ("3", Suffix),
+                ],
+                &[Heuristic::KnownOutputs, Heuristic::NumberedOutput],
+            ),
+            (
+                "rpcs3-9165-8ca53f9",
+                &[
+                    ("rpcs3", Name),
+                    ("9165", VersionStart),
+                    ("8ca53f9", Version),
+                ],
+                &[],
+            ),
+            (
+                "open-3d-viewer-1.0",
+                &[
+                    ("open", Name),
+                    ("3d", Name),
+                    ("viewer", Name),
+                    ("1.0", VersionStart),
+                ],
+                &[Heuristic::LaterVersion],
+            ),
+            (
+                "no-version-dev-bin",
+                &[
+                    ("no", Name),
+                    ("version", Name),
+                    ("dev", Suffix),
+                    ("bin", Suffix),
+                ],
+                &[Heuristic::KnownOutputs],
+            ),
+        ];
+
+        for (name, expected_fragments, expected_heuristics) in &cases {
+            let trace = trace(name);
+
+            assert_eq!(trace.stripped.as_deref(), Some(*name));
+            assert_eq!(fragments(&trace), *expected_fragments, "{}", name);
+            assert_eq!(trace.heuristics, *expected_heuristics, "{}", name);
+
+            let expected = Store::parse(0, 0, format!("{}{}", PREFIX, name))
+                .map(|store| (store.name, store.version, store.suffix));
+
+            let traced = trace
+                .store
+                .map(|store| (store.name, store.version, store.suffix));
+
+            assert_eq!(traced, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn trace_ecosystem_rewrites() {
+        let trace = trace("ocaml4.14.1-zarith-1.13");
+
+        let (rewritten, ecosystem) = trace.ecosystem.as_ref().unwrap();
+        assert_eq!(rewritten, "zarith-1.13");
+        assert_eq!(ecosystem.name, "ocaml");
+
+        // The rewritten name is what gets split into fragments
+        assert_eq!(
+            fragments(&trace),
+            [("zarith", Name), ("1.13", VersionStart)]
+        );
+        assert_eq!(trace.store.unwrap().name, "zarith");
+    }
+
+    #[test]
+    fn trace_unstrippable_paths() {
+        let trace = ParseTrace::new("pcre-8.42");
+
+        assert_eq!(trace.stripped, None);
+        assert!(trace.fragments.is_empty() && trace.heuristics.is_empty());
+        assert!(trace.store.is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use super::database::SystemDatabase;
+use super::{Derivation, Store, StoreKey};
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+
+/// Which stores of a saved state are no longer in the Nix store.
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// The number of packages that were checked.
+    pub packages: usize,
+    /// The number of unique dependencies that were checked, not counting ones that are also packages.
+    pub deps: usize,
+    /// Every package that is gone, sorted by name.
+    pub missing_packages: Vec<Store>,
+    /// Every dependency that is gone, sorted by name.
+    pub missing_deps: Vec<Store>,
+}
+
+impl Verification {
+    /// Checks whether every package and dependency in `packages` still has a valid path in `db`.
+    ///
+    /// States only keep the name, version, and suffix of each store, so a store counts as present when any valid
+    /// path parses to the same name, version, and suffix. Every path in `db` is read in batches of `db.batch_size()`
+    /// rows, since dependencies can be any path rather than only the top-level ones that are scanned when diffing.
+    pub fn check(db: &SystemDatabase, packages: &HashSet<Derivation>) -> Result<Self> {
+        let live = live_keys(db)?;
+        Ok(Self::against(&live, packages))
+    }
+
+    fn against(live: &HashSet<StoreKey>, packages: &HashSet<Derivation>) -> Self {
+        let mut seen = HashSet::new();
+        let mut result = Self::default();
+
+        for pkg in packages {
+            seen.insert(pkg.store.key());
+            result.packages += 1;
+
+            if !live.contains(&pkg.store.key()) {
+                result.missing_packages.push(pkg.store.clone());
+            }
+        }
+
+        for dep in packages.iter().flat_map(|pkg| &pkg.deps) {
+            let key = dep.key();
+
+            if seen.contains(&key) {
+                continue;
+            }
+
+            result.deps += 1;
+
+            if !live.contains(&key) {
+                result.missing_deps.push(dep.clone());
+            }
+
+            seen.insert(key);
+        }
+
+        let sort = |stores: &mut Vec<Store>| {
+            stores.sort_unstable_by_key(Store::key);
+        };
+
+        sort(&mut result.missing_packages);
+        sort(&mut result.missing_deps);
+        result
+    }
+
+    /// Returns the number of stores that are gone.
+    pub fn missing(&self) -> usize {
+        self.missing_packages.len() + self.missing_deps.len()
+    }
+}
+
+/// Returns the name, version, and suffix of every valid path in `db` that can be parsed as a store.
+fn live_keys(db: &SystemDatabase) -> Result<HashSet<StoreKey>> {
+    use super::database::schema::ValidPaths::dsl::*;
+    use diesel::prelude::*;
+
+    let mut keys = HashSet::new();
+
+    // The id of the last path read, which the next batch starts after
+    let mut last_id = 0;
+
+    loop {
+        let rows = ValidPaths
+            .filter(id.gt(last_id))
+            .select((id, path))
+            .order(id.asc())
+            .limit(db.batch_size() as i64)
+            .get_results::<(i32, String)>(db.conn())
+            .context("failed to get paths from nix database")?;
+
+        let num_rows = rows.len();
+
+        if let Some((last, _)) = rows.last() {
+            last_id = *last;
+        }
+
+        for (_, store_path) in rows {
+            if let Some(store) = Store::parse(0, 0, &store_path) {
+                keys.insert(store.key());
+            }
+        }
+
+        if num_rows < db.batch_size() {
+            break;
+        }
+    }
+
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::database::fixture;
+    use std::collections::HashMap;
+
+    fn store(name: &str) -> Store {
+        Store::parse_stripped(name).unwrap()
+    }
+
+    #[test]
+    fn find_collected_stores() {
+        let mut db = fixture::empty();
+        db.set_batch_size(2);
+
+        let live = [
+            "firefox-124.0",
+            "nss-3.98",
+            "glibc-2.39",
+            "glibc-2.39-bin",
+            "openssl-3.0.13",
+        ];
+
+        for (i, name) in live.iter().enumerate() {
+            fixture::add_path(&db, i as i32 + 1, name, 0);
+        }
+
+        let package = |name: &str, deps: &[&str]| Derivation {
+            store: store(name),
+            deps: deps.iter().map(|dep| store(dep)).collect(),
+            paths: HashMap::new(),
+        };
+
+        let packages = vec![
+            package("firefox-124.0", &["nss-3.97", "glibc-2.39"]),
+            package("openssl-3.0.12", &["glibc-2.39", "zlib-1.3"]),
+            package("glibc-2.39-bin", &[]),
+        ]
+        .into_iter()
+        .collect();
+
+        let result = Verification::check(&db, &packages).unwrap();
+
+        let names = |stores: &[Store]| {
+            stores
+                .iter()
+                .map(|store| format!("{}-{}", store.name, store.version))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(result.packages, 3);
+        assert_eq!(result.deps, 3);
+        assert_eq!(names(&result.missing_packages), ["openssl-3.0.12"]);
+        assert_eq!(names(&result.missing_deps), ["nss-3.97", "zlib-1.3"]);
+        assert_eq!(result.missing(), 3);
+    }
+}
@@ -0,0 +1,507 @@
+use smallvec::SmallVec;
+use std::cmp::Ordering;
+
+/// A single component of a version.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Part<'a> {
+    /// The digits of a number without its leading zeros, so `0` is empty.
+    ///
+    /// Numbers are never parsed, as dates and build numbers can be too long to fit in any integer.
+    Num(&'a str),
+    Text(&'a str),
+}
+
+impl<'a> Ord for Part<'a> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            // Without leading zeros, a longer number is always a larger one
+            (Part::Num(x), Part::Num(y)) => x.len().cmp(&y.len()).then_with(|| x.cmp(y)),
+            (Part::Text(x), Part::Text(y)) => x.cmp(y),
+            // Text usually marks a pre-release, such as the `rc` in `1.0rc1`
+            (Part::Num(_), Part::Text(_)) => Ordering::Greater,
+            (Part::Text(_), Part::Num(_)) => Ordering::Less,
+        }
+    }
+}
+
+impl<'a> PartialOrd for Part<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A version split into its numeric and textual components, so it can be ordered.
+///
+/// Components are separated by `.`, `-` and `_`, as well as wherever digits and letters meet,
+/// so `1.0rc5` is made up of `1`, `0`, `rc`, and `5`. Build metadata is ignored like in SemVer,
+/// so `1.0.0+build.5` is made up of `1`, `0`, and `0`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version<'a> {
+    parts: SmallVec<[Part<'a>; 6]>,
+}
+
+impl<'a> Version<'a> {
+    pub fn parse(version: &'a str) -> Self {
+        let mut parts = SmallVec::new();
+
+        for segment in strip_build_metadata(version).split(&['.', '-', '_'][..]) {
+            let mut rest = segment;
+
+            while let Some(first) = rest.chars().next() {
+                let is_digit = first.is_ascii_digit();
+                let end = rest
+                    .find(|ch: char| ch.is_ascii_digit() != is_digit)
+                    .unwrap_or(rest.len());
+
+                let (part, remaining) = rest.split_at(end);
+
+                let part = if is_digit {
+                    Part::Num(part.trim_start_matches('0'))
+                } else {
+                    Part::Text(part)
+                };
+
+                parts.push(part);
+                rest = remaining;
+            }
+        }
+
+        Self { parts }
+    }
+
+    /// Returns true if every component of the version is a number, such as `1.2.3` but not `1.0rc1` or a commit hash.
+    ///
+    /// Only numeric versions can be reliably ordered across schemes.
+    pub fn is_numeric(&self) -> bool {
+        !self.parts.is_empty() && self.parts.iter().all(|part| matches!(part, Part::Num(_)))
+    }
+
+    /// Returns how far apart `self` and `other` are, based on the first component that differs.
+    pub fn jump(&self, other: &Self) -> Jump {
+        let len = self.parts.len().max(other.parts.len());
+
+        let first_diff = (0..len).find(|&i| self.parts.get(i) != other.parts.get(i));
+
+        match first_diff {
+            None => Jump::None,
+            Some(0) => Jump::Major,
+            Some(1) => Jump::Minor,
+            Some(2) => Jump::Patch,
+            Some(_) => Jump::Other,
+        }
+    }
+}
+
+impl<'a> Ord for Version<'a> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.parts.len().max(other.parts.len());
+
+        for i in 0..len {
+            let ordering = match (self.parts.get(i), other.parts.get(i)) {
+                (Some(x), Some(y)) => x.cmp(y),
+                // A trailing pre-release marker makes a version older, while anything else makes it newer
+                (Some(Part::Text(_)), None) => Ordering::Less,
+                (None, Some(Part::Text(_))) => Ordering::Greater,
+                (Some(_), None) => Ordering::Greater,
+                (None, Some(_)) => Ordering::Less,
+                (None, None) => Ordering::Equal,
+            };
+
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        Ordering::Equal
+    }
+}
+
+impl<'a> PartialOrd for Version<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Returns `version` without its build metadata, which is everything after the first `+`.
+///
+/// Build metadata doesn't affect precedence, so versions that only differ in it are the same version.
+pub fn strip_build_metadata(version: &str) -> &str {
+    match version.find('+') {
+        Some(start) => &version[..start],
+        None => version,
+    }
+}
+
+/// Which way a version moved.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+    Same,
+    /// The versions can't be reliably ordered, such as pre-releases, commit hashes, or numbers that could be
+    /// read either way.
+    Unknown,
+}
+
+impl From<Ordering> for Direction {
+    fn from(ordering: Ordering) -> Self {
+        match ordering {
+            Ordering::Less => Self::Up,
+            Ordering::Greater => Self::Down,
+            Ordering::Equal => Self::Same,
+        }
+    }
+}
+
+/// Returns which way the version went from `from` to `to`.
+///
+/// Only versions made up entirely of numbers have a known direction, and they are compared in this order:
+///
+/// * When both are calendar versions, such as `23.11`, `2024.02` or `2024-01-15`, they're compared as dates, so
+///   `2024.02` and `2024.2` are the same month. A two digit year is taken to be in the 2000s.
+/// * Otherwise, each component is compared in turn. Components of the same length, or without a leading zero,
+///   are compared as numbers. A leading zero, like the one in `1.08`, means the component is either a number or
+///   the digits of a fraction depending on the upstream scheme, so the direction is only known when both
+///   readings agree. `1.08` to `1.10` is an update either way, but `1.08` to `1.8` is `Unknown`.
+///
+/// Build metadata is ignored like it is when ordering versions.
+pub fn direction(from: &str, to: &str) -> Direction {
+    let (from, to) = match (numeric_components(from), numeric_components(to)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Direction::Unknown,
+    };
+
+    if let (Some(from), Some(to)) = (calendar_date(&from), calendar_date(&to)) {
+        return from.cmp(&to).into();
+    }
+
+    let len = from.len().max(to.len());
+
+    for i in 0..len {
+        let (x, y) = match (from.get(i), to.get(i)) {
+            (Some(x), Some(y)) => (*x, *y),
+            // Like when ordering versions, an extra number makes a version newer
+            (Some(_), None) => return Direction::Down,
+            (None, Some(_)) => return Direction::Up,
+            (None, None) => break,
+        };
+
+        let as_number =
+            Part::Num(x.trim_start_matches('0')).cmp(&Part::Num(y.trim_start_matches('0')));
+
+        if x.len() == y.len() || (!x.starts_with('0') && !y.starts_with('0')) {
+            if as_number != Ordering::Equal {
+                return as_number.into();
+            }
+
+            continue;
+        }
+
+        let as_fraction = x.trim_end_matches('0').cmp(y.trim_end_matches('0'));
+
+        if as_number != as_fraction {
+            return Direction::Unknown;
+        }
+
+        if as_number != Ordering::Equal {
+            return as_number.into();
+        }
+    }
+
+    Direction::Same
+}
+
+/// Returns the digits of every component of `version`, or `None` if it has a component that isn't a number.
+///
+/// Components are separated the same way as in `Version::parse`, but keep their leading zeros.
+fn numeric_components(version: &str) -> Option<SmallVec<[&str; 6]>> {
+    if !Version::parse(version).is_numeric() {
+        return None;
+    }
+
+    let components = strip_build_metadata(version)
+        .split(&['.', '-', '_'][..])
+        .filter(|component| !component.is_empty())
+        .collect();
+
+    Some(components)
+}
+
+/// Returns the year, month, and day of a calendar version split into `components`, where the day is 0 for
+/// versions without one.
+///
+/// The shapes recognized are `YY.MM` and `YYYY.MM`, as well as `YYYY.MM.DD` with any separator.
+fn calendar_date(components: &[&str]) -> Option<(u32, u32, u32)> {
+    let (year, month, day) = match components {
+        [year, month] => (*year, *month, None),
+        [year, month, day] if year.len() == 4 => (*year, *month, Some(*day)),
+        _ => return None,
+    };
+
+    // Every part is at most four digits long at this point, so it always fits
+    let year = match year.len() {
+        2 => 2000 + year.parse::<u32>().ok()?,
+        4 if year.starts_with("19") || year.starts_with("20") => year.parse().ok()?,
+        _ => return None,
+    };
+
+    let within = |digits: &str, max: u32| {
+        Some(digits)
+            .filter(|digits| digits.len() <= 2)
+            .and_then(|digits| digits.parse::<u32>().ok())
+            .filter(|value| (1..=max).contains(value))
+    };
+
+    let month = within(month, 12)?;
+
+    let day = match day {
+        Some(day) => within(day, 31)?,
+        None => 0,
+    };
+
+    Some((year, month, day))
+}
+
+/// The magnitude of a version change, in the spirit of semantic versioning.
+///
+/// Magnitudes are ordered from smallest to largest.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Jump {
+    None,
+    /// A change past the third component.
+    Other,
+    Patch,
+    Minor,
+    Major,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn order_versions() {
+        let ordered = [
+            "0.9",
+            "1.0rc1",
+            "1.0rc2",
+            "1.0",
+            "1.0.1",
+            "1.2",
+            "1.10",
+            "2.0-beta",
+            "2.0",
+            "2.0-1",
+            "2024.01.15",
+        ];
+
+        for pair in ordered.windows(2) {
+            let (older, newer) = (Version::parse(pair[0]), Version::parse(pair[1]));
+            assert!(older < newer, "{} < {}", pair[0], pair[1]);
+            assert!(newer > older, "{} > {}", pair[1], pair[0]);
+        }
+
+        assert_eq!(
+            Version::parse("1.0").cmp(&Version::parse("1-0")),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn underscore_separators() {
+        let parts = |version| Version::parse(version).parts.into_vec();
+
+        assert_eq!(parts("2021_03"), [Part::Num("2021"), Part::Num("3")]);
+        assert_eq!(
+            parts("1_0_0"),
+            [Part::Num("1"), Part::Num(""), Part::Num("")]
+        );
+        assert_eq!(
+            parts("1_0beta"),
+            [Part::Num("1"), Part::Num(""), Part::Text("beta")]
+        );
+
+        let ordered = ["1_0_0", "1_9", "1_10", "2021_03", "2021_04", "2021_04_1"];
+
+        for pair in ordered.windows(2) {
+            let (older, newer) = (Version::parse(pair[0]), Version::parse(pair[1]));
+            assert!(older < newer, "{} < {}", pair[0], pair[1]);
+        }
+
+        // Underscores and dots are interchangeable
+        assert_eq!(Version::parse("1_0_0"), Version::parse("1.0.0"));
+        assert!(Version::parse("1_10") > Version::parse("1.9"));
+        assert!(Version::parse("2021_03").is_numeric());
+        assert_eq!(
+            Version::parse("2021_03").jump(&Version::parse("2021_04")),
+            Jump::Minor
+        );
+    }
+
+    #[test]
+    fn ignore_build_metadata() {
+        let cmp = |x, y| Version::parse(x).cmp(&Version::parse(y));
+
+        assert_eq!(cmp("1.0.0+a", "1.0.0+b"), Ordering::Equal);
+        assert_eq!(cmp("1.0.0+build.5", "1.0.0"), Ordering::Equal);
+        assert_eq!(cmp("1.0.0+build.5", "1.0.1+build.1"), Ordering::Less);
+        assert_eq!(cmp("1.0.0-rc1+build.5", "1.0.0"), Ordering::Less);
+
+        assert_eq!(Version::parse("1.0.0+build.5"), Version::parse("1.0.0"));
+        assert!(Version::parse("1.0.0+build.5").is_numeric());
+        assert_eq!(
+            Version::parse("1.0.0+a").jump(&Version::parse("1.0.0+b")),
+            Jump::None
+        );
+
+        assert_eq!(strip_build_metadata("1.0.0+build.5+x"), "1.0.0");
+        assert_eq!(strip_build_metadata("1.0.0"), "1.0.0");
+    }
+
+    #[test]
+    fn huge_numbers() {
+        let ordered = [
+            "1.999999999999999999999999999999",
+            "1.000000000000000000000000000001000000000000000000000000000000",
+            "20210304123456",
+            "100000000000000000000000000000",
+            "100000000000000000000000000000.1",
+            "100000000000000000000000000001",
+            "999999999999999999999999999999",
+            "1000000000000000000000000000000",
+        ];
+
+        for pair in ordered.windows(2) {
+            let (older, newer) = (Version::parse(pair[0]), Version::parse(pair[1]));
+            assert!(older < newer, "{} < {}", pair[0], pair[1]);
+            assert!(newer > older, "{} > {}", pair[1], pair[0]);
+        }
+
+        // Numbers past u64::MAX are still numbers, and are newer than any smaller one
+        let huge = Version::parse("18446744073709551616");
+        assert!(huge.is_numeric());
+        assert!(huge > Version::parse("18446744073709551615"));
+        assert!(huge > Version::parse("2"));
+
+        // Leading zeros don't matter, however long the number is
+        assert_eq!(
+            Version::parse("1.000000000000000000000000000042"),
+            Version::parse("1.42")
+        );
+        assert_eq!(Version::parse("1.00"), Version::parse("1.0"));
+
+        assert_eq!(
+            Version::parse("100000000000000000000000000000.1")
+                .jump(&Version::parse("100000000000000000000000000000.2")),
+            Jump::Minor
+        );
+    }
+
+    #[test]
+    fn numeric_versions() {
+        assert!(Version::parse("1.2.3").is_numeric());
+        assert!(Version::parse("2024-01-15").is_numeric());
+        assert!(!Version::parse("1.0rc1").is_numeric());
+        assert!(!Version::parse("c47095a8dcfa4c376d8e9c4276865b7f298137d8").is_numeric());
+        assert!(!Version::parse("").is_numeric());
+    }
+
+    #[test]
+    fn version_directions() {
+        use Direction::*;
+
+        let cases = [
+            // Plain numbers
+            ("1.2.3", "1.2.4", Up),
+            ("1.2.3", "1.2.2", Down),
+            ("2.0", "1.10", Down),
+            ("1.9", "1.10", Up),
+            ("1.0", "1.0.0", Up),
+            ("1.0", "1", Down),
+            ("1.0.0", "1_0_0", Same),
+            ("1.0.0+a", "1.0.0+b", Same),
+            // Leading zeros on both sides with the same length compare like strings
+            ("1.08", "1.09", Up),
+            ("1.09", "1.08", Down),
+            ("2024.0115", "2024.0201", Up),
+            // A leading zero on one side reads as a number or a fraction, which only sometimes agree
+            ("1.08", "1.10", Up),
+            ("1.10", "1.08", Down),
+            ("1.0", "1.05", Up),
+            ("1.007", "1.08", Up),
+            ("1.08", "1.8", Unknown),
+            ("1.08", "1.7", Unknown),
+            ("1.8", "1.08", Unknown),
+            ("1.0", "1.00", Same),
+            // Calendar versions compare as dates
+            ("2024.02", "2024.2", Same),
+            ("22.05", "22.5", Same),
+            ("22.5", "22.11", Up),
+            ("23.11", "24.05", Up),
+            ("24.05", "23.11", Down),
+            ("23.11", "2024.05", Up),
+            ("2024.10", "2024.9", Down),
+            ("2024-01-15", "2024-1-16", Up),
+            ("2024-02-01", "2024.01.31", Down),
+            ("2024-01-05", "2024.1.5", Same),
+            // Shapes that aren't dates fall back to comparing numbers
+            ("22.13", "22.013", Unknown),
+            ("2024.0.1", "2024.00.1", Same),
+            ("1999.02", "1999.2", Same),
+            ("3000.02", "3000.2", Unknown),
+            ("2024-01-32", "2024-01-032", Unknown),
+            // Anything that isn't made up of numbers can't be ordered
+            ("1.0", "1.0rc1", Unknown),
+            ("1.0rc1", "1.0", Unknown),
+            ("c47095a8dcfa4c376d8e9c4276865b7f298137d8", "1.0", Unknown),
+            ("9165-8ca53f9", "9164-1b2c3d4", Unknown),
+            ("", "1.0", Unknown),
+        ];
+
+        for &(from, to, expected) in &cases {
+            assert_eq!(direction(from, to), expected, "{} -> {}", from, to);
+        }
+    }
+
+    #[test]
+    fn calendar_shapes() {
+        let date = |version| calendar_date(&numeric_components(version).unwrap());
+
+        assert_eq!(date("23.11"), Some((2023, 11, 0)));
+        assert_eq!(date("2024.02"), Some((2024, 2, 0)));
+        assert_eq!(date("2024-01-15"), Some((2024, 1, 15)));
+        assert_eq!(date("2024_1_5"), Some((2024, 1, 5)));
+
+        // Neither the year, month, nor day can be out of range
+        assert_eq!(date("1.10"), None);
+        assert_eq!(date("123.10"), None);
+        assert_eq!(date("22.13"), None);
+        assert_eq!(date("22.0"), None);
+        assert_eq!(date("22.005"), None);
+        assert_eq!(date("2024.01.32"), None);
+        assert_eq!(date("22.01.15"), None);
+        assert_eq!(date("1024.01"), None);
+        assert_eq!(date("2024.01.15.1"), None);
+        assert_eq!(date("2024"), None);
+
+        assert!(numeric_components("1.0rc1").is_none());
+        assert_eq!(
+            numeric_components("1.08+build.5").unwrap().into_vec(),
+            ["1", "08"]
+        );
+    }
+
+    #[test]
+    fn version_jumps() {
+        let jump = |from, to| Version::parse(from).jump(&Version::parse(to));
+
+        assert_eq!(jump("1.0", "1.0"), Jump::None);
+        assert_eq!(jump("1.2.3", "2.0.0"), Jump::Major);
+        assert_eq!(jump("1.2.3", "1.3.0"), Jump::Minor);
+        assert_eq!(jump("1.2.3", "1.2.4"), Jump::Patch);
+        assert_eq!(jump("1.2.3.4", "1.2.3.5"), Jump::Other);
+        assert_eq!(jump("1.2", "1.2.1"), Jump::Patch);
+        assert_eq!(jump("3.97", "3.97rc1"), Jump::Patch);
+        assert!(Jump::Major > Jump::Minor && Jump::Patch > Jump::Other);
+    }
+}
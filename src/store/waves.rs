@@ -0,0 +1,190 @@
+use super::diff::PackageDiff;
+
+/// The default number of seconds between two registration times that separates them into different waves.
+pub const DEFAULT_GAP: u32 = 10 * 60;
+
+/// Package diffs whose new stores were registered around the same time, such as by a single rebuild.
+#[derive(Debug)]
+pub struct Wave {
+    /// The registration time of the first store of the wave.
+    pub start: u32,
+    pub diffs: Vec<PackageDiff>,
+}
+
+/// Splits `diffs` into waves of updates, in the order they were registered in.
+///
+/// The registration times of every new store in `diffs` are sorted, and any gap between two of them that is longer
+/// than `gap` seconds starts a new wave. Each diff goes into the wave of its own store, or the wave of its newest
+/// dependency when only its dependencies changed. Waves that no diff went into are left out, and the order of the
+/// diffs within each wave is kept.
+pub fn group(diffs: Vec<PackageDiff>, gap: u32) -> Vec<Wave> {
+    let mut times = diffs
+        .iter()
+        .flat_map(|diff| diff.pkg.iter().chain(&diff.deps))
+        .map(|store| store.register_time)
+        .collect::<Vec<_>>();
+
+    let mut waves = cluster(&mut times, gap)
+        .into_iter()
+        .map(|start| Wave {
+            start,
+            diffs: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+
+    for diff in diffs {
+        let time = time_of(&diff);
+
+        let index = waves
+            .iter()
+            .rposition(|wave| wave.start <= time)
+            .unwrap_or(0);
+
+        match waves.get_mut(index) {
+            Some(wave) => wave.diffs.push(diff),
+            // There are only no waves when no diff has a store, so every diff goes into the same one
+            None => waves.push(Wave {
+                start: time,
+                diffs: vec![diff],
+            }),
+        }
+    }
+
+    waves.retain(|wave| !wave.diffs.is_empty());
+    waves
+}
+
+/// Returns the first time of every cluster of `times` that is separated from the others by more than `gap` seconds,
+/// in order.
+fn cluster(times: &mut [u32], gap: u32) -> Vec<u32> {
+    times.sort_unstable();
+
+    let mut starts = Vec::new();
+
+    for (i, &time) in times.iter().enumerate() {
+        if i == 0 || time - times[i - 1] > gap {
+            starts.push(time);
+        }
+    }
+
+    starts
+}
+
+/// Returns the registration time that decides which wave `diff` goes into.
+fn time_of(diff: &PackageDiff) -> u32 {
+    match &diff.pkg {
+        Some(pkg) => pkg.register_time,
+        None => diff
+            .deps
+            .iter()
+            .map(|dep| dep.register_time)
+            .max()
+            .unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::diff::StoreDiff;
+
+    const HOUR: u32 = 60 * 60;
+
+    fn store(name: &str, register_time: u32) -> StoreDiff {
+        StoreDiff {
+            name: name.into(),
+            suffix: None,
+            suffix_from: None,
+            ver_from: "1.0".into(),
+            ver_to: "2.0".into(),
+            register_time,
+        }
+    }
+
+    fn diff(name: &str, register_time: Option<u32>, deps: &[u32]) -> PackageDiff {
+        PackageDiff {
+            name: name.into(),
+            pkg: register_time.map(|time| store(name, time)),
+            deps: deps
+                .iter()
+                .enumerate()
+                .map(|(i, &time)| store(&format!("{}-dep{}", name, i), time))
+                .collect(),
+            wrapper: None,
+            split_outputs: Vec::new(),
+            referrer_count: None,
+        }
+    }
+
+    fn summarize(waves: &[Wave]) -> Vec<(u32, Vec<&str>)> {
+        waves
+            .iter()
+            .map(|wave| {
+                let names = wave.diffs.iter().map(|diff| diff.name.as_str()).collect();
+                (wave.start, names)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn group_single_wave() {
+        // Stores registered a few minutes apart over a long rebuild are still a single wave
+        let diffs = vec![
+            diff("firefox", Some(1000), &[1100]),
+            diff("mesa", Some(1000 + 9 * 60), &[]),
+            diff("zsh", None, &[1000 + 18 * 60]),
+        ];
+
+        assert_eq!(
+            summarize(&group(diffs, DEFAULT_GAP)),
+            [(1000, vec!["firefox", "mesa", "zsh"])]
+        );
+    }
+
+    #[test]
+    fn group_two_waves() {
+        let diffs = vec![
+            diff("firefox", Some(HOUR), &[]),
+            diff("mesa", Some(5 * HOUR), &[5 * HOUR + 60]),
+            diff("neovim", Some(HOUR + 30), &[]),
+            // A dependency from the first wave doesn't pull a package out of the wave of its own store
+            diff("vulkan-loader", Some(5 * HOUR), &[HOUR]),
+        ];
+
+        assert_eq!(
+            summarize(&group(diffs, DEFAULT_GAP)),
+            [
+                (HOUR, vec!["firefox", "neovim"]),
+                (5 * HOUR, vec!["mesa", "vulkan-loader"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn group_three_waves() {
+        let diffs = vec![
+            diff("firefox", Some(HOUR), &[]),
+            // Only dependencies changed, so the newest of them decides the wave
+            diff("gimp", None, &[HOUR, 3 * HOUR + 120]),
+            diff("mesa", Some(3 * HOUR), &[]),
+            diff("zsh", Some(8 * HOUR), &[]),
+            // A wave made up only of dependencies of packages in other waves is left out
+            diff("wine", Some(8 * HOUR + 60), &[6 * HOUR]),
+        ];
+
+        let waves = group(diffs, DEFAULT_GAP);
+
+        assert_eq!(
+            summarize(&waves),
+            [
+                (HOUR, vec!["firefox"]),
+                (3 * HOUR, vec!["gimp", "mesa"]),
+                (8 * HOUR, vec!["zsh", "wine"]),
+            ]
+        );
+
+        // A wider gap merges waves together
+        let diffs = waves.into_iter().flat_map(|wave| wave.diffs).collect();
+        assert_eq!(group(diffs, 2 * HOUR + HOUR / 2).len(), 2);
+    }
+}
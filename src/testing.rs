@@ -0,0 +1,41 @@
+use crate::store::Store;
+
+/// A small xorshift generator, so randomized fixtures are reproducible.
+pub struct Rng(u64);
+
+impl Rng {
+    /// Creates a generator from a non-zero `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number in the range of `0..max`.
+    pub fn below(&mut self, max: u64) -> u64 {
+        self.next() % max
+    }
+}
+
+/// Returns a store called `name` at `version` with every other field left unset.
+///
+/// Tests set the fields they care about with struct update syntax, so adding a field to `Store` only
+/// means updating this.
+pub fn store(name: &str, version: &str) -> Store {
+    Store {
+        id: 0,
+        register_time: 0,
+        name: name.into(),
+        version: version.into(),
+        suffix: None,
+        deriver: None,
+        locally_built: None,
+        referrer_count: None,
+        ecosystem: None,
+    }
+}
@@ -0,0 +1,473 @@
+use crate::profile::SYSTEM_PROFILE;
+use anyhow::{anyhow, Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The directory profiles given by name live in.
+pub const PROFILES_DIR: &str = "/nix/var/nix/profiles";
+
+/// What should trigger the generated units.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UnitMode {
+    /// Diff and save the state every time a profile changes, such as after `nixos-rebuild switch`.
+    PostRebuild,
+}
+
+impl FromStr for UnitMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "post-rebuild" => Ok(Self::PostRebuild),
+            _ => Err(anyhow!(
+                "unknown unit mode \"{}\", expected post-rebuild",
+                value
+            )),
+        }
+    }
+}
+
+/// Whether the units run under the user's own service manager, or the system's.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UnitScope {
+    User,
+    System,
+}
+
+impl UnitScope {
+    fn target(self) -> &'static str {
+        match self {
+            Self::User => "default.target",
+            Self::System => "multi-user.target",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnitOptions {
+    pub mode: UnitMode,
+    /// The profile to watch for changes.
+    pub profile: PathBuf,
+    pub scope: UnitScope,
+    /// Extra arguments to pass when showing the diff.
+    pub diff_args: Vec<String>,
+    /// Write the units to the user's unit directory instead of printing them.
+    pub install: bool,
+    /// Overwrite existing units when installing.
+    pub force: bool,
+}
+
+impl UnitOptions {
+    /// Returns the path of the profile named `name`, which is relative to `PROFILES_DIR` unless it's absolute.
+    pub fn profile_path(name: &str) -> PathBuf {
+        Path::new(PROFILES_DIR).join(name)
+    }
+
+    pub fn default_profile() -> PathBuf {
+        PathBuf::from(SYSTEM_PROFILE)
+    }
+}
+
+/// A single generated unit file.
+#[derive(Debug, PartialEq)]
+pub struct Unit {
+    pub name: String,
+    pub contents: String,
+}
+
+/// Returns the name of the unit that should be enabled to start watching for changes.
+pub fn trigger_name(mode: UnitMode) -> String {
+    match mode {
+        UnitMode::PostRebuild => format!("{}-post-rebuild.path", env!("CARGO_PKG_NAME")),
+    }
+}
+
+/// Generates the units described by `opts`, where every command runs `exe` with `data_dir` if it was given.
+///
+/// For `UnitMode::PostRebuild`, a path unit starts the save state service whenever the profile changes.
+/// The save state service pulls in the diff service and is ordered after it, so the diff is always
+/// shown against the state saved after the previous change. A failed diff, such as when no state
+/// has been saved yet, doesn't stop the state from being saved.
+pub fn generate(exe: &Path, data_dir: Option<&Path>, opts: &UnitOptions) -> Result<Vec<Unit>> {
+    let name = env!("CARGO_PKG_NAME");
+
+    let mut base = vec![path_arg(exe)?];
+
+    if let Some(data_dir) = data_dir {
+        base.push("--data-dir".into());
+        base.push(path_arg(data_dir)?);
+    }
+
+    let diff_cmd = base
+        .iter()
+        .cloned()
+        .chain(opts.diff_args.iter().map(|arg| escape_arg(arg)))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let save_cmd = format!("{} --save-state", base.join(" "));
+    let profile = escape_path(&opts.profile)?;
+
+    let diff_service = format!("{}-diff.service", name);
+    let save_service = format!("{}-save-state.service", name);
+
+    match opts.mode {
+        UnitMode::PostRebuild => Ok(vec![
+            Unit {
+                name: trigger_name(opts.mode),
+                contents: format!(
+                    "[Unit]\n\
+                     Description=Watch {profile} for changes with {name}\n\
+                     \n\
+                     [Path]\n\
+                     PathChanged={profile}\n\
+                     Unit={save}\n\
+                     \n\
+                     [Install]\n\
+                     WantedBy={target}\n",
+                    profile = profile,
+                    name = name,
+                    save = save_service,
+                    target = opts.scope.target(),
+                ),
+            },
+            Unit {
+                name: save_service.clone(),
+                contents: format!(
+                    "[Unit]\n\
+                     Description=Save the package state with {name}\n\
+                     Wants={diff}\n\
+                     After={diff}\n\
+                     \n\
+                     [Service]\n\
+                     Type=oneshot\n\
+                     ExecStart={cmd}\n",
+                    name = name,
+                    diff = diff_service,
+                    cmd = save_cmd,
+                ),
+            },
+            Unit {
+                name: diff_service,
+                contents: format!(
+                    "[Unit]\n\
+                     Description=Show what changed since the last package state saved by {name}\n\
+                     \n\
+                     [Service]\n\
+                     Type=oneshot\n\
+                     ExecStart={cmd}\n",
+                    name = name,
+                    cmd = diff_cmd,
+                ),
+            },
+        ]),
+    }
+}
+
+/// Returns the directory the user's own units are installed to.
+pub fn user_unit_dir() -> Result<PathBuf> {
+    dirs_next::config_dir()
+        .map(|dir| dir.join("systemd").join("user"))
+        .ok_or_else(|| anyhow!("failed to find the user's config directory"))
+}
+
+/// Writes `units` to `dir`, and returns the path of every unit that was written.
+///
+/// Nothing is written if any of the units already exist, unless `force` is set.
+pub fn install(dir: &Path, units: &[Unit], force: bool) -> Result<Vec<PathBuf>> {
+    let paths = units
+        .iter()
+        .map(|unit| dir.join(&unit.name))
+        .collect::<Vec<_>>();
+
+    if !force {
+        if let Some(existing) = paths.iter().find(|path| path.exists()) {
+            return Err(anyhow!(
+                "{} already exists\nuse --force to overwrite it",
+                existing.display()
+            ));
+        }
+    }
+
+    fs::create_dir_all(dir)
+        .with_context(|| anyhow!("failed to create directory at {}", dir.display()))?;
+
+    for (unit, path) in units.iter().zip(&paths) {
+        fs::write(path, &unit.contents)
+            .with_context(|| anyhow!("failed to write unit to {}", path.display()))?;
+    }
+
+    Ok(paths)
+}
+
+/// Escapes `arg` so systemd passes it to a command in `ExecStart` as a single argument, exactly as given.
+///
+/// Arguments made up of only safe characters are left as is. Everything else is double quoted with
+/// C-style escapes, and `%` and `$` are doubled so they aren't expanded as specifiers or variables.
+pub fn escape_arg(arg: &str) -> String {
+    let is_plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || "-_./:=@+,".contains(ch));
+
+    if is_plain {
+        return arg.into();
+    }
+
+    let mut escaped = String::with_capacity(arg.len() + 2);
+    escaped.push('"');
+
+    for ch in arg.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '%' => escaped.push_str("%%"),
+            '$' => escaped.push_str("$$"),
+            ch if ch.is_control() => {
+                let mut buf = [0; 4];
+
+                for byte in ch.encode_utf8(&mut buf).bytes() {
+                    write!(escaped, "\\x{:02x}", byte).unwrap();
+                }
+            }
+            ch => escaped.push(ch),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
+
+/// Escapes `path` to be used as a path argument of a command.
+fn path_arg(path: &Path) -> Result<String> {
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| anyhow!("{} is not valid UTF-8", path.display()))?;
+
+    Ok(escape_arg(path_str))
+}
+
+/// Escapes `path` to be used as the value of a setting that takes a single path, such as `PathChanged`.
+///
+/// These settings can't be quoted, so paths with control characters or surrounding whitespace are rejected.
+pub fn escape_path(path: &Path) -> Result<String> {
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| anyhow!("{} is not valid UTF-8", path.display()))?;
+
+    if !path.is_absolute() {
+        return Err(anyhow!("{} is not an absolute path", path_str));
+    }
+
+    if path_str.chars().any(char::is_control) || path_str.trim() != path_str {
+        return Err(anyhow!("{:?} can't be used in a unit file", path_str));
+    }
+
+    Ok(path_str.replace('%', "%%"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EXE: &str = "/run/current-system/sw/bin/nixup";
+
+    fn options(scope: UnitScope, diff_args: &[&str]) -> UnitOptions {
+        UnitOptions {
+            mode: UnitMode::PostRebuild,
+            profile: UnitOptions::default_profile(),
+            scope,
+            diff_args: diff_args.iter().map(|&arg| arg.into()).collect(),
+            install: false,
+            force: false,
+        }
+    }
+
+    fn find<'a>(units: &'a [Unit], suffix: &str) -> &'a str {
+        &units
+            .iter()
+            .find(|unit| unit.name.ends_with(suffix))
+            .unwrap()
+            .contents
+    }
+
+    #[test]
+    fn escape_args() {
+        let cases = [
+            ("--packages-only", "--packages-only"),
+            ("/home/user/.local/share", "/home/user/.local/share"),
+            ("", "\"\""),
+            ("two words", "\"two words\""),
+            ("100%", "\"100%%\""),
+            ("$HOME", "\"$$HOME\""),
+            ("say \"hi\"", "\"say \\\"hi\\\"\""),
+            ("C:\\path", "\"C:\\\\path\""),
+            ("a\nb\tc", "\"a\\nb\\tc\""),
+            ("bell\u{7}", "\"bell\\x07\""),
+            (";", "\";\""),
+            ("it's", "\"it's\""),
+            ("naïve", "\"naïve\""),
+        ];
+
+        for (arg, expected) in &cases {
+            assert_eq!(escape_arg(arg), *expected, "{:?}", arg);
+        }
+    }
+
+    #[test]
+    fn escape_paths() {
+        assert_eq!(
+            escape_path(Path::new("/nix/var/nix/profiles/50% off")).unwrap(),
+            "/nix/var/nix/profiles/50%% off"
+        );
+
+        for invalid in &["relative/profile", "/nix/new\nline", "/nix/trailing "] {
+            assert!(escape_path(Path::new(invalid)).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn profile_paths() {
+        assert_eq!(
+            UnitOptions::profile_path("system"),
+            Path::new("/nix/var/nix/profiles/system")
+        );
+        assert_eq!(
+            UnitOptions::profile_path("per-user/alice/profile"),
+            Path::new("/nix/var/nix/profiles/per-user/alice/profile")
+        );
+        assert_eq!(
+            UnitOptions::profile_path("/home/user/.nix-profile"),
+            Path::new("/home/user/.nix-profile")
+        );
+    }
+
+    #[test]
+    fn generate_user_units() {
+        let units = generate(Path::new(EXE), None, &options(UnitScope::User, &[])).unwrap();
+
+        let names = units
+            .iter()
+            .map(|unit| unit.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "nixup-post-rebuild.path",
+                "nixup-save-state.service",
+                "nixup-diff.service"
+            ]
+        );
+
+        assert_eq!(
+            find(&units, ".path"),
+            "[Unit]\n\
+             Description=Watch /nix/var/nix/profiles/system for changes with nixup\n\
+             \n\
+             [Path]\n\
+             PathChanged=/nix/var/nix/profiles/system\n\
+             Unit=nixup-save-state.service\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n"
+        );
+
+        assert_eq!(
+            find(&units, "save-state.service"),
+            "[Unit]\n\
+             Description=Save the package state with nixup\n\
+             Wants=nixup-diff.service\n\
+             After=nixup-diff.service\n\
+             \n\
+             [Service]\n\
+             Type=oneshot\n\
+             ExecStart=/run/current-system/sw/bin/nixup --save-state\n"
+        );
+
+        assert_eq!(
+            find(&units, "diff.service"),
+            "[Unit]\n\
+             Description=Show what changed since the last package state saved by nixup\n\
+             \n\
+             [Service]\n\
+             Type=oneshot\n\
+             ExecStart=/run/current-system/sw/bin/nixup\n"
+        );
+    }
+
+    #[test]
+    fn generate_system_units_with_flags() {
+        let mut opts = options(
+            UnitScope::System,
+            &["--packages-only", "--format", "ndjson", "--timeout", "30"],
+        );
+        opts.profile = UnitOptions::profile_path("per-user/alice/profile");
+
+        let units = generate(Path::new(EXE), Some(Path::new("/var/lib/nixup")), &opts).unwrap();
+
+        let path = find(&units, ".path");
+        assert!(path.contains("PathChanged=/nix/var/nix/profiles/per-user/alice/profile\n"));
+        assert!(path.contains("WantedBy=multi-user.target\n"));
+
+        assert!(find(&units, "save-state.service").contains(
+            "ExecStart=/run/current-system/sw/bin/nixup --data-dir /var/lib/nixup --save-state\n"
+        ));
+
+        assert!(find(&units, "diff.service").contains(
+            "ExecStart=/run/current-system/sw/bin/nixup --data-dir /var/lib/nixup \
+             --packages-only --format ndjson --timeout 30\n"
+        ));
+    }
+
+    #[test]
+    fn generate_units_with_special_characters() {
+        let opts = options(UnitScope::User, &["--message", "50% of $USER's \"stuff\""]);
+        let units = generate(
+            Path::new("/opt/my tools/nixup"),
+            Some(Path::new("/home/user/nixup data")),
+            &opts,
+        )
+        .unwrap();
+
+        assert!(find(&units, "save-state.service").contains(
+            "ExecStart=\"/opt/my tools/nixup\" --data-dir \"/home/user/nixup data\" --save-state\n"
+        ));
+
+        assert!(
+            find(&units, "diff.service").contains("--message \"50%% of $$USER's \\\"stuff\\\"\"\n")
+        );
+
+        let mut opts = options(UnitScope::User, &[]);
+        opts.profile = PathBuf::from("relative");
+        assert!(generate(Path::new(EXE), None, &opts).is_err());
+    }
+
+    #[test]
+    fn install_refuses_to_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit_dir = dir.path().join("systemd").join("user");
+
+        let units = generate(Path::new(EXE), None, &options(UnitScope::User, &[])).unwrap();
+
+        let paths = install(&unit_dir, &units, false).unwrap();
+        assert_eq!(paths.len(), units.len());
+
+        for (unit, path) in units.iter().zip(&paths) {
+            assert_eq!(fs::read_to_string(path).unwrap(), unit.contents);
+        }
+
+        // Changing one unit and reinstalling shouldn't touch any of them
+        fs::write(&paths[2], "edited").unwrap();
+
+        let err = install(&unit_dir, &units, false).unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+        assert_eq!(fs::read_to_string(&paths[2]).unwrap(), "edited");
+
+        install(&unit_dir, &units, true).unwrap();
+        assert_eq!(fs::read_to_string(&paths[2]).unwrap(), units[2].contents);
+    }
+}